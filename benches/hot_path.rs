@@ -0,0 +1,113 @@
+//! Per-datagram cost of the send and receive paths, without the network:
+//! encoding a payload, decoding it, and writing the line logged for it.
+//!
+//! `cargo bench` prints the time and the number of allocations each takes.
+//! The buffers are reused, as the sender and receiver reuse theirs, so a
+//! step that allocates shows up here.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fmt::Write;
+use std::hint::black_box;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use udp_bcast_ts::epoch::Epoch;
+use udp_bcast_ts::message::MessageType;
+use udp_bcast_ts::payload::{self, Field, Format, Packet};
+use udp_bcast_ts::units;
+
+struct Counting;
+
+static ALLOCS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const ITERATIONS: u64 = 1_000_000;
+
+/// Runs `f` once to fill its buffers, then [`ITERATIONS`] times, and
+/// prints the mean time and allocations per run.
+fn bench(name: &str, mut f: impl FnMut(u64)) {
+    f(0);
+    let allocs = ALLOCS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for i in 0..ITERATIONS {
+        f(i);
+    }
+    let ns = start.elapsed().as_nanos() as f64 / ITERATIONS as f64;
+    let allocs = (ALLOCS.load(Ordering::Relaxed) - allocs) as f64 / ITERATIONS as f64;
+    println!("{name:<24} {ns:>8.1} ns {allocs:>6.2} allocs");
+}
+
+fn main() {
+    let packet = |i: u64| Packet {
+        kind: MessageType::DATA,
+        seq: Some(i as u32),
+        ts_ms: 1_772_366_400_000 + i,
+        group: Some(3),
+        control: None,
+        mono_us: Some(i * 1000),
+        era: Some(0),
+        relay: None,
+        priority: None,
+        unsynced: false,
+        epoch: Epoch::Unix,
+    };
+    let fields = [Field::parse("site=lab").unwrap()];
+    let dest: SocketAddr = "192.0.2.255:12345".parse().unwrap();
+
+    let mut out = Vec::new();
+    bench("encode v2", |i| {
+        payload::encode_with_fields(Format::V2, &packet(i), &fields, None, &mut out);
+        black_box(&out);
+    });
+
+    let mut encoded = Vec::new();
+    payload::encode_with_fields(Format::V2, &packet(1), &fields, None, &mut encoded);
+    bench("decode v2", |_| {
+        black_box(payload::decode(black_box(&encoded)).unwrap());
+    });
+
+    let mut line = String::new();
+    bench("sent line", |i| {
+        line.clear();
+        let _ = write!(
+            line,
+            "Sent broadcast to {dest} {}",
+            units::ts(packet(i).ts_ms)
+        );
+        black_box(&line);
+    });
+    bench("received line", |i| {
+        let p = packet(i);
+        line.clear();
+        let _ = write!(line, "Received from {dest}");
+        if let Some(s) = p.seq {
+            let _ = write!(line, " seq={s}");
+        }
+        let _ = write!(
+            line,
+            " {} offset_ms={}",
+            units::ts(p.ts_ms),
+            units::millis(-3)
+        );
+        black_box(&line);
+    });
+}
@@ -0,0 +1,274 @@
+//! Adaptive send interval from receiver acks (`send --adaptive`).
+//!
+//! Receivers started with `recv --ack` answer every timestamp with an ack
+//! echoing its sequence number and timestamp, to the port it came from.
+//! The sender reads them on a thread of its own and, every
+//! [`WINDOW_TICKS`] ticks, looks at each receiver that acked anything:
+//!
+//! * loss: sequence numbers skipped between the acks it sent, and
+//! * jitter: how much the round trip, from the echoed timestamp to the
+//!   ack's arrival, varies, estimated as in RFC 3550.
+//!
+//! Any loss, or jitter beyond twice what it was in calm windows plus a
+//! millisecond, halves the interval, down to the lower bound; a calm
+//! window lengthens it by a quarter, up to the upper bound. Receivers
+//! that stop acking are left out rather than counted as loss, and with
+//! none acking the interval is left as it is.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::log;
+use crate::message::MessageType;
+use crate::payload;
+
+/// Ticks between decisions.
+pub const WINDOW_TICKS: u32 = 8;
+
+/// Jitter above `2 * calm + SLACK_US` counts as unstable.
+const SLACK_US: f64 = 1000.0;
+
+/// Bounds for `--adaptive`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AdaptiveConfig {
+    pub min: Duration,
+    pub max: Duration,
+}
+
+/// One ack, as read off the sender's socket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ack {
+    pub from: SocketAddr,
+    pub seq: u32,
+    /// From the acked timestamp being taken to the ack arriving.
+    pub rtt_us: i64,
+}
+
+/// Reads acks arriving on `sock` on a thread of its own, until the
+/// returned receiver is dropped.
+pub fn listen(sock: &UdpSocket) -> Result<mpsc::Receiver<Ack>, String> {
+    let sock = sock
+        .try_clone()
+        .map_err(|e| format!("Failed to read acks: {e}"))?;
+    let (tx, rx) = mpsc::channel();
+    std::thread::Builder::new()
+        .name("acks".to_string())
+        .spawn(move || {
+            let mut buf = [0u8; 1500];
+            loop {
+                let (len, from) = match sock.recv_from(&mut buf) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        log::debug!("Failed to read an ack: {e}");
+                        continue;
+                    }
+                };
+                let arrived_us = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_micros() as i64);
+                let packet = match payload::decode(&buf[..len]) {
+                    Ok(d) if d.packet.kind == MessageType::ACK => d.packet,
+                    _ => continue,
+                };
+                let Some(seq) = packet.seq else { continue };
+                // The timestamp was taken somewhere in its millisecond
+                let rtt_us = arrived_us - packet.ts_ms as i64 * 1000 - 500;
+                if tx.send(Ack { from, seq, rtt_us }).is_err() {
+                    return;
+                }
+            }
+        })
+        .map_err(|e| format!("Failed to start the ack reader: {e}"))?;
+    Ok(rx)
+}
+
+/// What one receiver's acks showed so far.
+#[derive(Default)]
+struct Peer {
+    /// Highest sequence number acked before this window, then in it.
+    before: Option<u32>,
+    last: Option<u32>,
+    acked: u32,
+    last_rtt_us: Option<i64>,
+    jitter_us: f64,
+}
+
+impl Peer {
+    fn ack(&mut self, ack: &Ack) {
+        if let Some(last) = self.last_rtt_us {
+            let d = (ack.rtt_us - last).abs() as f64;
+            self.jitter_us += (d - self.jitter_us) / 16.0;
+        }
+        self.last_rtt_us = Some(ack.rtt_us);
+        let newer = |than: Option<u32>| than.is_none_or(|t| ack.seq.wrapping_sub(t) as i32 > 0);
+        if !newer(self.before) {
+            return;
+        }
+        self.acked += 1;
+        if newer(self.last) {
+            self.last = Some(ack.seq);
+        }
+    }
+
+    /// Fraction of the sequence numbers since the last window that went
+    /// unacked, or `None` if nothing was acked; starts the next window.
+    fn close(&mut self) -> Option<f64> {
+        let acked = std::mem::take(&mut self.acked);
+        let last = self.last?;
+        let before = self.before.replace(last);
+        if acked == 0 {
+            return None;
+        }
+        // The first window counts from the first ack
+        let expected = before.map_or(acked, |b| last.wrapping_sub(b));
+        Some(1.0 - (acked as f64 / expected.max(1) as f64).min(1.0))
+    }
+}
+
+/// The outcome of one window.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Decision {
+    pub interval: Duration,
+    /// Worst loss among the receivers that acked, as a fraction.
+    pub loss: f64,
+    pub jitter_us: u64,
+    pub receivers: usize,
+}
+
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "interval_us={} loss={:.1}% jitter_us={} receivers={}",
+            self.interval.as_micros(),
+            self.loss * 100.0,
+            self.jitter_us,
+            self.receivers
+        )
+    }
+}
+
+/// Decides the interval from the acks seen.
+pub struct Controller {
+    cfg: AdaptiveConfig,
+    peers: BTreeMap<SocketAddr, Peer>,
+    ticks: u32,
+    /// Jitter in calm windows, averaged.
+    calm_jitter_us: Option<f64>,
+}
+
+impl Controller {
+    pub fn new(cfg: AdaptiveConfig) -> Controller {
+        Controller {
+            cfg,
+            peers: BTreeMap::new(),
+            ticks: 0,
+            calm_jitter_us: None,
+        }
+    }
+
+    pub fn ack(&mut self, ack: &Ack) {
+        self.peers.entry(ack.from).or_default().ack(ack);
+    }
+
+    /// Counts a tick at `interval`; at the end of each window, returns
+    /// what the acks showed and the interval to use, unless no receiver
+    /// acked.
+    pub fn tick(&mut self, interval: Duration) -> Option<Decision> {
+        self.ticks += 1;
+        if self.ticks < WINDOW_TICKS {
+            return None;
+        }
+        self.ticks = 0;
+        let mut loss: f64 = 0.0;
+        let mut jitter_us: f64 = 0.0;
+        let mut receivers = 0;
+        for peer in self.peers.values_mut() {
+            if let Some(l) = peer.close() {
+                loss = loss.max(l);
+                jitter_us = jitter_us.max(peer.jitter_us);
+                receivers += 1;
+            }
+        }
+        if receivers == 0 {
+            return None;
+        }
+        let calm = *self.calm_jitter_us.get_or_insert(jitter_us);
+        let unstable = loss > 0.0 || jitter_us > 2.0 * calm + SLACK_US;
+        let interval = if unstable {
+            (interval / 2).max(self.cfg.min)
+        } else {
+            self.calm_jitter_us = Some(calm + (jitter_us - calm) / 8.0);
+            (interval + interval / 4).min(self.cfg.max)
+        };
+        Some(Decision {
+            interval,
+            loss,
+            jitter_us: jitter_us as u64,
+            receivers,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ack(seq: u32, rtt_us: i64) -> Ack {
+        Ack {
+            from: "192.0.2.20:12321".parse().unwrap(),
+            seq,
+            rtt_us,
+        }
+    }
+
+    #[test]
+    fn backs_off_when_calm_and_tightens_on_loss() {
+        let cfg = AdaptiveConfig {
+            min: Duration::from_millis(100),
+            max: Duration::from_millis(1000),
+        };
+        let mut c = Controller::new(cfg);
+        let mut interval = Duration::from_millis(400);
+        let mut window = |c: &mut Controller, seqs: std::ops::Range<u32>, skip: Option<u32>| {
+            for seq in seqs.filter(|&s| Some(s) != skip) {
+                c.ack(&ack(seq, 300));
+            }
+            for _ in 1..WINDOW_TICKS {
+                assert_eq!(c.tick(interval), None);
+            }
+            let d = c.tick(interval).unwrap();
+            interval = d.interval;
+            d
+        };
+
+        let d = window(&mut c, 0..8, None);
+        assert_eq!(
+            (d.interval, d.loss, d.receivers),
+            (Duration::from_millis(500), 0.0, 1)
+        );
+        assert_eq!(
+            window(&mut c, 8..16, None).interval,
+            Duration::from_millis(625)
+        );
+        let d = window(&mut c, 16..24, Some(17));
+        assert_eq!(
+            d.interval,
+            Duration::from_millis(312) + Duration::from_micros(500)
+        );
+        assert_eq!(d.loss, 0.125);
+        assert_eq!(
+            window(&mut c, 24..32, Some(30)).interval,
+            Duration::from_millis(156) + Duration::from_micros(250)
+        );
+        assert_eq!(window(&mut c, 32..40, Some(33)).interval, cfg.min);
+
+        // Silence says nothing either way
+        for _ in 0..WINDOW_TICKS {
+            assert_eq!(c.tick(interval), None);
+        }
+    }
+}
@@ -0,0 +1,284 @@
+//! ChaCha20-Poly1305 (RFC 8439) for payloads whose contents other hosts
+//! on the network must not read (`--encrypt-key`).
+//!
+//! Like SHA-256 in [`auth`](crate::auth), both primitives are implemented
+//! here rather than pulled in as a dependency. Poly1305 uses the 44/44/42
+//! bit limb layout of poly1305-donna, with `u128` products; tags are
+//! compared in constant time.
+//!
+//! A key file holds any secret, like a `--group` key file; the cipher key
+//! is its SHA-256 digest, taken with a label so that the same file used
+//! as an HMAC key yields an unrelated key. How payloads are sealed, and
+//! where the nonce comes from, is in [`crate::payload`].
+
+use std::collections::hash_map::RandomState;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+
+use crate::auth::sha256;
+use crate::rng::Rng;
+
+pub const KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 12;
+pub const TAG_LEN: usize = 16;
+
+/// Keeps a digest of a key file from matching its HMAC use.
+const LABEL: &[u8] = b"udp_bcast_ts payload encryption";
+
+/// A ChaCha20-Poly1305 key.
+#[derive(Clone)]
+pub struct AeadKey([u8; KEY_LEN]);
+
+impl AeadKey {
+    pub fn new(key: [u8; KEY_LEN]) -> AeadKey {
+        AeadKey(key)
+    }
+
+    /// Reads a key file; trailing whitespace is ignored so keys can be
+    /// written with `echo`.
+    pub fn load(path: &str) -> Result<AeadKey, String> {
+        let data = fs::read(path).map_err(|e| format!("Failed to read key file {path}: {e}"))?;
+        let end = data
+            .iter()
+            .rposition(|b| !b.is_ascii_whitespace())
+            .map_or(0, |i| i + 1);
+        if end == 0 {
+            return Err(format!("Key file {path} is empty"));
+        }
+        Ok(AeadKey(sha256(&[LABEL, &data[..end]])))
+    }
+
+    /// Encrypts `data` in place and returns the tag over `aad` and the
+    /// ciphertext.
+    pub fn seal(&self, nonce: &[u8; NONCE_LEN], aad: &[u8], data: &mut [u8]) -> [u8; TAG_LEN] {
+        let poly_key = self.poly_key(nonce);
+        chacha20_xor(&self.0, nonce, 1, data);
+        poly1305_tag(&poly_key, aad, data)
+    }
+
+    /// Checks `tag` and decrypts `data` in place, leaving it untouched and
+    /// returning false if the tag doesn't match.
+    pub fn open(&self, nonce: &[u8; NONCE_LEN], aad: &[u8], data: &mut [u8], tag: &[u8]) -> bool {
+        let expected = poly1305_tag(&self.poly_key(nonce), aad, data);
+        let ok = tag.len() == TAG_LEN
+            && expected
+                .iter()
+                .zip(tag)
+                .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+                == 0;
+        if ok {
+            chacha20_xor(&self.0, nonce, 1, data);
+        }
+        ok
+    }
+
+    fn poly_key(&self, nonce: &[u8; NONCE_LEN]) -> [u8; 32] {
+        let block = chacha20_block(&self.0, nonce, 0);
+        block[..32].try_into().unwrap()
+    }
+}
+
+/// Eight bytes that differ between calls and between hosts, for nonces.
+/// They need to be unique, not secret; the standard library's hasher keys
+/// come from the OS's random source.
+pub fn salt() -> [u8; 8] {
+    let mut h = RandomState::new().build_hasher();
+    h.write_u64(Rng::from_entropy().next_u64());
+    h.finish().to_be_bytes()
+}
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+fn chacha20_block(key: &[u8; KEY_LEN], nonce: &[u8; NONCE_LEN], counter: u32) -> [u8; 64] {
+    let word = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+    let mut state = [0u32; 16];
+    state[..4].copy_from_slice(&[0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574]);
+    for (i, chunk) in key.chunks_exact(4).enumerate() {
+        state[4 + i] = word(chunk);
+    }
+    state[12] = counter;
+    for (i, chunk) in nonce.chunks_exact(4).enumerate() {
+        state[13 + i] = word(chunk);
+    }
+
+    let mut s = state;
+    for _ in 0..10 {
+        quarter_round(&mut s, 0, 4, 8, 12);
+        quarter_round(&mut s, 1, 5, 9, 13);
+        quarter_round(&mut s, 2, 6, 10, 14);
+        quarter_round(&mut s, 3, 7, 11, 15);
+        quarter_round(&mut s, 0, 5, 10, 15);
+        quarter_round(&mut s, 1, 6, 11, 12);
+        quarter_round(&mut s, 2, 7, 8, 13);
+        quarter_round(&mut s, 3, 4, 9, 14);
+    }
+    let mut out = [0u8; 64];
+    for (i, chunk) in out.chunks_exact_mut(4).enumerate() {
+        chunk.copy_from_slice(&s[i].wrapping_add(state[i]).to_le_bytes());
+    }
+    out
+}
+
+fn chacha20_xor(key: &[u8; KEY_LEN], nonce: &[u8; NONCE_LEN], counter: u32, data: &mut [u8]) {
+    for (i, chunk) in data.chunks_mut(64).enumerate() {
+        let block = chacha20_block(key, nonce, counter.wrapping_add(i as u32));
+        for (b, k) in chunk.iter_mut().zip(block) {
+            *b ^= k;
+        }
+    }
+}
+
+const M44: u64 = (1 << 44) - 1;
+const M42: u64 = (1 << 42) - 1;
+
+struct Poly1305 {
+    r: [u64; 3],
+    h: [u64; 3],
+    pad: [u64; 2],
+}
+
+impl Poly1305 {
+    fn new(key: &[u8; 32]) -> Poly1305 {
+        let le = |b: &[u8]| u64::from_le_bytes(b.try_into().unwrap());
+        let (t0, t1) = (le(&key[0..8]), le(&key[8..16]));
+        Poly1305 {
+            r: [
+                t0 & 0xffc_0fff_ffff,
+                ((t0 >> 44) | (t1 << 20)) & 0xfff_ffc0_ffff,
+                (t1 >> 24) & 0x00f_ffff_fc0f,
+            ],
+            h: [0; 3],
+            pad: [le(&key[16..24]), le(&key[24..32])],
+        }
+    }
+
+    /// Absorbs one whole block. The AEAD construction pads everything to
+    /// whole blocks, so the final partial block of plain Poly1305 never
+    /// occurs.
+    fn block(&mut self, b: &[u8; 16]) {
+        let t0 = u64::from_le_bytes(b[..8].try_into().unwrap());
+        let t1 = u64::from_le_bytes(b[8..].try_into().unwrap());
+        let hibit = 1 << 40;
+
+        let [r0, r1, r2] = self.r.map(u128::from);
+        let (s1, s2) = (r1 * 20, r2 * 20);
+        let h0 = u128::from(self.h[0] + (t0 & M44));
+        let h1 = u128::from(self.h[1] + (((t0 >> 44) | (t1 << 20)) & M44));
+        let h2 = u128::from(self.h[2] + (((t1 >> 24) & M42) | hibit));
+
+        let d0 = h0 * r0 + h1 * s2 + h2 * s1;
+        let mut d1 = h0 * r1 + h1 * r0 + h2 * s2;
+        let mut d2 = h0 * r2 + h1 * r1 + h2 * r0;
+        d1 += d0 >> 44;
+        let mut h0 = d0 as u64 & M44;
+        d2 += d1 >> 44;
+        let mut h1 = d1 as u64 & M44;
+        let c = (d2 >> 42) as u64;
+        let h2 = d2 as u64 & M42;
+        h0 += c * 5;
+        h1 += h0 >> 44;
+        h0 &= M44;
+        self.h = [h0, h1, h2];
+    }
+
+    /// Absorbs `data` padded with zeros to a multiple of 16 bytes, as the
+    /// AEAD construction does for the AAD and the ciphertext.
+    fn padded(&mut self, data: &[u8]) {
+        for chunk in data.chunks(16) {
+            let mut b = [0u8; 16];
+            b[..chunk.len()].copy_from_slice(chunk);
+            self.block(&b);
+        }
+    }
+
+    fn finish(self) -> [u8; TAG_LEN] {
+        let [mut h0, mut h1, mut h2] = self.h;
+        for _ in 0..2 {
+            h2 += h1 >> 44;
+            h1 &= M44;
+            h0 += (h2 >> 42) * 5;
+            h2 &= M42;
+            h1 += h0 >> 44;
+            h0 &= M44;
+        }
+
+        // h - p, kept if h >= p
+        let mut g0 = h0 + 5;
+        let mut g1 = h1 + (g0 >> 44);
+        g0 &= M44;
+        let g2 = (h2 + (g1 >> 44)).wrapping_sub(1 << 42);
+        g1 &= M44;
+        let keep_g = (g2 >> 63).wrapping_sub(1);
+        h0 = (h0 & !keep_g) | (g0 & keep_g);
+        h1 = (h1 & !keep_g) | (g1 & keep_g);
+        h2 = (h2 & !keep_g) | (g2 & keep_g);
+
+        let [t0, t1] = self.pad;
+        h0 += t0 & M44;
+        h1 += (((t0 >> 44) | (t1 << 20)) & M44) + (h0 >> 44);
+        h0 &= M44;
+        h2 += ((t1 >> 24) & M42) + (h1 >> 44);
+        h1 &= M44;
+        h2 &= M42;
+
+        let mut out = [0u8; TAG_LEN];
+        out[..8].copy_from_slice(&(h0 | (h1 << 44)).to_le_bytes());
+        out[8..].copy_from_slice(&((h1 >> 20) | (h2 << 24)).to_le_bytes());
+        out
+    }
+}
+
+fn poly1305_tag(poly_key: &[u8; 32], aad: &[u8], ciphertext: &[u8]) -> [u8; TAG_LEN] {
+    let mut p = Poly1305::new(poly_key);
+    p.padded(aad);
+    p.padded(ciphertext);
+    let mut lengths = [0u8; 16];
+    lengths[..8].copy_from_slice(&(aad.len() as u64).to_le_bytes());
+    lengths[8..].copy_from_slice(&(ciphertext.len() as u64).to_le_bytes());
+    p.block(&lengths);
+    p.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        let s: String = s.split_whitespace().collect();
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn matches_rfc_8439_and_rejects_tampering() {
+        // RFC 8439, section 2.8.2
+        let key = AeadKey::new(std::array::from_fn(|i| 0x80 + i as u8));
+        let nonce: [u8; NONCE_LEN] = hex("07000000 40414243 44454647").try_into().unwrap();
+        let aad = hex("50515253 c0c1c2c3 c4c5c6c7");
+        let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you \
+only one tip for the future, sunscreen would be it.";
+        let mut data = plaintext.to_vec();
+        let tag = key.seal(&nonce, &aad, &mut data);
+        assert_eq!(data[..16], hex("d31a8d34 648e60db 7b86afbc 53ef7ec2"));
+        assert_eq!(data[data.len() - 2..], hex("6116"));
+        assert_eq!(tag[..], hex("1ae10b59 4f09e26a 7e902ecb d0600691"));
+
+        let mut tampered = data.clone();
+        tampered[0] ^= 1;
+        assert!(!key.open(&nonce, &aad, &mut tampered, &tag));
+        assert!(!key.open(&nonce, &aad[1..], &mut data, &tag));
+        assert!(key.open(&nonce, &aad, &mut data, &tag));
+        assert_eq!(data, plaintext);
+    }
+}
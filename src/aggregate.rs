@@ -0,0 +1,526 @@
+//! Aggregate mode: a fleet-wide view built from many receivers' reports.
+//!
+//! A receiver run with `--report-to HOST:PORT` sends the aggregator what
+//! its sender table knows every `--report-interval`: per sender, datagrams
+//! received and lost, and the offsets seen. The aggregator keeps the latest
+//! report from each site (`--site`, the receiver's hostname by default) and
+//! prints the fleet as a whole: a line per site, a loss matrix with a row
+//! per sender and a column per site, and the worst offenders by offset and
+//! by loss. A sender one site never heard shows `-` in its column, which
+//! tells a filtered subnet from a lossy one.
+//!
+//! Reports are plain text over UDP, so they can be read off the wire and
+//! written by other tools. A report is a header line followed by a line
+//! per sender:
+//!
+//! ```text
+//! udp_bcast_ts-report 1 site=lab-a
+//! sender=10.0.0.5:4000/- received=1200 lost=3 offset_min_ms=-2 offset_mean_ms=1 offset_max_ms=9 last_seen_ms=140
+//! ```
+//!
+//! Counts are since the receiver started. A table too large for one
+//! datagram of [`MAX_DATAGRAM`] bytes is split across several, each with
+//! the header, and each replaces only the senders it lists.
+
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+use crate::exit::{self, Failure};
+use crate::log;
+use crate::send::bind_for;
+use crate::transmit;
+use crate::units;
+
+/// First word of the header line; the version follows it.
+pub const MAGIC: &str = "udp_bcast_ts-report";
+
+pub const VERSION: u32 = 1;
+
+/// Largest report datagram, sized to avoid IP fragmentation.
+pub const MAX_DATAGRAM: usize = 1400;
+
+/// Default `--report-interval`.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// One receiver's view of one sender.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Row {
+    /// Source address and group, as `ip:port/group` with `-` for no group.
+    pub sender: String,
+    pub received: u64,
+    pub lost: u64,
+    pub offset_min_ms: i64,
+    pub offset_mean_ms: i64,
+    pub offset_max_ms: i64,
+    /// How long before the report the sender was last heard.
+    pub last_seen_ms: u64,
+}
+
+impl Row {
+    fn loss_pct(&self) -> f64 {
+        loss_pct(self.received, self.lost)
+    }
+
+    fn line(&self) -> String {
+        format!(
+            "sender={} received={} lost={} offset_min_ms={} offset_mean_ms={} offset_max_ms={} last_seen_ms={}",
+            self.sender,
+            self.received,
+            self.lost,
+            self.offset_min_ms,
+            self.offset_mean_ms,
+            self.offset_max_ms,
+            self.last_seen_ms,
+        )
+    }
+
+    fn parse(line: &str) -> Result<Row, String> {
+        let mut row = Row {
+            sender: String::new(),
+            received: 0,
+            lost: 0,
+            offset_min_ms: 0,
+            offset_mean_ms: 0,
+            offset_max_ms: 0,
+            last_seen_ms: 0,
+        };
+        for field in line.split_whitespace() {
+            let (key, value) = field
+                .split_once('=')
+                .ok_or_else(|| format!("expected key=value, got {field:?}"))?;
+            let bad = |e: std::num::ParseIntError| format!("bad {key}: {e}");
+            match key {
+                "sender" => row.sender = value.to_string(),
+                "received" => row.received = value.parse().map_err(bad)?,
+                "lost" => row.lost = value.parse().map_err(bad)?,
+                "offset_min_ms" => row.offset_min_ms = value.parse().map_err(bad)?,
+                "offset_mean_ms" => row.offset_mean_ms = value.parse().map_err(bad)?,
+                "offset_max_ms" => row.offset_max_ms = value.parse().map_err(bad)?,
+                "last_seen_ms" => row.last_seen_ms = value.parse().map_err(bad)?,
+                // Fields added by later versions
+                _ => {}
+            }
+        }
+        if row.sender.is_empty() {
+            return Err("row without a sender".to_string());
+        }
+        Ok(row)
+    }
+}
+
+/// Percentage of datagrams lost. Counters come from unauthenticated
+/// reports, so the total saturates rather than overflowing.
+fn loss_pct(received: u64, lost: u64) -> f64 {
+    match received.saturating_add(lost) {
+        0 => 0.0,
+        n => lost as f64 * 100.0 / n as f64,
+    }
+}
+
+/// Checks a `--site` name, which must fit in a `key=value` field.
+pub fn parse_site(s: &str) -> Result<String, String> {
+    if s.is_empty() || s.chars().any(|c| c.is_whitespace() || c == '=') {
+        return Err("expected a name without spaces or '='".to_string());
+    }
+    Ok(s.to_string())
+}
+
+/// Encodes `rows` as one or more report datagrams from `site`.
+pub fn encode(site: &str, rows: &[Row]) -> Vec<Vec<u8>> {
+    let header = format!("{MAGIC} {VERSION} site={site}\n");
+    let mut out = Vec::new();
+    let mut datagram = header.clone();
+    for row in rows {
+        let line = row.line() + "\n";
+        if datagram.len() > header.len() && datagram.len() + line.len() > MAX_DATAGRAM {
+            out.push(std::mem::replace(&mut datagram, header.clone()).into_bytes());
+        }
+        datagram.push_str(&line);
+    }
+    // An empty table still reports the site as alive
+    out.push(datagram.into_bytes());
+    out
+}
+
+/// Decodes a report datagram into its site and rows.
+pub fn decode(data: &[u8]) -> Result<(String, Vec<Row>), String> {
+    let text = std::str::from_utf8(data).map_err(|_| "not UTF-8".to_string())?;
+    let mut lines = text.lines();
+    let header = lines.next().unwrap_or_default();
+    let mut words = header.split_whitespace();
+    if words.next() != Some(MAGIC) {
+        return Err("not a report".to_string());
+    }
+    let version: u32 = words
+        .next()
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| "missing version".to_string())?;
+    if version != VERSION {
+        return Err(format!("unsupported report version {version}"));
+    }
+    let site = words
+        .find_map(|w| w.strip_prefix("site="))
+        .filter(|s| !s.is_empty())
+        .ok_or_else(|| "missing site".to_string())?;
+    let rows = lines
+        .filter(|l| !l.trim().is_empty())
+        .map(Row::parse)
+        .collect::<Result<_, _>>()?;
+    Ok((site.to_string(), rows))
+}
+
+/// Settings for `recv --report-to`.
+#[derive(Clone, Debug)]
+pub struct ReportConfig {
+    /// Aggregator, as `HOST:PORT`.
+    pub addr: String,
+    pub site: String,
+    pub interval: Duration,
+}
+
+/// Sends a receiver's sender table to the aggregator.
+pub struct Reporter {
+    sock: UdpSocket,
+    dest: SocketAddr,
+    site: String,
+    interval: Duration,
+    next: Instant,
+}
+
+impl Reporter {
+    /// Resolves the aggregator and binds a socket to report from. The first
+    /// report goes out one interval from now.
+    pub fn connect(cfg: &ReportConfig) -> Result<Reporter, String> {
+        let dest = cfg
+            .addr
+            .to_socket_addrs()
+            .map_err(|e| format!("Failed to resolve --report-to {}: {e}", cfg.addr))?
+            .next()
+            .ok_or_else(|| format!("--report-to {} resolved to no addresses", cfg.addr))?;
+        let sock = bind_for(dest)?;
+        log::info!("Reporting to aggregator {dest} as site {}", cfg.site);
+        Ok(Reporter {
+            sock,
+            dest,
+            site: cfg.site.clone(),
+            interval: cfg.interval,
+            next: Instant::now() + cfg.interval,
+        })
+    }
+
+    /// Sends `rows` if a report is due at `now`. A failed send is logged;
+    /// the next report carries the same counts anyway.
+    pub fn report(&mut self, now: Instant, rows: impl FnOnce() -> Vec<Row>) {
+        if now < self.next {
+            return;
+        }
+        self.next += self.interval;
+        if self.next <= now {
+            self.next = now + self.interval;
+        }
+        for datagram in encode(&self.site, &rows()) {
+            if let Err(e) = transmit::send_to(&self.sock, &datagram, self.dest) {
+                log::warn!("Failed to send report to {}: {e}", self.dest);
+                return;
+            }
+        }
+        log::debug!("Sent report to {}", self.dest);
+    }
+}
+
+/// The latest report from one site.
+struct Site {
+    from: SocketAddr,
+    at: Instant,
+    rows: BTreeMap<String, Row>,
+}
+
+/// Every site's latest report.
+#[derive(Default)]
+pub struct Fleet {
+    sites: BTreeMap<String, Site>,
+}
+
+impl Fleet {
+    /// Takes in a report from `site`, received from `from` at `now`.
+    pub fn observe(&mut self, site: String, from: SocketAddr, rows: Vec<Row>, now: Instant) {
+        let s = self.sites.entry(site).or_insert_with(|| Site {
+            from,
+            at: now,
+            rows: BTreeMap::new(),
+        });
+        s.from = from;
+        s.at = now;
+        for row in rows {
+            s.rows.insert(row.sender.clone(), row);
+        }
+    }
+
+    /// The fleet view: a summary, a line per site, a loss matrix row per
+    /// sender, and the `worst` rows by absolute mean offset and by loss.
+    pub fn lines(&self, now: Instant, worst: usize) -> Vec<String> {
+        let senders: BTreeMap<&str, ()> = self
+            .sites
+            .values()
+            .flat_map(|s| s.rows.keys().map(|k| (k.as_str(), ())))
+            .collect();
+        let mut lines = vec![format!(
+            "Fleet: sites={} senders={}",
+            self.sites.len(),
+            senders.len()
+        )];
+
+        for (name, s) in &self.sites {
+            let (received, lost) = s.rows.values().fold((0u64, 0u64), |(received, lost), r| {
+                (
+                    received.saturating_add(r.received),
+                    lost.saturating_add(r.lost),
+                )
+            });
+            let loss_pct = loss_pct(received, lost);
+            let worst_offset = s
+                .rows
+                .values()
+                .flat_map(|r| [r.offset_min_ms, r.offset_max_ms])
+                .max_by_key(|o| o.unsigned_abs())
+                .map_or_else(|| "-".to_string(), units::millis);
+            lines.push(format!(
+                "Site: site={name} from={} {} senders={} received={received} lost={lost} loss_pct={loss_pct:.2} {}",
+                s.from,
+                units::pair(
+                    "last_report_ms",
+                    units::millis(now.saturating_duration_since(s.at).as_millis() as i64)
+                ),
+                s.rows.len(),
+                units::pair("worst_offset_ms", worst_offset),
+            ));
+        }
+
+        for sender in senders.keys() {
+            let mut line = format!("Loss: sender={sender}");
+            for (name, s) in &self.sites {
+                match s.rows.get(*sender) {
+                    Some(r) => line.push_str(&format!(" {name}={:.2}", r.loss_pct())),
+                    None => line.push_str(&format!(" {name}=-")),
+                }
+            }
+            lines.push(line);
+        }
+
+        let mut all: Vec<(&str, &Row)> = self
+            .sites
+            .iter()
+            .flat_map(|(name, s)| s.rows.values().map(move |r| (name.as_str(), r)))
+            .collect();
+        all.sort_by_key(|(_, r)| std::cmp::Reverse(r.offset_mean_ms.unsigned_abs()));
+        for (rank, (site, r)) in all.iter().take(worst).enumerate() {
+            lines.push(format!(
+                "Worst: by=offset rank={} site={site} sender={} {} {} {}",
+                rank + 1,
+                r.sender,
+                units::pair("offset_mean_ms", units::millis(r.offset_mean_ms)),
+                units::pair("offset_min_ms", units::millis(r.offset_min_ms)),
+                units::pair("offset_max_ms", units::millis(r.offset_max_ms)),
+            ));
+        }
+        all.retain(|(_, r)| r.lost > 0);
+        all.sort_by(|a, b| b.1.loss_pct().total_cmp(&a.1.loss_pct()));
+        for (rank, (site, r)) in all.iter().take(worst).enumerate() {
+            lines.push(format!(
+                "Worst: by=loss rank={} site={site} sender={} received={} lost={} loss_pct={:.2}",
+                rank + 1,
+                r.sender,
+                r.received,
+                r.lost,
+                r.loss_pct(),
+            ));
+        }
+        lines
+    }
+}
+
+/// Settings for aggregate mode.
+pub struct AggregateConfig {
+    pub addr: Option<IpAddr>,
+    pub port: u16,
+    /// How often to print the fleet view.
+    pub print_interval: Duration,
+    /// Rows in each worst-offender list.
+    pub worst: usize,
+}
+
+/// Listens for reports and prints the fleet view forever.
+pub fn run(cfg: &AggregateConfig) -> ExitCode {
+    let ip = cfg.addr.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    let bind_addr = SocketAddr::new(ip, cfg.port);
+    let sock = match UdpSocket::bind(bind_addr) {
+        Ok(s) => s,
+        Err(e) => {
+            return exit::fail(Failure::io(
+                &e,
+                format!("Failed to bind UDP socket on {bind_addr}: {e}"),
+            ))
+        }
+    };
+    if let Err(e) = sock.set_read_timeout(Some(Duration::from_millis(500))) {
+        return exit::fail(format!("Failed to set receive timeout: {e}"));
+    }
+    log::info!(
+        "Aggregating reports on {}",
+        sock.local_addr().unwrap_or(bind_addr)
+    );
+
+    let mut fleet = Fleet::default();
+    let mut next_print = Instant::now() + cfg.print_interval;
+    let mut buf = [0u8; 2048];
+    loop {
+        let now = Instant::now();
+        if now >= next_print {
+            for line in fleet.lines(now, cfg.worst) {
+                println!("{line}");
+            }
+            next_print += cfg.print_interval;
+        }
+        let (len, src) = match sock.recv_from(&mut buf) {
+            Ok(r) => r,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            Err(e) => {
+                log::warn!("recv_from failed: {e}");
+                continue;
+            }
+        };
+        match decode(&buf[..len]) {
+            Ok((site, rows)) => {
+                log::debug!("Report from {src}: site={site} senders={}", rows.len());
+                fleet.observe(site, src, rows, Instant::now());
+            }
+            Err(e) => log::warn!("Ignoring datagram from {src}: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Rng;
+
+    fn row(sender: &str, received: u64, lost: u64, mean: i64) -> Row {
+        Row {
+            sender: sender.to_string(),
+            received,
+            lost,
+            offset_min_ms: mean - 2,
+            offset_mean_ms: mean,
+            offset_max_ms: mean + 3,
+            last_seen_ms: 100,
+        }
+    }
+
+    #[test]
+    fn reports_round_trip_and_build_the_fleet_view() {
+        let a = vec![
+            row("10.0.0.5:4000/-", 99, 1, 4),
+            row("10.0.0.6:4000/3", 50, 0, -1),
+        ];
+        let datagrams = encode("lab-a", &a);
+        assert_eq!(datagrams.len(), 1);
+        assert_eq!(decode(&datagrams[0]), Ok(("lab-a".to_string(), a.clone())));
+
+        // Too many rows for one datagram: each carries the header
+        let many: Vec<_> = (0..40)
+            .map(|i| row(&format!("10.0.1.{i}:4000/-"), 1, 0, 0))
+            .collect();
+        let datagrams = encode("lab-a", &many);
+        assert!(datagrams.len() > 1);
+        assert!(datagrams.iter().all(|d| d.len() <= MAX_DATAGRAM));
+        let decoded: Vec<Row> = datagrams
+            .iter()
+            .flat_map(|d| decode(d).unwrap().1)
+            .collect();
+        assert_eq!(decoded, many);
+
+        assert!(decode(b"udp_bcast_ts-report 2 site=x\n").is_err());
+        assert!(decode(b"hello").is_err());
+        assert!(parse_site("lab a").is_err());
+
+        let t0 = Instant::now();
+        let from: SocketAddr = "192.0.2.1:5000".parse().unwrap();
+        let mut fleet = Fleet::default();
+        fleet.observe("lab-a".to_string(), from, a, t0);
+        fleet.observe(
+            "lab-b".to_string(),
+            from,
+            vec![row("10.0.0.5:4000/-", 90, 10, -30)],
+            t0 + Duration::from_secs(1),
+        );
+        assert_eq!(
+            fleet.lines(t0 + Duration::from_secs(2), 1),
+            [
+                "Fleet: sites=2 senders=2",
+                "Site: site=lab-a from=192.0.2.1:5000 last_report=2.00s senders=2 received=149 lost=1 loss_pct=0.67 worst_offset=7ms",
+                "Site: site=lab-b from=192.0.2.1:5000 last_report=1.00s senders=1 received=90 lost=10 loss_pct=10.00 worst_offset=-32ms",
+                "Loss: sender=10.0.0.5:4000/- lab-a=1.00 lab-b=10.00",
+                "Loss: sender=10.0.0.6:4000/3 lab-a=0.00 lab-b=-",
+                "Worst: by=offset rank=1 site=lab-b sender=10.0.0.5:4000/- offset_mean=-30ms offset_min=-32ms offset_max=-27ms",
+                "Worst: by=loss rank=1 site=lab-b sender=10.0.0.5:4000/- received=90 lost=10 loss_pct=10.00",
+            ]
+        );
+    }
+
+    #[test]
+    fn extreme_counters_saturate() {
+        let (site, rows) = decode(
+            format!(
+                "{MAGIC} {VERSION} site=x\nsender=a received={} lost=1\nsender=b received=1 lost={}\n",
+                u64::MAX,
+                u64::MAX
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        assert!(rows[0].loss_pct() < 1e-15);
+        assert_eq!(rows[1].loss_pct(), 100.0);
+        let t0 = Instant::now();
+        let mut fleet = Fleet::default();
+        fleet.observe(site, "192.0.2.1:5000".parse().unwrap(), rows, t0);
+        let lines = fleet.lines(t0, 1);
+        assert!(lines[1].contains(&format!("received={} lost={}", u64::MAX, u64::MAX)));
+    }
+
+    #[test]
+    fn random_reports_with_extreme_counters_round_trip() {
+        let mut rng = Rng::new(0xf1ee7);
+        let mut r = || rng.next_u64();
+        let t0 = Instant::now();
+        let from: SocketAddr = "192.0.2.1:5000".parse().unwrap();
+        let mut fleet = Fleet::default();
+        for i in 0..200 {
+            let rows: Vec<Row> = (0..r() % 8)
+                .map(|j| {
+                    let counter = [0, 1, u64::MAX - 1, u64::MAX, r()][(r() % 5) as usize];
+                    let offset = [0, i64::MIN, i64::MAX, r() as i64][(r() % 4) as usize];
+                    Row {
+                        sender: format!("10.0.0.{j}:4000/-"),
+                        received: counter,
+                        lost: [0, u64::MAX, r()][(r() % 3) as usize],
+                        offset_min_ms: offset,
+                        offset_mean_ms: r() as i64,
+                        offset_max_ms: offset,
+                        last_seen_ms: r(),
+                    }
+                })
+                .collect();
+            let site = format!("site-{}", i % 5);
+            let decoded: Vec<Row> = encode(&site, &rows)
+                .iter()
+                .flat_map(|d| decode(d).unwrap().1)
+                .collect();
+            assert_eq!(decoded, rows);
+            fleet.observe(site, from, decoded, t0);
+            assert!(fleet.lines(t0, 3).iter().all(|l| !l.contains("NaN")));
+        }
+    }
+}
@@ -0,0 +1,128 @@
+//! Offset alarms for receiver mode: a cheap clock-sanity monitor.
+//!
+//! Each sender's offset is tracked separately, so one misbehaving sender
+//! can't be masked by a healthy one interleaving with it. An alarm fires
+//! once a sender's offset has exceeded the threshold for the configured
+//! number of consecutive packets, and re-arms once it is back in bounds.
+
+use std::collections::HashMap;
+use std::process::Command;
+use std::thread;
+
+use crate::log;
+
+pub const DEFAULT_CONSECUTIVE: u32 = 3;
+
+/// What to do when an alarm fires.
+#[derive(Clone, Debug)]
+pub enum Action {
+    /// Stop receiving and exit with [`EXIT_CODE_ALERT`](crate::EXIT_CODE_ALERT).
+    Exit,
+    /// Run a shell command and keep receiving.
+    Command(String),
+}
+
+/// Settings for offset alarms.
+#[derive(Clone, Debug)]
+pub struct AlertConfig {
+    /// Largest tolerated offset either way, in milliseconds.
+    pub offset_ms: u64,
+    /// Packets in a row that must exceed `offset_ms` to fire.
+    pub consecutive: u32,
+    pub action: Action,
+}
+
+/// Per-sender runs of out-of-bounds offsets.
+pub struct Alarm {
+    offset_ms: u64,
+    consecutive: u32,
+    streaks: HashMap<String, u32>,
+}
+
+impl Alarm {
+    pub fn new(cfg: &AlertConfig) -> Alarm {
+        Alarm {
+            offset_ms: cfg.offset_ms,
+            consecutive: cfg.consecutive,
+            streaks: HashMap::new(),
+        }
+    }
+
+    /// Records one offset from `sender`, returning true if it completes a
+    /// run of `consecutive` out-of-bounds packets.
+    pub fn observe(&mut self, sender: &str, offset_ms: i64) -> bool {
+        if offset_ms.unsigned_abs() <= self.offset_ms {
+            self.streaks.remove(sender);
+            return false;
+        }
+        let streak = self.streaks.entry(sender.to_string()).or_insert(0);
+        *streak = streak.saturating_add(1);
+        *streak == self.consecutive
+    }
+}
+
+/// Runs an `--on-alert` hook with `sh -c` in the background, so a slow
+/// hook never stalls the receive loop. What fired, the sender and, for
+/// offset alerts, the offset are passed in `UDP_BCAST_TS_ALERT_KIND`,
+/// `UDP_BCAST_TS_ALERT_SENDER` and `UDP_BCAST_TS_ALERT_OFFSET_MS`.
+pub fn run_hook(cmd: &str, kind: &str, sender: &str, offset_ms: Option<i64>) {
+    let mut command = Command::new("sh");
+    command
+        .arg("-c")
+        .arg(cmd)
+        .env("UDP_BCAST_TS_ALERT_KIND", kind)
+        .env("UDP_BCAST_TS_ALERT_SENDER", sender);
+    if let Some(offset_ms) = offset_ms {
+        command.env("UDP_BCAST_TS_ALERT_OFFSET_MS", offset_ms.to_string());
+    }
+    let child = command.spawn();
+    match child {
+        Ok(mut child) => {
+            thread::spawn(move || match child.wait() {
+                Ok(status) if !status.success() => {
+                    log::warn!("Alert command exited with {status}")
+                }
+                Ok(_) => {}
+                Err(e) => log::warn!("Failed to wait for alert command: {e}"),
+            });
+        }
+        Err(e) => log::warn!("Failed to run alert command: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alarm(offset_ms: u64, consecutive: u32) -> Alarm {
+        Alarm::new(&AlertConfig {
+            offset_ms,
+            consecutive,
+            action: Action::Exit,
+        })
+    }
+
+    #[test]
+    fn fires_once_per_run_of_consecutive_packets() {
+        let mut a = alarm(50, 3);
+        assert!(!a.observe("a", 60));
+        assert!(!a.observe("a", -70));
+        assert!(a.observe("a", 80));
+        // Still out of bounds, but already reported
+        assert!(!a.observe("a", 90));
+        // Back in bounds re-arms
+        assert!(!a.observe("a", 50));
+        assert!(!a.observe("a", 60));
+        assert!(!a.observe("a", 60));
+        assert!(a.observe("a", 60));
+    }
+
+    #[test]
+    fn senders_are_tracked_separately() {
+        let mut a = alarm(10, 2);
+        assert!(!a.observe("a", 20));
+        assert!(!a.observe("b", 0));
+        assert!(a.observe("a", 20));
+        assert!(!a.observe("b", 20));
+    }
+}
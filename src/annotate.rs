@@ -0,0 +1,74 @@
+//! Auxiliary values (e.g. CPU temperature) attached to each measurement,
+//! for correlating drift with environmental conditions.
+//!
+//! The value is refreshed in a background thread every interval so that a
+//! slow command never stalls the receive loop; each measurement carries
+//! the latest value seen.
+
+use std::fs;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::log;
+
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Where annotation values come from.
+#[derive(Clone, Debug)]
+pub enum Source {
+    /// A shell command whose trimmed stdout is the value.
+    Command(String),
+    /// A file whose trimmed contents are the value.
+    File(String),
+}
+
+impl Source {
+    fn read(&self) -> Result<String, String> {
+        let raw = match self {
+            Source::Command(cmd) => {
+                let out = Command::new("sh")
+                    .arg("-c")
+                    .arg(cmd)
+                    .output()
+                    .map_err(|e| format!("Failed to run annotate command: {e}"))?;
+                if !out.status.success() {
+                    return Err(format!("Annotate command exited with {}", out.status));
+                }
+                String::from_utf8_lossy(&out.stdout).into_owned()
+            }
+            Source::File(path) => fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read annotate file {path}: {e}"))?,
+        };
+        // Keep the value on one line so it fits a log line or CSV cell
+        Ok(raw.split_whitespace().collect::<Vec<_>>().join(" "))
+    }
+}
+
+/// The latest annotation value, shared with the refresh thread.
+#[derive(Clone)]
+pub struct Annotator {
+    value: Arc<Mutex<String>>,
+}
+
+impl Annotator {
+    /// Reads the first value synchronously, then starts refreshing it.
+    pub fn start(source: Source, interval: Duration) -> Result<Annotator, String> {
+        let value = Arc::new(Mutex::new(source.read()?));
+        let shared = Arc::clone(&value);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            match source.read() {
+                Ok(v) => *shared.lock().unwrap() = v,
+                // Keep the last good value; a transient failure shouldn't blank it
+                Err(e) => log::warn!("{e}"),
+            }
+        });
+        Ok(Annotator { value })
+    }
+
+    pub fn current(&self) -> String {
+        self.value.lock().unwrap().clone()
+    }
+}
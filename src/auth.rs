@@ -0,0 +1,284 @@
+//! HMAC-SHA256 message authentication for framed payloads.
+//!
+//! SHA-256 is implemented here (FIPS 180-4) rather than pulling in a
+//! crypto dependency; it is only used for HMAC over short datagrams, so
+//! throughput is not a concern.
+//!
+//! A key can be rotated without downtime by giving a directory of keys
+//! instead of a key file, each file named by its key id, e.g. `1` and `2`.
+//! Senders authenticate with the highest id and name it in the payload;
+//! receivers accept any key in the directory. Rotating means adding the
+//! new key on every receiver, then on the senders, then removing the old
+//! one.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// Length of the truncated MAC carried in the payload.
+pub const MAC_LEN: usize = 16;
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+const H0: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+fn compress(state: &mut [u32; 8], block: &[u8]) {
+    let mut w = [0u32; 64];
+    for (i, word) in block.chunks_exact(4).enumerate() {
+        w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+    }
+    for i in 16..64 {
+        let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+        let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+        w[i] = w[i - 16]
+            .wrapping_add(s0)
+            .wrapping_add(w[i - 7])
+            .wrapping_add(s1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+    for i in 0..64 {
+        let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+        let ch = (e & f) ^ (!e & g);
+        let t1 = h
+            .wrapping_add(s1)
+            .wrapping_add(ch)
+            .wrapping_add(K[i])
+            .wrapping_add(w[i]);
+        let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+        let maj = (a & b) ^ (a & c) ^ (b & c);
+        let t2 = s0.wrapping_add(maj);
+        h = g;
+        g = f;
+        f = e;
+        e = d.wrapping_add(t1);
+        d = c;
+        c = b;
+        b = a;
+        a = t1.wrapping_add(t2);
+    }
+
+    for (s, v) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+        *s = s.wrapping_add(v);
+    }
+}
+
+/// Computes the SHA-256 digest of the concatenation of `parts`.
+pub fn sha256(parts: &[&[u8]]) -> [u8; 32] {
+    let mut state = H0;
+    let mut block = [0u8; 64];
+    let mut filled = 0;
+    let mut total: u64 = 0;
+
+    for part in parts {
+        total += part.len() as u64;
+        for &byte in *part {
+            block[filled] = byte;
+            filled += 1;
+            if filled == 64 {
+                compress(&mut state, &block);
+                filled = 0;
+            }
+        }
+    }
+
+    block[filled] = 0x80;
+    filled += 1;
+    if filled > 56 {
+        block[filled..].fill(0);
+        compress(&mut state, &block);
+        filled = 0;
+    }
+    block[filled..56].fill(0);
+    block[56..].copy_from_slice(&(total * 8).to_be_bytes());
+    compress(&mut state, &block);
+
+    let mut out = [0u8; 32];
+    for (chunk, s) in out.chunks_exact_mut(4).zip(state) {
+        chunk.copy_from_slice(&s.to_be_bytes());
+    }
+    out
+}
+
+/// A shared HMAC key.
+#[derive(Clone)]
+pub struct Key {
+    /// The key padded (or hashed) to the SHA-256 block size.
+    block: [u8; 64],
+    /// Id of the key in its key directory, sent with the MAC.
+    id: Option<u16>,
+}
+
+impl Key {
+    pub fn new(secret: &[u8]) -> Key {
+        let mut block = [0u8; 64];
+        if secret.len() > 64 {
+            block[..32].copy_from_slice(&sha256(&[secret]));
+        } else {
+            block[..secret.len()].copy_from_slice(secret);
+        }
+        Key { block, id: None }
+    }
+
+    pub fn id(&self) -> Option<u16> {
+        self.id
+    }
+
+    /// Reads a key file; trailing whitespace is ignored so keys can be
+    /// written with `echo`.
+    pub fn load(path: &str) -> Result<Key, String> {
+        let data = fs::read(path).map_err(|e| format!("Failed to read key file {path}: {e}"))?;
+        let end = data
+            .iter()
+            .rposition(|b| !b.is_ascii_whitespace())
+            .map_or(0, |i| i + 1);
+        if end == 0 {
+            return Err(format!("Key file {path} is empty"));
+        }
+        Ok(Key::new(&data[..end]))
+    }
+
+    /// Computes HMAC-SHA256 over `data`, truncated to [`MAC_LEN`] bytes.
+    pub fn mac(&self, data: &[u8]) -> [u8; MAC_LEN] {
+        let mut ipad = [0x36u8; 64];
+        let mut opad = [0x5cu8; 64];
+        for i in 0..64 {
+            ipad[i] ^= self.block[i];
+            opad[i] ^= self.block[i];
+        }
+        let inner = sha256(&[&ipad, data]);
+        let outer = sha256(&[&opad, &inner]);
+        let mut out = [0u8; MAC_LEN];
+        out.copy_from_slice(&outer[..MAC_LEN]);
+        out
+    }
+
+    /// Checks `mac` against `data` in constant time.
+    pub fn verify(&self, data: &[u8], mac: &[u8]) -> bool {
+        let expected = self.mac(data);
+        mac.len() == MAC_LEN
+            && expected
+                .iter()
+                .zip(mac)
+                .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+                == 0
+    }
+}
+
+/// The keys of a `--group` key file or key directory.
+#[derive(Clone)]
+pub struct KeyRing {
+    /// By id; a key file's one key has none.
+    keys: BTreeMap<Option<u16>, Key>,
+}
+
+impl KeyRing {
+    /// Loads a key file, or every key in a directory of files named by key
+    /// id, dotfiles aside.
+    pub fn load(path: &str) -> Result<KeyRing, String> {
+        if !Path::new(path).is_dir() {
+            let key = Key::load(path)?;
+            return Ok(KeyRing {
+                keys: BTreeMap::from([(None, key)]),
+            });
+        }
+        let entries =
+            fs::read_dir(path).map_err(|e| format!("Failed to read key directory {path}: {e}"))?;
+        let mut keys = BTreeMap::new();
+        for entry in entries {
+            let file = entry
+                .map_err(|e| format!("Failed to read key directory {path}: {e}"))?
+                .path();
+            let stem = match file.file_stem().and_then(|s| s.to_str()) {
+                Some(s) if !s.starts_with('.') && file.is_file() => s,
+                _ => continue,
+            };
+            let id = stem.parse().map_err(|_| {
+                format!("Key file {stem} in {path} must be named by its key id, 0 to 65535")
+            })?;
+            let mut key = Key::load(&file.to_string_lossy())?;
+            key.id = Some(id);
+            if keys.insert(Some(id), key).is_some() {
+                return Err(format!("Key id {id} appears twice in {path}"));
+            }
+        }
+        if keys.is_empty() {
+            return Err(format!("No keys in {path}"));
+        }
+        Ok(KeyRing { keys })
+    }
+
+    /// The key to authenticate with: the one with the highest id.
+    pub fn active(&self) -> &Key {
+        self.keys
+            .values()
+            .next_back()
+            .expect("key rings are never empty")
+    }
+
+    /// Checks `mac` against `data`, with the key `key_id` names if there is
+    /// one and otherwise each key in turn, returning the key that accepted.
+    pub fn verify(&self, key_id: Option<u16>, data: &[u8], mac: &[u8]) -> Option<&Key> {
+        match key_id {
+            // A key file's key is taken to be whichever the sender named
+            Some(id) => self
+                .keys
+                .get(&Some(id))
+                .or_else(|| self.keys.get(&None))
+                .filter(|k| k.verify(data, mac)),
+            None => self.keys.values().find(|k| k.verify(data, mac)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::{self, Format, Packet};
+
+    #[test]
+    fn key_directories_sign_with_the_highest_id_and_accept_any() {
+        let dir = std::env::temp_dir().join(format!("udp_bcast_ts-keys-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("1"), "old\n").unwrap();
+        fs::write(dir.join("2"), "new\n").unwrap();
+        let ring = KeyRing::load(&dir.to_string_lossy()).unwrap();
+        fs::write(dir.join("current"), "new\n").unwrap();
+        assert!(KeyRing::load(&dir.to_string_lossy()).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(ring.active().id(), Some(2));
+
+        let packet = Packet {
+            group: Some(7),
+            ..Packet::new(Some(1), 1_000)
+        };
+        let mut out = Vec::new();
+        let verified = |key: &Key, ring: &KeyRing, out: &mut Vec<u8>| {
+            payload::encode(Format::V2, &packet, Some(key), out);
+            let decoded = payload::decode(out).unwrap();
+            decoded.verify_any(ring).map(Key::id)
+        };
+        // A sender still on key 1, or on a key file, is accepted too
+        let mut old = Key::new(b"old");
+        old.id = Some(1);
+        assert_eq!(verified(&old, &ring, &mut out), Some(Some(1)));
+        assert_eq!(verified(&Key::new(b"new"), &ring, &mut out), Some(Some(2)));
+        assert_eq!(verified(&Key::new(b"other"), &ring, &mut out), None);
+        // A receiver on a key file takes the key id as a hint
+        let file = KeyRing {
+            keys: BTreeMap::from([(None, Key::new(b"new"))]),
+        };
+        assert_eq!(verified(ring.active(), &file, &mut out), Some(None));
+    }
+}
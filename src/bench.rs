@@ -0,0 +1,526 @@
+//! End-to-end latency benchmark between two hosts.
+//!
+//! `bench reflect` runs on the far host and answers every probe it receives
+//! by sending it straight back, stamped with when it arrived and left.
+//! `bench run` sends probes at a fixed rate for a while, collects the
+//! replies, and reports loss, reordering and the latency distribution.
+//!
+//! The round trip excludes the time a probe spent inside the reflector, so
+//! it needs no clock agreement between the hosts. The one-way figures
+//! subtract one host's clock from the other's, so they are only meaningful
+//! when both are synchronized (e.g. with PTP); otherwise they carry the
+//! clock offset. Loss is split by direction using the reflector's count of
+//! probes received, and forward reordering using the highest sequence
+//! number it had seen when each probe arrived.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::process::ExitCode;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::exit::{self, Failure};
+use crate::log;
+use crate::rate::TokenBucket;
+use crate::resolve::Target;
+use crate::rng::Rng;
+use crate::send::bind_for;
+use crate::sys;
+use crate::transmit;
+use crate::units;
+
+const MAGIC: &[u8; 4] = b"UBTB";
+const KIND_PROBE: u8 = 1;
+const KIND_REPLY: u8 = 2;
+
+/// Smallest probe: the header below, with no padding.
+pub const MIN_SIZE: usize = 48;
+/// Largest probe that fits an Ethernet frame unfragmented.
+pub const MAX_SIZE: usize = 1472;
+
+/// How long `bench run` waits for stragglers after the last probe.
+const LINGER: Duration = Duration::from_secs(1);
+
+/// Settings for `bench reflect`.
+pub struct ReflectConfig {
+    pub addr: Option<IpAddr>,
+    pub port: u16,
+}
+
+/// Settings for `bench run`.
+pub struct RunConfig {
+    pub target: Target,
+    pub port: u16,
+    /// Probes per second.
+    pub rate: f64,
+    pub duration: Duration,
+    /// Probe size, in bytes.
+    pub size: usize,
+    /// File to write the report to as JSON.
+    pub json: Option<String>,
+}
+
+/// One probe or reply. Times are nanoseconds since the Unix epoch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Probe {
+    kind: u8,
+    /// Random per `bench run`, so a reflector can tell runs apart.
+    session: u32,
+    seq: u32,
+    /// When the probe was sent.
+    t1: u64,
+    /// When the reflector received it, and sent it back.
+    t2: u64,
+    t3: u64,
+    /// Probes of this session the reflector had received, this one included.
+    received: u32,
+    /// Highest sequence number the reflector had seen before this probe.
+    highest: u32,
+}
+
+impl Probe {
+    /// Writes the probe into `buf`, padded with zeros to its length.
+    fn encode(&self, buf: &mut [u8]) {
+        buf.fill(0);
+        buf[0..4].copy_from_slice(MAGIC);
+        buf[4] = self.kind;
+        buf[8..12].copy_from_slice(&self.session.to_be_bytes());
+        buf[12..16].copy_from_slice(&self.seq.to_be_bytes());
+        buf[16..24].copy_from_slice(&self.t1.to_be_bytes());
+        buf[24..32].copy_from_slice(&self.t2.to_be_bytes());
+        buf[32..40].copy_from_slice(&self.t3.to_be_bytes());
+        buf[40..44].copy_from_slice(&self.received.to_be_bytes());
+        buf[44..48].copy_from_slice(&self.highest.to_be_bytes());
+    }
+
+    fn decode(buf: &[u8]) -> Option<Probe> {
+        if buf.len() < MIN_SIZE || &buf[0..4] != MAGIC {
+            return None;
+        }
+        let u32_at = |at: usize| u32::from_be_bytes(buf[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_be_bytes(buf[at..at + 8].try_into().unwrap());
+        Some(Probe {
+            kind: buf[4],
+            session: u32_at(8),
+            seq: u32_at(12),
+            t1: u64_at(16),
+            t2: u64_at(24),
+            t3: u64_at(32),
+            received: u32_at(40),
+            highest: u32_at(44),
+        })
+    }
+}
+
+fn epoch_ns(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64)
+}
+
+/// Receives one datagram, returning its length, source and arrival time in
+/// ns, from the kernel's timestamp where there is one.
+fn receive(sock: &UdpSocket, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr, u64)> {
+    let meta = sys::recv(sock, buf)?;
+    let at = meta.kernel_time.unwrap_or_else(SystemTime::now);
+    Ok((meta.len, meta.src, epoch_ns(at)))
+}
+
+/// A reflector's state for the session a source last ran.
+struct Session {
+    id: u32,
+    received: u32,
+    highest: Option<u32>,
+}
+
+/// Runs the reflector until a fatal error occurs.
+pub fn reflect(cfg: &ReflectConfig) -> ExitCode {
+    let ip = cfg.addr.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    let bind_addr = SocketAddr::new(ip, cfg.port);
+    let sock = match UdpSocket::bind(bind_addr) {
+        Ok(s) => s,
+        Err(e) => {
+            return exit::fail(Failure::io(
+                &e,
+                format!("Failed to bind UDP socket on {bind_addr}: {e}"),
+            ))
+        }
+    };
+    if let Err(e) = sys::enable_rx_timestamps(&sock) {
+        log::warn!("Kernel receive timestamps unavailable: {e}");
+    }
+    log::info!("Reflecting bench probes on {bind_addr}");
+
+    let mut sessions: HashMap<SocketAddr, Session> = HashMap::new();
+    let mut buf = [0u8; MAX_SIZE];
+    loop {
+        let (len, src, t2) = match receive(&sock, &mut buf) {
+            Ok(r) => r,
+            Err(e) => {
+                log::warn!("recv_from failed: {e}");
+                continue;
+            }
+        };
+        let Some(mut probe) = Probe::decode(&buf[..len]).filter(|p| p.kind == KIND_PROBE) else {
+            continue;
+        };
+        let session = sessions.entry(src).or_insert(Session {
+            id: probe.session,
+            received: 0,
+            highest: None,
+        });
+        if session.received == 0 || session.id != probe.session {
+            log::info!("Reflecting for {src} session={:08x}", probe.session);
+            *session = Session {
+                id: probe.session,
+                received: 0,
+                highest: None,
+            };
+        }
+        session.received += 1;
+        probe.kind = KIND_REPLY;
+        probe.t2 = t2;
+        probe.received = session.received;
+        probe.highest = session.highest.unwrap_or(probe.seq);
+        session.highest = Some(session.highest.map_or(probe.seq, |h| h.max(probe.seq)));
+        probe.t3 = epoch_ns(SystemTime::now());
+        probe.encode(&mut buf[..len]);
+        if let Err(e) = transmit::send_to(&sock, &buf[..len], src) {
+            log::warn!("send_to({src}) failed: {e}");
+        }
+    }
+}
+
+/// Distribution of one latency, in µs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Summary {
+    pub min: i64,
+    pub p50: i64,
+    pub p90: i64,
+    pub p99: i64,
+    pub max: i64,
+    pub mean: i64,
+}
+
+impl Summary {
+    /// Summarizes `samples`; `None` if there are none.
+    fn of(samples: &mut [i64]) -> Option<Summary> {
+        if samples.is_empty() {
+            return None;
+        }
+        samples.sort_unstable();
+        // Nearest rank
+        let at = |p: usize| samples[(samples.len() * p).div_ceil(100).max(1) - 1];
+        Some(Summary {
+            min: samples[0],
+            p50: at(50),
+            p90: at(90),
+            p99: at(99),
+            max: samples[samples.len() - 1],
+            mean: samples.iter().sum::<i64>() / samples.len() as i64,
+        })
+    }
+
+    fn line(&self) -> String {
+        format!(
+            "min={} p50={} p90={} p99={} max={} mean={}",
+            units::micros_i64(self.min),
+            units::micros_i64(self.p50),
+            units::micros_i64(self.p90),
+            units::micros_i64(self.p99),
+            units::micros_i64(self.max),
+            units::micros_i64(self.mean)
+        )
+    }
+
+    fn json(&self) -> String {
+        format!(
+            "{{\"min\":{},\"p50\":{},\"p90\":{},\"p99\":{},\"max\":{},\"mean\":{}}}",
+            self.min, self.p50, self.p90, self.p99, self.max, self.mean
+        )
+    }
+}
+
+/// What `bench run` measured.
+#[derive(Debug)]
+pub struct Report {
+    pub target: SocketAddr,
+    pub duration: Duration,
+    pub size: usize,
+    pub sent: u32,
+    /// Distinct probes answered.
+    pub received: u32,
+    pub duplicates: u32,
+    /// Most probes the reflector said it had received.
+    pub reflected: u32,
+    /// Probes that reached the reflector after a later one.
+    pub reordered_fwd: u32,
+    /// Replies that arrived after the reply to a later probe.
+    pub reordered: u32,
+    pub rtt: Option<Summary>,
+    pub fwd: Option<Summary>,
+    pub back: Option<Summary>,
+}
+
+impl Report {
+    pub fn lost(&self) -> u32 {
+        self.sent.saturating_sub(self.received)
+    }
+
+    pub fn loss_pct(&self) -> f64 {
+        match self.sent {
+            0 => 0.0,
+            n => f64::from(self.lost()) * 100.0 / f64::from(n),
+        }
+    }
+
+    /// Lost on the way out, and on the way back.
+    pub fn lost_by_direction(&self) -> (u32, u32) {
+        (
+            self.sent.saturating_sub(self.reflected),
+            self.reflected.saturating_sub(self.received),
+        )
+    }
+
+    pub fn print(&self) {
+        let (lost_fwd, lost_back) = self.lost_by_direction();
+        println!(
+            "Bench: target={} duration={} size={} sent={} received={} lost={} loss_pct={:.2} \
+             lost_fwd={lost_fwd} lost_back={lost_back} duplicates={} reordered_fwd={} reordered={}",
+            self.target,
+            units::millis(self.duration.as_millis() as i64),
+            units::bytes(self.size as u64),
+            self.sent,
+            self.received,
+            self.lost(),
+            self.loss_pct(),
+            self.duplicates,
+            self.reordered_fwd,
+            self.reordered
+        );
+        for (name, summary) in [
+            ("rtt", self.rtt),
+            ("one_way_fwd", self.fwd),
+            ("one_way_back", self.back),
+        ] {
+            if let Some(s) = summary {
+                println!("Bench: {name} {}", s.line());
+            }
+        }
+    }
+
+    /// The report as one JSON object, with latencies in µs.
+    pub fn json(&self) -> String {
+        let (lost_fwd, lost_back) = self.lost_by_direction();
+        let summary = |s: Option<Summary>| s.map_or_else(|| "null".to_string(), |s| s.json());
+        format!(
+            "{{\"target\":\"{}\",\"duration_ms\":{},\"size\":{},\"sent\":{},\"received\":{},\
+             \"lost\":{},\"loss_pct\":{:.3},\"lost_fwd\":{lost_fwd},\"lost_back\":{lost_back},\
+             \"duplicates\":{},\"reordered_fwd\":{},\"reordered\":{},\
+             \"rtt_us\":{},\"one_way_fwd_us\":{},\"one_way_back_us\":{}}}\n",
+            self.target,
+            self.duration.as_millis(),
+            self.size,
+            self.sent,
+            self.received,
+            self.lost(),
+            self.loss_pct(),
+            self.duplicates,
+            self.reordered_fwd,
+            self.reordered,
+            summary(self.rtt),
+            summary(self.fwd),
+            summary(self.back)
+        )
+    }
+}
+
+/// Sends probes at the configured rate for the configured time, returning
+/// how many went out.
+fn send_probes(sock: UdpSocket, dest: SocketAddr, cfg: &RunConfig, session: u32) -> u32 {
+    let mut bucket = TokenBucket::new(cfg.rate);
+    let mut buf = vec![0u8; cfg.size];
+    let end = Instant::now() + cfg.duration;
+    let mut seq = 0u32;
+    while Instant::now() < end {
+        for _ in 0..bucket.take(Instant::now()) {
+            let probe = Probe {
+                kind: KIND_PROBE,
+                session,
+                seq,
+                t1: epoch_ns(SystemTime::now()),
+                t2: 0,
+                t3: 0,
+                received: 0,
+                highest: 0,
+            };
+            probe.encode(&mut buf);
+            match transmit::send_to(&sock, &buf, dest) {
+                Ok(_) => seq += 1,
+                Err(e) => log::warn!("send_to({dest}) failed: {e}"),
+            }
+        }
+        thread::sleep(bucket.wait());
+    }
+    seq
+}
+
+/// Runs one benchmark and returns what it measured.
+pub fn measure(cfg: &RunConfig) -> Result<Report, Failure> {
+    let dest = cfg.target.resolve(cfg.port, None)?;
+    let sock = bind_for(dest)?;
+    if let Err(e) = sys::enable_rx_timestamps(&sock) {
+        log::debug!("Kernel receive timestamps unavailable: {e}");
+    }
+    sock.set_read_timeout(Some(Duration::from_millis(100)))
+        .map_err(|e| format!("Failed to set receive timeout: {e}"))?;
+    let tx = sock
+        .try_clone()
+        .map_err(|e| format!("Failed to clone socket: {e}"))?;
+    let session = Rng::from_entropy().next_u64() as u32;
+    log::info!(
+        "Benchmarking {dest} for {} at {}, session={session:08x}",
+        units::millis(cfg.duration.as_millis() as i64),
+        units::pps(cfg.rate)
+    );
+
+    let report = thread::scope(|scope| {
+        let mut sender = Some(scope.spawn(|| send_probes(tx, dest, cfg, session)));
+        let mut report = Report {
+            target: dest,
+            duration: cfg.duration,
+            size: cfg.size,
+            sent: 0,
+            received: 0,
+            duplicates: 0,
+            reflected: 0,
+            reordered_fwd: 0,
+            reordered: 0,
+            rtt: None,
+            fwd: None,
+            back: None,
+        };
+        let (mut rtt, mut fwd, mut back) = (Vec::new(), Vec::new(), Vec::new());
+        let mut seen = HashSet::new();
+        let mut highest: Option<u32> = None;
+        let mut buf = [0u8; MAX_SIZE];
+        // When the last probe went out
+        let mut done: Option<Instant> = None;
+        loop {
+            if let Some(h) = sender.take_if(|h| h.is_finished()) {
+                report.sent = h.join().unwrap_or(0);
+                done = Some(Instant::now());
+            }
+            if done.is_some_and(|d| report.received >= report.sent || d.elapsed() >= LINGER) {
+                break;
+            }
+            let (len, _, t4) = match receive(&sock, &mut buf) {
+                Ok(r) => r,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    continue;
+                }
+                Err(e) => {
+                    log::warn!("recv_from failed: {e}");
+                    continue;
+                }
+            };
+            let Some(p) =
+                Probe::decode(&buf[..len]).filter(|p| p.kind == KIND_REPLY && p.session == session)
+            else {
+                continue;
+            };
+            if !seen.insert(p.seq) {
+                report.duplicates += 1;
+                continue;
+            }
+            report.received += 1;
+            report.reflected = report.reflected.max(p.received);
+            if p.highest > p.seq {
+                report.reordered_fwd += 1;
+            }
+            if highest.is_some_and(|h| h > p.seq) {
+                report.reordered += 1;
+            }
+            highest = Some(highest.map_or(p.seq, |h| h.max(p.seq)));
+            let us = |a: u64, b: u64| (a as i64 - b as i64) / 1000;
+            rtt.push(us(t4, p.t1) - us(p.t3, p.t2));
+            fwd.push(us(p.t2, p.t1));
+            back.push(us(t4, p.t3));
+        }
+        report.rtt = Summary::of(&mut rtt);
+        report.fwd = Summary::of(&mut fwd);
+        report.back = Summary::of(&mut back);
+        report
+    });
+    Ok(report)
+}
+
+/// Runs `bench run`: measures, prints the report and writes it as JSON if
+/// asked to.
+pub fn run(cfg: &RunConfig) -> ExitCode {
+    let report = match measure(cfg) {
+        Ok(r) => r,
+        Err(e) => return exit::fail(e),
+    };
+    report.print();
+    if let Some(path) = &cfg.json {
+        if let Err(e) = fs::write(path, report.json()) {
+            return exit::fail(format!("Failed to write {path}: {e}"));
+        }
+    }
+    if report.received == 0 {
+        return exit::fail(format!(
+            "No replies from {}; is `bench reflect` running there?",
+            report.target
+        ));
+    }
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_use_the_nearest_rank() {
+        let mut samples: Vec<i64> = (1..=200).rev().collect();
+        let s = Summary::of(&mut samples).unwrap();
+        assert_eq!((s.min, s.p50, s.p90, s.p99, s.max), (1, 100, 180, 198, 200));
+        assert_eq!(s.mean, 100);
+        assert_eq!(Summary::of(&mut [7]).unwrap().p99, 7);
+        assert!(Summary::of(&mut []).is_none());
+    }
+
+    #[test]
+    fn measures_a_loopback_reflector() {
+        let reflector = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = reflector.local_addr().unwrap().port();
+        drop(reflector);
+        thread::spawn(move || {
+            reflect(&ReflectConfig {
+                addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                port,
+            })
+        });
+        thread::sleep(Duration::from_millis(50));
+
+        let report = measure(&RunConfig {
+            target: Target::Ip(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            port,
+            rate: 200.0,
+            duration: Duration::from_millis(300),
+            size: 100,
+            json: None,
+        })
+        .unwrap();
+        assert!(report.sent >= 50, "{report:?}");
+        assert_eq!(report.received, report.sent, "{report:?}");
+        assert_eq!(report.lost_by_direction(), (0, 0));
+        let rtt = report.rtt.unwrap();
+        assert!(rtt.min >= 0 && rtt.max < 100_000, "{rtt:?}");
+        assert!(report
+            .json()
+            .contains(&format!("\"sent\":{},", report.sent)));
+    }
+}
@@ -0,0 +1,260 @@
+//! Windows in which `send` holds back its datagrams (`send --blackout`).
+//!
+//! While switches are reconfigured, timestamps that arrive late or by an
+//! odd path mislead whoever consumes them, so a maintenance window can be
+//! blacked out. A window recurs weekly, on some or all days between two
+//! times of day (UTC), or is a one-off between two RFC 3339 times:
+//!
+//! ```text
+//! 02:00-04:00                 every day
+//! Sat,Sun 22:00-02:00         from Saturday and Sunday evenings, past midnight
+//! Mon-Fri 12:00-12:15
+//! 2026-11-03T01:00:00Z/2026-11-03T03:30:00Z
+//! 10.0.0.255=Sat 02:00-04:00  only for destination 10.0.0.255
+//! ```
+//!
+//! A window prefixed with `IP=` or `IP:PORT=` applies to that destination
+//! alone, the others to every destination. The sender keeps ticking through
+//! a window, so it resumes on its usual grid as soon as the window ends.
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::start::parse_rfc3339;
+
+const DAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+/// When a window is.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Schedule {
+    /// Every day in `days` (bit 0 for Monday), from `start` to `end`
+    /// minutes into the day, ending the next day if `end <= start`.
+    Weekly {
+        days: u8,
+        start: u32,
+        end: u32,
+    },
+    Once {
+        start: SystemTime,
+        end: SystemTime,
+    },
+}
+
+/// One `--blackout` window.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Blackout {
+    /// The destination it applies to, any port if `None`; all if unset.
+    dest: Option<(IpAddr, Option<u16>)>,
+    schedule: Schedule,
+    /// As given, for logging.
+    text: String,
+}
+
+impl Blackout {
+    /// Parses `[DEST=]WINDOW`, as in the module documentation.
+    pub fn parse(s: &str) -> Result<Blackout, String> {
+        let (dest, window) = match s.split_once('=') {
+            Some((dest, window)) => (Some(parse_dest(dest.trim())?), window.trim()),
+            None => (None, s.trim()),
+        };
+        let schedule = match window.split_once('/') {
+            Some((start, end)) => {
+                let (start, end) = (parse_rfc3339(start)?, parse_rfc3339(end)?);
+                if end <= start {
+                    return Err(format!("window '{window}' ends before it starts"));
+                }
+                Schedule::Once { start, end }
+            }
+            None => parse_weekly(window)?,
+        };
+        Ok(Blackout {
+            dest,
+            schedule,
+            text: s.trim().to_string(),
+        })
+    }
+
+    /// Whether the window applies to datagrams for `dest`.
+    pub fn applies_to(&self, dest: SocketAddr) -> bool {
+        match self.dest {
+            None => true,
+            Some((ip, port)) => ip == dest.ip() && port.is_none_or(|p| p == dest.port()),
+        }
+    }
+
+    /// The end of the window, if `now` is in it.
+    pub fn until(&self, now: SystemTime) -> Option<SystemTime> {
+        match self.schedule {
+            Schedule::Once { start, end } => (start..end).contains(&now).then_some(end),
+            Schedule::Weekly { days, start, end } => {
+                let secs = now.duration_since(UNIX_EPOCH).ok()?.as_secs();
+                let today = secs / 86_400;
+                // A window that began yesterday may run into today
+                [today.checked_sub(1), Some(today)]
+                    .into_iter()
+                    .flatten()
+                    .filter(|&day| days & (1 << weekday(day)) != 0)
+                    .find_map(|day| {
+                        let from = day * 86_400 + u64::from(start) * 60;
+                        let mut to = day * 86_400 + u64::from(end) * 60;
+                        if end <= start {
+                            to += 86_400;
+                        }
+                        (from..to)
+                            .contains(&secs)
+                            .then(|| UNIX_EPOCH + Duration::from_secs(to))
+                    })
+            }
+        }
+    }
+}
+
+impl fmt::Display for Blackout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+/// The end of the longest of `windows` for `dest` that `now` is in.
+pub fn until(windows: &[Blackout], dest: SocketAddr, now: SystemTime) -> Option<SystemTime> {
+    windows
+        .iter()
+        .filter(|w| w.applies_to(dest))
+        .filter_map(|w| w.until(now))
+        .max()
+}
+
+/// Monday is 0; 1970-01-01 was a Thursday.
+fn weekday(days_since_epoch: u64) -> u64 {
+    (days_since_epoch + 3) % 7
+}
+
+fn parse_dest(s: &str) -> Result<(IpAddr, Option<u16>), String> {
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+        return Ok((addr.ip(), Some(addr.port())));
+    }
+    s.parse::<IpAddr>()
+        .map(|ip| (ip, None))
+        .map_err(|_| format!("'{s}' is not an IP address or IP:PORT"))
+}
+
+/// Parses `[DAYS ]HH:MM-HH:MM`, DAYS being days or ranges of days
+/// separated by commas, e.g. `Mon-Fri` or `Sat,Sun`.
+fn parse_weekly(s: &str) -> Result<Schedule, String> {
+    let usage = || format!("expected [DAYS ]HH:MM-HH:MM or START/END in RFC 3339, got '{s}'");
+    let (days, times) = match s.rsplit_once(' ') {
+        Some((days, times)) => (parse_days(days.trim())?, times),
+        None => (0x7f, s),
+    };
+    let (start, end) = times.split_once('-').ok_or_else(usage)?;
+    let (start, end) = (parse_minutes(start, false)?, parse_minutes(end, true)?);
+    if start == end {
+        return Err(format!("window '{s}' is empty"));
+    }
+    Ok(Schedule::Weekly { days, start, end })
+}
+
+fn parse_days(s: &str) -> Result<u8, String> {
+    let day = |name: &str| {
+        DAYS.iter()
+            .position(|d| d.eq_ignore_ascii_case(name))
+            .ok_or_else(|| format!("unknown day '{name}'; expected Mon, Tue, ..., Sun"))
+    };
+    let mut mask = 0u8;
+    for part in s.split(',').map(str::trim) {
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (day(first)?, day(last)?),
+            None => (day(part)?, day(part)?),
+        };
+        // Fri-Mon wraps over the weekend
+        let mut d = first;
+        loop {
+            mask |= 1 << d;
+            if d == last {
+                break;
+            }
+            d = (d + 1) % 7;
+        }
+    }
+    Ok(mask)
+}
+
+/// Minutes into the day of `HH:MM`; `24:00` only as an `end`.
+fn parse_minutes(s: &str, end: bool) -> Result<u32, String> {
+    let bad = || format!("'{s}' is not a time of day, e.g. 02:30");
+    let (h, m) = s.split_once(':').ok_or_else(bad)?;
+    if h.is_empty() || h.len() > 2 || m.len() != 2 {
+        return Err(bad());
+    }
+    let (h, m): (u32, u32) = (h.parse().map_err(|_| bad())?, m.parse().map_err(|_| bad())?);
+    match (h, m) {
+        (24, 0) if end => Ok(24 * 60),
+        (0..=23, 0..=59) => Ok(h * 60 + m),
+        _ => Err(bad()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_windows_and_finds_their_ends() {
+        let at = |s: &str| parse_rfc3339(s).unwrap();
+        // 2026-10-17 is a Saturday
+        let night = Blackout::parse("Sat,Sun 22:00-02:00").unwrap();
+        assert_eq!(night.until(at("2026-10-17T21:59:59Z")), None);
+        assert_eq!(
+            night.until(at("2026-10-17T22:00:00Z")),
+            Some(at("2026-10-18T02:00:00Z"))
+        );
+        // Monday morning, carried over from Sunday night
+        assert_eq!(
+            night.until(at("2026-10-19T01:30:00Z")),
+            Some(at("2026-10-19T02:00:00Z"))
+        );
+        assert_eq!(night.until(at("2026-10-20T01:30:00Z")), None);
+
+        let weekdays = Blackout::parse("fri-mon 12:00-24:00").unwrap();
+        assert!(weekdays.until(at("2026-10-19T13:00:00Z")).is_some());
+        assert!(weekdays.until(at("2026-10-20T13:00:00Z")).is_none());
+        let sunday = Blackout::parse("Sun 00:00-24:00").unwrap();
+        assert_eq!(
+            sunday.until(at("2026-10-18T23:59:59Z")),
+            Some(at("2026-10-19T00:00:00Z"))
+        );
+
+        let once = Blackout::parse("2026-11-03T01:00:00Z/2026-11-03T03:30:00+01:00").unwrap();
+        assert_eq!(
+            once.until(at("2026-11-03T02:00:00Z")),
+            Some(at("2026-11-03T02:30:00Z"))
+        );
+        assert_eq!(once.until(at("2026-11-03T02:30:00Z")), None);
+
+        let daily = Blackout::parse("10.0.0.255=02:00-04:00").unwrap();
+        let peer = Blackout::parse("[fe80::1]:12321 = 03:00-05:00").unwrap();
+        let dest: SocketAddr = "10.0.0.255:12321".parse().unwrap();
+        assert!(daily.applies_to(dest));
+        assert!(!peer.applies_to(dest));
+        assert!(peer.applies_to("[fe80::1]:12321".parse().unwrap()));
+        assert!(!peer.applies_to("[fe80::1]:12322".parse().unwrap()));
+        assert_eq!(
+            until(&[daily, peer], dest, at("2026-10-20T03:00:00Z")),
+            Some(at("2026-10-20T04:00:00Z"))
+        );
+
+        for bad in [
+            "02:00",
+            "02:00-02:00",
+            "00:00-00:00",
+            "24:00-01:00",
+            "Mon-Fry 02:00-03:00",
+            "2:5-03:00",
+            "host=02:00-03:00",
+            "2026-11-03T03:00:00Z/2026-11-03T01:00:00Z",
+        ] {
+            assert!(Blackout::parse(bad).is_err(), "{bad}");
+        }
+    }
+}
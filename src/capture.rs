@@ -0,0 +1,292 @@
+//! Evidence capture around anomalies in receiver mode.
+//!
+//! [`FlightRecorder`] keeps the last `pre` of received datagrams in
+//! memory. When an anomaly is reported, it writes them to a new pcap file
+//! and keeps appending everything that arrives for another `post`, so an
+//! intermittent problem leaves behind the traffic on both sides of it.
+//!
+//! Only the payload and source of each datagram are known, so the pcap
+//! uses the raw-IP link type with IP and UDP headers rebuilt around them,
+//! addressed to the receiving socket. Wireshark and tcpdump read it as
+//! ordinary UDP traffic.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::record::Record;
+
+/// Sequence numbers a sender may skip before it counts as a loss burst.
+pub const LOSS_BURST: u32 = 3;
+
+/// pcap link type for packets that start with an IPv4 or IPv6 header.
+pub(crate) const LINKTYPE_RAW: u32 = 101;
+
+/// Settings for `--capture-window`.
+#[derive(Clone, Debug)]
+pub struct CaptureConfig {
+    /// Traffic kept from before an anomaly.
+    pub pre: Duration,
+    /// Traffic captured after an anomaly.
+    pub post: Duration,
+    /// Directory the pcap files are written to.
+    pub dir: String,
+}
+
+/// A capture file being written until `until_us`.
+struct Active {
+    out: BufWriter<File>,
+    until_us: u64,
+}
+
+/// Ring buffer of recent datagrams that turns into a pcap file on demand.
+pub struct FlightRecorder {
+    cfg: CaptureConfig,
+    /// Address the datagrams were received on.
+    local: SocketAddr,
+    buf: VecDeque<Record>,
+    active: Option<Active>,
+    last_seq: HashMap<String, u32>,
+}
+
+impl FlightRecorder {
+    pub fn new(cfg: &CaptureConfig, local: SocketAddr) -> FlightRecorder {
+        FlightRecorder {
+            cfg: cfg.clone(),
+            local,
+            buf: VecDeque::new(),
+            active: None,
+            last_seq: HashMap::new(),
+        }
+    }
+
+    /// Adds a received datagram, also writing it out while a capture is
+    /// in progress.
+    pub fn push(&mut self, arrival: SystemTime, src: SocketAddr, payload: &[u8]) -> io::Result<()> {
+        let record = Record {
+            arrival_us: micros(arrival),
+            src,
+            payload: payload.to_vec(),
+        };
+        if let Some(active) = self.active.as_mut() {
+            if record.arrival_us <= active.until_us {
+                write_packet(&mut active.out, &record, self.local)?;
+                active.out.flush()?;
+            } else {
+                self.active = None;
+            }
+        }
+
+        let horizon = record
+            .arrival_us
+            .saturating_sub(self.cfg.pre.as_micros() as u64);
+        while self.buf.front().is_some_and(|r| r.arrival_us < horizon) {
+            self.buf.pop_front();
+        }
+        self.buf.push_back(record);
+        Ok(())
+    }
+
+    /// Tracks `sender`'s sequence numbers, returning true if `seq` skips
+    /// at least [`LOSS_BURST`] of them.
+    pub fn loss_burst(&mut self, sender: &str, seq: u32) -> bool {
+        let last = self.last_seq.insert(sender.to_string(), seq);
+        // Reordered or replayed packets go backwards, which isn't loss
+        last.is_some_and(|last| {
+            let skipped = seq.wrapping_sub(last).wrapping_sub(1);
+            (LOSS_BURST..u32::MAX / 2).contains(&skipped)
+        })
+    }
+
+    /// Starts a capture for an anomaly seen at `now`, returning the file it
+    /// is written to, or `None` if one is already in progress.
+    pub fn trigger(&mut self, now: SystemTime) -> Result<Option<String>, String> {
+        if self.active.is_some() {
+            return Ok(None);
+        }
+        let now_us = micros(now);
+        let path = Path::new(&self.cfg.dir)
+            .join(format!("udp_bcast_ts-{}.pcap", now_us / 1000))
+            .to_string_lossy()
+            .into_owned();
+        let fail = |e: io::Error| format!("Failed to write capture {path}: {e}");
+
+        let file = File::create(&path).map_err(fail)?;
+        let mut out = BufWriter::new(file);
+        write_header(&mut out).map_err(fail)?;
+        for record in &self.buf {
+            write_packet(&mut out, record, self.local).map_err(fail)?;
+        }
+        out.flush().map_err(fail)?;
+        self.active = Some(Active {
+            out,
+            until_us: now_us + self.cfg.post.as_micros() as u64,
+        });
+        Ok(Some(path))
+    }
+}
+
+fn micros(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_micros() as u64)
+}
+
+/// Writes the pcap global header.
+fn write_header(out: &mut impl Write) -> io::Result<()> {
+    out.write_all(&0xa1b2_c3d4u32.to_le_bytes())?;
+    out.write_all(&2u16.to_le_bytes())?;
+    out.write_all(&4u16.to_le_bytes())?;
+    // Time zone offset and timestamp accuracy, both unused
+    out.write_all(&[0; 8])?;
+    out.write_all(&65_535u32.to_le_bytes())?;
+    out.write_all(&LINKTYPE_RAW.to_le_bytes())
+}
+
+/// Writes one datagram as a pcap record holding an IP/UDP packet.
+fn write_packet(out: &mut impl Write, record: &Record, local: SocketAddr) -> io::Result<()> {
+    let packet = ip_packet(record.src, local, &record.payload);
+    out.write_all(&((record.arrival_us / 1_000_000) as u32).to_le_bytes())?;
+    out.write_all(&((record.arrival_us % 1_000_000) as u32).to_le_bytes())?;
+    out.write_all(&(packet.len() as u32).to_le_bytes())?;
+    out.write_all(&(packet.len() as u32).to_le_bytes())?;
+    out.write_all(&packet)
+}
+
+/// Builds an IPv4 or IPv6 UDP packet from `src` to `dst`. A `dst` of the
+/// other address family (e.g. an IPv4 sender reaching a dual-stack
+/// socket) is replaced by the unspecified address.
+pub(crate) fn ip_packet(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let udp_len = 8 + payload.len() as u16;
+    let mut udp = Vec::with_capacity(udp_len as usize);
+    udp.extend_from_slice(&src.port().to_be_bytes());
+    udp.extend_from_slice(&dst.port().to_be_bytes());
+    udp.extend_from_slice(&udp_len.to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(payload);
+
+    let mut packet = Vec::with_capacity(40 + udp.len());
+    let pseudo = match src.ip() {
+        IpAddr::V4(s) => {
+            let d = match dst.ip() {
+                IpAddr::V4(d) => d,
+                IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+            };
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&(20 + udp_len).to_be_bytes());
+            // Identification, then Don't Fragment
+            packet.extend_from_slice(&[0, 0, 0x40, 0]);
+            packet.extend_from_slice(&[64, 17, 0, 0]);
+            packet.extend_from_slice(&s.octets());
+            packet.extend_from_slice(&d.octets());
+            let sum = checksum(&[&packet]);
+            packet[10..12].copy_from_slice(&sum.to_be_bytes());
+            [
+                &s.octets()[..],
+                &d.octets(),
+                &[0, 17],
+                &udp_len.to_be_bytes(),
+            ]
+            .concat()
+        }
+        IpAddr::V6(s) => {
+            let d = match dst.ip() {
+                IpAddr::V6(d) => d,
+                IpAddr::V4(_) => Ipv6Addr::UNSPECIFIED,
+            };
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&udp_len.to_be_bytes());
+            packet.extend_from_slice(&[17, 64]);
+            packet.extend_from_slice(&s.octets());
+            packet.extend_from_slice(&d.octets());
+            let len = (udp_len as u32).to_be_bytes();
+            [&s.octets()[..], &d.octets(), &len, &[0, 0, 0, 17]].concat()
+        }
+    };
+    let sum = match checksum(&[&pseudo, &udp]) {
+        // Zero means "no checksum", so a computed zero is sent as all ones
+        0 => 0xffff,
+        s => s,
+    };
+    udp[6..8].copy_from_slice(&sum.to_be_bytes());
+    packet.extend_from_slice(&udp);
+    packet
+}
+
+/// The Internet checksum over the concatenation of `parts`, each of which
+/// but the last must have even length.
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum: u32 = 0;
+    for part in parts {
+        for pair in part.chunks(2) {
+            let hi = pair[0] as u32;
+            let lo = pair.get(1).copied().unwrap_or(0) as u32;
+            sum += (hi << 8) | lo;
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recorder(dir: &str) -> FlightRecorder {
+        let cfg = CaptureConfig {
+            pre: Duration::from_secs(1),
+            post: Duration::from_secs(1),
+            dir: dir.to_string(),
+        };
+        FlightRecorder::new(&cfg, "0.0.0.0:12321".parse().unwrap())
+    }
+
+    #[test]
+    fn captures_both_sides_of_the_trigger() {
+        let dir = std::env::temp_dir().join(format!("udp_bcast_ts-capture-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut fr = recorder(dir.to_str().unwrap());
+        let src: SocketAddr = "10.0.0.5:40000".parse().unwrap();
+        let at = |ms: u64| UNIX_EPOCH + Duration::from_millis(1_800_000_000_000 + ms);
+
+        for ms in [0, 500, 1_500, 2_000] {
+            fr.push(at(ms), src, b"12345678").unwrap();
+        }
+        let path = fr.trigger(at(2_000)).unwrap().unwrap();
+        assert_eq!(fr.trigger(at(2_100)).unwrap(), None);
+        for ms in [2_500, 3_000, 3_500] {
+            fr.push(at(ms), src, b"12345678").unwrap();
+        }
+
+        // 1.5 s and 2 s from before, 2.5 s and 3 s from after
+        let data = std::fs::read(&path).unwrap();
+        let record_len = 16 + 20 + 8 + 8;
+        assert_eq!(data.len(), 24 + 4 * record_len);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn ipv4_packet_checksums_verify() {
+        let src = "192.168.1.20:5000".parse().unwrap();
+        let dst = "192.168.1.255:12321".parse().unwrap();
+        let packet = ip_packet(src, dst, b"abc");
+        assert_eq!(packet.len(), 20 + 8 + 3);
+        assert_eq!(checksum(&[&packet[..20]]), 0);
+        let pseudo = [&packet[12..20], &[0, 17], &11u16.to_be_bytes()[..]].concat();
+        assert_eq!(checksum(&[&pseudo, &packet[20..]]), 0);
+    }
+
+    #[test]
+    fn loss_bursts_need_several_missing_sequence_numbers() {
+        let mut fr = recorder(".");
+        assert!(!fr.loss_burst("a", 10));
+        assert!(!fr.loss_burst("a", 13));
+        assert!(fr.loss_burst("a", 17));
+        assert!(!fr.loss_burst("a", 16));
+        assert!(!fr.loss_burst("b", 100));
+    }
+}
@@ -0,0 +1,53 @@
+//! Fault injection for testing consumers of the broadcast.
+//!
+//! Drops or holds back datagrams at random, so receivers can be exercised
+//! against loss and jitter without `tc netem` or similar. A held datagram
+//! keeps the timestamp it was stamped with, as if the network had delayed
+//! it. Only built with the `chaos` cargo feature, so production binaries
+//! can't enable it by accident.
+
+use std::time::Duration;
+
+use crate::rng::Rng;
+
+/// Settings for `--drop-probability` and `--delay-jitter-ms`.
+#[derive(Clone, Copy, Debug)]
+pub struct ChaosConfig {
+    /// Chance, from 0 to 1, that a datagram is not sent.
+    pub drop_probability: f64,
+    /// Longest a datagram is held before sending; the hold is uniform
+    /// between zero and this.
+    pub delay_jitter: Duration,
+}
+
+impl ChaosConfig {
+    /// Decides the fate of one datagram: `None` to drop it, or how long
+    /// to hold it first.
+    pub fn fate(&self, rng: &mut Rng) -> Option<Duration> {
+        if rng.next_f64() < self.drop_probability {
+            return None;
+        }
+        Some(self.delay_jitter.mul_f64(rng.next_f64()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_and_delays_in_proportion() {
+        let chaos = ChaosConfig {
+            drop_probability: 0.25,
+            delay_jitter: Duration::from_millis(40),
+        };
+        let mut rng = Rng::new(7);
+        let fates: Vec<_> = (0..10_000).map(|_| chaos.fate(&mut rng)).collect();
+        let dropped = fates.iter().filter(|f| f.is_none()).count();
+        assert!((2_300..2_700).contains(&dropped), "dropped {dropped}");
+        assert!(fates
+            .iter()
+            .flatten()
+            .all(|d| *d < Duration::from_millis(40)));
+    }
+}
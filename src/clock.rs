@@ -0,0 +1,144 @@
+//! Time source used by the send loop, abstracted so tests can drive it
+//! with a fake clock.
+//!
+//! The clock also reports whether the kernel counts it as synchronized
+//! (`send --require-clock-sync`). On Linux that is `adjtimex(2)`'s
+//! `STA_UNSYNC` status bit, which NTP daemons and `recv --discipline`
+//! clear, and its `maxerror`, the daemon's bound on how far off the clock
+//! may be, which the kernel grows by 500 ppm of the time since it was
+//! last set. Other platforms can't tell.
+
+use std::hint;
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Intervals shorter than this are kept by finishing each wait with a spin.
+pub const SPIN_BELOW: Duration = Duration::from_millis(10);
+
+/// How much of each wait is spun rather than slept, when spinning. It
+/// covers the usual timer slack and wakeup latency of a sleeping thread.
+pub const SPIN_WINDOW: Duration = Duration::from_micros(200);
+
+/// Wall-clock and monotonic time plus the ability to wait.
+pub trait Clock {
+    /// Wall-clock time, which is what gets broadcast.
+    fn now(&self) -> SystemTime;
+    /// Monotonic time, used for scheduling.
+    fn instant(&self) -> Instant;
+    /// Blocks for `d`.
+    fn sleep(&mut self, d: Duration);
+    /// Whether the kernel counts the wall clock as synchronized.
+    fn sync_status(&self) -> io::Result<SyncStatus> {
+        sync_status()
+    }
+}
+
+/// What the kernel knows of the wall clock's synchronization.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SyncStatus {
+    /// `STA_UNSYNC` is clear.
+    pub synced: bool,
+    /// Most the clock may be off by, as last estimated.
+    pub max_error: Duration,
+}
+
+impl SyncStatus {
+    /// Whether the clock is synchronized to within `max_error`.
+    pub fn within(&self, max_error: Duration) -> bool {
+        self.synced && self.max_error <= max_error
+    }
+}
+
+/// Reads the kernel's synchronization status.
+pub fn sync_status() -> io::Result<SyncStatus> {
+    imp::sync_status()
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::io;
+    use std::mem;
+    use std::time::Duration;
+
+    use super::SyncStatus;
+
+    pub fn sync_status() -> io::Result<SyncStatus> {
+        // SAFETY: timex is plain data, for which all zeroes is valid, and
+        // modes 0 only reads.
+        let mut tx: libc::timex = unsafe { mem::zeroed() };
+        // SAFETY: `tx` is a valid, exclusively borrowed timex.
+        let state = unsafe { libc::adjtimex(&mut tx) };
+        if state < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(SyncStatus {
+            synced: tx.status & libc::STA_UNSYNC == 0 && state != libc::TIME_ERROR,
+            max_error: Duration::from_micros(tx.maxerror.max(0) as u64),
+        })
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use std::io;
+
+    use super::SyncStatus;
+
+    pub fn sync_status() -> io::Result<SyncStatus> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the clock's synchronization status is only known on Linux",
+        ))
+    }
+}
+
+/// The real system clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock {
+    spin: Duration,
+}
+
+impl SystemClock {
+    /// A clock whose waits sleep until `spin` before they end, then
+    /// busy-wait the rest. A sleeping thread wakes tens of microseconds
+    /// late, which a sub-millisecond cadence can't absorb; the spin costs
+    /// up to `spin` of CPU per wait instead.
+    pub fn spinning(spin: Duration) -> SystemClock {
+        SystemClock { spin }
+    }
+
+    /// The clock for a loop ticking every `interval`: spinning below
+    /// `SPIN_BELOW` (10 ms), plain sleeps otherwise.
+    pub fn for_interval(interval: Duration) -> SystemClock {
+        if interval < SPIN_BELOW {
+            SystemClock::spinning(SPIN_WINDOW)
+        } else {
+            SystemClock::default()
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&mut self, d: Duration) {
+        if self.spin.is_zero() {
+            thread::sleep(d);
+            return;
+        }
+        let deadline = Instant::now() + d;
+        if d > self.spin {
+            thread::sleep(d - self.spin);
+        }
+        while Instant::now() < deadline {
+            hint::spin_loop();
+        }
+    }
+}
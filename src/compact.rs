@@ -0,0 +1,309 @@
+//! The `compact` payload, for links that can't afford a full timestamp
+//! with every tick (`send --format compact`).
+//!
+//! Every `--full-every`th datagram is a full frame; those in between are
+//! delta frames that a receiver expands against the last full one:
+//!
+//! ```text
+//! full frame, 11 bytes                delta frame, 4 bytes
+//! offset  size  field                 offset  size  field
+//!      0     2  sequence number            0     2  sequence number
+//!      2     1  full every N               2     2  milliseconds after the
+//!      3     8  timestamp                             full frame's timestamp
+//! ```
+//!
+//! All fields are big-endian, and the sequence number is the low 16 bits of
+//! the sender's. A full frame goes out whenever the sequence number is a
+//! multiple of N, and the delta frames up to the next multiple count from
+//! its timestamp. A receiver only expands a delta against the full frame
+//! of its own group, numbered `seq - seq % N`: if that one was lost, the
+//! rest of the group is dropped rather than expanded against an older
+//! frame. A delta that wouldn't fit in 16 bits, after a clock step or when
+//! N ticks span more than 65.5 s, goes out as a full frame instead.
+//!
+//! As with legacy payloads there is no room for the time scale, a group,
+//! extensions or a MAC: a full frame's timestamp is in the `--epoch` scale,
+//! which receivers must be told. Receivers widen sequence numbers back to
+//! 32 bits per sender.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::net::SocketAddr;
+
+use crate::epoch::Epoch;
+use crate::payload::{DecodeError, Decoded, Format, Packet};
+
+pub const FULL_LEN: usize = 11;
+pub const DELTA_LEN: usize = 4;
+
+/// Default for `--full-every`.
+pub const DEFAULT_FULL_EVERY: u8 = 10;
+
+/// The sequence number of the full frame `seq`'s group starts with.
+fn group_start(seq: u16, every: u8) -> u16 {
+    seq - seq % u16::from(every)
+}
+
+/// Encodes `packet` as a full frame announcing `every` into `out`,
+/// replacing its contents.
+pub fn encode_full(packet: &Packet, every: u8, out: &mut Vec<u8>) {
+    out.clear();
+    out.extend_from_slice(&(packet.seq.unwrap_or(0) as u16).to_be_bytes());
+    out.push(every);
+    out.extend_from_slice(&packet.epoch.value_of(packet.ts_ms).to_be_bytes());
+}
+
+/// Decodes a full frame on its own. As for legacy payloads, the timestamp
+/// is left in the scale it was sent in.
+pub fn decode_full(data: &[u8]) -> Decoded<'_> {
+    decoded(
+        u32::from(u16::from_be_bytes([data[0], data[1]])),
+        u64::from_be_bytes(data[3..11].try_into().unwrap()),
+        Epoch::Unix,
+    )
+}
+
+fn decoded(seq: u32, ts_ms: u64, epoch: Epoch) -> Decoded<'static> {
+    Decoded {
+        packet: Packet {
+            epoch,
+            ..Packet::new(Some(seq), ts_ms)
+        },
+        format: Format::Compact,
+        mac: None,
+        key_id: None,
+        sig: None,
+        fields: Vec::new(),
+        echo: None,
+        crc: None,
+    }
+}
+
+/// Chooses between full and delta frames for a sender.
+pub struct Encoder {
+    every: u8,
+    /// Sequence number and Unix timestamp of the current group's full frame.
+    full: Option<(u16, u64)>,
+}
+
+impl Encoder {
+    pub fn new(every: u8) -> Encoder {
+        Encoder { every, full: None }
+    }
+
+    /// Encodes `packet` into `out`, replacing its contents.
+    pub fn encode(&mut self, packet: &Packet, out: &mut Vec<u8>) {
+        let seq = packet.seq.unwrap_or(0) as u16;
+        if seq.is_multiple_of(u16::from(self.every)) {
+            self.full = Some((seq, packet.ts_ms));
+        }
+        let delta = self
+            .full
+            .filter(|&(start, _)| start == group_start(seq, self.every) && start != seq)
+            .and_then(|(_, ts_ms)| packet.ts_ms.checked_sub(ts_ms))
+            .and_then(|d| u16::try_from(d).ok());
+        match delta {
+            Some(delta) => {
+                out.clear();
+                out.extend_from_slice(&seq.to_be_bytes());
+                out.extend_from_slice(&delta.to_be_bytes());
+            }
+            None => encode_full(packet, self.every, out),
+        }
+    }
+}
+
+/// What a receiver knows of one sender.
+#[derive(Default)]
+struct Source {
+    every: u8,
+    /// Sequence number and Unix timestamp of the latest group's full frame.
+    full: Option<(u16, u64)>,
+    /// Widened sequence number of the latest frame.
+    last_seq: Option<u32>,
+}
+
+impl Source {
+    /// Widens a 16-bit sequence number to the 32-bit one nearest the last.
+    fn widen(&mut self, seq: u16) -> u32 {
+        let wide = match self.last_seq {
+            Some(last) => {
+                let ahead = seq.wrapping_sub(last as u16) as i16;
+                last.wrapping_add(ahead as u32)
+            }
+            None => u32::from(seq),
+        };
+        self.last_seq = Some(wide);
+        wide
+    }
+}
+
+/// Expands compact frames into full timestamps, per sender.
+pub struct Expander {
+    epoch: Epoch,
+    sources: HashMap<SocketAddr, Source>,
+}
+
+impl Expander {
+    /// Expands frames whose timestamps are in the `epoch` scale.
+    pub fn new(epoch: Epoch) -> Expander {
+        Expander {
+            epoch,
+            sources: HashMap::new(),
+        }
+    }
+
+    /// Decodes a frame from `src`, converting its timestamp to Unix time.
+    /// Returns `None` if `data` isn't the length of either frame.
+    pub fn expand<'a>(
+        &mut self,
+        src: SocketAddr,
+        data: &'a [u8],
+    ) -> Option<Result<Decoded<'a>, DecodeError>> {
+        if data.len() != FULL_LEN && data.len() != DELTA_LEN {
+            return None;
+        }
+        let source = self.sources.entry(src).or_default();
+        let seq = u16::from_be_bytes([data[0], data[1]]);
+        if data.len() == FULL_LEN {
+            let every = data[2];
+            if every == 0 {
+                return Some(Err(DecodeError::CompactEmptyGroup));
+            }
+            let Some(ts_ms) = self.epoch.to_unix_ms(decode_full(data).packet.ts_ms) else {
+                return Some(Err(DecodeError::TimestampOutOfRange));
+            };
+            source.every = every;
+            if seq.is_multiple_of(u16::from(every)) {
+                source.full = Some((seq, ts_ms));
+            }
+            return Some(Ok(decoded(source.widen(seq), ts_ms, self.epoch)));
+        }
+        let start = match source.every {
+            0 => None,
+            every => Some(group_start(seq, every)),
+        };
+        let result = match (start, source.full) {
+            (Some(start), Some((full_seq, ts_ms))) if start == full_seq => {
+                let delta = u16::from_be_bytes([data[2], data[3]]);
+                match ts_ms.checked_add(u64::from(delta)) {
+                    Some(ts_ms) => Ok(decoded(source.widen(seq), ts_ms, self.epoch)),
+                    None => Err(DecodeError::TimestampOutOfRange),
+                }
+            }
+            (full, _) => Err(DecodeError::CompactMissingFull { seq, full }),
+        };
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Rng;
+
+    #[test]
+    fn deltas_expand_only_against_their_groups_full_frame() {
+        let mut encoder = Encoder::new(4);
+        let mut expander = Expander::new(Epoch::Unix);
+        let src: SocketAddr = "192.0.2.1:12321".parse().unwrap();
+        let packet = |seq: u32, ts_ms| Packet::new(Some(seq), ts_ms);
+        let mut out = Vec::new();
+        let mut send = |seq: u32, ts_ms: u64| {
+            encoder.encode(&packet(seq, ts_ms), &mut out);
+            out.clone()
+        };
+
+        // Starting mid-group, everything is full until the group boundary
+        let frames: Vec<Vec<u8>> = (65_534..65_542)
+            .map(|seq| send(seq, 1_000_000 + u64::from(seq - 65_534) * 100))
+            .collect();
+        let lens: Vec<usize> = frames.iter().map(Vec::len).collect();
+        assert_eq!(lens, [11, 11, 11, 4, 4, 4, 11, 4]);
+        for (i, frame) in frames.iter().enumerate() {
+            let d = expander.expand(src, frame).unwrap().unwrap();
+            // Widened across the 16-bit wrap
+            assert_eq!(d.packet.seq, Some(65_534 + i as u32));
+            assert_eq!(d.packet.ts_ms, 1_000_000 + i as u64 * 100);
+        }
+
+        // A clock step back sends a full frame in place of a delta
+        assert_eq!(send(65_542, 999_000).len(), FULL_LEN);
+        // With seq 8's full frame lost, its group's deltas are dropped
+        send(65_544, 1_001_000);
+        let delta = send(65_545, 1_001_100);
+        assert_eq!(delta.len(), DELTA_LEN);
+        assert!(expander.expand(src, &delta).unwrap().is_err());
+        assert!(expander.expand(src, &[0; 8]).is_none());
+    }
+
+    #[test]
+    fn delta_past_u64_max_is_an_error() {
+        let mut expander = Expander::new(Epoch::Unix);
+        let src: SocketAddr = "192.0.2.1:12321".parse().unwrap();
+        let mut full = vec![0, 4, 4];
+        full.extend_from_slice(&(u64::MAX - 1).to_be_bytes());
+        let d = expander.expand(src, &full).unwrap().unwrap();
+        assert_eq!(d.packet.ts_ms, u64::MAX - 1);
+        let d = expander.expand(src, &[0, 5, 0, 1]).unwrap().unwrap();
+        assert_eq!(d.packet.ts_ms, u64::MAX);
+        assert_eq!(
+            expander.expand(src, &[0, 6, 0, 2]).unwrap().err(),
+            Some(DecodeError::TimestampOutOfRange)
+        );
+    }
+
+    #[test]
+    fn random_frames_expand_or_fail_without_panicking() {
+        let mut rng = Rng::new(0xc0de);
+        let src: SocketAddr = "192.0.2.1:12321".parse().unwrap();
+        for epoch in [Epoch::Unix, Epoch::Ntp, Epoch::Gps] {
+            let mut expander = Expander::new(epoch);
+            // Sequence number, group and Unix timestamp of the last full frame
+            let mut full: Option<(u16, u8, Option<u64>)> = None;
+            for _ in 0..5000 {
+                let r = rng.next_u64();
+                let mut frame = Vec::new();
+                match full {
+                    Some((start, every, ts_ms)) if !r.is_multiple_of(4) => {
+                        let seq = start.wrapping_add((r >> 8) as u16 % u16::from(every));
+                        let delta = (r >> 32) as u16;
+                        frame.extend_from_slice(&seq.to_be_bytes());
+                        frame.extend_from_slice(&delta.to_be_bytes());
+                        let expected = ts_ms.and_then(|t| t.checked_add(u64::from(delta)));
+                        match expander.expand(src, &frame).unwrap() {
+                            Ok(d) => assert_eq!(Some(d.packet.ts_ms), expected),
+                            Err(e) => assert!(
+                                matches!(e, DecodeError::CompactMissingFull { .. })
+                                    || (e == DecodeError::TimestampOutOfRange
+                                        && expected.is_none()),
+                                "{e}"
+                            ),
+                        }
+                    }
+                    _ => {
+                        let every = (r >> 8) as u8 % 9;
+                        let seq =
+                            ((r >> 16) as u16) / u16::from(every.max(1)) * u16::from(every.max(1));
+                        let extremes = [0, 1 << 63, u64::MAX - 1, u64::MAX, rng.next_u64()];
+                        let value = extremes[(r >> 32) as usize % extremes.len()];
+                        frame.extend_from_slice(&seq.to_be_bytes());
+                        frame.push(every);
+                        frame.extend_from_slice(&value.to_be_bytes());
+                        let ts_ms = epoch.to_unix_ms(value);
+                        match expander.expand(src, &frame).unwrap() {
+                            Ok(d) => assert_eq!(Some(d.packet.ts_ms), ts_ms),
+                            Err(DecodeError::CompactEmptyGroup) => assert_eq!(every, 0),
+                            Err(e) => {
+                                assert_eq!((e, ts_ms), (DecodeError::TimestampOutOfRange, None))
+                            }
+                        }
+                        if every != 0 && ts_ms.is_some() {
+                            full = Some((seq, every, ts_ms));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,524 @@
+//! Interoperability checks against other implementations of the wire
+//! format.
+//!
+//! `conformance receiver` sends a scripted set of valid and invalid
+//! datagrams to a receiver under test. The receiver is expected to answer
+//! every datagram it accepts as a timestamp with an ack, and nothing else:
+//! a v2 header of type [`MessageType::ACK`] echoing the accepted packet's
+//! sequence number (0 for legacy) and `ts_ms`, sent back to the source
+//! address. `recv --ack` is the reference behavior.
+//!
+//! `conformance sender` listens to a sender under test for a while and
+//! checks what it transmits.
+//!
+//! Each check prints one `PASS` or `FAIL` line; the exit code is 0 only
+//! if all of them pass.
+
+use std::collections::BTreeSet;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::process::ExitCode;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::auth::Key;
+use crate::epoch::Epoch;
+use crate::exit;
+use crate::log;
+use crate::message::MessageType;
+use crate::payload::{self, Format, Packet, EXT_MAC, V2_VERSION};
+use crate::resolve::Target;
+use crate::send::bind_for;
+use crate::transmit;
+
+/// Gap between scripted datagrams, so a slow receiver isn't overrun.
+const CASE_SPACING: Duration = Duration::from_millis(50);
+
+/// Settings for `conformance receiver`.
+pub struct ReceiverTest {
+    pub target: Target,
+    pub port: u16,
+    /// Group the receiver accepts, and the key it requires, if any.
+    pub group: Option<u16>,
+    pub key: Option<Key>,
+    /// How long to wait for acks after the last datagram.
+    pub timeout: Duration,
+}
+
+/// Settings for `conformance sender`.
+pub struct SenderTest {
+    pub addr: Option<IpAddr>,
+    pub port: u16,
+    pub group: Option<u16>,
+    pub key: Option<Key>,
+    pub duration: Duration,
+    /// Largest acceptable difference between the sender's timestamps and
+    /// the local clock.
+    pub max_offset_ms: u64,
+}
+
+/// One scripted datagram and whether a conforming receiver accepts it.
+pub struct Case {
+    pub name: &'static str,
+    pub data: Vec<u8>,
+    pub accept: bool,
+    /// The `(seq, ts_ms)` an ack for this case echoes.
+    pub echo: (u32, u64),
+}
+
+/// Outcome of one check.
+pub struct Outcome {
+    pub name: &'static str,
+    pub failure: Option<String>,
+}
+
+impl Outcome {
+    fn check(name: &'static str, failure: Option<String>) -> Outcome {
+        Outcome { name, failure }
+    }
+}
+
+fn now_ms() -> Result<u64, String> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .map_err(|e| format!("System clock error (before UNIX_EPOCH): {e:?}"))
+}
+
+/// Builds the datagrams sent to a receiver under test. Every case has a
+/// distinct timestamp starting at `base_ms`, so acks can be matched up.
+pub fn receiver_cases(base_ms: u64, group: Option<u16>, key: Option<&Key>) -> Vec<Case> {
+    let mut cases = Vec::new();
+    let mut next = 0u32;
+    let mut add = |name, accept, build: &dyn Fn(&Packet) -> Vec<u8>| {
+        next += 1;
+        let packet = Packet {
+            group,
+            ..Packet::new(Some(next), base_ms + next as u64)
+        };
+        let data = build(&packet);
+        let seq = if data.len() == payload::LEGACY_LEN {
+            0
+        } else {
+            next
+        };
+        cases.push(Case {
+            name,
+            data,
+            accept,
+            echo: (seq, packet.ts_ms),
+        });
+    };
+    let v2 = |p: &Packet| {
+        let mut buf = Vec::new();
+        payload::encode(Format::V2, p, key, &mut buf);
+        buf
+    };
+    // Encodes `p` with `ext` inserted before any MAC, and the MAC
+    // recomputed over the result.
+    let with_ext = |p: &Packet, ext: &[u8]| {
+        let mut buf = Vec::new();
+        payload::encode(Format::V2, p, None, &mut buf);
+        buf.extend_from_slice(ext);
+        if let Some(key) = key {
+            let mac = key.mac(&buf);
+            buf.push(EXT_MAC);
+            buf.push(mac.len() as u8);
+            buf.extend_from_slice(&mac);
+        }
+        buf
+    };
+
+    // Legacy payloads can't carry a group or MAC, so a receiver that
+    // requires either must drop them.
+    let legacy_ok = group.is_none() && key.is_none();
+    add("legacy_timestamp", legacy_ok, &|p| {
+        p.ts_ms.to_be_bytes().to_vec()
+    });
+    add("v2_data", true, &v2);
+    add("v2_unknown_extension_skipped", true, &|p| {
+        with_ext(p, &[0x7e, 3, 1, 2, 3])
+    });
+    add("v2_monotonic_extension", true, &|p| {
+        v2(&Packet {
+            mono_us: Some(1_234_567),
+            era: None,
+            ..*p
+        })
+    });
+    add("v2_relay_extension", true, &|p| {
+        v2(&Packet {
+            relay: Some(payload::Relay { hops: 2, id: 7 }),
+            epoch: Epoch::Unix,
+            ..*p
+        })
+    });
+    add("v2_unknown_message_type_ignored", false, &|p| {
+        v2(&Packet {
+            kind: MessageType(0x90),
+            ..*p
+        })
+    });
+    add("v2_control_not_a_timestamp", false, &|p| {
+        v2(&Packet {
+            kind: MessageType::CONTROL,
+            control: Some(payload::Control {
+                apply_at_ms: p.ts_ms,
+                interval_ms: 1_000,
+            }),
+            ..*p
+        })
+    });
+    add("v2_bad_version_rejected", false, &|p| {
+        let mut buf = v2(p);
+        buf[2] = V2_VERSION + 1;
+        buf
+    });
+    add("bad_magic_rejected", false, &|p| {
+        let mut buf = v2(p);
+        buf[..2].copy_from_slice(b"XX");
+        buf
+    });
+    add("short_datagram_rejected", false, &|p| v2(p)[..12].to_vec());
+    add("v2_truncated_extension_rejected", false, &|p| {
+        let mut buf = Vec::new();
+        payload::encode(Format::V2, p, None, &mut buf);
+        buf.extend_from_slice(&[0x7e, 10, 1, 2]);
+        buf
+    });
+    if key.is_some() {
+        add("v2_bad_mac_rejected", false, &|p| {
+            let mut buf = v2(p);
+            *buf.last_mut().unwrap() ^= 1;
+            buf
+        });
+        add("v2_missing_mac_rejected", false, &|p| {
+            let mut buf = Vec::new();
+            payload::encode(Format::V2, p, None, &mut buf);
+            buf
+        });
+        add("v2_data_after_mac_rejected", false, &|p| {
+            let mut buf = v2(p);
+            buf.extend_from_slice(&[0x7e, 0]);
+            buf
+        });
+    }
+    cases
+}
+
+/// Matches received acks against the scripted cases.
+pub fn grade_receiver(cases: &[Case], acks: &[(u32, u64)]) -> Vec<Outcome> {
+    let acked: BTreeSet<(u32, u64)> = acks.iter().copied().collect();
+    let mut outcomes: Vec<Outcome> = cases
+        .iter()
+        .map(|c| {
+            let got = acked.contains(&c.echo);
+            let failure = match (c.accept, got) {
+                (true, false) => Some("no ack; the datagram should be accepted".to_string()),
+                (false, true) => Some("acked; the datagram should be dropped".to_string()),
+                _ => None,
+            };
+            Outcome::check(c.name, failure)
+        })
+        .collect();
+    let stray = acked
+        .iter()
+        .filter(|a| !cases.iter().any(|c| c.echo == **a))
+        .count();
+    outcomes.push(Outcome::check(
+        "ack_echoes_packet",
+        (stray > 0).then(|| format!("{stray} ack(s) matched no datagram sent")),
+    ));
+    outcomes
+}
+
+/// Checks the datagrams heard from a sender under test.
+#[derive(Default)]
+pub struct SenderGrader {
+    received: u64,
+    malformed: Vec<String>,
+    reserved_type: u64,
+    auth_failed: u64,
+    wrong_group: u64,
+    seq_errors: u64,
+    ts_backwards: u64,
+    max_offset_ms: u64,
+    last: Option<(Option<u32>, u64)>,
+}
+
+impl SenderGrader {
+    /// Records one datagram received at `recv_ms`.
+    pub fn observe(&mut self, data: &[u8], recv_ms: u64, group: Option<u16>, key: Option<&Key>) {
+        self.received += 1;
+        let decoded = match payload::decode(data) {
+            Ok(d) => d,
+            Err(e) => {
+                self.malformed.push(e.to_string());
+                return;
+            }
+        };
+        let packet = decoded.packet;
+        let kind = packet.kind;
+        if !kind.is_user() && (kind.0 > MessageType::REPLY.0) {
+            self.reserved_type += 1;
+        }
+        if key.is_some_and(|k| !decoded.verify(k)) {
+            self.auth_failed += 1;
+        }
+        if group.is_some() && packet.group != group {
+            self.wrong_group += 1;
+        }
+        if kind != MessageType::DATA {
+            return;
+        }
+        if let Some((last_seq, last_ts)) = self.last {
+            // Gaps are loss; a repeat or step backwards is a sender bug
+            if let (Some(last), Some(seq)) = (last_seq, packet.seq) {
+                if seq.wrapping_sub(last) == 0 || seq.wrapping_sub(last) > u32::MAX / 2 {
+                    self.seq_errors += 1;
+                }
+            }
+            if packet.ts_ms < last_ts {
+                self.ts_backwards += 1;
+            }
+        }
+        self.last = Some((packet.seq, packet.ts_ms));
+        self.max_offset_ms = self.max_offset_ms.max(recv_ms.abs_diff(packet.ts_ms));
+    }
+
+    pub fn grade(&self, max_offset_ms: u64, authenticated: bool, grouped: bool) -> Vec<Outcome> {
+        let count = |n: u64, what: &str| (n > 0).then(|| format!("{n} {what}"));
+        let mut outcomes = vec![
+            Outcome::check(
+                "datagrams_received",
+                (self.received < 2).then(|| format!("only {} received", self.received)),
+            ),
+            Outcome::check(
+                "datagrams_decode",
+                self.malformed
+                    .first()
+                    .map(|e| format!("{} malformed, first: {e}", self.malformed.len())),
+            ),
+            Outcome::check(
+                "message_types_assigned",
+                count(self.reserved_type, "datagram(s) used a reserved type"),
+            ),
+            Outcome::check(
+                "sequence_increases",
+                count(self.seq_errors, "repeated or backwards sequence number(s)"),
+            ),
+            Outcome::check(
+                "timestamps_monotonic",
+                count(self.ts_backwards, "timestamp(s) went backwards"),
+            ),
+            Outcome::check(
+                "clock_offset",
+                (self.max_offset_ms > max_offset_ms).then(|| {
+                    format!(
+                        "offset reached {} ms, limit {max_offset_ms} ms",
+                        self.max_offset_ms
+                    )
+                }),
+            ),
+        ];
+        if grouped {
+            outcomes.push(Outcome::check(
+                "group_tagged",
+                count(self.wrong_group, "datagram(s) without the expected group"),
+            ));
+        }
+        if authenticated {
+            outcomes.push(Outcome::check(
+                "mac_valid",
+                count(self.auth_failed, "datagram(s) failed authentication"),
+            ));
+        }
+        outcomes
+    }
+}
+
+fn report(outcomes: &[Outcome]) -> ExitCode {
+    let mut failed = 0;
+    for o in outcomes {
+        match &o.failure {
+            None => println!("PASS {}", o.name),
+            Some(why) => {
+                failed += 1;
+                println!("FAIL {}: {why}", o.name);
+            }
+        }
+    }
+    println!(
+        "{} of {} checks passed",
+        outcomes.len() - failed,
+        outcomes.len()
+    );
+    if failed == 0 {
+        ExitCode::SUCCESS
+    } else {
+        exit::fail(format!("{failed} conformance check(s) failed"))
+    }
+}
+
+/// Runs the scripted exchange against a receiver.
+pub fn test_receiver(cfg: &ReceiverTest) -> ExitCode {
+    let result = (|| {
+        let dest = cfg.target.resolve(cfg.port, None)?;
+        let sock = bind_for(dest)?;
+        sock.set_read_timeout(Some(Duration::from_millis(100)))
+            .map_err(|e| format!("Failed to set receive timeout: {e}"))?;
+        let cases = receiver_cases(now_ms()?, cfg.group, cfg.key.as_ref());
+
+        let mut acks = Vec::new();
+        for case in &cases {
+            transmit::send_to(&sock, &case.data, dest)
+                .map_err(|e| format!("send_to({dest}) failed: {e}"))?;
+            collect_acks(&sock, CASE_SPACING, &mut acks);
+        }
+        collect_acks(&sock, cfg.timeout, &mut acks);
+        Ok::<_, String>(grade_receiver(&cases, &acks))
+    })();
+    match result {
+        Ok(outcomes) => report(&outcomes),
+        Err(e) => exit::fail(e),
+    }
+}
+
+/// Reads acks for `wait`; other datagrams are reported and skipped.
+fn collect_acks(sock: &UdpSocket, wait: Duration, acks: &mut Vec<(u32, u64)>) {
+    let until = Instant::now() + wait;
+    let mut buf = [0u8; 1500];
+    while Instant::now() < until {
+        let (n, src) = match sock.recv_from(&mut buf) {
+            Ok(r) => r,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            Err(e) => {
+                log::warn!("recv_from failed: {e}");
+                thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+        };
+        match payload::decode(&buf[..n]) {
+            Ok(d) if d.packet.kind == MessageType::ACK => {
+                acks.push((d.packet.seq.unwrap_or(0), d.packet.ts_ms))
+            }
+            _ => log::warn!("Ignoring non-ack datagram from {src}"),
+        }
+    }
+}
+
+/// Listens to a sender for the configured duration and checks its output.
+pub fn test_sender(cfg: &SenderTest) -> ExitCode {
+    let ip = cfg.addr.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    let (bind_ip, group_ip) = match ip {
+        IpAddr::V4(g) if g.is_multicast() => (IpAddr::V4(Ipv4Addr::UNSPECIFIED), Some(ip)),
+        IpAddr::V6(g) if g.is_multicast() => (IpAddr::V6(Ipv6Addr::UNSPECIFIED), Some(ip)),
+        _ => (ip, None),
+    };
+    let result = (|| {
+        let addr = SocketAddr::new(bind_ip, cfg.port);
+        let sock = UdpSocket::bind(addr)
+            .map_err(|e| format!("Failed to bind UDP socket on {addr}: {e}"))?;
+        match group_ip {
+            Some(IpAddr::V4(g)) => sock.join_multicast_v4(&g, &Ipv4Addr::UNSPECIFIED),
+            Some(IpAddr::V6(g)) => sock.join_multicast_v6(&g, 0),
+            None => Ok(()),
+        }
+        .map_err(|e| format!("Failed to join multicast group {ip}: {e}"))?;
+        sock.set_read_timeout(Some(Duration::from_millis(100)))
+            .map_err(|e| format!("Failed to set receive timeout: {e}"))?;
+
+        let mut grader = SenderGrader::default();
+        let until = Instant::now() + cfg.duration;
+        let mut buf = [0u8; 1500];
+        while Instant::now() < until {
+            match sock.recv_from(&mut buf) {
+                Ok((n, _)) => grader.observe(&buf[..n], now_ms()?, cfg.group, cfg.key.as_ref()),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(e) => return Err(format!("recv_from failed: {e}")),
+            }
+        }
+        Ok(grader.grade(cfg.max_offset_ms, cfg.key.is_some(), cfg.group.is_some()))
+    })();
+    match result {
+        Ok(outcomes) => report(&outcomes),
+        Err(e) => exit::fail(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What the reference decoder accepts must match each case's
+    /// expectation, or the suite would fail `recv --ack` itself.
+    fn reference_accepts(data: &[u8], key: Option<&Key>) -> bool {
+        match payload::decode(data) {
+            Ok(d) => d.packet.kind == MessageType::DATA && key.is_none_or(|k| d.verify(k)),
+            Err(_) => false,
+        }
+    }
+
+    #[test]
+    fn cases_match_reference_decoder() {
+        let key = Key::new(b"vendor");
+        for key in [None, Some(&key)] {
+            for case in receiver_cases(1_700_000_000_000, None, key) {
+                assert_eq!(
+                    reference_accepts(&case.data, key),
+                    case.accept,
+                    "{}",
+                    case.name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn grades_missing_and_unexpected_acks() {
+        let cases = receiver_cases(1_000, None, None);
+        let mut acks: Vec<_> = cases.iter().filter(|c| c.accept).map(|c| c.echo).collect();
+        assert!(grade_receiver(&cases, &acks)
+            .iter()
+            .all(|o| o.failure.is_none()));
+
+        acks.remove(0);
+        acks.push(cases.iter().find(|c| !c.accept).unwrap().echo);
+        let failed: Vec<_> = grade_receiver(&cases, &acks)
+            .into_iter()
+            .filter(|o| o.failure.is_some())
+            .map(|o| o.name)
+            .collect();
+        assert_eq!(
+            failed,
+            ["legacy_timestamp", "v2_unknown_message_type_ignored"]
+        );
+    }
+
+    #[test]
+    fn sender_grader_flags_sequence_and_clock_errors() {
+        let mut grader = SenderGrader::default();
+        let mut buf = Vec::new();
+        for (seq, ts) in [(1, 1_000), (2, 2_000), (2, 3_000), (4, 2_500)] {
+            let packet = Packet::new(Some(seq), ts);
+            payload::encode(Format::V2, &packet, None, &mut buf);
+            grader.observe(&buf, ts + 10, None, None);
+        }
+        grader.observe(&payload::V2_MAGIC, 0, None, None);
+
+        let failed: Vec<_> = grader
+            .grade(100, false, false)
+            .into_iter()
+            .filter(|o| o.failure.is_some())
+            .map(|o| o.name)
+            .collect();
+        assert_eq!(
+            failed,
+            [
+                "datagrams_decode",
+                "sequence_increases",
+                "timestamps_monotonic"
+            ]
+        );
+    }
+}
@@ -0,0 +1,229 @@
+//! Fleet reconfiguration over the beacon channel.
+//!
+//! `control` broadcasts an authenticated v2 datagram carrying a
+//! [`Control`] extension. Senders started with `--follow` listen for it on
+//! a control port and, once the requested time arrives, switch to the new
+//! interval with their send grid anchored at that time, so every follower
+//! ticks in step afterwards.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::process::ExitCode;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::auth::Key;
+use crate::exit::{self, Failure};
+use crate::log;
+use crate::message::MessageType;
+use crate::payload::{self, Control, Format, Packet};
+use crate::resolve::Target;
+use crate::send::bind_for;
+use crate::transmit;
+use crate::EXIT_CODE_CLOCK_ERROR;
+
+/// Copies of each command sent, to ride out loss; followers apply one.
+pub const DEFAULT_REPEAT: u32 = 3;
+/// Gap between repeated copies.
+const REPEAT_SPACING: Duration = Duration::from_millis(100);
+/// Commands whose timestamp is further than this from the follower's
+/// clock are ignored, so a captured command can't be replayed later.
+const MAX_AGE_MS: u64 = 60_000;
+
+/// Settings for the `control` subcommand.
+pub struct ControlConfig {
+    pub target: Target,
+    pub port: u16,
+    /// Only followers in this group apply the command; `None` addresses all.
+    pub group: Option<u16>,
+    pub key: Key,
+    pub control: Control,
+    pub repeat: u32,
+}
+
+/// Settings for a sender's `--follow` role.
+#[derive(Clone)]
+pub struct FollowConfig {
+    pub port: u16,
+    pub key: Key,
+}
+
+fn now_ms() -> Result<u64, Failure> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .map_err(|e| {
+            Failure::new(
+                EXIT_CODE_CLOCK_ERROR,
+                format!("System clock error (before UNIX_EPOCH): {e:?}"),
+            )
+        })
+}
+
+/// Sends one control command and exits.
+pub fn run(cfg: &ControlConfig) -> ExitCode {
+    let dest = match cfg.target.resolve(cfg.port, None) {
+        Ok(d) => d,
+        Err(e) => return exit::fail(e),
+    };
+    let sock = match bind_for(dest) {
+        Ok(s) => s,
+        Err(e) => return exit::fail(e),
+    };
+    let ts_ms = match now_ms() {
+        Ok(t) => t,
+        Err(e) => return exit::fail(e),
+    };
+
+    // Every copy is identical, so followers apply the command once
+    let packet = Packet {
+        kind: MessageType::CONTROL,
+        group: cfg.group,
+        control: Some(cfg.control),
+        ..Packet::new(Some(0), ts_ms)
+    };
+    let mut buf = Vec::with_capacity(64);
+    payload::encode(Format::V2, &packet, Some(&cfg.key), &mut buf);
+
+    let mut sent = 0;
+    for i in 0..cfg.repeat {
+        if i > 0 {
+            thread::sleep(REPEAT_SPACING);
+        }
+        match transmit::send_to(&sock, &buf, dest) {
+            Ok(_) => sent += 1,
+            Err(e) => log::warn!("send_to({dest}) failed: {e}"),
+        }
+    }
+    if sent == 0 {
+        return exit::fail(format!("Failed to send control to {dest}"));
+    }
+    log::info!(
+        "Sent control to {dest}: interval_ms={} at unix_ms={} ({sent} cop{})",
+        cfg.control.interval_ms,
+        cfg.control.apply_at_ms,
+        if sent == 1 { "y" } else { "ies" }
+    );
+    ExitCode::SUCCESS
+}
+
+/// Binds a non-blocking receive socket on `port`, in the address family of
+/// the beacon destination; used for control commands and by `--role`.
+pub fn bind_receiver(port: u16, family_of: SocketAddr) -> Result<UdpSocket, String> {
+    let ip = match family_of.ip() {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let addr = SocketAddr::new(ip, port);
+    let sock = UdpSocket::bind(addr)
+        .map_err(|e| format!("Failed to bind receive socket on {addr}: {e}"))?;
+    sock.set_nonblocking(true)
+        .map_err(|e| format!("Failed to configure receive socket: {e}"))?;
+    Ok(sock)
+}
+
+/// Decides which received commands a follower applies.
+pub struct Follower {
+    key: Key,
+    group: Option<u16>,
+    /// Timestamp of the newest command accepted so far.
+    last_ts_ms: Option<u64>,
+}
+
+impl Follower {
+    pub fn new(key: Key, group: Option<u16>) -> Follower {
+        Follower {
+            key,
+            group,
+            last_ts_ms: None,
+        }
+    }
+
+    /// Checks a received datagram. Returns the command it carries if it is
+    /// authentic, fresh, new and addressed to this follower; `Ok(None)` for
+    /// repeats and commands for other groups; and an error describing why
+    /// anything else was rejected.
+    pub fn accept(&mut self, data: &[u8], now_ms: u64) -> Result<Option<Control>, String> {
+        let decoded = payload::decode(data)?;
+        let packet = decoded.packet;
+        let control = packet
+            .control
+            .filter(|_| packet.kind == MessageType::CONTROL)
+            .ok_or_else(|| "not a control message".to_string())?;
+        if !decoded.verify(&self.key) {
+            return Err("authentication failed".to_string());
+        }
+        if packet.ts_ms.abs_diff(now_ms) > MAX_AGE_MS {
+            return Err(format!("stale command (ts_ms={})", packet.ts_ms));
+        }
+        if packet.group.is_some_and(|g| self.group != Some(g)) {
+            return Ok(None);
+        }
+        if self.last_ts_ms.is_some_and(|last| packet.ts_ms <= last) {
+            return Ok(None);
+        }
+        self.last_ts_ms = Some(packet.ts_ms);
+        Ok(Some(control))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000_000;
+
+    fn command(key: &Key, ts_ms: u64, group: Option<u16>) -> Vec<u8> {
+        let packet = Packet {
+            kind: MessageType::CONTROL,
+            group,
+            control: Some(Control {
+                apply_at_ms: ts_ms + 5_000,
+                interval_ms: 5_000,
+            }),
+            ..Packet::new(Some(0), ts_ms)
+        };
+        let mut buf = Vec::new();
+        payload::encode(Format::V2, &packet, Some(key), &mut buf);
+        buf
+    }
+
+    #[test]
+    fn accepts_each_command_once() {
+        let key = Key::new(b"fleet");
+        let mut follower = Follower::new(key.clone(), None);
+        let data = command(&key, NOW, None);
+
+        let control = follower.accept(&data, NOW).unwrap().unwrap();
+        assert_eq!(control.interval_ms, 5_000);
+        assert_eq!(follower.accept(&data, NOW), Ok(None));
+        assert!(follower
+            .accept(&command(&key, NOW + 1, None), NOW)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn rejects_wrong_key_and_stale_commands() {
+        let key = Key::new(b"fleet");
+        let mut follower = Follower::new(key.clone(), None);
+
+        assert!(follower
+            .accept(&command(&Key::new(b"other"), NOW, None), NOW)
+            .is_err());
+        assert!(follower
+            .accept(&command(&key, NOW - MAX_AGE_MS - 1, None), NOW)
+            .is_err());
+    }
+
+    #[test]
+    fn ignores_commands_for_other_groups() {
+        let key = Key::new(b"fleet");
+        let mut follower = Follower::new(key.clone(), Some(7));
+
+        assert_eq!(follower.accept(&command(&key, NOW, Some(8)), NOW), Ok(None));
+        assert!(follower
+            .accept(&command(&key, NOW, Some(7)), NOW)
+            .unwrap()
+            .is_some());
+    }
+}
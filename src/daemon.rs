@@ -0,0 +1,531 @@
+//! Running in the background for SysV-style init scripts.
+//!
+//! `--daemon` detaches the process the classic way: fork, `setsid`, fork
+//! again so it can never reacquire a controlling terminal, and point stdin
+//! at `/dev/null` and stdout and stderr at `--log-file` (or `/dev/null`).
+//! The working directory is kept, so relative paths such as a `--record`
+//! file still resolve. The original process waits until the daemon has
+//! written its `--pid-file` and exits 0, or reports why it couldn't.
+//!
+//! Detaching has to happen before any thread is started, since only the
+//! forking thread survives in the child; `main` does it right after
+//! parsing the command line. A pid file naming a process that is still
+//! running is refused, and a stale one is replaced.
+//!
+//! A `--log-file` that rotates, or is written as JSON, is fed through
+//! pipes by a [`Pump`] instead; see [`logfile`](crate::logfile). Windows
+//! can't detach this way, but a service needs `--log-file` just the same,
+//! so there the standard handles are swapped for the file or the pipes.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::exit::Failure;
+use crate::logfile::{LogFile, LogFormat, Pump, Rotation};
+
+/// How to detach, and where to leave the pid and the output.
+#[derive(Clone, Debug, Default)]
+pub struct DaemonConfig {
+    /// Fork into the background.
+    pub detach: bool,
+    /// File to write the daemon's process ID to.
+    pub pid_file: Option<PathBuf>,
+    /// File to append stdout and stderr to.
+    pub log_file: Option<PathBuf>,
+    /// When to rotate the log file.
+    pub rotation: Rotation,
+    /// How lines are written to the log file.
+    pub log_format: LogFormat,
+}
+
+/// Which side of the fork this is.
+pub enum Role {
+    /// The process that was started, which should exit with the code.
+    Launcher(u8),
+    /// The process that carries on, removing its pid file when dropped.
+    Daemon(Running),
+}
+
+/// What the daemon cleans up on the way out.
+pub struct Running {
+    log: Option<Pump>,
+    _pid_file: PidFile,
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        if let Some(pump) = self.log.take() {
+            // Closes the pipes, so the pump sees the last lines and stops
+            let _ = io::stdout().flush();
+            let _ = imp::close_output();
+            pump.finish(Duration::from_secs(1));
+        }
+    }
+}
+
+/// A pid file this process wrote.
+pub struct PidFile(Option<PathBuf>);
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Some(path) = &self.0 {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// The process ID recorded in `path`, if that process is still running.
+fn running_pid(path: &Path) -> Option<u32> {
+    let pid: u32 = fs::read_to_string(path).ok()?.trim().parse().ok()?;
+    (pid != std::process::id() && imp::alive(pid)).then_some(pid)
+}
+
+fn write_pid(path: &Path) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, format!("{}\n", std::process::id()))?;
+    // Renamed into place so that a reader never sees it half-written
+    fs::rename(&tmp, path)
+}
+
+fn pid_error(path: &Path, e: &io::Error) -> String {
+    format!("Failed to write pid file {}: {e}", path.display())
+}
+
+/// Where stdout and stderr go.
+enum Output {
+    /// Straight into the file.
+    Append(File),
+    /// Through a pump into a rotated file.
+    Rotate(LogFile),
+}
+
+fn open_log(cfg: &DaemonConfig, path: &Path) -> Result<Output, Failure> {
+    let rotate = cfg.rotation.active() || cfg.log_format == LogFormat::Json;
+    let opened = if rotate {
+        LogFile::open(path, cfg.rotation, cfg.log_format).map(Output::Rotate)
+    } else {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map(Output::Append)
+    };
+    opened.map_err(|e| {
+        Failure::io(
+            &e,
+            format!("Failed to open log file {}: {e}", path.display()),
+        )
+    })
+}
+
+/// Points stdout and stderr at `log`, and stdin at /dev/null if
+/// `detached`, starting the pump for a rotated log.
+fn redirect(log: Option<Output>, detached: bool) -> Result<Option<Pump>, String> {
+    let file = match &log {
+        Some(Output::Append(file)) => Some(file),
+        _ => None,
+    };
+    let redirected = match (detached, file) {
+        (true, file) => imp::redirect(file),
+        (false, Some(file)) => imp::redirect_output(file),
+        (false, None) => Ok(()),
+    };
+    let pump = redirected.and_then(|()| match log {
+        Some(Output::Rotate(log)) => {
+            let [stdout, stderr] = imp::pipe_output()?;
+            Ok(Some(Pump::start(log, stdout, stderr)))
+        }
+        _ => Ok(None),
+    });
+    pump.map_err(|e| format!("Failed to redirect output: {e}"))
+}
+
+/// Detaches if `cfg.detach`, redirects output and writes the pid file.
+/// Returns [`Role::Launcher`] in the process that was started when it
+/// detached, and [`Role::Daemon`] in the one that should go on running.
+pub fn start(cfg: &DaemonConfig) -> Result<Role, Failure> {
+    if let Some(path) = &cfg.pid_file {
+        if let Some(pid) = running_pid(path) {
+            let msg = format!(
+                "Already running as process {pid} (pid file {})",
+                path.display()
+            );
+            return Err(msg.into());
+        }
+    }
+    // Opened first, so that a bad path is reported on the terminal
+    let log = match &cfg.log_file {
+        Some(path) => Some(open_log(cfg, path)?),
+        None => None,
+    };
+
+    let pump = if cfg.detach {
+        match imp::detach()? {
+            imp::Side::Launcher(code) => return Ok(Role::Launcher(code)),
+            imp::Side::Daemon(ready) => {
+                let started = match &cfg.pid_file {
+                    Some(path) => write_pid(path).map_err(|e| pid_error(path, &e)),
+                    None => Ok(()),
+                }
+                .and_then(|()| redirect(log, true));
+                match started {
+                    Ok(pump) => {
+                        ready.done();
+                        pump
+                    }
+                    Err(e) => {
+                        ready.fail(&e);
+                        imp::exit(1);
+                    }
+                }
+            }
+        }
+    } else {
+        if let Some(path) = &cfg.pid_file {
+            write_pid(path).map_err(|e| Failure::io(&e, pid_error(path, &e)))?;
+        }
+        redirect(log, false)?
+    };
+    Ok(Role::Daemon(Running {
+        log: pump,
+        _pid_file: PidFile(cfg.pid_file.clone()),
+    }))
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::fs::File;
+    use std::io::{self, Read, Write};
+    use std::os::fd::{AsRawFd, FromRawFd};
+
+    use crate::exit::Failure;
+
+    /// The write end of the pipe the launcher waits on.
+    pub struct Ready(File);
+
+    impl Ready {
+        /// Tells the launcher the daemon is up.
+        pub fn done(self) {}
+
+        /// Tells the launcher why the daemon couldn't start.
+        pub fn fail(mut self, msg: &str) {
+            let _ = self.0.write_all(msg.as_bytes());
+        }
+    }
+
+    pub enum Side {
+        Launcher(u8),
+        Daemon(Ready),
+    }
+
+    pub fn alive(pid: u32) -> bool {
+        let Ok(pid) = libc::pid_t::try_from(pid) else {
+            return false;
+        };
+        // SAFETY: signal 0 only checks that the process exists.
+        let rc = unsafe { libc::kill(pid, 0) };
+        // EPERM: it exists, but belongs to someone else
+        rc == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+
+    fn check(rc: libc::c_int) -> io::Result<libc::c_int> {
+        if rc < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(rc)
+        }
+    }
+
+    pub fn exit(code: i32) -> ! {
+        // SAFETY: ends this process without running the parent's
+        // destructors or atexit handlers a second time.
+        unsafe { libc::_exit(code) }
+    }
+
+    pub fn detach() -> Result<Side, Failure> {
+        let mut fds = [0; 2];
+        // SAFETY: `fds` has room for the two descriptors pipe(2) returns.
+        check(unsafe { libc::pipe(fds.as_mut_ptr()) })
+            .map_err(|e| format!("Failed to create pipe: {e}"))?;
+        // SAFETY: both descriptors are fresh and owned only here.
+        let (read, write) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+        // SAFETY: no other thread is running yet, so the child starts
+        // from a consistent copy of the process.
+        match check(unsafe { libc::fork() }).map_err(|e| format!("Failed to fork: {e}"))? {
+            0 => {}
+            _ => {
+                drop(write);
+                return Ok(Side::Launcher(wait_ready(read)));
+            }
+        }
+        drop(read);
+        let ready = Ready(write);
+        // SAFETY: plain setsid(2) and fork(2) calls in the single-threaded child.
+        let forked = check(unsafe { libc::setsid() })
+            .and_then(|_| check(unsafe { libc::fork() }))
+            .map_err(|e| format!("Failed to detach: {e}"));
+        match forked {
+            Ok(0) => Ok(Side::Daemon(ready)),
+            // The session leader goes away, leaving the grandchild unable
+            // to acquire a terminal
+            Ok(_) => exit(0),
+            Err(e) => {
+                ready.fail(&e);
+                exit(1)
+            }
+        }
+    }
+
+    /// Waits for the daemon to finish starting, and returns the code the
+    /// launcher should exit with.
+    fn wait_ready(mut read: File) -> u8 {
+        let mut msg = String::new();
+        let _ = read.read_to_string(&mut msg);
+        if msg.is_empty() {
+            0
+        } else {
+            crate::exit::report(&Failure::from(msg));
+            crate::EXIT_CODE_RUNTIME_ERROR
+        }
+    }
+
+    /// Points stdin at /dev/null, and stdout and stderr at `log` or /dev/null.
+    pub fn redirect(log: Option<&File>) -> io::Result<()> {
+        let null = File::options().read(true).write(true).open("/dev/null")?;
+        // SAFETY: dup2 onto the standard descriptors, from descriptors
+        // that stay open for the duration of the call.
+        check(unsafe { libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO) })?;
+        redirect_output(log.unwrap_or(&null))
+    }
+
+    pub fn redirect_output(log: &File) -> io::Result<()> {
+        for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+            // SAFETY: as above.
+            check(unsafe { libc::dup2(log.as_raw_fd(), fd) })?;
+        }
+        Ok(())
+    }
+
+    /// Points stdout and stderr at a pipe each, returning the read ends.
+    pub fn pipe_output() -> io::Result<[File; 2]> {
+        let mut read = Vec::with_capacity(2);
+        for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+            let mut fds = [0; 2];
+            // SAFETY: `fds` has room for the two descriptors pipe(2) returns.
+            check(unsafe { libc::pipe(fds.as_mut_ptr()) })?;
+            // SAFETY: both descriptors are fresh and owned only here; the
+            // write end lives on only as `fd`.
+            let (r, w) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+            check(unsafe { libc::dup2(w.as_raw_fd(), fd) })?;
+            read.push(r);
+        }
+        Ok([read.remove(0), read.remove(0)])
+    }
+
+    /// Points stdout and stderr at /dev/null, closing whatever they were.
+    pub fn close_output() -> io::Result<()> {
+        let null = File::options().write(true).open("/dev/null")?;
+        redirect_output(&null)
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::fs::File;
+    use std::io;
+    use std::os::windows::io::{FromRawHandle, IntoRawHandle};
+    use std::ptr;
+
+    use windows_sys::Win32::Foundation::{CloseHandle, HANDLE};
+    use windows_sys::Win32::System::Console::{
+        GetStdHandle, SetStdHandle, STD_ERROR_HANDLE, STD_HANDLE, STD_OUTPUT_HANDLE,
+    };
+    use windows_sys::Win32::System::Pipes::CreatePipe;
+
+    use crate::exit::Failure;
+
+    pub enum Ready {}
+
+    impl Ready {
+        pub fn done(self) {
+            match self {}
+        }
+
+        pub fn fail(self, _msg: &str) {
+            match self {}
+        }
+    }
+
+    pub enum Side {
+        Launcher(u8),
+        Daemon(Ready),
+    }
+
+    pub fn alive(_pid: u32) -> bool {
+        // Without a way to tell, assume a recorded process is gone
+        false
+    }
+
+    pub fn exit(code: i32) -> ! {
+        std::process::exit(code)
+    }
+
+    pub fn detach() -> Result<Side, Failure> {
+        Err("--daemon is only supported on Unix; see --service"
+            .to_string()
+            .into())
+    }
+
+    pub fn redirect(log: Option<&File>) -> io::Result<()> {
+        match log {
+            Some(log) => redirect_output(log),
+            None => Ok(()),
+        }
+    }
+
+    /// Makes `handle` the standard handle `which`, closing the one before
+    /// if `close` (one this module set).
+    fn set_std(which: STD_HANDLE, handle: HANDLE, close: bool) -> io::Result<()> {
+        // SAFETY: Get/SetStdHandle only read and replace a process-wide slot.
+        let old = unsafe { GetStdHandle(which) };
+        if unsafe { SetStdHandle(which, handle) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if close && !old.is_null() {
+            // SAFETY: the old handle was created here and is no longer
+            // reachable through the standard handle.
+            unsafe { CloseHandle(old) };
+        }
+        Ok(())
+    }
+
+    /// Standard output and error are looked up for every write, so setting
+    /// them redirects the process's output from then on.
+    pub fn redirect_output(log: &File) -> io::Result<()> {
+        for which in [STD_OUTPUT_HANDLE, STD_ERROR_HANDLE] {
+            // A handle of its own for each, which the process keeps
+            let handle = log.try_clone()?.into_raw_handle() as HANDLE;
+            set_std(which, handle, false)?;
+        }
+        Ok(())
+    }
+
+    /// Points stdout and stderr at a pipe each, returning the read ends.
+    pub fn pipe_output() -> io::Result<[File; 2]> {
+        let mut read = Vec::with_capacity(2);
+        for which in [STD_OUTPUT_HANDLE, STD_ERROR_HANDLE] {
+            let (mut r, mut w): (HANDLE, HANDLE) = (ptr::null_mut(), ptr::null_mut());
+            // SAFETY: both out-pointers are valid; default security and size.
+            if unsafe { CreatePipe(&mut r, &mut w, ptr::null(), 0) } == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: `r` is a fresh handle owned only here.
+            read.push(unsafe { File::from_raw_handle(r as _) });
+            set_std(which, w, false)?;
+        }
+        Ok([read.remove(0), read.remove(0)])
+    }
+
+    /// Points stdout and stderr at NUL, closing the pipes they were.
+    pub fn close_output() -> io::Result<()> {
+        let null = File::options().write(true).open("NUL")?;
+        for which in [STD_OUTPUT_HANDLE, STD_ERROR_HANDLE] {
+            let handle = null.try_clone()?.into_raw_handle() as HANDLE;
+            set_std(which, handle, true)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod imp {
+    use std::fs::File;
+    use std::io;
+
+    use crate::exit::Failure;
+
+    pub enum Ready {}
+
+    impl Ready {
+        pub fn done(self) {
+            match self {}
+        }
+
+        pub fn fail(self, _msg: &str) {
+            match self {}
+        }
+    }
+
+    pub enum Side {
+        Launcher(u8),
+        Daemon(Ready),
+    }
+
+    pub fn alive(_pid: u32) -> bool {
+        // Without a way to tell, assume a recorded process is gone
+        false
+    }
+
+    pub fn exit(code: i32) -> ! {
+        std::process::exit(code)
+    }
+
+    pub fn detach() -> Result<Side, Failure> {
+        Err("--daemon is only supported on Unix".to_string().into())
+    }
+
+    pub fn redirect(_log: Option<&File>) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn redirect_output(_log: &File) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "--log-file is only supported on Unix",
+        ))
+    }
+
+    pub fn pipe_output() -> io::Result<[File; 2]> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "--log-file is only supported on Unix",
+        ))
+    }
+
+    pub fn close_output() -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_a_pid_file_of_a_running_process() {
+        let dir = std::env::temp_dir().join(format!("udp_bcast_ts-daemon-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("beacon.pid");
+
+        // Stale: no process has that ID
+        fs::write(&path, "999999999\n").unwrap();
+        assert_eq!(running_pid(&path), None);
+        // Our own ID is what a daemon rewriting its file would see
+        write_pid(&path).unwrap();
+        assert_eq!(running_pid(&path), None);
+        // The parent of the test runner is certainly running
+        let parent = std::os::unix::process::parent_id();
+        fs::write(&path, format!("{parent}\n")).unwrap();
+        assert_eq!(running_pid(&path), Some(parent));
+        let cfg = DaemonConfig {
+            pid_file: Some(path.clone()),
+            ..DaemonConfig::default()
+        };
+        assert!(start(&cfg).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,217 @@
+//! Slewing the local clock toward one sender (`recv --discipline`).
+//!
+//! On an isolated segment where a broadcaster is the only reference, the
+//! receiver can act as a very small NTP client: it keeps the lowest offset
+//! seen from that sender over each `--discipline-interval`, the sample
+//! least inflated by network and queueing delay, and hands it to the
+//! kernel's PLL with `adjtimex(2)`. The kernel then slews the clock
+//! gradually; it is never stepped.
+//!
+//! The bounds are deliberately tight. A window needs [`MIN_SAMPLES`]
+//! samples, and an offset beyond `--discipline-max-offset-ms` (at most
+//! 500 ms, the most the kernel will slew by) is refused with a warning
+//! rather than applied: a clock that far off needs setting by hand, and a
+//! sender that far off shouldn't be followed. Timestamps have millisecond
+//! resolution and the minimum one-way delay can't be measured, so the
+//! local clock settles about that delay behind the sender, to within a
+//! millisecond.
+//!
+//! Only Linux has the interface, and setting it needs `CAP_SYS_TIME`. Don't
+//! run it alongside ntpd or chrony, which would fight over the clock.
+
+use std::io;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Samples a window needs before its offset is trusted.
+pub const MIN_SAMPLES: u32 = 3;
+
+/// Settings for disciplining the clock.
+#[derive(Clone, Debug)]
+pub struct DisciplineConfig {
+    /// The sender to follow; everything else is ignored.
+    pub source: IpAddr,
+    /// Largest offset that will be slewed away.
+    pub max_offset: Duration,
+    /// How long to collect samples for each adjustment.
+    pub interval: Duration,
+}
+
+/// What to do at the end of a window.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Slew by the negation of this offset, in microseconds.
+    Slew { offset_us: i64, samples: u32 },
+    /// The offset is beyond the limit; leave the clock alone.
+    Refuse { offset_us: i64 },
+    /// Too few samples arrived to go by.
+    Skip { samples: u32 },
+}
+
+/// The per-window minimum filter.
+pub struct Discipline {
+    cfg: DisciplineConfig,
+    window_end: Option<Instant>,
+    best_us: Option<i64>,
+    samples: u32,
+}
+
+impl Discipline {
+    pub fn new(cfg: DisciplineConfig) -> Discipline {
+        Discipline {
+            cfg,
+            window_end: None,
+            best_us: None,
+            samples: 0,
+        }
+    }
+
+    pub fn source(&self) -> IpAddr {
+        self.cfg.source
+    }
+
+    /// The PLL time constant matching the interval: the kernel expects
+    /// roughly 2^(constant + 4) seconds between updates.
+    pub fn time_constant(&self) -> i64 {
+        let secs = self.cfg.interval.as_secs().max(1);
+        (i64::from(secs.ilog2()) - 4).clamp(0, 10)
+    }
+
+    /// Takes one sample, local clock minus the sender's, and returns what
+    /// to do once its window is over. The first sample opens a window.
+    pub fn observe(&mut self, offset_us: i64, now: Instant) -> Option<Action> {
+        let end = *self.window_end.get_or_insert(now + self.cfg.interval);
+        let action = if now >= end {
+            self.window_end = Some(now + self.cfg.interval);
+            let samples = std::mem::take(&mut self.samples);
+            Some(match self.best_us.take() {
+                _ if samples < MIN_SAMPLES => Action::Skip { samples },
+                Some(us) if us.unsigned_abs() > self.cfg.max_offset.as_micros() as u64 => {
+                    Action::Refuse { offset_us: us }
+                }
+                Some(us) => Action::Slew {
+                    offset_us: us,
+                    samples,
+                },
+                None => Action::Skip { samples },
+            })
+        } else {
+            None
+        };
+        self.samples += 1;
+        self.best_us = Some(self.best_us.map_or(offset_us, |b| b.min(offset_us)));
+        action
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::io;
+    use std::mem;
+
+    fn adjtimex(tx: &mut libc::timex) -> io::Result<()> {
+        // SAFETY: `tx` is a valid, exclusively borrowed timex.
+        if unsafe { libc::adjtimex(tx) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn check() -> io::Result<()> {
+        // SAFETY: timex is plain data, for which all zeroes is valid.
+        let mut tx: libc::timex = unsafe { mem::zeroed() };
+        adjtimex(&mut tx)?;
+        // Writing the status back unchanged takes the same privilege as
+        // any other change
+        tx.modes = libc::ADJ_STATUS;
+        adjtimex(&mut tx)
+    }
+
+    pub fn slew(offset_us: i64, time_constant: i64) -> io::Result<()> {
+        // SAFETY: as above.
+        let mut tx: libc::timex = unsafe { mem::zeroed() };
+        tx.modes = libc::ADJ_OFFSET
+            | libc::ADJ_STATUS
+            | libc::ADJ_TIMECONST
+            | libc::ADJ_MAXERROR
+            | libc::ADJ_ESTERROR;
+        tx.offset = offset_us as _;
+        // PLL mode, and no longer unsynchronized
+        tx.status = libc::STA_PLL;
+        tx.constant = time_constant as _;
+        tx.maxerror = offset_us.unsigned_abs() as _;
+        tx.esterror = offset_us.unsigned_abs() as _;
+        adjtimex(&mut tx)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use std::io;
+
+    fn unsupported() -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            "disciplining the clock is only supported on Linux",
+        )
+    }
+
+    pub fn check() -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    pub fn slew(_offset_us: i64, _time_constant: i64) -> io::Result<()> {
+        Err(unsupported())
+    }
+}
+
+/// Checks that the clock can be adjusted, before privileges are dropped.
+pub fn check() -> io::Result<()> {
+    imp::check()
+}
+
+/// Starts the kernel slewing the clock by `offset_us`, positive to advance
+/// it, with the given PLL time constant.
+pub fn slew(offset_us: i64, time_constant: i64) -> io::Result<()> {
+    imp::slew(offset_us, time_constant)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slews_by_each_windows_lowest_offset_within_bounds() {
+        let mut d = Discipline::new(DisciplineConfig {
+            source: "192.0.2.1".parse().unwrap(),
+            max_offset: Duration::from_millis(100),
+            interval: Duration::from_secs(16),
+        });
+        assert_eq!(d.time_constant(), 0);
+        let t0 = Instant::now();
+        let at = |s: u64| t0 + Duration::from_secs(s);
+
+        for (s, us) in [(0, 4_000), (5, 2_500), (10, 9_000)] {
+            assert_eq!(d.observe(us, at(s)), None);
+        }
+        assert_eq!(
+            d.observe(3_000, at(16)),
+            Some(Action::Slew {
+                offset_us: 2_500,
+                samples: 3
+            })
+        );
+        // The sample that closed the last window opens this one
+        assert_eq!(d.observe(-1_000, at(20)), None);
+        assert_eq!(d.observe(0, at(32)), Some(Action::Skip { samples: 2 }));
+        for s in [33, 34, 35] {
+            d.observe(-150_000, at(s));
+        }
+        assert_eq!(
+            d.observe(0, at(48)),
+            Some(Action::Refuse {
+                offset_us: -150_000
+            })
+        );
+    }
+}
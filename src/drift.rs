@@ -0,0 +1,228 @@
+//! Long-term clock drift tracking.
+//!
+//! In listen mode, [`DriftLog`] appends one sample per sender per window:
+//! the minimum offset seen in that window, which filters out most queuing
+//! delay. The log is plain text, one `<unix_ms> <sender> <offset_ms>` line
+//! per sample, so it survives restarts and can span days.
+//!
+//! `--mode analyze` fits a line through each sender's samples to estimate
+//! its drift rate, and optionally forecasts when the offset will cross a
+//! bound if the clock is left uncorrected.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::process::ExitCode;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::exit;
+use crate::log;
+use crate::units;
+
+/// Default length of the window each drift sample summarizes.
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(60);
+
+/// The minimum offset seen from one sender in the current window.
+struct Window {
+    start_ms: u64,
+    min_offset_ms: i64,
+}
+
+/// Appends per-sender drift samples to a log file.
+pub struct DriftLog {
+    file: File,
+    path: String,
+    window_ms: u64,
+    senders: HashMap<String, Window>,
+}
+
+impl DriftLog {
+    pub fn open(path: &str, window: Duration) -> Result<DriftLog, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("Failed to open drift log {path}: {e}"))?;
+        Ok(DriftLog {
+            file,
+            path: path.to_string(),
+            window_ms: window.as_millis() as u64,
+            senders: HashMap::new(),
+        })
+    }
+
+    /// Records an observation, writing out the sender's previous window
+    /// once it has closed.
+    pub fn observe(&mut self, sender: &str, recv_ms: u64, offset_ms: i64) -> Result<(), String> {
+        let window_ms = self.window_ms;
+        let closed = match self.senders.get_mut(sender) {
+            Some(w) if recv_ms < w.start_ms + window_ms => {
+                w.min_offset_ms = w.min_offset_ms.min(offset_ms);
+                None
+            }
+            Some(w) => Some(std::mem::replace(
+                w,
+                Window {
+                    start_ms: recv_ms,
+                    min_offset_ms: offset_ms,
+                },
+            )),
+            None => {
+                self.senders.insert(
+                    sender.to_string(),
+                    Window {
+                        start_ms: recv_ms,
+                        min_offset_ms: offset_ms,
+                    },
+                );
+                None
+            }
+        };
+
+        if let Some(w) = closed {
+            writeln!(self.file, "{} {sender} {}", w.start_ms, w.min_offset_ms)
+                .map_err(|e| format!("Failed to write drift log {}: {e}", self.path))?;
+        }
+        Ok(())
+    }
+
+    /// Drops the sender's unfinished window instead of writing it out.
+    pub fn forget(&mut self, sender: &str) {
+        self.senders.remove(sender);
+    }
+}
+
+/// A least-squares line `y = intercept + slope * x`.
+#[derive(Clone, Copy, Debug)]
+pub struct Fit {
+    pub slope: f64,
+    pub intercept: f64,
+}
+
+/// Fits a line through `(x, y)` points; needs at least two distinct x values.
+pub fn linear_fit(points: &[(f64, f64)]) -> Option<Fit> {
+    let n = points.len() as f64;
+    if points.len() < 2 {
+        return None;
+    }
+    let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+    let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+    let (mut sxx, mut sxy) = (0.0, 0.0);
+    for &(x, y) in points {
+        sxx += (x - mean_x) * (x - mean_x);
+        sxy += (x - mean_x) * (y - mean_y);
+    }
+    if sxx == 0.0 {
+        return None;
+    }
+    let slope = sxy / sxx;
+    Some(Fit {
+        slope,
+        intercept: mean_y - slope * mean_x,
+    })
+}
+
+/// Settings for analyze mode.
+pub struct AnalyzeConfig {
+    pub drift_log: String,
+    /// Offset bound to forecast against, if forecasting.
+    pub forecast_bound_ms: Option<u64>,
+}
+
+/// Reads a drift log and reports each sender's drift, plus a forecast.
+pub fn analyze(cfg: &AnalyzeConfig) -> ExitCode {
+    let file = match File::open(&cfg.drift_log) {
+        Ok(f) => f,
+        Err(e) => return exit::fail(format!("Failed to open drift log {}: {e}", cfg.drift_log)),
+    };
+
+    let mut samples: BTreeMap<String, Vec<(f64, f64)>> = BTreeMap::new();
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line = match line {
+            Ok(l) => l,
+            Err(e) => {
+                return exit::fail(format!("Failed to read drift log {}: {e}", cfg.drift_log))
+            }
+        };
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let parsed = match fields[..] {
+            [t, sender, offset] => t
+                .parse::<u64>()
+                .ok()
+                .zip(offset.parse::<i64>().ok())
+                .map(|(t, o)| (sender, t, o)),
+            _ => None,
+        };
+        match parsed {
+            Some((sender, t, o)) => samples
+                .entry(sender.to_string())
+                .or_default()
+                .push((t as f64, o as f64)),
+            None => log::warn!("Skipping malformed drift log line {}", n + 1),
+        }
+    }
+
+    let now_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |d| d.as_millis() as f64);
+
+    for (sender, points) in &samples {
+        let span_h = (points.last().unwrap().0 - points[0].0) / 3_600_000.0;
+        let fit = match linear_fit(points) {
+            Some(f) => f,
+            None => {
+                println!(
+                    "{sender}: samples={} (not enough to estimate drift)",
+                    points.len()
+                );
+                continue;
+            }
+        };
+        // Slope is ms of offset gained per ms elapsed
+        let drift_ppm = fit.slope * 1e6;
+        let offset_now = fit.intercept + fit.slope * now_ms;
+        println!(
+            "{sender}: samples={} span_h={span_h:.1} drift_ppm={drift_ppm:.3} {}",
+            points.len(),
+            units::pair("offset_now_ms", units::millis_f64(offset_now))
+        );
+
+        if let Some(bound) = cfg.forecast_bound_ms {
+            let bound = bound as f64;
+            if offset_now.abs() >= bound {
+                println!("{sender}: forecast: offset already exceeds {bound} ms");
+            } else if fit.slope == 0.0 {
+                println!("{sender}: forecast: no drift, bound of {bound} ms never reached");
+            } else {
+                let target = bound.copysign(fit.slope);
+                let at_ms = (target - fit.intercept) / fit.slope;
+                let days = (at_ms - now_ms) / 86_400_000.0;
+                println!(
+                    "{sender}: forecast: exceeds {bound} ms in {days:.1} days (at unix_ms={})",
+                    at_ms as u64
+                );
+            }
+        }
+    }
+
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn linear_fit_recovers_slope_and_intercept() {
+        let points: Vec<(f64, f64)> = (0..10).map(|i| (i as f64, 3.0 + 0.5 * i as f64)).collect();
+        let fit = linear_fit(&points).unwrap();
+        assert!((fit.slope - 0.5).abs() < 1e-9);
+        assert!((fit.intercept - 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn linear_fit_needs_distinct_x() {
+        assert!(linear_fit(&[(1.0, 2.0)]).is_none());
+        assert!(linear_fit(&[(1.0, 2.0), (1.0, 3.0)]).is_none());
+    }
+}
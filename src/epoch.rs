@@ -0,0 +1,156 @@
+//! Time scales the 64-bit timestamp can be sent in (`--epoch`).
+//!
+//! * `unix`: milliseconds since 1970-01-01 UTC, the default.
+//! * `ntp`: a 64-bit NTP timestamp, seconds since 1900-01-01 in the upper
+//!   32 bits and the fraction of a second in the lower 32, as in RFC 5905.
+//!   The seconds wrap in February 2036; values with the top bit clear are
+//!   read as the era after that, so the scale works until 2104.
+//! * `gps`: milliseconds of GPS time since 1980-01-06. GPS time doesn't
+//!   count leap seconds, so it runs [`GPS_UTC_OFFSET_S`] seconds ahead of
+//!   UTC; the offset is fixed, as no leap second has been scheduled since
+//!   2017 and they are to stop altogether by 2035.
+//!
+//! Internally every timestamp is Unix milliseconds: senders convert just
+//! before encoding and receivers just after decoding, so offsets and
+//! everything built on them work the same in any scale.
+
+use std::fmt;
+
+/// Seconds from the NTP era (1900) to the Unix epoch.
+const NTP_UNIX_OFFSET_S: u64 = 2_208_988_800;
+/// Milliseconds from the Unix epoch to the GPS epoch, 1980-01-06.
+const GPS_UNIX_OFFSET_MS: u64 = 315_964_800_000;
+/// GPS time minus UTC, in seconds, since 2017-01-01.
+pub const GPS_UTC_OFFSET_S: u64 = 18;
+
+/// The scale of a payload's timestamp.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Epoch {
+    #[default]
+    Unix,
+    Ntp,
+    Gps,
+}
+
+impl Epoch {
+    /// Parses an `--epoch` value.
+    pub fn parse(s: &str) -> Result<Epoch, String> {
+        match s {
+            "unix" => Ok(Epoch::Unix),
+            "ntp" => Ok(Epoch::Ntp),
+            "gps" => Ok(Epoch::Gps),
+            _ => Err("expected unix, ntp or gps".to_string()),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Epoch::Unix => "unix",
+            Epoch::Ntp => "ntp",
+            Epoch::Gps => "gps",
+        }
+    }
+
+    /// Expresses `unix_ms` in this scale.
+    pub fn value_of(self, unix_ms: u64) -> u64 {
+        match self {
+            Epoch::Unix => unix_ms,
+            Epoch::Ntp => {
+                // Truncated to the era
+                let secs = (unix_ms / 1000 + NTP_UNIX_OFFSET_S) as u32;
+                let frac = ((unix_ms % 1000) << 32).div_ceil(1000);
+                u64::from(secs) << 32 | frac
+            }
+            Epoch::Gps => unix_ms
+                .saturating_add(GPS_UTC_OFFSET_S * 1000)
+                .saturating_sub(GPS_UNIX_OFFSET_MS),
+        }
+    }
+
+    /// Converts `value`, in this scale, back to Unix milliseconds; `None`
+    /// if that is past what a u64 holds.
+    pub fn to_unix_ms(self, value: u64) -> Option<u64> {
+        match self {
+            Epoch::Unix => Some(value),
+            Epoch::Ntp => {
+                let mut secs = value >> 32;
+                if secs < 1 << 31 {
+                    // Era 1 starts on 2036-02-07
+                    secs += 1 << 32;
+                }
+                let ms = ((value & 0xffff_ffff) * 1000) >> 32;
+                Some(secs.saturating_sub(NTP_UNIX_OFFSET_S) * 1000 + ms)
+            }
+            Epoch::Gps => Some(
+                value
+                    .checked_add(GPS_UNIX_OFFSET_MS)?
+                    .saturating_sub(GPS_UTC_OFFSET_S * 1000),
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Epoch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::{self, Format, Packet};
+
+    #[test]
+    fn converts_between_scales() {
+        let unix_ms = 1_700_000_000_250;
+        // 2023-11-14T22:13:20.25Z: NTP seconds 3_908_988_800, a quarter second
+        let ntp = Epoch::Ntp.value_of(unix_ms);
+        assert_eq!(ntp, 3_908_988_800 << 32 | 0x4000_0000);
+        // The GPS week count of that day is 2288
+        let gps = Epoch::Gps.value_of(unix_ms);
+        assert_eq!(gps / (7 * 86_400_000), 2288);
+        assert_eq!(gps, unix_ms - 315_964_800_000 + 18_000);
+        for epoch in [Epoch::Unix, Epoch::Ntp, Epoch::Gps] {
+            for ms in [unix_ms, unix_ms + 1, unix_ms + 999] {
+                assert_eq!(epoch.to_unix_ms(epoch.value_of(ms)), Some(ms), "{epoch}");
+            }
+        }
+        // After the NTP seconds wrap
+        let after_2036 = 2_200_000_000_000;
+        assert!(Epoch::Ntp.value_of(after_2036) >> 32 < 1 << 31);
+        assert_eq!(
+            Epoch::Ntp.to_unix_ms(Epoch::Ntp.value_of(after_2036)),
+            Some(after_2036)
+        );
+        // GPS time near u64::MAX has no Unix equivalent
+        assert_eq!(Epoch::Gps.to_unix_ms(u64::MAX), None);
+        assert_eq!(Epoch::Gps.value_of(u64::MAX), u64::MAX - GPS_UNIX_OFFSET_MS);
+
+        // Payloads carry the scale and decode back to Unix time
+        let packet = Packet {
+            epoch: Epoch::Ntp,
+            ..Packet::new(Some(1), unix_ms)
+        };
+        let mut out = Vec::new();
+        for format in [Format::V2, Format::Proto] {
+            payload::encode(format, &packet, None, &mut out);
+            assert_eq!(payload::decode(&out).unwrap().packet, packet, "{format:?}");
+        }
+        payload::encode(Format::V2, &packet, None, &mut out);
+        assert_eq!(out[8..16], ntp.to_be_bytes());
+        payload::encode(Format::Legacy, &packet, None, &mut out);
+        assert_eq!(out, ntp.to_be_bytes());
+
+        let gps = Packet {
+            epoch: Epoch::Gps,
+            ..packet
+        };
+        payload::encode(Format::V2, &gps, None, &mut out);
+        out[8..16].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert!(payload::decode(&out).is_err());
+
+        assert_eq!(Epoch::parse("gps"), Ok(Epoch::Gps));
+        assert!(Epoch::parse("tai").is_err());
+    }
+}
@@ -0,0 +1,170 @@
+//! The exit-code contract, and how the final error is reported.
+//!
+//! Every mode ends a failed run through [`fail`], which prints the error
+//! and returns the matching [`ExitCode`]. The codes are fixed (see the
+//! `EXIT_CODE_*` constants) so that a supervisor can tell a port already
+//! in use, which retrying won't fix, from a route that isn't up yet, which
+//! it might. With `--error-format json` the error is written to stderr as
+//! one JSON object instead of a plain line.
+
+use std::fmt;
+use std::io;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::log;
+use crate::status::json_string;
+use crate::{
+    EXIT_CODE_ALERT, EXIT_CODE_BIND_FAILED, EXIT_CODE_CLOCK_ERROR, EXIT_CODE_CLOCK_OFFSET,
+    EXIT_CODE_NETWORK_UNREACHABLE, EXIT_CODE_PERMISSION_DENIED, EXIT_CODE_RUNTIME_ERROR,
+    EXIT_CODE_USAGE_ERROR,
+};
+
+/// How the final error is printed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorFormat {
+    Text,
+    Json,
+}
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+impl ErrorFormat {
+    /// Parses an `--error-format` value.
+    pub fn parse(s: &str) -> Result<ErrorFormat, String> {
+        match s {
+            "text" => Ok(ErrorFormat::Text),
+            "json" => Ok(ErrorFormat::Json),
+            _ => Err("expected text or json".to_string()),
+        }
+    }
+}
+
+/// Prints the final error in `format` from now on, for the whole process.
+pub fn set_format(format: ErrorFormat) {
+    JSON.store(format == ErrorFormat::Json, Ordering::Relaxed);
+}
+
+/// Whether the final error is printed as JSON.
+pub fn json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// An error that ends the process, with the exit code it ends it with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Failure {
+    pub code: u8,
+    pub message: String,
+}
+
+impl Failure {
+    pub fn new(code: u8, message: impl Into<String>) -> Failure {
+        Failure {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// A failure caused by `e`, with the exit code its kind maps to.
+    pub fn io(e: &io::Error, message: impl Into<String>) -> Failure {
+        Failure::new(code_for(e), message)
+    }
+
+    /// The name of the code, as printed in the `kind` field.
+    pub fn kind(&self) -> &'static str {
+        match self.code {
+            EXIT_CODE_USAGE_ERROR => "usage",
+            EXIT_CODE_ALERT => "alert",
+            EXIT_CODE_CLOCK_OFFSET => "clock_offset",
+            EXIT_CODE_BIND_FAILED => "bind_failed",
+            EXIT_CODE_PERMISSION_DENIED => "permission_denied",
+            EXIT_CODE_NETWORK_UNREACHABLE => "network_unreachable",
+            EXIT_CODE_CLOCK_ERROR => "clock_error",
+            _ => "runtime",
+        }
+    }
+
+    /// The failure as one JSON object.
+    pub fn json(&self) -> String {
+        format!(
+            r#"{{"code":{},"kind":"{}","message":{}}}"#,
+            self.code,
+            self.kind(),
+            json_string(&self.message)
+        )
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl From<String> for Failure {
+    fn from(message: String) -> Failure {
+        Failure::new(EXIT_CODE_RUNTIME_ERROR, message)
+    }
+}
+
+impl From<Failure> for String {
+    fn from(f: Failure) -> String {
+        f.message
+    }
+}
+
+/// The exit code for a socket or system call that failed with `e`.
+pub fn code_for(e: &io::Error) -> u8 {
+    match e.kind() {
+        io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable => EXIT_CODE_BIND_FAILED,
+        io::ErrorKind::PermissionDenied => EXIT_CODE_PERMISSION_DENIED,
+        io::ErrorKind::NetworkUnreachable | io::ErrorKind::HostUnreachable => {
+            EXIT_CODE_NETWORK_UNREACHABLE
+        }
+        _ => EXIT_CODE_RUNTIME_ERROR,
+    }
+}
+
+/// Prints `failure` in the chosen format.
+pub fn report(failure: &Failure) {
+    if json() {
+        // Always printed: a supervisor parsing it can't know the log level
+        eprintln!("{}", failure.json());
+    } else {
+        log::error!("{failure}");
+    }
+}
+
+/// Reports `failure` and returns its exit code, to end a mode's `run`.
+pub fn fail(failure: impl Into<Failure>) -> ExitCode {
+    let failure = failure.into();
+    report(&failure);
+    ExitCode::from(failure.code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn io_errors_map_to_distinct_codes() {
+        let in_use = io::Error::from(io::ErrorKind::AddrInUse);
+        let f = Failure::io(&in_use, "Failed to bind \"0.0.0.0:9999\"");
+        assert_eq!(f.code, EXIT_CODE_BIND_FAILED);
+        assert_eq!(
+            f.json(),
+            r#"{"code":5,"kind":"bind_failed","message":"Failed to bind \"0.0.0.0:9999\""}"#
+        );
+        let unreachable = io::Error::from_raw_os_error(libc::ENETUNREACH);
+        assert_eq!(code_for(&unreachable), EXIT_CODE_NETWORK_UNREACHABLE);
+        assert_eq!(
+            code_for(&io::Error::from_raw_os_error(libc::EACCES)),
+            EXIT_CODE_PERMISSION_DENIED
+        );
+        // Anything else, including plain messages, is a runtime error
+        assert_eq!(code_for(&io::Error::other("boom")), EXIT_CODE_RUNTIME_ERROR);
+        assert_eq!(Failure::from("boom".to_string()).kind(), "runtime");
+        assert_eq!(ErrorFormat::parse("json"), Ok(ErrorFormat::Json));
+        assert!(ErrorFormat::parse("yaml").is_err());
+    }
+}
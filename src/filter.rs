@@ -0,0 +1,400 @@
+//! Source address filtering for receiver mode (`--allow-source`,
+//! `--deny-source`).
+//!
+//! A source matching any deny rule is dropped; otherwise, if there are
+//! allow rules, it must match one of them. On Linux the rules are also
+//! compiled to a classic BPF program and attached to the socket, so
+//! unwanted traffic sharing the port is dropped in the kernel and never
+//! wakes the receive loop. The same rules are checked again in userspace,
+//! which covers other platforms and datagrams queued before the filter
+//! was attached.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// An address prefix such as `10.0.0.0/8` or `fd00::/8`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Parses `ADDR/PREFIX`, or a bare address matching only itself.
+    pub fn parse(s: &str) -> Result<Cidr, String> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("'{addr}' is not an IP address"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|&p| p <= max)
+                .ok_or_else(|| format!("prefix length must be 0-{max}"))?,
+            None => max,
+        };
+        Ok(Cidr { addr, prefix }.masked())
+    }
+
+    /// Clears the host bits, so `10.1.2.3/8` is stored as `10.0.0.0/8`.
+    fn masked(self) -> Cidr {
+        let addr = match self.addr {
+            IpAddr::V4(a) => IpAddr::V4(Ipv4Addr::from(u32::from(a) & mask32(self.prefix))),
+            IpAddr::V6(a) => IpAddr::V6(Ipv6Addr::from(u128::from(a) & mask128(self.prefix))),
+        };
+        Cidr { addr, ..self }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                u32::from(ip) & mask32(self.prefix) == u32::from(net)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                u128::from(ip) & mask128(self.prefix) == u128::from(net)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn mask32(prefix: u8) -> u32 {
+    u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0)
+}
+
+fn mask128(prefix: u8) -> u128 {
+    u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0)
+}
+
+/// The `--allow-source` and `--deny-source` rules.
+#[derive(Clone, Debug, Default)]
+pub struct SourceFilter {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl SourceFilter {
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// Whether datagrams from `ip` are accepted.
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|c| c.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|c| c.contains(ip)))
+    }
+
+    /// Compiles the rules to a classic BPF socket filter, or `None` if the
+    /// program would be too long for BPF's 8-bit jumps.
+    pub fn bpf(&self) -> Option<Vec<Insn>> {
+        let mut p = Program::default();
+        // Dispatch on the IP version nibble of the network header
+        p.push(
+            BPF_LD | BPF_B | BPF_ABS,
+            Jump::Next,
+            Jump::Next,
+            SKF_NET_OFF,
+        );
+        p.push(BPF_ALU | BPF_RSH | BPF_K, Jump::Next, Jump::Next, 4);
+        p.push(BPF_JMP | BPF_JEQ | BPF_K, Jump::To(V4), Jump::Next, 4);
+        p.push(BPF_JMP | BPF_JEQ | BPF_K, Jump::To(V6), Jump::To(ACCEPT), 6);
+        for (label, v4) in [(V4, true), (V6, false)] {
+            p.label(label);
+            let rules = |list: &[Cidr]| -> Vec<Cidr> {
+                list.iter()
+                    .copied()
+                    .filter(|c| c.addr.is_ipv4() == v4)
+                    .collect()
+            };
+            for c in rules(&self.deny) {
+                p.matcher(c, DROP);
+            }
+            if self.allow.is_empty() {
+                p.jump_always(ACCEPT);
+                continue;
+            }
+            for c in rules(&self.allow) {
+                p.matcher(c, ACCEPT);
+            }
+            p.jump_always(DROP);
+        }
+        p.label(ACCEPT);
+        p.push(BPF_RET | BPF_K, Jump::Next, Jump::Next, u32::MAX);
+        p.label(DROP);
+        p.push(BPF_RET | BPF_K, Jump::Next, Jump::Next, 0);
+        p.resolve()
+    }
+}
+
+/// One classic BPF instruction, as in `struct sock_filter`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Insn {
+    pub code: u16,
+    pub jt: u8,
+    pub jf: u8,
+    pub k: u32,
+}
+
+pub(crate) const BPF_LD: u16 = 0x00;
+const BPF_ALU: u16 = 0x04;
+pub(crate) const BPF_JMP: u16 = 0x05;
+pub(crate) const BPF_RET: u16 = 0x06;
+pub(crate) const BPF_W: u16 = 0x00;
+const BPF_B: u16 = 0x10;
+pub(crate) const BPF_ABS: u16 = 0x20;
+const BPF_AND: u16 = 0x50;
+const BPF_RSH: u16 = 0x70;
+pub(crate) const BPF_JEQ: u16 = 0x10;
+pub(crate) const BPF_K: u16 = 0x00;
+/// Offsets from here address the network (IP) header instead of the UDP
+/// header a socket filter otherwise starts at.
+const SKF_NET_OFF: u32 = -0x100000i32 as u32;
+/// Where the source address sits in an IPv4 and an IPv6 header.
+const V4_SRC: u32 = 12;
+const V6_SRC: u32 = 8;
+
+const V4: usize = 0;
+const V6: usize = 1;
+const ACCEPT: usize = 2;
+const DROP: usize = 3;
+
+#[derive(Clone, Copy)]
+enum Jump {
+    Next,
+    /// Past this many following instructions.
+    Skip(usize),
+    /// To a label.
+    To(usize),
+}
+
+/// A program under construction, with jumps to labels not yet placed.
+#[derive(Default)]
+struct Program {
+    insns: Vec<(u16, Jump, Jump, u32)>,
+    labels: [Option<usize>; 4],
+}
+
+impl Program {
+    fn push(&mut self, code: u16, jt: Jump, jf: Jump, k: u32) {
+        self.insns.push((code, jt, jf, k));
+    }
+
+    fn label(&mut self, label: usize) {
+        self.labels[label] = Some(self.insns.len());
+    }
+
+    /// An unconditional jump, written as a JEQ taken either way so that it
+    /// resolves like the conditional ones.
+    fn jump_always(&mut self, label: usize) {
+        self.push(
+            BPF_JMP | BPF_JEQ | BPF_K,
+            Jump::To(label),
+            Jump::To(label),
+            0,
+        );
+    }
+
+    /// Jumps to `label` if the source address is in `cidr`, falling
+    /// through otherwise.
+    fn matcher(&mut self, cidr: Cidr, label: usize) {
+        let words: Vec<(u32, u32, u32)> = match cidr.addr {
+            IpAddr::V4(a) => vec![(V4_SRC, u32::from(a), mask32(cidr.prefix))],
+            IpAddr::V6(a) => {
+                let (net, mask) = (u128::from(a), mask128(cidr.prefix));
+                (0..4)
+                    .map(|i| {
+                        let shift = 96 - 32 * i;
+                        (
+                            V6_SRC + 4 * i,
+                            (net >> shift) as u32,
+                            (mask >> shift) as u32,
+                        )
+                    })
+                    .filter(|&(_, _, mask)| mask != 0)
+                    .collect()
+            }
+        };
+        if words.is_empty() {
+            // A /0 matches everything of its family
+            self.jump_always(label);
+            return;
+        }
+        // Skip the rest of this matcher on the first word that differs
+        for (n, &(offset, net, mask)) in words.iter().enumerate() {
+            self.push(
+                BPF_LD | BPF_W | BPF_ABS,
+                Jump::Next,
+                Jump::Next,
+                SKF_NET_OFF + offset,
+            );
+            self.push(BPF_ALU | BPF_AND | BPF_K, Jump::Next, Jump::Next, mask);
+            let rest = words.len() - n - 1;
+            self.push(
+                BPF_JMP | BPF_JEQ | BPF_K,
+                if rest == 0 {
+                    Jump::To(label)
+                } else {
+                    Jump::Next
+                },
+                Jump::Skip(3 * rest),
+                net,
+            );
+        }
+    }
+
+    fn resolve(self) -> Option<Vec<Insn>> {
+        let target = |at: usize, j: Jump| -> Option<u8> {
+            let to = match j {
+                Jump::Next => return Some(0),
+                Jump::Skip(n) => return u8::try_from(n).ok(),
+                Jump::To(label) => self.labels[label]?,
+            };
+            u8::try_from(to.checked_sub(at + 1)?).ok()
+        };
+        let mut out = Vec::with_capacity(self.insns.len());
+        for (at, &(code, jt, jf, k)) in self.insns.iter().enumerate() {
+            out.push(Insn {
+                code,
+                jt: target(at, jt)?,
+                jf: target(at, jf)?,
+                k,
+            });
+        }
+        Some(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `prog` the way the kernel would on a datagram whose IP header
+    /// is `net`.
+    fn run(prog: &[Insn], net: &[u8]) -> u32 {
+        let (mut a, mut pc) = (0u32, 0usize);
+        loop {
+            let i = prog[pc];
+            pc += 1;
+            let load = |k: u32, len: usize| {
+                let at = k.wrapping_sub(SKF_NET_OFF) as usize;
+                net[at..at + len]
+                    .iter()
+                    .fold(0u32, |v, &b| v << 8 | b as u32)
+            };
+            match i.code {
+                0x30 => a = load(i.k, 1),
+                0x20 => a = load(i.k, 4),
+                0x54 => a &= i.k,
+                0x74 => a >>= i.k,
+                0x15 => pc += usize::from(if a == i.k { i.jt } else { i.jf }),
+                0x06 => return i.k,
+                code => panic!("unexpected opcode {code:#x}"),
+            }
+        }
+    }
+
+    fn v4_header(src: Ipv4Addr) -> Vec<u8> {
+        let mut h = vec![0x45; 20];
+        h[12..16].copy_from_slice(&src.octets());
+        h
+    }
+
+    fn v6_header(src: Ipv6Addr) -> Vec<u8> {
+        let mut h = vec![0x60; 40];
+        h[8..24].copy_from_slice(&src.octets());
+        h
+    }
+
+    #[test]
+    fn parses_cidrs() {
+        let c = Cidr::parse("10.1.2.3/8").unwrap();
+        assert_eq!(c, Cidr::parse("10.0.0.0/8").unwrap());
+        assert!(c.contains("10.200.0.1".parse().unwrap()));
+        assert!(c.contains("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(!c.contains("11.0.0.1".parse().unwrap()));
+        assert!(Cidr::parse("fe80::1")
+            .unwrap()
+            .contains("fe80::1".parse().unwrap()));
+        assert!(Cidr::parse("0.0.0.0/0")
+            .unwrap()
+            .contains("1.2.3.4".parse().unwrap()));
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("host/8").is_err());
+    }
+
+    #[test]
+    fn kernel_program_agrees_with_userspace() {
+        let filter = SourceFilter {
+            allow: ["192.168.0.0/16", "10.0.0.5", "fd00::/8", "2001:db8::/96"]
+                .iter()
+                .map(|c| Cidr::parse(c).unwrap())
+                .collect(),
+            deny: ["192.168.7.0/24", "fd00:1::/32"]
+                .iter()
+                .map(|c| Cidr::parse(c).unwrap())
+                .collect(),
+        };
+        let prog = filter.bpf().unwrap();
+        for ip in [
+            "192.168.1.1",
+            "192.168.7.9",
+            "10.0.0.5",
+            "10.0.0.6",
+            "8.8.8.8",
+            "fd00::1",
+            "fd00:1::1",
+            "2001:db8::1",
+            "2001:db8:0:0:1::1",
+            "fe80::1",
+        ] {
+            let ip: IpAddr = ip.parse().unwrap();
+            let header = match ip {
+                IpAddr::V4(a) => v4_header(a),
+                IpAddr::V6(a) => v6_header(a),
+            };
+            assert_eq!(run(&prog, &header) != 0, filter.permits(ip), "{ip}");
+        }
+
+        // With only deny rules, everything else is let through
+        let deny_only = SourceFilter {
+            allow: Vec::new(),
+            deny: vec![Cidr::parse("10.0.0.0/8").unwrap()],
+        };
+        let prog = deny_only.bpf().unwrap();
+        assert_eq!(run(&prog, &v4_header(Ipv4Addr::new(10, 1, 1, 1))), 0);
+        assert_ne!(run(&prog, &v4_header(Ipv4Addr::new(11, 1, 1, 1))), 0);
+        assert_ne!(run(&prog, &v6_header(Ipv6Addr::LOCALHOST)), 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn kernel_drops_denied_sources() {
+        use std::net::UdpSocket;
+        use std::time::Duration;
+
+        let filter = SourceFilter {
+            allow: vec![Cidr::parse("127.0.0.0/8").unwrap()],
+            deny: vec![Cidr::parse("127.0.0.2").unwrap()],
+        };
+        let rx = UdpSocket::bind("0.0.0.0:0").unwrap();
+        crate::sys::attach_filter(&rx, &filter.bpf().unwrap()).unwrap();
+        rx.set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let dest = ("127.0.0.1", rx.local_addr().unwrap().port());
+        for (src, payload) in [("127.0.0.2", &b"denied"[..]), ("127.0.0.1", b"allowed")] {
+            let tx = UdpSocket::bind((src, 0)).unwrap();
+            tx.send_to(payload, dest).unwrap();
+        }
+        let mut buf = [0u8; 16];
+        let (n, src) = rx.recv_from(&mut buf).unwrap();
+        assert_eq!(
+            (&buf[..n], src.ip()),
+            (&b"allowed"[..], "127.0.0.1".parse().unwrap())
+        );
+        assert!(rx.recv_from(&mut buf).is_err());
+    }
+}
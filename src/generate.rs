@@ -0,0 +1,654 @@
+//! Shell completion scripts and the instances file's schema (`generate`),
+//! both derived from the command-line definition so they can't fall
+//! behind it.
+//!
+//! The completion scripts walk the words typed so far to find the
+//! subcommand being completed, skipping the values of options that take
+//! one, then offer that subcommand's options or subcommands, or a value
+//! for the option just typed: one of its fixed choices, or a file name
+//! where it takes a path. Other values are left to the user.
+//!
+//! The schema is JSON Schema (draft 2020-12) for the TOML file `multi`
+//! reads (see [`crate::instances`]). Every `send` option is a key, typed
+//! from what its value is parsed into: switches are booleans, counts and
+//! ports integers, and everything else, durations and addresses
+//! included, a string. Repeatable options take a value or an array.
+
+use std::any::TypeId;
+use std::fmt::Write as _;
+
+use clap::{Arg, ArgAction, Command, ValueHint};
+
+use crate::status::json_string;
+
+/// Shells there are completion scripts for.
+pub const SHELLS: [&str; 3] = ["bash", "zsh", "fish"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl Shell {
+    pub fn parse(s: &str) -> Result<Shell, String> {
+        match s {
+            "bash" => Ok(Shell::Bash),
+            "zsh" => Ok(Shell::Zsh),
+            "fish" => Ok(Shell::Fish),
+            _ => Err(format!("expected one of {}, got '{s}'", SHELLS.join(", "))),
+        }
+    }
+}
+
+/// What to offer for a value.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Values {
+    Any,
+    Files,
+    Choices(Vec<String>),
+}
+
+/// An option as the scripts see it.
+struct Opt {
+    /// Its spellings, e.g. `--quiet` and `-q`.
+    flags: Vec<String>,
+    help: String,
+    /// Takes a value, as the next word.
+    takes_value: bool,
+    values: Values,
+}
+
+/// One command or subcommand.
+struct Node {
+    /// The words naming it, from the program's name on.
+    path: String,
+    /// Its subcommands' names and descriptions.
+    subcommands: Vec<(String, String)>,
+    /// Subcommand names and aliases, with the path each leads to.
+    steps: Vec<(String, String)>,
+    opts: Vec<Opt>,
+    /// What to offer for its first positional argument, if it takes one.
+    positional: Option<Values>,
+}
+
+impl Node {
+    /// The options taking a value, space-separated.
+    fn takes(&self) -> String {
+        let flags: Vec<&str> = self
+            .opts
+            .iter()
+            .filter(|o| o.takes_value)
+            .flat_map(|o| o.flags.iter().map(String::as_str))
+            .collect();
+        flags.join(" ")
+    }
+
+    fn flags(&self) -> String {
+        let flags: Vec<&str> = self
+            .opts
+            .iter()
+            .flat_map(|o| o.flags.iter().map(String::as_str))
+            .collect();
+        flags.join(" ")
+    }
+}
+
+/// The first line of an argument's or command's help.
+fn summary(help: Option<String>) -> String {
+    help.and_then(|h| h.lines().next().map(str::to_string))
+        .unwrap_or_default()
+}
+
+fn values(arg: &Arg) -> Values {
+    let choices: Vec<String> = arg
+        .get_possible_values()
+        .iter()
+        .filter(|v| !v.is_hide_set())
+        .map(|v| v.get_name().to_string())
+        .collect();
+    if !choices.is_empty() {
+        return Values::Choices(choices);
+    }
+    let hinted = matches!(
+        arg.get_value_hint(),
+        ValueHint::AnyPath | ValueHint::FilePath | ValueHint::DirPath
+    );
+    // Most paths are only told apart by what they are called
+    let named = arg.get_value_names().unwrap_or_default().iter().any(|n| {
+        ["FILE", "DIR", "PATH"]
+            .iter()
+            .any(|p| n.as_str().contains(p))
+    });
+    if hinted || named {
+        Values::Files
+    } else {
+        Values::Any
+    }
+}
+
+/// `cmd` and every subcommand under it, depth first.
+fn nodes(cmd: &Command, path: String, out: &mut Vec<Node>) {
+    let mut opts = Vec::new();
+    let mut positional = None;
+    for arg in cmd.get_arguments().filter(|a| !a.is_hide_set()) {
+        if arg.is_positional() {
+            positional.get_or_insert_with(|| values(arg));
+            continue;
+        }
+        let mut flags = Vec::new();
+        if let Some(long) = arg.get_long() {
+            flags.push(format!("--{long}"));
+        }
+        if let Some(short) = arg.get_short() {
+            flags.push(format!("-{short}"));
+        }
+        opts.push(Opt {
+            flags,
+            help: summary(arg.get_help().map(ToString::to_string)),
+            takes_value: arg.get_action().takes_values(),
+            values: values(arg),
+        });
+    }
+    let subs: Vec<&Command> = cmd.get_subcommands().filter(|s| !s.is_hide_set()).collect();
+    let mut steps = Vec::new();
+    for sub in &subs {
+        let to = format!("{path} {}", sub.get_name());
+        steps.push((sub.get_name().to_string(), to.clone()));
+        for alias in sub.get_all_aliases() {
+            steps.push((alias.to_string(), to.clone()));
+        }
+    }
+    out.push(Node {
+        path: path.clone(),
+        subcommands: subs
+            .iter()
+            .map(|s| {
+                let about = s.get_about().map(ToString::to_string);
+                (s.get_name().to_string(), summary(about))
+            })
+            .collect(),
+        steps,
+        opts,
+        positional,
+    });
+    for sub in subs {
+        nodes(sub, format!("{path} {}", sub.get_name()), out);
+    }
+}
+
+/// The completion script for `shell`, from `cmd` as built, so it includes
+/// global and help options.
+pub fn completions(cmd: &Command, shell: Shell) -> String {
+    let name = cmd.get_name().to_string();
+    let mut all = Vec::new();
+    nodes(cmd, name.clone(), &mut all);
+    match shell {
+        Shell::Bash => bash(&name, &all),
+        Shell::Zsh => zsh(&name, &all),
+        Shell::Fish => fish(&name, &all),
+    }
+}
+
+/// Every `"<path> <option>"` pattern whose value is completed from
+/// `values`, grouped as one alternation per kind of value.
+fn value_patterns(all: &[Node]) -> Vec<(Vec<String>, &Values)> {
+    let mut out: Vec<(Vec<String>, &Values)> = Vec::new();
+    for node in all {
+        for opt in node.opts.iter().filter(|o| o.takes_value) {
+            if opt.values == Values::Any {
+                continue;
+            }
+            let patterns = opt.flags.iter().map(|f| format!("{} {f}", node.path));
+            match out.iter_mut().find(|(_, v)| **v == opt.values) {
+                Some((p, _)) => p.extend(patterns),
+                None => out.push((patterns.collect(), &opt.values)),
+            }
+        }
+    }
+    out
+}
+
+fn fn_name(name: &str) -> String {
+    name.replace(|c: char| !c.is_ascii_alphanumeric(), "_")
+}
+
+fn bash(name: &str, all: &[Node]) -> String {
+    let f = format!("_{}", fn_name(name));
+    let mut s = String::new();
+    let _ = writeln!(
+        s,
+        "# bash completion for {name}, from `{name} generate completions bash`\n"
+    );
+    let _ = writeln!(s, "{f}_takes() {{\n    case \"$1\" in");
+    for node in all.iter().filter(|n| n.opts.iter().any(|o| o.takes_value)) {
+        let _ = writeln!(s, "        \"{}\") echo \"{}\" ;;", node.path, node.takes());
+    }
+    let _ = writeln!(s, "    esac\n}}\n");
+    let _ = writeln!(s, "{f}() {{");
+    let _ = writeln!(
+        s,
+        "    local cur=${{COMP_WORDS[COMP_CWORD]}} prev=${{COMP_WORDS[COMP_CWORD-1]}}"
+    );
+    let _ = writeln!(s, "    local cmd={name} skip=0 i w");
+    let _ = writeln!(s, "    COMPREPLY=()");
+    let _ = writeln!(s, "    for ((i = 1; i < COMP_CWORD; i++)); do");
+    let _ = writeln!(s, "        w=${{COMP_WORDS[i]}}");
+    let _ = writeln!(s, "        if ((skip)); then skip=0; continue; fi");
+    let _ = writeln!(s, "        case \"$cmd $w\" in");
+    for node in all {
+        for (word, to) in &node.steps {
+            let _ = writeln!(s, "            \"{} {word}\") cmd=\"{to}\" ;;", node.path);
+        }
+    }
+    let _ = writeln!(
+        s,
+        "            *) [[ \" $({f}_takes \"$cmd\") \" == *\" $w \"* ]] && skip=1 ;;"
+    );
+    let _ = writeln!(s, "        esac\n    done");
+    let _ = writeln!(s, "    case \"$cmd $prev\" in");
+    for (patterns, values) in value_patterns(all) {
+        let quoted: Vec<String> = patterns.iter().map(|p| format!("\"{p}\"")).collect();
+        let reply = match values {
+            Values::Files => "compgen -f -- \"$cur\"".to_string(),
+            Values::Choices(c) => format!("compgen -W \"{}\" -- \"$cur\"", c.join(" ")),
+            Values::Any => unreachable!("left out"),
+        };
+        let _ = writeln!(
+            s,
+            "        {}) COMPREPLY=($({reply})); return ;;",
+            quoted.join("|")
+        );
+    }
+    let _ = writeln!(s, "    esac");
+    let _ = writeln!(
+        s,
+        "    [[ \" $({f}_takes \"$cmd\") \" == *\" $prev \"* ]] && return"
+    );
+    let _ = writeln!(s, "    case \"$cmd\" in");
+    for node in all {
+        let opts = format!("COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))", node.flags());
+        let other = if !node.subcommands.is_empty() {
+            let subs: Vec<&str> = node.subcommands.iter().map(|(n, _)| n.as_str()).collect();
+            Some(format!("compgen -W \"{}\" -- \"$cur\"", subs.join(" ")))
+        } else {
+            match &node.positional {
+                Some(Values::Files) => Some("compgen -f -- \"$cur\"".to_string()),
+                Some(Values::Choices(c)) => {
+                    Some(format!("compgen -W \"{}\" -- \"$cur\"", c.join(" ")))
+                }
+                Some(Values::Any) | None => None,
+            }
+        };
+        let _ = writeln!(s, "        \"{}\")", node.path);
+        match other {
+            Some(reply) => {
+                let _ = writeln!(s, "            if [[ $cur == -* ]]; then {opts}");
+                let _ = writeln!(s, "            else COMPREPLY=($({reply})); fi ;;");
+            }
+            None => {
+                let _ = writeln!(s, "            {opts} ;;");
+            }
+        }
+    }
+    let _ = writeln!(s, "    esac\n}}\n");
+    let _ = writeln!(s, "complete -F {f} {name}");
+    s
+}
+
+/// Quotes `s` in single quotes for zsh and bash.
+fn sh_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+fn zsh(name: &str, all: &[Node]) -> String {
+    let f = format!("_{}", fn_name(name));
+    let mut s = String::new();
+    let _ = writeln!(s, "#compdef {name}");
+    let _ = writeln!(
+        s,
+        "# zsh completion for {name}, from `{name} generate completions zsh`\n"
+    );
+    let _ = writeln!(s, "{f}_takes() {{\n    case $1 in");
+    for node in all.iter().filter(|n| n.opts.iter().any(|o| o.takes_value)) {
+        let _ = writeln!(s, "        (\"{}\") reply=({}) ;;", node.path, node.takes());
+    }
+    let _ = writeln!(s, "        (*) reply=() ;;\n    esac\n}}\n");
+    let _ = writeln!(s, "{f}() {{");
+    // `path` is taken in zsh
+    let _ = writeln!(s, "    local cmd={name} skip=0 i w prev");
+    let _ = writeln!(s, "    local -a reply opts subs");
+    let _ = writeln!(s, "    for ((i = 2; i < CURRENT; i++)); do");
+    let _ = writeln!(s, "        w=${{words[i]}}");
+    let _ = writeln!(s, "        if ((skip)); then skip=0; continue; fi");
+    let _ = writeln!(s, "        case \"$cmd $w\" in");
+    for node in all {
+        for (word, to) in &node.steps {
+            let _ = writeln!(s, "            (\"{} {word}\") cmd=\"{to}\" ;;", node.path);
+        }
+    }
+    let _ = writeln!(
+        s,
+        "            (*) {f}_takes \"$cmd\"; (( ${{reply[(Ie)$w]}} )) && skip=1 ;;"
+    );
+    let _ = writeln!(s, "        esac\n    done");
+    let _ = writeln!(s, "    prev=${{words[CURRENT-1]}}");
+    let _ = writeln!(s, "    case \"$cmd $prev\" in");
+    for (patterns, values) in value_patterns(all) {
+        let quoted: Vec<String> = patterns.iter().map(|p| format!("\"{p}\"")).collect();
+        let reply = match values {
+            Values::Files => "_files".to_string(),
+            Values::Choices(c) => format!("compadd -- {}", c.join(" ")),
+            Values::Any => unreachable!("left out"),
+        };
+        let _ = writeln!(s, "        ({}) {reply}; return ;;", quoted.join("|"));
+    }
+    let _ = writeln!(s, "    esac");
+    let _ = writeln!(s, "    {f}_takes \"$cmd\"");
+    let _ = writeln!(
+        s,
+        "    (( CURRENT > 2 && ${{reply[(Ie)$prev]}} )) && return 1"
+    );
+    let _ = writeln!(s, "    case $cmd in");
+    for node in all {
+        let _ = writeln!(s, "        (\"{}\")", node.path);
+        let opts: Vec<String> = node
+            .opts
+            .iter()
+            .flat_map(|o| o.flags.iter().map(|f| sh_quote(&format!("{f}:{}", o.help))))
+            .collect();
+        let _ = writeln!(s, "            opts=({})", opts.join(" "));
+        let subs: Vec<String> = node
+            .subcommands
+            .iter()
+            .map(|(n, about)| sh_quote(&format!("{n}:{about}")))
+            .collect();
+        if !subs.is_empty() {
+            let _ = writeln!(s, "            subs=({})", subs.join(" "));
+        }
+        match &node.positional {
+            Some(Values::Files) if subs.is_empty() => {
+                let _ = writeln!(s, "            [[ $PREFIX != -* ]] && {{ _files; return }}");
+            }
+            Some(Values::Choices(c)) if subs.is_empty() => {
+                let _ = writeln!(
+                    s,
+                    "            [[ $PREFIX != -* ]] && {{ compadd -- {}; return }}",
+                    c.join(" ")
+                );
+            }
+            _ => {}
+        }
+        let _ = writeln!(s, "            ;;");
+    }
+    let _ = writeln!(s, "    esac");
+    let _ = writeln!(
+        s,
+        "    if [[ $PREFIX != -* && ${{#subs}} -gt 0 ]]; then\n        _describe -t commands command subs\n    else\n        _describe -t options option opts\n    fi\n}}\n"
+    );
+    let _ = writeln!(s, "if [[ $zsh_eval_context[-1] == loadautofunc ]]; then");
+    let _ = writeln!(s, "    {f} \"$@\"\nelse\n    compdef {f} {name}\nfi");
+    s
+}
+
+/// Quotes `s` in single quotes for fish.
+fn fish_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+fn fish(name: &str, all: &[Node]) -> String {
+    let f = format!("__{}", fn_name(name));
+    let mut s = String::new();
+    let _ = writeln!(
+        s,
+        "# fish completion for {name}, from `{name} generate completions fish`\n"
+    );
+    let _ = writeln!(s, "function {f}_takes\n    switch $argv[1]");
+    for node in all.iter().filter(|n| n.opts.iter().any(|o| o.takes_value)) {
+        let _ = writeln!(s, "        case \"{}\"", node.path);
+        let _ = writeln!(s, "            string split ' ' -- \"{}\"", node.takes());
+    }
+    let _ = writeln!(s, "    end\nend\n");
+    let _ = writeln!(s, "function {f}_cmd");
+    let _ = writeln!(s, "    set -l cmd {name}\n    set -l skip 0");
+    let _ = writeln!(s, "    for w in (commandline -opc)[2..-1]");
+    let _ = writeln!(
+        s,
+        "        if test $skip = 1\n            set skip 0\n            continue\n        end"
+    );
+    let _ = writeln!(s, "        switch \"$cmd $w\"");
+    for node in all {
+        for (word, to) in &node.steps {
+            let _ = writeln!(s, "            case \"{} {word}\"", node.path);
+            let _ = writeln!(s, "                set cmd \"{to}\"");
+        }
+    }
+    let _ = writeln!(s, "            case '*'");
+    let _ = writeln!(
+        s,
+        "                contains -- $w ({f}_takes \"$cmd\"); and set skip 1"
+    );
+    let _ = writeln!(s, "        end\n    end\n    echo $cmd\nend\n");
+    let _ = writeln!(s, "complete -c {name} -f");
+    for node in all {
+        let when = format!("-n 'test ({f}_cmd) = \"{}\"'", node.path);
+        for (sub, about) in &node.subcommands {
+            let _ = writeln!(
+                s,
+                "complete -c {name} {when} -a {sub} -d {}",
+                fish_quote(about)
+            );
+        }
+        match &node.positional {
+            Some(Values::Files) if node.subcommands.is_empty() => {
+                let _ = writeln!(s, "complete -c {name} {when} -F");
+            }
+            Some(Values::Choices(c)) if node.subcommands.is_empty() => {
+                let _ = writeln!(s, "complete -c {name} {when} -a '{}'", c.join(" "));
+            }
+            _ => {}
+        }
+        for opt in &node.opts {
+            let mut line = format!("complete -c {name} {when}");
+            for flag in &opt.flags {
+                match flag.strip_prefix("--") {
+                    Some(long) => line.push_str(&format!(" -l {long}")),
+                    None => line.push_str(&format!(" -s {}", &flag[1..])),
+                }
+            }
+            match (&opt.values, opt.takes_value) {
+                (_, false) => {}
+                (Values::Files, true) => line.push_str(" -r -F"),
+                (Values::Choices(c), true) => line.push_str(&format!(" -x -a '{}'", c.join(" "))),
+                (Values::Any, true) => line.push_str(" -x"),
+            }
+            let _ = writeln!(s, "{line} -d {}", fish_quote(&opt.help));
+        }
+    }
+    s
+}
+
+/// The JSON Schema type of what `arg`'s values are parsed into.
+fn json_type(arg: &Arg) -> &'static str {
+    if !arg.get_action().takes_values() {
+        return "boolean";
+    }
+    let id = arg.get_value_parser().type_id();
+    let integers = [
+        TypeId::of::<u8>(),
+        TypeId::of::<u16>(),
+        TypeId::of::<u32>(),
+        TypeId::of::<u64>(),
+        TypeId::of::<usize>(),
+        TypeId::of::<i32>(),
+        TypeId::of::<i64>(),
+    ];
+    if integers.iter().any(|&t| id == t) {
+        "integer"
+    } else if id == TypeId::of::<f64>() {
+        "number"
+    } else if id == TypeId::of::<bool>() {
+        "boolean"
+    } else {
+        "string"
+    }
+}
+
+/// One option's schema, indented for its place in the document.
+fn option_schema(arg: &Arg) -> String {
+    let ty = json_type(arg);
+    let mut item = format!("\"type\": \"{ty}\"");
+    let choices: Vec<String> = arg
+        .get_possible_values()
+        .iter()
+        .filter(|_| ty == "string")
+        .map(|v| json_string(v.get_name()))
+        .collect();
+    if !choices.is_empty() {
+        let _ = write!(item, ", \"enum\": [{}]", choices.join(", "));
+    }
+    let mut out = String::from("{\n");
+    let help = summary(arg.get_help().map(ToString::to_string));
+    if !help.is_empty() {
+        let _ = writeln!(out, "          \"description\": {},", json_string(&help));
+    }
+    if let Some(default) = arg.get_default_values().first() {
+        let default = default.to_string_lossy();
+        let value = match ty {
+            "integer" | "number" | "boolean" => default.to_string(),
+            _ => json_string(&default),
+        };
+        let _ = writeln!(out, "          \"default\": {value},");
+    }
+    if matches!(arg.get_action(), ArgAction::Append) {
+        let _ = writeln!(
+            out,
+            "          \"oneOf\": [{{ {item} }}, {{ \"type\": \"array\", \"items\": {{ {item} }} }}]"
+        );
+    } else {
+        let _ = writeln!(out, "          {item}");
+    }
+    out.push_str("        }");
+    out
+}
+
+/// JSON Schema for the instances file `multi` reads, from the `send`
+/// subcommand's options, leaving out those with an id in `excluded`.
+pub fn config_schema(send: &Command, excluded: &[&str]) -> String {
+    let options: Vec<String> = send
+        .get_arguments()
+        .filter(|a| !a.is_hide_set() && !a.is_positional() && !a.is_global_set())
+        .filter(|a| !excluded.contains(&a.get_id().as_str()))
+        .filter_map(|a| {
+            let long = a.get_long()?;
+            Some(format!(
+                "        {}: {}",
+                json_string(long),
+                option_schema(a)
+            ))
+        })
+        .collect();
+    let refs: Vec<String> = send
+        .get_arguments()
+        .filter(|a| !a.is_hide_set() && !a.is_positional() && !a.is_global_set())
+        .filter(|a| !excluded.contains(&a.get_id().as_str()))
+        .filter_map(|a| a.get_long())
+        .map(|long| {
+            format!(
+                "    {}: {{ \"$ref\": \"#/$defs/instance/properties/{long}\" }}",
+                json_string(long)
+            )
+        })
+        .collect();
+    format!(
+        r##"{{
+  "$schema": "https://json-schema.org/draft/2020-12/schema",
+  "title": "udp_bcast_ts multi instances file",
+  "description": "send options shared by every instance, then one [instances.<name>] table of send options per sender",
+  "type": "object",
+  "properties": {{
+{},
+    "instances": {{
+      "type": "object",
+      "minProperties": 1,
+      "propertyNames": {{ "pattern": "^[A-Za-z0-9_-]+$" }},
+      "additionalProperties": {{ "$ref": "#/$defs/instance" }}
+    }}
+  }},
+  "required": ["instances"],
+  "additionalProperties": false,
+  "$defs": {{
+    "instance": {{
+      "type": "object",
+      "properties": {{
+{}
+      }},
+      "additionalProperties": false
+    }}
+  }}
+}}
+"##,
+        refs.join(",\n"),
+        options.join(",\n")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{value_parser, Arg};
+
+    fn cli() -> Command {
+        Command::new("tool")
+            .arg(
+                Arg::new("quiet")
+                    .long("quiet")
+                    .short('q')
+                    .action(ArgAction::SetTrue),
+            )
+            .subcommand(
+                Command::new("send")
+                    .about("Send things")
+                    .alias("post")
+                    .arg(
+                        Arg::new("port")
+                            .long("port")
+                            .value_parser(value_parser!(u16)),
+                    )
+                    .arg(Arg::new("key").long("key").value_name("KEYFILE"))
+                    .arg(Arg::new("field").long("field").action(ArgAction::Append))
+                    .arg(
+                        Arg::new("mode")
+                            .long("mode")
+                            .value_parser(["fast", "slow"])
+                            .default_value("fast"),
+                    ),
+            )
+    }
+
+    #[test]
+    fn completes_subcommands_options_and_values() {
+        let bash = completions(&cli(), Shell::Bash);
+        assert!(bash.contains("\"tool post\") cmd=\"tool send\" ;;"));
+        assert!(bash.contains("\"tool send\") echo \"--port --key --field --mode\" ;;"));
+        assert!(bash.contains("\"tool send --key\") COMPREPLY=($(compgen -f -- \"$cur\"))"));
+        assert!(bash.contains("compgen -W \"fast slow\""));
+        assert!(bash.contains("compgen -W \"send\" -- \"$cur\""));
+
+        let zsh = completions(&cli(), Shell::Zsh);
+        assert!(zsh.contains("subs=('send:Send things')"));
+        assert!(zsh.contains("'-q:'"));
+
+        let fish = completions(&cli(), Shell::Fish);
+        assert!(fish.contains("-l quiet -s q -d ''"));
+        assert!(fish.contains("-l key -r -F"));
+        assert!(fish.contains("-l mode -x -a 'fast slow'"));
+
+        let schema = config_schema(cli().find_subcommand("send").unwrap(), &["field"]);
+        assert!(schema.contains("\"port\": {\n          \"type\": \"integer\""));
+        assert!(schema.contains("\"default\": \"fast\",\n          \"type\": \"string\", \"enum\": [\"fast\", \"slow\"]"));
+        assert!(!schema.contains("\"field\""));
+        assert!(schema.contains("\"$ref\": \"#/$defs/instance/properties/key\""));
+    }
+}
@@ -0,0 +1,173 @@
+//! Duplicate sender detection (`send --guard` and `--exclusive`).
+//!
+//! Two senders broadcasting the same group on the same port, say a
+//! forgotten instance and its replacement, interleave their timestamps and
+//! sequence numbers, and every receiver on the segment sees one confused
+//! stream. A guarded sender also listens on its destination port, like a
+//! `--role follower`, and reports any other source of data messages for
+//! its group once it has heard two of them, with the interval between the
+//! timestamps they carry. A sender that falls silent is forgotten after five of its intervals,
+//! and at least [`FORGET_MS`], so it is reported again if it comes back.
+//!
+//! With `--exclusive`, the sender listens for [`PROBE`] before its first
+//! send and refuses to start if it heard another sender by then.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::message::MessageType;
+use crate::payload::Packet;
+
+/// How long `--exclusive` listens before the first send.
+pub const PROBE: Duration = Duration::from_secs(2);
+
+/// Silence after which another sender is forgotten, at least.
+pub const FORGET_MS: u64 = 10_000;
+
+/// Datagrams heard from another sender before it is reported.
+const MIN_HEARD: u32 = 2;
+
+/// What to do about another sender.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Warn and keep sending.
+    Warn,
+    /// Refuse to start if one is heard during the [`PROBE`], and warn
+    /// about any heard later.
+    Exclusive,
+}
+
+/// Another sender broadcasting our group on our port.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Intruder {
+    pub src: SocketAddr,
+    /// Mean time between its datagrams so far.
+    pub interval_ms: u64,
+    pub seq: Option<u32>,
+}
+
+impl fmt::Display for Intruder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "src={} interval_ms={}", self.src, self.interval_ms)?;
+        if let Some(seq) = self.seq {
+            write!(f, " seq={seq}")?;
+        }
+        Ok(())
+    }
+}
+
+struct Peer {
+    /// Timestamps of its first and latest datagrams.
+    first_ts_ms: u64,
+    last_ts_ms: u64,
+    /// When its latest datagram arrived.
+    last_ms: u64,
+    heard: u32,
+    reported: bool,
+}
+
+impl Peer {
+    fn interval_ms(&self) -> u64 {
+        self.last_ts_ms.saturating_sub(self.first_ts_ms) / u64::from(self.heard.max(2) - 1)
+    }
+}
+
+/// Keeps track of the other senders heard on the port.
+pub struct Guard {
+    group: Option<u16>,
+    peers: BTreeMap<SocketAddr, Peer>,
+}
+
+impl Guard {
+    /// Watches for senders of `group`, as this sender's payloads carry it.
+    pub fn new(group: Option<u16>) -> Guard {
+        Guard {
+            group,
+            peers: BTreeMap::new(),
+        }
+    }
+
+    /// Counts a datagram from another source, returning it the first time
+    /// it counts as another sender.
+    pub fn heard(&mut self, src: SocketAddr, packet: &Packet, now_ms: u64) -> Option<Intruder> {
+        if packet.kind != MessageType::DATA || packet.group != self.group {
+            return None;
+        }
+        let peer = self.peers.entry(src).or_insert(Peer {
+            first_ts_ms: packet.ts_ms,
+            last_ts_ms: packet.ts_ms,
+            last_ms: now_ms,
+            heard: 0,
+            reported: false,
+        });
+        peer.heard += 1;
+        peer.last_ts_ms = packet.ts_ms;
+        peer.last_ms = now_ms.max(peer.last_ms);
+        if peer.reported || peer.heard < MIN_HEARD {
+            return None;
+        }
+        peer.reported = true;
+        Some(Intruder {
+            src,
+            interval_ms: peer.interval_ms(),
+            seq: packet.seq,
+        })
+    }
+
+    /// Forgets senders that fell silent, returning those that had been
+    /// reported.
+    pub fn expire(&mut self, now_ms: u64) -> Vec<SocketAddr> {
+        let mut gone = Vec::new();
+        self.peers.retain(|src, peer| {
+            let quiet =
+                now_ms.saturating_sub(peer.last_ms) >= (5 * peer.interval_ms()).max(FORGET_MS);
+            if quiet && peer.reported {
+                gone.push(*src);
+            }
+            !quiet
+        });
+        gone
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(group: Option<u16>, seq: u32) -> Packet {
+        Packet {
+            group,
+            ..Packet::new(Some(seq), 1_700_000_000_000 + u64::from(seq) * 500)
+        }
+    }
+
+    #[test]
+    fn reports_another_sender_of_the_group_once_until_it_goes_quiet() {
+        let mut guard = Guard::new(Some(7));
+        let other: SocketAddr = "192.0.2.9:40000".parse().unwrap();
+
+        // Other groups and other message types share the port legitimately
+        assert_eq!(guard.heard(other, &data(Some(8), 1), 0), None);
+        assert_eq!(guard.heard(other, &data(None, 1), 0), None);
+        let heartbeat = Packet {
+            kind: MessageType::HEARTBEAT,
+            ..data(Some(7), 1)
+        };
+        assert_eq!(guard.heard(other, &heartbeat, 0), None);
+
+        assert_eq!(guard.heard(other, &data(Some(7), 1), 1_000), None);
+        let intruder = guard.heard(other, &data(Some(7), 2), 1_500).unwrap();
+        assert_eq!(
+            intruder.to_string(),
+            "src=192.0.2.9:40000 interval_ms=500 seq=2"
+        );
+        assert_eq!(guard.heard(other, &data(Some(7), 3), 2_000), None);
+
+        assert!(guard.expire(11_999).is_empty());
+        assert_eq!(guard.expire(12_000), [other]);
+        assert_eq!(guard.heard(other, &data(Some(7), 90), 20_000), None);
+        assert!(guard.heard(other, &data(Some(7), 91), 20_500).is_some());
+    }
+}
@@ -0,0 +1,400 @@
+//! HdrHistogram interval logs of receive offsets (`recv --hdr-log`).
+//!
+//! Each sender's offsets are recorded, in microseconds, into a histogram
+//! with three significant digits up to an hour. Every interval, each
+//! histogram that recorded anything is appended to the log as one line in
+//! the HdrHistogram log format (version 1.3), tagged with the sender, and
+//! cleared:
+//!
+//! ```text
+//! Tag=192.0.2.10,60.000,60.000,1.734,HISTFAAAA...
+//! ```
+//!
+//! so `HistogramLogProcessor`, `hdr-plot` and the other HdrHistogram tools
+//! read the whole distribution where min, mean and max would hide its
+//! modes. A histogram holds only values of zero or more; offsets below
+//! zero, where the sender's clock is ahead by more than the network delay,
+//! go to a second histogram tagged `<sender>/ahead`, by magnitude.
+//!
+//! Histograms are encoded as HdrHistogram's V2 compressed format expects,
+//! but the zlib stream inside uses uncompressed blocks, which every
+//! inflater reads, rather than carry a deflate implementation.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::start;
+
+/// Default for `--hdr-interval`.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Largest offset recorded, in microseconds; larger ones count as this.
+pub const HIGHEST: u64 = 3_600_000_000;
+const LOWEST: u64 = 1;
+const SIGNIFICANT_DIGITS: u32 = 3;
+
+/// 2 * 10^SIGNIFICANT_DIGITS values get unit resolution: 2048 sub-buckets,
+/// half of which each bucket past the first adds.
+const SUB_BUCKET_HALF_COUNT_MAGNITUDE: u32 = 10;
+const SUB_BUCKET_HALF_COUNT: usize = 1 << SUB_BUCKET_HALF_COUNT_MAGNITUDE;
+const SUB_BUCKET_COUNT: u64 = 2 << SUB_BUCKET_HALF_COUNT_MAGNITUDE;
+const LEADING_ZERO_COUNT_BASE: u32 = 64 - SUB_BUCKET_HALF_COUNT_MAGNITUDE - 1;
+
+const ENCODING_COOKIE: u32 = 0x1c84_9313;
+const COMPRESSED_ENCODING_COOKIE: u32 = 0x1c84_9314;
+/// Largest stored deflate block.
+const STORED_BLOCK: usize = 0xffff;
+
+/// One histogram of offsets.
+#[derive(Clone)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+    max: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram {
+            counts: vec![0; counts_len()],
+            total: 0,
+            max: 0,
+        }
+    }
+}
+
+/// Buckets needed to cover up to [`HIGHEST`].
+fn bucket_count() -> usize {
+    let mut untrackable = SUB_BUCKET_COUNT;
+    let mut buckets = 1;
+    while untrackable <= HIGHEST {
+        untrackable <<= 1;
+        buckets += 1;
+    }
+    buckets
+}
+
+fn counts_len() -> usize {
+    (bucket_count() + 1) * SUB_BUCKET_HALF_COUNT
+}
+
+fn bucket_index(value: u64) -> u32 {
+    LEADING_ZERO_COUNT_BASE - (value | (SUB_BUCKET_COUNT - 1)).leading_zeros()
+}
+
+/// Index of the count `value` falls in.
+fn index_of(value: u64) -> usize {
+    let bucket = bucket_index(value);
+    let sub_bucket = (value >> bucket) as usize;
+    ((bucket as usize + 1) << SUB_BUCKET_HALF_COUNT_MAGNITUDE) + sub_bucket - SUB_BUCKET_HALF_COUNT
+}
+
+/// The highest value counted the same as `value`.
+fn highest_equivalent(value: u64) -> u64 {
+    let bucket = bucket_index(value);
+    let sub_bucket = value >> bucket;
+    let lowest = sub_bucket << bucket;
+    let size = if sub_bucket >= SUB_BUCKET_COUNT {
+        1 << (bucket + 1)
+    } else {
+        1 << bucket
+    };
+    lowest + size - 1
+}
+
+impl Histogram {
+    pub fn record(&mut self, value: u64) {
+        let value = value.min(HIGHEST);
+        self.counts[index_of(value)] += 1;
+        self.total += 1;
+        self.max = self.max.max(value);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.total == 0
+    }
+
+    /// The largest value recorded, as the histogram reports it.
+    pub fn max(&self) -> u64 {
+        if self.total == 0 {
+            0
+        } else {
+            highest_equivalent(self.max)
+        }
+    }
+
+    /// Encodes the histogram in the V2 compressed format.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut plain = Vec::new();
+        plain.extend_from_slice(&ENCODING_COOKIE.to_be_bytes());
+        plain.extend_from_slice(&0u32.to_be_bytes()); // payload length, below
+        plain.extend_from_slice(&0u32.to_be_bytes()); // normalizing index offset
+        plain.extend_from_slice(&SIGNIFICANT_DIGITS.to_be_bytes());
+        plain.extend_from_slice(&LOWEST.to_be_bytes());
+        plain.extend_from_slice(&HIGHEST.to_be_bytes());
+        plain.extend_from_slice(&1f64.to_be_bytes()); // integer to double ratio
+        let header = plain.len();
+
+        // Counts as ZigZag LEB128, with a run of zeros as its negated length
+        let limit = index_of(self.max) + 1;
+        let mut i = 0;
+        while i < limit {
+            let count = self.counts[i];
+            i += 1;
+            let mut zeros = 0;
+            if count == 0 {
+                zeros = 1;
+                while i < limit && self.counts[i] == 0 {
+                    zeros += 1;
+                    i += 1;
+                }
+            }
+            if zeros > 1 {
+                put_zigzag(&mut plain, -zeros);
+            } else {
+                put_zigzag(&mut plain, count as i64);
+            }
+        }
+        let payload = (plain.len() - header) as u32;
+        plain[4..8].copy_from_slice(&payload.to_be_bytes());
+
+        let zlib = zlib_stored(&plain);
+        let mut out = Vec::with_capacity(8 + zlib.len());
+        out.extend_from_slice(&COMPRESSED_ENCODING_COOKIE.to_be_bytes());
+        out.extend_from_slice(&(zlib.len() as u32).to_be_bytes());
+        out.extend_from_slice(&zlib);
+        out
+    }
+}
+
+/// Appends `value` ZigZag-encoded as HdrHistogram does: LEB128, with the
+/// ninth byte, if any, carrying eight bits.
+fn put_zigzag(out: &mut Vec<u8>, value: i64) {
+    let mut v = ((value << 1) ^ (value >> 63)) as u64;
+    for _ in 0..8 {
+        if v >> 7 == 0 {
+            out.push(v as u8);
+            return;
+        }
+        out.push((v & 0x7f) as u8 | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+/// Wraps `data` in a zlib stream of stored deflate blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        out.push(u8::from(blocks.peek().is_none()));
+        let len = block.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + u32::from(byte)) % 65_521;
+        b = (b + a) % 65_521;
+    }
+    out.extend_from_slice(&((b << 16) | a).to_be_bytes());
+    out
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn secs(t: SystemTime) -> f64 {
+    t.duration_since(UNIX_EPOCH)
+        .map_or(0.0, |d| d.as_secs_f64())
+}
+
+/// Writes each sender's offset histogram to a log every interval.
+pub struct HdrLog {
+    file: BufWriter<File>,
+    path: String,
+    interval: Duration,
+    /// When the log, and the interval being recorded, started.
+    start: SystemTime,
+    interval_start: SystemTime,
+    histograms: BTreeMap<String, Histogram>,
+}
+
+impl HdrLog {
+    /// Creates the log at `path`, replacing any file there, and writes its
+    /// header.
+    pub fn create(path: &str, interval: Duration, now: SystemTime) -> Result<HdrLog, String> {
+        let file = File::create(path).map_err(|e| format!("Failed to create {path}: {e}"))?;
+        let mut log = HdrLog {
+            file: BufWriter::new(file),
+            path: path.to_string(),
+            interval,
+            start: now,
+            interval_start: now,
+            histograms: BTreeMap::new(),
+        };
+        let header = format!(
+            "#[Logged with udp_bcast_ts {}]\n\
+             #[Histogram log format version 1.3]\n\
+             #[StartTime: {:.3} (seconds since epoch), {}]\n\
+             #[BaseTime: {:.3} (seconds since epoch)]\n\
+             \"StartTimestamp\",\"Interval_Length\",\"Interval_Max\",\"Interval_Compressed_Histogram\"\n",
+            env!("CARGO_PKG_VERSION"),
+            secs(now),
+            start::format_rfc3339(now),
+            secs(now),
+        );
+        log.write(header.as_bytes())?;
+        Ok(log)
+    }
+
+    /// Records an offset from `sender`.
+    pub fn observe(&mut self, sender: &str, offset_us: i64) {
+        let tag = if offset_us < 0 {
+            format!("{sender}/ahead")
+        } else {
+            sender.to_string()
+        };
+        self.histograms
+            .entry(tag)
+            .or_default()
+            .record(offset_us.unsigned_abs());
+    }
+
+    /// Writes out the interval if it has ended by `now`.
+    pub fn tick(&mut self, now: SystemTime) -> Result<(), String> {
+        match now.duration_since(self.interval_start) {
+            Ok(elapsed) if elapsed >= self.interval => self.flush(now),
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes out the interval so far, ending at `now`, and starts the next.
+    pub fn flush(&mut self, now: SystemTime) -> Result<(), String> {
+        let start = self
+            .interval_start
+            .duration_since(self.start)
+            .unwrap_or_default();
+        let length = now.duration_since(self.interval_start).unwrap_or_default();
+        let mut lines = String::new();
+        for (tag, h) in self.histograms.iter_mut().filter(|(_, h)| !h.is_empty()) {
+            lines.push_str(&format!(
+                "Tag={tag},{:.3},{:.3},{:.3},{}\n",
+                start.as_secs_f64(),
+                length.as_secs_f64(),
+                // Interval_Max in milliseconds
+                h.max() as f64 / 1000.0,
+                base64(&h.encode())
+            ));
+            *h = Histogram::default();
+        }
+        self.interval_start = now;
+        self.write(lines.as_bytes())?;
+        self.file
+            .flush()
+            .map_err(|e| format!("Failed to write {}: {e}", self.path))
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), String> {
+        self.file
+            .write_all(data)
+            .map_err(|e| format!("Failed to write {}: {e}", self.path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads back a stream of stored blocks.
+    fn inflate_stored(zlib: &[u8]) -> Vec<u8> {
+        assert_eq!(&zlib[..2], [0x78, 0x01]);
+        let (mut out, mut pos) = (Vec::new(), 2);
+        loop {
+            let last = zlib[pos] & 1 == 1;
+            let len = u16::from_le_bytes([zlib[pos + 1], zlib[pos + 2]]) as usize;
+            out.extend_from_slice(&zlib[pos + 5..pos + 5 + len]);
+            pos += 5 + len;
+            if last {
+                return out;
+            }
+        }
+    }
+
+    fn get_zigzag(data: &[u8], pos: &mut usize) -> i64 {
+        let (mut v, mut shift) = (0u64, 0);
+        loop {
+            let b = data[*pos];
+            *pos += 1;
+            v |= u64::from(b & 0x7f) << shift;
+            shift += 7;
+            if b & 0x80 == 0 {
+                return (v >> 1) as i64 ^ -((v & 1) as i64);
+            }
+        }
+    }
+
+    #[test]
+    fn encodes_counts_as_hdrhistogram_reads_them() {
+        // Unit resolution up to 2048, then coarser by powers of two
+        assert_eq!(index_of(0), 0);
+        assert_eq!(index_of(2047), 2047);
+        assert_eq!((index_of(2048), index_of(2049)), (2048, 2048));
+        assert_eq!(highest_equivalent(2048), 2049);
+        assert_eq!(bucket_count(), 22);
+
+        let mut h = Histogram::default();
+        for v in [3, 3, 700, 2048, 2049, HIGHEST * 2] {
+            h.record(v);
+        }
+        let encoded = h.encode();
+        assert_eq!(&base64(&encoded)[..5], "HISTF");
+        assert_eq!(
+            u32::from_be_bytes(encoded[..4].try_into().unwrap()),
+            0x1c84_9314
+        );
+        let len = u32::from_be_bytes(encoded[4..8].try_into().unwrap()) as usize;
+        let plain = inflate_stored(&encoded[8..8 + len]);
+        assert_eq!(&plain[..4], 0x1c84_9313u32.to_be_bytes());
+        assert_eq!(&plain[12..16], 3u32.to_be_bytes());
+        assert_eq!(&plain[24..32], HIGHEST.to_be_bytes());
+
+        let mut counts = Vec::new();
+        let mut pos = 40;
+        while pos < plain.len() {
+            match get_zigzag(&plain, &mut pos) {
+                n if n < 0 => counts.extend(std::iter::repeat_n(0, -n as usize)),
+                n => counts.push(n as u64),
+            }
+        }
+        assert_eq!(counts.len(), index_of(HIGHEST) + 1);
+        assert_eq!((counts[3], counts[700], counts[2048]), (2, 1, 2));
+        assert_eq!(counts.iter().sum::<u64>(), 6);
+        assert_eq!(h.max(), highest_equivalent(HIGHEST));
+
+        assert_eq!(base64(b"ab"), "YWI=");
+        assert_eq!(zlib_stored(b"")[2..7], [1, 0, 0, 0xff, 0xff]);
+    }
+}
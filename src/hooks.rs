@@ -0,0 +1,94 @@
+//! Per-tick callbacks for programs embedding the sender
+//! ([`SendConfig::hooks`](crate::send::SendConfig::hooks)).
+//!
+//! `on_before_send` sees each timestamp before it is encoded, with the
+//! metadata fields it will carry, and can change the fields (say, to add a
+//! correlation ID) or veto the datagram. A vetoed datagram takes no
+//! sequence number, so receivers don't count it as lost. `on_sent` and
+//! `on_error` then see the outcome of each send, once per destination and
+//! `--redundancy` copy. With an I/O thread they see whether the datagram
+//! was queued, as the sender's own counters do.
+//!
+//! Hooks run on the send loop, between reading the clock and sending, so
+//! they should be quick: time spent in `on_before_send` is time the
+//! timestamp spends getting older. The payload size checked against the
+//! path MTU at startup is without the fields hooks add; a datagram they
+//! grow past it fails to send, to `on_error`.
+
+use std::io;
+use std::net::SocketAddr;
+
+use crate::payload::{Field, Packet};
+
+type OnBeforeSend = dyn Fn(&Packet, &mut Vec<Field>) -> Verdict + Send + Sync;
+type OnSent = dyn Fn(&Outcome) + Send + Sync;
+type OnError = dyn Fn(&Outcome, &io::Error) + Send + Sync;
+
+/// What `on_before_send` decides for a datagram.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Send,
+    Veto,
+}
+
+/// One datagram sent to one destination.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Outcome {
+    pub seq: u32,
+    pub ts_ms: u64,
+    pub dest: SocketAddr,
+}
+
+/// The callbacks to make, each optional.
+#[derive(Default)]
+pub struct Hooks {
+    before_send: Option<Box<OnBeforeSend>>,
+    sent: Option<Box<OnSent>>,
+    error: Option<Box<OnError>>,
+}
+
+impl Hooks {
+    pub fn new() -> Hooks {
+        Hooks::default()
+    }
+
+    /// Calls `f` with each packet about to be encoded and the fields it
+    /// will carry, starting from the configured ones. Changes apply to this
+    /// datagram only; v2 and proto payloads and custom codecs carry fields,
+    /// legacy and compact payloads ignore them. [`Field::parse`] checks
+    /// that a field will survive the trip.
+    pub fn on_before_send(
+        mut self,
+        f: impl Fn(&Packet, &mut Vec<Field>) -> Verdict + Send + Sync + 'static,
+    ) -> Hooks {
+        self.before_send = Some(Box::new(f));
+        self
+    }
+
+    /// Calls `f` after each successful send.
+    pub fn on_sent(mut self, f: impl Fn(&Outcome) + Send + Sync + 'static) -> Hooks {
+        self.sent = Some(Box::new(f));
+        self
+    }
+
+    /// Calls `f` after each failed send.
+    pub fn on_error(mut self, f: impl Fn(&Outcome, &io::Error) + Send + Sync + 'static) -> Hooks {
+        self.error = Some(Box::new(f));
+        self
+    }
+
+    pub(crate) fn before_send(&self, packet: &Packet, fields: &mut Vec<Field>) -> Verdict {
+        match &self.before_send {
+            Some(f) => f(packet, fields),
+            None => Verdict::Send,
+        }
+    }
+
+    pub(crate) fn after_send(&self, outcome: &Outcome, result: &io::Result<usize>) {
+        match (result, &self.sent, &self.error) {
+            (Ok(_), Some(f), _) => f(outcome),
+            (Err(e), _, Some(f)) => f(outcome, e),
+            _ => {}
+        }
+    }
+}
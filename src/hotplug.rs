@@ -0,0 +1,351 @@
+//! Interface hotplug notifications for send mode, and for receivers
+//! holding multicast memberships (see [`crate::membership`]).
+//!
+//! A dock/undock or VPN toggle takes an interface away and brings it back,
+//! sometimes under a new index and with new addresses. A socket and a
+//! destination chosen before the flap can keep failing afterwards, so the
+//! send loop watches for link and address changes and re-selects both when
+//! one arrives. On Linux the changes come from an rtnetlink socket
+//! subscribed to link and address events; elsewhere [`Monitor::open`]
+//! reports the feature as unavailable and nothing is watched.
+
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+
+/// One link or address change.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    /// An interface came up (with carrier) or went down or away.
+    Link { name: String, up: bool },
+    /// An address was added to or removed from an interface.
+    Addr {
+        name: String,
+        addr: IpAddr,
+        added: bool,
+    },
+    /// The kernel dropped notifications; anything may have changed.
+    Lost,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Link { name, up } => {
+                write!(f, "{name} {}", if *up { "up" } else { "down" })
+            }
+            Change::Addr { name, addr, added } => {
+                write!(
+                    f,
+                    "{name} {} {addr}",
+                    if *added { "added" } else { "removed" }
+                )
+            }
+            Change::Lost => f.write_str("notifications lost"),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use super::Change;
+    use std::collections::HashMap;
+    use std::ffi::CStr;
+    use std::io;
+    use std::mem;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    const NLMSG_HDRLEN: usize = 16;
+    const IFINFOMSG_LEN: usize = 16;
+    const IFADDRMSG_LEN: usize = 8;
+    const RTA_HDRLEN: usize = 4;
+
+    fn align(len: usize) -> usize {
+        (len + 3) & !3
+    }
+
+    fn u16_at(buf: &[u8], at: usize) -> u16 {
+        u16::from_ne_bytes([buf[at], buf[at + 1]])
+    }
+
+    fn u32_at(buf: &[u8], at: usize) -> u32 {
+        u32::from_ne_bytes(buf[at..at + 4].try_into().unwrap())
+    }
+
+    /// Iterates over the (type, payload) route attributes in `buf`.
+    fn attrs(mut buf: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+        std::iter::from_fn(move || {
+            if buf.len() < RTA_HDRLEN {
+                return None;
+            }
+            let len = usize::from(u16_at(buf, 0));
+            if len < RTA_HDRLEN || len > buf.len() {
+                return None;
+            }
+            let attr = (u16_at(buf, 2), &buf[RTA_HDRLEN..len]);
+            buf = &buf[align(len).min(buf.len())..];
+            Some(attr)
+        })
+    }
+
+    fn interface_name(index: u32) -> String {
+        let mut name = [0 as libc::c_char; libc::IF_NAMESIZE];
+        // SAFETY: `name` has room for IF_NAMESIZE bytes, as if_indextoname requires.
+        let found = unsafe { !libc::if_indextoname(index, name.as_mut_ptr()).is_null() };
+        if found {
+            // SAFETY: on success if_indextoname wrote a NUL-terminated name.
+            unsafe { CStr::from_ptr(name.as_ptr()) }
+                .to_string_lossy()
+                .into_owned()
+        } else {
+            // The interface is already gone
+            format!("if{index}")
+        }
+    }
+
+    /// Decodes the link and address messages in one netlink datagram.
+    /// `links` holds each interface's last known state, so that repeats of
+    /// it aren't reported.
+    pub fn parse(buf: &[u8], links: &mut HashMap<u32, bool>) -> Vec<Change> {
+        let mut changes = Vec::new();
+        let mut rest = buf;
+        while rest.len() >= NLMSG_HDRLEN {
+            let len = u32_at(rest, 0) as usize;
+            if len < NLMSG_HDRLEN || len > rest.len() {
+                break;
+            }
+            let kind = u16_at(rest, 4);
+            let body = &rest[NLMSG_HDRLEN..len];
+            rest = &rest[align(len).min(rest.len())..];
+
+            match kind {
+                libc::RTM_NEWLINK | libc::RTM_DELLINK if body.len() >= IFINFOMSG_LEN => {
+                    let index = u32_at(body, 4);
+                    let flags = u32_at(body, 8) as libc::c_int;
+                    let running = libc::IFF_UP | libc::IFF_RUNNING;
+                    let up = kind == libc::RTM_NEWLINK && flags & running == running;
+                    if links.insert(index, up) == Some(up) {
+                        continue;
+                    }
+                    let name = attrs(&body[IFINFOMSG_LEN..])
+                        .find(|&(t, _)| t == libc::IFLA_IFNAME)
+                        .map(|(_, v)| {
+                            String::from_utf8_lossy(v.split(|&b| b == 0).next().unwrap_or(v))
+                                .into_owned()
+                        })
+                        .unwrap_or_else(|| interface_name(index));
+                    changes.push(Change::Link { name, up });
+                }
+                libc::RTM_NEWADDR | libc::RTM_DELADDR if body.len() >= IFADDRMSG_LEN => {
+                    let index = u32_at(body, 4);
+                    let mut addr = None;
+                    for (t, v) in attrs(&body[IFADDRMSG_LEN..]) {
+                        let ip = match v.len() {
+                            4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(v).unwrap())),
+                            16 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(v).unwrap())),
+                            _ => continue,
+                        };
+                        // IFA_LOCAL is the interface's own address on
+                        // point-to-point links, where IFA_ADDRESS is the peer's
+                        if t == libc::IFA_LOCAL || (t == libc::IFA_ADDRESS && addr.is_none()) {
+                            addr = Some(ip);
+                        }
+                    }
+                    if let Some(addr) = addr {
+                        changes.push(Change::Addr {
+                            name: interface_name(index),
+                            addr,
+                            added: kind == libc::RTM_NEWADDR,
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+        changes
+    }
+
+    pub struct Monitor {
+        fd: OwnedFd,
+        links: HashMap<u32, bool>,
+    }
+
+    impl Monitor {
+        pub fn open() -> io::Result<Monitor> {
+            // SAFETY: plain socket(2) call; the descriptor is owned below.
+            let fd = unsafe {
+                libc::socket(
+                    libc::AF_NETLINK,
+                    libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                    libc::NETLINK_ROUTE,
+                )
+            };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: `fd` is a fresh descriptor nothing else owns.
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+            // SAFETY: sockaddr_nl is plain data; all-zero is a valid value.
+            let mut sa: libc::sockaddr_nl = unsafe { mem::zeroed() };
+            sa.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+            sa.nl_groups =
+                (libc::RTMGRP_LINK | libc::RTMGRP_IPV4_IFADDR | libc::RTMGRP_IPV6_IFADDR) as u32;
+            // SAFETY: `sa` outlives the call and its size is passed alongside it.
+            let rc = unsafe {
+                libc::bind(
+                    fd.as_raw_fd(),
+                    &sa as *const libc::sockaddr_nl as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+                )
+            };
+            if rc != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Monitor {
+                fd,
+                links: HashMap::new(),
+            })
+        }
+
+        pub fn poll(&mut self) -> io::Result<Vec<Change>> {
+            let mut changes = Vec::new();
+            let mut buf = [0u8; 8192];
+            loop {
+                // SAFETY: `buf` is valid for writes of its length.
+                let n = unsafe {
+                    libc::recv(
+                        self.fd.as_raw_fd(),
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        buf.len(),
+                        0,
+                    )
+                };
+                if n < 0 {
+                    let e = io::Error::last_os_error();
+                    match e.raw_os_error() {
+                        Some(libc::EAGAIN) => return Ok(changes),
+                        // The receive buffer overflowed during a storm of changes
+                        Some(libc::ENOBUFS) => {
+                            self.links.clear();
+                            changes.push(Change::Lost);
+                            continue;
+                        }
+                        _ => return Err(e),
+                    }
+                }
+                changes.extend(parse(&buf[..n as usize], &mut self.links));
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use super::Change;
+    use std::io;
+
+    pub enum Monitor {}
+
+    impl Monitor {
+        pub fn open() -> io::Result<Monitor> {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "interface change notifications are only supported on Linux",
+            ))
+        }
+
+        pub fn poll(&mut self) -> io::Result<Vec<Change>> {
+            match *self {}
+        }
+    }
+}
+
+/// Watches for interface link and address changes.
+pub struct Monitor(imp::Monitor);
+
+impl Monitor {
+    /// Subscribes to link and address changes.
+    pub fn open() -> io::Result<Monitor> {
+        imp::Monitor::open().map(Monitor)
+    }
+
+    /// Returns the changes since the last call, without blocking. A link
+    /// whose state didn't change is reported once at most.
+    pub fn poll(&mut self) -> io::Result<Vec<Change>> {
+        self.0.poll()
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn message(kind: u16, body: &[u8], attrs: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut payload = body.to_vec();
+        for &(t, v) in attrs {
+            payload.extend_from_slice(&((4 + v.len()) as u16).to_ne_bytes());
+            payload.extend_from_slice(&t.to_ne_bytes());
+            payload.extend_from_slice(v);
+            payload.resize(payload.len().next_multiple_of(4), 0);
+        }
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&(16 + payload.len() as u32).to_ne_bytes());
+        msg.extend_from_slice(&kind.to_ne_bytes());
+        msg.extend_from_slice(&[0; 10]);
+        msg.extend_from_slice(&payload);
+        msg
+    }
+
+    fn link(kind: u16, index: u32, flags: libc::c_int) -> Vec<u8> {
+        let mut body = [0u8; 16];
+        body[4..8].copy_from_slice(&index.to_ne_bytes());
+        body[8..12].copy_from_slice(&(flags as u32).to_ne_bytes());
+        message(kind, &body, &[(libc::IFLA_IFNAME, b"eth9\0")])
+    }
+
+    #[test]
+    fn decodes_link_and_address_changes() {
+        let mut links = HashMap::new();
+        let up = libc::IFF_UP | libc::IFF_RUNNING;
+        let mut buf = link(libc::RTM_NEWLINK, 7, up);
+        // A repeat of the same state is not a change
+        buf.extend(link(libc::RTM_NEWLINK, 7, up));
+        let mut addr = [0u8; 8];
+        addr[0] = libc::AF_INET as u8;
+        addr[4..8].copy_from_slice(&u32::MAX.to_ne_bytes());
+        buf.extend(message(
+            libc::RTM_NEWADDR,
+            &addr,
+            &[
+                (libc::IFA_ADDRESS, &[10, 0, 0, 9]),
+                (libc::IFA_LOCAL, &[10, 0, 0, 2]),
+            ],
+        ));
+        buf.extend(link(libc::RTM_DELLINK, 7, 0));
+
+        assert_eq!(
+            imp::parse(&buf, &mut links),
+            [
+                Change::Link {
+                    name: "eth9".to_string(),
+                    up: true
+                },
+                Change::Addr {
+                    name: format!("if{}", u32::MAX),
+                    addr: "10.0.0.2".parse().unwrap(),
+                    added: true
+                },
+                Change::Link {
+                    name: "eth9".to_string(),
+                    up: false
+                },
+            ]
+        );
+        // Truncated input stops the walk rather than panicking
+        assert!(imp::parse(&buf[..20], &mut links).is_empty());
+        assert!(Monitor::open().is_ok());
+    }
+}
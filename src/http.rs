@@ -0,0 +1,273 @@
+//! The HTTP/1.0 spoken by `--status-addr`, `--control-api` and
+//! `recv --web-addr`: one request per connection, read up to a bounded
+//! size, then one response and the connection closes.
+//!
+//! A request line or header longer than [`MAX_LINE`] bytes, more than
+//! [`MAX_HEADERS`] headers or a body over [`MAX_BODY`] bytes fails the
+//! request instead of growing a buffer for as long as the client keeps
+//! sending, and so does a request not in within [`CLIENT_TIMEOUT`].
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+/// Longest a client may take to send its whole request; a slow one times
+/// out rather than holding up the clients behind it, however steadily it
+/// trickles bytes.
+pub const CLIENT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Longest request line or header, in bytes, line ending included.
+pub const MAX_LINE: usize = 8192;
+
+/// Most headers read.
+pub const MAX_HEADERS: usize = 100;
+
+/// Largest request body. None of the servers use one, so it is read and
+/// discarded.
+pub const MAX_BODY: u64 = 65536;
+
+/// What a client asked for.
+#[derive(Debug, Default)]
+pub struct Request {
+    pub method: String,
+    /// Path and query, as sent.
+    pub target: String,
+    /// Names lowercased, values trimmed.
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    /// The value of the first header called `name`, in lowercase.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Reads a request from `stream`, headers, body and all, so the client
+/// sees a clean close.
+pub fn read_request(stream: &TcpStream) -> io::Result<Request> {
+    let mut reader = BufReader::new(Deadline {
+        stream,
+        until: Instant::now() + CLIENT_TIMEOUT,
+    });
+    let mut line = String::new();
+    read_line(&mut reader, &mut line)?;
+    let mut parts = line.split_whitespace();
+    let mut request = Request {
+        method: parts.next().unwrap_or_default().to_string(),
+        target: parts.next().unwrap_or_default().to_string(),
+        headers: Vec::new(),
+    };
+    loop {
+        if read_line(&mut reader, &mut line)? == 0 || line == "\r\n" || line == "\n" {
+            break;
+        }
+        if request.headers.len() == MAX_HEADERS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "too many request headers",
+            ));
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            request
+                .headers
+                .push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+    }
+    let body = match request.header("content-length") {
+        Some(len) => len
+            .parse::<u64>()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad Content-Length"))?,
+        None => 0,
+    };
+    if body > MAX_BODY {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("request body over {MAX_BODY} bytes"),
+        ));
+    }
+    if io::copy(&mut reader.take(body), &mut io::sink())? < body {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "truncated request body",
+        ));
+    }
+    Ok(request)
+}
+
+/// Reads from a stream until a deadline, rather than for as long as each
+/// read brings a byte before the timeout.
+struct Deadline<'a> {
+    stream: &'a TcpStream,
+    until: Instant,
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timed_out = || {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                "request not in within the client timeout",
+            )
+        };
+        let left = self.until.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(timed_out());
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        let mut stream = self.stream;
+        // A timed out read is WouldBlock on Unix and TimedOut on Windows
+        stream.read(buf).map_err(|e| match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => timed_out(),
+            _ => e,
+        })
+    }
+}
+
+/// Reads one line into `line`, replacing what it held; 0 at the end of
+/// the stream.
+fn read_line(reader: &mut impl BufRead, line: &mut String) -> io::Result<usize> {
+    line.clear();
+    let n = reader.take(MAX_LINE as u64).read_line(line)?;
+    if n == MAX_LINE && !line.ends_with('\n') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("request line longer than {MAX_LINE} bytes"),
+        ));
+    }
+    Ok(n)
+}
+
+/// Writes a whole response. Each of `headers` is a `Name: value` line
+/// without its line ending.
+pub fn respond(
+    mut stream: &TcpStream,
+    status: &str,
+    content_type: &str,
+    headers: &[&str],
+    body: &str,
+) -> io::Result<()> {
+    let mut head = format!("HTTP/1.0 {status}\r\nContent-Type: {content_type}\r\n");
+    for header in headers {
+        head.push_str(header);
+        head.push_str("\r\n");
+    }
+    write!(
+        stream,
+        "{head}Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Shutdown, TcpListener};
+    use std::thread;
+
+    #[test]
+    fn reads_requests_and_refuses_endless_ones() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let send = |data: Vec<u8>| {
+            thread::spawn(move || {
+                let mut stream = TcpStream::connect(addr).unwrap();
+                // The server may stop reading and close first
+                let _ = stream.write_all(&data);
+                let mut reply = String::new();
+                let _ = stream.read_to_string(&mut reply);
+                reply
+            })
+        };
+
+        let client = send(b"POST /v1/pause HTTP/1.1\r\nAuthorization:  Bearer x \r\n\r\n".to_vec());
+        let (stream, _) = listener.accept().unwrap();
+        let request = read_request(&stream).unwrap();
+        assert_eq!(
+            (request.method.as_str(), request.target.as_str()),
+            ("POST", "/v1/pause")
+        );
+        assert_eq!(request.header("authorization"), Some("Bearer x"));
+        respond(&stream, "200 OK", "text/plain", &["X-A: 1"], "ok\n").unwrap();
+        drop(stream);
+        assert_eq!(
+            client.join().unwrap(),
+            "HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\nX-A: 1\r\nContent-Length: 3\r\nConnection: close\r\n\r\nok\n"
+        );
+
+        let mut long = b"GET /".to_vec();
+        long.resize(MAX_LINE * 2, b'a');
+        let client = send(long);
+        let (stream, _) = listener.accept().unwrap();
+        assert!(read_request(&stream).is_err());
+        drop(stream);
+        client.join().unwrap();
+
+        let many = format!(
+            "GET / HTTP/1.0\r\n{}\r\n",
+            "A: b\r\n".repeat(MAX_HEADERS + 1)
+        );
+        let client = send(many.into_bytes());
+        let (stream, _) = listener.accept().unwrap();
+        assert!(read_request(&stream).is_err());
+        drop(stream);
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn headers_end_at_a_blank_line_and_bodies_are_drained() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let read = |data: &'static [u8]| {
+            let client = thread::spawn(move || {
+                let mut stream = TcpStream::connect(addr).unwrap();
+                let _ = stream.write_all(data);
+                let _ = stream.shutdown(Shutdown::Write);
+                let _ = stream.read_to_end(&mut Vec::new());
+            });
+            let (stream, _) = listener.accept().unwrap();
+            let request = read_request(&stream);
+            drop(stream);
+            client.join().unwrap();
+            request
+        };
+
+        // Short header lines aren't the end of the headers
+        let request = read(b"GET / HTTP/1.0\nX\nA:\nB: c\n\n").unwrap();
+        assert_eq!(request.header("a"), Some(""));
+        assert_eq!(request.header("b"), Some("c"));
+
+        let request = read(b"POST /v1/pause HTTP/1.0\r\nContent-Length: 5\r\n\r\nhello").unwrap();
+        assert_eq!(request.target, "/v1/pause");
+        assert!(read(b"POST / HTTP/1.0\r\nContent-Length: 5\r\n\r\nhi").is_err());
+        assert!(read(b"POST / HTTP/1.0\r\nContent-Length: 99999999\r\n\r\n").is_err());
+        assert!(read(b"POST / HTTP/1.0\r\nContent-Length: -1\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn a_trickling_client_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            // Each header comes well within a read timeout of the last
+            for line in ["GET / HTTP/1.0\r\n"].into_iter().chain(["A: b\r\n"; 6]) {
+                if stream.write_all(line.as_bytes()).is_err() {
+                    break;
+                }
+                thread::sleep(CLIENT_TIMEOUT / 4);
+            }
+        });
+        let (stream, _) = listener.accept().unwrap();
+        let start = Instant::now();
+        let e = read_request(&stream).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < CLIENT_TIMEOUT * 2);
+        drop(stream);
+        client.join().unwrap();
+    }
+}
@@ -0,0 +1,546 @@
+//! Interface enumeration, for `--per-interface` broadcasts and the
+//! `interfaces` listing.
+//!
+//! The limited broadcast address 255.255.255.255 goes out of a single
+//! interface chosen by the OS (on Windows, whichever has the lowest
+//! metric), so hosts with several networks reach only one of them. Directed
+//! broadcasts to each subnet's own broadcast address are routed out of the
+//! interface that owns it on every platform. Interfaces are listed with
+//! `getifaddrs` on Unix and `GetAdaptersAddresses` on Windows.
+//!
+//! `udp_bcast_ts interfaces` prints every interface with what the options
+//! naming one need: its index and name for an IPv6 `%zone`, its addresses
+//! and broadcast addresses for `--addr`, and whether it does multicast.
+
+use std::fmt::Write as _;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::process::ExitCode;
+
+use crate::exit::{self, Failure};
+use crate::status::json_string;
+
+/// An IPv4 address assigned to an up, non-loopback interface.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Interface {
+    pub name: String,
+    pub addr: Ipv4Addr,
+    pub prefix_len: u8,
+}
+
+impl Interface {
+    /// The subnet's directed broadcast address, if the subnet has one
+    /// (/31 and /32 don't).
+    pub fn broadcast(&self) -> Option<Ipv4Addr> {
+        directed_broadcast(self.addr, self.prefix_len)
+    }
+}
+
+fn directed_broadcast(addr: Ipv4Addr, prefix_len: u8) -> Option<Ipv4Addr> {
+    if prefix_len >= 31 {
+        return None;
+    }
+    let host_mask = u32::MAX >> prefix_len;
+    Some(Ipv4Addr::from(u32::from(addr) | host_mask))
+}
+
+/// A network interface, whatever its state, with all its addresses.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Link {
+    pub name: String,
+    /// Interface index, as used for IPv6 zones; `None` if unknown.
+    pub index: Option<u32>,
+    pub up: bool,
+    pub loopback: bool,
+    /// Whether it can send broadcasts.
+    pub broadcast: bool,
+    pub multicast: bool,
+    pub mtu: Option<u32>,
+    /// Addresses with their prefix lengths, IPv4 first.
+    pub addrs: Vec<(IpAddr, u8)>,
+}
+
+impl Link {
+    /// The directed broadcast address for `addr`, if the interface
+    /// broadcasts and its subnet has one.
+    pub fn broadcast_for(&self, addr: IpAddr, prefix_len: u8) -> Option<Ipv4Addr> {
+        match addr {
+            IpAddr::V4(v4) if self.broadcast => directed_broadcast(v4, prefix_len),
+            _ => None,
+        }
+    }
+
+    fn ipv4(&self) -> impl Iterator<Item = (Ipv4Addr, u8)> + '_ {
+        self.addrs.iter().filter_map(|&(addr, len)| match addr {
+            IpAddr::V4(v4) => Some((v4, len)),
+            IpAddr::V6(_) => None,
+        })
+    }
+
+    fn flags(&self) -> String {
+        let flags = [
+            (self.up, "up"),
+            (!self.up, "down"),
+            (self.loopback, "loopback"),
+            (self.broadcast, "broadcast"),
+            (self.multicast, "multicast"),
+        ];
+        let set: Vec<&str> = flags.iter().filter(|f| f.0).map(|f| f.1).collect();
+        set.join(",")
+    }
+
+    /// `addr`, with the `%zone` a link-local IPv6 address needs.
+    fn zoned(&self, addr: IpAddr) -> String {
+        match addr {
+            IpAddr::V6(v6) if v6.segments()[0] & 0xffc0 == 0xfe80 => {
+                format!("{v6}%{}", self.name)
+            }
+            addr => addr.to_string(),
+        }
+    }
+}
+
+/// Adds an address to the link named `name`, first adding the link with
+/// `link` if it isn't in `links` yet.
+#[cfg_attr(not(any(unix, windows)), allow(dead_code))]
+fn add(links: &mut Vec<Link>, name: &str, link: impl FnOnce() -> Link, addr: Option<(IpAddr, u8)>) {
+    let i = match links.iter().position(|l| l.name == name) {
+        Some(i) => i,
+        None => {
+            links.push(link());
+            links.len() - 1
+        }
+    };
+    if let Some(addr) = addr {
+        if !links[i].addrs.contains(&addr) {
+            links[i].addrs.push(addr);
+        }
+    }
+}
+
+/// The prefix length of a netmask.
+#[cfg_attr(not(unix), allow(dead_code))]
+fn prefix_len(mask: &[u8]) -> u8 {
+    mask.iter().map(|b| b.count_ones()).sum::<u32>() as u8
+}
+
+#[cfg(unix)]
+mod imp {
+    use super::{add, prefix_len, Link};
+    use std::ffi::CStr;
+    use std::io;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use std::ptr;
+
+    /// An address and its netmask, if both are IPv4 or IPv6.
+    fn addr(sa: *const libc::sockaddr, mask: *const libc::sockaddr) -> Option<(IpAddr, u8)> {
+        // SAFETY: non-null sockaddrs from getifaddrs are valid for their family.
+        unsafe {
+            if sa.is_null() || mask.is_null() {
+                return None;
+            }
+            match (*sa).sa_family as libc::c_int {
+                libc::AF_INET => {
+                    let sin = &*(sa as *const libc::sockaddr_in);
+                    let mask = &*(mask as *const libc::sockaddr_in);
+                    let ip = Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
+                    let len = prefix_len(&mask.sin_addr.s_addr.to_ne_bytes());
+                    Some((IpAddr::V4(ip), len))
+                }
+                libc::AF_INET6 => {
+                    let sin6 = &*(sa as *const libc::sockaddr_in6);
+                    let mask = &*(mask as *const libc::sockaddr_in6);
+                    let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+                    Some((IpAddr::V6(ip), prefix_len(&mask.sin6_addr.s6_addr)))
+                }
+                _ => None,
+            }
+        }
+    }
+
+    /// The interface's MTU (SIOCGIFMTU).
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn mtu(name: &CStr) -> Option<u32> {
+        use std::os::fd::AsRawFd;
+
+        let sock = std::net::UdpSocket::bind("0.0.0.0:0").ok()?;
+        // SAFETY: an all-zero ifreq is valid; the name is copied in with
+        // room for its NUL, and the ioctl only writes within the struct.
+        unsafe {
+            let mut req: libc::ifreq = std::mem::zeroed();
+            let name = name.to_bytes();
+            if name.len() >= req.ifr_name.len() {
+                return None;
+            }
+            for (dst, &src) in req.ifr_name.iter_mut().zip(name) {
+                *dst = src as libc::c_char;
+            }
+            if libc::ioctl(sock.as_raw_fd(), libc::SIOCGIFMTU, &mut req) != 0 {
+                return None;
+            }
+            u32::try_from(req.ifr_ifru.ifru_mtu).ok()
+        }
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn mtu(_name: &CStr) -> Option<u32> {
+        None
+    }
+
+    pub fn links() -> io::Result<Vec<Link>> {
+        let mut ifap: *mut libc::ifaddrs = ptr::null_mut();
+        // SAFETY: on success getifaddrs stores a list we free below.
+        if unsafe { libc::getifaddrs(&mut ifap) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut links = Vec::new();
+        let mut cur = ifap;
+        while !cur.is_null() {
+            // SAFETY: `cur` walks the list getifaddrs returned.
+            let ifa = unsafe { &*cur };
+            cur = ifa.ifa_next;
+
+            let flags = ifa.ifa_flags as libc::c_int;
+            // SAFETY: ifa_name is a NUL-terminated string owned by the list.
+            let cname = unsafe { CStr::from_ptr(ifa.ifa_name) };
+            let name = cname.to_string_lossy();
+            let link = || Link {
+                name: name.to_string(),
+                index: crate::sys::interface_index(&name).ok(),
+                up: flags & libc::IFF_UP != 0,
+                loopback: flags & libc::IFF_LOOPBACK != 0,
+                broadcast: flags & libc::IFF_BROADCAST != 0,
+                multicast: flags & libc::IFF_MULTICAST != 0,
+                mtu: mtu(cname),
+                addrs: Vec::new(),
+            };
+            add(&mut links, &name, link, addr(ifa.ifa_addr, ifa.ifa_netmask));
+        }
+
+        // SAFETY: `ifap` came from getifaddrs and is freed exactly once.
+        unsafe { libc::freeifaddrs(ifap) };
+        Ok(links)
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use super::{add, Link};
+    use std::io;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use std::ptr;
+
+    use windows_sys::Win32::Foundation::{ERROR_BUFFER_OVERFLOW, NO_ERROR};
+    use windows_sys::Win32::NetworkManagement::IpHelper::{
+        GetAdaptersAddresses, GAA_FLAG_SKIP_ANYCAST, GAA_FLAG_SKIP_DNS_SERVER,
+        GAA_FLAG_SKIP_MULTICAST, IF_TYPE_SOFTWARE_LOOPBACK, IP_ADAPTER_ADDRESSES_LH,
+        IP_ADAPTER_NO_MULTICAST,
+    };
+    use windows_sys::Win32::NetworkManagement::Ndis::IfOperStatusUp;
+    use windows_sys::Win32::Networking::WinSock::{
+        AF_INET, AF_INET6, AF_UNSPEC, SOCKADDR_IN, SOCKADDR_IN6,
+    };
+
+    fn wide_to_string(p: *const u16) -> String {
+        if p.is_null() {
+            return String::new();
+        }
+        // SAFETY: the adapter's FriendlyName is NUL-terminated UTF-16.
+        unsafe {
+            let len = (0..).take_while(|&i| *p.add(i) != 0).count();
+            String::from_utf16_lossy(std::slice::from_raw_parts(p, len))
+        }
+    }
+
+    pub fn links() -> io::Result<Vec<Link>> {
+        let flags = GAA_FLAG_SKIP_ANYCAST | GAA_FLAG_SKIP_MULTICAST | GAA_FLAG_SKIP_DNS_SERVER;
+        // u64 elements keep the buffer aligned for the adapter structs
+        let mut buf: Vec<u64> = vec![0; 2048];
+        loop {
+            let mut size = (buf.len() * 8) as u32;
+            // SAFETY: `buf` is writable for `size` bytes.
+            let rc = unsafe {
+                GetAdaptersAddresses(
+                    AF_UNSPEC as u32,
+                    flags,
+                    ptr::null(),
+                    buf.as_mut_ptr() as *mut IP_ADAPTER_ADDRESSES_LH,
+                    &mut size,
+                )
+            };
+            match rc {
+                NO_ERROR => break,
+                ERROR_BUFFER_OVERFLOW => buf = vec![0; (size as usize).div_ceil(8)],
+                e => return Err(io::Error::from_raw_os_error(e as i32)),
+            }
+        }
+
+        let mut links = Vec::new();
+        let mut adapter = buf.as_ptr() as *const IP_ADAPTER_ADDRESSES_LH;
+        while !adapter.is_null() {
+            // SAFETY: `adapter` walks the list GetAdaptersAddresses wrote to `buf`.
+            let a = unsafe { &*adapter };
+            adapter = a.Next;
+            let name = wide_to_string(a.FriendlyName);
+            // SAFETY: both union members are plain integers.
+            let (index, adapter_flags) =
+                unsafe { (a.Anonymous1.Anonymous.IfIndex, a.Anonymous2.Flags) };
+            let index = if index != 0 { index } else { a.Ipv6IfIndex };
+            let loopback = a.IfType == IF_TYPE_SOFTWARE_LOOPBACK;
+            let link = || Link {
+                name: name.clone(),
+                index: (index != 0).then_some(index),
+                up: a.OperStatus == IfOperStatusUp,
+                loopback,
+                // Every IPv4 interface but loopback takes directed broadcasts
+                broadcast: !loopback,
+                multicast: adapter_flags & IP_ADAPTER_NO_MULTICAST == 0,
+                mtu: (a.Mtu != u32::MAX).then_some(a.Mtu),
+                addrs: Vec::new(),
+            };
+            add(&mut links, &name, link, None);
+
+            let mut unicast = a.FirstUnicastAddress;
+            while !unicast.is_null() {
+                // SAFETY: as above; addresses are valid for their family.
+                let u = unsafe { &*unicast };
+                unicast = u.Next;
+                let sa = u.Address.lpSockaddr;
+                if sa.is_null() {
+                    continue;
+                }
+                let ip = match unsafe { (*sa).sa_family } {
+                    AF_INET => {
+                        let sin = unsafe { &*(sa as *const SOCKADDR_IN) };
+                        IpAddr::V4(Ipv4Addr::from(u32::from_be(unsafe {
+                            sin.sin_addr.S_un.S_addr
+                        })))
+                    }
+                    AF_INET6 => {
+                        let sin6 = unsafe { &*(sa as *const SOCKADDR_IN6) };
+                        IpAddr::V6(Ipv6Addr::from(unsafe { sin6.sin6_addr.u.Byte }))
+                    }
+                    _ => continue,
+                };
+                add(&mut links, &name, link, Some((ip, u.OnLinkPrefixLength)));
+            }
+        }
+        Ok(links)
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod imp {
+    use super::Link;
+    use std::io;
+
+    pub fn links() -> io::Result<Vec<Link>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "interface enumeration is not supported on this platform",
+        ))
+    }
+}
+
+/// Lists every interface, in the order the OS gives them.
+pub fn links() -> io::Result<Vec<Link>> {
+    let mut links = imp::links()?;
+    for link in &mut links {
+        // IPv4 first, each family in the order given
+        link.addrs.sort_by_key(|(addr, _)| addr.is_ipv6());
+    }
+    Ok(links)
+}
+
+/// Lists IPv4 addresses on up, non-loopback interfaces.
+pub fn ipv4_interfaces() -> io::Result<Vec<Interface>> {
+    Ok(links()?
+        .into_iter()
+        .filter(|l| l.up && !l.loopback)
+        .flat_map(|l| {
+            l.ipv4()
+                .map(|(addr, prefix_len)| Interface {
+                    name: l.name.clone(),
+                    addr,
+                    prefix_len,
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect())
+}
+
+/// Settings for `interfaces`.
+pub struct ListConfig {
+    /// Print a JSON array instead of a table.
+    pub json: bool,
+}
+
+/// Prints the local interfaces.
+pub fn run(cfg: &ListConfig) -> ExitCode {
+    match links() {
+        Ok(links) if cfg.json => {
+            println!("{}", json(&links));
+            ExitCode::SUCCESS
+        }
+        Ok(links) => {
+            print!("{}", table(&links));
+            ExitCode::SUCCESS
+        }
+        Err(e) => exit::fail(Failure::io(&e, format!("Failed to list interfaces: {e}"))),
+    }
+}
+
+/// One row per address, the interface's details on its first.
+fn table(links: &[Link]) -> String {
+    let dash = || "-".to_string();
+    let mut rows =
+        vec![["INDEX", "NAME", "FLAGS", "MTU", "ADDRESS", "BROADCAST"].map(String::from)];
+    for link in links {
+        let mut details = Some([
+            link.index.map_or_else(dash, |i| i.to_string()),
+            link.name.clone(),
+            link.flags(),
+            link.mtu.map_or_else(dash, |m| m.to_string()),
+        ]);
+        let addrs = link.addrs.iter().map(|&(addr, len)| {
+            let broadcast = link.broadcast_for(addr, len);
+            (
+                format!("{}/{len}", link.zoned(addr)),
+                broadcast.map_or_else(dash, |b| b.to_string()),
+            )
+        });
+        let addrs: Vec<(String, String)> = if link.addrs.is_empty() {
+            vec![(dash(), dash())]
+        } else {
+            addrs.collect()
+        };
+        for (addr, broadcast) in addrs {
+            let [index, name, flags, mtu] = details.take().unwrap_or_default();
+            rows.push([index, name, flags, mtu, addr, broadcast]);
+        }
+    }
+    let widths: Vec<usize> = (0..6)
+        .map(|c| rows.iter().map(|r| r[c].chars().count()).max().unwrap_or(0))
+        .collect();
+    let mut out = String::new();
+    for row in &rows {
+        let mut line = String::new();
+        for (cell, width) in row.iter().zip(&widths) {
+            let _ = write!(line, "{cell:<width$}  ");
+        }
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+/// The interfaces as a JSON array.
+fn json(links: &[Link]) -> String {
+    let opt = |n: Option<u32>| n.map_or("null".to_string(), |n| n.to_string());
+    let objects: Vec<String> = links
+        .iter()
+        .map(|l| {
+            let addrs: Vec<String> = l
+                .addrs
+                .iter()
+                .map(|&(addr, len)| {
+                    let broadcast = l
+                        .broadcast_for(addr, len)
+                        .map_or("null".to_string(), |b| json_string(&b.to_string()));
+                    format!(
+                        "{{\"addr\":{},\"prefix_len\":{len},\"broadcast\":{broadcast}}}",
+                        json_string(&l.zoned(addr))
+                    )
+                })
+                .collect();
+            format!(
+                "{{\"index\":{},\"name\":{},\"up\":{},\"loopback\":{},\"broadcast\":{},\
+                 \"multicast\":{},\"mtu\":{},\"addrs\":[{}]}}",
+                opt(l.index),
+                json_string(&l.name),
+                l.up,
+                l.loopback,
+                l.broadcast,
+                l.multicast,
+                opt(l.mtu),
+                addrs.join(",")
+            )
+        })
+        .collect();
+    format!("[{}]", objects.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn iface(addr: [u8; 4], prefix_len: u8) -> Interface {
+        Interface {
+            name: "test0".to_string(),
+            addr: Ipv4Addr::from(addr),
+            prefix_len,
+        }
+    }
+
+    #[test]
+    fn broadcast_sets_host_bits() {
+        assert_eq!(
+            iface([192, 168, 1, 23], 24).broadcast(),
+            Some(Ipv4Addr::new(192, 168, 1, 255))
+        );
+        assert_eq!(
+            iface([10, 1, 2, 3], 8).broadcast(),
+            Some(Ipv4Addr::new(10, 255, 255, 255))
+        );
+        assert_eq!(
+            iface([172, 16, 5, 1], 0).broadcast(),
+            Some(Ipv4Addr::BROADCAST)
+        );
+    }
+
+    #[test]
+    fn point_to_point_subnets_have_no_broadcast() {
+        assert_eq!(iface([10, 0, 0, 1], 31).broadcast(), None);
+        assert_eq!(iface([10, 0, 0, 1], 32).broadcast(), None);
+    }
+
+    #[test]
+    fn lists_each_address_with_its_zone_and_broadcast() {
+        let links = [
+            Link {
+                name: "eth0".into(),
+                index: Some(2),
+                up: true,
+                broadcast: true,
+                multicast: true,
+                mtu: Some(1500),
+                addrs: vec![
+                    ("192.168.1.23".parse().unwrap(), 24),
+                    ("fe80::1".parse().unwrap(), 64),
+                ],
+                ..Link::default()
+            },
+            Link {
+                name: "wg0".into(),
+                index: Some(5),
+                mtu: Some(1420),
+                ..Link::default()
+            },
+        ];
+        assert_eq!(
+            table(&links),
+            "INDEX  NAME  FLAGS                   MTU   ADDRESS          BROADCAST\n\
+             2      eth0  up,broadcast,multicast  1500  192.168.1.23/24  192.168.1.255\n\
+             \x20                                          fe80::1%eth0/64  -\n\
+             5      wg0   down                    1420  -                -\n"
+        );
+        assert_eq!(
+            json(&links[1..]),
+            r#"[{"index":5,"name":"wg0","up":false,"loopback":false,"broadcast":false,"multicast":false,"mtu":1420,"addrs":[]}]"#
+        );
+        assert!(json(&links[..1]).contains(
+            r#"{"addr":"192.168.1.23","prefix_len":24,"broadcast":"192.168.1.255"},{"addr":"fe80::1%eth0","prefix_len":64,"broadcast":null}"#
+        ));
+    }
+}
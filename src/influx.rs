@@ -0,0 +1,320 @@
+//! InfluxDB line protocol output from listen mode (`--influx`).
+//!
+//! Every accepted packet becomes a point in the `udp_bcast_ts`
+//! measurement, stamped with its arrival time in nanoseconds and tagged
+//! with the sender (its IP, or `IP/GROUP`), the receiving node's host name
+//! and the sender's `--field`s:
+//!
+//! ```text
+//! udp_bcast_ts,node=rx1,sender=10.0.0.5,site=lab offset_ms=-3i,seq=42i,lost=0i 1700000000123000000
+//! ```
+//!
+//! Points are POSTed to an InfluxDB write endpoint over HTTP, sent as
+//! datagrams to a UDP listener (InfluxDB 1.x, Telegraf's
+//! `socket_listener`), or printed on stdout. Writes happen on a thread of
+//! their own, in batches of up to [`MAX_BATCH`] points or
+//! [`FLUSH_INTERVAL`], and datagrams are sized as statsd's. As with statsd
+//! and OTLP, output is best effort: a failed write is logged and its
+//! points dropped, as are points arriving while the queue is full.
+
+use std::fs;
+use std::io::Write;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::export::Observation;
+use crate::log;
+use crate::otlp::{self, Endpoint};
+use crate::send::bind_for;
+use crate::statsd::MAX_DATAGRAM;
+use crate::transmit;
+
+const MEASUREMENT: &str = "udp_bcast_ts";
+
+/// Points written in one request at most.
+pub const MAX_BATCH: usize = 5000;
+
+/// Longest a point waits before it is written.
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Points queued for the writer thread at most.
+const MAX_QUEUE: usize = 4 * MAX_BATCH;
+
+/// Longest one HTTP write may take.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where `--influx` writes points.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Target {
+    /// A write endpoint, e.g. `/api/v2/write?org=..&bucket=..`.
+    Http(Endpoint),
+    Udp(SocketAddr),
+    Stdout,
+}
+
+impl Target {
+    /// Parses `http://HOST[:PORT]/PATH?QUERY`, `udp://HOST:PORT` or `-`,
+    /// resolving the host once.
+    pub fn parse(s: &str) -> Result<Target, String> {
+        if s == "-" {
+            return Ok(Target::Stdout);
+        }
+        match s.strip_prefix("udp://") {
+            Some(addr) => addr
+                .to_socket_addrs()
+                .map_err(|e| format!("Failed to resolve {s}: {e}"))?
+                .next()
+                .map(Target::Udp)
+                .ok_or_else(|| format!("{s} resolved to nothing")),
+            None => Endpoint::parse(s).map(Target::Http),
+        }
+    }
+}
+
+/// Settings for `--influx`.
+#[derive(Clone, Debug)]
+pub struct InfluxConfig {
+    pub target: Target,
+    /// Sent as `Authorization: Token <token>` over HTTP.
+    pub token: Option<String>,
+    /// The `node` tag: this host's name.
+    pub node: String,
+}
+
+impl InfluxConfig {
+    /// Reads an API token file; surrounding whitespace is ignored.
+    pub fn load_token(path: &str) -> Result<String, String> {
+        let token = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read token file {path}: {e}"))?;
+        match token.trim() {
+            "" => Err(format!("Token file {path} is empty")),
+            t => Ok(t.to_string()),
+        }
+    }
+}
+
+/// Hands points to the writer thread.
+pub struct Influx {
+    tx: Option<SyncSender<String>>,
+    node: String,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl Influx {
+    /// Starts the writer thread.
+    pub fn start(cfg: InfluxConfig) -> Result<Influx, String> {
+        let out = match &cfg.target {
+            Target::Http(e) => {
+                log::info!("Writing InfluxDB points to http://{}{}", e.host, e.path);
+                let headers = cfg
+                    .token
+                    .iter()
+                    .map(|t| ("Authorization".to_string(), format!("Token {t}")))
+                    .collect();
+                Output::Http(e.clone(), headers)
+            }
+            Target::Udp(dest) => {
+                log::info!("Writing InfluxDB points to udp://{dest}");
+                Output::Udp(bind_for(*dest)?, *dest)
+            }
+            Target::Stdout => Output::Stdout,
+        };
+        let (tx, rx) = mpsc::sync_channel(MAX_QUEUE);
+        let writer = log::spawn(move || write(out, rx));
+        Ok(Influx {
+            tx: Some(tx),
+            node: cfg.node,
+            writer: Some(writer),
+        })
+    }
+
+    /// Queues the point for one accepted packet; `lost` is how many
+    /// sequence numbers it showed missing, `jitter_us` the sender's
+    /// arrival jitter if known.
+    pub fn observe(&self, o: &Observation, sender: &str, lost: u64, jitter_us: Option<u64>) {
+        let line = point(o, &self.node, sender, lost, jitter_us);
+        if let Some(Err(TrySendError::Full(_))) = self.tx.as_ref().map(|tx| tx.try_send(line)) {
+            log::debug!("InfluxDB queue full; point dropped");
+        }
+    }
+}
+
+impl Drop for Influx {
+    /// Writes what is queued before returning.
+    fn drop(&mut self) {
+        self.tx = None;
+        if let Some(w) = self.writer.take() {
+            let _ = w.join();
+        }
+    }
+}
+
+enum Output {
+    Http(Endpoint, Vec<(String, String)>),
+    Udp(UdpSocket, SocketAddr),
+    Stdout,
+}
+
+impl Output {
+    fn flush(&self, batch: &mut Vec<String>) {
+        if batch.is_empty() {
+            return;
+        }
+        let result = match self {
+            Output::Http(e, headers) => {
+                let body = batch.join("\n");
+                otlp::post(e, headers, TIMEOUT, "text/plain; charset=utf-8", &body)
+            }
+            Output::Udp(sock, dest) => datagrams(batch).iter().try_for_each(|d| {
+                transmit::send_to(sock, d.as_bytes(), *dest)
+                    .map(drop)
+                    .map_err(|e| e.to_string())
+            }),
+            Output::Stdout => {
+                let mut out = std::io::stdout().lock();
+                batch
+                    .iter()
+                    .try_for_each(|l| writeln!(out, "{l}"))
+                    .and_then(|_| out.flush())
+                    .map_err(|e| e.to_string())
+            }
+        };
+        if let Err(e) = result {
+            log::warn!("InfluxDB write of {} point(s) failed: {e}", batch.len());
+        }
+        batch.clear();
+    }
+}
+
+fn write(out: Output, rx: Receiver<String>) {
+    let mut batch = Vec::new();
+    let mut due = Instant::now() + FLUSH_INTERVAL;
+    loop {
+        match rx.recv_timeout(due.saturating_duration_since(Instant::now())) {
+            Ok(line) => {
+                if batch.is_empty() {
+                    due = Instant::now() + FLUSH_INTERVAL;
+                }
+                batch.push(line);
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                out.flush(&mut batch);
+                return;
+            }
+        }
+        // Stdout is meant to be piped, so it isn't held back
+        if matches!(out, Output::Stdout) || batch.len() >= MAX_BATCH || Instant::now() >= due {
+            out.flush(&mut batch);
+            due = Instant::now() + FLUSH_INTERVAL;
+        }
+    }
+}
+
+/// Packs lines into datagrams of at most [`MAX_DATAGRAM`] bytes; a longer
+/// line goes alone.
+fn datagrams(lines: &[String]) -> Vec<String> {
+    let mut out: Vec<String> = Vec::new();
+    for line in lines {
+        match out.last_mut() {
+            Some(d) if d.len() + 1 + line.len() <= MAX_DATAGRAM => {
+                d.push('\n');
+                d.push_str(line);
+            }
+            _ => out.push(line.clone()),
+        }
+    }
+    out
+}
+
+/// Escapes a tag key or value.
+fn tag(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, ',' | '=' | ' ' | '\\') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Formats the point for one observation.
+fn point(o: &Observation, node: &str, sender: &str, lost: u64, jitter_us: Option<u64>) -> String {
+    let mut tags = vec![("node", node), ("sender", sender)];
+    // A sender's field can't override ours
+    for (k, v) in o.fields.iter().filter_map(|f| f.split_once('=')) {
+        if !tags.iter().any(|(t, _)| *t == k) && !v.is_empty() {
+            tags.push((k, v));
+        }
+    }
+    tags.sort();
+    let mut line = MEASUREMENT.to_string();
+    for (k, v) in tags {
+        line.push_str(&format!(",{}={}", tag(k), tag(v)));
+    }
+    line.push_str(&format!(" offset_ms={}i", o.offset_ms));
+    if let Some(seq) = o.seq {
+        line.push_str(&format!(",seq={seq}i"));
+    }
+    line.push_str(&format!(",lost={lost}i"));
+    if let Some(us) = o.host_delay_us {
+        line.push_str(&format!(",host_delay_us={us}i"));
+    }
+    if let Some(us) = jitter_us {
+        line.push_str(&format!(",jitter_us={us}i"));
+    }
+    if let Some(a) = &o.annotation {
+        let a = a.replace('\\', "\\\\").replace('"', "\\\"");
+        line.push_str(&format!(",annotation=\"{a}\""));
+    }
+    line.push_str(&format!(" {}", o.recv_ms * 1_000_000));
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn observation() -> Observation {
+        Observation {
+            recv_ms: 1_700_000_000_123,
+            send_ms: 1_700_000_000_126,
+            src: "10.0.0.5:12321".parse().unwrap(),
+            group: Some(3),
+            seq: Some(42),
+            offset_ms: -3,
+            host_delay_us: None,
+            annotation: None,
+            fields: vec!["site=lab 2".to_string(), "node=spoof".to_string()],
+        }
+    }
+
+    #[test]
+    fn formats_points_and_batches_datagrams() {
+        let mut o = observation();
+        assert_eq!(
+            point(&o, "rx1", "10.0.0.5/3", 0, None),
+            "udp_bcast_ts,node=rx1,sender=10.0.0.5/3,site=lab\\ 2 \
+             offset_ms=-3i,seq=42i,lost=0i 1700000000123000000"
+        );
+        o.host_delay_us = Some(80);
+        o.annotation = Some("temp=\"41\"".to_string());
+        o.fields.clear();
+        assert_eq!(
+            point(&o, "rx,1", "10.0.0.5", 2, Some(150)),
+            "udp_bcast_ts,node=rx\\,1,sender=10.0.0.5 offset_ms=-3i,seq=42i,lost=2i,\
+             host_delay_us=80i,jitter_us=150i,annotation=\"temp=\\\"41\\\"\" 1700000000123000000"
+        );
+
+        // 99-byte lines: 14 fit in one datagram, newlines included
+        let lines = vec!["x".repeat(99); 20];
+        let d = datagrams(&lines);
+        assert_eq!(
+            d.iter().map(String::len).collect::<Vec<_>>(),
+            [14 * 100 - 1, 6 * 100 - 1]
+        );
+    }
+}
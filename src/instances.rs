@@ -0,0 +1,384 @@
+//! Several senders in one process (`multi`), configured in a TOML file.
+//!
+//! Each `[instances.<name>]` table is one sender, its keys the `send`
+//! options without the leading dashes. Keys before the first table are
+//! shared by every instance, which can override them:
+//!
+//! ```toml
+//! statsd = "127.0.0.1:8125"
+//! statsd-tags = true
+//!
+//! [instances.line-a]
+//! addr = "10.1.255.255"
+//! port = 12321
+//! interval = "1s"
+//!
+//! [instances.line-b]
+//! addr = "10.2.255.255"
+//! port = 12321
+//! format = "v2"
+//! field = ["site=b", "line=4"]
+//! ```
+//!
+//! Values are strings, numbers, booleans or arrays of them: `true` gives a
+//! switch such as `per-interface`, `false` leaves it out, and an array
+//! repeats the option once per element. The file is turned into a `send`
+//! command line per instance, checked as one would be, so a mistake names
+//! the instance and the option. Only this subset of TOML is read: no
+//! inline tables, multi-line strings or values spanning lines.
+//!
+//! Every instance runs its own send loop on a thread of its own. Their
+//! lines share stdout and stderr, each prefixed with the instance's name,
+//! and their statsd metrics carry an `instance` tag. An instance that
+//! fails ends the process with its exit code; one that finishes, as with
+//! `dry-run`, leaves the others running.
+
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::process::ExitCode;
+use std::sync::mpsc;
+
+use crate::log;
+use crate::send::{self, SendConfig};
+
+/// One `[instances.<name>]` table, as `send` arguments.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Instance {
+    pub name: String,
+    /// Options such as `--addr=10.1.255.255`, shared ones first.
+    pub args: Vec<String>,
+}
+
+/// One value: a scalar's text, or an array's elements.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Value {
+    Text(String),
+    Bool(bool),
+    Array(Vec<Value>),
+}
+
+/// Reads the instances in the file at `path`.
+pub fn load(path: &Path) -> Result<Vec<Instance>, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read instances file {}: {e}", path.display()))?;
+    parse(&text).map_err(|e| format!("{}: {e}", path.display()))
+}
+
+/// Parses an instances file.
+pub fn parse(text: &str) -> Result<Vec<Instance>, String> {
+    let mut shared: Vec<(String, Value)> = Vec::new();
+    let mut tables: Vec<(String, Vec<(String, Value)>)> = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let fail = |e: String| format!("line {}: {e}", i + 1);
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('[') {
+            let name = header
+                .strip_suffix(']')
+                .map(str::trim)
+                .and_then(|h| h.strip_prefix("instances."))
+                .map(|n| unquote(n.trim()))
+                .ok_or_else(|| fail("expected a table header like [instances.<name>]".into()))?;
+            if !valid_name(&name) {
+                return Err(fail(format!(
+                    "instance names are letters, digits, '-' and '_', got '{name}'"
+                )));
+            }
+            if tables.iter().any(|(n, _)| *n == name) {
+                return Err(fail(format!("instance {name} is defined twice")));
+            }
+            tables.push((name, Vec::new()));
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| fail("expected key = value".into()))?;
+        let key = unquote(key.trim()).replace('_', "-");
+        if key.is_empty() || key.starts_with('-') {
+            return Err(fail(format!("expected an option name, got '{key}'")));
+        }
+        let value = parse_value(value.trim()).map_err(fail)?;
+        let entries = match tables.last_mut() {
+            Some((_, entries)) => entries,
+            None => &mut shared,
+        };
+        if entries.iter().any(|(k, _)| *k == key) {
+            return Err(fail(format!("{key} is set twice")));
+        }
+        entries.push((key, value));
+    }
+    if tables.is_empty() {
+        return Err("no [instances.<name>] tables".to_string());
+    }
+    Ok(tables
+        .into_iter()
+        .map(|(name, own)| {
+            let inherited = shared
+                .iter()
+                .filter(|(k, _)| !own.iter().any(|(o, _)| o == k));
+            let mut args = Vec::new();
+            for (key, value) in inherited.chain(&own) {
+                push_args(&mut args, key, value);
+            }
+            Instance { name, args }
+        })
+        .collect())
+}
+
+/// Runs each named sender on a thread of its own, until one fails or all
+/// have finished.
+pub fn run(instances: Vec<(String, SendConfig)>) -> ExitCode {
+    let (tx, rx) = mpsc::channel();
+    let count = instances.len();
+    for (name, cfg) in instances {
+        let tx = tx.clone();
+        let spawned = std::thread::Builder::new()
+            .name(name.clone())
+            .spawn(move || {
+                log::set_instance(&name);
+                // A panic has been reported by now; it ends the process too
+                let code = panic::catch_unwind(AssertUnwindSafe(|| send::run(&cfg)))
+                    .unwrap_or(ExitCode::FAILURE);
+                let _ = tx.send((name, code));
+            });
+        if let Err(e) = spawned {
+            log::error!("Failed to start a thread for an instance: {e}");
+            return ExitCode::FAILURE;
+        }
+    }
+    drop(tx);
+    for _ in 0..count {
+        let Ok((name, code)) = rx.recv() else { break };
+        if code != ExitCode::SUCCESS {
+            log::error!("Instance {name} stopped; stopping the others");
+            return code;
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+/// Adds `--key=value`, `--key`, or nothing for `false`, once per element
+/// of an array.
+fn push_args(args: &mut Vec<String>, key: &str, value: &Value) {
+    match value {
+        Value::Text(v) => args.push(format!("--{key}={v}")),
+        Value::Bool(true) => args.push(format!("--{key}")),
+        Value::Bool(false) => {}
+        Value::Array(items) => {
+            for item in items {
+                push_args(args, key, item);
+            }
+        }
+    }
+}
+
+fn parse_value(s: &str) -> Result<Value, String> {
+    if let Some(inner) = s.strip_prefix('[') {
+        let inner = inner
+            .strip_suffix(']')
+            .ok_or("arrays must end on the line they start")?;
+        return split_items(inner)?
+            .into_iter()
+            .map(|item| match parse_value(item)? {
+                Value::Array(_) => Err("arrays can't be nested".to_string()),
+                v => Ok(v),
+            })
+            .collect::<Result<_, _>>()
+            .map(Value::Array);
+    }
+    match s {
+        "true" => return Ok(Value::Bool(true)),
+        "false" => return Ok(Value::Bool(false)),
+        "" => return Err("missing value".to_string()),
+        _ => {}
+    }
+    if s.starts_with('"') || s.starts_with('\'') {
+        return parse_string(s).map(Value::Text);
+    }
+    // Numbers, as written less TOML's digit separators
+    if s.starts_with(|c: char| c.is_ascii_digit() || c == '+' || c == '-')
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+-._".contains(c))
+    {
+        return Ok(Value::Text(s.replace('_', "")));
+    }
+    Err(format!(
+        "expected a string, number, boolean or array, got {s}"
+    ))
+}
+
+/// Parses a basic ("...") or literal ('...') string.
+fn parse_string(s: &str) -> Result<String, String> {
+    if let Some(inner) = s.strip_prefix('\'') {
+        return inner
+            .strip_suffix('\'')
+            .filter(|i| !i.contains('\''))
+            .map(str::to_string)
+            .ok_or_else(|| format!("unterminated string {s}"));
+    }
+    let inner = s
+        .strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .ok_or_else(|| format!("unterminated string {s}"))?;
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => out.push(match chars.next() {
+                Some('"') => '"',
+                Some('\\') => '\\',
+                Some('n') => '\n',
+                Some('t') => '\t',
+                other => return Err(format!("unsupported escape \\{}", other.unwrap_or(' '))),
+            }),
+            '"' => return Err(format!("unterminated string {s}")),
+            c => out.push(c),
+        }
+    }
+    Ok(out)
+}
+
+/// Splits an array's inside at the commas outside strings.
+fn split_items(s: &str) -> Result<Vec<&str>, String> {
+    let mut items = Vec::new();
+    let mut quote = None;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, ',') => {
+                items.push(s[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+        escaped = false;
+    }
+    if quote.is_some() {
+        return Err(format!("unterminated string in [{s}]"));
+    }
+    let last = s[start..].trim();
+    // A trailing comma is allowed
+    if !last.is_empty() {
+        items.push(last);
+    }
+    if items.iter().any(|i| i.is_empty()) {
+        return Err(format!("empty element in [{s}]"));
+    }
+    Ok(items)
+}
+
+/// Cuts a `#` comment from `line`, unless it is inside a string.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '#') => return &line[..i],
+            _ => {}
+        }
+        escaped = false;
+    }
+    line
+}
+
+/// A bare key as is, or a quoted one without its quotes.
+fn unquote(s: &str) -> String {
+    parse_string(s).unwrap_or_else(|_| s.to_string())
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tables_become_send_command_lines() {
+        let text = r#"
+            # Shared by both
+            statsd = "127.0.0.1:8125"
+            statsd_tags = true
+            interval-ms = 1_000
+
+            [instances.line-a]
+            addr = "10.1.255.255"   # the plant floor
+            port = 12321
+            field = ["site=a", 'note=#1', "q=\"x\""]
+
+            [instances."line-b"]
+            addr = "10.2.255.255"
+            port = 12322
+            interval-ms = 100
+            statsd-tags = false
+        "#;
+        let instances = parse(text).unwrap();
+        assert_eq!(
+            instances,
+            [
+                Instance {
+                    name: "line-a".into(),
+                    args: [
+                        "--statsd=127.0.0.1:8125",
+                        "--statsd-tags",
+                        "--interval-ms=1000",
+                        "--addr=10.1.255.255",
+                        "--port=12321",
+                        "--field=site=a",
+                        "--field=note=#1",
+                        "--field=q=\"x\"",
+                    ]
+                    .map(String::from)
+                    .into()
+                },
+                Instance {
+                    name: "line-b".into(),
+                    args: [
+                        "--statsd=127.0.0.1:8125",
+                        "--addr=10.2.255.255",
+                        "--port=12322",
+                        "--interval-ms=100",
+                    ]
+                    .map(String::from)
+                    .into()
+                },
+            ]
+        );
+
+        assert_eq!(
+            parse("port = 1").unwrap_err(),
+            "no [instances.<name>] tables"
+        );
+        assert_eq!(
+            parse("[instances.a]\nport = 1\nport = 2").unwrap_err(),
+            "line 3: port is set twice"
+        );
+        assert_eq!(
+            parse("[instances.a]\n[instances.a]").unwrap_err(),
+            "line 2: instance a is defined twice"
+        );
+        assert!(parse("[servers.a]").is_err());
+        assert!(parse("[instances.a]\naddr = \"10.0.0.1").is_err());
+        assert!(parse("[instances.a]\nfield = [\"a\",\n").is_err());
+    }
+}
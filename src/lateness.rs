@@ -0,0 +1,112 @@
+//! How late the send loop's ticks go out (`send --max-lateness-ms`).
+//!
+//! Each tick has a deadline: the grid point it was scheduled for, moved by
+//! `--jitter` if set. A tick that starts sending after its deadline is
+//! late by the difference, which is down to the sender's host: a sleep
+//! that overslept, a preempted thread, a slow send on the previous tick.
+//! Jitter measured at a receiver beyond that is the network's. A loop that
+//! falls more than a whole interval behind skips the ticks it missed
+//! rather than sending them in a burst; those are counted separately.
+
+use std::time::Duration;
+
+/// Upper bounds of the lateness histogram's buckets, in microseconds; a
+/// last bucket holds everything later.
+pub const BUCKETS_US: [u64; 7] = [100, 250, 500, 1_000, 2_500, 10_000, 100_000];
+
+/// Lateness of the ticks sent so far.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Lateness {
+    /// Ticks that sent something.
+    pub ticks: u64,
+    /// Ticks later than the limit.
+    pub late: u64,
+    /// Ticks skipped by falling a whole interval behind.
+    pub missed: u64,
+    pub max_us: u64,
+    pub sum_us: u64,
+    /// Ticks by lateness, bucketed by [`BUCKETS_US`].
+    pub histogram: [u64; BUCKETS_US.len() + 1],
+}
+
+impl Lateness {
+    /// Records a tick `late` after its deadline, returning whether that is
+    /// over `limit`.
+    pub fn record(&mut self, late: Duration, limit: Option<Duration>) -> bool {
+        let us = late.as_micros() as u64;
+        self.ticks += 1;
+        self.sum_us += us;
+        self.max_us = self.max_us.max(us);
+        self.histogram[BUCKETS_US.partition_point(|&b| b <= us)] += 1;
+        let over = limit.is_some_and(|limit| late > limit);
+        if over {
+            self.late += 1;
+        }
+        over
+    }
+
+    /// Records ticks skipped to catch up.
+    pub fn skipped(&mut self, ticks: u64) {
+        self.missed += ticks;
+    }
+
+    /// Mean lateness of the ticks sent, in microseconds.
+    pub fn mean_us(&self) -> u64 {
+        self.sum_us.checked_div(self.ticks).unwrap_or(0)
+    }
+
+    /// The smallest bucket bound, in microseconds, that at least `pct`
+    /// percent of ticks were under; `None` if only the last bucket is.
+    pub fn bound_us(&self, pct: f64) -> Option<u64> {
+        let needed = (self.ticks as f64 * pct / 100.0).ceil() as u64;
+        let mut seen = 0;
+        for (i, &us) in BUCKETS_US.iter().enumerate() {
+            seen += self.histogram[i];
+            if seen >= needed {
+                return Some(us);
+            }
+        }
+        None
+    }
+
+    /// The histogram, e.g. `<100µs 3412, <250µs 171, ..., later 0`.
+    pub fn histogram_summary(&self) -> String {
+        let buckets: Vec<String> = self
+            .histogram
+            .iter()
+            .enumerate()
+            .map(|(i, n)| match BUCKETS_US.get(i) {
+                Some(&us) if us < 1000 => format!("<{us}µs {n}"),
+                Some(&us) => format!("<{}ms {n}", us as f64 / 1000.0),
+                None => format!("later {n}"),
+            })
+            .collect();
+        buckets.join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_ticks_over_the_limit() {
+        let mut l = Lateness::default();
+        let limit = Some(Duration::from_millis(2));
+        assert!(!l.record(Duration::from_micros(300), limit));
+        assert!(!l.record(Duration::from_millis(2), limit));
+        assert!(l.record(Duration::from_micros(5_700), limit));
+        assert!(!l.record(Duration::from_secs(1), None));
+        l.skipped(3);
+        assert_eq!((l.ticks, l.late, l.missed), (4, 1, 3));
+        assert_eq!(l.max_us, 1_000_000);
+        assert_eq!(l.mean_us(), (300 + 2_000 + 5_700 + 1_000_000) / 4);
+        assert_eq!(l.histogram, [0, 0, 1, 0, 1, 1, 0, 1]);
+        assert_eq!(l.bound_us(50.0), Some(2_500));
+        assert_eq!(l.bound_us(75.0), Some(10_000));
+        assert_eq!(l.bound_us(99.0), None);
+        assert!(l
+            .histogram_summary()
+            .starts_with("<100µs 0, <250µs 0, <500µs 1, <1ms 0"));
+    }
+}
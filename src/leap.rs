@@ -0,0 +1,220 @@
+//! Leap-second smearing of the broadcast timestamp.
+//!
+//! When the kernel inserts a leap second it steps the Unix clock back by
+//! one second, and receivers treating the broadcast as a time hint see
+//! time run backwards. With a smear, the broadcast clock instead runs
+//! slightly slow (or fast, for a deleted leap second) over a window
+//! centred on the leap, and absorbs the whole second by the window's end.
+//! Outside windows the broadcast is the plain system time.
+//!
+//! Leap seconds come from a table in the IERS/NTP `leap-seconds.list`
+//! format, which most systems ship with tzdata. The smear assumes the
+//! system clock itself steps at the leap, as Linux does by default; a
+//! clock that is already smeared (e.g. by chrony or a smearing NTP
+//! server) must not be smeared again.
+
+use std::fs;
+
+/// Where tzdata installs the table.
+pub const DEFAULT_TABLE: &str = "/usr/share/zoneinfo/leap-seconds.list";
+
+/// Seconds from the NTP epoch (1900) to the Unix epoch.
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// One scheduled leap second.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Leap {
+    /// Unix time, in milliseconds, of the midnight the leap precedes.
+    pub at_ms: u64,
+    /// True for an inserted second, false for a deleted one.
+    pub inserted: bool,
+}
+
+/// A parsed leap-second table.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Table {
+    pub leaps: Vec<Leap>,
+    /// Unix time, in seconds, after which the table may be missing leaps.
+    pub expires: Option<u64>,
+}
+
+impl Table {
+    pub fn load(path: &str) -> Result<Table, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read leap-second table {path}: {e}"))?;
+        Table::parse(&text).map_err(|e| format!("Invalid leap-second table {path}: {e}"))
+    }
+
+    /// Parses `leap-seconds.list`: lines of NTP seconds and the TAI-UTC
+    /// offset from then on, with `#@` giving the expiry and other `#`
+    /// lines comments.
+    pub fn parse(text: &str) -> Result<Table, String> {
+        let mut leaps = Vec::new();
+        let mut expires = None;
+        let mut last_offset: Option<i64> = None;
+        for (n, line) in text.lines().enumerate() {
+            let ntp_secs = |s: &str| {
+                s.parse::<u64>()
+                    .ok()
+                    .and_then(|s| s.checked_sub(NTP_UNIX_OFFSET))
+                    .ok_or_else(|| format!("line {}: bad NTP time '{s}'", n + 1))
+            };
+            if let Some(rest) = line.strip_prefix("#@") {
+                expires = Some(ntp_secs(rest.trim())?);
+                continue;
+            }
+            let line = line.split('#').next().unwrap_or("");
+            let mut fields = line.split_whitespace();
+            let (Some(at), Some(offset)) = (fields.next(), fields.next()) else {
+                continue;
+            };
+            let at = ntp_secs(at)?;
+            let offset: i64 = offset
+                .parse()
+                .map_err(|_| format!("line {}: bad TAI offset '{offset}'", n + 1))?;
+            // The first entry sets the starting offset; later ones change it
+            if let Some(last) = last_offset {
+                match offset - last {
+                    1 => leaps.push(Leap {
+                        at_ms: at * 1000,
+                        inserted: true,
+                    }),
+                    -1 => leaps.push(Leap {
+                        at_ms: at * 1000,
+                        inserted: false,
+                    }),
+                    _ => return Err(format!("line {}: offset must change by one", n + 1)),
+                }
+            }
+            last_offset = Some(offset);
+        }
+        Ok(Table { leaps, expires })
+    }
+}
+
+/// Maps system time to smeared time, remembering whether the system
+/// clock has stepped back for an inserted leap second yet.
+#[derive(Clone, Debug)]
+pub struct Smear {
+    leaps: Vec<Leap>,
+    window_ms: u64,
+    /// Last system time seen, to notice the step back.
+    last_ms: Option<u64>,
+    /// Leap whose step back has been seen.
+    stepped: Option<u64>,
+}
+
+impl Smear {
+    /// `window_ms` must exceed one second.
+    pub fn new(table: &Table, window_ms: u64) -> Smear {
+        Smear {
+            leaps: table.leaps.clone(),
+            window_ms,
+            last_ms: None,
+            stepped: None,
+        }
+    }
+
+    /// Returns the smeared time for a system time, both in Unix ms.
+    pub fn apply(&mut self, sys_ms: u64) -> u64 {
+        let last = self.last_ms.replace(sys_ms);
+        let half = self.window_ms / 2;
+        let Some(leap) = self
+            .leaps
+            .iter()
+            .find(|l| sys_ms + half + 1000 >= l.at_ms && sys_ms < l.at_ms + half + 1000)
+            .copied()
+        else {
+            return sys_ms;
+        };
+
+        // Elapsed time as if the clock had never stepped
+        let real_ms = if leap.inserted {
+            // The second before midnight repeats; the jump back from its
+            // first run marks the step
+            if last.is_some_and(|l| sys_ms + 500 <= l && l >= leap.at_ms - 1000) {
+                self.stepped = Some(leap.at_ms);
+            }
+            if sys_ms >= leap.at_ms || self.stepped == Some(leap.at_ms) {
+                sys_ms + 1000
+            } else {
+                sys_ms
+            }
+        } else if sys_ms >= leap.at_ms {
+            sys_ms - 1000
+        } else {
+            sys_ms
+        };
+
+        let start = leap.at_ms - half;
+        let end = leap.at_ms + half;
+        if real_ms < start || real_ms >= end {
+            return sys_ms;
+        }
+        let absorbed = (real_ms - start) * 1000 / self.window_ms;
+        if leap.inserted {
+            real_ms - absorbed
+        } else {
+            real_ms + absorbed
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2017-01-01T00:00:00Z, after the last leap second so far
+    const NEW_YEAR_2017: u64 = 1_483_228_800_000;
+
+    #[test]
+    fn parses_leap_seconds_list() {
+        let text = "\
+#\tUpdated through IERS Bulletin C
+#@\t3960057600
+2272060800\t10\t# 1 Jan 1972
+3692217600\t37\t# 1 Jan 2017
+";
+        assert_eq!(
+            Table::parse(text).unwrap_err(),
+            "line 4: offset must change by one"
+        );
+        let text = text.replace("3692217600\t37", "3644697600\t11");
+        let table = Table::parse(&text).unwrap();
+        assert_eq!(table.expires, Some(1_751_068_800));
+        assert_eq!(
+            table.leaps,
+            [Leap {
+                at_ms: 1_435_708_800_000,
+                inserted: true
+            }]
+        );
+    }
+
+    #[test]
+    fn inserted_second_is_absorbed_without_going_back() {
+        let table = Table {
+            leaps: vec![Leap {
+                at_ms: NEW_YEAR_2017,
+                inserted: true,
+            }],
+            expires: None,
+        };
+        // A 10 s window, stepping through the kernel's repeated second
+        let mut smear = Smear::new(&table, 10_000);
+        let at = |d: i64| (NEW_YEAR_2017 as i64 + d) as u64;
+        let mut sys: Vec<u64> = (-6_000..0).step_by(250).map(at).collect();
+        sys.extend((-1_000..6_000).step_by(250).map(at));
+        let out: Vec<u64> = sys.iter().map(|&s| smear.apply(s)).collect();
+
+        assert_eq!(out[0], NEW_YEAR_2017 - 6_000);
+        assert!(out.windows(2).all(|w| w[1] > w[0]));
+        // Halfway through, half a second has been absorbed
+        let at_leap = sys
+            .iter()
+            .rposition(|&s| s == NEW_YEAR_2017 - 1_000)
+            .unwrap();
+        assert_eq!(out[at_leap], NEW_YEAR_2017 - 500);
+        assert_eq!(*out.last().unwrap(), *sys.last().unwrap());
+    }
+}
@@ -0,0 +1,183 @@
+//! Other layouts of the legacy payload (`--legacy-format`).
+//!
+//! The legacy payload is a bare timestamp with nothing to say how it is
+//! laid out, and consumers written against other tools expect their own:
+//! little-endian, whole seconds, or a 32-bit `time_t`. A [`Layout`] names
+//! one, and is a [`PayloadCodec`] so senders and receivers use it in place
+//! of the built-in format while a fleet migrates.
+//!
+//! | Name | Byte order | Width | Unit |
+//! |---|---|---|---|
+//! | `be-u64-ms` | big-endian | 8 | milliseconds (the built-in layout) |
+//! | `le-u64-ms` | little-endian | 8 | milliseconds |
+//! | `be-u64-us`, `le-u64-us` | either | 8 | microseconds |
+//! | `be-u64-s`, `le-u64-s` | either | 8 | seconds |
+//! | `be-u32-s`, `le-u32-s` | either | 4 | seconds, wrapping in 2106 |
+//!
+//! Timestamps are converted from milliseconds of the `--epoch` scale, so
+//! the scale must count milliseconds: `unix` or `gps`. A seconds layout
+//! truncates to the second, so its offsets are only good to a second.
+//! Receivers decode datagrams of the layout's width with it and anything
+//! else as usual, so v2 and proto senders can share the port.
+
+use std::convert::TryInto;
+
+use crate::epoch::Epoch;
+use crate::payload::{self, DecodeError, Decoded, Field, Format, Packet, PayloadCodec};
+
+/// What a layout's integer counts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unit {
+    Seconds,
+    Millis,
+    Micros,
+}
+
+/// A named legacy layout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Layout {
+    pub name: &'static str,
+    pub big_endian: bool,
+    /// Bytes on the wire: 4 or 8.
+    pub width: usize,
+    pub unit: Unit,
+}
+
+const fn layout(name: &'static str, big_endian: bool, width: usize, unit: Unit) -> Layout {
+    Layout {
+        name,
+        big_endian,
+        width,
+        unit,
+    }
+}
+
+/// Every layout `--legacy-format` accepts.
+pub const LAYOUTS: [Layout; 8] = [
+    layout("be-u64-ms", true, 8, Unit::Millis),
+    layout("le-u64-ms", false, 8, Unit::Millis),
+    layout("be-u64-us", true, 8, Unit::Micros),
+    layout("le-u64-us", false, 8, Unit::Micros),
+    layout("be-u64-s", true, 8, Unit::Seconds),
+    layout("le-u64-s", false, 8, Unit::Seconds),
+    layout("be-u32-s", true, 4, Unit::Seconds),
+    layout("le-u32-s", false, 4, Unit::Seconds),
+];
+
+impl Layout {
+    /// Parses a `--legacy-format` name.
+    pub fn parse(s: &str) -> Result<Layout, String> {
+        LAYOUTS
+            .iter()
+            .find(|l| l.name == s)
+            .copied()
+            .ok_or_else(|| {
+                let names: Vec<&str> = LAYOUTS.iter().map(|l| l.name).collect();
+                format!("expected one of {}", names.join(", "))
+            })
+    }
+
+    /// Whether timestamps in `epoch` can be sent in this layout.
+    pub fn supports(self, epoch: Epoch) -> bool {
+        epoch != Epoch::Ntp || self == LAYOUTS[0]
+    }
+
+    fn value_of(self, ms: u64) -> u64 {
+        match self.unit {
+            Unit::Seconds => ms / 1000,
+            Unit::Millis => ms,
+            Unit::Micros => ms.saturating_mul(1000),
+        }
+    }
+
+    fn to_ms(self, value: u64) -> u64 {
+        match self.unit {
+            Unit::Seconds => value.saturating_mul(1000),
+            Unit::Millis => value,
+            Unit::Micros => value / 1000,
+        }
+    }
+}
+
+impl PayloadCodec for Layout {
+    fn encode(&self, packet: &Packet, _fields: &[Field], out: &mut Vec<u8>) {
+        out.clear();
+        let value = self.value_of(packet.epoch.value_of(packet.ts_ms));
+        match (self.width, self.big_endian) {
+            (4, true) => out.extend_from_slice(&(value as u32).to_be_bytes()),
+            (4, false) => out.extend_from_slice(&(value as u32).to_le_bytes()),
+            (_, true) => out.extend_from_slice(&value.to_be_bytes()),
+            (_, false) => out.extend_from_slice(&value.to_le_bytes()),
+        }
+    }
+
+    /// Decodes datagrams of this layout's width with it, and anything else
+    /// as [`payload::decode`] does. The timestamp is left in the scale it
+    /// was sent in, as for [`Format::Legacy`].
+    fn decode<'a>(&self, data: &'a [u8]) -> Result<Decoded<'a>, DecodeError> {
+        if data.len() != self.width {
+            return payload::decode(data);
+        }
+        let value = match (self.width, self.big_endian) {
+            (4, true) => u64::from(u32::from_be_bytes(data.try_into().unwrap())),
+            (4, false) => u64::from(u32::from_le_bytes(data.try_into().unwrap())),
+            (_, true) => u64::from_be_bytes(data.try_into().unwrap()),
+            (_, false) => u64::from_le_bytes(data.try_into().unwrap()),
+        };
+        Ok(Decoded {
+            packet: Packet::new(None, self.to_ms(value)),
+            format: Format::Legacy,
+            mac: None,
+            key_id: None,
+            sig: None,
+            fields: Vec::new(),
+            echo: None,
+            crc: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layouts_round_trip_at_their_resolution() {
+        let mut packet = payload::decode(&1_700_000_000_250u64.to_be_bytes())
+            .unwrap()
+            .packet;
+        let mut out = Vec::new();
+
+        let le_s = Layout::parse("le-u64-s").unwrap();
+        le_s.encode(&packet, &[], &mut out);
+        assert_eq!(out, 1_700_000_000u64.to_le_bytes());
+        assert_eq!(le_s.decode(&out).unwrap().packet.ts_ms, 1_700_000_000_000);
+
+        let be_u32 = Layout::parse("be-u32-s").unwrap();
+        be_u32.encode(&packet, &[], &mut out);
+        assert_eq!(out, 1_700_000_000u32.to_be_bytes());
+        // Other widths decode as the built-in formats
+        let builtin = 1_700_000_000_250u64.to_be_bytes();
+        assert_eq!(
+            be_u32.decode(&builtin).unwrap().packet,
+            payload::decode(&builtin).unwrap().packet
+        );
+
+        packet.epoch = Epoch::Gps;
+        let le_us = Layout::parse("le-u64-us").unwrap();
+        le_us.encode(&packet, &[], &mut out);
+        let decoded = le_us.decode(&out).unwrap();
+        assert_eq!(decoded.packet.ts_ms, Epoch::Gps.value_of(1_700_000_000_250));
+
+        assert_eq!(
+            Layout::parse("be-u64-ms")
+                .unwrap()
+                .decode(&out)
+                .unwrap()
+                .packet,
+            payload::decode(&out).unwrap().packet
+        );
+        assert!(!le_s.supports(Epoch::Ntp));
+        assert!(Layout::parse("le-u16-s").is_err());
+    }
+}
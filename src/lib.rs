@@ -0,0 +1,186 @@
+//! UDP timestamp beacons: the senders, receivers and wire formats behind
+//! the `udp_bcast_ts` binary, for embedding in other tools.
+//!
+//! Sending and receiving are a `*Config` struct plus a `run` function
+//! returning the process [`ExitCode`](std::process::ExitCode), in [`send`]
+//! and [`listen`]; the wire format lives in [`payload`], its message types
+//! in [`message`], and stream transports in [`transport`]. An application
+//! can substitute its own wire format with a [`payload::PayloadCodec`],
+//! and follow or amend each datagram sent with [`hooks::Hooks`].
+
+pub mod hooks;
+pub mod listen;
+pub mod message;
+pub mod payload;
+pub mod send;
+pub mod transport;
+
+// Public only because the binary, a separate crate, is built from them:
+// left out of the documentation and not part of the library's API, so
+// any release may change them. Modules the binary doesn't use are
+// private below.
+#[doc(hidden)]
+pub mod adaptive;
+#[doc(hidden)]
+pub mod aead;
+#[doc(hidden)]
+pub mod aggregate;
+#[doc(hidden)]
+pub mod alert;
+#[doc(hidden)]
+pub mod annotate;
+#[doc(hidden)]
+pub mod api;
+#[doc(hidden)]
+pub mod auth;
+#[doc(hidden)]
+pub mod bench;
+#[doc(hidden)]
+pub mod blackout;
+#[doc(hidden)]
+pub mod capture;
+#[cfg(feature = "chaos")]
+#[doc(hidden)]
+pub mod chaos;
+#[doc(hidden)]
+pub mod compact;
+#[doc(hidden)]
+pub mod conformance;
+#[doc(hidden)]
+pub mod control;
+#[doc(hidden)]
+pub mod daemon;
+#[doc(hidden)]
+pub mod discipline;
+#[doc(hidden)]
+pub mod drift;
+#[doc(hidden)]
+pub mod ed25519;
+#[doc(hidden)]
+pub mod epoch;
+#[doc(hidden)]
+pub mod exit;
+#[doc(hidden)]
+pub mod export;
+#[doc(hidden)]
+pub mod filter;
+#[doc(hidden)]
+pub mod generate;
+#[doc(hidden)]
+pub mod guard;
+#[doc(hidden)]
+pub mod hdr;
+#[doc(hidden)]
+pub mod iface;
+#[doc(hidden)]
+pub mod influx;
+#[doc(hidden)]
+pub mod instances;
+#[doc(hidden)]
+pub mod leap;
+#[doc(hidden)]
+pub mod legacy;
+#[doc(hidden)]
+pub mod log;
+#[doc(hidden)]
+pub mod logfile;
+#[doc(hidden)]
+pub mod mdns;
+#[doc(hidden)]
+pub mod membership;
+#[doc(hidden)]
+pub mod mqtt;
+#[doc(hidden)]
+pub mod offload;
+#[doc(hidden)]
+pub mod otlp;
+#[doc(hidden)]
+pub mod peers;
+#[doc(hidden)]
+pub mod privs;
+#[doc(hidden)]
+pub mod publish;
+#[doc(hidden)]
+pub mod record;
+#[doc(hidden)]
+pub mod relay;
+#[doc(hidden)]
+pub mod replay;
+#[doc(hidden)]
+pub mod resolve;
+#[doc(hidden)]
+pub mod role;
+#[doc(hidden)]
+pub mod script;
+#[doc(hidden)]
+pub mod selftest;
+#[doc(hidden)]
+pub mod service;
+#[doc(hidden)]
+pub mod sink;
+#[doc(hidden)]
+pub mod sntp;
+#[doc(hidden)]
+pub mod start;
+#[doc(hidden)]
+pub mod statsd;
+#[doc(hidden)]
+pub mod store;
+#[doc(hidden)]
+pub mod sys;
+#[doc(hidden)]
+pub mod timing;
+#[doc(hidden)]
+pub mod txdelay;
+#[doc(hidden)]
+pub mod units;
+#[doc(hidden)]
+pub mod watchdog;
+
+mod clock;
+mod hotplug;
+mod http;
+mod lateness;
+mod multihome;
+mod pcapng;
+mod plan;
+mod proto;
+mod query;
+mod rate;
+mod rng;
+mod senders;
+mod shm;
+mod skew;
+mod snapshot;
+mod sockerr;
+mod state;
+mod status;
+mod step;
+mod transmit;
+mod tui;
+mod web;
+
+/// Exit code for runtime failures not covered by a more specific code.
+pub const EXIT_CODE_RUNTIME_ERROR: u8 = 1;
+
+/// Exit code for invalid arguments or missing required options.
+pub const EXIT_CODE_USAGE_ERROR: u8 = 2;
+
+/// Exit code when a receiver's offset alarm fires without an `--on-alert` hook.
+pub const EXIT_CODE_ALERT: u8 = 3;
+
+/// Exit code when the local clock is further from NTP time than allowed.
+pub const EXIT_CODE_CLOCK_OFFSET: u8 = 4;
+
+/// Exit code when a socket can't be bound, e.g. because the port is in use.
+pub const EXIT_CODE_BIND_FAILED: u8 = 5;
+
+/// Exit code when the system refuses an operation, such as binding a
+/// privileged port or raising the scheduling priority.
+pub const EXIT_CODE_PERMISSION_DENIED: u8 = 6;
+
+/// Exit code when there is no route to the destination at startup.
+pub const EXIT_CODE_NETWORK_UNREACHABLE: u8 = 7;
+
+/// Exit code when the system clock can't be read as a Unix timestamp.
+pub const EXIT_CODE_CLOCK_ERROR: u8 = 8;
@@ -0,0 +1,1319 @@
+//! Receiver ("observer") mode: binds the broadcast port and logs every
+//! timestamp that arrives, together with its offset from the local clock.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt::Write as _;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, UdpSocket};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::aead::AeadKey;
+use crate::aggregate::{ReportConfig, Reporter};
+use crate::alert::{self, Alarm, AlertConfig};
+use crate::annotate::{self, Annotator};
+use crate::auth;
+use crate::capture::{CaptureConfig, FlightRecorder};
+use crate::compact;
+use crate::discipline::{self, Action, Discipline, DisciplineConfig};
+use crate::drift::{self, DriftLog};
+use crate::ed25519::KeyRing;
+use crate::epoch::Epoch;
+use crate::exit::{self, Failure};
+use crate::export::{CsvExporter, Observation, OutputFormat};
+use crate::filter::SourceFilter;
+use crate::hdr::HdrLog;
+use crate::influx::{Influx, InfluxConfig};
+use crate::log;
+use crate::membership::{JoinOn, Membership};
+use crate::message::{MessageType, Registry};
+use crate::mqtt::{Mqtt, MqttConfig};
+use crate::multihome::InterfaceDedup;
+use crate::payload::{self, DecodeError, Format, PayloadCodec};
+use crate::pcapng::PcapngWriter;
+use crate::privs::Hardening;
+use crate::publish::{self, Publisher, Stream, StreamFormat};
+use crate::record::Recorder;
+use crate::replay::{Rejection, ReplayGuard};
+use crate::script::{self, Reply, Script};
+use crate::senders::{SenderTable, SeqEvent};
+use crate::shm::Refclock;
+use crate::skew::SkewEstimator;
+use crate::statsd::{Statsd, StatsdConfig};
+use crate::step::{self, Eras, StepDetector};
+use crate::store::{Store, StoreConfig};
+use crate::sys;
+use crate::transmit;
+use crate::transport::{DatagramSource, StreamClient, Transport};
+use crate::tui::Dashboard;
+use crate::units;
+use crate::watchdog::{ExpectConfig, Finding, Watchdog};
+use crate::web;
+use crate::{EXIT_CODE_ALERT, EXIT_CODE_CLOCK_ERROR};
+
+/// Settings for receiver mode.
+pub struct ListenConfig {
+    /// Local address to bind, or a multicast group to join.
+    pub addr: Option<IpAddr>,
+    /// IPv6 zone of `addr`: the interface to bind on or join through, or 0.
+    pub scope_id: u32,
+    pub port: u16,
+    /// Interfaces to join a multicast `addr` on.
+    pub join_on: JoinOn,
+    /// How often to leave and rejoin a multicast `addr`.
+    pub rejoin: Option<Duration>,
+    /// Refuse every transmit path and verify that none was taken.
+    pub observe_only: bool,
+    /// Replay window size for sequenced payloads; 0 disables the check.
+    pub replay_window: u32,
+    /// Beacon groups to accept; empty accepts everything.
+    pub groups: Vec<Group>,
+    /// Sender addresses to accept; empty accepts everything.
+    pub sources: Vec<IpAddr>,
+    /// Source prefixes to accept and to drop, in the kernel where possible.
+    pub source_filter: SourceFilter,
+    /// How often to print per-group statistics.
+    pub stats_interval: Option<Duration>,
+    /// File to record every received datagram to.
+    pub record: Option<String>,
+    /// pcapng file to capture every received datagram to.
+    pub pcap: Option<String>,
+    /// File to append long-term drift samples to.
+    pub drift_log: Option<String>,
+    /// HdrHistogram log of offsets, and how often to write an interval.
+    pub hdr_log: Option<(String, Duration)>,
+    /// Per-packet export destination.
+    pub output: Option<(OutputFormat, String)>,
+    /// Database to keep observations and aggregates in.
+    pub store: Option<StoreConfig>,
+    /// Where to write InfluxDB points.
+    pub influx: Option<InfluxConfig>,
+    /// MQTT broker to publish each accepted timestamp to.
+    pub mqtt: Option<MqttConfig>,
+    /// Source of an auxiliary value attached to each measurement.
+    pub annotate: Option<annotate::Source>,
+    pub annotate_interval: Duration,
+    /// Command asked to drop or annotate each timestamp, see [`crate::script`].
+    pub script: Option<String>,
+    /// Answer each accepted timestamp with an ack, for `conformance`.
+    pub ack: bool,
+    /// Drop timestamps older than this on arrival, in milliseconds.
+    pub max_age_ms: Option<u64>,
+    /// Message types to report; others are counted and skipped.
+    pub registry: Registry,
+    /// Decodes payloads instead of the built-in formats, see [`PayloadCodec`].
+    pub codec: Option<Arc<dyn PayloadCodec>>,
+    /// Alarm on offsets beyond a threshold.
+    pub alert: Option<AlertConfig>,
+    /// Traffic to capture around loss bursts and offset alerts.
+    pub capture: Option<CaptureConfig>,
+    /// Agent to push receive counters and offsets to.
+    pub statsd: Option<StatsdConfig>,
+    /// Aggregator to send the sender table to.
+    pub report: Option<ReportConfig>,
+    /// Requested receive buffer size (SO_RCVBUF), in bytes.
+    pub rcvbuf: Option<u32>,
+    /// Window for per-sender offset and skew fits, reported with stats.
+    pub skew_window: Option<Duration>,
+    /// Public keys one of which must have signed each payload.
+    pub verify_keys: Option<KeyRing>,
+    /// Key that every payload must be sealed with.
+    pub encrypt_key: Option<AeadKey>,
+    /// Show a live dashboard instead of a line per packet.
+    pub tui: bool,
+    /// Serve a live web dashboard on this address.
+    pub web_addr: Option<SocketAddr>,
+    /// Local consumers to hand every accepted timestamp on to.
+    pub publish: Vec<publish::Target>,
+    /// Write each accepted timestamp to stdout in this format.
+    pub stream: Option<StreamFormat>,
+    /// NTP shared memory segment to publish each timestamp to.
+    pub shm_segment: Option<u8>,
+    /// User to switch to and syscalls to allow once set up.
+    pub hardening: Hardening,
+    /// Scale of the timestamp in legacy payloads, which can't carry one.
+    pub epoch: Epoch,
+    /// Which receive time offsets are measured from.
+    pub rx_timestamp: RxTimestamp,
+    /// Slew the local clock toward one sender.
+    pub discipline: Option<DisciplineConfig>,
+    /// Let other sockets bind the port too.
+    pub reuse: sys::Reuse,
+    /// Senders the topology has, to report others and silences.
+    pub expect: Option<ExpectConfig>,
+    /// How timestamps arrive; with TCP, `addr` is the sender to connect to.
+    pub transport: Transport,
+}
+
+/// What [`run`] receives from: the bound socket, or a connection to the
+/// sender with `--transport tcp`.
+enum Inlet {
+    Socket(UdpSocket),
+    Stream(StreamClient),
+}
+
+impl DatagramSource for Inlet {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<sys::RecvMeta> {
+        match self {
+            Inlet::Socket(s) => s.recv(buf),
+            Inlet::Stream(s) => s.recv(buf),
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Inlet::Socket(s) => DatagramSource::local_addr(s),
+            Inlet::Stream(s) => s.local_addr(),
+        }
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Inlet::Socket(s) => DatagramSource::set_read_timeout(s, timeout),
+            Inlet::Stream(s) => s.set_read_timeout(timeout),
+        }
+    }
+}
+
+/// Where the receive time of a datagram comes from (`--rx-timestamp`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RxTimestamp {
+    /// When the kernel received it, falling back to `User` where the
+    /// platform doesn't say.
+    Kernel,
+    /// When this process read it, including any scheduling delay.
+    User,
+}
+
+impl RxTimestamp {
+    /// Parses an `--rx-timestamp` value.
+    pub fn parse(s: &str) -> Result<RxTimestamp, String> {
+        match s {
+            "kernel" => Ok(RxTimestamp::Kernel),
+            "user" => Ok(RxTimestamp::User),
+            _ => Err("expected kernel or user".to_string()),
+        }
+    }
+}
+
+/// A beacon group sharing the port, with optional keys one of which its
+/// senders must authenticate with.
+pub struct Group {
+    pub id: u16,
+    pub key: Option<auth::KeyRing>,
+}
+
+/// Counters for one beacon group.
+#[derive(Default)]
+struct GroupStats {
+    received: u64,
+    auth_failed: u64,
+    replayed: u64,
+    /// Redundant copies of a sequence number already accepted.
+    duplicates: u64,
+    /// Copies of a datagram already received on another interface.
+    interface_copies: u64,
+    /// Timestamps older than `--max-age-ms` on arrival.
+    stale: u64,
+    /// Timestamps a `--script` dropped.
+    script_dropped: u64,
+    host_delay_us_sum: u64,
+    host_delay_samples: u64,
+}
+
+/// Receive counters, keyed by group (`None` for untagged payloads).
+#[derive(Default)]
+struct Stats {
+    groups: BTreeMap<Option<u16>, GroupStats>,
+    unknown_group: u64,
+    unknown_type: u64,
+    malformed: u64,
+    /// Datagrams whose checksum didn't match.
+    corrupt: u64,
+    /// Datagrams that weren't sealed with `--encrypt-key`.
+    undecrypted: u64,
+}
+
+impl Stats {
+    fn group(&mut self, id: Option<u16>) -> &mut GroupStats {
+        self.groups.entry(id).or_default()
+    }
+
+    fn print(&self) {
+        println!(
+            "Stats: malformed={} corrupt={} undecrypted={} unknown_group={} unknown_type={}",
+            self.malformed, self.corrupt, self.undecrypted, self.unknown_group, self.unknown_type
+        );
+        for (id, g) in &self.groups {
+            let id = id.map_or_else(|| "-".to_string(), |id| id.to_string());
+            let host_delay = match g.host_delay_samples {
+                0 => String::new(),
+                n => format!(
+                    " {}",
+                    units::pair("mean_host_delay_us", units::micros(g.host_delay_us_sum / n))
+                ),
+            };
+            println!(
+                "Stats: group={id} received={} auth_failed={} replayed={} duplicates={} interface_copies={} stale={} script_dropped={}{host_delay}",
+                g.received, g.auth_failed, g.replayed, g.duplicates, g.interface_copies, g.stale, g.script_dropped
+            );
+        }
+    }
+}
+
+/// Converts a wall-clock time to milliseconds since the Unix epoch.
+fn epoch_ms(t: SystemTime) -> Result<u64, String> {
+    let d = t
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| format!("System clock error (before UNIX_EPOCH): {e:?}"))?;
+    d.as_millis()
+        .try_into()
+        .map_err(|_| "Timestamp overflow: system time too large for u64".to_string())
+}
+
+/// The sender's timestamp and the time its offset is reckoned from, which
+/// is its monotonic time where it sends one, in signed microseconds;
+/// `None` for a packet past what those hold.
+fn timing_us(packet: &payload::Packet) -> Option<(i64, i64)> {
+    let ts_us = packet
+        .ts_ms
+        .checked_mul(1000)
+        .and_then(|us| i64::try_from(us).ok())?;
+    let sent_us = match packet.mono_us {
+        Some(us) => i64::try_from(us).ok()?,
+        None => ts_us,
+    };
+    Some((ts_us, sent_us))
+}
+
+/// How long ago, by this host's clock, a timestamp checked by
+/// [`timing_us`] was taken.
+fn age_ms(recv_ms: u64, ts_us: i64) -> i64 {
+    (i128::from(recv_ms) - i128::from(ts_us / 1000)) as i64
+}
+
+/// Reports an `--expect-sender` finding, and with `--strict` takes the
+/// alert action: the failure returned is the one to exit with.
+fn sender_alarm(finding: &Finding, expect: &ExpectConfig) -> Result<(), Failure> {
+    log::warn!("{finding}");
+    match &expect.strict {
+        Some(alert::Action::Exit) => Err(Failure::new(
+            EXIT_CODE_ALERT,
+            format!("Sender alarm fired for {}", finding.sender()),
+        )),
+        Some(alert::Action::Command(cmd)) => {
+            alert::run_hook(cmd, finding.kind(), &finding.sender(), None);
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+/// Binds a receive socket on `port`, joining `addr` if it is a multicast
+/// group. `scope_id` is the IPv6 zone of `addr`, or 0. `reuse` lets other
+/// sockets bind the port too.
+pub fn bind(
+    addr: Option<IpAddr>,
+    scope_id: u32,
+    port: u16,
+    observe_only: bool,
+    reuse: sys::Reuse,
+) -> Result<UdpSocket, Failure> {
+    let sock = bind_port(addr, scope_id, port, reuse)?;
+    if let Some(group) = addr.filter(IpAddr::is_multicast) {
+        check_join(group, observe_only)?;
+        let joined = match group {
+            IpAddr::V4(g) => sock.join_multicast_v4(&g, &Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(g) => sock.join_multicast_v6(&g, scope_id),
+        };
+        joined
+            .map_err(|e| Failure::io(&e, format!("Failed to join multicast group {group}: {e}")))?;
+        log::debug!("Joined multicast group {group} (scope {scope_id})");
+    }
+    Ok(sock)
+}
+
+/// Joining a group makes the kernel emit IGMP/MLD membership reports,
+/// which a strictly passive host must not do.
+fn check_join(group: IpAddr, observe_only: bool) -> Result<(), Failure> {
+    if observe_only {
+        return Err(format!(
+            "--observe-only cannot join multicast group {group}: membership reports are transmissions"
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Binds a receive socket on `port` without joining anything: on the
+/// wildcard address of its family if `addr` is a multicast group.
+fn bind_port(
+    addr: Option<IpAddr>,
+    scope_id: u32,
+    port: u16,
+    reuse: sys::Reuse,
+) -> Result<UdpSocket, Failure> {
+    let (bind_ip, group) = match addr {
+        Some(ip) if ip.is_multicast() => {
+            let any = match ip {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            };
+            (any, Some(ip))
+        }
+        Some(ip) => (ip, None),
+        None => (IpAddr::V4(Ipv4Addr::UNSPECIFIED), None),
+    };
+
+    let bind_addr = match (bind_ip, group) {
+        (IpAddr::V6(ip), None) => SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope_id)),
+        _ => SocketAddr::new(bind_ip, port),
+    };
+    let sock = sys::bind_reusable(bind_addr, reuse)
+        .map_err(|e| Failure::io(&e, format!("Failed to bind UDP socket on {bind_addr}: {e}")))?;
+    log::debug!("Socket bound to {bind_addr}");
+    Ok(sock)
+}
+
+/// A receive socket, with what it can tell apart copies of a datagram by,
+/// if anything, and its multicast memberships.
+type Opened = (UdpSocket, Option<InterfaceDedup>, Option<Membership>);
+
+/// Binds and sets up the receive socket, joining a multicast `addr`.
+fn open_socket(cfg: &ListenConfig) -> Result<Opened, Failure> {
+    let sock = bind_port(cfg.addr, cfg.scope_id, cfg.port, cfg.reuse)?;
+    let membership = match cfg.addr.filter(IpAddr::is_multicast) {
+        Some(group) => {
+            check_join(group, cfg.observe_only)?;
+            Some(Membership::join(
+                &sock,
+                group,
+                cfg.scope_id,
+                cfg.join_on.clone(),
+                cfg.rejoin,
+                Instant::now(),
+            )?)
+        }
+        None => None,
+    };
+
+    if !cfg.source_filter.is_empty() {
+        let attached = match cfg.source_filter.bpf() {
+            Some(prog) => sys::attach_filter(&sock, &prog).map(|()| prog.len()),
+            None => Err(io::Error::other("too many rules")),
+        };
+        match attached {
+            Ok(n) => log::debug!("Source filter attached to the socket ({n} BPF instructions)"),
+            Err(e) => log::warn!("Filtering sources in userspace; kernel filter unavailable: {e}"),
+        }
+    }
+
+    if let Some(bytes) = cfg.rcvbuf {
+        match sys::set_buffer_size(&sock, sys::Buffer::Recv, bytes) {
+            Ok(got) => log::info!(
+                "Receive buffer: {} (requested {})",
+                units::bytes(got as u64),
+                units::bytes(bytes as u64)
+            ),
+            Err(e) => {
+                return Err(format!("Failed to set receive buffer size: {e}").into());
+            }
+        }
+    }
+
+    // Kernel receive timestamps keep this process's scheduling delay out of
+    // the offsets, and let the delay be split into time on the network and
+    // time spent waiting for this process to pick the datagram up.
+    match sys::enable_rx_timestamps(&sock) {
+        Ok(()) => log::debug!("Kernel receive timestamps enabled"),
+        Err(e) if cfg.rx_timestamp == RxTimestamp::Kernel => log::warn!(
+            "Kernel receive timestamps unavailable, measuring offsets from when datagrams are read: {e}"
+        ),
+        Err(e) => log::warn!("Kernel receive timestamps unavailable: {e}"),
+    }
+    // The receiving interface tells copies of one datagram apart on a
+    // multihomed host
+    let dedup = match sys::enable_pktinfo(&sock) {
+        Ok(()) => Some(InterfaceDedup::default()),
+        Err(e) => {
+            log::debug!("Receiving interface unavailable: {e}");
+            None
+        }
+    };
+    Ok((sock, dedup, membership))
+}
+
+/// Runs the receive loop until a fatal error occurs or an offset alarm
+/// without a hook fires.
+pub fn run(cfg: &ListenConfig) -> ExitCode {
+    if cfg.observe_only {
+        transmit::disable();
+    }
+
+    let (mut inlet, mut dedup, mut membership) = match (cfg.transport, cfg.addr) {
+        (Transport::Udp, _) => match open_socket(cfg) {
+            Ok((sock, dedup, membership)) => (Inlet::Socket(sock), dedup, membership),
+            Err(e) => return exit::fail(e),
+        },
+        (Transport::Tcp, Some(ip)) => {
+            let sender = match ip {
+                IpAddr::V4(_) => SocketAddr::new(ip, cfg.port),
+                IpAddr::V6(ip) => SocketAddrV6::new(ip, cfg.port, 0, cfg.scope_id).into(),
+            };
+            (Inlet::Stream(StreamClient::new(sender)), None, None)
+        }
+        (Transport::Tcp, None) => {
+            return exit::fail(
+                "--transport tcp requires --addr, the sender to connect to".to_string(),
+            )
+        }
+    };
+
+    if cfg.observe_only {
+        log::info!("Observe-only: all transmit paths disabled");
+    }
+
+    let mut replay = (cfg.replay_window > 0).then(|| ReplayGuard::new(cfg.replay_window));
+    let mut stats = Stats::default();
+    let mut senders = SenderTable::default();
+    let mut expander = compact::Expander::new(cfg.epoch);
+    let mut skew = cfg.skew_window.map(SkewEstimator::new);
+    let mut recorder = match cfg.record.as_deref().map(Recorder::create).transpose() {
+        Ok(r) => r,
+        Err(e) => return exit::fail(e),
+    };
+    let mut pcap = match (&cfg.pcap, inlet.local_addr()) {
+        (Some(path), Ok(local)) => match PcapngWriter::create(path, local) {
+            Ok(w) => Some(w),
+            Err(e) => return exit::fail(e),
+        },
+        (Some(_), Err(e)) => {
+            return exit::fail(format!("Failed to read local address: {e}"));
+        }
+        (None, _) => None,
+    };
+    let annotator = match cfg
+        .annotate
+        .clone()
+        .map(|src| Annotator::start(src, cfg.annotate_interval))
+        .transpose()
+    {
+        Ok(a) => a,
+        Err(e) => return exit::fail(e),
+    };
+    let mut script = match cfg.script.as_deref().map(Script::spawn).transpose() {
+        Ok(s) => s,
+        Err(e) => return exit::fail(e),
+    };
+    let annotated = annotator.is_some() || script.is_some();
+    let mut exporter = match &cfg.output {
+        Some((OutputFormat::Csv, path)) => match CsvExporter::create(path, annotated) {
+            Ok(e) => Some(e),
+            Err(e) => return exit::fail(e),
+        },
+        None => None,
+    };
+    let mut store = match cfg
+        .store
+        .as_ref()
+        .map(|s| Store::open(s, SystemTime::now()))
+        .transpose()
+    {
+        Ok(s) => s,
+        Err(e) => return exit::fail(e),
+    };
+    let influx = match cfg.influx.clone().map(Influx::start).transpose() {
+        Ok(i) => i,
+        Err(e) => return exit::fail(e),
+    };
+    let mqtt = cfg.mqtt.clone().map(Mqtt::start);
+    let mut drift_log = match cfg
+        .drift_log
+        .as_deref()
+        .map(|p| DriftLog::open(p, drift::DEFAULT_WINDOW))
+        .transpose()
+    {
+        Ok(d) => d,
+        Err(e) => return exit::fail(e),
+    };
+    let mut hdr = match cfg
+        .hdr_log
+        .as_ref()
+        .map(|(path, every)| HdrLog::create(path, *every, SystemTime::now()))
+        .transpose()
+    {
+        Ok(h) => h,
+        Err(e) => return exit::fail(e),
+    };
+    if hdr.is_some() || store.is_some() || influx.is_some() || mqtt.is_some() {
+        // The last interval is written on the way out
+        if let Err(e) = sys::watch_termination() {
+            log::warn!(
+                "Histograms, aggregates, points and messages are written at intervals only: {e}"
+            );
+        }
+    }
+    let mut alarm = cfg.alert.as_ref().map(Alarm::new);
+    let mut watchdog = cfg
+        .expect
+        .as_ref()
+        .map(|e| Watchdog::new(e, Instant::now()));
+    let mut steps = StepDetector::default();
+    let mut eras = Eras::default();
+    let mut statsd = match cfg.statsd.as_ref().map(Statsd::connect).transpose() {
+        Ok(s) => s,
+        Err(e) => return exit::fail(e),
+    };
+    let mut reporter = match cfg.report.as_ref().map(Reporter::connect).transpose() {
+        Ok(r) => r,
+        Err(e) => return exit::fail(e),
+    };
+    let mut refclock = match cfg.shm_segment.map(|unit| (unit, Refclock::open(unit))) {
+        Some((unit, Ok(r))) => {
+            log::info!("Publishing timestamps to NTP shared memory segment {unit}");
+            Some(r)
+        }
+        Some((unit, Err(e))) => {
+            let msg = format!("Failed to attach NTP shared memory segment {unit}: {e}");
+            return exit::fail(Failure::io(&e, msg));
+        }
+        None => None,
+    };
+    let mut discipline = match cfg.discipline.clone().map(|d| (d, discipline::check())) {
+        Some((d, Ok(()))) => {
+            log::info!(
+                "Disciplining the clock to {}, at most {} every {}s",
+                d.source,
+                units::micros(d.max_offset.as_micros() as u64),
+                d.interval.as_secs()
+            );
+            Some(Discipline::new(d))
+        }
+        Some((_, Err(e))) => {
+            let msg = format!("Cannot adjust the clock: {e}");
+            return exit::fail(Failure::io(&e, msg));
+        }
+        None => None,
+    };
+    let mut flight = match (&cfg.capture, inlet.local_addr()) {
+        (Some(c), Ok(local)) => Some(FlightRecorder::new(c, local)),
+        (Some(_), Err(e)) => {
+            return exit::fail(format!("Failed to read local address: {e}"));
+        }
+        (None, _) => None,
+    };
+    let mut next_stats = cfg.stats_interval.map(|i| Instant::now() + i);
+    let mut dashboard = match (cfg.tui, inlet.local_addr()) {
+        (true, Ok(local)) => Some(Dashboard::new(format!("Listening on {local}"))),
+        (true, Err(e)) => {
+            return exit::fail(format!("Failed to read local address: {e}"));
+        }
+        (false, _) => None,
+    };
+
+    let mut web = match cfg.web_addr.map(web::serve).transpose() {
+        Ok(w) => w.map(|(web, _)| web),
+        Err(e) => return exit::fail(e),
+    };
+    let publishers: Vec<Publisher> = match cfg.publish.iter().map(Publisher::open).collect() {
+        Ok(p) => p,
+        Err(e) => return exit::fail(e),
+    };
+    let mut stream = cfg.stream.map(|f| Stream::new(f, io::stdout()));
+
+    if next_stats.is_some()
+        || dashboard.is_some()
+        || reporter.is_some()
+        || watchdog.is_some()
+        || hdr.is_some()
+        || store.is_some()
+        || influx.is_some()
+        || mqtt.is_some()
+        || membership.as_ref().is_some_and(Membership::needs_wakeups)
+    {
+        // Wake up periodically so stats, reports and silences go out even when the port is quiet
+        if let Err(e) = inlet.set_read_timeout(Some(Duration::from_millis(500))) {
+            return exit::fail(format!("Failed to set receive timeout: {e}"));
+        }
+    }
+
+    // Everything privileged is done by now
+    if let Err(e) = cfg.hardening.apply() {
+        return exit::fail(e);
+    }
+
+    let mut buf = [0u8; 1500];
+    // The "Received from" line, kept from one packet to the next
+    let mut line = String::new();
+    let mut opened = Vec::new();
+    loop {
+        if let Some(d) = dashboard.as_mut() {
+            d.draw(Instant::now());
+        }
+        if let (Some(m), Inlet::Socket(sock)) = (membership.as_mut(), &inlet) {
+            m.maintain(sock, Instant::now());
+        }
+        if let (Some(at), Some(every)) = (next_stats, cfg.stats_interval) {
+            if Instant::now() >= at {
+                stats.print();
+                senders.print(Instant::now());
+                if let Some(d) = dedup.as_mut() {
+                    d.print();
+                }
+                if let Some(skew) = &skew {
+                    skew.print();
+                }
+                if let Some(m) = &membership {
+                    m.print(Instant::now());
+                }
+                next_stats = Some(at + every);
+            }
+        }
+        if let Some(r) = reporter.as_mut() {
+            let now = Instant::now();
+            r.report(now, || senders.rows(now));
+        }
+        if let (Some(dog), Some(expect)) = (watchdog.as_mut(), &cfg.expect) {
+            for finding in dog.check(Instant::now()) {
+                if let Err(e) = sender_alarm(&finding, expect) {
+                    return exit::fail(e);
+                }
+            }
+        }
+
+        if let Some(s) = store.as_mut() {
+            let now = SystemTime::now();
+            let stopping = sys::terminating();
+            let written = if stopping { s.flush(now) } else { s.tick(now) };
+            if let Err(e) = written {
+                return exit::fail(e);
+            }
+            if stopping && hdr.is_none() {
+                log::info!("Stopping; store written");
+                return ExitCode::SUCCESS;
+            }
+        }
+        if let Some(h) = hdr.as_mut() {
+            let now = SystemTime::now();
+            if sys::terminating() {
+                return match h.flush(now) {
+                    Ok(()) => {
+                        log::info!("Stopping; histograms written");
+                        ExitCode::SUCCESS
+                    }
+                    Err(e) => exit::fail(e),
+                };
+            }
+            if let Err(e) = h.tick(now) {
+                return exit::fail(e);
+            }
+        }
+        if (influx.is_some() || mqtt.is_some()) && sys::terminating() {
+            // Dropping them writes what is queued
+            log::info!("Stopping; writing the last points and messages");
+            return ExitCode::SUCCESS;
+        }
+
+        let (len, src, kernel_time, iface) = match inlet.recv(&mut buf) {
+            Ok(m) => (m.len, m.src, m.kernel_time, m.iface),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+                ) =>
+            {
+                continue
+            }
+            Err(e) => {
+                log::warn!("recv_from failed: {e}");
+                continue;
+            }
+        };
+
+        if !cfg.sources.is_empty() && !cfg.sources.contains(&src.ip()) {
+            continue;
+        }
+        if !cfg.source_filter.permits(src.ip()) {
+            continue;
+        }
+
+        let arrival = SystemTime::now();
+        if let Some(rec) = recorder.as_mut() {
+            if let Err(e) = rec.write(arrival, src, &buf[..len]) {
+                return exit::fail(format!("Failed to write recording: {e}"));
+            }
+        }
+        if let Some(fr) = flight.as_mut() {
+            // Evidence collection must not take the monitor down with it
+            if let Err(e) = fr.push(arrival, src, &buf[..len]) {
+                log::warn!("Failed to write capture: {e}");
+            }
+        }
+
+        let received = match cfg.rx_timestamp {
+            RxTimestamp::Kernel => kernel_time.unwrap_or(arrival),
+            RxTimestamp::User => arrival,
+        };
+        let recv_ms = match epoch_ms(received) {
+            Ok(ms) => ms,
+            Err(e) => return exit::fail(Failure::new(EXIT_CODE_CLOCK_ERROR, e)),
+        };
+
+        if cfg.observe_only && transmit::sent_count() != 0 {
+            return exit::fail(format!(
+                "Observe-only violated: {} datagram(s) were transmitted",
+                transmit::sent_count()
+            ));
+        }
+
+        let data = match &cfg.encrypt_key {
+            Some(key) => match payload::open(&buf[..len], key, &mut opened) {
+                Ok(()) => &opened[..],
+                Err(e) => {
+                    stats.undecrypted += 1;
+                    if let Some(s) = statsd.as_mut() {
+                        s.count("undecrypted", 1, &[("source", &src.ip().to_string())]);
+                    }
+                    log::warn!("Ignoring datagram from {src}: {e}");
+                    continue;
+                }
+            },
+            None => &buf[..len],
+        };
+        let decoded = match &cfg.codec {
+            Some(codec) => codec.decode(data),
+            None => expander
+                .expand(src, data)
+                .unwrap_or_else(|| payload::decode(data)),
+        };
+        let decoded = decoded.and_then(|mut d| {
+            if d.format == Format::Legacy {
+                d.packet.epoch = cfg.epoch;
+                d.packet.ts_ms = cfg
+                    .epoch
+                    .to_unix_ms(d.packet.ts_ms)
+                    .ok_or(DecodeError::TimestampOutOfRange)?;
+            }
+            Ok(d)
+        });
+        if let Some(w) = pcap.as_mut() {
+            let ts_ms = decoded.as_ref().ok().map(|d| d.packet.ts_ms);
+            if let Err(e) = w.write(kernel_time.unwrap_or(arrival), src, &buf[..len], ts_ms) {
+                return exit::fail(format!("Failed to write pcapng: {e}"));
+            }
+        }
+        let decoded = match decoded {
+            Ok(d) => d,
+            Err(e) => {
+                stats.malformed += 1;
+                if let Some(s) = statsd.as_mut() {
+                    s.count("malformed", 1, &[("source", &src.ip().to_string())]);
+                }
+                log::warn!("Ignoring datagram from {src}: {e}");
+                continue;
+            }
+        };
+        if !decoded.checksum_ok() {
+            stats.corrupt += 1;
+            if let Some(s) = statsd.as_mut() {
+                s.count("corrupt", 1, &[("source", &src.ip().to_string())]);
+            }
+            log::warn!("Corrupt datagram from {src}: checksum mismatch");
+            continue;
+        }
+        let packet = decoded.packet;
+        let Some(kind_name) = cfg.registry.name(packet.kind) else {
+            stats.unknown_type += 1;
+            continue;
+        };
+
+        let mut key_id = None;
+        // Whether a group key or a signature vouches for the packet
+        let mut authenticated = false;
+        if !cfg.groups.is_empty() {
+            let group = packet
+                .group
+                .and_then(|id| cfg.groups.iter().find(|g| g.id == id));
+            let group = match group {
+                Some(g) => g,
+                None => {
+                    stats.unknown_group += 1;
+                    continue;
+                }
+            };
+            if let Some(keys) = &group.key {
+                match decoded.verify_any(keys) {
+                    Some(key) => {
+                        key_id = key.id();
+                        authenticated = true;
+                    }
+                    None => {
+                        stats.group(packet.group).auth_failed += 1;
+                        if let Some(s) = statsd.as_mut() {
+                            s.count("auth_failed", 1, &[("source", &src.ip().to_string())]);
+                        }
+                        log::warn!("Authentication failed from {src} group={}", group.id);
+                        continue;
+                    }
+                }
+            }
+        }
+
+        let signer = match cfg
+            .verify_keys
+            .as_ref()
+            .map(|k| decoded.verify_signature(k))
+        {
+            Some(Ok(name)) => {
+                authenticated = true;
+                Some(name)
+            }
+            Some(Err(e)) => {
+                stats.group(packet.group).auth_failed += 1;
+                if let Some(s) = statsd.as_mut() {
+                    s.count("auth_failed", 1, &[("source", &src.ip().to_string())]);
+                }
+                log::warn!("Signature check failed from {src}: {e}");
+                continue;
+            }
+            None => None,
+        };
+        if let (Some(dog), Some(expect)) = (watchdog.as_mut(), &cfg.expect) {
+            if let Some(finding) = dog.observe(src.ip(), signer, Instant::now()) {
+                if let Err(e) = sender_alarm(&finding, expect) {
+                    return exit::fail(e);
+                }
+            }
+        }
+
+        if let (Some(d), Some(seq), Some(iface)) = (dedup.as_mut(), packet.seq, iface) {
+            if let Some(copy) = d.observe(src, seq, iface, kernel_time.unwrap_or(arrival)) {
+                stats.group(packet.group).interface_copies += 1;
+                if let Some(s) = statsd.as_mut() {
+                    s.count("interface_copies", 1, &[("source", &src.ip().to_string())]);
+                }
+                let iface = d.name(iface).to_string();
+                log::debug!(
+                    "Interface copy dropped from {src} seq={seq} iface={iface} first_iface={} {}",
+                    d.name(copy.first),
+                    units::pair("skew_us", units::micros(copy.skew_us))
+                );
+                continue;
+            }
+        }
+
+        if let (Some(guard), Some(seq)) = (replay.as_mut(), packet.seq) {
+            match guard.check(src, seq) {
+                Ok(()) => {}
+                // Expected from a sender with --redundancy: dropped quietly
+                Err(Rejection::Duplicate) => {
+                    stats.group(packet.group).duplicates += 1;
+                    senders.duplicate(src, packet.group);
+                    if let Some(s) = statsd.as_mut() {
+                        s.count("duplicates", 1, &[("source", &src.ip().to_string())]);
+                    }
+                    log::debug!("Duplicate dropped from {src} seq={seq}");
+                    continue;
+                }
+                Err(Rejection::Stale) => {
+                    stats.group(packet.group).replayed += 1;
+                    if let Some(s) = statsd.as_mut() {
+                        s.count("replayed", 1, &[("source", &src.ip().to_string())]);
+                    }
+                    log::warn!("Replay rejected from {src}: stale seq={seq}");
+                    continue;
+                }
+            }
+        }
+
+        // Only data messages are timing samples
+        if packet.kind != MessageType::DATA {
+            let seq = packet.seq.map_or_else(String::new, |s| format!(" seq={s}"));
+            log::info!(
+                "Received {kind_name} message ({}) from {src}{seq} {}",
+                packet.kind,
+                units::ts(packet.ts_ms)
+            );
+            continue;
+        }
+
+        // Past what signed microseconds hold, so no offset to reckon; such
+        // a timestamp reaches neither the sender table nor the stats
+        let Some((ts_us, sent_us)) = timing_us(&packet) else {
+            stats.malformed += 1;
+            if let Some(s) = statsd.as_mut() {
+                s.count("malformed", 1, &[("source", &src.ip().to_string())]);
+            }
+            log::warn!("Ignoring datagram from {src}: timestamp out of range");
+            continue;
+        };
+
+        // Nothing downstream may act on a timestamp held up past the budget
+        let age_ms = age_ms(recv_ms, ts_us);
+        if let Some(max) = cfg.max_age_ms.filter(|&max| age_ms > max as i64) {
+            stats.group(packet.group).stale += 1;
+            senders.stale(src, packet.group, packet.seq, Instant::now());
+            if let Some(s) = statsd.as_mut() {
+                s.count("stale", 1, &[("source", &src.ip().to_string())]);
+            }
+            let seq = packet.seq.map_or_else(String::new, |s| format!(" seq={s}"));
+            log::warn!("Stale timestamp dropped from {src}{seq}: age_ms={age_ms} max_age_ms={max}");
+            continue;
+        }
+
+        let scripted = script
+            .as_mut()
+            .and_then(|s| s.ask(&script::recv_request(src, &packet, age_ms, &decoded.fields)));
+        let script_annotation = match scripted {
+            Some(Reply::Drop) => {
+                stats.group(packet.group).script_dropped += 1;
+                // Received all the same, so not lost
+                senders.stale(src, packet.group, packet.seq, Instant::now());
+                if let Some(s) = statsd.as_mut() {
+                    s.count("script_dropped", 1, &[("source", &src.ip().to_string())]);
+                }
+                let seq = packet.seq.map_or_else(String::new, |s| format!(" seq={s}"));
+                log::debug!("Script dropped timestamp from {src}{seq}");
+                continue;
+            }
+            Some(Reply::Keep(a)) => a,
+            None => None,
+        };
+
+        if let (true, Inlet::Socket(sock)) = (cfg.ack, &inlet) {
+            let ack = payload::Packet {
+                kind: MessageType::ACK,
+                group: None,
+                control: None,
+                mono_us: None,
+                era: None,
+                relay: None,
+                priority: None,
+                epoch: Epoch::Unix,
+                ..packet
+            };
+            let mut out = Vec::with_capacity(payload::V2_LEN);
+            payload::encode(payload::Format::V2, &ack, None, &mut out);
+            if let Err(e) = transmit::send_to(sock, &out, src) {
+                log::warn!("Failed to ack {src}: {e}");
+            }
+        }
+
+        // Time between kernel receipt and this process reading the datagram
+        let host_delay_us = kernel_time
+            .and_then(|k| arrival.duration_since(k).ok())
+            .map(|d| d.as_micros() as u64);
+
+        let g = stats.group(packet.group);
+        g.received += 1;
+        if let Some(us) = host_delay_us {
+            g.host_delay_us_sum += us;
+            g.host_delay_samples += 1;
+        }
+
+        let offset_ms = age_ms;
+        // Timed by the sender's monotonic clock where it sends one, which
+        // is finer than its millisecond wall clock and never stepped
+        let recv_us = received
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_micros() as i64);
+        let seq_event = senders.observe(
+            src,
+            packet.group,
+            packet.seq,
+            offset_ms,
+            recv_us - sent_us,
+            Instant::now(),
+        );
+        let annotation = script_annotation.or_else(|| annotator.as_ref().map(Annotator::current));
+
+        // Keyed by IP rather than socket address so samples from one
+        // sender stay together across restarts.
+        let sender = match packet.group {
+            Some(g) => format!("{}/{g}", src.ip()),
+            None => src.ip().to_string(),
+        };
+        if exporter.is_some()
+            || store.is_some()
+            || influx.is_some()
+            || mqtt.is_some()
+            || !publishers.is_empty()
+            || stream.is_some()
+        {
+            let row = Observation {
+                recv_ms,
+                send_ms: packet.ts_ms,
+                src,
+                group: packet.group,
+                seq: packet.seq,
+                offset_ms,
+                host_delay_us,
+                annotation: annotation.clone(),
+                fields: decoded
+                    .fields
+                    .iter()
+                    .map(|(k, v)| format!("{k}={v}"))
+                    .collect(),
+            };
+            let lost = seq_event.map_or(0, |e| e.lost());
+            if let Some(i) = &influx {
+                i.observe(&row, &sender, lost, senders.jitter_us(src, packet.group));
+            }
+            if let Some(m) = &mqtt {
+                m.observe(&row, &sender, lost);
+            }
+            for p in &publishers {
+                p.publish(&row, &sender, lost);
+            }
+            match stream.as_mut().map(|s| s.write(&row, &sender, lost)) {
+                Some(Err(e)) if e.kind() == ErrorKind::BrokenPipe => {
+                    // The reader is done, as `head` is
+                    log::debug!("Stdout closed; stopping");
+                    return ExitCode::SUCCESS;
+                }
+                Some(Err(e)) => return exit::fail(format!("Failed to write to stdout: {e}")),
+                _ => {}
+            }
+            let written = exporter
+                .as_mut()
+                .map_or(Ok(()), |exp| exp.write(&row))
+                .and_then(|_| {
+                    store
+                        .as_mut()
+                        .map_or(Ok(()), |s| s.observe(&row, &sender, lost))
+                });
+            if let Err(e) = written {
+                return exit::fail(e);
+            }
+        }
+        if let Some(s) = statsd.as_mut() {
+            let tags = [("sender", sender.as_str())];
+            s.count("received", 1, &tags);
+            s.timing_ms("offset_ms", offset_ms, &tags);
+            if let Some(us) = senders.jitter_us(src, packet.group) {
+                s.gauge("jitter_us", us, &tags);
+            }
+            if let Some(lost) = seq_event.map(|e| e.lost()).filter(|&n| n > 0) {
+                s.count("lost", lost, &tags);
+            }
+        }
+        if let (Some(event), Some(seq)) = (seq_event, packet.seq) {
+            match event {
+                SeqEvent::Gap { first, last } if first == last => {
+                    log::warn!("Sequence gap: sender={sender} missing_seq={first} lost=1")
+                }
+                SeqEvent::Gap { first, last } => log::warn!(
+                    "Sequence gap: sender={sender} missing_seq={first}-{last} lost={}",
+                    event.lost()
+                ),
+                SeqEvent::Reordered { depth } => {
+                    log::debug!("Reordered: sender={sender} seq={seq} depth={depth}")
+                }
+                SeqEvent::Duplicate => log::debug!("Duplicate: sender={sender} seq={seq}"),
+                SeqEvent::Restart => log::info!("Sequence restarted: sender={sender} seq={seq}"),
+            }
+        }
+        // Nothing averaged over the sender's step carries over
+        if let Some(was) = eras.observe(&sender, packet.era.unwrap_or(0)) {
+            log::info!(
+                "Clock era changed: sender={sender} era={} was={was}",
+                packet.era.unwrap_or(0)
+            );
+            if let Some(skew) = skew.as_mut() {
+                skew.forget(&sender);
+            }
+            if let Some(log) = drift_log.as_mut() {
+                log.forget(&sender);
+            }
+        }
+        if let Some(skew) = skew.as_mut() {
+            skew.observe(&sender, recv_ms, offset_ms);
+        }
+        if let Some(r) = refclock.as_mut() {
+            r.update(
+                UNIX_EPOCH + Duration::from_millis(packet.ts_ms),
+                kernel_time.unwrap_or(arrival),
+            );
+        }
+        if let Some(log) = drift_log.as_mut() {
+            if let Err(e) = log.observe(&sender, recv_ms, offset_ms) {
+                return exit::fail(e);
+            }
+        }
+        // Timestamps are truncated to the millisecond, so the sender's
+        // clock read half a millisecond more on average
+        let offset_us = recv_us - (ts_us + 500);
+        if let Some(h) = hdr.as_mut() {
+            h.observe(&sender, offset_us);
+        }
+        // Only a packet vouched for may steer the clock
+        if let Some(d) = discipline
+            .as_mut()
+            .filter(|d| authenticated && d.source() == src.ip())
+        {
+            match d.observe(offset_us, Instant::now()) {
+                Some(Action::Slew { offset_us, samples }) => {
+                    match discipline::slew(-offset_us, d.time_constant()) {
+                        Ok(()) => log::info!(
+                            "Clock slew: sender={sender} offset_us={offset_us} samples={samples}"
+                        ),
+                        Err(e) => log::warn!("Failed to slew the clock: {e}"),
+                    }
+                }
+                Some(Action::Refuse { offset_us }) => log::warn!(
+                    "Clock not slewed: sender={sender} offset_us={offset_us} is beyond the limit; set the clock by hand"
+                ),
+                Some(Action::Skip { samples }) => log::debug!(
+                    "Clock not slewed: sender={sender} only {samples} sample(s) this interval"
+                ),
+                None => {}
+            }
+        }
+        // Reported ahead of the packet whose offset it explains
+        match packet
+            .mono_us
+            .and_then(|us| steps.observe(&sender, packet.ts_ms, us))
+        {
+            Some(step::Event::Step { step_ms }) => log::info!(
+                "Clock step: sender={sender} {}",
+                units::pair("step_ms", units::millis(step_ms))
+            ),
+            Some(step::Event::Restart) => log::info!("Sender restarted: sender={sender}"),
+            None => {}
+        }
+        if let Some(w) = web.as_mut() {
+            let lost = seq_event.map_or(0, |e| e.lost());
+            w.observe(&sender, packet.seq, recv_ms, offset_ms, lost);
+        }
+        match dashboard.as_mut() {
+            Some(d) => d.observe(&sender, packet.seq, offset_ms, Instant::now()),
+            // Written into the same buffer every time, and only when shown
+            None if log::enabled(log::Level::Info) => {
+                line.clear();
+                let _ = write!(line, "Received from {src}");
+                // Only worth telling apart once there is more than one
+                if let (Some(d), Some(i)) = (dedup.as_mut(), iface) {
+                    if d.multihomed() {
+                        let _ = write!(line, " iface={}", d.name(i));
+                    }
+                }
+                if let Some(g) = packet.group {
+                    let _ = write!(line, " group={g}");
+                }
+                if let Some(k) = key_id {
+                    let _ = write!(line, " key_id={k}");
+                }
+                if let Some(s) = packet.seq {
+                    let _ = write!(line, " seq={s}");
+                }
+                if let Some(e) = packet.era {
+                    let _ = write!(line, " era={e}");
+                }
+                if packet.unsynced {
+                    line.push_str(" clock=unsynced");
+                }
+                if let Some(r) = packet.relay {
+                    let _ = write!(line, " hops={} relay_id={}", r.hops, r.id);
+                }
+                if let Some(name) = signer {
+                    let _ = write!(line, " signer={name}");
+                }
+                for (k, v) in &decoded.fields {
+                    let _ = write!(line, " field.{k}={v}");
+                }
+                if packet.epoch != Epoch::Unix {
+                    let _ = write!(line, " epoch={}", packet.epoch);
+                }
+                let _ = write!(
+                    line,
+                    " {} {}",
+                    units::ts(packet.ts_ms),
+                    units::pair("offset_ms", units::millis(offset_ms))
+                );
+                if let Some(us) = host_delay_us {
+                    let _ = write!(line, " {}", units::pair("host_delay_us", units::micros(us)));
+                }
+                if let Some(a) = annotation {
+                    let _ = write!(line, " annotation=\"{a}\"");
+                }
+                log::info!("{line}");
+            }
+            None => {}
+        }
+
+        let mut anomaly = flight
+            .as_mut()
+            .zip(packet.seq)
+            .is_some_and(|(fr, seq)| fr.loss_burst(&sender, seq))
+            .then_some("loss_burst");
+        let alerted = alarm
+            .as_mut()
+            .is_some_and(|alarm| alarm.observe(&sender, offset_ms));
+        if let (true, Some(alert)) = (alerted, &cfg.alert) {
+            log::warn!(
+                "Offset alert: sender={sender} offset_ms={offset_ms} threshold_ms={} consecutive={}",
+                alert.offset_ms, alert.consecutive
+            );
+            anomaly = Some("offset_alert");
+        }
+        if let (Some(fr), Some(why)) = (flight.as_mut(), anomaly) {
+            match fr.trigger(arrival) {
+                Ok(Some(path)) => log::info!("Capturing {why} from {sender} to {path}"),
+                Ok(None) => {}
+                Err(e) => log::warn!("{e}"),
+            }
+        }
+        if let (true, Some(alert)) = (alerted, &cfg.alert) {
+            match &alert.action {
+                alert::Action::Exit => {
+                    return exit::fail(Failure::new(
+                        EXIT_CODE_ALERT,
+                        format!("Offset alarm fired for {sender}"),
+                    ))
+                }
+                alert::Action::Command(cmd) => {
+                    alert::run_hook(cmd, "offset", &sender, Some(offset_ms))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::Packet;
+
+    #[test]
+    fn timestamps_at_the_ends_of_the_range_are_dropped_or_reckoned() {
+        let recv_ms = 1_700_000_000_000;
+        let recv_us = recv_ms as i64 * 1000;
+        let now = Instant::now();
+        let src: SocketAddr = "10.0.0.5:4000".parse().unwrap();
+        let mut senders = SenderTable::default();
+        let mut out = Vec::new();
+        let extremes = [0, 1 << 63, u64::MAX, i64::MAX as u64 / 1000];
+        for (i, &ts_ms) in extremes.iter().enumerate() {
+            for mono_us in [None, Some(0), Some(1 << 63), Some(u64::MAX)] {
+                let packet = Packet {
+                    mono_us,
+                    ..Packet::new(Some(i as u32), ts_ms)
+                };
+                payload::encode(Format::V2, &packet, None, &mut out);
+                let packet = payload::decode(&out).unwrap().packet;
+                let fits = ts_ms <= i64::MAX as u64 / 1000 && mono_us.is_none_or(|us| us < 1 << 63);
+                let Some((ts_us, sent_us)) = timing_us(&packet) else {
+                    assert!(!fits, "{ts_ms} {mono_us:?}");
+                    continue;
+                };
+                assert!(fits, "{ts_ms} {mono_us:?}");
+                let age = age_ms(recv_ms, ts_us);
+                senders.observe(src, None, packet.seq, age, recv_us - sent_us, now);
+            }
+        }
+        assert_eq!(age_ms(recv_ms, 0), recv_ms as i64);
+        assert_eq!(senders.rows(now).len(), 1);
+    }
+}
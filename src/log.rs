@@ -0,0 +1,189 @@
+//! Leveled logging for the long-running modes.
+//!
+//! Progress and per-datagram lines go to stdout and problems to stderr,
+//! each tagged with a [`Level`]; `-q`, `-v`/`-vv` and `--log-level` pick the
+//! most detailed level printed, for the whole process. The default, `info`,
+//! prints what earlier versions printed. `debug` and `trace` add socket and
+//! scheduling details on stderr, prefixed with their level so they stand
+//! apart from the usual output.
+//!
+//! A mode whose stdout is a stream for programs, such as `recv --stream`,
+//! calls [`reserve_stdout`], and the lines meant for stdout go to stderr
+//! instead.
+//!
+//! Summary output that a mode exists to produce, such as `--stats-interval`
+//! lines or an `analyze` report, is printed regardless of the level.
+//!
+//! Under `multi`, each line is prefixed with the name of the instance
+//! whose thread logged it, and threads an instance starts with [`spawn`]
+//! carry the name on.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::thread::{self, JoinHandle};
+
+/// How important a line is; each level includes the ones before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static STDOUT_RESERVED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// The `multi` instance this thread works for.
+    static INSTANCE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+impl Level {
+    /// Parses a `--log-level` value.
+    pub fn parse(s: &str) -> Result<Level, String> {
+        match s {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            _ => Err("expected error, warn, info, debug or trace".to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        })
+    }
+}
+
+/// Prints lines up to `level` from now on, for the whole process.
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Whether lines at `level` are printed.
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// Leaves stdout to a stream for programs from now on, sending what would
+/// be logged there to stderr.
+pub fn reserve_stdout() {
+    STDOUT_RESERVED.store(true, Ordering::Relaxed);
+}
+
+/// Whether [`reserve_stdout`] was called.
+pub fn stdout_reserved() -> bool {
+    STDOUT_RESERVED.load(Ordering::Relaxed)
+}
+
+/// Prefixes this thread's lines with `name` from now on.
+pub fn set_instance(name: &str) {
+    INSTANCE.with(|i| *i.borrow_mut() = Some(name.to_string()));
+}
+
+/// Starts a thread that logs for the same instance as this one.
+pub fn spawn<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> JoinHandle<T> {
+    let instance = INSTANCE.with(|i| i.borrow().clone());
+    thread::spawn(move || {
+        if let Some(name) = instance {
+            set_instance(&name);
+        }
+        f()
+    })
+}
+
+/// The start of each line: the instance's name in brackets, if any.
+pub struct Prefix;
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        INSTANCE.with(|i| match &*i.borrow() {
+            Some(name) => write!(f, "[{name}] "),
+            None => Ok(()),
+        })
+    }
+}
+
+/// Logs a failure, on stderr; always printed.
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Error) {
+            eprintln!("{}{}", $crate::log::Prefix, format_args!($($arg)*));
+        }
+    };
+}
+
+/// Logs a tolerated problem, on stderr.
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Warn) {
+            eprintln!("{}{}", $crate::log::Prefix, format_args!($($arg)*));
+        }
+    };
+}
+
+/// Logs progress or a datagram sent or received, on stdout unless it is
+/// reserved.
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Info) {
+            if $crate::log::stdout_reserved() {
+                eprintln!("{}{}", $crate::log::Prefix, format_args!($($arg)*));
+            } else {
+                println!("{}{}", $crate::log::Prefix, format_args!($($arg)*));
+            }
+        }
+    };
+}
+
+/// Logs a setup detail, on stderr.
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Debug) {
+            eprintln!("{}debug: {}", $crate::log::Prefix, format_args!($($arg)*));
+        }
+    };
+}
+
+/// Logs a per-tick detail, on stderr.
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Trace) {
+            eprintln!("{}trace: {}", $crate::log::Prefix, format_args!($($arg)*));
+        }
+    };
+}
+
+pub use crate::{debug, error, info, trace, warn};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_include_the_ones_before() {
+        assert_eq!(Level::parse("debug"), Ok(Level::Debug));
+        assert!(Level::parse("verbose").is_err());
+        assert_eq!(Level::Warn.to_string(), "warn");
+        assert!(Level::Error < Level::Warn && Level::Debug < Level::Trace);
+        // The process-wide level starts at info
+        assert!(enabled(Level::Info) && !enabled(Level::Debug));
+    }
+}
@@ -0,0 +1,293 @@
+//! A `--log-file` that rotates itself.
+//!
+//! Appending stdout and stderr to a file is enough until the file fills
+//! the disk, which on an embedded box with a small flash partition takes
+//! a few weeks of per-datagram lines. With `--log-max-size` or
+//! `--log-rotate-secs`, the log is renamed to `<path>.1` once it grows past
+//! the size or has been written to for that long, older ones shift up to
+//! `<path>.2` and so on, and the one past `--log-keep` is deleted.
+//!
+//! A file descriptor can't rotate itself, so stdout and stderr are pointed
+//! at pipes instead, and a [`Pump`] thread per stream copies whole lines
+//! into the [`LogFile`]. With `--log-format json`, each line is written as
+//! a JSON object instead, with the time it was read, the stream it came
+//! from, and the text, escaped as in `--error-format json`.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::status::json_string;
+
+/// Rotated files kept by default.
+pub const DEFAULT_KEEP: u32 = 5;
+
+/// How each line is written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// As printed.
+    #[default]
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+impl LogFormat {
+    /// Parses a `--log-format` value.
+    pub fn parse(s: &str) -> Result<LogFormat, String> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("expected text or json".to_string()),
+        }
+    }
+}
+
+/// When to start a new file, and how many old ones to keep.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rotation {
+    /// Size past which the file is rotated.
+    pub max_size: Option<u64>,
+    /// Time after which the file is rotated, counted from when it was opened.
+    pub every: Option<Duration>,
+    /// Rotated files kept as `<path>.1` to `<path>.<keep>`.
+    pub keep: u32,
+}
+
+impl Default for Rotation {
+    fn default() -> Rotation {
+        Rotation {
+            max_size: None,
+            every: None,
+            keep: DEFAULT_KEEP,
+        }
+    }
+}
+
+impl Rotation {
+    /// Whether the file is ever rotated.
+    pub fn active(&self) -> bool {
+        self.max_size.is_some() || self.every.is_some()
+    }
+}
+
+/// The stream a line was printed on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    fn name(self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+}
+
+/// The log being written, and what's needed to rotate it.
+pub struct LogFile {
+    path: PathBuf,
+    rotation: Rotation,
+    format: LogFormat,
+    file: File,
+    size: u64,
+    opened: SystemTime,
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl LogFile {
+    /// Opens `path` for appending; a file left by an earlier run is
+    /// continued, and counts towards `max_size`.
+    pub fn open(path: &Path, rotation: Rotation, format: LogFormat) -> io::Result<LogFile> {
+        let file = open_append(path)?;
+        let size = file.metadata()?.len();
+        Ok(LogFile {
+            path: path.to_path_buf(),
+            rotation,
+            format,
+            file,
+            size,
+            opened: SystemTime::now(),
+        })
+    }
+
+    /// `path` with `.n` appended.
+    fn numbered(&self, n: u32) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{n}"));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let ignore_missing = |r: io::Result<()>| match r {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
+        if self.rotation.keep == 0 {
+            ignore_missing(fs::remove_file(&self.path))?;
+        } else {
+            ignore_missing(fs::remove_file(self.numbered(self.rotation.keep)))?;
+            for n in (1..self.rotation.keep).rev() {
+                ignore_missing(fs::rename(self.numbered(n), self.numbered(n + 1)))?;
+            }
+            fs::rename(&self.path, self.numbered(1))?;
+        }
+        self.file = open_append(&self.path)?;
+        Ok(())
+    }
+
+    /// Rotates if writing `len` more bytes at `now` calls for it. A failed
+    /// rotation is noted in the current file and retried once the limit
+    /// is reached again.
+    fn rotate_before(&mut self, len: u64, now: SystemTime) {
+        let full = self
+            .rotation
+            .max_size
+            .is_some_and(|max| self.size > 0 && self.size + len > max);
+        let due = self
+            .rotation
+            .every
+            .is_some_and(|every| now.duration_since(self.opened).is_ok_and(|d| d >= every));
+        if !full && !due {
+            return;
+        }
+        let rotated = self.rotate();
+        self.size = 0;
+        self.opened = now;
+        if let Err(e) = rotated {
+            let msg = format!("Failed to rotate log file {}: {e}", self.path.display());
+            self.append(Stream::Stderr, msg.as_bytes(), now);
+        }
+    }
+
+    fn append(&mut self, stream: Stream, line: &[u8], now: SystemTime) {
+        let mut out = match self.format {
+            LogFormat::Text => line.to_vec(),
+            LogFormat::Json => {
+                let text = String::from_utf8_lossy(line);
+                let unix_ms = now.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis());
+                format!(
+                    "{{\"unix_ms\":{unix_ms},\"stream\":\"{}\",\"message\":{}}}",
+                    stream.name(),
+                    json_string(text.trim_end_matches(['\n', '\r']))
+                )
+                .into_bytes()
+            }
+        };
+        if out.last() != Some(&b'\n') {
+            out.push(b'\n');
+        }
+        // Nowhere left to report a write error; the next line tries again
+        if self.file.write_all(&out).is_ok() {
+            self.size += out.len() as u64;
+        }
+    }
+
+    /// Writes one line read from `stream` at `now`, rotating first if
+    /// it's time to.
+    pub fn write_line(&mut self, stream: Stream, line: &[u8], now: SystemTime) {
+        self.rotate_before(line.len() as u64, now);
+        self.append(stream, line, now);
+    }
+}
+
+/// The threads copying stdout and stderr into a [`LogFile`].
+pub struct Pump {
+    done: mpsc::Receiver<()>,
+}
+
+impl Pump {
+    /// Copies lines from `stdout` and `stderr`, the read ends of the pipes
+    /// the process writes to, into `log` until they're closed.
+    pub fn start<R: Read + Send + 'static>(log: LogFile, stdout: R, stderr: R) -> Pump {
+        let log = Arc::new(Mutex::new(log));
+        let (tx, done) = mpsc::channel();
+        for (stream, source) in [(Stream::Stdout, stdout), (Stream::Stderr, stderr)] {
+            let (log, tx) = (Arc::clone(&log), tx.clone());
+            thread::spawn(move || {
+                let mut reader = BufReader::new(source);
+                let mut line = Vec::new();
+                while matches!(reader.read_until(b'\n', &mut line), Ok(n) if n > 0) {
+                    let mut log = log.lock().unwrap_or_else(|e| e.into_inner());
+                    log.write_line(stream, &line, SystemTime::now());
+                    line.clear();
+                }
+                let _ = tx.send(());
+            });
+        }
+        Pump { done }
+    }
+
+    /// Waits up to `timeout` for both streams to be drained, once the
+    /// process has closed its ends of the pipes.
+    pub fn finish(self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        for _ in 0..2 {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if self.done.recv_timeout(left).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotates_by_size_and_age_and_keeps_the_newest() {
+        let dir = std::env::temp_dir().join(format!("udp_bcast_ts-logfile-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("beacon.log");
+        let rotation = Rotation {
+            max_size: Some(40),
+            every: Some(Duration::from_secs(3600)),
+            keep: 2,
+        };
+        let mut log = LogFile::open(&path, rotation, LogFormat::Text).unwrap();
+        let t0 = log.opened;
+        let read = |p: &Path| fs::read_to_string(p).unwrap();
+
+        // 25 bytes each: two don't fit in 40
+        for n in 0..4 {
+            log.write_line(
+                Stream::Stdout,
+                format!("Sent seq={n} to 10.0.0.255\n").as_bytes(),
+                t0,
+            );
+        }
+        assert_eq!(read(&path), "Sent seq=3 to 10.0.0.255\n");
+        assert_eq!(read(&log.numbered(1)), "Sent seq=2 to 10.0.0.255\n");
+        assert_eq!(read(&log.numbered(2)), "Sent seq=1 to 10.0.0.255\n");
+        assert!(!log.numbered(3).exists());
+
+        // An hour on, a short line still starts a new file
+        log.write_line(Stream::Stderr, b"late", t0 + Duration::from_secs(3600));
+        assert_eq!(read(&path), "late\n");
+        assert_eq!(read(&log.numbered(2)), "Sent seq=2 to 10.0.0.255\n");
+
+        log.format = LogFormat::Json;
+        log.write_line(
+            Stream::Stderr,
+            b"debug: \"quoted\"\n",
+            UNIX_EPOCH + Duration::from_millis(1500),
+        );
+        assert_eq!(
+            read(&path).lines().last(),
+            Some(r#"{"unix_ms":1500,"stream":"stderr","message":"debug: \"quoted\""}"#)
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,191 +1,2480 @@
-use std::convert::TryInto;
 use std::env;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::io::IsTerminal;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::process::ExitCode;
-use std::thread::sleep;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-
-const EXIT_CODE_USAGE_ERROR: u8 = 2;
-const EXIT_CODE_RUNTIME_ERROR: u8 = 1;
-
-/// Returns the usage message for the program.
-fn usage(program: &str) -> String {
-    format!(
-        "Usage:
-  {program} --addr <IPv4-or-IPv6> --port <1-65535> [--interval-ms <ms>]
-
-Example:
-  {program} --addr 255.255.255.255 --port 12321 --interval-ms 1000
-  {program} --addr ff02::1 --port 12321 --interval-ms 500
-"
-    )
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::builder::BoolishValueParser;
+use clap::error::ErrorKind;
+use clap::{value_parser, ArgAction, Args, CommandFactory, Parser, Subcommand};
+
+#[cfg(feature = "chaos")]
+use udp_bcast_ts::chaos;
+use udp_bcast_ts::epoch::Epoch;
+use udp_bcast_ts::exit::{self, ErrorFormat, Failure};
+use udp_bcast_ts::logfile::LogFormat;
+use udp_bcast_ts::payload::{self, Format};
+use udp_bcast_ts::resolve::{self, Target};
+use udp_bcast_ts::{
+    adaptive, aead, aggregate, alert, annotate, api, auth, bench, blackout, capture, compact,
+    conformance, control, daemon, discipline, drift, ed25519, export, filter, generate, guard, hdr,
+    iface, influx, instances, leap, legacy, listen, log, logfile, mdns, membership, message, mqtt,
+    offload, otlp, peers, privs, publish, record, relay, replay, role, script, selftest, send,
+    service, sink, sntp, start, statsd, store, sys, timing, transport, txdelay, units, watchdog,
+    EXIT_CODE_USAGE_ERROR,
+};
+
+const EXAMPLES: &str = "Examples:
+  udp_bcast_ts send --addr 255.255.255.255 --port 12321 --interval-ms 1000
+  udp_bcast_ts send --addr ff02::1%eth0 --port 12321 --interval-ms 500 --format v2
+  udp_bcast_ts recv --port 12321 --observe-only";
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Broadcast Unix timestamps over UDP, or receive and measure them",
+    after_help = EXAMPLES,
+    arg_required_else_help = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+    /// Print plain numbers instead of scaled values with units (e.g. 5000.0, not 5.00kpps)
+    #[arg(long, global = true)]
+    raw_numbers: bool,
+    /// How to print timestamps: epoch-ms, iso8601 for UTC, or local for the TZ zone
+    #[arg(long, global = true, env = "UDP_BCAST_TS_DISPLAY_FORMAT", default_value = "epoch-ms",
+          value_parser = units::TimeDisplay::parse, value_name = "FORMAT")]
+    display_format: units::TimeDisplay,
+    /// Print only warnings and errors, not a line per datagram
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Also print socket and scheduling details; twice for per-tick timing
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    verbose: u8,
+    /// Most detailed messages to print: error, warn, info, debug or trace (-q and -v take precedence)
+    #[arg(long, global = true, env = "UDP_BCAST_TS_LOG_LEVEL", default_value = "info",
+          value_parser = log::Level::parse, value_name = "LEVEL")]
+    log_level: log::Level,
+    /// How to print the error that ends the process: text, or json for one object on stderr
+    #[arg(long, global = true, env = "UDP_BCAST_TS_ERROR_FORMAT", default_value = "text",
+          value_parser = ErrorFormat::parse, value_name = "FORMAT")]
+    error_format: ErrorFormat,
+    /// Detach and run in the background (Unix)
+    #[arg(long, global = true, env = "UDP_BCAST_TS_DAEMON", value_parser = BoolishValueParser::new())]
+    daemon: bool,
+    /// Write the process ID to this file, and remove it on exit
+    #[arg(
+        long,
+        global = true,
+        env = "UDP_BCAST_TS_PID_FILE",
+        value_name = "PATH"
+    )]
+    pid_file: Option<PathBuf>,
+    /// Append stdout and stderr to this file (with --daemon, instead of discarding them)
+    #[arg(
+        long,
+        global = true,
+        env = "UDP_BCAST_TS_LOG_FILE",
+        value_name = "PATH"
+    )]
+    log_file: Option<PathBuf>,
+    /// Rotate the log file once it grows past this many bytes
+    #[arg(long, global = true, env = "UDP_BCAST_TS_LOG_MAX_SIZE", requires = "log_file",
+          value_parser = value_parser!(u64).range(1..), value_name = "BYTES")]
+    log_max_size: Option<u64>,
+    /// Rotate the log file after this many seconds
+    #[arg(long, global = true, env = "UDP_BCAST_TS_LOG_ROTATE_SECS", requires = "log_file",
+          value_parser = value_parser!(u64).range(1..), value_name = "SECS")]
+    log_rotate_secs: Option<u64>,
+    /// Rotated log files to keep, as PATH.1 (newest) to PATH.N
+    #[arg(long, global = true, env = "UDP_BCAST_TS_LOG_KEEP", requires = "log_file",
+          default_value_t = logfile::DEFAULT_KEEP, value_name = "N")]
+    log_keep: u32,
+    /// How to write lines to the log file: text, or json for one object per line
+    #[arg(long, global = true, env = "UDP_BCAST_TS_LOG_FORMAT", requires = "log_file", default_value = "text",
+          value_parser = LogFormat::parse, value_name = "FORMAT")]
+    log_format: LogFormat,
+    /// Install, uninstall or run the command as a Windows service or launchd daemon, or print its launchd plist
+    #[arg(long, global = true, value_parser = service::Action::parse, value_name = "ACTION",
+          conflicts_with = "daemon")]
+    service: Option<service::Action>,
+    /// Name of the service, and label of the launchd daemon
+    #[arg(long, global = true, default_value = service::DEFAULT_NAME,
+          value_parser = service::parse_name, value_name = "NAME")]
+    service_name: String,
+}
+
+// Parsed once, so the size of the largest set of arguments doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand)]
+enum Command {
+    /// Send timestamp datagrams at a fixed interval
+    Send(SendArgs),
+    /// Run several senders in one process, as configured in a TOML file
+    Multi(MultiArgs),
+    /// Receive timestamp datagrams and report their offsets
+    #[command(alias = "listen")]
+    Recv(RecvArgs),
+    /// Run many counting receivers to load-test a sender
+    Sink(SinkArgs),
+    /// Collect reports from recv --report-to into a fleet-wide view
+    Aggregate(AggregateArgs),
+    /// Estimate per-sender drift from a drift log
+    Analyze(AnalyzeArgs),
+    /// Re-send a recording with its original timing
+    Replay(ReplayArgs),
+    /// Forward received timestamps to another destination, e.g. across a router
+    Relay(RelayArgs),
+    /// Broadcast an authenticated reconfiguration command to --follow senders
+    Control(ControlArgs),
+    /// Check another implementation of the wire format against this one
+    Conformance(ConformanceArgs),
+    /// Compare the local clock against an NTP server
+    Check(CheckArgs),
+    /// Measure latency, loss and reordering to a reflector on another host
+    Bench(BenchArgs),
+    /// Send every payload format across loopback and check it arrives intact
+    Selftest(SelftestArgs),
+    /// List local interfaces with their indexes, addresses and broadcast addresses
+    Interfaces(InterfacesArgs),
+    /// Print shell completions, or the JSON Schema of the instances file
+    Generate(GenerateArgs),
+    /// Run the send loop without sending and report how closely it keeps time
+    TimingReport(TimingReportArgs),
+}
+
+#[derive(Args)]
+struct SendArgs {
+    /// Destination: IPv4 or IPv6 broadcast/multicast address (link-local needs %zone), or a hostname
+    #[arg(long, env = "UDP_BCAST_TS_ADDR", value_name = "IP-OR-HOST", value_parser = Target::parse,
+          required_unless_present_any = ["peers", "dest"])]
+    addr: Option<Target>,
+    /// Destination port
+    #[arg(long, env = "UDP_BCAST_TS_PORT", value_parser = value_parser!(u16).range(1..),
+          required_unless_present = "dest")]
+    port: Option<u16>,
+    /// Send to ADDR:PORT every INTERVAL (e.g. 10.0.0.255:12321@100ms), with its own sequence numbers; may be repeated (overrides --addr)
+    #[arg(long, value_parser = send::Dest::parse, value_name = "ADDR:PORT@INTERVAL",
+          conflicts_with_all = ["addr", "interval_ms", "interval_us", "interval", "rate", "peers", "per_interface", "resolve_interval",
+                                "follow", "role", "standby", "failover_ms", "mdns", "status_addr", "control_api"])]
+    dest: Vec<send::Dest>,
+    /// Interval between sends, in milliseconds
+    #[arg(long, env = "UDP_BCAST_TS_INTERVAL_MS", default_value_t = 1000,
+          value_parser = value_parser!(u64).range(1..), value_name = "MS")]
+    interval_ms: u64,
+    /// Interval between sends, in microseconds
+    #[arg(long, env = "UDP_BCAST_TS_INTERVAL_US", value_parser = value_parser!(u64).range(1..),
+          value_name = "US", conflicts_with = "interval_ms")]
+    interval_us: Option<u64>,
+    /// Interval between sends, with a unit: us, ms or s (e.g. 250us)
+    #[arg(long, env = "UDP_BCAST_TS_INTERVAL", value_parser = send::parse_interval,
+          value_name = "DURATION", conflicts_with_all = ["interval_ms", "interval_us"])]
+    interval: Option<Duration>,
+    /// Send PPS datagrams per second instead (fractions allowed), paced by a token bucket
+    #[arg(long, env = "UDP_BCAST_TS_RATE", value_parser = parse_rate, value_name = "PPS",
+          conflicts_with_all = ["interval_ms", "interval_us", "interval", "jitter", "burst", "burst_spacing_us", "follow"])]
+    rate: Option<f64>,
+    /// Lengthen the interval while receivers started with --ack report no loss, and shorten it when loss or jitter rises
+    #[arg(long, env = "UDP_BCAST_TS_ADAPTIVE", value_parser = BoolishValueParser::new(),
+          conflicts_with_all = ["rate", "peers", "dual_stack", "dest", "follow", "per_interface", "resolve_interval", "io_thread"])]
+    adaptive: bool,
+    /// Shortest interval --adaptive goes down to, with a unit (default: a quarter of the interval)
+    #[arg(long, env = "UDP_BCAST_TS_ADAPTIVE_MIN", value_parser = send::parse_interval, value_name = "DURATION",
+          requires = "adaptive")]
+    adaptive_min: Option<Duration>,
+    /// Longest interval --adaptive goes up to, with a unit (default: four times the interval)
+    #[arg(long, env = "UDP_BCAST_TS_ADAPTIVE_MAX", value_parser = send::parse_interval, value_name = "DURATION",
+          requires = "adaptive")]
+    adaptive_max: Option<Duration>,
+    /// Payload layout: legacy, v2, proto (protocol buffers) or compact (4-byte deltas between full timestamps)
+    #[arg(long, alias = "payload-format", env = "UDP_BCAST_TS_FORMAT", default_value = "legacy",
+          value_parser = Format::parse)]
+    format: Format,
+    /// With --format compact, send a full timestamp every N datagrams [default: 10]
+    #[arg(long, env = "UDP_BCAST_TS_FULL_EVERY", value_parser = value_parser!(u8).range(1..), value_name = "N")]
+    full_every: Option<u8>,
+    /// Time scale of the timestamp: unix (ms since 1970), ntp (NTP 32.32 seconds since 1900) or gps (ms of GPS time since 1980-01-06)
+    #[arg(long, env = "UDP_BCAST_TS_EPOCH", default_value = "unix", value_parser = Epoch::parse, value_name = "EPOCH")]
+    epoch: Epoch,
+    /// Lay legacy payloads out as NAME instead, e.g. le-u64-s for little-endian seconds
+    #[arg(long, env = "UDP_BCAST_TS_LEGACY_FORMAT", value_parser = legacy::Layout::parse, value_name = "NAME")]
+    legacy_format: Option<legacy::Layout>,
+    /// Re-resolve a hostname --addr every SECS seconds
+    #[arg(long, env = "UDP_BCAST_TS_RESOLVE_INTERVAL", value_parser = value_parser!(u64).range(1..), value_name = "SECS")]
+    resolve_interval: Option<u64>,
+    /// Tag payloads with a beacon group, optionally authenticated with a key file or the highest-numbered key in a directory (v2 only)
+    #[arg(long, env = "UDP_BCAST_TS_GROUP", value_parser = parse_group, value_name = "ID[:KEYFILE]")]
+    group: Option<GroupArg>,
+    /// Attach KEY=VALUE metadata to every payload; may be repeated (v2 only)
+    #[arg(long, value_parser = payload::Field::parse, value_name = "KEY=VALUE")]
+    field: Vec<payload::Field>,
+    /// Ask this command about every tick, over stdin and stdout, whether to send it and with which fields
+    #[arg(long, env = "UDP_BCAST_TS_SCRIPT", value_name = "CMD")]
+    script: Option<String>,
+    /// Randomize each gap by up to this much either way, in ms or as a percentage of the interval
+    #[arg(long, env = "UDP_BCAST_TS_JITTER", value_name = "MS|PERCENT%")]
+    jitter: Option<String>,
+    /// Warn when a tick starts sending more than MS after its scheduled time
+    #[arg(long, env = "UDP_BCAST_TS_MAX_LATENESS_MS", value_parser = value_parser!(u64).range(1..),
+          value_name = "MS", conflicts_with = "rate")]
+    max_lateness_ms: Option<u64>,
+    /// Add the time it takes to send to each timestamp: US microseconds, or auto to measure it
+    #[arg(long, env = "UDP_BCAST_TS_TX_COMPENSATION_US", value_parser = txdelay::Compensation::parse,
+          value_name = "US|auto")]
+    tx_compensation_us: Option<txdelay::Compensation>,
+    /// Hand each tick's datagrams to the kernel ahead of time with the time they are to leave (SO_TXTIME, Linux with the ETF qdisc)
+    #[arg(long, env = "UDP_BCAST_TS_TXTIME", value_parser = BoolishValueParser::new(),
+          conflicts_with_all = ["tx_compensation_us", "io_thread", "peers", "rate", "burst_spacing_us"])]
+    txtime: bool,
+    /// How far ahead of its transmit time each tick is handed to the kernel with --txtime, in microseconds
+    #[arg(long, env = "UDP_BCAST_TS_TXTIME_LEAD_US", requires = "txtime", default_value_t = 1000,
+          value_parser = value_parser!(u64).range(1..), value_name = "US")]
+    txtime_lead_us: u64,
+    /// Datagrams to send back-to-back on every tick
+    #[arg(long, env = "UDP_BCAST_TS_BURST", default_value_t = 1, value_parser = value_parser!(u32).range(1..), value_name = "N")]
+    burst: u32,
+    /// Gap between datagrams inside a burst, in microseconds
+    #[arg(
+        long,
+        env = "UDP_BCAST_TS_BURST_SPACING_US",
+        default_value_t = 0,
+        value_name = "US"
+    )]
+    burst_spacing_us: u64,
+    /// Send each datagram N times, with the same sequence number (v2 only)
+    #[arg(long, env = "UDP_BCAST_TS_REDUNDANCY", default_value_t = 1, value_parser = value_parser!(u32).range(1..=16), value_name = "N")]
+    redundancy: u32,
+    /// Gap between redundant copies, in milliseconds
+    #[arg(
+        long,
+        env = "UDP_BCAST_TS_REDUNDANCY_SPACING_MS",
+        default_value_t = 5,
+        value_name = "MS"
+    )]
+    redundancy_spacing_ms: u64,
+    /// Apply control commands received on PORT (requires --control-key)
+    #[arg(long, env = "UDP_BCAST_TS_FOLLOW", value_parser = value_parser!(u16).range(1..), value_name = "PORT",
+          requires = "control_key")]
+    follow: Option<u16>,
+    /// Key file control commands must be authenticated with
+    #[arg(
+        long,
+        env = "UDP_BCAST_TS_CONTROL_KEY",
+        value_name = "KEYFILE",
+        requires = "follow"
+    )]
+    control_key: Option<String>,
+    /// primary always transmits; follower and auto stand by while another sender is heard on --port
+    #[arg(long, env = "UDP_BCAST_TS_ROLE", default_value = "primary", value_parser = role::Role::parse)]
+    role: role::Role,
+    /// Silence on --port after which a follower takes over [default: 3 intervals]
+    #[arg(long, env = "UDP_BCAST_TS_FAILOVER_MS", value_parser = value_parser!(u64).range(1..), value_name = "MS")]
+    failover_ms: Option<u64>,
+    /// Shorthand for --role follower
+    #[arg(long, env = "UDP_BCAST_TS_STANDBY", value_parser = BoolishValueParser::new(), conflicts_with = "role")]
+    standby: bool,
+    /// Rank among redundant senders, 0-255: standbys stay silent only for higher-ranked senders (v2 and proto)
+    #[arg(long, env = "UDP_BCAST_TS_PRIORITY", value_name = "N")]
+    priority: Option<u8>,
+    /// Listen on --port and warn while another sender broadcasts the same group there
+    #[arg(long, env = "UDP_BCAST_TS_GUARD", value_parser = BoolishValueParser::new(), conflicts_with_all = ["dest", "standby"])]
+    guard: bool,
+    /// Like --guard, but first listen for 2 s and refuse to start if another sender is heard
+    #[arg(long, env = "UDP_BCAST_TS_EXCLUSIVE", value_parser = BoolishValueParser::new(), conflicts_with_all = ["dest", "standby"])]
+    exclusive: bool,
+    /// With --addr 255.255.255.255, send a directed broadcast on each IPv4 interface instead
+    #[arg(long, env = "UDP_BCAST_TS_PER_INTERFACE", value_parser = BoolishValueParser::new())]
+    per_interface: bool,
+    /// Send every datagram to this IPv6 multicast group as well as the IPv4 --addr (link-local needs %zone)
+    #[arg(long, env = "UDP_BCAST_TS_DUAL_STACK", value_parser = send::parse_dual_stack, value_name = "IPV6-GROUP",
+          conflicts_with_all = ["peers", "dest", "per_interface", "resolve_interval", "ttl", "mdns"])]
+    dual_stack: Option<Target>,
+    /// udp broadcasts as usual; tcp listens on --addr:--port and streams to every consumer that connects
+    #[arg(long, env = "UDP_BCAST_TS_TRANSPORT", default_value = "udp", value_parser = transport::Transport::parse,
+          value_name = "TRANSPORT",
+          conflicts_with_all = ["peers", "dest", "dual_stack", "per_interface", "role", "standby", "mdns", "dry_run", "resolve_interval"])]
+    transport: transport::Transport,
+    /// Send and log from a separate thread, so neither can delay reading the clock on the next tick
+    #[arg(long, env = "UDP_BCAST_TS_IO_THREAD", value_parser = BoolishValueParser::new(), conflicts_with = "peers")]
+    io_thread: bool,
+    /// Queue at most N ticks for the --io-thread, dropping ticks beyond that
+    #[arg(long, env = "UDP_BCAST_TS_IO_QUEUE", requires = "io_thread", value_name = "N",
+          default_value_t = offload::DEFAULT_DEPTH as u32, value_parser = value_parser!(u32).range(1..=65536))]
+    io_queue: u32,
+    /// Push metrics to a statsd/DogStatsD agent at HOST:PORT
+    #[arg(long, env = "UDP_BCAST_TS_STATSD", value_name = "HOST:PORT")]
+    statsd: Option<String>,
+    /// Tag --statsd metrics DogStatsD-style (e.g. with the sender)
+    #[arg(long, env = "UDP_BCAST_TS_STATSD_TAGS", value_parser = BoolishValueParser::new(), requires = "statsd")]
+    statsd_tags: bool,
+    /// Export a span per tick and send metrics over OTLP/HTTP, configured by the OTEL_* variables
+    #[arg(long, env = "UDP_BCAST_TS_OTLP", value_parser = BoolishValueParser::new())]
+    otlp: bool,
+    /// Advertise the beacon via mDNS/DNS-SD as _udptimestamp._udp.local
+    #[arg(long, visible_alias = "advertise", env = "UDP_BCAST_TS_MDNS", value_parser = BoolishValueParser::new())]
+    mdns: bool,
+    /// DNS-SD instance name [default: udp_bcast_ts on <hostname>]
+    #[arg(
+        long,
+        env = "UDP_BCAST_TS_MDNS_NAME",
+        requires = "mdns",
+        value_name = "NAME"
+    )]
+    mdns_name: Option<String>,
+    /// Smear leap seconds from a leap-seconds.list table out of the timestamp instead of stepping
+    #[arg(long, value_name = "TABLE", num_args = 0..=1,
+          default_missing_value = leap::DEFAULT_TABLE)]
+    leap_smear: Option<String>,
+    /// Width of the smear, centred on the leap second
+    #[arg(long, default_value_t = 86400, value_parser = value_parser!(u64).range(2..),
+          value_name = "SECS", requires = "leap_smear")]
+    leap_smear_window: u64,
+    /// Stop sending for this long after the wall clock steps, so no datagram straddles the correction
+    #[arg(long, env = "UDP_BCAST_TS_STEP_HOLDDOWN_MS", value_parser = value_parser!(u64).range(1..),
+          value_name = "MS")]
+    step_holddown_ms: Option<u64>,
+    /// Run the send loop under a realtime policy: fifo:<1-99> (Linux; needs CAP_SYS_NICE)
+    #[arg(long, env = "UDP_BCAST_TS_SCHED", value_parser = parse_sched, value_name = "fifo:PRIO")]
+    sched: Option<i32>,
+    /// Pin the send loop to one CPU core (Linux)
+    #[arg(long, env = "UDP_BCAST_TS_CPU_AFFINITY", value_name = "CORE")]
+    cpu_affinity: Option<usize>,
+    /// Switch to this user, and optionally group, once the sockets are bound (Unix; needs root)
+    #[arg(long, env = "UDP_BCAST_TS_USER", value_parser = privs::UserSpec::parse, value_name = "USER[:GROUP]")]
+    user: Option<privs::UserSpec>,
+    /// Allow only the syscalls the send loop makes once running (Linux)
+    #[arg(long, env = "UDP_BCAST_TS_SECCOMP", value_parser = BoolishValueParser::new())]
+    seccomp: bool,
+    /// Request a send socket buffer (SO_SNDBUF) of BYTES
+    #[arg(long, env = "UDP_BCAST_TS_SO_SNDBUF", value_parser = value_parser!(u32).range(1..=i32::MAX as i64), value_name = "BYTES")]
+    so_sndbuf: Option<u32>,
+    /// Give up on a send that blocks longer than MS, instead of stalling the loop
+    #[arg(long, env = "UDP_BCAST_TS_SEND_TIMEOUT_MS", value_parser = value_parser!(u64).range(1..), value_name = "MS")]
+    send_timeout_ms: Option<u64>,
+    /// IP TTL for outgoing datagrams, 1-255 (IPv4)
+    #[arg(long, env = "UDP_BCAST_TS_TTL", value_parser = value_parser!(u32).range(1..=255), value_name = "N")]
+    ttl: Option<u32>,
+    /// Let payloads larger than the path MTU go out fragmented instead of refusing to start
+    #[arg(long, env = "UDP_BCAST_TS_ALLOW_FRAGMENTATION", value_parser = BoolishValueParser::new())]
+    allow_fragmentation: bool,
+    /// Unicast to each HOST[:PORT] listed in FILE instead of --addr (overrides --addr)
+    #[arg(long, env = "UDP_BCAST_TS_PEERS", value_name = "FILE",
+          conflicts_with_all = ["per_interface", "resolve_interval", "mdns", "role", "standby", "failover_ms"])]
+    peers: Option<String>,
+    /// Skip a --peers entry for SECS seconds after it is reported unreachable
+    #[arg(long, env = "UDP_BCAST_TS_PEER_BACKOFF", value_parser = value_parser!(u64).range(1..), value_name = "SECS",
+          requires = "peers")]
+    peer_backoff: Option<u64>,
+    /// Send to the --peers from N threads in parallel, so a send that blocks holds up only the peers sharing its thread
+    #[arg(long, env = "UDP_BCAST_TS_FANOUT_THREADS", value_parser = value_parser!(u32).range(2..=256), value_name = "N",
+          requires = "peers")]
+    fanout_threads: Option<u32>,
+    /// Sign payloads with this Ed25519 private key (hex seed or PKCS#8 PEM; v2 only)
+    #[arg(long, env = "UDP_BCAST_TS_SIGN_KEY", value_name = "KEYFILE")]
+    sign_key: Option<String>,
+    /// End payloads with a CRC-32C, for receivers to drop corrupted ones (v2 only)
+    #[arg(long, env = "UDP_BCAST_TS_CRC", value_parser = BoolishValueParser::new(), conflicts_with = "sign_key")]
+    crc: bool,
+    /// Encrypt payloads with ChaCha20-Poly1305, keyed by this file (v2 only)
+    #[arg(long, env = "UDP_BCAST_TS_ENCRYPT_KEY", value_name = "KEYFILE",
+          conflicts_with_all = ["crc", "serve_queries", "adaptive"])]
+    encrypt_key: Option<String>,
+    /// Query this NTP server at startup and refuse to send if the local clock is off
+    #[arg(long, env = "UDP_BCAST_TS_REQUIRE_SYNC", value_name = "HOST[:PORT]")]
+    require_sync: Option<String>,
+    /// Largest clock offset --require-sync accepts, in milliseconds [default: 100]
+    #[arg(
+        long,
+        env = "UDP_BCAST_TS_MAX_CLOCK_OFFSET_MS",
+        value_name = "MS",
+        requires = "require_sync"
+    )]
+    max_clock_offset_ms: Option<u64>,
+    /// While the kernel doesn't count the clock as synchronized (Linux): hold sends, or mark them as unsynced (v2 and proto)
+    #[arg(long, env = "UDP_BCAST_TS_REQUIRE_CLOCK_SYNC", value_name = "MODE", num_args = 0..=1,
+          default_missing_value = "hold", value_parser = send::SyncMode::parse)]
+    require_clock_sync: Option<send::SyncMode>,
+    /// Largest error the kernel may estimate for a clock --require-clock-sync counts as synchronized, in milliseconds
+    #[arg(long, env = "UDP_BCAST_TS_MAX_CLOCK_ERROR_MS", value_name = "MS", default_value_t = 100,
+          value_parser = value_parser!(u64).range(1..), requires = "require_clock_sync")]
+    max_clock_error_ms: u64,
+    /// Serve /healthz and /status over HTTP on this address, e.g. 0.0.0.0:8080
+    #[arg(long, env = "UDP_BCAST_TS_STATUS_ADDR", value_name = "IP:PORT")]
+    status_addr: Option<SocketAddr>,
+    /// Answer unicast time queries on this port, besides broadcasting
+    #[arg(long, env = "UDP_BCAST_TS_SERVE_QUERIES", value_parser = value_parser!(u16).range(1..), value_name = "PORT")]
+    serve_queries: Option<u16>,
+    /// Take pause, resume, interval and destination changes over HTTP on this loopback address, e.g. 127.0.0.1:8081
+    #[arg(long, env = "UDP_BCAST_TS_CONTROL_API", value_parser = api::parse_addr, value_name = "IP:PORT",
+          requires = "control_token_file")]
+    control_api: Option<SocketAddr>,
+    /// File holding the bearer token --control-api requests must carry
+    #[arg(
+        long,
+        env = "UDP_BCAST_TS_CONTROL_TOKEN_FILE",
+        requires = "control_api",
+        value_name = "FILE"
+    )]
+    control_token_file: Option<String>,
+    /// Wait DURATION (e.g. 30s) before the first datagram
+    #[arg(long, env = "UDP_BCAST_TS_START_DELAY", value_parser = start::parse_duration, value_name = "DURATION")]
+    start_delay: Option<Duration>,
+    /// Wait a random time of up to DURATION more, so units booted together spread out
+    #[arg(long, env = "UDP_BCAST_TS_START_RANDOM", value_parser = start::parse_duration, value_name = "DURATION")]
+    start_random: Option<Duration>,
+    /// Wait until this wall-clock time (RFC 3339, e.g. 2026-03-01T12:00:00Z) before the first datagram
+    #[arg(long, env = "UDP_BCAST_TS_START_AT", value_parser = start::parse_rfc3339, value_name = "TIME")]
+    start_at: Option<std::time::SystemTime>,
+    /// Append statistics snapshots, taken on SIGUSR1 or POST /v1/snapshot, to FILE instead of stderr
+    #[arg(long, env = "UDP_BCAST_TS_SNAPSHOT_FILE", value_name = "FILE")]
+    snapshot_file: Option<String>,
+    /// Keep the next sequence number and clock era in a file in DIR, and carry on from them after a restart
+    #[arg(long, env = "UDP_BCAST_TS_STATE_DIR", value_name = "DIR")]
+    state_dir: Option<PathBuf>,
+    /// Hold back datagrams in this window, e.g. "Sat 02:00-04:00" (UTC), optionally for one destination ("10.0.0.255=..."); may be repeated
+    #[arg(long, value_parser = blackout::Blackout::parse, value_name = "[DEST=]WINDOW")]
+    blackout: Vec<blackout::Blackout>,
+    /// Set up the sockets, print the resolved plan and exit without sending
+    #[arg(long, env = "UDP_BCAST_TS_DRY_RUN", value_parser = BoolishValueParser::new(),
+          conflicts_with = "daemon")]
+    dry_run: bool,
+    /// Send one timestamp and exit, with status 0 only if every destination took it
+    #[arg(long, env = "UDP_BCAST_TS_ONCE", value_parser = BoolishValueParser::new(),
+          conflicts_with_all = ["rate", "burst", "adaptive", "dest", "follow", "role", "standby", "guard", "exclusive",
+                                "io_thread", "resolve_interval", "status_addr", "control_api", "serve_queries", "mdns",
+                                "daemon", "dry_run"])]
+    once: bool,
+    /// With --once, wait up to MS for a receiver started with --ack to ack the timestamp, and fail if none does
+    #[arg(long, env = "UDP_BCAST_TS_WAIT_ACK_MS", requires = "once", value_parser = value_parser!(u64).range(1..),
+          value_name = "MS", conflicts_with_all = ["peers", "dual_stack", "per_interface"])]
+    wait_ack_ms: Option<u64>,
+    /// Testing: skip each datagram with probability P (0-1)
+    #[cfg(feature = "chaos")]
+    #[arg(long, value_parser = parse_probability, value_name = "P")]
+    drop_probability: Option<f64>,
+    /// Testing: hold each datagram for a random 0-MS ms before sending it
+    #[cfg(feature = "chaos")]
+    #[arg(long, value_name = "MS")]
+    delay_jitter_ms: Option<u64>,
+}
+
+#[derive(Args)]
+struct RecvArgs {
+    /// Port to listen on
+    #[arg(long, env = "UDP_BCAST_TS_PORT", value_parser = value_parser!(u16).range(1..),
+          required_unless_present = "discover")]
+    port: Option<u16>,
+    /// Unicast address to bind to, or multicast group to join, with optional %zone [default: 0.0.0.0]
+    #[arg(long, env = "UDP_BCAST_TS_ADDR", value_name = "BIND-OR-GROUP",
+          value_parser = resolve::parse_ip)]
+    addr: Option<(IpAddr, u32)>,
+    /// Find beacons via mDNS/DNS-SD and receive from INSTANCE, or from all of them (overrides --port and --addr)
+    #[arg(long, value_name = "INSTANCE", conflicts_with = "observe_only")]
+    discover: Option<Option<String>>,
+    /// How long to browse for --discover, in seconds
+    #[arg(long, default_value_t = 3, value_parser = value_parser!(u64).range(1..),
+          value_name = "SECS", requires = "discover")]
+    discover_wait: u64,
+    /// Hard-disable every transmit path and verify none was used
+    #[arg(long, env = "UDP_BCAST_TS_OBSERVE_ONLY", value_parser = BoolishValueParser::new())]
+    observe_only: bool,
+    /// Join a multicast --addr on this interface instead of the one the kernel picks, or on every one with all; may be repeated
+    #[arg(long, value_name = "IFACE", conflicts_with = "observe_only")]
+    join_iface: Vec<String>,
+    /// Leave and rejoin a multicast --addr every SECS seconds, so snooping switches keep forwarding it
+    #[arg(long, env = "UDP_BCAST_TS_REJOIN_SECS", value_parser = value_parser!(u64).range(1..),
+          value_name = "SECS", conflicts_with = "observe_only")]
+    rejoin_secs: Option<u64>,
+    /// Time scale of legacy payloads, which can't say: unix, ntp or gps (v2 and proto payloads carry theirs)
+    #[arg(long, env = "UDP_BCAST_TS_EPOCH", default_value = "unix", value_parser = Epoch::parse, value_name = "EPOCH")]
+    epoch: Epoch,
+    /// Read legacy payloads laid out as NAME, e.g. le-u64-s for little-endian seconds
+    #[arg(long, env = "UDP_BCAST_TS_LEGACY_FORMAT", value_parser = legacy::Layout::parse, value_name = "NAME")]
+    legacy_format: Option<legacy::Layout>,
+    /// Per-sender replay window for sequenced payloads (0 disables)
+    #[arg(long, env = "UDP_BCAST_TS_REPLAY_WINDOW", default_value_t = replay::DEFAULT_WINDOW, value_name = "N",
+          value_parser = value_parser!(u32).range(..=replay::MAX_WINDOW as i64))]
+    replay_window: u32,
+    /// Accept only this beacon group, optionally requiring a valid MAC from a key file or any key in a directory; may be repeated
+    #[arg(long, value_parser = parse_group, value_name = "ID[:KEYFILE]")]
+    group: Vec<GroupArg>,
+    /// Accept datagrams only from this address or prefix, e.g. 10.0.0.0/8; may be repeated
+    #[arg(long, value_parser = filter::Cidr::parse, value_name = "CIDR")]
+    allow_source: Vec<filter::Cidr>,
+    /// Drop datagrams from this address or prefix, even if allowed; may be repeated
+    #[arg(long, value_parser = filter::Cidr::parse, value_name = "CIDR")]
+    deny_source: Vec<filter::Cidr>,
+    /// Print per-group receive statistics every SECS seconds
+    #[arg(long, env = "UDP_BCAST_TS_STATS_INTERVAL", value_parser = value_parser!(u64).range(1..), value_name = "SECS")]
+    stats_interval: Option<u64>,
+    /// Write every received datagram to FILE for later replay
+    #[arg(long, env = "UDP_BCAST_TS_RECORD", value_name = "FILE")]
+    record: Option<String>,
+    /// Capture every received datagram to FILE as pcapng, with kernel receive times
+    #[arg(long, env = "UDP_BCAST_TS_PCAP", value_name = "FILE")]
+    pcap: Option<String>,
+    /// Append per-sender drift samples to FILE
+    #[arg(long, env = "UDP_BCAST_TS_DRIFT_LOG", value_name = "FILE")]
+    drift_log: Option<String>,
+    /// Write each sender's offset histogram to FILE every --hdr-interval, in the HdrHistogram log format
+    #[arg(long, env = "UDP_BCAST_TS_HDR_LOG", value_name = "FILE")]
+    hdr_log: Option<String>,
+    /// Seconds each --hdr-log histogram covers
+    #[arg(long, env = "UDP_BCAST_TS_HDR_INTERVAL", requires = "hdr_log", value_name = "SECS",
+          default_value_t = hdr::DEFAULT_INTERVAL.as_secs(), value_parser = value_parser!(u64).range(1..))]
+    hdr_interval: u64,
+    /// Write one row per accepted packet to FILE (FORMAT: csv)
+    #[arg(long, num_args = 2, value_names = ["FORMAT", "FILE"])]
+    output: Option<Vec<String>>,
+    /// Keep every accepted packet and per-sender aggregates in a database (sqlite:<path>; builds with the sqlite feature)
+    #[arg(long, env = "UDP_BCAST_TS_STORE", value_parser = store::Backend::parse, value_name = "sqlite:PATH")]
+    store: Option<store::Backend>,
+    /// Seconds each --store aggregate covers
+    #[arg(long, env = "UDP_BCAST_TS_STORE_AGGREGATE_INTERVAL", requires = "store", value_name = "SECS",
+          default_value_t = store::DEFAULT_AGGREGATE_INTERVAL.as_secs(), value_parser = value_parser!(u64).range(1..))]
+    store_aggregate_interval: u64,
+    /// Delete --store rows older than DAYS days
+    #[arg(long, env = "UDP_BCAST_TS_STORE_RETENTION_DAYS", requires = "store", value_name = "DAYS",
+          value_parser = value_parser!(u64).range(1..=36500))]
+    store_retention_days: Option<u64>,
+    /// Write each accepted packet as an InfluxDB point to an http:// write URL, a udp://HOST:PORT listener, or - for stdout
+    #[arg(long, env = "UDP_BCAST_TS_INFLUX", value_parser = influx::Target::parse, value_name = "URL",
+          conflicts_with = "tui")]
+    influx: Option<influx::Target>,
+    /// Authenticate --influx writes with the API token in FILE
+    #[arg(
+        long,
+        env = "UDP_BCAST_TS_INFLUX_TOKEN_FILE",
+        requires = "influx",
+        value_name = "FILE"
+    )]
+    influx_token_file: Option<String>,
+    /// Publish each accepted packet as JSON to the MQTT broker at an mqtt://HOST[:PORT] URL (QoS 0; TLS is not supported)
+    #[arg(long, env = "UDP_BCAST_TS_MQTT", value_parser = mqtt::Broker::parse, value_name = "URL",
+          conflicts_with = "observe_only")]
+    mqtt: Option<mqtt::Broker>,
+    /// MQTT topic to publish on; {sender} is replaced by the sender's IP, or IP/GROUP
+    #[arg(long, env = "UDP_BCAST_TS_TOPIC", requires = "mqtt", value_parser = mqtt::parse_topic,
+          default_value = mqtt::DEFAULT_TOPIC)]
+    topic: String,
+    /// User name to log in to the --mqtt broker with
+    #[arg(
+        long,
+        env = "UDP_BCAST_TS_MQTT_USER",
+        requires = "mqtt",
+        value_name = "NAME"
+    )]
+    mqtt_user: Option<String>,
+    /// Log in to the --mqtt broker with the password in FILE
+    #[arg(
+        long,
+        env = "UDP_BCAST_TS_MQTT_PASSWORD_FILE",
+        requires = "mqtt_user",
+        value_name = "FILE"
+    )]
+    mqtt_password_file: Option<String>,
+    /// Attach the output of a shell command to each measurement
+    #[arg(long, value_name = "CMD", conflicts_with = "annotate_file")]
+    annotate_cmd: Option<String>,
+    /// Attach the contents of a file to each measurement
+    #[arg(long, value_name = "FILE")]
+    annotate_file: Option<String>,
+    /// How often to refresh the annotation, in seconds
+    #[arg(long, default_value_t = annotate::DEFAULT_INTERVAL.as_secs(),
+          value_parser = value_parser!(u64).range(1..), value_name = "SECS")]
+    annotate_interval: u64,
+    /// Ask this command about every timestamp, over stdin and stdout, whether to keep it and how to annotate it
+    #[arg(long, env = "UDP_BCAST_TS_SCRIPT", value_name = "CMD", conflicts_with_all = ["annotate_cmd", "annotate_file", "observe_only"])]
+    script: Option<String>,
+    /// Answer each accepted timestamp with an ack datagram (see conformance)
+    #[arg(long, conflicts_with = "observe_only")]
+    ack: bool,
+    /// Decrypt payloads sealed with this --encrypt-key file, dropping any that aren't sealed
+    #[arg(long, env = "UDP_BCAST_TS_ENCRYPT_KEY", value_name = "KEYFILE", conflicts_with_all = ["ack", "legacy_format"])]
+    encrypt_key: Option<String>,
+    /// Drop timestamps that arrive more than MS old by this host's clock, counting them as stale
+    #[arg(long, env = "UDP_BCAST_TS_MAX_AGE_MS", value_parser = value_parser!(u64).range(1..), value_name = "MS")]
+    max_age_ms: Option<u64>,
+    /// Alert when a sender's offset exceeds MS either way (exits with code 3 unless --on-alert)
+    #[arg(long, env = "UDP_BCAST_TS_ALERT_OFFSET_MS", value_parser = value_parser!(u64).range(1..), value_name = "MS")]
+    alert_offset_ms: Option<u64>,
+    /// Consecutive out-of-bounds packets from one sender before alerting
+    #[arg(long, env = "UDP_BCAST_TS_ALERT_CONSECUTIVE", requires = "alert_offset_ms", default_value_t = alert::DEFAULT_CONSECUTIVE,
+          value_parser = value_parser!(u32).range(1..), value_name = "N")]
+    alert_consecutive: u32,
+    /// Run a shell command on each alert and keep receiving, instead of exiting
+    #[arg(long, env = "UDP_BCAST_TS_ON_ALERT", value_name = "CMD")]
+    on_alert: Option<String>,
+    /// Sender the topology should have, by IP or by --verify-keys key name; may be repeated
+    #[arg(long, value_parser = watchdog::Expected::parse, value_name = "ADDR|KEY")]
+    expect_sender: Vec<watchdog::Expected>,
+    /// Report an expected sender unheard for SECS seconds
+    #[arg(long, env = "UDP_BCAST_TS_EXPECT_TIMEOUT", requires = "expect_sender", default_value_t = watchdog::DEFAULT_TIMEOUT.as_secs(),
+          value_parser = value_parser!(u64).range(1..), value_name = "SECS")]
+    expect_timeout: u64,
+    /// Alert on unexpected and quiet senders (exits with code 3 unless --on-alert)
+    #[arg(long, env = "UDP_BCAST_TS_STRICT", requires = "expect_sender", value_parser = BoolishValueParser::new())]
+    strict: bool,
+    /// On a loss burst or offset alert, write the traffic from PRE seconds before to POST after to a pcap
+    #[arg(long, value_parser = parse_capture_window, value_name = "PRE:POST")]
+    capture_window: Option<(Duration, Duration)>,
+    /// Directory for --capture-window pcap files
+    #[arg(
+        long,
+        requires = "capture_window",
+        default_value = ".",
+        value_name = "DIR"
+    )]
+    capture_dir: String,
+    /// Push metrics to a statsd/DogStatsD agent at HOST:PORT
+    #[arg(
+        long,
+        env = "UDP_BCAST_TS_STATSD",
+        value_name = "HOST:PORT",
+        conflicts_with = "observe_only"
+    )]
+    statsd: Option<String>,
+    /// Tag --statsd metrics DogStatsD-style (e.g. with the sender)
+    #[arg(long, env = "UDP_BCAST_TS_STATSD_TAGS", value_parser = BoolishValueParser::new(), requires = "statsd")]
+    statsd_tags: bool,
+    /// Send what is known about each sender to an `aggregate` instance at HOST:PORT
+    #[arg(
+        long,
+        env = "UDP_BCAST_TS_REPORT_TO",
+        value_name = "HOST:PORT",
+        conflicts_with = "observe_only"
+    )]
+    report_to: Option<String>,
+    /// Name this receiver reports as [default: the host name]
+    #[arg(long, env = "UDP_BCAST_TS_SITE", requires = "report_to", value_parser = aggregate::parse_site, value_name = "NAME")]
+    site: Option<String>,
+    /// Send a report every SECS seconds
+    #[arg(long, env = "UDP_BCAST_TS_REPORT_INTERVAL", requires = "report_to",
+          default_value_t = aggregate::DEFAULT_INTERVAL.as_secs(), value_parser = value_parser!(u64).range(1..), value_name = "SECS")]
+    report_interval: u64,
+    /// Let other sockets that set it too bind the same port (SO_REUSEADDR; Unix)
+    #[arg(long, env = "UDP_BCAST_TS_REUSE_ADDR", value_parser = BoolishValueParser::new())]
+    reuse_addr: bool,
+    /// Let other sockets that set it too bind the same port, sharing unicast load on Linux (SO_REUSEPORT; Unix)
+    #[arg(long, env = "UDP_BCAST_TS_REUSE_PORT", value_parser = BoolishValueParser::new())]
+    reuse_port: bool,
+    /// Request a receive socket buffer (SO_RCVBUF) of BYTES
+    #[arg(long, env = "UDP_BCAST_TS_SO_RCVBUF", value_parser = value_parser!(u32).range(1..=i32::MAX as i64), value_name = "BYTES")]
+    so_rcvbuf: Option<u32>,
+    /// Fit each sender's offset and clock skew over the last SECS seconds, reported with --stats-interval
+    #[arg(long, env = "UDP_BCAST_TS_SKEW_WINDOW", requires = "stats_interval", value_parser = value_parser!(u64).range(2..), value_name = "SECS")]
+    skew_window: Option<u64>,
+    /// Accept only payloads signed by one of the Ed25519 public keys in DIR
+    #[arg(long, env = "UDP_BCAST_TS_VERIFY_KEYS", value_name = "DIR")]
+    verify_keys: Option<String>,
+    /// Show a live dashboard of senders instead of a line per packet
+    #[arg(long, conflicts_with = "stats_interval")]
+    tui: bool,
+    /// Serve a live web dashboard of senders' offsets and loss on ADDR, e.g. 0.0.0.0:8080
+    #[arg(
+        long,
+        env = "UDP_BCAST_TS_WEB_ADDR",
+        value_name = "ADDR",
+        conflicts_with = "observe_only"
+    )]
+    web_addr: Option<SocketAddr>,
+    /// Hand every accepted timestamp to local consumers as JSON, at unix:PATH, fifo:PATH or zmq:tcp://ADDR:PORT; may be repeated
+    #[arg(long, value_parser = publish::Target::parse, value_name = "TARGET")]
+    publish: Vec<publish::Target>,
+    /// Write each accepted timestamp to stdout as one JSON object per line (FORMAT: ndjson), moving log lines to stderr
+    #[arg(long, env = "UDP_BCAST_TS_STREAM", value_parser = publish::StreamFormat::parse, value_name = "FORMAT",
+          conflicts_with_all = ["stats_interval", "tui"])]
+    stream: Option<publish::StreamFormat>,
+    /// Publish each timestamp to NTP shared memory segment N, as a refclock for chrony or ntpd
+    #[arg(long, env = "UDP_BCAST_TS_SHM_SEGMENT", value_name = "N")]
+    shm_segment: Option<u8>,
+    /// Measure offsets from the kernel's receive time, or from when this process reads each datagram
+    #[arg(long, env = "UDP_BCAST_TS_RX_TIMESTAMP", default_value = "kernel", value_parser = listen::RxTimestamp::parse, value_name = "SOURCE")]
+    rx_timestamp: listen::RxTimestamp,
+    /// udp binds --port as usual; tcp connects to the sender at --addr:--port instead
+    #[arg(long, env = "UDP_BCAST_TS_TRANSPORT", default_value = "udp", value_parser = transport::Transport::parse,
+          value_name = "TRANSPORT",
+          conflicts_with_all = ["ack", "reuse_addr", "reuse_port", "discover", "so_rcvbuf"])]
+    transport: transport::Transport,
+    /// Slew the local clock toward the sender at IP (Linux; needs CAP_SYS_TIME)
+    #[arg(long, env = "UDP_BCAST_TS_DISCIPLINE", value_name = "IP",
+          conflicts_with_all = ["observe_only", "shm_segment", "user"])]
+    discipline: Option<IpAddr>,
+    /// Refuse to slew away offsets larger than MS milliseconds
+    #[arg(long, env = "UDP_BCAST_TS_DISCIPLINE_MAX_OFFSET_MS", requires = "discipline",
+          default_value_t = 128, value_parser = value_parser!(u64).range(1..=500), value_name = "MS")]
+    discipline_max_offset_ms: u64,
+    /// Adjust the clock at most once every SECS seconds
+    #[arg(long, env = "UDP_BCAST_TS_DISCIPLINE_INTERVAL", requires = "discipline",
+          default_value_t = 16, value_parser = value_parser!(u64).range(4..=3600), value_name = "SECS")]
+    discipline_interval: u64,
+    /// Switch to this user, and optionally group, once the socket is bound (Unix; needs root)
+    #[arg(long, env = "UDP_BCAST_TS_USER", value_parser = privs::UserSpec::parse, value_name = "USER[:GROUP]")]
+    user: Option<privs::UserSpec>,
+    /// Allow only the syscalls the receive loop makes once running; hooks can't run (Linux)
+    #[arg(long, env = "UDP_BCAST_TS_SECCOMP", value_parser = BoolishValueParser::new(),
+          conflicts_with_all = ["on_alert", "annotate_cmd"])]
+    seccomp: bool,
+}
+
+#[derive(Args)]
+struct MultiArgs {
+    /// Instances file: a table of send options per [instances.<name>], after options they share
+    #[arg(value_name = "FILE")]
+    file: PathBuf,
+}
+
+#[derive(Args)]
+struct SinkArgs {
+    /// First port; instance i binds PORT + i
+    #[arg(long, env = "UDP_BCAST_TS_PORT", value_parser = value_parser!(u16).range(1..))]
+    port: u16,
+    /// Number of in-process receivers
+    #[arg(long, value_parser = value_parser!(u16).range(1..), value_name = "N")]
+    instances: u16,
+    /// Address to bind to [default: 0.0.0.0]
+    #[arg(long, value_name = "BIND")]
+    addr: Option<IpAddr>,
+    /// Print aggregate rates every SECS seconds
+    #[arg(long, default_value_t = 1, value_parser = value_parser!(u64).range(1..), value_name = "SECS")]
+    stats_interval: u64,
 }
 
-/// Parses a string as a u16 port number (1-65535).
-fn parse_u16(s: &str, flag: &str) -> Result<u16, String> {
-    let v: u32 = s
-        .parse()
-        .map_err(|_| format!("Invalid value for {flag}: {s}"))?;
-    if v == 0 || v > 65535 {
-        return Err(format!("Port out of range for {flag}: {v}"));
+#[derive(Args)]
+struct AggregateArgs {
+    /// Port to receive reports on
+    #[arg(long, env = "UDP_BCAST_TS_PORT", value_parser = value_parser!(u16).range(1..))]
+    port: u16,
+    /// Address to bind to [default: 0.0.0.0]
+    #[arg(long, value_name = "BIND")]
+    addr: Option<IpAddr>,
+    /// Print the fleet view every SECS seconds
+    #[arg(long, env = "UDP_BCAST_TS_STATS_INTERVAL", default_value_t = 10, value_parser = value_parser!(u64).range(1..), value_name = "SECS")]
+    stats_interval: u64,
+    /// Rows in each worst-offender list
+    #[arg(
+        long,
+        env = "UDP_BCAST_TS_WORST",
+        default_value_t = 5,
+        value_name = "N"
+    )]
+    worst: usize,
+}
+
+#[derive(Args)]
+struct AnalyzeArgs {
+    /// Drift log written by recv --drift-log
+    #[arg(long, value_name = "FILE")]
+    drift_log: String,
+    /// Forecast when each sender's offset will exceed --offset-bound-ms
+    #[arg(long, requires = "offset_bound_ms")]
+    forecast: bool,
+    /// Offset bound to forecast against, in milliseconds
+    #[arg(long, requires = "forecast", value_parser = value_parser!(u64).range(1..), value_name = "MS")]
+    offset_bound_ms: Option<u64>,
+}
+
+#[derive(Args)]
+struct ReplayArgs {
+    /// Recording written by recv --record
+    #[arg(value_name = "FILE")]
+    file: String,
+    /// Destination: IPv4 or IPv6 address, or a hostname
+    #[arg(long, env = "UDP_BCAST_TS_ADDR", value_name = "IP-OR-HOST", value_parser = Target::parse)]
+    addr: Target,
+    /// Destination port
+    #[arg(long, env = "UDP_BCAST_TS_PORT", value_parser = value_parser!(u16).range(1..))]
+    port: u16,
+}
+
+#[derive(Args)]
+struct RelayArgs {
+    /// Port to receive timestamps on
+    #[arg(long, value_parser = value_parser!(u16).range(1..))]
+    listen_port: u16,
+    /// Unicast address to bind to, or multicast group to join, with optional %zone [default: 0.0.0.0]
+    #[arg(long, value_name = "BIND-OR-GROUP", value_parser = resolve::parse_ip)]
+    listen_addr: Option<(IpAddr, u32)>,
+    /// Destination: IPv4 or IPv6 broadcast/multicast address (link-local needs %zone), or a hostname
+    #[arg(long, value_name = "IP-OR-HOST", value_parser = Target::parse)]
+    addr: Target,
+    /// Destination port
+    #[arg(long, value_parser = value_parser!(u16).range(1..))]
+    port: u16,
+    /// Stamp relayed v2 payloads with this relay id and a hop count
+    #[arg(long, value_name = "ID")]
+    relay_id: Option<u16>,
+    /// Drop payloads that have already passed through N relays
+    #[arg(long, default_value_t = relay::DEFAULT_MAX_HOPS,
+          value_parser = value_parser!(u8).range(1..), value_name = "N")]
+    max_hops: u8,
+}
+
+#[derive(Args)]
+#[command(group(clap::ArgGroup::new("when").required(true).args(["at", "in_secs"])))]
+struct ControlArgs {
+    /// Destination: the followers' broadcast/multicast address, or a hostname
+    #[arg(long, env = "UDP_BCAST_TS_ADDR", value_name = "IP-OR-HOST", value_parser = Target::parse)]
+    addr: Target,
+    /// The followers' --follow port
+    #[arg(long, value_parser = value_parser!(u16).range(1..))]
+    port: u16,
+    /// Key file to authenticate the command with
+    #[arg(long, value_name = "KEYFILE")]
+    key: String,
+    /// Only address followers sending with this --group
+    #[arg(long, value_name = "ID")]
+    group: Option<u16>,
+    /// New send interval, in milliseconds
+    #[arg(long, value_parser = value_parser!(u64).range(1..), value_name = "MS")]
+    interval_ms: u64,
+    /// When to switch, in milliseconds since the Unix epoch
+    #[arg(long, value_name = "UNIX_MS")]
+    at: Option<u64>,
+    /// When to switch, in seconds from now
+    #[arg(long = "in", value_name = "SECS")]
+    in_secs: Option<u64>,
+    /// Copies to send, to ride out packet loss
+    #[arg(long, default_value_t = control::DEFAULT_REPEAT,
+          value_parser = value_parser!(u32).range(1..), value_name = "N")]
+    repeat: u32,
+}
+
+#[derive(Args)]
+struct CheckArgs {
+    /// NTP server to query, as HOST or HOST:PORT
+    #[arg(long, env = "UDP_BCAST_TS_NTP_SERVER", value_name = "HOST[:PORT]")]
+    server: String,
+    /// Fail when the local clock is further than this from the server's, in milliseconds
+    #[arg(long, default_value_t = sntp::DEFAULT_MAX_OFFSET_MS, value_name = "MS")]
+    max_offset_ms: u64,
+    /// How long to wait for the reply, in milliseconds
+    #[arg(long, default_value_t = 2000, value_parser = value_parser!(u64).range(1..), value_name = "MS")]
+    timeout_ms: u64,
+}
+
+#[derive(Args)]
+struct SelftestArgs {
+    /// Loopback address to run over, e.g. ::1
+    #[arg(long, default_value = "127.0.0.1", value_name = "IP")]
+    addr: IpAddr,
+}
+
+#[derive(Args)]
+struct InterfacesArgs {
+    /// Print a JSON array instead of a table
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct GenerateArgs {
+    #[command(subcommand)]
+    what: Generate,
+}
+
+#[derive(Subcommand)]
+enum Generate {
+    /// Print a completion script for SHELL: bash, zsh or fish
+    Completions {
+        #[arg(value_parser = generate::SHELLS)]
+        shell: String,
+    },
+    /// Print JSON Schema for the instances file `multi` reads
+    ConfigSchema,
+}
+
+#[derive(Args)]
+struct TimingReportArgs {
+    /// How long to run the send loop, in seconds
+    #[arg(long, default_value_t = 10, value_parser = value_parser!(u64).range(1..),
+          value_name = "SECS")]
+    duration_secs: u64,
+    /// Print the report as JSON
+    #[arg(long)]
+    json: bool,
+    /// Send options to time, as for `send` (e.g. --interval-ms 1 --sched fifo:50); --addr and --port may be left out
+    #[arg(
+        trailing_var_arg = true,
+        allow_hyphen_values = true,
+        value_name = "SEND-OPTIONS"
+    )]
+    send: Vec<String>,
+}
+
+#[derive(Args)]
+struct BenchArgs {
+    #[command(subcommand)]
+    role: BenchRole,
+}
+
+/// Which end of the benchmark this host is.
+#[derive(Subcommand)]
+enum BenchRole {
+    /// Answer probes from `bench run` on the far host
+    Reflect {
+        /// Port to listen on
+        #[arg(long, value_parser = value_parser!(u16).range(1..))]
+        port: u16,
+        /// Address to bind to [default: 0.0.0.0]
+        #[arg(long, value_name = "BIND")]
+        addr: Option<IpAddr>,
+    },
+    /// Send probes to a reflector and report what came back
+    Run {
+        /// The reflector's address, or a hostname
+        #[arg(long, value_name = "IP-OR-HOST", value_parser = Target::parse)]
+        addr: Target,
+        /// The reflector's port
+        #[arg(long, value_parser = value_parser!(u16).range(1..))]
+        port: u16,
+        /// Probes per second (fractions allowed)
+        #[arg(long, default_value = "100", value_parser = parse_rate, value_name = "PPS")]
+        rate: f64,
+        /// How long to send probes, in seconds
+        #[arg(long, default_value_t = 10, value_parser = value_parser!(u64).range(1..),
+              value_name = "SECS")]
+        duration_secs: u64,
+        /// Probe size, in bytes
+        #[arg(long, default_value_t = 64,
+              value_parser = value_parser!(u16).range(bench::MIN_SIZE as i64..=bench::MAX_SIZE as i64),
+              value_name = "BYTES")]
+        size: u16,
+        /// Also write the report to FILE as JSON
+        #[arg(long, value_name = "FILE")]
+        json: Option<String>,
+    },
+}
+
+#[derive(Args)]
+struct ConformanceArgs {
+    #[command(subcommand)]
+    dut: Dut,
+}
+
+/// The role of the implementation under test.
+#[derive(Subcommand)]
+enum Dut {
+    /// Send scripted datagrams to a receiver, which must ack the valid ones
+    Receiver {
+        /// The receiver's address, or a hostname
+        #[arg(long, value_name = "IP-OR-HOST", value_parser = Target::parse)]
+        addr: Target,
+        /// The receiver's port
+        #[arg(long, value_parser = value_parser!(u16).range(1..))]
+        port: u16,
+        /// Group the receiver accepts, and the key file it requires
+        #[arg(long, value_parser = parse_group, value_name = "ID[:KEYFILE]")]
+        group: Option<GroupArg>,
+        /// How long to wait for acks after the last datagram, in milliseconds
+        #[arg(long, default_value_t = 1000, value_name = "MS")]
+        timeout_ms: u64,
+    },
+    /// Listen to a sender and check what it transmits
+    Sender {
+        /// Port the sender sends to
+        #[arg(long, value_parser = value_parser!(u16).range(1..))]
+        port: u16,
+        /// Address to bind to, or multicast group to join [default: 0.0.0.0]
+        #[arg(long, value_name = "BIND-OR-GROUP")]
+        addr: Option<IpAddr>,
+        /// Group the sender should tag, and the key file it should authenticate with
+        #[arg(long, value_parser = parse_group, value_name = "ID[:KEYFILE]")]
+        group: Option<GroupArg>,
+        /// How long to listen, in seconds
+        #[arg(long, default_value_t = 10, value_parser = value_parser!(u64).range(1..),
+              value_name = "SECS")]
+        duration_secs: u64,
+        /// Largest acceptable offset from the local clock, in milliseconds
+        #[arg(long, default_value_t = 1000, value_name = "MS")]
+        max_offset_ms: u64,
+    },
+}
+
+/// A `--group` value: a group id and an optional key file.
+#[derive(Clone)]
+struct GroupArg {
+    id: u16,
+    key_file: Option<String>,
+}
+
+/// Parses a `--group` value of the form `<id>[:<keyfile>]`.
+fn parse_group(s: &str) -> Result<GroupArg, String> {
+    let (id, key_file) = match s.split_once(':') {
+        Some((id, key)) if !key.is_empty() => (id, Some(key.to_string())),
+        Some(_) => return Err("missing key file".to_string()),
+        None => (s, None),
+    };
+    let id = id.parse().map_err(|_| format!("invalid group id {id}"))?;
+    Ok(GroupArg { id, key_file })
+}
+
+/// Parses a `--rate` value: packets per second, up to a million.
+fn parse_rate(s: &str) -> Result<f64, String> {
+    // At least one a day, so the time between sends is a Duration
+    match s.parse::<f64>() {
+        Ok(r) if (1.0 / 86_400.0..=1_000_000.0).contains(&r) => Ok(r),
+        _ => Err("expected packets per second, from 1/86400 (one a day) up to 1000000".to_string()),
     }
-    Ok(v as u16)
 }
 
-/// Parses a string as a u64 value.
-fn parse_u64(s: &str, flag: &str) -> Result<u64, String> {
-    s.parse()
-        .map_err(|_| format!("Invalid value for {flag}: {s}"))
+/// Parses a `--capture-window` value: seconds before and after, e.g. `10:5`.
+fn parse_capture_window(s: &str) -> Result<(Duration, Duration), String> {
+    let secs = |v: &str| match v.parse::<f64>() {
+        Ok(secs) if (0.0..=3600.0).contains(&secs) => Ok(Duration::from_secs_f64(secs)),
+        _ => Err(format!("expected seconds from 0 to 3600, got '{v}'")),
+    };
+    let (pre, post) = s
+        .split_once(':')
+        .ok_or_else(|| "expected PRE:POST in seconds".to_string())?;
+    Ok((secs(pre)?, secs(post)?))
 }
 
-/// Parses a string as an IP address (IPv4 or IPv6).
-fn parse_ip(s: &str, flag: &str) -> Result<IpAddr, String> {
-    s.parse()
-        .map_err(|_| format!("Invalid IP address for {flag}: {s}"))
+/// Parses a `--drop-probability` value.
+#[cfg(feature = "chaos")]
+fn parse_probability(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(p) if (0.0..=1.0).contains(&p) => Ok(p),
+        _ => Err("expected a probability from 0 to 1".to_string()),
+    }
 }
 
-/// Helper function to get the next argument value or return an error.
-fn get_arg_value(
-    it: &mut impl Iterator<Item = String>,
-    flag: &str,
-) -> Result<String, String> {
-    match it.next() {
-        Some(v) if !v.is_empty() => Ok(v),
-        _ => Err(format!("Missing value for {flag}")),
+/// Parses a `--sched` value, e.g. `fifo:50`, into the SCHED_FIFO priority.
+fn parse_sched(s: &str) -> Result<i32, String> {
+    match s.split_once(':') {
+        Some(("fifo", prio)) => match prio.parse::<i32>() {
+            Ok(p) if (1..=99).contains(&p) => Ok(p),
+            _ => Err(format!("expected a priority from 1 to 99, got '{prio}'")),
+        },
+        _ => Err("expected fifo:<1-99>".to_string()),
     }
 }
 
-/// Helper function to print an error and return exit code.
-fn error_exit(msg: &str, program: &str, code: u8) -> ExitCode {
-    eprintln!("{msg}\n{}", usage(program));
-    ExitCode::from(code)
+/// Loads the key files and directories named by `--group` options.
+fn load_groups(groups: Vec<GroupArg>) -> Result<Vec<listen::Group>, String> {
+    groups
+        .into_iter()
+        .map(|g| {
+            Ok(listen::Group {
+                id: g.id,
+                key: g.key_file.as_deref().map(auth::KeyRing::load).transpose()?,
+            })
+        })
+        .collect()
 }
 
-fn main() -> ExitCode {
-    let program = env::args().next().unwrap_or_else(|| "udp_bcast_ts".to_string());
+/// Parses a `--jitter` value, either milliseconds or a percentage of `interval`.
+fn parse_jitter(s: &str, flag: &str, interval: Duration) -> Result<Duration, String> {
+    let parse = |v: &str| {
+        v.parse::<u64>()
+            .map_err(|_| format!("Invalid value for {flag}: {s}"))
+    };
+    let jitter = match s.strip_suffix('%') {
+        Some(pct) => {
+            let pct = parse(pct)?;
+            if pct > 100 {
+                return Err(format!("{flag} must be at most 100%"));
+            }
+            interval * pct as u32 / 100
+        }
+        None => Duration::from_millis(parse(s.strip_suffix("ms").unwrap_or(s))?),
+    };
+    if jitter > interval {
+        return Err(format!("{flag} must not exceed the interval"));
+    }
+    Ok(jitter)
+}
 
-    let mut addr: Option<IpAddr> = None;
-    let mut port: Option<u16> = None;
-    let mut interval_ms: u64 = 1000;
+/// Names `upgrade_legacy_args` leaves alone as the first argument.
+const PASSTHROUGH: &[&str] = &[
+    "send",
+    "multi",
+    "recv",
+    "listen",
+    "sink",
+    "aggregate",
+    "analyze",
+    "replay",
+    "relay",
+    "control",
+    "conformance",
+    "check",
+    "bench",
+    "selftest",
+    "interfaces",
+    "generate",
+    "timing-report",
+    "help",
+    "-h",
+    "--help",
+    "-V",
+    "--version",
+];
 
-    let mut it = env::args().skip(1);
-    while let Some(arg) = it.next() {
+/// Counts the options for every subcommand given ahead of the
+/// subcommand, such as `-v` in `udp_bcast_ts -v send`.
+fn global_options(args: &[String]) -> usize {
+    let mut n = 0;
+    while let Some(arg) = args.get(n) {
         match arg.as_str() {
-            "--addr" => {
-                let v = match get_arg_value(&mut it, "--addr") {
-                    Ok(v) => v,
-                    Err(e) => return error_exit(&e, &program, EXIT_CODE_USAGE_ERROR),
-                };
-                match parse_ip(&v, "--addr") {
-                    Ok(ip) => addr = Some(ip),
-                    Err(e) => return error_exit(&e, &program, EXIT_CODE_USAGE_ERROR),
-                }
+            "--log-level" | "--error-format" | "--pid-file" | "--log-file" | "--log-max-size"
+            | "--log-rotate-secs" | "--log-keep" | "--log-format" | "--service"
+            | "--service-name" => n += 2,
+            "-q" | "--quiet" | "--verbose" | "--raw-numbers" | "--daemon" => n += 1,
+            a if [
+                "--log-level=",
+                "--error-format=",
+                "--pid-file=",
+                "--log-file=",
+                "--log-max-size=",
+                "--log-rotate-secs=",
+                "--log-keep=",
+                "--log-format=",
+                "--service=",
+                "--service-name=",
+            ]
+            .iter()
+            .any(|p| a.starts_with(p)) =>
+            {
+                n += 1
             }
-            "--port" => {
-                let v = match get_arg_value(&mut it, "--port") {
-                    Ok(v) => v,
-                    Err(e) => return error_exit(&e, &program, EXIT_CODE_USAGE_ERROR),
-                };
-                match parse_u16(&v, "--port") {
-                    Ok(p) => port = Some(p),
-                    Err(e) => return error_exit(&e, &program, EXIT_CODE_USAGE_ERROR),
-                }
+            a if a.len() > 1 && a.starts_with('-') && a[1..].bytes().all(|b| b == b'v') => n += 1,
+            _ => break,
+        }
+    }
+    n
+}
+
+/// Rewrites a flag-only invocation from before subcommands existed
+/// (`--listen`, `--observe-only`, `--mode <mode>`, `--replay <file>`, or
+/// plain send flags) into the equivalent subcommand form.
+fn upgrade_legacy_args(args: Vec<String>) -> Vec<String> {
+    let mut args = args.into_iter();
+    let program = args.next().unwrap_or_else(|| "udp_bcast_ts".to_string());
+    let rest: Vec<String> = args.collect();
+    match rest.get(global_options(&rest)) {
+        None => return vec![program],
+        Some(first) if PASSTHROUGH.contains(&first.as_str()) => {
+            return std::iter::once(program).chain(rest).collect();
+        }
+        Some(_) => {}
+    }
+
+    let mut command = "send".to_string();
+    let mut positional = Vec::new();
+    let mut flags = Vec::new();
+    let mut it = rest.into_iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--listen" => command = "recv".to_string(),
+            "--observe-only" => {
+                command = "recv".to_string();
+                flags.push(arg);
             }
-            "--interval-ms" => {
-                let v = match get_arg_value(&mut it, "--interval-ms") {
-                    Ok(v) => v,
-                    Err(e) => return error_exit(&e, &program, EXIT_CODE_USAGE_ERROR),
-                };
-                match parse_u64(&v, "--interval-ms") {
-                    Ok(ms) if ms > 0 => interval_ms = ms,
-                    Ok(_) => {
-                        return error_exit(
-                            "--interval-ms must be > 0",
-                            &program,
-                            EXIT_CODE_USAGE_ERROR,
-                        );
-                    }
-                    Err(e) => return error_exit(&e, &program, EXIT_CODE_USAGE_ERROR),
+            "--mode" => match it.next() {
+                Some(m) => command = m,
+                None => flags.push(arg),
+            },
+            "--replay" => match it.next() {
+                Some(file) => {
+                    command = "replay".to_string();
+                    positional.push(file);
                 }
-            }
-            "-h" | "--help" => {
-                print!("{}", usage(&program));
-                return ExitCode::SUCCESS;
-            }
-            other => {
-                return error_exit(
-                    &format!("Unknown argument: {other}"),
-                    &program,
-                    EXIT_CODE_USAGE_ERROR,
-                );
-            }
+                None => flags.push(arg),
+            },
+            _ => flags.push(arg),
         }
     }
+    [program, command]
+        .into_iter()
+        .chain(positional)
+        .chain(flags)
+        .collect()
+}
+
+/// Reports a usage error clap can't detect on its own, with the usage
+/// line of `subcommand`.
+fn usage_error(subcommand: &str, kind: ErrorKind, msg: impl fmt::Display) -> ExitCode {
+    let mut cli = Cli::command();
+    cli.build();
+    let cmd = cli
+        .find_subcommand_mut(subcommand)
+        .expect("usage_error called with an unknown subcommand");
+    if exit::json() {
+        return exit::fail(Failure::new(EXIT_CODE_USAGE_ERROR, msg.to_string()));
+    }
+    let _ = cmd.error(kind, msg).print();
+    ExitCode::from(EXIT_CODE_USAGE_ERROR)
+}
+
+/// The `--error-format` given on the command line or in the environment,
+/// looked up before parsing so that a parse error can honor it.
+fn requested_error_format(args: &[String]) -> ErrorFormat {
+    let given = args.iter().enumerate().find_map(|(i, a)| match a.as_str() {
+        "--error-format" => args.get(i + 1).cloned(),
+        a => a.strip_prefix("--error-format=").map(str::to_string),
+    });
+    given
+        .or_else(|| env::var("UDP_BCAST_TS_ERROR_FORMAT").ok())
+        .and_then(|f| ErrorFormat::parse(&f).ok())
+        .unwrap_or(ErrorFormat::Text)
+}
+
+/// Lists the environment variables that set an option conflicting with one
+/// given on the command line. Clap checks conflicts without regard to
+/// where a value came from, so an environment default would otherwise
+/// turn an explicit option into an error instead of being overridden.
+fn overridden_env(args: &[String], is_set: impl Fn(&OsStr) -> bool) -> Vec<OsString> {
+    let cli = Cli::command();
+    let Some(cmd) = args
+        .get(1 + global_options(args.get(1..).unwrap_or_default()))
+        .and_then(|name| cli.find_subcommand(name))
+    else {
+        return Vec::new();
+    };
+    let given = |arg: &clap::Arg| {
+        arg.get_long().is_some_and(|long| {
+            let flag = format!("--{long}");
+            args.iter()
+                .any(|a| *a == flag || a.starts_with(&format!("{flag}=")))
+        })
+    };
+    let conflict = |a: &clap::Arg, b: &clap::Arg| {
+        cmd.get_arg_conflicts_with(a)
+            .iter()
+            .any(|c| c.get_id() == b.get_id())
+            || cmd
+                .get_arg_conflicts_with(b)
+                .iter()
+                .any(|c| c.get_id() == a.get_id())
+    };
+    cmd.get_arguments()
+        .filter(|arg| !given(arg))
+        .filter_map(|arg| Some((arg, arg.get_env().filter(|name| is_set(name))?)))
+        .filter(|(arg, _)| {
+            cmd.get_arguments()
+                .any(|other| given(other) && conflict(arg, other))
+        })
+        .map(|(_, name)| name.to_os_string())
+        .collect()
+}
 
-    let addr = match addr {
-        Some(a) => a,
-        None => return error_exit("Missing required --addr", &program, EXIT_CODE_USAGE_ERROR),
+fn main() -> ExitCode {
+    let args = upgrade_legacy_args(env::args().collect());
+    for name in overridden_env(&args, |name| env::var_os(name).is_some()) {
+        env::remove_var(name);
+    }
+    exit::set_format(requested_error_format(&args));
+    let cli = match Cli::try_parse_from(&args) {
+        Ok(cli) => cli,
+        // --help and --version aren't errors
+        Err(e) if exit::json() && e.use_stderr() => {
+            // Just the message, without the usage and help lines after it
+            let rendered = e.render().to_string();
+            let msg = rendered
+                .lines()
+                .take_while(|l| !l.is_empty())
+                .map(str::trim)
+                .collect::<Vec<_>>()
+                .join(" ");
+            let msg = msg.strip_prefix("error: ").unwrap_or(&msg);
+            return exit::fail(Failure::new(EXIT_CODE_USAGE_ERROR, msg));
+        }
+        Err(e) => e.exit(),
     };
-    let port = match port {
-        Some(p) => p,
-        None => return error_exit("Missing required --port", &program, EXIT_CODE_USAGE_ERROR),
+    exit::set_format(cli.error_format);
+    units::set_raw(cli.raw_numbers);
+    units::set_time_display(cli.display_format);
+    log::set_level(match (cli.quiet, cli.verbose) {
+        (true, _) => log::Level::Warn,
+        (false, 0) => cli.log_level,
+        (false, 1) => log::Level::Debug,
+        (false, _) => log::Level::Trace,
+    });
+    if cli.daemon && matches!(&cli.command, Command::Recv(r) if r.tui) {
+        return usage_error(
+            "recv",
+            ErrorKind::ArgumentConflict,
+            "--tui needs a terminal; it can't be used with --daemon",
+        );
+    }
+    if let Some(action) = cli.service.filter(|a| *a != service::Action::Run) {
+        return service::manage(&service::ServiceConfig {
+            action,
+            args: service::run_args(&args, &cli.service_name),
+            name: cli.service_name,
+            log_file: cli.log_file,
+        });
+    }
+    // Before any mode starts a thread, which a fork would leave behind
+    let _running = match daemon::start(&daemon::DaemonConfig {
+        detach: cli.daemon,
+        pid_file: cli.pid_file,
+        log_file: cli.log_file,
+        rotation: logfile::Rotation {
+            max_size: cli.log_max_size,
+            every: cli.log_rotate_secs.map(Duration::from_secs),
+            keep: cli.log_keep,
+        },
+        log_format: cli.log_format,
+    }) {
+        Ok(daemon::Role::Launcher(code)) => return ExitCode::from(code),
+        Ok(daemon::Role::Daemon(running)) => running,
+        Err(e) => return exit::fail(e),
     };
+    let command = cli.command;
+    match cli.service {
+        Some(service::Action::Run) => service::run(&cli.service_name, move || run_command(command)),
+        _ => run_command(command),
+    }
+}
+
+fn run_command(command: Command) -> ExitCode {
+    match command {
+        Command::Send(args) => run_send(args),
+        Command::Multi(args) => run_multi(args),
+        Command::Recv(args) => run_recv(args),
+        Command::Sink(args) => sink::run(&sink::SinkConfig {
+            addr: args.addr,
+            port: args.port,
+            instances: args.instances,
+            stats_interval: Duration::from_secs(args.stats_interval),
+        }),
+        Command::Aggregate(args) => aggregate::run(&aggregate::AggregateConfig {
+            addr: args.addr,
+            port: args.port,
+            print_interval: Duration::from_secs(args.stats_interval),
+            worst: args.worst,
+        }),
+        Command::Analyze(args) => drift::analyze(&drift::AnalyzeConfig {
+            drift_log: args.drift_log,
+            forecast_bound_ms: args.offset_bound_ms,
+        }),
+        Command::Replay(args) => record::replay(&args.file, &args.addr, args.port),
+        Command::Relay(args) => relay::run(&relay::RelayConfig {
+            listen_addr: args.listen_addr.map(|(ip, _)| ip),
+            scope_id: args.listen_addr.map_or(0, |(_, scope_id)| scope_id),
+            listen_port: args.listen_port,
+            target: args.addr,
+            port: args.port,
+            relay_id: args.relay_id,
+            max_hops: args.max_hops,
+        }),
+        Command::Control(args) => run_control(args),
+        Command::Conformance(args) => run_conformance(args),
+        Command::Bench(args) => match args.role {
+            BenchRole::Reflect { port, addr } => {
+                bench::reflect(&bench::ReflectConfig { addr, port })
+            }
+            BenchRole::Run {
+                addr,
+                port,
+                rate,
+                duration_secs,
+                size,
+                json,
+            } => bench::run(&bench::RunConfig {
+                target: addr,
+                port,
+                rate,
+                duration: Duration::from_secs(duration_secs),
+                size: usize::from(size),
+                json,
+            }),
+        },
+        Command::Selftest(args) => selftest::run(&selftest::SelftestConfig { addr: args.addr }),
+        Command::Interfaces(args) => iface::run(&iface::ListConfig { json: args.json }),
+        Command::Generate(args) => run_generate(args),
+        Command::TimingReport(args) => run_timing_report(args),
+        Command::Check(args) => sntp::run(&sntp::CheckConfig {
+            server: args.server,
+            timeout: Duration::from_millis(args.timeout_ms),
+            max_offset_ms: args.max_offset_ms,
+        }),
+    }
+}
 
-    // Bind to an ephemeral local port on the appropriate address family.
-    // (This avoids having to know the local interface address.)
-    let bind_addr = match addr {
-        IpAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
-        IpAddr::V6(_) => SocketAddr::new(IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED), 0),
+fn run_generate(args: GenerateArgs) -> ExitCode {
+    let out = match args.what {
+        Generate::Completions { shell } => {
+            let shell = generate::Shell::parse(&shell).expect("checked by clap");
+            let mut cmd = Cli::command();
+            cmd.build();
+            generate::completions(&cmd, shell)
+        }
+        Generate::ConfigSchema => {
+            let cmd = Cli::command();
+            let send = cmd.find_subcommand("send").expect("send is a subcommand");
+            // As run_multi refuses them
+            generate::config_schema(send, &["user", "seccomp"])
+        }
     };
+    print!("{out}");
+    ExitCode::SUCCESS
+}
 
-    let sock = match UdpSocket::bind(bind_addr) {
-        Ok(s) => s,
+fn run_timing_report(args: TimingReportArgs) -> ExitCode {
+    let mut argv = vec!["udp_bcast_ts".to_string(), "send".to_string()];
+    argv.extend(args.send.iter().cloned());
+    // Nothing is sent, so the destination is only there to satisfy send
+    let given = |name: &str| {
+        args.send
+            .iter()
+            .any(|a| a == name || a.starts_with(&format!("{name}=")))
+    };
+    if !given("--addr") && !given("--peers") && !given("--dest") {
+        argv.extend(["--addr".to_string(), "127.0.0.1".to_string()]);
+    }
+    if !given("--port") && !given("--dest") {
+        argv.extend(["--port".to_string(), "9".to_string()]);
+    }
+    let send = match Cli::try_parse_from(argv) {
+        Ok(Cli {
+            command: Command::Send(send),
+            ..
+        }) => send,
+        Ok(_) => unreachable!("parsed as send"),
         Err(e) => {
-            eprintln!("Failed to bind UDP socket on {bind_addr}: {e}");
-            return ExitCode::from(EXIT_CODE_RUNTIME_ERROR);
+            let _ = e.print();
+            return ExitCode::from(EXIT_CODE_USAGE_ERROR);
         }
     };
+    if !send.dest.is_empty() {
+        return usage_error(
+            "timing-report",
+            ErrorKind::ArgumentConflict,
+            "--dest runs a loop per destination; time one at a time with --interval-ms",
+        );
+    }
+    if send.rate.is_some() {
+        return usage_error(
+            "timing-report",
+            ErrorKind::ArgumentConflict,
+            "--rate has no per-tick deadline to time; use --interval-ms or --interval-us",
+        );
+    }
+    let cfg = match send_config(send) {
+        Ok(cfg) => cfg,
+        Err(code) => return code,
+    };
+    timing::run(
+        &cfg,
+        &timing::TimingConfig {
+            duration: Duration::from_secs(args.duration_secs),
+            json: args.json,
+        },
+    )
+}
 
-    if let Err(e) = sock.set_broadcast(true) {
-        eprintln!("Failed to enable broadcast: {e}");
-        return ExitCode::from(EXIT_CODE_RUNTIME_ERROR);
+fn run_conformance(args: ConformanceArgs) -> ExitCode {
+    let group = match &args.dut {
+        Dut::Receiver { group, .. } | Dut::Sender { group, .. } => group.clone(),
+    };
+    let group = match load_groups(group.into_iter().collect()) {
+        Ok(mut g) => g.pop(),
+        Err(e) => return exit::fail(e),
+    };
+    let (group, key) = (
+        group.as_ref().map(|g| g.id),
+        group.and_then(|g| g.key).map(|k| k.active().clone()),
+    );
+    match args.dut {
+        Dut::Receiver {
+            addr,
+            port,
+            timeout_ms,
+            ..
+        } => conformance::test_receiver(&conformance::ReceiverTest {
+            target: addr,
+            port,
+            group,
+            key,
+            timeout: Duration::from_millis(timeout_ms),
+        }),
+        Dut::Sender {
+            port,
+            addr,
+            duration_secs,
+            max_offset_ms,
+            ..
+        } => conformance::test_sender(&conformance::SenderTest {
+            addr,
+            port,
+            group,
+            key,
+            duration: Duration::from_secs(duration_secs),
+            max_offset_ms,
+        }),
     }
+}
 
-    let dest = SocketAddr::new(addr, port);
-    let interval = Duration::from_millis(interval_ms);
+fn run_control(args: ControlArgs) -> ExitCode {
+    let key = match auth::Key::load(&args.key) {
+        Ok(k) => k,
+        Err(e) => return exit::fail(e),
+    };
+    let apply_at_ms = match (args.at, args.in_secs) {
+        (Some(at), _) => at,
+        (None, secs) => {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default();
+            (now + Duration::from_secs(secs.unwrap_or(0))).as_millis() as u64
+        }
+    };
+    control::run(&control::ControlConfig {
+        target: args.addr,
+        port: args.port,
+        group: args.group,
+        key,
+        control: payload::Control {
+            apply_at_ms,
+            interval_ms: args.interval_ms,
+        },
+        repeat: args.repeat,
+    })
+}
 
-    loop {
-        // Get milliseconds since Unix epoch
-        let ts_ms: u64 = match SystemTime::now().duration_since(UNIX_EPOCH) {
-            Ok(d) => {
-                // Convert u128 to u64, checking for overflow
-                match d.as_millis().try_into() {
-                    Ok(ms) => ms,
-                    Err(_) => {
-                        eprintln!("Timestamp overflow: system time too large for u64");
-                        return ExitCode::from(EXIT_CODE_RUNTIME_ERROR);
-                    }
-                }
+fn run_send(args: SendArgs) -> ExitCode {
+    match send_config(args) {
+        Ok(cfg) => send::run(&cfg),
+        Err(code) => code,
+    }
+}
+
+/// Checks `send` arguments clap can't, and builds the sender's config
+/// from them; on a mistake, reports it and returns the exit code.
+fn send_config(args: SendArgs) -> Result<send::SendConfig, ExitCode> {
+    // Present unless --dest, which clap enforces
+    let port = match args.dest.first() {
+        Some(d) => d.port,
+        None => args.port.unwrap_or_default(),
+    };
+    let peer_list = match args.peers.as_deref().map(|p| peers::load(p, port)) {
+        Some(Ok(p)) => Some(p),
+        Some(Err(e)) => return Err(exit::fail(e)),
+        None => None,
+    };
+    // --peers takes over from an --addr that may come from the environment
+    let addr = match (&peer_list, args.dest.first(), args.addr) {
+        (Some(p), _, _) => Target::Ip(p[0].ip()),
+        (None, Some(d), _) => d.target.clone(),
+        (None, None, Some(addr)) => addr,
+        // Present unless --peers or --dest, which clap enforces
+        (None, None, None) => Target::Ip(IpAddr::V4(std::net::Ipv4Addr::BROADCAST)),
+    };
+    if args.group.is_some() && matches!(args.format, Format::Legacy | Format::Compact) {
+        return Err(usage_error(
+            "send",
+            ErrorKind::ArgumentConflict,
+            "--group requires --format v2 or proto",
+        ));
+    }
+    // Protobuf payloads have no room for a MAC
+    if args.format == Format::Proto && args.group.as_ref().is_some_and(|g| g.key_file.is_some()) {
+        return Err(usage_error(
+            "send",
+            ErrorKind::ArgumentConflict,
+            "a --group key file requires --format v2",
+        ));
+    }
+    if !args.field.is_empty() && matches!(args.format, Format::Legacy | Format::Compact) {
+        return Err(usage_error(
+            "send",
+            ErrorKind::ArgumentConflict,
+            "--field requires --format v2 or proto",
+        ));
+    }
+    if args.redundancy > 1 && args.format == Format::Legacy {
+        return Err(usage_error(
+            "send",
+            ErrorKind::ArgumentConflict,
+            "--redundancy requires --format v2, proto or compact",
+        ));
+    }
+    if args.full_every.is_some() && args.format != Format::Compact {
+        return Err(usage_error(
+            "send",
+            ErrorKind::ArgumentConflict,
+            "--full-every requires --format compact",
+        ));
+    }
+    if args.legacy_format.is_some() && args.format != Format::Legacy {
+        return Err(usage_error(
+            "send",
+            ErrorKind::ArgumentConflict,
+            "--legacy-format requires --format legacy",
+        ));
+    }
+    if let Some(layout) = args.legacy_format.filter(|l| !l.supports(args.epoch)) {
+        return Err(usage_error(
+            "send",
+            ErrorKind::ArgumentConflict,
+            format!(
+                "--legacy-format {} requires --epoch unix or gps",
+                layout.name
+            ),
+        ));
+    }
+    if args.sign_key.is_some() && args.format != Format::V2 {
+        return Err(usage_error(
+            "send",
+            ErrorKind::ArgumentConflict,
+            "--sign-key requires --format v2",
+        ));
+    }
+    if args.crc && args.format != Format::V2 {
+        return Err(usage_error(
+            "send",
+            ErrorKind::ArgumentConflict,
+            "--crc requires --format v2",
+        ));
+    }
+    if args.encrypt_key.is_some() && args.format != Format::V2 {
+        return Err(usage_error(
+            "send",
+            ErrorKind::ArgumentConflict,
+            "--encrypt-key requires --format v2",
+        ));
+    }
+    // A MAC catches corruption too
+    if args.crc && args.group.as_ref().is_some_and(|g| g.key_file.is_some()) {
+        return Err(usage_error(
+            "send",
+            ErrorKind::ArgumentConflict,
+            "--crc cannot be combined with a --group key file",
+        ));
+    }
+    if args.sign_key.is_some() && args.group.as_ref().is_some_and(|g| g.key_file.is_some()) {
+        return Err(usage_error(
+            "send",
+            ErrorKind::ArgumentConflict,
+            "--sign-key cannot be combined with a --group key file",
+        ));
+    }
+    if args.per_interface && !matches!(addr, Target::Ip(IpAddr::V4(ip)) if ip.is_broadcast()) {
+        return Err(usage_error(
+            "send",
+            ErrorKind::ArgumentConflict,
+            "--per-interface requires --addr 255.255.255.255",
+        ));
+    }
+    if args.dual_stack.is_some() && !matches!(addr, Target::Ip(IpAddr::V4(_))) {
+        return Err(usage_error(
+            "send",
+            ErrorKind::ArgumentConflict,
+            "--dual-stack requires an IPv4 --addr",
+        ));
+    }
+    if args.resolve_interval.is_some() && !addr.is_host() {
+        return Err(usage_error(
+            "send",
+            ErrorKind::ArgumentConflict,
+            "--resolve-interval requires a hostname --addr",
+        ));
+    }
+
+    // Bursts, copies and jitter must fit the shortest --dest interval
+    let interval = match (args.rate, args.dest.iter().map(|d| d.interval).min()) {
+        (Some(rate), _) => Duration::from_secs_f64(1.0 / rate),
+        (None, Some(shortest)) => shortest,
+        (None, None) => args
+            .interval
+            .or(args.interval_us.map(Duration::from_micros))
+            .unwrap_or(Duration::from_millis(args.interval_ms)),
+    };
+    let jitter = match args
+        .jitter
+        .map(|j| parse_jitter(&j, "--jitter", interval))
+        .transpose()
+    {
+        Ok(j) => j.unwrap_or(Duration::ZERO),
+        Err(e) => return Err(usage_error("send", ErrorKind::ValueValidation, e)),
+    };
+    let burst_spacing = Duration::from_micros(args.burst_spacing_us);
+    if burst_spacing
+        .checked_mul(args.burst - 1)
+        .is_none_or(|d| d >= interval)
+    {
+        return Err(usage_error(
+            "send",
+            ErrorKind::ValueValidation,
+            "--burst spacing must fit within the interval",
+        ));
+    }
+
+    let adaptive = args.adaptive.then(|| adaptive::AdaptiveConfig {
+        min: args.adaptive_min.unwrap_or(interval / 4),
+        max: args.adaptive_max.unwrap_or(interval * 4),
+    });
+    if let Some(a) = adaptive {
+        let checks = [
+            (
+                a.min > interval || a.max < interval,
+                "--adaptive-min and --adaptive-max must bound the interval",
+            ),
+            (
+                jitter >= a.min,
+                "--jitter must be shorter than --adaptive-min",
+            ),
+            (
+                burst_spacing * (args.burst - 1) >= a.min,
+                "--burst spacing must fit within --adaptive-min",
+            ),
+            (
+                args.format == Format::Legacy,
+                "--adaptive requires --format v2, proto or compact",
+            ),
+            (
+                args.transport == transport::Transport::Tcp,
+                "--adaptive requires --transport udp",
+            ),
+        ];
+        if let Some((_, msg)) = checks.into_iter().find(|(failed, _)| *failed) {
+            return Err(usage_error("send", ErrorKind::ArgumentConflict, msg));
+        }
+    }
+    let guard = match (args.exclusive, args.guard) {
+        (true, _) => Some(guard::Mode::Exclusive),
+        (false, true) => Some(guard::Mode::Warn),
+        (false, false) => None,
+    };
+    if guard.is_some() {
+        let checks = [
+            (
+                args.role != role::Role::Primary,
+                "--guard and --exclusive can't be used with --role follower or auto, which stand by for other senders",
+            ),
+            (
+                args.transport == transport::Transport::Tcp,
+                "--guard and --exclusive require --transport udp",
+            ),
+            (
+                args.follow == Some(port),
+                "--guard and --exclusive need --follow on a port other than --port",
+            ),
+        ];
+        if let Some((_, msg)) = checks.into_iter().find(|(failed, _)| *failed) {
+            return Err(usage_error("send", ErrorKind::ArgumentConflict, msg));
+        }
+    }
+    if args.once {
+        let checks = [
+            (
+                args.transport == transport::Transport::Tcp,
+                "--once requires --transport udp",
+            ),
+            (
+                args.wait_ack_ms.is_some() && args.format == Format::Legacy,
+                "--wait-ack-ms requires --format v2, proto or compact",
+            ),
+        ];
+        if let Some((_, msg)) = checks.into_iter().find(|(failed, _)| *failed) {
+            return Err(usage_error("send", ErrorKind::ArgumentConflict, msg));
+        }
+    }
+    if args.require_clock_sync == Some(send::SyncMode::Mark)
+        && !matches!(args.format, Format::V2 | Format::Proto)
+    {
+        return Err(usage_error(
+            "send",
+            ErrorKind::ArgumentConflict,
+            "--require-clock-sync mark requires --format v2 or proto",
+        ));
+    }
+    let txtime = args
+        .txtime
+        .then(|| Duration::from_micros(args.txtime_lead_us));
+    if txtime.is_some_and(|lead| lead >= interval) {
+        return Err(usage_error(
+            "send",
+            ErrorKind::ValueValidation,
+            "--txtime-lead-us must be shorter than the interval",
+        ));
+    }
+    if txtime.is_some() && args.transport == transport::Transport::Tcp {
+        return Err(usage_error(
+            "send",
+            ErrorKind::ArgumentConflict,
+            "--txtime requires --transport udp",
+        ));
+    }
+
+    let group = match load_groups(args.group.into_iter().collect()) {
+        Ok(mut g) => g.pop(),
+        Err(e) => return Err(exit::fail(e)),
+    };
+    let follow = match (args.follow, args.control_key) {
+        (Some(port), Some(path)) => match auth::Key::load(&path) {
+            Ok(key) => Some(control::FollowConfig { port, key }),
+            Err(e) => return Err(exit::fail(e)),
+        },
+        _ => None,
+    };
+
+    let encrypt_key = match args.encrypt_key.as_deref().map(aead::AeadKey::load) {
+        Some(Ok(key)) => Some(key),
+        Some(Err(e)) => return Err(exit::fail(e)),
+        None => None,
+    };
+    let sign_key = match args.sign_key.as_deref().map(ed25519::SigningKey::load) {
+        Some(Ok(key)) => {
+            log::info!(
+                "Signing payloads with Ed25519 key {}",
+                key.public().to_hex()
+            );
+            Some(key)
+        }
+        Some(Err(e)) => return Err(exit::fail(e)),
+        None => None,
+    };
+
+    let control_api = match (args.control_api, args.control_token_file) {
+        (Some(addr), Some(path)) => match api::Token::load(&path) {
+            Ok(token) => Some(api::ApiConfig { addr, token }),
+            Err(e) => return Err(exit::fail(e)),
+        },
+        _ => None,
+    };
+
+    let leap_smear = match args.leap_smear.as_deref().map(leap::Table::load) {
+        Some(Ok(table)) => {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default();
+            if table.expires.is_some_and(|e| e < now.as_secs()) {
+                log::warn!("Leap-second table has expired and may be missing a scheduled leap");
             }
+            Some(leap::Smear::new(&table, args.leap_smear_window * 1000))
+        }
+        Some(Err(e)) => return Err(exit::fail(e)),
+        None => None,
+    };
+
+    let otlp = match args
+        .otlp
+        .then(|| otlp::OtlpConfig::from_env(|k| std::env::var(k).ok()))
+    {
+        Some(Ok(None)) => {
+            log::info!("OTLP export disabled by the OTEL_* variables");
+            None
+        }
+        Some(Ok(cfg)) => cfg,
+        Some(Err(e)) => return Err(exit::fail(e)),
+        None => None,
+    };
+    let hooks = match args.script.as_deref().map(script::send_hooks).transpose() {
+        Ok(h) => h.map(Arc::new),
+        Err(e) => return Err(exit::fail(e)),
+    };
+
+    #[cfg(feature = "chaos")]
+    let chaos = (args.drop_probability.is_some() || args.delay_jitter_ms.is_some()).then(|| {
+        let chaos = chaos::ChaosConfig {
+            drop_probability: args.drop_probability.unwrap_or(0.0),
+            delay_jitter: Duration::from_millis(args.delay_jitter_ms.unwrap_or(0)),
+        };
+        log::warn!(
+            "Chaos: dropping {:.1}% of datagrams, delaying the rest by up to {}",
+            chaos.drop_probability * 100.0,
+            units::millis(chaos.delay_jitter.as_millis() as i64)
+        );
+        chaos
+    });
+
+    let cfg = send::SendConfig {
+        target: addr,
+        port,
+        transport: args.transport,
+        interval,
+        rate: args.rate,
+        adaptive,
+        jitter,
+        max_lateness: args.max_lateness_ms.map(Duration::from_millis),
+        tx_compensation: args.tx_compensation_us,
+        txtime,
+        burst: args.burst,
+        burst_spacing,
+        redundancy: args.redundancy,
+        redundancy_spacing: Duration::from_millis(args.redundancy_spacing_ms),
+        format: args.format,
+        full_every: args.full_every.unwrap_or(compact::DEFAULT_FULL_EVERY),
+        codec: args
+            .legacy_format
+            .map(|l| Arc::new(l) as Arc<dyn payload::PayloadCodec>),
+        hooks,
+        epoch: args.epoch,
+        resolve_interval: args.resolve_interval.map(Duration::from_secs),
+        group: group.as_ref().map(|g| g.id),
+        fields: args.field,
+        key: group.and_then(|g| g.key).map(|k| k.active().clone()),
+        sign_key,
+        crc: args.crc,
+        encrypt_key,
+        follow,
+        role: if args.standby {
+            role::Role::Follower
+        } else {
+            args.role
+        },
+        failover: args.failover_ms.map_or(interval * 3, Duration::from_millis),
+        guard,
+        once: args.once,
+        wait_ack: args.wait_ack_ms.map(Duration::from_millis),
+        priority: args.priority,
+        per_interface: args.per_interface,
+        statsd: args.statsd.map(|addr| statsd::StatsdConfig {
+            addr,
+            tags: args.statsd_tags,
+            instance: None,
+        }),
+        otlp,
+        mdns: args.mdns,
+        mdns_name: args.mdns_name,
+        leap_smear,
+        step_holddown: args.step_holddown_ms.map(Duration::from_millis),
+        require_clock_sync: args.require_clock_sync.map(|mode| send::ClockSync {
+            mode,
+            max_error: Duration::from_millis(args.max_clock_error_ms),
+        }),
+        sched_fifo: args.sched,
+        cpu_affinity: args.cpu_affinity,
+        hardening: privs::Hardening {
+            user: args.user,
+            seccomp: args.seccomp,
+        },
+        sndbuf: args.so_sndbuf,
+        send_timeout: args.send_timeout_ms.map(Duration::from_millis),
+        ttl: args.ttl,
+        allow_fragmentation: args.allow_fragmentation,
+        peers: peer_list,
+        dests: args.dest,
+        dual_stack: args.dual_stack,
+        io_queue: args.io_thread.then_some(args.io_queue as usize),
+        peer_backoff: args.peer_backoff.map(Duration::from_secs),
+        fanout_threads: args.fanout_threads.map(|n| n as usize),
+        status_addr: args.status_addr,
+        serve_queries: args.serve_queries,
+        control_api,
+        require_sync: args.require_sync.map(|server| sntp::CheckConfig {
+            server,
+            timeout: sntp::DEFAULT_TIMEOUT,
+            max_offset_ms: args
+                .max_clock_offset_ms
+                .unwrap_or(sntp::DEFAULT_MAX_OFFSET_MS),
+        }),
+        dry_run: args.dry_run,
+        start: start::StartConfig {
+            delay: args.start_delay.unwrap_or_default(),
+            random: args.start_random.unwrap_or_default(),
+            at: args.start_at,
+        },
+        snapshot_file: args.snapshot_file,
+        state_dir: args.state_dir,
+        blackout: args.blackout,
+        #[cfg(feature = "chaos")]
+        chaos,
+    };
+    if cfg.rate.is_none() && cfg.tick_span().is_none_or(|d| d >= interval) {
+        return Err(usage_error(
+            "send",
+            ErrorKind::ValueValidation,
+            "--redundancy copies must fit within the interval",
+        ));
+    }
+    Ok(cfg)
+}
+
+fn run_multi(args: MultiArgs) -> ExitCode {
+    let instances = match instances::load(&args.file) {
+        Ok(i) => i,
+        Err(e) => return exit::fail(Failure::new(EXIT_CODE_USAGE_ERROR, e)),
+    };
+    let mut cfgs = Vec::with_capacity(instances.len());
+    for instance in instances {
+        let name = instance.name;
+        let misconfigured = || log::error!("In instance {name} of {}", args.file.display());
+        // As the command line `udp_bcast_ts send <options>`
+        let argv = ["udp_bcast_ts".to_string(), "send".to_string()];
+        let send = match Cli::try_parse_from(argv.into_iter().chain(instance.args)) {
+            Ok(Cli {
+                command: Command::Send(send),
+                ..
+            }) => send,
+            Ok(_) => unreachable!("parsed as send"),
             Err(e) => {
-                eprintln!("System clock error (before UNIX_EPOCH): {e:?}");
-                return ExitCode::from(EXIT_CODE_RUNTIME_ERROR);
+                let _ = e.print();
+                misconfigured();
+                return ExitCode::from(EXIT_CODE_USAGE_ERROR);
             }
         };
+        // Both are for the whole process, which the instances share
+        if send.user.is_some() || send.seccomp {
+            misconfigured();
+            return usage_error(
+                "multi",
+                ErrorKind::ArgumentConflict,
+                "user and seccomp can't be set in an instances file",
+            );
+        }
+        let mut cfg = match send_config(send) {
+            Ok(cfg) => cfg,
+            Err(code) => {
+                misconfigured();
+                return code;
+            }
+        };
+        if let Some(statsd) = cfg.statsd.as_mut() {
+            statsd.instance = Some(name.clone());
+        }
+        cfgs.push((name, cfg));
+    }
+    instances::run(cfgs)
+}
 
-        // 8-byte big-endian u64, equivalent to struct.pack("!Q", ts_ms)
-        let payload = ts_ms.to_be_bytes();
+/// The options that would send something off the host under
+/// `--observe-only` and depend on a value, so clap can't refuse them.
+fn observe_only_conflict(args: &RecvArgs) -> Option<&'static str> {
+    if !args.observe_only {
+        return None;
+    }
+    if args.transport == transport::Transport::Tcp {
+        // Connecting to the sender is a transmission
+        return Some("--transport tcp can't be combined with --observe-only");
+    }
+    if args
+        .influx
+        .as_ref()
+        .is_some_and(|t| *t != influx::Target::Stdout)
+    {
+        return Some("--influx can only print to stdout (-) with --observe-only");
+    }
+    if args
+        .publish
+        .iter()
+        .any(|t| matches!(t, publish::Target::Zmq(_)))
+    {
+        return Some("--publish zmq: can't be combined with --observe-only");
+    }
+    None
+}
 
-        match sock.send_to(&payload, dest) {
-            Ok(_) => {
-                println!("Sent broadcast to {dest} ts_ms={ts_ms}");
-            }
+fn run_recv(args: RecvArgs) -> ExitCode {
+    if args.tui && !std::io::stdout().is_terminal() {
+        return exit::fail("--tui needs a terminal on stdout".to_string());
+    }
+    if args.stream.is_some() {
+        // Stdout is for the stream only
+        log::reserve_stdout();
+    }
+    if args.on_alert.is_some() && args.alert_offset_ms.is_none() && !args.strict {
+        return usage_error(
+            "recv",
+            ErrorKind::MissingRequiredArgument,
+            "--on-alert requires --alert-offset-ms or --strict",
+        );
+    }
+    if let Some(name) = args
+        .expect_sender
+        .iter()
+        .find(|e| matches!(e, watchdog::Expected::Key(_)))
+        .filter(|_| args.verify_keys.is_none())
+    {
+        return usage_error(
+            "recv",
+            ErrorKind::MissingRequiredArgument,
+            format!("--expect-sender {name} names a key, which requires --verify-keys"),
+        );
+    }
+    if args.transport == transport::Transport::Tcp && args.addr.is_none() {
+        return usage_error(
+            "recv",
+            ErrorKind::MissingRequiredArgument,
+            "--transport tcp requires --addr, the sender to connect to",
+        );
+    }
+    if let Some(msg) = observe_only_conflict(&args) {
+        return usage_error("recv", ErrorKind::ArgumentConflict, msg);
+    }
+    let membership_opts = !args.join_iface.is_empty() || args.rejoin_secs.is_some();
+    if membership_opts && args.transport == transport::Transport::Tcp {
+        return usage_error(
+            "recv",
+            ErrorKind::ArgumentConflict,
+            "--join-iface and --rejoin-secs can't be combined with --transport tcp",
+        );
+    }
+    if membership_opts
+        && args.discover.is_none()
+        && !args.addr.is_some_and(|(ip, _)| ip.is_multicast())
+    {
+        return usage_error(
+            "recv",
+            ErrorKind::MissingRequiredArgument,
+            "--join-iface and --rejoin-secs require a multicast --addr",
+        );
+    }
+    if let Some(layout) = args.legacy_format.filter(|l| !l.supports(args.epoch)) {
+        return usage_error(
+            "recv",
+            ErrorKind::ArgumentConflict,
+            format!(
+                "--legacy-format {} requires --epoch unix or gps",
+                layout.name
+            ),
+        );
+    }
+    let output = match args.output.as_deref() {
+        Some([format, path]) => match export::OutputFormat::parse(format) {
+            Ok(f) => Some((f, path.clone())),
             Err(e) => {
-                eprintln!("send_to({dest}) failed: {e}");
-                // Continue on send errors to allow recovery from transient network issues
+                return usage_error(
+                    "recv",
+                    ErrorKind::InvalidValue,
+                    format!("invalid value '{format}' for '--output <FORMAT> <FILE>': {e}"),
+                )
+            }
+        },
+        _ => None,
+    };
+    let influx = match args.influx {
+        Some(influx::Target::Stdout) if args.stream.is_some() => {
+            return usage_error(
+                "recv",
+                ErrorKind::ArgumentConflict,
+                "--influx - can't be combined with --stream, which writes to stdout too",
+            );
+        }
+        Some(influx::Target::Stdout) if args.stats_interval.is_some() => {
+            return usage_error(
+                "recv",
+                ErrorKind::ArgumentConflict,
+                "--influx - can't be combined with --stats-interval, which prints to stdout too",
+            );
+        }
+        Some(target)
+            if args.influx_token_file.is_some() && !matches!(target, influx::Target::Http(_)) =>
+        {
+            return usage_error(
+                "recv",
+                ErrorKind::ArgumentConflict,
+                "--influx-token-file requires an http:// --influx URL",
+            );
+        }
+        Some(target) => {
+            let token = match args
+                .influx_token_file
+                .as_deref()
+                .map(influx::InfluxConfig::load_token)
+            {
+                Some(Ok(t)) => Some(t),
+                Some(Err(e)) => return exit::fail(e),
+                None => None,
+            };
+            let node = match sys::hostname() {
+                Ok(n) => n,
+                Err(e) => return exit::fail(format!("Failed to read host name for --influx: {e}")),
+            };
+            if target == influx::Target::Stdout && log::enabled(log::Level::Info) {
+                // Stdout is for points only
+                log::set_level(log::Level::Warn);
+            }
+            Some(influx::InfluxConfig {
+                target,
+                token,
+                node,
+            })
+        }
+        None => None,
+    };
+    let mqtt = match args.mqtt {
+        Some(broker) => {
+            let password = match args
+                .mqtt_password_file
+                .as_deref()
+                .map(mqtt::MqttConfig::load_password)
+            {
+                Some(Ok(p)) => Some(p),
+                Some(Err(e)) => return exit::fail(e),
+                None => None,
+            };
+            let node = match sys::hostname() {
+                Ok(n) => n,
+                Err(e) => return exit::fail(format!("Failed to read host name for --mqtt: {e}")),
+            };
+            Some(mqtt::MqttConfig {
+                broker,
+                topic: args.topic,
+                client_id: format!("udp_bcast_ts-{node}-{}", std::process::id()),
+                user: args.mqtt_user,
+                password,
+            })
+        }
+        None => None,
+    };
+    let annotate = match (args.annotate_cmd, args.annotate_file) {
+        (Some(cmd), _) => Some(annotate::Source::Command(cmd)),
+        (None, Some(path)) => Some(annotate::Source::File(path)),
+        (None, None) => None,
+    };
+    let report = match (args.report_to, args.site) {
+        (Some(addr), Some(site)) => Some((addr, site)),
+        (Some(addr), None) => match sys::hostname() {
+            Ok(site) => Some((addr, site)),
+            Err(e) => return exit::fail(format!("Failed to read host name for --site: {e}")),
+        },
+        (None, _) => None,
+    }
+    .map(|(addr, site)| aggregate::ReportConfig {
+        addr,
+        site,
+        interval: Duration::from_secs(args.report_interval),
+    });
+    let groups = match load_groups(args.group) {
+        Ok(g) => g,
+        Err(e) => return exit::fail(e),
+    };
+    // Else anyone on the segment could steer the clock
+    if args.discipline.is_some()
+        && args.verify_keys.is_none()
+        && !groups.iter().any(|g| g.key.is_some())
+    {
+        return usage_error(
+            "recv",
+            ErrorKind::MissingRequiredArgument,
+            "--discipline requires a --group with a key, or --verify-keys",
+        );
+    }
+    let encrypt_key = match args.encrypt_key.as_deref().map(aead::AeadKey::load) {
+        Some(Ok(key)) => Some(key),
+        Some(Err(e)) => return exit::fail(e),
+        None => None,
+    };
+    let verify_keys = match args.verify_keys.as_deref().map(ed25519::KeyRing::load) {
+        Some(Ok(ring)) => {
+            log::info!(
+                "Verifying signatures against {} key(s) from {}",
+                ring.len(),
+                args.verify_keys.as_deref().unwrap_or_default()
+            );
+            Some(ring)
+        }
+        Some(Err(e)) => return exit::fail(e),
+        None => None,
+    };
+
+    let (addr, port, sources) = match &args.discover {
+        Some(instance) => {
+            let found = match mdns::browse(Duration::from_secs(args.discover_wait)) {
+                Ok(f) => f,
+                Err(e) => return exit::fail(e),
+            };
+            for beacon in &found {
+                log::info!("Discovered {}", beacon.summary());
+            }
+            match mdns::subscribe(&found, instance.as_deref()) {
+                Ok(sub) => (sub.mcast.map(|ip| (ip, 0)), sub.port, sub.sources),
+                Err(e) => return exit::fail(e),
             }
         }
+        // Present unless --discover, which clap enforces
+        None => (args.addr, args.port.unwrap_or_default(), Vec::new()),
+    };
 
-        sleep(interval);
+    listen::run(&listen::ListenConfig {
+        addr: addr.map(|(ip, _)| ip),
+        scope_id: addr.map_or(0, |(_, scope_id)| scope_id),
+        port,
+        join_on: membership::JoinOn::from_names(args.join_iface),
+        rejoin: args.rejoin_secs.map(Duration::from_secs),
+        observe_only: args.observe_only,
+        replay_window: args.replay_window,
+        groups,
+        sources,
+        source_filter: filter::SourceFilter {
+            allow: args.allow_source,
+            deny: args.deny_source,
+        },
+        stats_interval: args.stats_interval.map(Duration::from_secs),
+        record: args.record,
+        pcap: args.pcap,
+        drift_log: args.drift_log,
+        hdr_log: args
+            .hdr_log
+            .map(|path| (path, Duration::from_secs(args.hdr_interval))),
+        output,
+        store: args.store.map(|backend| store::StoreConfig {
+            backend,
+            aggregate_interval: Duration::from_secs(args.store_aggregate_interval),
+            retention: args
+                .store_retention_days
+                .map(|days| Duration::from_secs(days * 86_400)),
+        }),
+        influx,
+        mqtt,
+        annotate,
+        annotate_interval: Duration::from_secs(args.annotate_interval),
+        script: args.script,
+        ack: args.ack,
+        max_age_ms: args.max_age_ms,
+        registry: message::Registry::default(),
+        codec: args
+            .legacy_format
+            .map(|l| Arc::new(l) as Arc<dyn payload::PayloadCodec>),
+        alert: args.alert_offset_ms.map(|offset_ms| alert::AlertConfig {
+            offset_ms,
+            consecutive: args.alert_consecutive,
+            action: args
+                .on_alert
+                .clone()
+                .map_or(alert::Action::Exit, alert::Action::Command),
+        }),
+        capture: args
+            .capture_window
+            .map(|(pre, post)| capture::CaptureConfig {
+                pre,
+                post,
+                dir: args.capture_dir,
+            }),
+        statsd: args.statsd.map(|addr| statsd::StatsdConfig {
+            addr,
+            tags: args.statsd_tags,
+            instance: None,
+        }),
+        report,
+        rcvbuf: args.so_rcvbuf,
+        skew_window: args.skew_window.map(Duration::from_secs),
+        verify_keys,
+        encrypt_key,
+        tui: args.tui,
+        web_addr: args.web_addr,
+        publish: args.publish,
+        stream: args.stream,
+        shm_segment: args.shm_segment,
+        hardening: privs::Hardening {
+            user: args.user,
+            seccomp: args.seccomp,
+        },
+        epoch: args.epoch,
+        rx_timestamp: args.rx_timestamp,
+        transport: args.transport,
+        discipline: args.discipline.map(|source| discipline::DisciplineConfig {
+            source,
+            max_offset: Duration::from_millis(args.discipline_max_offset_ms),
+            interval: Duration::from_secs(args.discipline_interval),
+        }),
+        reuse: sys::Reuse {
+            addr: args.reuse_addr,
+            port: args.reuse_port,
+        },
+        expect: (!args.expect_sender.is_empty()).then(|| watchdog::ExpectConfig {
+            senders: args.expect_sender,
+            timeout: Duration::from_secs(args.expect_timeout),
+            strict: args.strict.then(|| {
+                args.on_alert
+                    .map_or(alert::Action::Exit, alert::Action::Command)
+            }),
+        }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upgrade(args: &[&str]) -> Vec<String> {
+        upgrade_legacy_args(args.iter().map(|s| s.to_string()).collect())
+    }
+
+    #[test]
+    fn cli_definition_is_consistent() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn legacy_flags_map_to_subcommands() {
+        assert_eq!(
+            upgrade(&["p", "--addr", "ff02::1", "--port", "1"]),
+            ["p", "send", "--addr", "ff02::1", "--port", "1"]
+        );
+        assert_eq!(
+            upgrade(&["p", "--port", "1", "--listen"]),
+            ["p", "recv", "--port", "1"]
+        );
+        assert_eq!(
+            upgrade(&["p", "--observe-only", "--port", "1"]),
+            ["p", "recv", "--observe-only", "--port", "1"]
+        );
+        assert_eq!(
+            upgrade(&["p", "--mode", "sink", "--port", "1"]),
+            ["p", "sink", "--port", "1"]
+        );
+        assert_eq!(
+            upgrade(&["p", "--port", "1", "--replay", "r.bin"]),
+            ["p", "replay", "r.bin", "--port", "1"]
+        );
+        assert_eq!(
+            upgrade(&["p", "-vv", "--log-level", "warn", "recv", "--port", "1"]),
+            ["p", "-vv", "--log-level", "warn", "recv", "--port", "1"]
+        );
+    }
+
+    #[test]
+    fn command_line_overrides_conflicting_env() {
+        let args: Vec<String> = ["p", "send", "--port", "1", "--interval-ms", "5"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let set = [
+            "UDP_BCAST_TS_RATE",
+            "UDP_BCAST_TS_TTL",
+            "UDP_BCAST_TS_INTERVAL_MS",
+        ];
+        assert_eq!(
+            overridden_env(&args, |name| set.iter().any(|s| name == *s)),
+            ["UDP_BCAST_TS_RATE"]
+        );
+    }
+
+    #[test]
+    fn subcommand_invocations_are_unchanged() {
+        assert_eq!(
+            upgrade(&["p", "recv", "--port", "1"]),
+            ["p", "recv", "--port", "1"]
+        );
+        assert_eq!(upgrade(&["p", "--help"]), ["p", "--help"]);
+    }
+
+    #[test]
+    fn observe_only_refuses_network_egress() {
+        let conflict = |extra: &[&str]| {
+            let argv = ["p", "recv", "--port", "1", "--observe-only"];
+            match Cli::try_parse_from(argv.iter().chain(extra))
+                .unwrap()
+                .command
+            {
+                Command::Recv(args) => observe_only_conflict(&args),
+                _ => unreachable!(),
+            }
+        };
+        assert_eq!(conflict(&[]), None);
+        assert_eq!(conflict(&["--influx", "-"]), None);
+        assert_eq!(conflict(&["--publish", "unix:/tmp/ts.sock"]), None);
+        assert!(conflict(&["--publish", "zmq:tcp://127.0.0.1:5556"]).is_some());
+        assert!(conflict(&["--influx", "udp://127.0.0.1:8089"]).is_some());
+    }
+
+    #[test]
+    fn rates_pace_within_a_day() {
+        assert_eq!(parse_rate("0.5"), Ok(0.5));
+        assert!(parse_rate("0.0000116").is_ok());
+        for bad in ["0", "1e-300", "-1", "1000001", "NaN", "inf"] {
+            assert!(parse_rate(bad).is_err(), "{bad}");
+        }
     }
 }
@@ -0,0 +1,654 @@
+//! DNS-SD advertisement of a running sender over multicast DNS.
+//!
+//! The beacon is published as an instance of `_udptimestamp._udp.local`,
+//! with a TXT record carrying its port, payload format and group, so
+//! receivers can find it with any DNS-SD browser (`avahi-browse`,
+//! `dns-sd -B`) instead of a hard-coded address. The responder announces
+//! the records twice at startup, then answers queries for the service
+//! type, the instance, the host, and DNS-SD service enumeration. It binds
+//! the mDNS port shared, so it runs alongside a system responder.
+//!
+//! Name conflicts are not probed for; give each sender on a link its own
+//! `--mdns-name` if they share a host name.
+//!
+//! [`browse`] is the receiving side: it asks for the service type and
+//! collects whatever beacons answer.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::iface;
+use crate::log;
+use crate::payload::Format;
+use crate::sys;
+use crate::transmit;
+
+pub const SERVICE: &str = "_udptimestamp._udp.local";
+const ENUMERATION: &str = "_services._dns-sd._udp.local";
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Tells caches to replace, not add to, records they hold for the name.
+const CACHE_FLUSH: u16 = 0x8000;
+
+/// TTL of records tied to the host (SRV, A), and of the rest.
+const HOST_TTL: u32 = 120;
+const OTHER_TTL: u32 = 4500;
+
+/// The same record set is multicast at most this often.
+const MIN_REPLY_GAP: Duration = Duration::from_secs(1);
+
+/// What gets advertised.
+#[derive(Clone, Debug)]
+pub struct Advert {
+    /// Instance name, the human-readable part before the service type.
+    pub instance: String,
+    /// Host name, without `.local`.
+    pub host: String,
+    pub addrs: Vec<Ipv4Addr>,
+    pub port: u16,
+    pub format: Format,
+    pub group: Option<u16>,
+    /// Multicast group the beacon is sent to, for receivers to join.
+    pub mcast: Option<IpAddr>,
+}
+
+impl Advert {
+    /// An advert for this host under `instance`, or `udp_bcast_ts on <host>`.
+    pub fn for_host(
+        instance: Option<String>,
+        port: u16,
+        format: Format,
+        group: Option<u16>,
+        mcast: Option<IpAddr>,
+    ) -> Result<Advert, String> {
+        let host = sys::hostname().map_err(|e| format!("Failed to read host name: {e}"))?;
+        let addrs = iface::ipv4_interfaces()
+            .map_err(|e| format!("Failed to list interfaces: {e}"))?
+            .into_iter()
+            .map(|i| i.addr)
+            .collect();
+        Ok(Advert {
+            instance: instance.unwrap_or_else(|| format!("udp_bcast_ts on {host}")),
+            host,
+            addrs,
+            port,
+            format,
+            group,
+            mcast,
+        })
+    }
+
+    fn instance_name(&self) -> String {
+        format!("{}.{SERVICE}", self.instance)
+    }
+
+    /// The instance name encoded with the instance as one label, since
+    /// it may contain dots.
+    fn instance_wire(&self) -> Vec<u8> {
+        let label = &self.instance.as_bytes()[..self.instance.len().min(63)];
+        let mut out = vec![label.len() as u8];
+        out.extend_from_slice(label);
+        out.extend_from_slice(&name(SERVICE));
+        out
+    }
+
+    fn host_name(&self) -> String {
+        format!("{}.local", self.host)
+    }
+
+    /// True if a question for `name` should be answered.
+    fn answers(&self, name: &str) -> bool {
+        [
+            SERVICE.to_string(),
+            ENUMERATION.to_string(),
+            self.instance_name(),
+            self.host_name(),
+        ]
+        .iter()
+        .any(|n| n.eq_ignore_ascii_case(name))
+    }
+
+    /// The TXT record strings.
+    fn txt(&self) -> Vec<String> {
+        let format = match self.format {
+            Format::Legacy => "legacy",
+            Format::V2 => "v2",
+            Format::Proto => "proto",
+            Format::Compact => "compact",
+        };
+        let mut txt = vec![
+            "txtvers=1".to_string(),
+            format!("port={}", self.port),
+            format!("format={format}"),
+        ];
+        if let Some(g) = self.group {
+            txt.push(format!("group={g}"));
+        }
+        if let Some(ip) = self.mcast {
+            txt.push(format!("addr={ip}"));
+        }
+        txt
+    }
+
+    /// Encodes a response carrying every record.
+    pub fn response(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(512);
+        let answers = 4 + self.addrs.len() as u16;
+        // ID 0; response, authoritative; no questions
+        for v in [0, 0x8400, 0, answers, 0, 0] {
+            out.extend_from_slice(&u16::to_be_bytes(v));
+        }
+
+        let instance = self.instance_wire();
+        record(
+            &mut out,
+            &name(ENUMERATION),
+            TYPE_PTR,
+            CLASS_IN,
+            OTHER_TTL,
+            &name(SERVICE),
+        );
+        record(
+            &mut out,
+            &name(SERVICE),
+            TYPE_PTR,
+            CLASS_IN,
+            OTHER_TTL,
+            &instance,
+        );
+
+        let mut srv = Vec::new();
+        // Priority and weight, unused with a single target
+        srv.extend_from_slice(&[0, 0, 0, 0]);
+        srv.extend_from_slice(&self.port.to_be_bytes());
+        srv.extend_from_slice(&name(&self.host_name()));
+        let unique = CLASS_IN | CACHE_FLUSH;
+        record(&mut out, &instance, TYPE_SRV, unique, HOST_TTL, &srv);
+
+        let mut txt = Vec::new();
+        for s in self.txt() {
+            txt.push(s.len() as u8);
+            txt.extend_from_slice(s.as_bytes());
+        }
+        record(&mut out, &instance, TYPE_TXT, unique, OTHER_TTL, &txt);
+
+        let host = name(&self.host_name());
+        for addr in &self.addrs {
+            record(&mut out, &host, TYPE_A, unique, HOST_TTL, &addr.octets());
+        }
+        out
+    }
+}
+
+/// Encodes a dot-separated domain name as uncompressed labels.
+fn name(n: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(n.len() + 2);
+    for label in n.split('.') {
+        // Labels are limited to 63 bytes
+        let label = &label.as_bytes()[..label.len().min(63)];
+        out.push(label.len() as u8);
+        out.extend_from_slice(label);
+    }
+    out.push(0);
+    out
+}
+
+fn record(out: &mut Vec<u8>, owner: &[u8], ty: u16, class: u16, ttl: u32, rdata: &[u8]) {
+    out.extend_from_slice(owner);
+    out.extend_from_slice(&ty.to_be_bytes());
+    out.extend_from_slice(&class.to_be_bytes());
+    out.extend_from_slice(&ttl.to_be_bytes());
+    out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    out.extend_from_slice(rdata);
+}
+
+/// Reads a possibly compressed name starting at `pos`, returning it and
+/// the position just after it.
+fn read_name(msg: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Bounds pointer chains, which could otherwise loop
+    for _ in 0..32 {
+        let len = *msg.get(pos)? as usize;
+        match len {
+            0 => {
+                return Some((labels.join("."), end.unwrap_or(pos + 1)));
+            }
+            l if l & 0xc0 == 0xc0 => {
+                let target = ((l & 0x3f) << 8) | *msg.get(pos + 1)? as usize;
+                end.get_or_insert(pos + 2);
+                pos = target;
+            }
+            l => {
+                let label = msg.get(pos + 1..pos + 1 + l)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                pos += 1 + l;
+            }
+        }
+    }
+    None
+}
+
+/// Returns the names asked about by a query, or nothing for a response.
+fn questions(msg: &[u8]) -> Vec<(String, u16)> {
+    let mut out = Vec::new();
+    if msg.len() < 12 || msg[2] & 0x80 != 0 {
+        return out;
+    }
+    let count = u16::from_be_bytes([msg[4], msg[5]]);
+    let mut pos = 12;
+    for _ in 0..count {
+        let Some((n, next)) = read_name(msg, pos) else {
+            break;
+        };
+        let Some(ty) = msg.get(next..next + 2) else {
+            break;
+        };
+        out.push((n, u16::from_be_bytes([ty[0], ty[1]])));
+        pos = next + 4;
+    }
+    out
+}
+
+/// Resource records of a message, as (owner, type, rdata position and
+/// length), skipping its questions.
+fn records(msg: &[u8]) -> Vec<(String, u16, usize, usize)> {
+    let mut out = Vec::new();
+    if msg.len() < 12 {
+        return out;
+    }
+    let count = |i: usize| u16::from_be_bytes([msg[i], msg[i + 1]]) as usize;
+    let mut pos = 12;
+    for _ in 0..count(4) {
+        let Some((_, next)) = read_name(msg, pos) else {
+            return out;
+        };
+        pos = next + 4;
+    }
+    for _ in 0..count(6) + count(8) + count(10) {
+        let Some((owner, next)) = read_name(msg, pos) else {
+            break;
+        };
+        let Some(fixed) = msg.get(next..next + 10) else {
+            break;
+        };
+        let ty = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let len = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        if msg.len() < next + 10 + len {
+            break;
+        }
+        out.push((owner, ty, next + 10, len));
+        pos = next + 10 + len;
+    }
+    out
+}
+
+/// A beacon found by [`browse`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Beacon {
+    pub instance: String,
+    pub host: String,
+    /// Addresses the beacon may send from: the host's A records, or the
+    /// responder's address without them.
+    pub addrs: Vec<IpAddr>,
+    pub port: u16,
+    pub format: Option<String>,
+    pub group: Option<u16>,
+    pub mcast: Option<IpAddr>,
+}
+
+impl Beacon {
+    /// One line describing the beacon, for listing.
+    pub fn summary(&self) -> String {
+        let addrs: Vec<String> = self.addrs.iter().map(IpAddr::to_string).collect();
+        let mut line = format!(
+            "\"{}\" host={} addrs={} port={}",
+            self.instance,
+            self.host,
+            addrs.join(","),
+            self.port
+        );
+        if let Some(f) = &self.format {
+            line.push_str(&format!(" format={f}"));
+        }
+        if let Some(g) = self.group {
+            line.push_str(&format!(" group={g}"));
+        }
+        if let Some(ip) = self.mcast {
+            line.push_str(&format!(" addr={ip}"));
+        }
+        line
+    }
+}
+
+/// What the responses heard while browsing said, by owner name.
+#[derive(Default)]
+struct Answers {
+    instances: BTreeSet<String>,
+    srv: BTreeMap<String, (u16, String)>,
+    txt: BTreeMap<String, Vec<String>>,
+    a: BTreeMap<String, Vec<IpAddr>>,
+    responder: BTreeMap<String, IpAddr>,
+}
+
+impl Answers {
+    fn add(&mut self, msg: &[u8], from: IpAddr) {
+        if msg.len() < 12 || msg[2] & 0x80 == 0 {
+            return;
+        }
+        for (owner, ty, at, len) in records(msg) {
+            let owner = owner.to_ascii_lowercase();
+            let rdata = &msg[at..at + len];
+            match ty {
+                TYPE_PTR if owner == SERVICE => {
+                    if let Some((instance, _)) = read_name(msg, at) {
+                        self.responder.insert(instance.to_ascii_lowercase(), from);
+                        self.instances.insert(instance);
+                    }
+                }
+                TYPE_SRV if len > 6 => {
+                    if let Some((target, _)) = read_name(msg, at + 6) {
+                        let port = u16::from_be_bytes([rdata[4], rdata[5]]);
+                        self.responder.entry(owner.clone()).or_insert(from);
+                        self.srv.insert(owner, (port, target.to_ascii_lowercase()));
+                    }
+                }
+                TYPE_TXT => {
+                    let mut strings = Vec::new();
+                    let mut rest = rdata;
+                    while let Some((&n, tail)) = rest.split_first() {
+                        let n = (n as usize).min(tail.len());
+                        strings.push(String::from_utf8_lossy(&tail[..n]).into_owned());
+                        rest = &tail[n..];
+                    }
+                    self.txt.insert(owner, strings);
+                }
+                TYPE_A if len == 4 => {
+                    let ip = IpAddr::V4(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]));
+                    let addrs = self.a.entry(owner).or_default();
+                    if !addrs.contains(&ip) {
+                        addrs.push(ip);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Beacons whose service and SRV records were both heard.
+    fn beacons(&self) -> Vec<Beacon> {
+        let suffix = format!(".{SERVICE}");
+        let mut out = Vec::new();
+        for full in &self.instances {
+            let key = full.to_ascii_lowercase();
+            let Some((port, target)) = self.srv.get(&key) else {
+                continue;
+            };
+            let instance = full
+                .get(..full.len().saturating_sub(suffix.len()))
+                .unwrap_or(full)
+                .to_string();
+            let txt = self.txt.get(&key).cloned().unwrap_or_default();
+            let value = |k: &str| {
+                txt.iter()
+                    .find_map(|s| s.strip_prefix(k)?.strip_prefix('='))
+                    .map(str::to_string)
+            };
+            let addrs = match self.a.get(target) {
+                Some(a) => a.clone(),
+                None => self.responder.get(&key).copied().into_iter().collect(),
+            };
+            out.push(Beacon {
+                instance,
+                host: target.clone(),
+                addrs,
+                port: *port,
+                format: value("format"),
+                group: value("group").and_then(|g| g.parse().ok()),
+                mcast: value("addr").and_then(|a| a.parse().ok()),
+            });
+        }
+        out
+    }
+}
+
+/// Where to listen for a set of discovered beacons.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Subscription {
+    pub port: u16,
+    /// Multicast group to join, if the beacons are multicast.
+    pub mcast: Option<IpAddr>,
+    /// Addresses the beacons send from.
+    pub sources: Vec<IpAddr>,
+}
+
+/// Picks the beacon named `instance`, or every beacon, and works out
+/// where to listen for it. Beacons heard together must share a port and
+/// multicast group, since a receiver has one socket.
+pub fn subscribe(found: &[Beacon], instance: Option<&str>) -> Result<Subscription, String> {
+    let chosen: Vec<&Beacon> = match instance {
+        Some(name) => found
+            .iter()
+            .filter(|b| b.instance.eq_ignore_ascii_case(name))
+            .collect(),
+        None => found.iter().collect(),
+    };
+    let Some(first) = chosen.first() else {
+        return Err(match instance {
+            Some(name) => format!("No beacon named \"{name}\" was discovered"),
+            None => "No beacons were discovered".to_string(),
+        });
+    };
+    if chosen.iter().any(|b| b.port != first.port) {
+        return Err(
+            "Discovered beacons use different ports; choose one with --discover <INSTANCE>"
+                .to_string(),
+        );
+    }
+    if chosen.iter().any(|b| b.mcast != first.mcast) {
+        return Err(
+            "Discovered beacons use different multicast groups; choose one with --discover <INSTANCE>"
+                .to_string(),
+        );
+    }
+    let mut sources = Vec::new();
+    for ip in chosen.iter().flat_map(|b| &b.addrs) {
+        if !sources.contains(ip) {
+            sources.push(*ip);
+        }
+    }
+    Ok(Subscription {
+        port: first.port,
+        mcast: first.mcast,
+        sources,
+    })
+}
+
+/// Joins the mDNS group on a shared socket.
+fn open() -> Result<UdpSocket, String> {
+    let sock = sys::bind_shared_v4(MDNS_PORT)
+        .map_err(|e| format!("Failed to bind mDNS port {MDNS_PORT}: {e}"))?;
+    sock.join_multicast_v4(&MDNS_GROUP, &Ipv4Addr::UNSPECIFIED)
+        .map_err(|e| format!("Failed to join mDNS group {MDNS_GROUP}: {e}"))?;
+    sock.set_multicast_ttl_v4(255)
+        .map_err(|e| format!("Failed to configure mDNS socket: {e}"))?;
+    Ok(sock)
+}
+
+/// Asks for beacons and collects the answers heard within `wait`. The
+/// query is repeated once a second, since mDNS is lossy.
+pub fn browse(wait: Duration) -> Result<Vec<Beacon>, String> {
+    let sock = open()?;
+    sock.set_read_timeout(Some(Duration::from_millis(100)))
+        .map_err(|e| format!("Failed to configure mDNS socket: {e}"))?;
+    let mut query = Vec::new();
+    // ID 0, standard query, one question
+    for v in [0, 0, 1, 0, 0, 0] {
+        query.extend_from_slice(&u16::to_be_bytes(v));
+    }
+    query.extend_from_slice(&name(SERVICE));
+    query.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    let dest = SocketAddr::new(IpAddr::V4(MDNS_GROUP), MDNS_PORT);
+    let deadline = Instant::now() + wait;
+    let mut next_query = Instant::now();
+    let mut answers = Answers::default();
+    let mut buf = [0u8; 9000];
+    while Instant::now() < deadline {
+        if Instant::now() >= next_query {
+            transmit::send_to(&sock, &query, dest)
+                .map_err(|e| format!("Failed to send mDNS query: {e}"))?;
+            next_query += MIN_REPLY_GAP;
+        }
+        match sock.recv_from(&mut buf) {
+            Ok((n, src)) => answers.add(&buf[..n], src.ip()),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(e) => return Err(format!("mDNS socket error: {e}")),
+        }
+    }
+    Ok(answers.beacons())
+}
+
+/// Starts advertising `advert` from a background thread.
+pub fn start(advert: Advert) -> Result<(), String> {
+    let sock = open()?;
+    log::info!(
+        "Advertising \"{}\" as {SERVICE} on port {}",
+        advert.instance,
+        advert.port
+    );
+    log::spawn(move || respond(sock, advert));
+    Ok(())
+}
+
+fn respond(sock: UdpSocket, advert: Advert) {
+    let dest = SocketAddr::new(IpAddr::V4(MDNS_GROUP), MDNS_PORT);
+    let response = advert.response();
+    let send = || {
+        if let Err(e) = transmit::send_to(&sock, &response, dest) {
+            log::warn!("Failed to send mDNS response: {e}");
+        }
+    };
+
+    // Two announcements a second apart, per RFC 6762
+    send();
+    thread::sleep(MIN_REPLY_GAP);
+    send();
+    let mut last = Instant::now();
+
+    let mut buf = [0u8; 1500];
+    loop {
+        let n = match sock.recv_from(&mut buf) {
+            Ok((n, _)) => n,
+            Err(e) => {
+                log::warn!("mDNS socket error: {e}");
+                thread::sleep(MIN_REPLY_GAP);
+                continue;
+            }
+        };
+        let asked = questions(&buf[..n]).iter().any(|(n, ty)| {
+            matches!(*ty, TYPE_PTR | TYPE_SRV | TYPE_TXT | TYPE_A | TYPE_ANY) && advert.answers(n)
+        });
+        if asked && last.elapsed() >= MIN_REPLY_GAP {
+            send();
+            last = Instant::now();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn advert() -> Advert {
+        Advert {
+            instance: "beacon".to_string(),
+            host: "pi".to_string(),
+            addrs: vec![Ipv4Addr::new(192, 168, 1, 20)],
+            port: 12321,
+            format: Format::V2,
+            group: Some(3),
+            mcast: None,
+        }
+    }
+
+    #[test]
+    fn response_carries_every_record() {
+        let r = advert().response();
+        assert_eq!(&r[..12], &[0, 0, 0x84, 0, 0, 0, 0, 5, 0, 0, 0, 0]);
+        let txt = b"\x09txtvers=1\x0aport=12321\x09format=v2\x07group=3";
+        assert!(r.windows(txt.len()).any(|w| w == txt));
+        assert!(r.ends_with(&[192, 168, 1, 20]));
+    }
+
+    #[test]
+    fn queries_are_matched_through_compressed_names() {
+        // A query for the service type, then one for the instance that
+        // points back at it
+        let mut q = vec![0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0];
+        q.extend_from_slice(&name(SERVICE));
+        q.extend_from_slice(&[0, 12, 0, 1]);
+        q.extend_from_slice(b"\x06beacon\xc0\x0c");
+        q.extend_from_slice(&[0, 33, 0, 1]);
+
+        let asked = questions(&q);
+        assert_eq!(
+            asked,
+            [
+                (SERVICE.to_string(), TYPE_PTR),
+                (format!("beacon.{SERVICE}"), TYPE_SRV)
+            ]
+        );
+        assert!(asked.iter().all(|(n, _)| advert().answers(n)));
+        assert!(!advert().answers("_http._tcp.local"));
+    }
+
+    #[test]
+    fn responses_are_not_questions() {
+        assert!(questions(&advert().response()).is_empty());
+    }
+
+    #[test]
+    fn browsing_reads_back_an_advert() {
+        let mut a = advert();
+        a.mcast = Some("239.1.2.3".parse().unwrap());
+        let mut answers = Answers::default();
+        answers.add(&a.response(), "192.168.1.99".parse().unwrap());
+        let found = answers.beacons();
+        assert_eq!(
+            found,
+            [Beacon {
+                instance: "beacon".to_string(),
+                host: "pi.local".to_string(),
+                addrs: vec!["192.168.1.20".parse().unwrap()],
+                port: 12321,
+                format: Some("v2".to_string()),
+                group: Some(3),
+                mcast: Some("239.1.2.3".parse().unwrap()),
+            }]
+        );
+
+        let sub = subscribe(&found, Some("BEACON")).unwrap();
+        assert_eq!(sub.port, 12321);
+        assert_eq!(sub.sources, found[0].addrs);
+        assert!(subscribe(&found, Some("other")).is_err());
+
+        let mut elsewhere = found[0].clone();
+        elsewhere.instance = "elsewhere".to_string();
+        elsewhere.port = 5000;
+        let both = [found[0].clone(), elsewhere];
+        assert!(subscribe(&both, None).is_err());
+        assert_eq!(subscribe(&both, Some("elsewhere")).unwrap().port, 5000);
+    }
+}
@@ -0,0 +1,439 @@
+//! Multicast group membership for `recv` (`--join-iface`, `--rejoin-secs`).
+//!
+//! A receiver that joins a group without naming an interface is a member
+//! on whichever one the kernel picks, usually the one of the default
+//! route; `--join-iface` joins on the interfaces named instead, or on
+//! every up multicast interface with `all`.
+//!
+//! Switches with IGMP/MLD snooping forward a group only to ports that
+//! reported membership, and forget a port after a few minutes without a
+//! report. Reports are sent when a querier asks, so on a segment without
+//! one delivery stops silently once the switch times out. `--rejoin-secs`
+//! leaves and at once rejoins on an interval, which makes the kernel send a
+//! fresh unsolicited report. On Linux the memberships are also renewed on
+//! every interface change, the notifications `send` watches, since an
+//! interface that went away took its memberships with it.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::exit::Failure;
+use crate::hotplug;
+use crate::iface::{self, Link};
+use crate::log;
+
+/// How often interface changes are looked for.
+const POLL: Duration = Duration::from_millis(500);
+
+/// Which interfaces to join the group on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JoinOn {
+    /// The one the kernel picks, or the `%zone` of an IPv6 group.
+    Default,
+    /// Every up interface that does multicast, other than loopback.
+    All,
+    Named(Vec<String>),
+}
+
+impl JoinOn {
+    /// From the `--join-iface` values.
+    pub fn from_names(names: Vec<String>) -> JoinOn {
+        if names.is_empty() {
+            JoinOn::Default
+        } else if names.iter().any(|n| n == "all") {
+            JoinOn::All
+        } else {
+            JoinOn::Named(names)
+        }
+    }
+}
+
+/// One membership: the interface, by name unless the kernel picks it, and
+/// what the socket call names it by.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Target {
+    iface: Option<String>,
+    via: Via,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Via {
+    /// An address of the interface.
+    V4(Ipv4Addr),
+    /// The interface index.
+    V6(u32),
+}
+
+struct Joined {
+    target: Target,
+    since: Instant,
+    /// Joins made, the first one included.
+    reports: u64,
+}
+
+/// The memberships to hold for `group`, and the named interfaces that
+/// can't hold one, with why.
+fn targets(
+    group: IpAddr,
+    scope_id: u32,
+    on: &JoinOn,
+    links: &[Link],
+) -> (Vec<Target>, Vec<(String, &'static str)>) {
+    let target = |link: &Link| -> Result<Target, &'static str> {
+        if !link.up {
+            return Err("down");
+        }
+        if !link.multicast {
+            return Err("no multicast");
+        }
+        let via = match group {
+            IpAddr::V4(_) => link
+                .addrs
+                .iter()
+                .find_map(|&(a, _)| match a {
+                    IpAddr::V4(v4) => Some(Via::V4(v4)),
+                    IpAddr::V6(_) => None,
+                })
+                .ok_or("no IPv4 address")?,
+            IpAddr::V6(_) => Via::V6(link.index.ok_or("no index")?),
+        };
+        Ok(Target {
+            iface: Some(link.name.clone()),
+            via,
+        })
+    };
+    match on {
+        JoinOn::Default => {
+            let via = match group {
+                IpAddr::V4(_) => Via::V4(Ipv4Addr::UNSPECIFIED),
+                IpAddr::V6(_) => Via::V6(scope_id),
+            };
+            (vec![Target { iface: None, via }], Vec::new())
+        }
+        JoinOn::All => (
+            links
+                .iter()
+                .filter(|l| !l.loopback)
+                .filter_map(|l| target(l).ok())
+                .collect(),
+            Vec::new(),
+        ),
+        JoinOn::Named(names) => {
+            let mut found = Vec::new();
+            let mut skipped = Vec::new();
+            for name in names {
+                match links.iter().find(|l| &l.name == name).ok_or("missing") {
+                    Ok(link) => match target(link) {
+                        Ok(t) => found.push(t),
+                        Err(why) => skipped.push((name.clone(), why)),
+                    },
+                    Err(why) => skipped.push((name.clone(), why)),
+                }
+            }
+            (found, skipped)
+        }
+    }
+}
+
+fn join(sock: &UdpSocket, group: IpAddr, via: Via) -> io::Result<()> {
+    let joined = match (group, via) {
+        (IpAddr::V4(g), Via::V4(addr)) => sock.join_multicast_v4(&g, &addr),
+        (IpAddr::V6(g), Via::V6(index)) => sock.join_multicast_v6(&g, index),
+        _ => unreachable!("the group and interface are of one family"),
+    };
+    match joined {
+        // Still a member from before a change it survived
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => Ok(()),
+        r => r,
+    }
+}
+
+fn leave(sock: &UdpSocket, group: IpAddr, via: Via) {
+    // Fails where the interface or its address went away, and the
+    // membership with it
+    let _ = match (group, via) {
+        (IpAddr::V4(g), Via::V4(addr)) => sock.leave_multicast_v4(&g, &addr),
+        (IpAddr::V6(g), Via::V6(index)) => sock.leave_multicast_v6(&g, index),
+        _ => unreachable!("the group and interface are of one family"),
+    };
+}
+
+fn iface_name(target: &Target) -> &str {
+    target.iface.as_deref().unwrap_or("default")
+}
+
+/// A receive socket's memberships of one group.
+pub struct Membership {
+    group: IpAddr,
+    scope_id: u32,
+    on: JoinOn,
+    rejoin: Option<Duration>,
+    next_rejoin: Option<Instant>,
+    monitor: Option<hotplug::Monitor>,
+    next_poll: Instant,
+    joined: Vec<Joined>,
+}
+
+impl Membership {
+    /// Joins `group` on the interfaces `on` names, failing if it can't be
+    /// joined on any. `scope_id` is the zone of an IPv6 group, or 0.
+    pub fn join(
+        sock: &UdpSocket,
+        group: IpAddr,
+        scope_id: u32,
+        on: JoinOn,
+        rejoin: Option<Duration>,
+        now: Instant,
+    ) -> Result<Membership, Failure> {
+        let links = match on {
+            JoinOn::Default => Vec::new(),
+            _ => iface::links()
+                .map_err(|e| Failure::io(&e, format!("Failed to list interfaces: {e}")))?,
+        };
+        let (wanted, skipped) = targets(group, scope_id, &on, &links);
+        for (name, why) in skipped {
+            log::warn!("Not joining multicast group {group} on {name}: {why}");
+        }
+        let mut joined = Vec::new();
+        let mut failed = None;
+        for target in wanted {
+            match join(sock, group, target.via) {
+                Ok(()) => {
+                    log::debug!("Joined multicast group {group} on {}", iface_name(&target));
+                    joined.push(Joined {
+                        target,
+                        since: now,
+                        reports: 1,
+                    });
+                }
+                Err(e) => {
+                    log::warn!(
+                        "Failed to join multicast group {group} on {}: {e}",
+                        iface_name(&target)
+                    );
+                    failed = Some(e);
+                }
+            }
+        }
+        if joined.is_empty() {
+            let msg = format!("Failed to join multicast group {group} on any interface");
+            return Err(match failed {
+                Some(e) => Failure::io(&e, format!("{msg}: {e}")),
+                None => msg.into(),
+            });
+        }
+        let monitor = match hotplug::Monitor::open() {
+            Ok(m) => {
+                log::debug!("Watching for interface changes");
+                Some(m)
+            }
+            Err(e) => {
+                log::debug!("Not watching for interface changes: {e}");
+                None
+            }
+        };
+        Ok(Membership {
+            group,
+            scope_id,
+            on,
+            rejoin,
+            next_rejoin: rejoin.map(|r| now + r),
+            monitor,
+            next_poll: now + POLL,
+            joined,
+        })
+    }
+
+    /// Whether [`Self::maintain`] has anything to do when no datagrams
+    /// arrive.
+    pub fn needs_wakeups(&self) -> bool {
+        self.rejoin.is_some() || self.monitor.is_some()
+    }
+
+    /// Renews the memberships after an interface change, and when
+    /// `--rejoin-secs` is due.
+    pub fn maintain(&mut self, sock: &UdpSocket, now: Instant) {
+        let mut renew = false;
+        if now >= self.next_poll {
+            self.next_poll = now + POLL;
+            if let Some(m) = self.monitor.as_mut() {
+                match m.poll() {
+                    Ok(changes) => {
+                        for c in &changes {
+                            log::info!("Interface change: {c}");
+                        }
+                        renew = !changes.is_empty();
+                    }
+                    Err(e) => {
+                        log::warn!("Stopped watching for interface changes: {e}");
+                        self.monitor = None;
+                    }
+                }
+            }
+        }
+        if let (Some(at), Some(every)) = (self.next_rejoin, self.rejoin) {
+            if now >= at {
+                self.next_rejoin = Some(now + every);
+                renew = true;
+            }
+        }
+        if renew {
+            self.renew(sock, now);
+        }
+    }
+
+    /// Leaves and rejoins on every interface that should hold a
+    /// membership now, joining new ones and dropping those gone.
+    fn renew(&mut self, sock: &UdpSocket, now: Instant) {
+        let links = match self.on {
+            JoinOn::Default => Vec::new(),
+            _ => match iface::links() {
+                Ok(l) => l,
+                Err(e) => {
+                    log::warn!("Multicast memberships not renewed: {e}");
+                    return;
+                }
+            },
+        };
+        let (wanted, skipped) = targets(self.group, self.scope_id, &self.on, &links);
+        for (name, why) in skipped {
+            log::debug!(
+                "Not joining multicast group {} on {name}: {why}",
+                self.group
+            );
+        }
+        let group = self.group;
+        self.joined.retain(|j| {
+            let kept = wanted.iter().any(|t| t.iface == j.target.iface);
+            if !kept {
+                leave(sock, group, j.target.via);
+                log::info!(
+                    "Multicast membership: group={group} iface={} left",
+                    iface_name(&j.target)
+                );
+            }
+            kept
+        });
+        for target in wanted {
+            let held = self
+                .joined
+                .iter()
+                .position(|j| j.target.iface == target.iface);
+            if let Some(i) = held {
+                leave(sock, group, self.joined[i].target.via);
+            }
+            match join(sock, group, target.via) {
+                Ok(()) => match held {
+                    Some(i) => {
+                        let j = &mut self.joined[i];
+                        j.target = target;
+                        j.reports += 1;
+                    }
+                    None => {
+                        log::info!(
+                            "Multicast membership: group={group} iface={} joined",
+                            iface_name(&target)
+                        );
+                        self.joined.push(Joined {
+                            target,
+                            since: now,
+                            reports: 1,
+                        });
+                    }
+                },
+                Err(e) => {
+                    log::warn!(
+                        "Failed to rejoin multicast group {group} on {}: {e}",
+                        iface_name(&target)
+                    );
+                    if let Some(i) = held {
+                        self.joined.remove(i);
+                    }
+                }
+            }
+        }
+        if self.joined.is_empty() {
+            log::warn!("Multicast group {group} not joined on any interface; nothing will arrive");
+        }
+    }
+
+    /// Prints one `Membership:` line per interface holding one, as of `now`.
+    pub fn print(&self, now: Instant) {
+        if self.joined.is_empty() {
+            println!("Membership: group={} iface=none", self.group);
+        }
+        for j in &self.joined {
+            println!(
+                "Membership: group={} iface={} joined_secs={} reports={}",
+                self.group,
+                iface_name(&j.target),
+                now.saturating_duration_since(j.since).as_secs(),
+                j.reports
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link(name: &str, index: u32, up: bool, addrs: &[&str]) -> Link {
+        Link {
+            name: name.to_string(),
+            index: Some(index),
+            up,
+            loopback: name == "lo",
+            broadcast: true,
+            multicast: true,
+            mtu: Some(1500),
+            addrs: addrs.iter().map(|a| (a.parse().unwrap(), 24)).collect(),
+        }
+    }
+
+    #[test]
+    fn picks_the_interfaces_that_can_hold_a_membership() {
+        let links = [
+            link("lo", 1, true, &["127.0.0.1"]),
+            link("eth0", 2, true, &["fe80::1", "192.0.2.10"]),
+            link("eth1", 3, false, &["198.51.100.10"]),
+            link("wg0", 4, true, &["fd00::4"]),
+        ];
+        let v4: IpAddr = "239.1.2.3".parse().unwrap();
+        let v6: IpAddr = "ff02::123".parse().unwrap();
+        let names =
+            |t: &[Target]| -> Vec<String> { t.iter().map(|t| iface_name(t).into()).collect() };
+
+        let (all, _) = targets(v4, 0, &JoinOn::All, &links);
+        assert_eq!(names(&all), ["eth0"]);
+        assert_eq!(all[0].via, Via::V4("192.0.2.10".parse().unwrap()));
+        let (all, _) = targets(v6, 0, &JoinOn::All, &links);
+        assert_eq!(names(&all), ["eth0", "wg0"]);
+        assert_eq!(all[1].via, Via::V6(4));
+
+        let on = JoinOn::from_names(vec!["eth1".into(), "eth0".into(), "usb0".into()]);
+        let (found, skipped) = targets(v4, 0, &on, &links);
+        assert_eq!(names(&found), ["eth0"]);
+        assert_eq!(
+            skipped,
+            [
+                ("eth1".to_string(), "down"),
+                ("usb0".to_string(), "missing")
+            ]
+        );
+        assert_eq!(
+            JoinOn::from_names(vec!["eth0".into(), "all".into()]),
+            JoinOn::All
+        );
+
+        let (default, _) = targets(v6, 7, &JoinOn::from_names(Vec::new()), &links);
+        assert_eq!(
+            default,
+            [Target {
+                iface: None,
+                via: Via::V6(7)
+            }]
+        );
+    }
+}
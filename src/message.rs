@@ -0,0 +1,152 @@
+//! Message types carried in byte 3 of the v2 header.
+//!
+//! ```text
+//! 0x00        data: a timestamp beacon
+//! 0x01        heartbeat: liveness, not a timing sample
+//! 0x02        control: a fleet command (see `control`)
+//! 0x03        ack
+//! 0x04        goodbye: the sender is shutting down
+//! 0x05        trigger: an event marker
+//! 0x06        query: asks a sender for the time (see `query`)
+//! 0x07        reply: a sender's answer to a query
+//! 0x08..0x7f  reserved for future protocol use
+//! 0x80..0xfe  user extensions, see [`Registry::register`]
+//! 0xff        reserved
+//! ```
+//!
+//! Byte 3 was a reserved flags byte that senders always set to 0, so every
+//! earlier v2 payload decodes as data. Receivers skip types they don't
+//! know rather than rejecting them, so new types never break existing
+//! deployments.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::RangeInclusive;
+
+/// A v2 message type.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MessageType(pub u8);
+
+impl MessageType {
+    pub const DATA: MessageType = MessageType(0x00);
+    pub const HEARTBEAT: MessageType = MessageType(0x01);
+    pub const CONTROL: MessageType = MessageType(0x02);
+    pub const ACK: MessageType = MessageType(0x03);
+    pub const GOODBYE: MessageType = MessageType(0x04);
+    pub const TRIGGER: MessageType = MessageType(0x05);
+    pub const QUERY: MessageType = MessageType(0x06);
+    pub const REPLY: MessageType = MessageType(0x07);
+
+    /// Types third parties may assign.
+    pub const USER_RANGE: RangeInclusive<u8> = 0x80..=0xfe;
+
+    /// Returns true for types in [`Self::USER_RANGE`].
+    pub fn is_user(self) -> bool {
+        Self::USER_RANGE.contains(&self.0)
+    }
+}
+
+impl fmt::Display for MessageType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{:02x}", self.0)
+    }
+}
+
+/// Names of the message types a receiver knows about.
+///
+/// Starts with the built-in types; applications add their own from the
+/// user range. A receiver skips any type not registered here.
+#[derive(Clone, Debug)]
+pub struct Registry {
+    names: BTreeMap<MessageType, String>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        let builtin = [
+            (MessageType::DATA, "data"),
+            (MessageType::HEARTBEAT, "heartbeat"),
+            (MessageType::CONTROL, "control"),
+            (MessageType::ACK, "ack"),
+            (MessageType::GOODBYE, "goodbye"),
+            (MessageType::TRIGGER, "trigger"),
+            (MessageType::QUERY, "query"),
+            (MessageType::REPLY, "reply"),
+        ];
+        Registry {
+            names: builtin
+                .into_iter()
+                .map(|(ty, name)| (ty, name.to_string()))
+                .collect(),
+        }
+    }
+}
+
+impl Registry {
+    /// Registers a user type. Fails outside [`MessageType::USER_RANGE`] or
+    /// if the type is already taken.
+    pub fn register(&mut self, ty: u8, name: &str) -> Result<MessageType, String> {
+        let ty = MessageType(ty);
+        if !ty.is_user() {
+            return Err(format!(
+                "message type {ty} is outside the user range 0x80-0xfe"
+            ));
+        }
+        if let Some(existing) = self.names.get(&ty) {
+            return Err(format!(
+                "message type {ty} is already registered as {existing}"
+            ));
+        }
+        self.names.insert(ty, name.to_string());
+        Ok(ty)
+    }
+
+    /// The name of a registered type.
+    pub fn name(&self, ty: MessageType) -> Option<&str> {
+        self.names.get(&ty).map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::{self, Format, Packet};
+
+    #[test]
+    fn builtin_types_are_named() {
+        let registry = Registry::default();
+        assert_eq!(registry.name(MessageType::DATA), Some("data"));
+        assert_eq!(registry.name(MessageType::TRIGGER), Some("trigger"));
+        assert_eq!(registry.name(MessageType::REPLY), Some("reply"));
+        assert_eq!(registry.name(MessageType(0x08)), None);
+    }
+
+    #[test]
+    fn only_free_user_types_can_be_registered() {
+        let mut registry = Registry::default();
+        assert_eq!(registry.register(0x80, "telemetry"), Ok(MessageType(0x80)));
+        assert_eq!(registry.name(MessageType(0x80)), Some("telemetry"));
+        assert!(registry.register(0x80, "other").is_err());
+        assert!(registry.register(0x02, "mine").is_err());
+        assert!(registry.register(0xff, "mine").is_err());
+    }
+
+    #[test]
+    fn type_round_trips_in_header_byte() {
+        let packet = Packet {
+            kind: MessageType(0x80),
+            ..Packet::new(Some(1), 42)
+        };
+        let mut buf = Vec::new();
+        payload::encode(Format::V2, &packet, None, &mut buf);
+        assert_eq!(buf[3], 0x80);
+        assert_eq!(payload::decode(&buf).unwrap().packet, packet);
+
+        // earlier senders always wrote 0 here
+        buf[3] = 0;
+        assert_eq!(
+            payload::decode(&buf).unwrap().packet.kind,
+            MessageType::DATA
+        );
+    }
+}
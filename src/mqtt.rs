@@ -0,0 +1,448 @@
+//! MQTT output from listen mode (`--mqtt`).
+//!
+//! Every accepted packet is published to a broker as the JSON object
+//! `--publish` hands local consumers (see [`crate::publish`]), on
+//! `--topic`, where `{sender}` stands for the sender (its IP, or
+//! `IP/GROUP`). The client speaks MQTT 3.1.1 over plain TCP and publishes
+//! at QoS 0, with an optional user name and password; there is no TLS, so
+//! the broker must be reachable as `mqtt://`, normally one on the same
+//! host or a bridge or proxy that carries on over TLS.
+//!
+//! As with `--influx`, a thread of its own does the writing and output is
+//! best effort: messages that find the queue full, or the broker
+//! unreachable, are dropped and counted. A lost connection is made again,
+//! backing off from [`MIN_BACKOFF`] to [`MAX_BACKOFF`] while it fails.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::export::Observation;
+use crate::log;
+use crate::publish;
+use crate::transmit;
+
+/// Port used when the URL names none.
+pub const DEFAULT_PORT: u16 = 1883;
+
+/// Topic used without `--topic`.
+pub const DEFAULT_TOPIC: &str = "udp_bcast_ts/{sender}";
+
+/// Wait before the first reconnection attempt.
+pub const MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Longest wait between reconnection attempts.
+pub const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Keep-alive announced to the broker; a PINGREQ goes out after half of
+/// it without a publish.
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+
+/// Longest connecting, or waiting for CONNACK, may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest one write may block.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Messages queued for the writer thread at most.
+const MAX_QUEUE: usize = 4096;
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PINGREQ: u8 = 0xc0;
+const DISCONNECT: u8 = 0xe0;
+
+/// Where `--mqtt` publishes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Broker {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Broker {
+    /// Parses `mqtt://HOST[:PORT]` (or `tcp://`); the host is resolved
+    /// at each connection.
+    pub fn parse(url: &str) -> Result<Broker, String> {
+        let rest = match url.split_once("://") {
+            Some(("mqtt" | "tcp", rest)) => rest.trim_end_matches('/'),
+            Some(("mqtts" | "ssl" | "tls", _)) => {
+                return Err(format!(
+                    "{url}: TLS is not supported; point it at a local broker, or a proxy that forwards over TLS"
+                ))
+            }
+            _ => return Err(format!("{url}: expected an mqtt:// URL")),
+        };
+        if rest.contains('@') {
+            return Err(format!(
+                "{url}: give credentials with --mqtt-user and --mqtt-password-file"
+            ));
+        }
+        // A port is present unless the last colon is inside IPv6 brackets
+        let (host, port) = match rest.rfind(':') {
+            Some(i) if !rest[i..].contains(']') => {
+                let port = rest[i + 1..]
+                    .parse()
+                    .ok()
+                    .filter(|&p| p != 0)
+                    .ok_or_else(|| format!("{url}: invalid port '{}'", &rest[i + 1..]))?;
+                (&rest[..i], port)
+            }
+            _ => (rest, DEFAULT_PORT),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(format!("{url}: expected a host"));
+        }
+        Ok(Broker {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl std::fmt::Display for Broker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.host.contains(':') {
+            true => write!(f, "mqtt://[{}]:{}", self.host, self.port),
+            false => write!(f, "mqtt://{}:{}", self.host, self.port),
+        }
+    }
+}
+
+/// Checks a `--topic`: a topic to publish on can't hold wildcards.
+pub fn parse_topic(s: &str) -> Result<String, String> {
+    if s.is_empty() {
+        return Err("the topic is empty".to_string());
+    }
+    if s.contains(['+', '#', '\0']) {
+        return Err(format!(
+            "'{s}': topics to publish on can't contain +, # or NUL"
+        ));
+    }
+    if s.len() > usize::from(u16::MAX) / 2 {
+        return Err(format!("'{s}': topic too long"));
+    }
+    Ok(s.to_string())
+}
+
+/// Settings for `--mqtt`.
+#[derive(Clone, Debug)]
+pub struct MqttConfig {
+    pub broker: Broker,
+    /// Topic for each message; `{sender}` is replaced by the sender.
+    pub topic: String,
+    /// Client identifier; brokers drop an earlier connection with the
+    /// same one.
+    pub client_id: String,
+    pub user: Option<String>,
+    pub password: Option<String>,
+}
+
+impl MqttConfig {
+    /// Reads a password file; a trailing newline is ignored.
+    pub fn load_password(path: &str) -> Result<String, String> {
+        let password = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read password file {path}: {e}"))?;
+        match password.trim_end_matches(['\r', '\n']) {
+            "" => Err(format!("Password file {path} is empty")),
+            p => Ok(p.to_string()),
+        }
+    }
+}
+
+/// Hands messages to the writer thread.
+pub struct Mqtt {
+    tx: Option<SyncSender<(String, String)>>,
+    topic: String,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl Mqtt {
+    /// Starts the writer thread, which connects on the first message.
+    pub fn start(cfg: MqttConfig) -> Mqtt {
+        log::info!("Publishing to {} on {}", cfg.broker, cfg.topic);
+        let topic = cfg.topic.clone();
+        let (tx, rx) = mpsc::sync_channel(MAX_QUEUE);
+        let writer = log::spawn(move || write(&cfg, rx));
+        Mqtt {
+            tx: Some(tx),
+            topic,
+            writer: Some(writer),
+        }
+    }
+
+    /// Queues the message for one accepted packet; `lost` is how many
+    /// sequence numbers it showed missing.
+    pub fn observe(&self, o: &Observation, sender: &str, lost: u64) {
+        let topic = self.topic.replace("{sender}", sender);
+        let message = (topic, publish::json(o, sender, lost));
+        if let Some(Err(TrySendError::Full(_))) = self.tx.as_ref().map(|tx| tx.try_send(message)) {
+            log::debug!("MQTT queue full; message dropped");
+        }
+    }
+}
+
+impl Drop for Mqtt {
+    /// Publishes what is queued, then disconnects.
+    fn drop(&mut self) {
+        self.tx = None;
+        if let Some(w) = self.writer.take() {
+            let _ = w.join();
+        }
+    }
+}
+
+/// Appends the remaining length, 7 bits a byte.
+fn put_length(out: &mut Vec<u8>, mut len: usize) {
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        if len == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn put_string(out: &mut Vec<u8>, s: &[u8]) {
+    out.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    out.extend_from_slice(s);
+}
+
+/// A control packet: its type byte, then the body.
+fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = vec![kind];
+    put_length(&mut out, body.len());
+    out.extend_from_slice(body);
+    out
+}
+
+fn connect_packet(cfg: &MqttConfig) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_string(&mut body, b"MQTT");
+    body.push(4); // 3.1.1
+    let mut flags = 0x02; // Clean session
+    if cfg.user.is_some() {
+        flags |= 0x80;
+    }
+    if cfg.password.is_some() {
+        flags |= 0x40;
+    }
+    body.push(flags);
+    body.extend_from_slice(&(KEEP_ALIVE.as_secs() as u16).to_be_bytes());
+    put_string(&mut body, cfg.client_id.as_bytes());
+    for s in [&cfg.user, &cfg.password].into_iter().flatten() {
+        put_string(&mut body, s.as_bytes());
+    }
+    packet(CONNECT, &body)
+}
+
+fn publish_packet(topic: &str, payload: &str) -> Vec<u8> {
+    let mut body = Vec::with_capacity(2 + topic.len() + payload.len());
+    put_string(&mut body, topic.as_bytes());
+    body.extend_from_slice(payload.as_bytes());
+    packet(PUBLISH, &body)
+}
+
+/// Why a broker refused the connection, by CONNACK return code.
+fn refusal(code: u8) -> String {
+    match code {
+        1 => "it doesn't speak MQTT 3.1.1".to_string(),
+        2 => "it rejected the client identifier".to_string(),
+        3 => "it is unavailable".to_string(),
+        4 => "bad user name or password".to_string(),
+        5 => "not authorized".to_string(),
+        c => format!("return code {c}"),
+    }
+}
+
+/// Connects and logs in.
+fn connect(cfg: &MqttConfig) -> Result<TcpStream, String> {
+    // Connecting is itself a transmission
+    if transmit::is_disabled() {
+        return Err("transmission is disabled (--observe-only)".to_string());
+    }
+    let addrs = (cfg.broker.host.as_str(), cfg.broker.port)
+        .to_socket_addrs()
+        .map_err(|e| format!("Failed to resolve {}: {e}", cfg.broker.host))?;
+    let mut last = io::Error::new(io::ErrorKind::NotFound, "resolved to nothing");
+    let mut stream = None;
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(s) => {
+                stream = Some(s);
+                break;
+            }
+            Err(e) => last = e,
+        }
+    }
+    let mut stream = stream.ok_or_else(|| last.to_string())?;
+    let _ = stream.set_nodelay(true);
+    stream
+        .set_read_timeout(Some(CONNECT_TIMEOUT))
+        .and_then(|()| stream.set_write_timeout(Some(WRITE_TIMEOUT)))
+        .and_then(|()| stream.write_all(&connect_packet(cfg)))
+        .map_err(|e| e.to_string())?;
+    let mut ack = [0; 4];
+    stream
+        .read_exact(&mut ack)
+        .map_err(|e| format!("no CONNACK: {e}"))?;
+    if ack[..2] != [CONNACK, 2] {
+        return Err(format!("expected CONNACK, got {ack:02x?}"));
+    }
+    if ack[3] != 0 {
+        return Err(format!("refused: {}", refusal(ack[3])));
+    }
+    Ok(stream)
+}
+
+/// Reads and discards what the broker sent (PINGRESPs); fails once it
+/// has closed the connection.
+fn drain(stream: &mut TcpStream) -> io::Result<()> {
+    stream.set_nonblocking(true)?;
+    let mut buf = [0; 256];
+    let result = loop {
+        match stream.read(&mut buf) {
+            Ok(0) => break Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+            Err(e) => break Err(e),
+        }
+    };
+    stream.set_nonblocking(false)?;
+    result
+}
+
+fn write(cfg: &MqttConfig, rx: Receiver<(String, String)>) {
+    let mut conn: Option<TcpStream> = None;
+    let mut retry_at = Instant::now();
+    let mut backoff = MIN_BACKOFF;
+    let mut last_sent = Instant::now();
+    let mut dropped = 0u64;
+    loop {
+        let ping_at = last_sent + KEEP_ALIVE / 2;
+        let message = match rx.recv_timeout(ping_at.saturating_duration_since(Instant::now())) {
+            Ok(m) => Some(m),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => {
+                if let Some(mut s) = conn {
+                    let _ = s.write_all(&packet(DISCONNECT, &[]));
+                }
+                if dropped > 0 {
+                    log::warn!("{dropped} MQTT message(s) dropped while disconnected");
+                }
+                return;
+            }
+        };
+        if conn.is_none() && message.is_some() && Instant::now() >= retry_at {
+            match connect(cfg) {
+                Ok(s) => {
+                    log::info!("Connected to MQTT broker {}", cfg.broker);
+                    if dropped > 0 {
+                        log::warn!("{dropped} MQTT message(s) dropped while disconnected");
+                        dropped = 0;
+                    }
+                    conn = Some(s);
+                    backoff = MIN_BACKOFF;
+                }
+                Err(e) => {
+                    log::warn!(
+                        "Failed to connect to MQTT broker {}: {e}; retrying in {}s",
+                        cfg.broker,
+                        backoff.as_secs()
+                    );
+                    retry_at = Instant::now() + backoff;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+        let Some(stream) = conn.as_mut() else {
+            dropped += u64::from(message.is_some());
+            last_sent = Instant::now();
+            continue;
+        };
+        let written = match &message {
+            Some((topic, json)) => stream.write_all(&publish_packet(topic, json)),
+            None => drain(stream).and_then(|()| stream.write_all(&packet(PINGREQ, &[]))),
+        };
+        last_sent = Instant::now();
+        if let Err(e) = written {
+            log::warn!("Lost connection to MQTT broker {}: {e}", cfg.broker);
+            dropped += u64::from(message.is_some());
+            conn = None;
+            retry_at = Instant::now();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn connects_and_publishes() {
+        assert_eq!(
+            Broker::parse("mqtt://[::1]:1884/").unwrap(),
+            Broker {
+                host: "::1".to_string(),
+                port: 1884
+            }
+        );
+        assert_eq!(Broker::parse("tcp://broker").unwrap().port, DEFAULT_PORT);
+        assert!(Broker::parse("mqtts://broker").is_err());
+        assert!(Broker::parse("mqtt://u:p@broker").is_err());
+        assert!(parse_topic("plant/+/ts").is_err());
+
+        let mut long = Vec::new();
+        put_length(&mut long, 321);
+        assert_eq!(long, [0xc1, 0x02]);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mqtt = Mqtt::start(MqttConfig {
+            broker: Broker {
+                host: "127.0.0.1".to_string(),
+                port,
+            },
+            topic: "plant/{sender}/ts".to_string(),
+            client_id: "rx1".to_string(),
+            user: Some("u".to_string()),
+            password: Some("p".to_string()),
+        });
+        let o = Observation {
+            recv_ms: 1_700_000_000_123,
+            send_ms: 1_700_000_000_120,
+            src: "10.0.0.5:12321".parse().unwrap(),
+            group: None,
+            seq: Some(7),
+            offset_ms: 3,
+            host_delay_us: None,
+            annotation: None,
+            fields: Vec::new(),
+        };
+        mqtt.observe(&o, "10.0.0.5", 0);
+
+        let (mut broker, _) = listener.accept().unwrap();
+        let mut connect = [0; 23];
+        broker.read_exact(&mut connect).unwrap();
+        assert_eq!(
+            connect,
+            *b"\x10\x15\x00\x04MQTT\x04\xc2\x00\x3c\x00\x03rx1\x00\x01u\x00\x01p"
+        );
+        broker.write_all(&[CONNACK, 2, 0, 0]).unwrap();
+        drop(mqtt);
+
+        // Then the message, and DISCONNECT on the way out
+        let mut rest = Vec::new();
+        broker.read_to_end(&mut rest).unwrap();
+        let mut expected = publish_packet("plant/10.0.0.5/ts", &publish::json(&o, "10.0.0.5", 0));
+        expected.extend_from_slice(&[DISCONNECT, 0]);
+        assert_eq!(rest, expected);
+    }
+}
@@ -0,0 +1,168 @@
+//! Copies of one datagram delivered on several interfaces.
+//!
+//! A host with two interfaces on the same segment, or on two segments a
+//! sender broadcasts to, receives each datagram once per interface. The
+//! first copy of a (sender, sequence) pair is processed as usual; later
+//! ones that arrive on another interface are dropped, counted against the
+//! pair of interfaces, and timed against the first copy. The skew shows
+//! which path is faster and by how much.
+//!
+//! Copies on the interface that delivered first, such as `--redundancy`
+//! repeats, are left to the replay window.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::SystemTime;
+
+use crate::sys;
+use crate::units;
+
+/// Sequence numbers remembered per sender.
+const DEPTH: usize = 64;
+
+/// The first delivery of a sequence number, and the interfaces that
+/// delivered it since.
+struct Delivery {
+    seq: u32,
+    first: u32,
+    at: SystemTime,
+    seen: Vec<u32>,
+}
+
+/// A copy that arrived on another interface than the first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InterfaceCopy {
+    /// Interface that delivered the datagram first.
+    pub first: u32,
+    /// How much later this copy arrived, in microseconds.
+    pub skew_us: u64,
+}
+
+/// Copies between one pair of interfaces.
+#[derive(Default)]
+struct PairStats {
+    copies: u64,
+    skew_us_sum: u64,
+    skew_us_max: u64,
+}
+
+/// Recent deliveries per sender, and copy statistics per interface pair.
+#[derive(Default)]
+pub struct InterfaceDedup {
+    senders: HashMap<SocketAddr, VecDeque<Delivery>>,
+    /// Keyed by (first interface, later interface).
+    pairs: BTreeMap<(u32, u32), PairStats>,
+    interfaces: Vec<u32>,
+    names: HashMap<u32, String>,
+}
+
+impl InterfaceDedup {
+    /// Records `seq` from `src` arriving on `iface` at `at`, returning the
+    /// first delivery if another interface has already delivered it.
+    pub fn observe(
+        &mut self,
+        src: SocketAddr,
+        seq: u32,
+        iface: u32,
+        at: SystemTime,
+    ) -> Option<InterfaceCopy> {
+        if !self.interfaces.contains(&iface) {
+            self.interfaces.push(iface);
+        }
+        let recent = self.senders.entry(src).or_default();
+        let Some(d) = recent.iter_mut().find(|d| d.seq == seq) else {
+            if recent.len() == DEPTH {
+                recent.pop_front();
+            }
+            recent.push_back(Delivery {
+                seq,
+                first: iface,
+                at,
+                seen: vec![iface],
+            });
+            return None;
+        };
+        if d.seen.contains(&iface) {
+            return None;
+        }
+        d.seen.push(iface);
+        let skew_us = at.duration_since(d.at).map_or(0, |s| s.as_micros() as u64);
+        let p = self.pairs.entry((d.first, iface)).or_default();
+        p.copies += 1;
+        p.skew_us_sum += skew_us;
+        p.skew_us_max = p.skew_us_max.max(skew_us);
+        Some(InterfaceCopy {
+            first: d.first,
+            skew_us,
+        })
+    }
+
+    /// Whether datagrams have arrived on more than one interface.
+    pub fn multihomed(&self) -> bool {
+        self.interfaces.len() > 1
+    }
+
+    /// The interface's name, or its index if the name can't be looked up.
+    pub fn name(&mut self, iface: u32) -> &str {
+        self.names
+            .entry(iface)
+            .or_insert_with(|| sys::interface_name(iface).unwrap_or_else(|_| iface.to_string()))
+    }
+
+    /// One summary line per pair of interfaces that delivered copies.
+    pub fn print(&mut self) {
+        let pairs: Vec<_> = self
+            .pairs
+            .iter()
+            .map(|(&k, p)| (k, p.copies, p.skew_us_sum / p.copies, p.skew_us_max))
+            .collect();
+        for ((first, later), copies, mean, max) in pairs {
+            let first = self.name(first).to_string();
+            println!(
+                "Interfaces: first={first} later={} copies={copies} {} {}",
+                self.name(later),
+                units::pair("skew_mean_us", units::micros(mean)),
+                units::pair("skew_max_us", units::micros(max))
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn drops_copies_from_other_interfaces_and_times_them() {
+        let mut d = InterfaceDedup::default();
+        let src = "10.0.0.5:40000".parse().unwrap();
+        let t0 = UNIX_EPOCH + Duration::from_secs(1_800_000_000);
+        let us = |n| t0 + Duration::from_micros(n);
+
+        assert_eq!(d.observe(src, 1, 2, t0), None);
+        assert!(!d.multihomed());
+        // The same interface again is a redundant copy, not ours to drop
+        assert_eq!(d.observe(src, 1, 2, us(10)), None);
+        assert_eq!(
+            d.observe(src, 1, 3, us(150)),
+            Some(InterfaceCopy {
+                first: 2,
+                skew_us: 150
+            })
+        );
+        assert!(d.multihomed());
+        assert_eq!(d.observe(src, 1, 3, us(160)), None);
+        // Either interface may win the next one
+        assert_eq!(d.observe(src, 2, 3, us(1000)), None);
+        assert_eq!(d.observe(src, 2, 2, us(1040)).map(|c| c.first), Some(3));
+        assert_eq!(d.pairs[&(2, 3)].copies, 1);
+        assert_eq!(d.pairs[&(3, 2)].skew_us_max, 40);
+
+        // Only the most recent sequence numbers are remembered
+        for seq in 3..3 + DEPTH as u32 {
+            d.observe(src, seq, 2, us(2000));
+        }
+        assert_eq!(d.observe(src, 1, 3, us(3000)), None);
+    }
+}
@@ -0,0 +1,253 @@
+//! Sending from a separate I/O thread (`send --io-thread`).
+//!
+//! The send loop reads the clock, encodes each datagram and then sends and
+//! logs it, so a send that blocks, or a stdout piped into something slow,
+//! holds up the timestamp of the next tick. With an [`Offload`] the loop
+//! only reads the clock and encodes: each tick's datagrams go through a
+//! bounded queue to a worker thread, which sends them and prints the
+//! `Sent broadcast` lines.
+//!
+//! Queueing never blocks. A tick that finds the queue full is dropped and
+//! counted, as a `buffer_full` send error and in [`QueueStats::dropped`].
+//! A send the worker fails is reported back, against the next datagram to
+//! the same destination, so error counts and backoff still see it.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TrySendError};
+use std::sync::Arc;
+
+use crate::log;
+use crate::transmit::DatagramSink;
+use crate::units;
+
+/// Default number of ticks the queue holds.
+pub const DEFAULT_DEPTH: usize = 64;
+
+/// One datagram: its payload, destination and, to log, its timestamp.
+type Datagram = (Vec<u8>, SocketAddr, Option<u64>);
+
+enum Job<S> {
+    Send(Vec<Datagram>),
+    /// Sends through this sink from now on.
+    Replace(S),
+}
+
+/// How full the queue has been.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    /// Ticks the queue holds.
+    pub capacity: usize,
+    /// Ticks waiting now.
+    pub depth: usize,
+    /// Most ticks ever waiting at once.
+    pub high_water: usize,
+    /// Ticks dropped because the queue was full.
+    pub dropped: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    depth: AtomicUsize,
+    high_water: AtomicUsize,
+    dropped: AtomicU64,
+}
+
+/// Handle to the I/O thread; a [`DatagramSink`] that queues.
+pub struct Offload<S> {
+    tx: SyncSender<Job<S>>,
+    failures: Receiver<(SocketAddr, io::Error)>,
+    /// Failures reported by the worker, not yet passed on.
+    pending: HashMap<SocketAddr, Vec<io::Error>>,
+    capacity: usize,
+    counters: Arc<Counters>,
+}
+
+impl<S: DatagramSink + Send + 'static> Offload<S> {
+    /// Starts a worker sending through `sink`, with room for `capacity`
+    /// ticks. It exits once the handle is dropped and the queue drained.
+    pub fn start(sink: S, capacity: usize) -> Offload<S> {
+        let (tx, rx) = mpsc::sync_channel(capacity);
+        let (failed, failures) = mpsc::channel();
+        let counters = Arc::new(Counters::default());
+        let shared = Arc::clone(&counters);
+        log::spawn(move || work(sink, rx, failed, shared));
+        Offload {
+            tx,
+            failures,
+            pending: HashMap::new(),
+            capacity,
+            counters,
+        }
+    }
+
+    /// Has the worker send through `sink` from the next queued tick on.
+    pub fn replace(&mut self, sink: S) {
+        // Waits for room rather than losing the new sink
+        let _ = self.tx.send(Job::Replace(sink));
+    }
+
+    /// Queues `msgs` as one tick, logging each with the matching entry of
+    /// `stamps` once sent. Returns one result per datagram: the length if
+    /// queued, or an error if the queue was full or the worker failed an
+    /// earlier send to the same destination.
+    pub fn submit(
+        &mut self,
+        msgs: &[(&[u8], SocketAddr)],
+        stamps: Option<&[u64]>,
+    ) -> Vec<io::Result<usize>> {
+        while let Ok((dest, e)) = self.failures.try_recv() {
+            self.pending.entry(dest).or_default().push(e);
+        }
+        let job = msgs
+            .iter()
+            .enumerate()
+            .map(|(i, &(payload, dest))| (payload.to_vec(), dest, stamps.map(|s| s[i])))
+            .collect();
+        // Counted first, as the worker may take the tick straight away
+        let depth = self.counters.depth.fetch_add(1, Ordering::Relaxed) + 1;
+        let queued = match self.tx.try_send(Job::Send(job)) {
+            Ok(()) => {
+                self.counters.high_water.fetch_max(depth, Ordering::Relaxed);
+                true
+            }
+            Err(TrySendError::Full(_) | TrySendError::Disconnected(_)) => {
+                self.counters.depth.fetch_sub(1, Ordering::Relaxed);
+                self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        };
+        msgs.iter()
+            .map(|&(payload, dest)| {
+                if let Some(e) = self.pending.get_mut(&dest).and_then(Vec::pop) {
+                    return Err(e);
+                }
+                if queued {
+                    Ok(payload.len())
+                } else {
+                    Err(io::Error::new(
+                        io::ErrorKind::WouldBlock,
+                        "I/O queue full, datagram dropped",
+                    ))
+                }
+            })
+            .collect()
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        QueueStats {
+            capacity: self.capacity,
+            depth: self.counters.depth.load(Ordering::Relaxed),
+            high_water: self.counters.high_water.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl<S: DatagramSink + Send + 'static> DatagramSink for Offload<S> {
+    fn send_to(&mut self, payload: &[u8], dest: SocketAddr) -> io::Result<usize> {
+        self.submit(&[(payload, dest)], None).remove(0)
+    }
+
+    fn send_batch(&mut self, msgs: &[(&[u8], SocketAddr)]) -> Vec<io::Result<usize>> {
+        self.submit(msgs, None)
+    }
+
+    fn offload(
+        &mut self,
+        msgs: &[(&[u8], SocketAddr)],
+        stamps: &[u64],
+    ) -> Option<Vec<io::Result<usize>>> {
+        Some(self.submit(msgs, Some(stamps)))
+    }
+}
+
+fn work<S: DatagramSink>(
+    mut sink: S,
+    rx: Receiver<Job<S>>,
+    failed: Sender<(SocketAddr, io::Error)>,
+    counters: Arc<Counters>,
+) {
+    for job in rx {
+        let datagrams = match job {
+            Job::Send(d) => d,
+            Job::Replace(s) => {
+                sink = s;
+                continue;
+            }
+        };
+        counters.depth.fetch_sub(1, Ordering::Relaxed);
+        let msgs: Vec<(&[u8], SocketAddr)> = datagrams
+            .iter()
+            .map(|(payload, dest, _)| (payload.as_slice(), *dest))
+            .collect();
+        for ((_, dest, ts_ms), result) in datagrams.iter().zip(sink.send_batch(&msgs)) {
+            match (result, ts_ms) {
+                (Ok(_), Some(ts_ms)) => {
+                    log::info!("Sent broadcast to {dest} {}", units::ts(*ts_ms))
+                }
+                (Ok(_), None) => {}
+                (Err(e), _) => {
+                    let _ = failed.send((*dest, e));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Sends nothing until `gate` lets a batch through, then fails sends
+    /// to port 9.
+    struct Gated(Receiver<()>, SyncSender<SocketAddr>);
+
+    impl DatagramSink for Gated {
+        fn send_to(&mut self, _payload: &[u8], dest: SocketAddr) -> io::Result<usize> {
+            let _ = self.0.recv();
+            let _ = self.1.send(dest);
+            if dest.port() == 9 {
+                return Err(io::ErrorKind::ConnectionRefused.into());
+            }
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn full_queue_drops_ticks_and_failures_come_back() {
+        let (gate, opened) = mpsc::sync_channel(8);
+        let (sent, done) = mpsc::sync_channel(8);
+        let mut offload = Offload::start(Gated(opened, sent), 2);
+        let ok: SocketAddr = "192.0.2.1:12321".parse().unwrap();
+        let bad: SocketAddr = "192.0.2.1:9".parse().unwrap();
+
+        // The worker takes the first tick and blocks; two more fill the queue
+        for _ in 0..3 {
+            assert!(offload.submit(&[(b"x", bad)], Some(&[1])).remove(0).is_ok());
+            thread::sleep(Duration::from_millis(20));
+        }
+        let dropped = offload.submit(&[(b"x", ok)], Some(&[2])).remove(0);
+        assert_eq!(dropped.unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        let stats = offload.stats();
+        assert_eq!((stats.capacity, stats.dropped), (2, 1));
+        assert_eq!((stats.depth, stats.high_water), (2, 2));
+
+        for _ in 0..3 {
+            gate.send(()).unwrap();
+            assert_eq!(done.recv().unwrap(), bad);
+        }
+        thread::sleep(Duration::from_millis(20));
+        // Each failure is reported against a later datagram to its destination
+        let results = offload.submit(&[(b"x", ok), (b"x", bad)], None);
+        assert!(results[0].is_ok());
+        assert_eq!(
+            results[1].as_ref().unwrap_err().kind(),
+            io::ErrorKind::ConnectionRefused
+        );
+    }
+}
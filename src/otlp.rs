@@ -0,0 +1,673 @@
+//! OpenTelemetry export for send mode (`--otlp`).
+//!
+//! Each tick of the send loop becomes a span, `send_tick`, timed from the
+//! start of its burst to the last datagram's send and carrying how many
+//! went out and how many failed. Three metrics are kept alongside, with
+//! cumulative temporality:
+//!
+//! - `udp_bcast_ts.sent`: datagrams sent.
+//! - `udp_bcast_ts.send_errors`: failed sends, by `class` (see
+//!   `sockerr`).
+//! - `udp_bcast_ts.tick.duration`: histogram of tick durations in ms.
+//!
+//! They are exported over OTLP/HTTP with the JSON encoding, from a thread
+//! of their own so that a slow collector never holds up a tick. Settings
+//! come from the standard `OTEL_*` environment variables (see
+//! [`OtlpConfig::from_env`]). There is no TLS, so the endpoint must be
+//! `http://`, typically a collector on the same host.
+//!
+//! Export is best effort: a failed request is logged and its data
+//! dropped, and ticks arriving while the queue is full are dropped too.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::log;
+use crate::rng::Rng;
+use crate::sockerr::ErrorClass;
+use crate::status::json_string;
+use crate::transmit;
+
+/// Collector address used when `OTEL_EXPORTER_OTLP_ENDPOINT` is unset.
+pub const DEFAULT_ENDPOINT: &str = "http://localhost:4318";
+
+/// Upper bounds, in ms, of the tick duration histogram's buckets.
+const DURATION_BOUNDS_MS: [f64; 10] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 25.0, 100.0];
+
+/// Spans sent in one request at most.
+const MAX_EXPORT_BATCH: usize = 512;
+
+/// An HTTP endpoint, resolved once at startup.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Endpoint {
+    pub addr: SocketAddr,
+    /// `Host` header value.
+    pub host: String,
+    pub path: String,
+}
+
+impl Endpoint {
+    /// Parses and resolves an `http://host[:port][/path]` URL.
+    pub fn parse(url: &str) -> Result<Endpoint, String> {
+        let rest = match url.split_once("://") {
+            Some(("http", rest)) => rest,
+            Some(("https", _)) => {
+                return Err(format!(
+                "{url}: https is not supported; point it at a local collector or proxy over http"
+            ))
+            }
+            _ => return Err(format!("{url}: expected an http:// URL")),
+        };
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        // A port is present unless the last colon is inside IPv6 brackets
+        let with_port = match host.rfind(':') {
+            Some(i) if !host[i..].contains(']') => host.to_string(),
+            _ => format!("{host}:80"),
+        };
+        let addr = with_port
+            .to_socket_addrs()
+            .map_err(|e| format!("Failed to resolve {url}: {e}"))?
+            .next()
+            .ok_or_else(|| format!("{url} resolved to nothing"))?;
+        Ok(Endpoint {
+            addr,
+            host: host.to_string(),
+            path: path.to_string(),
+        })
+    }
+}
+
+/// Exporter settings, from the `OTEL_*` environment variables.
+#[derive(Clone, Debug)]
+pub struct OtlpConfig {
+    /// Where to send spans; `None` with `OTEL_TRACES_EXPORTER=none`.
+    pub traces: Option<Endpoint>,
+    /// Where to send metrics; `None` with `OTEL_METRICS_EXPORTER=none`.
+    pub metrics: Option<Endpoint>,
+    /// Extra request headers, e.g. for authentication.
+    pub headers: Vec<(String, String)>,
+    /// Longest one export request may take.
+    pub timeout: Duration,
+    /// Resource attributes, including `service.name`.
+    pub resource: Vec<(String, String)>,
+    /// How often spans are exported.
+    pub span_delay: Duration,
+    /// Spans queued for export at most.
+    pub max_queue: usize,
+    /// How often metrics are exported.
+    pub metric_interval: Duration,
+}
+
+impl OtlpConfig {
+    /// Reads the settings through `var`, normally [`std::env::var`].
+    /// Returns `None` with `OTEL_SDK_DISABLED=true`, or when both signals
+    /// are turned off.
+    ///
+    /// Recognised: `OTEL_SDK_DISABLED`, `OTEL_SERVICE_NAME`,
+    /// `OTEL_RESOURCE_ATTRIBUTES`, `OTEL_TRACES_EXPORTER`,
+    /// `OTEL_METRICS_EXPORTER`, `OTEL_EXPORTER_OTLP_ENDPOINT`,
+    /// `OTEL_EXPORTER_OTLP_{TRACES,METRICS}_ENDPOINT`,
+    /// `OTEL_EXPORTER_OTLP_[{TRACES,METRICS}_]PROTOCOL` (only `http/json`),
+    /// `OTEL_EXPORTER_OTLP_HEADERS`, `OTEL_EXPORTER_OTLP_TIMEOUT`,
+    /// `OTEL_BSP_SCHEDULE_DELAY`, `OTEL_BSP_MAX_QUEUE_SIZE` and
+    /// `OTEL_METRIC_EXPORT_INTERVAL`.
+    pub fn from_env(var: impl Fn(&str) -> Option<String>) -> Result<Option<OtlpConfig>, String> {
+        let var = |name: &str| var(name).filter(|v| !v.trim().is_empty());
+        if var("OTEL_SDK_DISABLED").is_some_and(|v| v.trim().eq_ignore_ascii_case("true")) {
+            return Ok(None);
+        }
+        let millis = |name: &str, default: u64| match var(name) {
+            None => Ok(Duration::from_millis(default)),
+            Some(v) => v
+                .trim()
+                .parse()
+                .map(Duration::from_millis)
+                .map_err(|_| format!("{name}: expected milliseconds, got {v}")),
+        };
+
+        let base = var("OTEL_EXPORTER_OTLP_ENDPOINT").unwrap_or_else(|| DEFAULT_ENDPOINT.into());
+        let endpoint = |signal: &str, path: &str| -> Result<Option<Endpoint>, String> {
+            let upper = signal.to_ascii_uppercase();
+            match var(&format!("OTEL_{upper}_EXPORTER"))
+                .as_deref()
+                .map(str::trim)
+            {
+                None | Some("otlp") => {}
+                Some("none") => return Ok(None),
+                Some(other) => {
+                    return Err(format!(
+                        "OTEL_{upper}_EXPORTER: only otlp and none are supported, got {other}"
+                    ))
+                }
+            }
+            for name in [
+                format!("OTEL_EXPORTER_OTLP_{upper}_PROTOCOL"),
+                "OTEL_EXPORTER_OTLP_PROTOCOL".to_string(),
+            ] {
+                if let Some(p) = var(&name).filter(|p| p.trim() != "http/json") {
+                    return Err(format!("{name}: only http/json is supported, got {p}"));
+                }
+            }
+            // Signal-specific endpoints are used as given
+            let url = var(&format!("OTEL_EXPORTER_OTLP_{upper}_ENDPOINT"))
+                .unwrap_or_else(|| format!("{}/{path}", base.trim_end_matches('/')));
+            Endpoint::parse(url.trim()).map(Some)
+        };
+        let traces = endpoint("traces", "v1/traces")?;
+        let metrics = endpoint("metrics", "v1/metrics")?;
+        if traces.is_none() && metrics.is_none() {
+            return Ok(None);
+        }
+
+        let headers = match var("OTEL_EXPORTER_OTLP_HEADERS") {
+            Some(v) => key_values("OTEL_EXPORTER_OTLP_HEADERS", &v)?,
+            None => Vec::new(),
+        };
+        let mut resource = match var("OTEL_RESOURCE_ATTRIBUTES") {
+            Some(v) => key_values("OTEL_RESOURCE_ATTRIBUTES", &v)?,
+            None => Vec::new(),
+        };
+        // OTEL_SERVICE_NAME wins over a service.name in the attributes
+        let service = var("OTEL_SERVICE_NAME").map(|s| s.trim().to_string());
+        let named = resource.iter().any(|(k, _)| k == "service.name");
+        if service.is_some() || !named {
+            resource.retain(|(k, _)| k != "service.name");
+            let name = service.unwrap_or_else(|| "udp_bcast_ts".to_string());
+            resource.insert(0, ("service.name".to_string(), name));
+        }
+
+        Ok(Some(OtlpConfig {
+            traces,
+            metrics,
+            headers,
+            timeout: millis("OTEL_EXPORTER_OTLP_TIMEOUT", 10_000)?,
+            resource,
+            span_delay: millis("OTEL_BSP_SCHEDULE_DELAY", 5_000)?,
+            max_queue: match var("OTEL_BSP_MAX_QUEUE_SIZE") {
+                None => 2048,
+                Some(v) => {
+                    v.trim().parse().ok().filter(|&n| n > 0).ok_or_else(|| {
+                        format!("OTEL_BSP_MAX_QUEUE_SIZE: expected a count, got {v}")
+                    })?
+                }
+            },
+            metric_interval: millis("OTEL_METRIC_EXPORT_INTERVAL", 60_000)?,
+        }))
+    }
+}
+
+/// Parses a `key=value,key=value` list with percent-encoded values.
+fn key_values(name: &str, list: &str) -> Result<Vec<(String, String)>, String> {
+    list.split(',')
+        .filter(|kv| !kv.trim().is_empty())
+        .map(|kv| {
+            let (k, v) = kv
+                .split_once('=')
+                .ok_or_else(|| format!("{name}: expected key=value, got {kv}"))?;
+            Ok((k.trim().to_string(), percent_decode(v.trim())))
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// What one tick of the send loop did.
+#[derive(Clone, Debug)]
+pub struct Tick {
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub sent: u32,
+    pub errors: BTreeMap<ErrorClass, u32>,
+    /// The first failure, for the span's status.
+    pub error: Option<String>,
+}
+
+impl Tick {
+    pub fn new(start: SystemTime) -> Tick {
+        Tick {
+            start,
+            end: start,
+            sent: 0,
+            errors: BTreeMap::new(),
+            error: None,
+        }
+    }
+
+    /// Counts one send's outcome.
+    pub fn record(&mut self, result: Result<(), (ErrorClass, String)>) {
+        match result {
+            Ok(()) => self.sent += 1,
+            Err((class, e)) => {
+                *self.errors.entry(class).or_default() += 1;
+                self.error.get_or_insert(e);
+            }
+        }
+    }
+}
+
+/// Handle to the exporter thread.
+#[derive(Clone)]
+pub struct Otlp {
+    tx: SyncSender<Tick>,
+}
+
+impl Otlp {
+    /// Starts the exporter thread.
+    pub fn start(cfg: OtlpConfig) -> Otlp {
+        let (tx, rx) = mpsc::sync_channel(cfg.max_queue);
+        for (signal, endpoint) in [("traces", &cfg.traces), ("metrics", &cfg.metrics)] {
+            if let Some(e) = endpoint {
+                log::info!("Exporting OTLP {signal} to http://{}{}", e.host, e.path);
+            }
+        }
+        log::spawn(move || Exporter::new(cfg).run(rx));
+        Otlp { tx }
+    }
+
+    /// Queues a finished tick, dropping it if the queue is full.
+    pub fn record(&self, tick: Tick) {
+        if let Err(TrySendError::Full(_)) = self.tx.try_send(tick) {
+            log::debug!("OTLP queue full; tick dropped");
+        }
+    }
+}
+
+/// Cumulative metric state and spans waiting for export.
+struct Exporter {
+    cfg: OtlpConfig,
+    rng: Rng,
+    start: SystemTime,
+    spans: Vec<Tick>,
+    sent: u64,
+    errors: BTreeMap<ErrorClass, u64>,
+    /// Tick durations: count per bucket, the last one unbounded.
+    buckets: [u64; DURATION_BOUNDS_MS.len() + 1],
+    duration_sum_ms: f64,
+}
+
+impl Exporter {
+    fn new(cfg: OtlpConfig) -> Exporter {
+        Exporter {
+            cfg,
+            rng: Rng::from_entropy(),
+            start: SystemTime::now(),
+            spans: Vec::new(),
+            sent: 0,
+            errors: BTreeMap::new(),
+            buckets: [0; DURATION_BOUNDS_MS.len() + 1],
+            duration_sum_ms: 0.0,
+        }
+    }
+
+    fn run(mut self, rx: Receiver<Tick>) {
+        let now = Instant::now();
+        let mut next_spans = now + self.cfg.span_delay;
+        let mut next_metrics = now + self.cfg.metric_interval;
+        loop {
+            let due = next_spans.min(next_metrics);
+            match rx.recv_timeout(due.saturating_duration_since(Instant::now())) {
+                Ok(tick) => self.add(tick),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.export_spans();
+                    self.export_metrics();
+                    return;
+                }
+            }
+            let now = Instant::now();
+            if now >= next_spans || self.spans.len() >= MAX_EXPORT_BATCH {
+                self.export_spans();
+                next_spans = now + self.cfg.span_delay;
+            }
+            if now >= next_metrics {
+                self.export_metrics();
+                next_metrics = now + self.cfg.metric_interval;
+            }
+        }
+    }
+
+    fn add(&mut self, tick: Tick) {
+        self.sent += u64::from(tick.sent);
+        for (&class, &n) in &tick.errors {
+            *self.errors.entry(class).or_default() += u64::from(n);
+        }
+        let ms = tick
+            .end
+            .duration_since(tick.start)
+            .unwrap_or_default()
+            .as_secs_f64()
+            * 1000.0;
+        let bucket = DURATION_BOUNDS_MS
+            .iter()
+            .position(|&b| ms <= b)
+            .unwrap_or(DURATION_BOUNDS_MS.len());
+        self.buckets[bucket] += 1;
+        self.duration_sum_ms += ms;
+        if self.cfg.traces.is_some() {
+            self.spans.push(tick);
+        }
+    }
+
+    fn export_spans(&mut self) {
+        let Some(endpoint) = &self.cfg.traces else {
+            return;
+        };
+        if self.spans.is_empty() {
+            return;
+        }
+        let spans = std::mem::take(&mut self.spans);
+        let body = spans_json(&self.cfg.resource, &spans, &mut self.rng);
+        if let Err(e) = post(endpoint, &self.cfg.headers, self.cfg.timeout, JSON, &body) {
+            log::warn!("OTLP export of {} span(s) failed: {e}", spans.len());
+        }
+    }
+
+    fn export_metrics(&self) {
+        let Some(endpoint) = &self.cfg.metrics else {
+            return;
+        };
+        let body = self.metrics_json(SystemTime::now());
+        if let Err(e) = post(endpoint, &self.cfg.headers, self.cfg.timeout, JSON, &body) {
+            log::warn!("OTLP export of metrics failed: {e}");
+        }
+    }
+
+    fn metrics_json(&self, now: SystemTime) -> String {
+        let times = format!(
+            "\"startTimeUnixNano\":\"{}\",\"timeUnixNano\":\"{}\"",
+            nanos(self.start),
+            nanos(now)
+        );
+        let errors: Vec<String> = ErrorClass::ALL
+            .iter()
+            .map(|c| {
+                format!(
+                    "{{\"attributes\":[{}],{times},\"asInt\":\"{}\"}}",
+                    attribute("class", c.name()),
+                    self.errors.get(c).copied().unwrap_or(0)
+                )
+            })
+            .collect();
+        let buckets: Vec<String> = self.buckets.iter().map(|n| format!("\"{n}\"")).collect();
+        let bounds: Vec<String> = DURATION_BOUNDS_MS.iter().map(f64::to_string).collect();
+        let metrics = [
+            format!(
+                "{{\"name\":\"udp_bcast_ts.sent\",\"unit\":\"{{datagram}}\",\"sum\":{{\
+                 \"aggregationTemporality\":2,\"isMonotonic\":true,\
+                 \"dataPoints\":[{{{times},\"asInt\":\"{}\"}}]}}}}",
+                self.sent
+            ),
+            format!(
+                "{{\"name\":\"udp_bcast_ts.send_errors\",\"unit\":\"{{datagram}}\",\"sum\":{{\
+                 \"aggregationTemporality\":2,\"isMonotonic\":true,\"dataPoints\":[{}]}}}}",
+                errors.join(",")
+            ),
+            format!(
+                "{{\"name\":\"udp_bcast_ts.tick.duration\",\"unit\":\"ms\",\"histogram\":{{\
+                 \"aggregationTemporality\":2,\"dataPoints\":[{{{times},\"count\":\"{}\",\
+                 \"sum\":{},\"bucketCounts\":[{}],\"explicitBounds\":[{}]}}]}}}}",
+                self.buckets.iter().sum::<u64>(),
+                self.duration_sum_ms,
+                buckets.join(","),
+                bounds.join(",")
+            ),
+        ];
+        format!(
+            "{{\"resourceMetrics\":[{{\"resource\":{},\"scopeMetrics\":[{{\"scope\":{},\
+             \"metrics\":[{}]}}]}}]}}",
+            resource_json(&self.cfg.resource),
+            scope_json(),
+            metrics.join(",")
+        )
+    }
+}
+
+/// The OTLP/JSON export request for `spans`, each its own trace.
+fn spans_json(resource: &[(String, String)], spans: &[Tick], rng: &mut Rng) -> String {
+    let mut out = format!(
+        "{{\"resourceSpans\":[{{\"resource\":{},\"scopeSpans\":[{{\"scope\":{},\"spans\":[",
+        resource_json(resource),
+        scope_json()
+    );
+    for (i, tick) in spans.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let errors: u32 = tick.errors.values().sum();
+        let mut attributes = vec![
+            int_attribute("udp_bcast_ts.sent", tick.sent.into()),
+            int_attribute("udp_bcast_ts.send_errors", errors.into()),
+        ];
+        let status = match &tick.error {
+            None => "{\"code\":1}".to_string(),
+            Some(e) => {
+                if let Some(class) = tick.errors.keys().next() {
+                    attributes.push(attribute("error.type", class.name()));
+                }
+                format!("{{\"code\":2,\"message\":{}}}", json_string(e))
+            }
+        };
+        let _ = write!(
+            out,
+            "{{\"traceId\":\"{:016x}{:016x}\",\"spanId\":\"{:016x}\",\"name\":\"send_tick\",\
+             \"kind\":1,\"startTimeUnixNano\":\"{}\",\"endTimeUnixNano\":\"{}\",\
+             \"attributes\":[{}],\"status\":{status}}}",
+            rng.next_u64() | 1,
+            rng.next_u64(),
+            rng.next_u64() | 1,
+            nanos(tick.start),
+            nanos(tick.end),
+            attributes.join(",")
+        );
+    }
+    out.push_str("]}]}]}");
+    out
+}
+
+fn resource_json(resource: &[(String, String)]) -> String {
+    let attributes: Vec<String> = resource.iter().map(|(k, v)| attribute(k, v)).collect();
+    format!("{{\"attributes\":[{}]}}", attributes.join(","))
+}
+
+fn scope_json() -> String {
+    format!(
+        "{{\"name\":\"udp_bcast_ts\",\"version\":\"{}\"}}",
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+fn attribute(key: &str, value: &str) -> String {
+    format!(
+        "{{\"key\":{},\"value\":{{\"stringValue\":{}}}}}",
+        json_string(key),
+        json_string(value)
+    )
+}
+
+fn int_attribute(key: &str, value: u64) -> String {
+    format!(
+        "{{\"key\":{},\"value\":{{\"intValue\":\"{value}\"}}}}",
+        json_string(key)
+    )
+}
+
+fn nanos(t: SystemTime) -> u128 {
+    t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos())
+}
+
+const JSON: &str = "application/json";
+
+/// POSTs `body` to `endpoint` with the extra `headers`, succeeding on any
+/// 2xx answer. Also used by [`crate::influx`].
+pub fn post(
+    endpoint: &Endpoint,
+    headers: &[(String, String)],
+    timeout: Duration,
+    content_type: &str,
+    body: &str,
+) -> Result<(), String> {
+    if transmit::is_disabled() {
+        return Err("transmission is disabled (--observe-only)".to_string());
+    }
+    let stream = TcpStream::connect_timeout(&endpoint.addr, timeout)
+        .map_err(|e| format!("connect to {}: {e}", endpoint.addr))?;
+    let io = |e: std::io::Error| e.to_string();
+    stream.set_read_timeout(Some(timeout)).map_err(io)?;
+    stream.set_write_timeout(Some(timeout)).map_err(io)?;
+
+    let mut request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {content_type}\r\n\
+         Content-Length: {}\r\nConnection: close\r\n",
+        endpoint.path,
+        endpoint.host,
+        body.len()
+    );
+    for (k, v) in headers {
+        let _ = write!(request, "{k}: {v}\r\n");
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+    (&stream).write_all(request.as_bytes()).map_err(io)?;
+
+    let mut status = String::new();
+    BufReader::new(&stream).read_line(&mut status).map_err(io)?;
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(format!("server answered {}", status.trim())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn config(vars: &[(&str, &str)]) -> Result<Option<OtlpConfig>, String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|&(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        OtlpConfig::from_env(|k| vars.get(k).cloned())
+    }
+
+    #[test]
+    fn reads_standard_environment_variables() {
+        let cfg = config(&[]).unwrap().unwrap();
+        assert_eq!(cfg.traces.unwrap().path, "/v1/traces");
+        assert_eq!(cfg.metrics.unwrap().addr.port(), 4318);
+        assert_eq!(
+            cfg.resource,
+            [("service.name".into(), "udp_bcast_ts".into())]
+        );
+
+        let cfg = config(&[
+            ("OTEL_EXPORTER_OTLP_ENDPOINT", "http://127.0.0.1:4000/otlp/"),
+            ("OTEL_EXPORTER_OTLP_METRICS_ENDPOINT", "http://[::1]/m"),
+            ("OTEL_EXPORTER_OTLP_HEADERS", "api-key=a%20b, x=1"),
+            ("OTEL_RESOURCE_ATTRIBUTES", "service.name=beacon,site=lab"),
+            ("OTEL_BSP_SCHEDULE_DELAY", "250"),
+        ])
+        .unwrap()
+        .unwrap();
+        let traces = cfg.traces.unwrap();
+        assert_eq!(
+            (traces.addr.port(), traces.path.as_str()),
+            (4000, "/otlp/v1/traces")
+        );
+        let metrics = cfg.metrics.unwrap();
+        assert_eq!((metrics.addr.port(), metrics.path.as_str()), (80, "/m"));
+        assert_eq!(cfg.headers[0], ("api-key".into(), "a b".into()));
+        assert_eq!(cfg.resource[0], ("service.name".into(), "beacon".into()));
+        assert_eq!(cfg.span_delay, Duration::from_millis(250));
+
+        assert!(config(&[("OTEL_SDK_DISABLED", "true")]).unwrap().is_none());
+        let cfg = config(&[("OTEL_TRACES_EXPORTER", "none")])
+            .unwrap()
+            .unwrap();
+        assert!(cfg.traces.is_none() && cfg.metrics.is_some());
+        assert!(config(&[("OTEL_EXPORTER_OTLP_ENDPOINT", "https://c:4318")]).is_err());
+        assert!(config(&[("OTEL_EXPORTER_OTLP_PROTOCOL", "grpc")]).is_err());
+    }
+
+    #[test]
+    fn exports_ticks_as_spans() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            // The body is JSON ending in a brace; the request ends with it
+            while !request.ends_with(b"]}]}]}") {
+                let n = stream.read(&mut buf).unwrap();
+                assert!(n > 0);
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let cfg = config(&[
+            ("OTEL_EXPORTER_OTLP_ENDPOINT", &url),
+            ("OTEL_EXPORTER_OTLP_HEADERS", "authorization=Bearer%20t"),
+            ("OTEL_METRICS_EXPORTER", "none"),
+        ])
+        .unwrap()
+        .unwrap();
+        let mut exporter = Exporter::new(cfg);
+        let start = UNIX_EPOCH + Duration::from_secs(1_800_000_000);
+        let mut tick = Tick::new(start);
+        tick.record(Ok(()));
+        tick.record(Err((ErrorClass::Permission, "denied".into())));
+        tick.end = start + Duration::from_micros(300);
+        exporter.add(tick);
+        exporter.export_spans();
+
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /v1/traces HTTP/1.1\r\n"));
+        assert!(request.contains("\r\nauthorization: Bearer t\r\n"));
+        assert!(request.contains("\"startTimeUnixNano\":\"1800000000000000000\""));
+        assert!(request.contains("\"endTimeUnixNano\":\"1800000000000300000\""));
+        assert!(
+            request.contains("{\"key\":\"error.type\",\"value\":{\"stringValue\":\"permission\"}}")
+        );
+        assert!(request.contains("\"status\":{\"code\":2,\"message\":\"denied\"}"));
+        // 0.3 ms falls in the 0.5 ms bucket
+        assert_eq!(exporter.buckets[3], 1);
+        assert_eq!(exporter.errors[&ErrorClass::Permission], 1);
+    }
+}
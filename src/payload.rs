@@ -0,0 +1,1001 @@
+//! Wire formats for the timestamp payload.
+//!
+//! * `legacy`: exactly 8 bytes, big-endian u64 milliseconds since the Unix
+//!   epoch. This is what existing embedded consumers expect.
+//! * `v2`: a framed 16-byte header that adds a sequence number:
+//!
+//! ```text
+//! offset  size  field
+//!      0     2  magic "TS"
+//!      2     1  version (2)
+//!      3     1  message type, see `message` (0 = data)
+//!      4     4  sequence number, big-endian u32
+//!      8     8  timestamp, big-endian u64: milliseconds since Unix epoch,
+//!               unless an epoch extension says otherwise
+//! ```
+//!
+//! The header may be followed by extensions, each encoded as a type byte,
+//! a length byte and `length` bytes of value. Receivers skip extension
+//! types they don't know. A MAC or signature extension, if present, must
+//! come last and covers every byte before it. Senders add the monotonic extension
+//! ([`EXT_MONO`]) to every data packet, and one [`EXT_FIELD`] per `--field`;
+//! relays add [`EXT_RELAY`]. A timestamp in another scale than Unix time
+//! (`--epoch`, see [`crate::epoch`]) is announced with [`EXT_EPOCH`]; the
+//! legacy format has no room for that, so its receivers must be told.
+//! Time queries and their replies carry an [`EXT_ECHO`] token, see
+//! `send --serve-queries`. Once a sender has seen its wall clock step, its
+//! data packets carry the number of steps so far in [`EXT_ERA`], and a sender
+//! given a `--priority` carries it in [`EXT_PRIORITY`]. A timestamp
+//! read while the sender's clock wasn't synchronized can be flagged in
+//! [`EXT_CLOCK_FLAGS`]. A MAC made
+//! with a key from a key directory is preceded by [`EXT_KEY_ID`], which
+//! receivers that don't know it skip like any other. A sender given
+//! `--crc` ends its payloads with [`EXT_CRC`] instead of a MAC, a checksum
+//! that catches corruption on links that don't check UDP checksums.
+//!
+//! A sender given `--encrypt-key` then seals the whole datagram with
+//! ChaCha20-Poly1305 (see [`seal`] and [`crate::aead`]):
+//!
+//! ```text
+//! offset  size  field
+//!      0     2  magic "TS"
+//!      2     1  version (0x82: sealed v2)
+//!      3     1  message type
+//!      4     4  sequence number, big-endian u32
+//!      8     8  salt
+//!     16     n  the rest of the v2 datagram, timestamp and extensions, encrypted
+//!   16+n    16  Poly1305 tag over the first 8 bytes and the ciphertext
+//! ```
+//!
+//! The nonce is the salt followed by the sequence number. Salts are drawn
+//! at random whenever a sender's sequence numbers start over, so nonces
+//! don't repeat under one key.
+//!
+//! * `proto`: the same information as a protocol buffers message, for
+//!   consumers with generated decoders; see [`PROTO_SCHEMA`].
+//! * `compact`: an 11-byte full frame every few ticks and 4-byte deltas in
+//!   between, for constrained links; see [`crate::compact`].
+
+use std::convert::TryInto;
+use std::fmt;
+
+use crate::aead::{AeadKey, NONCE_LEN, TAG_LEN};
+use crate::auth::{self, Key, MAC_LEN};
+use crate::compact;
+use crate::ed25519::{KeyRing, SigningKey, KEY_ID_LEN, SIGNATURE_LEN};
+use crate::epoch::Epoch;
+use crate::message::MessageType;
+use crate::proto;
+
+/// The protocol buffers schema of [`Format::Proto`] payloads, for
+/// publishing alongside a deployment.
+pub use crate::proto::SCHEMA as PROTO_SCHEMA;
+
+pub const LEGACY_LEN: usize = 8;
+pub const V2_LEN: usize = 16;
+pub const V2_MAGIC: [u8; 2] = *b"TS";
+pub const V2_VERSION: u8 = 2;
+/// Version byte of a v2 datagram sealed with [`seal`].
+pub const V2_SEALED_VERSION: u8 = 0x82;
+/// Length of the salt in a sealed datagram's nonce.
+pub const SALT_LEN: usize = 8;
+
+/// Extension: beacon group id, big-endian u16.
+pub const EXT_GROUP: u8 = 0x01;
+/// Extension: microseconds since the sender started, from its monotonic
+/// clock, big-endian u64.
+pub const EXT_MONO: u8 = 0x02;
+/// Extension: hop count (u8) and id of the last relay (big-endian u16),
+/// see [`Relay`].
+pub const EXT_RELAY: u8 = 0x03;
+/// Extension: a user-defined `key=value` field, UTF-8, see [`Field`].
+pub const EXT_FIELD: u8 = 0x04;
+/// Extension: the scale of the header's timestamp, one byte: 1 for NTP,
+/// 2 for GPS. Without it the timestamp is Unix milliseconds.
+pub const EXT_EPOCH: u8 = 0x05;
+/// Extension: a query's opaque echo token, 1-32 bytes, copied into the
+/// reply (see `send --serve-queries`).
+pub const EXT_ECHO: u8 = 0x06;
+/// Longest echo token.
+pub const MAX_ECHO_LEN: usize = 32;
+/// Extension: wall-clock steps the sender has seen since it started,
+/// big-endian u16. Absent until the first step.
+pub const EXT_ERA: u8 = 0x07;
+/// Extension: the sender's `--priority` among redundant senders, one
+/// byte (see [`crate::role`]).
+pub const EXT_PRIORITY: u8 = 0x08;
+/// Extension: one byte of flags about the sender's clock, see
+/// [`CLOCK_UNSYNCED`]. Absent when none is set.
+pub const EXT_CLOCK_FLAGS: u8 = 0x09;
+/// Clock flag: the clock wasn't synchronized when it was read.
+pub const CLOCK_UNSYNCED: u8 = 0x01;
+/// Extension: truncated HMAC-SHA256 over all preceding bytes.
+pub const EXT_MAC: u8 = 0x10;
+/// Extension: id of the key the MAC was made with, big-endian u16, just
+/// before the MAC (see [`crate::auth`]).
+pub const EXT_KEY_ID: u8 = 0x12;
+/// Extension: Ed25519 signature over all preceding bytes, after the
+/// first 8 bytes of the signer's public key.
+pub const EXT_SIG: u8 = 0x11;
+/// Extension: CRC-32C (Castagnoli) over all preceding bytes, big-endian
+/// u32. Like a MAC it must come last.
+pub const EXT_CRC: u8 = 0x13;
+/// Extension: fleet control command, see [`Control`].
+pub const EXT_CONTROL: u8 = 0x20;
+
+/// Payload layout selected with `--format`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Legacy,
+    V2,
+    Proto,
+    Compact,
+}
+
+impl Format {
+    /// Parses a `--format` value.
+    pub fn parse(s: &str) -> Result<Format, String> {
+        match s {
+            "legacy" => Ok(Format::Legacy),
+            "v2" => Ok(Format::V2),
+            "proto" => Ok(Format::Proto),
+            "compact" => Ok(Format::Compact),
+            _ => Err("expected legacy, v2, proto or compact".to_string()),
+        }
+    }
+}
+
+/// A decoded timestamp datagram.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Packet {
+    /// Message type; legacy payloads are always data.
+    pub kind: MessageType,
+    /// Sequence number; legacy payloads carry none.
+    pub seq: Option<u32>,
+    pub ts_ms: u64,
+    /// Beacon group, if the sender tagged one.
+    pub group: Option<u16>,
+    /// Reconfiguration command, if this is a control message.
+    pub control: Option<Control>,
+    /// Sender's monotonic clock in microseconds since it started. Unlike
+    /// `ts_ms` it never steps, so comparing the two separates wall-clock
+    /// corrections from changes in network delay.
+    pub mono_us: Option<u64>,
+    /// Number of wall-clock steps the sender has seen since it started;
+    /// timestamps from different eras shouldn't be compared.
+    pub era: Option<u16>,
+    /// Set once the packet has been through a relay.
+    pub relay: Option<Relay>,
+    /// The sender's rank among redundant senders, if it was given one.
+    pub priority: Option<u8>,
+    /// Set when the sender's clock wasn't synchronized as it was read
+    /// (`send --require-clock-sync mark`).
+    pub unsynced: bool,
+    /// Scale the timestamp is sent in. `ts_ms` itself is always Unix
+    /// time: encoding converts it and decoding converts it back.
+    pub epoch: Epoch,
+}
+
+impl Packet {
+    /// A data packet with no optional parts, its timestamp sent in Unix
+    /// time. Others are built from it with struct update syntax.
+    pub fn new(seq: Option<u32>, ts_ms: u64) -> Packet {
+        Packet {
+            kind: MessageType::DATA,
+            seq,
+            ts_ms,
+            group: None,
+            control: None,
+            mono_us: None,
+            era: None,
+            relay: None,
+            priority: None,
+            unsynced: false,
+            epoch: Epoch::Unix,
+        }
+    }
+}
+
+/// How far a packet has travelled through `relay` instances.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Relay {
+    /// Relays passed through.
+    pub hops: u8,
+    /// The last relay's `--relay-id`.
+    pub id: u16,
+}
+
+impl Relay {
+    fn to_bytes(self) -> [u8; 3] {
+        let id = self.id.to_be_bytes();
+        [self.hops, id[0], id[1]]
+    }
+}
+
+/// Deployment metadata a sender attaches to every payload (`--field`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Field {
+    pub key: String,
+    pub value: String,
+}
+
+impl Field {
+    /// Parses a `--field` value of the form `<key>=<value>`. Keys are
+    /// letters, digits, `_`, `-` and `.`; values are printable and free of
+    /// whitespace and `;`, so received lines still split into `key=value`
+    /// pairs and a CSV export's `fields` column into fields.
+    pub fn parse(s: &str) -> Result<Field, String> {
+        let (key, value) = s.split_once('=').ok_or("expected KEY=VALUE")?;
+        if key.is_empty()
+            || !key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+        {
+            return Err(format!("invalid field name '{key}'"));
+        }
+        if value
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || c == ';')
+        {
+            return Err(format!("field {key} has whitespace or ';' in its value"));
+        }
+        if s.len() > u8::MAX as usize {
+            return Err(format!("field {key} is longer than 255 bytes"));
+        }
+        Ok(Field {
+            key: key.to_string(),
+            value: value.to_string(),
+        })
+    }
+}
+
+/// A command for `--follow` senders: switch to `interval_ms` at
+/// `apply_at_ms`. Encoded as two big-endian u64s.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Control {
+    pub apply_at_ms: u64,
+    pub interval_ms: u64,
+}
+
+/// A decoded datagram together with its authentication trailer.
+pub struct Decoded<'a> {
+    pub packet: Packet,
+    /// The format it arrived in.
+    pub format: Format,
+    /// The MAC extension value and the bytes it covers.
+    pub mac: Option<(&'a [u8], &'a [u8])>,
+    /// Id of the key the MAC names.
+    pub key_id: Option<u16>,
+    /// The signature extension value and the bytes it covers.
+    pub sig: Option<(&'a [u8], &'a [u8])>,
+    /// User-defined `(key, value)` fields, in the order sent.
+    pub fields: Vec<(&'a str, &'a str)>,
+    /// A query's echo token, or the one a reply copied from its query.
+    pub echo: Option<&'a [u8]>,
+    /// The checksum extension value and the bytes it covers.
+    pub crc: Option<(u32, &'a [u8])>,
+}
+
+impl Decoded<'_> {
+    /// Returns true if the datagram carries a MAC that `key` accepts.
+    pub fn verify(&self, key: &Key) -> bool {
+        match self.mac {
+            Some((mac, covered)) => key.verify(covered, mac),
+            None => false,
+        }
+    }
+
+    /// Checks the datagram's MAC against `keys`, returning the key that
+    /// made it.
+    pub fn verify_any<'k>(&self, keys: &'k auth::KeyRing) -> Option<&'k Key> {
+        let (mac, covered) = self.mac?;
+        keys.verify(self.key_id, covered, mac)
+    }
+
+    /// Returns false if the datagram carries a checksum that doesn't match
+    /// its contents. Datagrams without one pass.
+    pub fn checksum_ok(&self) -> bool {
+        self.crc.is_none_or(|(crc, covered)| crc32c(covered) == crc)
+    }
+
+    /// Checks the datagram's signature against `keys`, returning the
+    /// signer's name.
+    pub fn verify_signature<'k>(&self, keys: &'k KeyRing) -> Result<&'k str, String> {
+        let (value, covered) = self.sig.ok_or("unsigned")?;
+        let (key_id, sig) = value.split_at(KEY_ID_LEN);
+        keys.verify(key_id, covered, sig)
+    }
+}
+
+/// Why a datagram couldn't be decoded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Not the length or magic of any format.
+    Unrecognized { len: usize },
+    /// A v2 version this one doesn't know.
+    UnsupportedVersion(u8),
+    /// A sealed datagram, which [`open`] must decrypt first.
+    Sealed,
+    /// [`open`] was handed a datagram that isn't sealed.
+    NotSealed,
+    /// A sealed datagram shorter than its salt and tag.
+    TruncatedSealed { len: usize },
+    /// The tag didn't match: the wrong key, or a mangled datagram.
+    DecryptionFailed,
+    /// A v2 extension header cut off by the end of the datagram.
+    TruncatedExtensionHeader,
+    /// The v2 extension of this type is cut off by the end of the datagram.
+    TruncatedExtension(u8),
+    /// A v2 extension of a known type with the wrong length.
+    BadExtensionLength { name: &'static str, len: usize },
+    /// An extension after the checksum, MAC or signature, which must come
+    /// last; `after` names which.
+    DataAfter { after: &'static str },
+    /// An epoch extension naming a scale this version doesn't know.
+    UnknownEpoch,
+    /// A field extension that isn't UTF-8 `key=value`.
+    BadField,
+    /// A protobuf field or varint cut off by the end of its message.
+    ProtoTruncated,
+    /// A protobuf varint longer than 10 bytes.
+    ProtoVarintTooLong,
+    /// A protobuf tag whose field number doesn't fit in 32 bits.
+    ProtoBadFieldNumber,
+    /// A protobuf wire type this decoder doesn't take.
+    ProtoUnsupportedWireType { wire: u8, field: u32 },
+    /// A known protobuf field with a wire type other than its own.
+    ProtoWrongWireType { field: &'static str },
+    /// A protobuf integer too large for its field.
+    ProtoOutOfRange { field: &'static str, value: u64 },
+    /// A protobuf string that isn't UTF-8.
+    ProtoNotUtf8 { field: &'static str },
+    /// A protobuf message without the udp_bcast_ts magic.
+    ProtoMissingMagic,
+    /// A compact full frame announcing a group of 0.
+    CompactEmptyGroup,
+    /// A compact delta frame without the full frame `full` its group
+    /// starts with, or, for `None`, before any full frame.
+    CompactMissingFull { seq: u16, full: Option<u16> },
+    /// A timestamp that doesn't fit in u64 milliseconds of Unix time once
+    /// converted from its scale or added to its full frame.
+    TimestampOutOfRange,
+    /// An error from a [`PayloadCodec`] other than the built-in ones.
+    Codec(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Unrecognized { len } => write!(f, "unrecognized {len}-byte datagram"),
+            DecodeError::UnsupportedVersion(v) => write!(f, "unsupported payload version {v}"),
+            DecodeError::Sealed => {
+                f.write_str("encrypted payload; receiving it needs --encrypt-key")
+            }
+            DecodeError::NotSealed => f.write_str("not encrypted"),
+            DecodeError::TruncatedSealed { len } => {
+                write!(f, "truncated {len}-byte encrypted datagram")
+            }
+            DecodeError::DecryptionFailed => f.write_str("decryption failed"),
+            DecodeError::TruncatedExtensionHeader => f.write_str("truncated extension header"),
+            DecodeError::TruncatedExtension(ty) => write!(f, "truncated extension 0x{ty:02x}"),
+            DecodeError::BadExtensionLength { name, len } => {
+                write!(f, "bad {name} extension length {len}")
+            }
+            DecodeError::DataAfter { after } => write!(f, "data after {after} extension"),
+            DecodeError::UnknownEpoch => f.write_str("unknown timestamp epoch"),
+            DecodeError::BadField => f.write_str("bad field extension"),
+            DecodeError::ProtoTruncated => f.write_str("truncated protobuf field"),
+            DecodeError::ProtoVarintTooLong => f.write_str("varint longer than 10 bytes"),
+            DecodeError::ProtoBadFieldNumber => f.write_str("bad protobuf field number"),
+            DecodeError::ProtoUnsupportedWireType { wire, field } => {
+                write!(f, "unsupported wire type {wire} in field {field}")
+            }
+            DecodeError::ProtoWrongWireType { field } => {
+                write!(f, "{field} has the wrong wire type")
+            }
+            DecodeError::ProtoOutOfRange { field, value } => {
+                write!(f, "{field} {value} out of range")
+            }
+            DecodeError::ProtoNotUtf8 { field } => write!(f, "{field} is not UTF-8"),
+            DecodeError::ProtoMissingMagic => {
+                f.write_str("protobuf payload without the udp_bcast_ts magic")
+            }
+            DecodeError::CompactEmptyGroup => f.write_str("compact frame with a group of 0"),
+            DecodeError::CompactMissingFull {
+                seq,
+                full: Some(full),
+            } => {
+                write!(
+                    f,
+                    "compact delta seq={seq} without its full frame seq={full}"
+                )
+            }
+            DecodeError::CompactMissingFull { seq, full: None } => {
+                write!(f, "compact delta seq={seq} before any full frame")
+            }
+            DecodeError::TimestampOutOfRange => f.write_str("timestamp out of range"),
+            DecodeError::Codec(e) => f.write_str(e),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<DecodeError> for String {
+    fn from(e: DecodeError) -> String {
+        e.to_string()
+    }
+}
+
+fn push_ext(out: &mut Vec<u8>, ty: u8, value: &[u8]) {
+    out.push(ty);
+    out.push(value.len() as u8);
+    out.extend_from_slice(value);
+}
+
+/// Appends the id of `key`, if it has one, and its MAC over all of that.
+fn push_mac(out: &mut Vec<u8>, key: &Key) {
+    if let Some(id) = key.id() {
+        push_ext(out, EXT_KEY_ID, &id.to_be_bytes());
+    }
+    let mac = key.mac(out);
+    push_ext(out, EXT_MAC, &mac);
+}
+
+/// Encodes `packet` in the given format into `out`, replacing its contents.
+///
+/// Legacy and compact payloads have no room for a group or MAC, and proto
+/// payloads none for a MAC; those are ignored. Compact payloads are
+/// encoded as full frames: deltas need a [`compact::Encoder`].
+pub fn encode(format: Format, packet: &Packet, key: Option<&Key>, out: &mut Vec<u8>) {
+    encode_with_fields(format, packet, &[], key, out);
+}
+
+/// Like [`encode`], adding `fields` to v2 payloads ahead of any MAC.
+pub fn encode_with_fields(
+    format: Format,
+    packet: &Packet,
+    fields: &[Field],
+    key: Option<&Key>,
+    out: &mut Vec<u8>,
+) {
+    out.clear();
+    match format {
+        Format::Legacy => {
+            // Equivalent to struct.pack("!Q", ts_ms)
+            let ts = packet.epoch.value_of(packet.ts_ms);
+            out.extend_from_slice(&ts.to_be_bytes());
+        }
+        Format::V2 => {
+            out.extend_from_slice(&V2_MAGIC);
+            out.push(V2_VERSION);
+            out.push(packet.kind.0);
+            out.extend_from_slice(&packet.seq.unwrap_or(0).to_be_bytes());
+            let ts = packet.epoch.value_of(packet.ts_ms);
+            out.extend_from_slice(&ts.to_be_bytes());
+            match packet.epoch {
+                Epoch::Unix => {}
+                Epoch::Ntp => push_ext(out, EXT_EPOCH, &[1]),
+                Epoch::Gps => push_ext(out, EXT_EPOCH, &[2]),
+            }
+            if let Some(group) = packet.group {
+                push_ext(out, EXT_GROUP, &group.to_be_bytes());
+            }
+            if let Some(mono_us) = packet.mono_us {
+                push_ext(out, EXT_MONO, &mono_us.to_be_bytes());
+            }
+            if let Some(era) = packet.era {
+                push_ext(out, EXT_ERA, &era.to_be_bytes());
+            }
+            if let Some(priority) = packet.priority {
+                push_ext(out, EXT_PRIORITY, &[priority]);
+            }
+            if packet.unsynced {
+                push_ext(out, EXT_CLOCK_FLAGS, &[CLOCK_UNSYNCED]);
+            }
+            if let Some(relay) = packet.relay {
+                push_ext(out, EXT_RELAY, &relay.to_bytes());
+            }
+            if let Some(control) = packet.control {
+                let mut value = [0u8; 16];
+                value[..8].copy_from_slice(&control.apply_at_ms.to_be_bytes());
+                value[8..].copy_from_slice(&control.interval_ms.to_be_bytes());
+                push_ext(out, EXT_CONTROL, &value);
+            }
+            for field in fields {
+                out.push(EXT_FIELD);
+                out.push((field.key.len() + 1 + field.value.len()) as u8);
+                out.extend_from_slice(field.key.as_bytes());
+                out.push(b'=');
+                out.extend_from_slice(field.value.as_bytes());
+            }
+            if let Some(key) = key {
+                push_mac(out, key);
+            }
+        }
+        Format::Proto => proto::encode(packet, fields, out),
+        Format::Compact => compact::encode_full(packet, 1, out),
+    }
+}
+
+/// Encodes `packet` as a v2 reply to a query, carrying the query's echo
+/// token, if it had one, ahead of any MAC.
+pub fn encode_reply(packet: &Packet, echo: Option<&[u8]>, key: Option<&Key>, out: &mut Vec<u8>) {
+    encode_with_fields(Format::V2, packet, &[], None, out);
+    if let Some(echo) = echo {
+        push_ext(out, EXT_ECHO, echo);
+    }
+    if let Some(key) = key {
+        push_mac(out, key);
+    }
+}
+
+/// Appends a checksum extension to a v2 datagram encoded without a key.
+pub fn append_crc(out: &mut Vec<u8>) {
+    let crc = crc32c(out);
+    push_ext(out, EXT_CRC, &crc.to_be_bytes());
+}
+
+/// CRC-32C, as used by iSCSI and SCTP: reflected, polynomial 0x1EDC6F41.
+pub fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0x82F6_3B78 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// Appends a signature extension to a v2 datagram encoded without a key.
+pub fn sign(out: &mut Vec<u8>, key: &SigningKey) {
+    let sig = key.sign(out);
+    let mut value = [0u8; KEY_ID_LEN + SIGNATURE_LEN];
+    value[..KEY_ID_LEN].copy_from_slice(&key.public().id());
+    value[KEY_ID_LEN..].copy_from_slice(&sig);
+    push_ext(out, EXT_SIG, &value);
+}
+
+/// Seals a v2 datagram in place, encrypting all but its first 8 bytes
+/// under a nonce made of `salt` and its sequence number.
+pub fn seal(out: &mut Vec<u8>, key: &AeadKey, salt: &[u8; SALT_LEN]) {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[..SALT_LEN].copy_from_slice(salt);
+    nonce[SALT_LEN..].copy_from_slice(&out[4..8]);
+    out[2] = V2_SEALED_VERSION;
+    out.splice(8..8, salt.iter().copied());
+    let (clear, data) = out.split_at_mut(8 + SALT_LEN);
+    let tag = key.seal(&nonce, &clear[..8], data);
+    out.extend_from_slice(&tag);
+}
+
+/// Returns true if `data` looks like a sealed datagram.
+pub fn is_sealed(data: &[u8]) -> bool {
+    data.len() > 2 && data[..2] == V2_MAGIC && data[2] == V2_SEALED_VERSION
+}
+
+/// Checks and decrypts a sealed datagram into `out`, as the v2 datagram
+/// it was before [`seal`].
+pub fn open(data: &[u8], key: &AeadKey, out: &mut Vec<u8>) -> Result<(), DecodeError> {
+    if !is_sealed(data) {
+        return Err(DecodeError::NotSealed);
+    }
+    if data.len() < 8 + SALT_LEN + TAG_LEN {
+        return Err(DecodeError::TruncatedSealed { len: data.len() });
+    }
+    let (sealed, tag) = data.split_at(data.len() - TAG_LEN);
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[..SALT_LEN].copy_from_slice(&sealed[8..8 + SALT_LEN]);
+    nonce[SALT_LEN..].copy_from_slice(&sealed[4..8]);
+    out.clear();
+    out.extend_from_slice(&sealed[..8]);
+    out.extend_from_slice(&sealed[8 + SALT_LEN..]);
+    if !key.open(&nonce, &sealed[..8], &mut out[8..], tag) {
+        return Err(DecodeError::DecryptionFailed);
+    }
+    out[2] = V2_VERSION;
+    Ok(())
+}
+
+/// Decodes a received datagram, accepting any format. Compact delta
+/// frames can't be decoded on their own; see [`compact::Expander`].
+pub fn decode(data: &[u8]) -> Result<Decoded<'_>, DecodeError> {
+    if data.len() == LEGACY_LEN {
+        return Ok(Decoded {
+            packet: Packet::new(None, u64::from_be_bytes(data.try_into().unwrap())),
+            format: Format::Legacy,
+            mac: None,
+            key_id: None,
+            sig: None,
+            fields: Vec::new(),
+            echo: None,
+            crc: None,
+        });
+    }
+    if proto::is_beacon(data) {
+        let (packet, fields) = proto::decode(data)?;
+        return Ok(Decoded {
+            packet,
+            format: Format::Proto,
+            mac: None,
+            key_id: None,
+            sig: None,
+            fields,
+            echo: None,
+            crc: None,
+        });
+    }
+    if data.len() == compact::FULL_LEN {
+        return Ok(compact::decode_full(data));
+    }
+    if data.len() < V2_LEN || data[0..2] != V2_MAGIC {
+        return Err(DecodeError::Unrecognized { len: data.len() });
+    }
+    if data[2] == V2_SEALED_VERSION {
+        return Err(DecodeError::Sealed);
+    }
+    if data[2] != V2_VERSION {
+        return Err(DecodeError::UnsupportedVersion(data[2]));
+    }
+
+    let mut packet = Packet {
+        kind: MessageType(data[3]),
+        ..Packet::new(
+            Some(u32::from_be_bytes(data[4..8].try_into().unwrap())),
+            u64::from_be_bytes(data[8..16].try_into().unwrap()),
+        )
+    };
+    let mut mac = None;
+    let mut key_id = None;
+    let mut sig = None;
+    let mut fields = Vec::new();
+    let mut echo = None;
+    let mut crc = None;
+
+    let mut pos = V2_LEN;
+    while pos < data.len() {
+        if crc.is_some() {
+            return Err(DecodeError::DataAfter { after: "checksum" });
+        }
+        if mac.is_some() {
+            return Err(DecodeError::DataAfter { after: "MAC" });
+        }
+        if sig.is_some() {
+            return Err(DecodeError::DataAfter { after: "signature" });
+        }
+        if pos + 2 > data.len() {
+            return Err(DecodeError::TruncatedExtensionHeader);
+        }
+        let (ty, len) = (data[pos], data[pos + 1] as usize);
+        let value = data
+            .get(pos + 2..pos + 2 + len)
+            .ok_or(DecodeError::TruncatedExtension(ty))?;
+        match ty {
+            EXT_GROUP => {
+                let v: [u8; 2] = value
+                    .try_into()
+                    .map_err(|_| DecodeError::BadExtensionLength { name: "group", len })?;
+                packet.group = Some(u16::from_be_bytes(v));
+            }
+            EXT_EPOCH => {
+                packet.epoch = match value {
+                    [1] => Epoch::Ntp,
+                    [2] => Epoch::Gps,
+                    _ => return Err(DecodeError::UnknownEpoch),
+                };
+            }
+            EXT_MONO => {
+                let v: [u8; 8] = value
+                    .try_into()
+                    .map_err(|_| DecodeError::BadExtensionLength {
+                        name: "monotonic",
+                        len,
+                    })?;
+                packet.mono_us = Some(u64::from_be_bytes(v));
+            }
+            EXT_ERA => {
+                let v: [u8; 2] = value
+                    .try_into()
+                    .map_err(|_| DecodeError::BadExtensionLength { name: "era", len })?;
+                packet.era = Some(u16::from_be_bytes(v));
+            }
+            EXT_PRIORITY => {
+                let [v] = value else {
+                    return Err(DecodeError::BadExtensionLength {
+                        name: "priority",
+                        len,
+                    });
+                };
+                packet.priority = Some(*v);
+            }
+            EXT_CLOCK_FLAGS => {
+                let [v] = value else {
+                    return Err(DecodeError::BadExtensionLength {
+                        name: "clock flags",
+                        len,
+                    });
+                };
+                // Flags this version doesn't know are ignored
+                packet.unsynced = v & CLOCK_UNSYNCED != 0;
+            }
+            EXT_RELAY => {
+                if len != 3 {
+                    return Err(DecodeError::BadExtensionLength { name: "relay", len });
+                }
+                packet.relay = Some(Relay {
+                    hops: value[0],
+                    id: u16::from_be_bytes([value[1], value[2]]),
+                });
+            }
+            EXT_CONTROL => {
+                if len != 16 {
+                    return Err(DecodeError::BadExtensionLength {
+                        name: "control",
+                        len,
+                    });
+                }
+                packet.control = Some(Control {
+                    apply_at_ms: u64::from_be_bytes(value[..8].try_into().unwrap()),
+                    interval_ms: u64::from_be_bytes(value[8..].try_into().unwrap()),
+                });
+            }
+            EXT_FIELD => {
+                let field = std::str::from_utf8(value)
+                    .ok()
+                    .and_then(|f| f.split_once('='))
+                    .ok_or(DecodeError::BadField)?;
+                fields.push(field);
+            }
+            EXT_ECHO => {
+                if !(1..=MAX_ECHO_LEN).contains(&len) {
+                    return Err(DecodeError::BadExtensionLength { name: "echo", len });
+                }
+                echo = Some(value);
+            }
+            EXT_KEY_ID => {
+                let v: [u8; 2] = value
+                    .try_into()
+                    .map_err(|_| DecodeError::BadExtensionLength {
+                        name: "key id",
+                        len,
+                    })?;
+                key_id = Some(u16::from_be_bytes(v));
+            }
+            EXT_MAC => {
+                if len != MAC_LEN {
+                    return Err(DecodeError::BadExtensionLength { name: "MAC", len });
+                }
+                mac = Some((value, &data[..pos]));
+            }
+            EXT_SIG => {
+                if len != KEY_ID_LEN + SIGNATURE_LEN {
+                    return Err(DecodeError::BadExtensionLength {
+                        name: "signature",
+                        len,
+                    });
+                }
+                sig = Some((value, &data[..pos]));
+            }
+            EXT_CRC => {
+                let v: [u8; 4] = value
+                    .try_into()
+                    .map_err(|_| DecodeError::BadExtensionLength {
+                        name: "checksum",
+                        len,
+                    })?;
+                crc = Some((u32::from_be_bytes(v), &data[..pos]));
+            }
+            // Unknown extensions are skipped for forward compatibility
+            _ => {}
+        }
+        pos += 2 + len;
+    }
+    packet.ts_ms = packet
+        .epoch
+        .to_unix_ms(packet.ts_ms)
+        .ok_or(DecodeError::TimestampOutOfRange)?;
+
+    Ok(Decoded {
+        packet,
+        format: Format::V2,
+        mac,
+        key_id,
+        sig,
+        fields,
+        echo,
+        crc,
+    })
+}
+
+/// A wire format, for applications that embed the crate and need their
+/// own, say to carry application-specific fields, while reusing the send
+/// scheduler, sockets and receive statistics. Hand one to
+/// [`SendConfig::codec`](crate::send::SendConfig::codec) and
+/// [`ListenConfig::codec`](crate::listen::ListenConfig::codec).
+///
+/// The built-in formats implement it through [`Format`]. A codec replaces
+/// MACs and signatures too: senders with a codec don't apply `key` or
+/// `sign_key`, so authenticating payloads is up to the codec.
+pub trait PayloadCodec: Send + Sync {
+    /// Encodes `packet` with `fields` into `out`, replacing its contents.
+    fn encode(&self, packet: &Packet, fields: &[Field], out: &mut Vec<u8>);
+
+    /// Decodes a received datagram. [`Decoded::format`] should name the
+    /// built-in format whose conventions the payload follows: receivers
+    /// apply `--epoch` to the timestamp of [`Format::Legacy`] payloads,
+    /// which can't carry a scale. Errors of the codec's own go in
+    /// [`DecodeError::Codec`].
+    fn decode<'a>(&self, data: &'a [u8]) -> Result<Decoded<'a>, DecodeError>;
+}
+
+impl PayloadCodec for Format {
+    fn encode(&self, packet: &Packet, fields: &[Field], out: &mut Vec<u8>) {
+        encode_with_fields(*self, packet, fields, None, out);
+    }
+
+    /// Decodes any of the built-in formats, as [`decode`] does.
+    fn decode<'a>(&self, data: &'a [u8]) -> Result<Decoded<'a>, DecodeError> {
+        decode(data)
+    }
+}
+
+/// Copies a decoded, unauthenticated v2 or proto datagram into `out` with
+/// its relay extension replaced by `relay`. Other extensions, including
+/// unknown ones, are kept as they are, but for a checksum, which is made
+/// anew over the result.
+pub fn set_relay(data: &[u8], relay: Relay, out: &mut Vec<u8>) {
+    out.clear();
+    if proto::is_beacon(data) {
+        return proto::set_relay(data, relay, out);
+    }
+    out.extend_from_slice(&data[..V2_LEN]);
+    let mut checksummed = false;
+    let mut pos = V2_LEN;
+    while pos + 2 <= data.len() {
+        let end = (pos + 2 + data[pos + 1] as usize).min(data.len());
+        match data[pos] {
+            EXT_RELAY => {}
+            EXT_CRC => checksummed = true,
+            _ => out.extend_from_slice(&data[pos..end]),
+        }
+        pos = end;
+    }
+    push_ext(out, EXT_RELAY, &relay.to_bytes());
+    if checksummed {
+        append_crc(out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Rng;
+
+    /// A packet with each optional part present or not at random, and a
+    /// timestamp between 2001 and 2096 or, one time in eight, at an end of
+    /// the u64 range in the Unix scale.
+    fn random_packet(rng: &mut Rng) -> (Packet, Vec<Field>) {
+        let mut r = || rng.next_u64();
+        let present = r();
+        let has = |part: u32| present >> part & 1 == 1;
+        let extreme = [0, 1 << 63, u64::MAX][(r() % 3) as usize];
+        let (ts_ms, epoch) = match r() % 8 {
+            0 => (extreme, Epoch::Unix),
+            _ => (
+                1_000_000_000_000 + r() % 3_000_000_000_000,
+                [Epoch::Unix, Epoch::Ntp, Epoch::Gps][(r() % 3) as usize],
+            ),
+        };
+        let packet = Packet {
+            kind: MessageType(r() as u8),
+            group: has(0).then_some(r() as u16),
+            control: has(1).then_some(Control {
+                apply_at_ms: r(),
+                interval_ms: r(),
+            }),
+            mono_us: has(2).then(|| [0, 1 << 63, u64::MAX, r()][(r() % 4) as usize]),
+            era: has(3).then_some(r() as u16),
+            relay: has(4).then_some(Relay {
+                hops: r() as u8,
+                id: r() as u16,
+            }),
+            priority: has(5).then_some(r() as u8),
+            unsynced: has(6),
+            epoch,
+            ..Packet::new(Some(r() as u32), ts_ms)
+        };
+        let fields = (0..r() % 3)
+            .map(|i| Field::parse(&format!("k{i}=v{}", r() % 1000)).unwrap())
+            .collect();
+        (packet, fields)
+    }
+
+    #[test]
+    fn every_format_round_trips_random_packets() {
+        let mut rng = Rng::new(0x5eed);
+        let mut out = Vec::new();
+        for _ in 0..2000 {
+            let (packet, fields) = random_packet(&mut rng);
+            let sent: Vec<(&str, &str)> = fields
+                .iter()
+                .map(|f| (f.key.as_str(), f.value.as_str()))
+                .collect();
+            for format in [Format::V2, Format::Proto] {
+                encode_with_fields(format, &packet, &fields, None, &mut out);
+                let d = decode(&out).unwrap();
+                assert_eq!((d.format, d.packet), (format, packet));
+                assert_eq!(d.fields, sent);
+            }
+
+            // Legacy and compact payloads carry a Unix timestamp and, for
+            // compact, 16 bits of sequence number
+            let unix = Packet {
+                epoch: Epoch::Unix,
+                ..packet
+            };
+            encode(Format::Legacy, &unix, None, &mut out);
+            let d = decode(&out).unwrap();
+            assert_eq!(
+                (d.format, d.packet.ts_ms, d.packet.seq),
+                (Format::Legacy, unix.ts_ms, None)
+            );
+            encode(Format::Compact, &unix, None, &mut out);
+            let d = decode(&out).unwrap();
+            assert_eq!(
+                (d.format, d.packet.ts_ms, d.packet.seq),
+                (Format::Compact, unix.ts_ms, unix.seq.map(|s| s & 0xffff))
+            );
+        }
+    }
+
+    #[test]
+    fn extreme_timestamps_in_every_scale_decode_or_are_out_of_range() {
+        let mut out = Vec::new();
+        for epoch in [Epoch::Unix, Epoch::Ntp, Epoch::Gps] {
+            let packet = Packet {
+                epoch,
+                ..Packet::new(Some(1), 0)
+            };
+            for value in [0, 1, 1 << 63, u64::MAX - 1, u64::MAX] {
+                encode(Format::V2, &packet, None, &mut out);
+                out[8..16].copy_from_slice(&value.to_be_bytes());
+                match (decode(&out), epoch.to_unix_ms(value)) {
+                    (Ok(d), Some(ts_ms)) => assert_eq!(d.packet.ts_ms, ts_ms),
+                    (Err(e), None) => assert_eq!(e, DecodeError::TimestampOutOfRange),
+                    (d, ts_ms) => panic!("{epoch:?} {value}: {:?} {ts_ms:?}", d.err()),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mangled_datagrams_are_rejected_without_panicking() {
+        let mut rng = Rng::new(0xbad);
+        let key = Key::new(b"k");
+        let mut out = Vec::new();
+        for i in 0..500 {
+            let (packet, fields) = random_packet(&mut rng);
+            let format = [Format::V2, Format::Proto][i % 2];
+            encode_with_fields(format, &packet, &fields, Some(&key), &mut out);
+            for len in 0..out.len() {
+                // Built-in formats only fail with errors of their own
+                if let Err(e) = decode(&out[..len]) {
+                    assert!(!matches!(e, DecodeError::Codec(_)), "{e}");
+                }
+            }
+            for _ in 0..32 {
+                let mut mangled = out.clone();
+                let at = rng.next_u64() as usize % mangled.len();
+                mangled[at] ^= 1 << (rng.next_u64() % 8);
+                if let Ok(d) = decode(&mangled) {
+                    let _ = d.checksum_ok();
+                    // A v2 MAC covers every byte before it
+                    if d.mac.is_some() && at < out.len() - MAC_LEN - 2 {
+                        assert!(!d.verify(&key));
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,184 @@
+//! pcapng capture of everything received, for `recv --pcap`.
+//!
+//! The file holds one section with a single raw-IP interface, timestamped
+//! in nanoseconds (`if_tsresol` 9) from the kernel's receive time where
+//! available. As with `--capture-window`, IP and UDP headers are rebuilt
+//! around each payload, addressed to the receiving socket.
+//!
+//! Each Enhanced Packet Block whose payload decoded carries a custom
+//! option (code 2988, a UTF-8 string) reading `ts_ms=<ms>`: the payload's
+//! timestamp as Unix milliseconds, whatever `--epoch` it was sent in.
+//! Custom options are tagged with a Private Enterprise Number; none is
+//! registered for this tool, so [`PEN`] is the one RFC 5612 sets aside
+//! for documentation.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::capture::{ip_packet, LINKTYPE_RAW};
+
+/// Private Enterprise Number the custom option is tagged with.
+pub const PEN: u32 = 32473;
+
+const BLOCK_SHB: u32 = 0x0a0d_0d0a;
+const BLOCK_IDB: u32 = 1;
+const BLOCK_EPB: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_SHB_USERAPPL: u16 = 4;
+const OPT_IF_NAME: u16 = 2;
+const OPT_IF_TSRESOL: u16 = 9;
+/// Custom option holding a UTF-8 string, which may be copied with the packet.
+const OPT_CUSTOM_STR: u16 = 2988;
+
+/// Writes received datagrams to a pcapng file.
+pub struct PcapngWriter {
+    out: BufWriter<File>,
+    /// Address the datagrams were received on.
+    local: SocketAddr,
+}
+
+impl PcapngWriter {
+    pub fn create(path: &str, local: SocketAddr) -> Result<PcapngWriter, String> {
+        let fail = |e: io::Error| format!("Failed to write pcapng {path}: {e}");
+        let file =
+            File::create(path).map_err(|e| format!("Failed to create pcapng {path}: {e}"))?;
+        let mut out = BufWriter::new(file);
+        write_preamble(&mut out, local).map_err(fail)?;
+        out.flush().map_err(fail)?;
+        Ok(PcapngWriter { out, local })
+    }
+
+    /// Writes one datagram received at `at`, with the timestamp it carried
+    /// if it decoded. Each packet is flushed so that a killed receiver
+    /// leaves a usable file behind.
+    pub fn write(
+        &mut self,
+        at: SystemTime,
+        src: SocketAddr,
+        payload: &[u8],
+        ts_ms: Option<u64>,
+    ) -> io::Result<()> {
+        let packet = ip_packet(src, self.local, payload);
+        let ns = at
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        let mut body = Vec::with_capacity(20 + packet.len() + 32);
+        // Interface 0, then the timestamp's high and low halves
+        body.extend_from_slice(&0u32.to_le_bytes());
+        body.extend_from_slice(&((ns >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(ns as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        push_padded(&mut body, &packet);
+        if let Some(ts_ms) = ts_ms {
+            let value = [&PEN.to_le_bytes()[..], format!("ts_ms={ts_ms}").as_bytes()].concat();
+            push_option(&mut body, OPT_CUSTOM_STR, &value);
+            push_option(&mut body, OPT_ENDOFOPT, &[]);
+        }
+        write_block(&mut self.out, BLOCK_EPB, &body)?;
+        self.out.flush()
+    }
+}
+
+/// Writes the Section Header Block and the one Interface Description Block.
+fn write_preamble(out: &mut impl Write, local: SocketAddr) -> io::Result<()> {
+    let mut shb = Vec::new();
+    shb.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+    // Version 1.0, and a section length left unspecified
+    shb.extend_from_slice(&1u16.to_le_bytes());
+    shb.extend_from_slice(&0u16.to_le_bytes());
+    shb.extend_from_slice(&(-1i64).to_le_bytes());
+    let app = format!("udp_bcast_ts {}", env!("CARGO_PKG_VERSION"));
+    push_option(&mut shb, OPT_SHB_USERAPPL, app.as_bytes());
+    push_option(&mut shb, OPT_ENDOFOPT, &[]);
+    write_block(out, BLOCK_SHB, &shb)?;
+
+    let mut idb = Vec::new();
+    idb.extend_from_slice(&(LINKTYPE_RAW as u16).to_le_bytes());
+    idb.extend_from_slice(&0u16.to_le_bytes());
+    // Snapshot length
+    idb.extend_from_slice(&65_535u32.to_le_bytes());
+    push_option(&mut idb, OPT_IF_NAME, local.to_string().as_bytes());
+    push_option(&mut idb, OPT_IF_TSRESOL, &[9]);
+    push_option(&mut idb, OPT_ENDOFOPT, &[]);
+    write_block(out, BLOCK_IDB, &idb)
+}
+
+/// Writes a block of type `kind` around `body`, which must already be
+/// padded to 32 bits.
+fn write_block(out: &mut impl Write, kind: u32, body: &[u8]) -> io::Result<()> {
+    let len = (12 + body.len() as u32).to_le_bytes();
+    out.write_all(&kind.to_le_bytes())?;
+    out.write_all(&len)?;
+    out.write_all(body)?;
+    out.write_all(&len)
+}
+
+fn push_option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    push_padded(body, value);
+}
+
+fn push_padded(body: &mut Vec<u8>, data: &[u8]) {
+    body.extend_from_slice(data);
+    body.resize(body.len() + (4 - data.len() % 4) % 4, 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn u32_at(data: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn writes_blocks_with_nanosecond_times_and_the_payload_timestamp() {
+        let path = std::env::temp_dir().join(format!("udp_bcast_ts-{}.pcapng", std::process::id()));
+        let path = path.to_str().unwrap();
+        let local = "0.0.0.0:12321".parse().unwrap();
+        let src = "10.0.0.5:40000".parse().unwrap();
+        let at = UNIX_EPOCH + Duration::new(1_800_000_000, 123_456_789);
+        let mut w = PcapngWriter::create(path, local).unwrap();
+        w.write(at, src, b"12345678", Some(1_799_999_999_990))
+            .unwrap();
+        w.write(at, src, b"junk", None).unwrap();
+        drop(w);
+        let data = std::fs::read(path).unwrap();
+        let _ = std::fs::remove_file(path);
+
+        // Walk the blocks, checking each trailing length matches
+        let mut blocks = Vec::new();
+        let mut at_byte = 0;
+        while at_byte < data.len() {
+            let len = u32_at(&data, at_byte + 4) as usize;
+            assert_eq!(len % 4, 0);
+            assert_eq!(u32_at(&data, at_byte + len - 4) as usize, len);
+            blocks.push(&data[at_byte..at_byte + len]);
+            at_byte += len;
+        }
+        let kinds: Vec<u32> = blocks.iter().map(|b| u32_at(b, 0)).collect();
+        assert_eq!(kinds, [BLOCK_SHB, BLOCK_IDB, BLOCK_EPB, BLOCK_EPB]);
+        assert_eq!(u32_at(blocks[0], 8), BYTE_ORDER_MAGIC);
+
+        let epb = blocks[2];
+        let ns = (u32_at(epb, 12) as u64) << 32 | u32_at(epb, 16) as u64;
+        assert_eq!(ns, 1_800_000_000_123_456_789);
+        // 20 bytes of IPv4, 8 of UDP, then the payload
+        assert_eq!(u32_at(epb, 20), 36);
+        assert_eq!(&epb[28 + 28..28 + 36], b"12345678");
+        let opt = &epb[28 + 36..];
+        assert_eq!(u16::from_le_bytes([opt[0], opt[1]]), OPT_CUSTOM_STR);
+        assert_eq!(u32_at(opt, 4), PEN);
+        assert_eq!(u16::from_le_bytes([opt[2], opt[3]]), 4 + 19);
+        assert_eq!(&opt[8..27], b"ts_ms=1799999999990");
+        // No options on a datagram that didn't decode
+        assert_eq!(blocks[3].len(), 12 + 20 + 32);
+    }
+}
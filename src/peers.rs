@@ -0,0 +1,427 @@
+//! Unicast to an explicit list of peers, for networks that block
+//! broadcast.
+//!
+//! `send --peers FILE` sends every timestamp to each address listed in
+//! FILE. Each peer gets its own connected socket, so the ICMP errors a
+//! peer's host or router sends back are reported on that peer's next send
+//! rather than lost. Send errors are counted per peer; with a backoff, a
+//! peer reported unreachable is skipped for a while and then retried.
+//!
+//! Peers are sent to one after another, so a send that blocks, say on a
+//! full socket buffer, holds up the peers after it. With
+//! `--fanout-threads N` they are split between N worker threads instead,
+//! which send each tick's datagrams in parallel: a blocked send then holds
+//! up only the peers sharing its thread. The tick still waits for every
+//! send to finish, so counts and logs stay as without, and
+//! `--send-timeout-ms` bounds how long that takes. How long each peer's
+//! sends take is reported either way.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::exit::Failure;
+use crate::log;
+use crate::transmit::{self, DatagramSink};
+
+/// How often the per-peer counters are printed.
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Reads a peer list: one `host` or `host:port` per line, with `#`
+/// comments. Entries without a port use `default_port`.
+pub fn load(path: &str, default_port: u16) -> Result<Vec<SocketAddr>, String> {
+    let text =
+        fs::read_to_string(path).map_err(|e| format!("Failed to read peer list {path}: {e}"))?;
+    parse(&text, default_port).map_err(|e| format!("Invalid peer list {path}: {e}"))
+}
+
+fn parse(text: &str, default_port: u16) -> Result<Vec<SocketAddr>, String> {
+    let mut peers = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let entry = line.split('#').next().unwrap_or("").trim();
+        if entry.is_empty() {
+            continue;
+        }
+        let addr = if let Ok(addr) = entry.parse::<SocketAddr>() {
+            addr
+        } else if let Ok(ip) = entry.parse::<IpAddr>() {
+            SocketAddr::new(ip, default_port)
+        } else {
+            let resolved = match entry.rsplit_once(':') {
+                Some((host, port)) => port
+                    .parse::<u16>()
+                    .map_err(|_| format!("line {}: bad port '{port}'", n + 1))
+                    .and_then(|port| {
+                        (host, port)
+                            .to_socket_addrs()
+                            .map_err(|e| format!("line {}: {host}: {e}", n + 1))
+                    }),
+                None => (entry, default_port)
+                    .to_socket_addrs()
+                    .map_err(|e| format!("line {}: {entry}: {e}", n + 1)),
+            };
+            resolved?
+                .next()
+                .ok_or_else(|| format!("line {}: {entry} has no address", n + 1))?
+        };
+        if addr.port() == 0 {
+            return Err(format!("line {}: port 0", n + 1));
+        }
+        if !peers.contains(&addr) {
+            peers.push(addr);
+        }
+    }
+    if peers.is_empty() {
+        return Err("no peers".to_string());
+    }
+    Ok(peers)
+}
+
+/// One peer's socket and history.
+struct Peer {
+    sock: Arc<UdpSocket>,
+    /// The fan-out worker that sends to it.
+    shard: usize,
+    sent: u64,
+    errors: u64,
+    unreachable: u64,
+    /// Skipped until then, after an ICMP unreachable.
+    paused_until: Option<Instant>,
+    /// How long sends took since the last report.
+    sends: SendTimes,
+}
+
+/// Durations of the sends to one peer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SendTimes {
+    pub count: u64,
+    pub total: Duration,
+    pub max: Duration,
+}
+
+impl SendTimes {
+    fn add(&mut self, took: Duration) {
+        self.count += 1;
+        self.total += took;
+        self.max = self.max.max(took);
+    }
+
+    pub fn mean(&self) -> Duration {
+        match self.count {
+            0 => Duration::ZERO,
+            n => self.total / n as u32,
+        }
+    }
+}
+
+/// One datagram for a fan-out worker: its place in the batch, the peer's
+/// socket and the payload.
+type Job = (usize, Arc<UdpSocket>, Vec<u8>);
+
+/// What a worker did with a [`Job`], and how long it took.
+type Done = (usize, io::Result<usize>, Duration);
+
+/// Worker threads sending to shards of the peers.
+struct Fanout {
+    workers: Vec<Sender<Vec<Job>>>,
+    done: Receiver<Done>,
+}
+
+impl Fanout {
+    fn start(threads: usize) -> Fanout {
+        let (finished, done) = mpsc::channel();
+        let workers = (0..threads)
+            .map(|_| {
+                let (tx, jobs) = mpsc::channel::<Vec<Job>>();
+                let finished = finished.clone();
+                log::spawn(move || {
+                    for batch in jobs {
+                        for (i, sock, payload) in batch {
+                            let start = Instant::now();
+                            let result = transmit::send(&sock, &payload);
+                            if finished.send((i, result, start.elapsed())).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                });
+                tx
+            })
+            .collect();
+        Fanout { workers, done }
+    }
+}
+
+/// Sends to each peer through its own connected socket.
+pub struct PeerSink {
+    peers: BTreeMap<SocketAddr, Peer>,
+    /// How long to skip a peer reported unreachable; `None` keeps
+    /// sending to it.
+    backoff: Option<Duration>,
+    next_report: Instant,
+    fanout: Option<Fanout>,
+}
+
+impl PeerSink {
+    /// Connects a socket from `bind` to each peer. With `threads`, sends
+    /// are spread over that many worker threads.
+    pub fn connect(
+        addrs: &[SocketAddr],
+        backoff: Option<Duration>,
+        threads: Option<usize>,
+        bind: impl Fn(SocketAddr) -> Result<UdpSocket, Failure>,
+    ) -> Result<PeerSink, Failure> {
+        // No more workers than peers to keep busy
+        let threads = threads.map(|t| t.min(addrs.len()));
+        let mut peers = BTreeMap::new();
+        for (n, &addr) in addrs.iter().enumerate() {
+            let sock = bind(addr)?;
+            sock.connect(addr)
+                .map_err(|e| Failure::io(&e, format!("Failed to connect to peer {addr}: {e}")))?;
+            peers.insert(
+                addr,
+                Peer {
+                    sock: Arc::new(sock),
+                    shard: threads.map_or(0, |t| n % t),
+                    sent: 0,
+                    errors: 0,
+                    unreachable: 0,
+                    paused_until: None,
+                    sends: SendTimes::default(),
+                },
+            );
+        }
+        Ok(PeerSink {
+            peers,
+            backoff,
+            next_report: Instant::now() + REPORT_INTERVAL,
+            fanout: threads.map(Fanout::start),
+        })
+    }
+
+    /// One line of counters per peer, with how long its sends took since
+    /// the last report.
+    pub fn lines(&self, now: Instant) -> Vec<String> {
+        self.peers
+            .iter()
+            .map(|(addr, p)| {
+                let state = match p.paused_until {
+                    Some(until) if until > now => "paused",
+                    _ => "active",
+                };
+                format!(
+                    "Peer: addr={addr} state={state} sent={} errors={} unreachable={} send_us_mean={} send_us_max={}",
+                    p.sent,
+                    p.errors,
+                    p.unreachable,
+                    p.sends.mean().as_micros(),
+                    p.sends.max.as_micros()
+                )
+            })
+            .collect()
+    }
+
+    /// How long the sends to `dest` took since the last report.
+    pub fn send_times(&self, dest: SocketAddr) -> Option<SendTimes> {
+        self.peers.get(&dest).map(|p| p.sends)
+    }
+
+    /// Prints the peer lines if it's time to.
+    fn report(&mut self, now: Instant) {
+        if now < self.next_report {
+            return;
+        }
+        self.next_report = now + REPORT_INTERVAL;
+        for line in self.lines(now) {
+            println!("{line}");
+        }
+        for peer in self.peers.values_mut() {
+            peer.sends = SendTimes::default();
+        }
+    }
+
+    /// Counts the outcome of one send to `dest`, pausing it if the
+    /// failure calls for it.
+    fn settle(
+        &mut self,
+        dest: SocketAddr,
+        result: io::Result<usize>,
+        took: Duration,
+        now: Instant,
+    ) -> io::Result<usize> {
+        let backoff = self.backoff;
+        let Some(peer) = self.peers.get_mut(&dest) else {
+            return result;
+        };
+        peer.sends.add(took);
+        match result {
+            Ok(n) => {
+                peer.sent += 1;
+                Ok(n)
+            }
+            Err(e) => {
+                peer.errors += 1;
+                if is_unreachable(&e) {
+                    peer.unreachable += 1;
+                    if let Some(backoff) = backoff {
+                        peer.paused_until = Some(now + backoff);
+                        log::info!(
+                            "Peer {dest} unreachable ({e}); pausing for {}s (sent={} errors={})",
+                            backoff.as_secs(),
+                            peer.sent,
+                            peer.errors
+                        );
+                    }
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+fn not_a_peer() -> io::Error {
+    io::Error::new(ErrorKind::NotFound, "not a listed peer")
+}
+
+/// Errors that mean an ICMP unreachable came back for an earlier send.
+fn is_unreachable(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::HostUnreachable
+            | ErrorKind::NetworkUnreachable
+    )
+}
+
+impl DatagramSink for PeerSink {
+    fn send_to(&mut self, payload: &[u8], dest: SocketAddr) -> io::Result<usize> {
+        let now = Instant::now();
+        self.report(now);
+        let peer = self.peers.get(&dest).ok_or_else(not_a_peer)?;
+        let result = transmit::send(&peer.sock, payload);
+        self.settle(dest, result, now.elapsed(), now)
+    }
+
+    fn send_batch(&mut self, msgs: &[(&[u8], SocketAddr)]) -> Vec<io::Result<usize>> {
+        let Some(fanout) = &self.fanout else {
+            return msgs
+                .iter()
+                .map(|&(payload, dest)| self.send_to(payload, dest))
+                .collect();
+        };
+        let now = Instant::now();
+        let mut results: Vec<Option<io::Result<usize>>> = msgs.iter().map(|_| None).collect();
+        let mut shards: Vec<Vec<Job>> = fanout.workers.iter().map(|_| Vec::new()).collect();
+        for (i, &(payload, dest)) in msgs.iter().enumerate() {
+            match self.peers.get(&dest) {
+                Some(peer) => {
+                    shards[peer.shard].push((i, Arc::clone(&peer.sock), payload.to_vec()))
+                }
+                None => results[i] = Some(Err(not_a_peer())),
+            }
+        }
+        let mut waiting = 0;
+        for (worker, batch) in fanout.workers.iter().zip(shards) {
+            let len = batch.len();
+            if len > 0 && worker.send(batch).is_ok() {
+                waiting += len;
+            }
+        }
+        let mut finished = Vec::with_capacity(waiting);
+        for _ in 0..waiting {
+            match fanout.done.recv() {
+                Ok(done) => finished.push(done),
+                Err(_) => break,
+            }
+        }
+        self.report(now);
+        for (i, result, took) in finished {
+            results[i] = Some(self.settle(msgs[i].1, result, took, now));
+        }
+        results
+            .into_iter()
+            .map(|r| r.unwrap_or_else(|| Err(io::Error::other("fan-out worker exited"))))
+            .collect()
+    }
+
+    fn paused(&mut self, dest: SocketAddr) -> bool {
+        let Some(peer) = self.peers.get_mut(&dest) else {
+            return false;
+        };
+        match peer.paused_until {
+            Some(until) if Instant::now() < until => true,
+            Some(_) => {
+                peer.paused_until = None;
+                log::info!("Peer {dest} re-added");
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_peer_list() {
+        let text = "\
+# lab hosts
+10.0.0.5
+10.0.0.6:4000   # custom port
+[fe80::1%1]:5000
+
+10.0.0.5
+localhost:7
+";
+        let peers = parse(text, 12321).unwrap();
+        assert_eq!(
+            peers[..3],
+            [
+                "10.0.0.5:12321".parse().unwrap(),
+                "10.0.0.6:4000".parse().unwrap(),
+                "[fe80::1%1]:5000".parse().unwrap(),
+            ]
+        );
+        assert_eq!(peers.len(), 4);
+        assert_eq!(peers[3].port(), 7);
+        assert!(parse("# nothing\n", 12321).is_err());
+        assert_eq!(
+            parse("10.0.0.5:x\n", 12321).unwrap_err(),
+            "line 1: bad port 'x'"
+        );
+    }
+
+    #[test]
+    fn fans_out_over_threads() {
+        let receivers: Vec<_> = (0..3)
+            .map(|_| UdpSocket::bind("127.0.0.1:0").unwrap())
+            .collect();
+        let addrs: Vec<_> = receivers.iter().map(|r| r.local_addr().unwrap()).collect();
+        let mut sink = PeerSink::connect(&addrs, None, Some(2), |_| {
+            UdpSocket::bind("127.0.0.1:0").map_err(|e| Failure::io(&e, e.to_string()))
+        })
+        .unwrap();
+
+        let stranger = "127.0.0.1:9".parse().unwrap();
+        let mut msgs: Vec<(&[u8], SocketAddr)> = addrs.iter().map(|&a| (&b"tick"[..], a)).collect();
+        msgs.insert(1, (b"tick", stranger));
+        let results = sink.send_batch(&msgs);
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[1].as_ref().unwrap_err().kind(), ErrorKind::NotFound);
+        let mut buf = [0u8; 16];
+        // Results come back in the order given, the stranger's included
+        for (i, (r, at)) in receivers.iter().zip([0, 2, 3]).enumerate() {
+            assert_eq!(*results[at].as_ref().unwrap(), 4);
+            assert_eq!(r.recv(&mut buf).unwrap(), 4);
+            assert_eq!(sink.send_times(addrs[i]).unwrap().count, 1);
+        }
+        assert!(sink.lines(Instant::now())[0].contains(" sent=1 errors=0 "));
+    }
+}
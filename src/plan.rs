@@ -0,0 +1,159 @@
+//! What `send --dry-run` prints: the destinations, sockets and payload a
+//! sender resolved to, once everything up to the first send is done.
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use crate::compact;
+use crate::epoch::Epoch;
+use crate::payload::{self, Format};
+use crate::units;
+
+/// One destination and the socket that would send to it.
+pub struct Route {
+    pub dest: SocketAddr,
+    /// The socket's local address, and the source address the routing
+    /// table picks for `dest`.
+    pub bind: SocketAddr,
+    pub source: IpAddr,
+    pub interval: Duration,
+    pub ttl: Option<u32>,
+    /// The traffic class byte (IP_TOS/IPV6_TCLASS).
+    pub tos: Option<u8>,
+    pub sndbuf: Option<usize>,
+    /// The route's MTU, which each datagram must fit with its headers.
+    pub mtu: Option<u32>,
+}
+
+/// Everything a sender would do, short of doing it.
+pub struct Plan {
+    pub routes: Vec<Route>,
+    /// How sends are paced within and across ticks.
+    pub pacing: String,
+    /// The payload format, or `None` for an application's own codec.
+    pub format: Option<Format>,
+    pub epoch: Epoch,
+    /// A payload encoded as the first one would be.
+    pub sample: Vec<u8>,
+    /// NTP server the clock would be checked against.
+    pub clock_check: Option<String>,
+}
+
+/// Splits an encoded payload into its parts and their sizes.
+pub fn layout(format: Format, data: &[u8]) -> Vec<(&'static str, usize)> {
+    match format {
+        Format::Legacy => vec![("timestamp", data.len())],
+        Format::Proto => vec![("protobuf message", data.len())],
+        Format::Compact if data.len() == compact::FULL_LEN => {
+            vec![("sequence", 2), ("full every", 1), ("timestamp", 8)]
+        }
+        Format::Compact => vec![("sequence", 2), ("delta", data.len() - 2)],
+        Format::V2 => {
+            let mut parts = vec![("header", payload::V2_LEN.min(data.len()))];
+            let mut at = payload::V2_LEN;
+            while at + 2 <= data.len() {
+                let len = 2 + data[at + 1] as usize;
+                parts.push((extension_name(data[at]), len.min(data.len() - at)));
+                at += len;
+            }
+            parts
+        }
+    }
+}
+
+fn extension_name(kind: u8) -> &'static str {
+    match kind {
+        payload::EXT_GROUP => "group",
+        payload::EXT_MONO => "monotonic",
+        payload::EXT_RELAY => "relay",
+        payload::EXT_FIELD => "field",
+        payload::EXT_EPOCH => "epoch",
+        payload::EXT_ECHO => "echo",
+        payload::EXT_ERA => "era",
+        payload::EXT_PRIORITY => "priority",
+        payload::EXT_KEY_ID => "key id",
+        payload::EXT_MAC => "mac",
+        payload::EXT_SIG => "signature",
+        payload::EXT_CONTROL => "control",
+        _ => "unknown",
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Dry run: nothing will be sent")?;
+        for r in &self.routes {
+            writeln!(
+                f,
+                "  to {} every {}",
+                r.dest,
+                units::micros(r.interval.as_micros() as u64)
+            )?;
+            writeln!(f, "    bind {}, source {}", r.bind, r.source)?;
+            let unknown = || "?".to_string();
+            writeln!(
+                f,
+                "    ttl {}, dscp {}, send buffer {}, mtu {}",
+                r.ttl.map_or_else(unknown, |t| t.to_string()),
+                r.tos.map_or_else(unknown, |t| (t >> 2).to_string()),
+                r.sndbuf.map_or_else(unknown, |b| units::bytes(b as u64)),
+                r.mtu.map_or_else(unknown, |m| m.to_string())
+            )?;
+        }
+        writeln!(f, "  pacing: {}", self.pacing)?;
+        let parts = match self.format {
+            Some(format) => layout(format, &self.sample),
+            None => vec![("payload", self.sample.len())],
+        };
+        let parts: Vec<String> = parts
+            .iter()
+            .map(|(name, len)| format!("{name} {len}"))
+            .collect();
+        writeln!(
+            f,
+            "  payload: {}, {} epoch, {} bytes ({})",
+            match self.format {
+                Some(Format::Legacy) => "legacy",
+                Some(Format::V2) => "v2",
+                Some(Format::Proto) => "proto",
+                Some(Format::Compact) => "compact",
+                None => "custom codec",
+            },
+            self.epoch,
+            self.sample.len(),
+            parts.join(" + ")
+        )?;
+        if let Some(server) = &self.clock_check {
+            writeln!(f, "  clock check against {server}: skipped")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::{Field, Packet};
+
+    #[test]
+    fn lays_out_v2_extensions() {
+        let packet = Packet {
+            group: Some(3),
+            mono_us: Some(5),
+            ..Packet::new(Some(1), 1_700_000_000_000)
+        };
+        let fields = [Field::parse("site=a").unwrap()];
+        let mut out = Vec::new();
+        payload::encode_with_fields(Format::V2, &packet, &fields, None, &mut out);
+        let parts = layout(Format::V2, &out);
+        assert_eq!(parts[0], ("header", 16));
+        let names: Vec<&str> = parts.iter().map(|p| p.0).collect();
+        assert!(names.contains(&"group") && names.contains(&"monotonic"));
+        assert!(parts.contains(&("field", 2 + 6)));
+        assert_eq!(parts.iter().map(|p| p.1).sum::<usize>(), out.len());
+
+        payload::encode(Format::Legacy, &packet, None, &mut out);
+        assert_eq!(layout(Format::Legacy, &out), [("timestamp", 8)]);
+    }
+}
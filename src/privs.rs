@@ -0,0 +1,440 @@
+//! Giving up privileges once the sockets are open.
+//!
+//! Binding a port below 1024 and SCHED_FIFO need root (or
+//! CAP_NET_BIND_SERVICE and CAP_SYS_NICE), but nothing after them does.
+//! `--user` switches to an unprivileged user and group once everything
+//! the loop needs is bound, opened and tuned; the switch is checked by
+//! trying to get root back, and failing to make it is fatal.
+//!
+//! `--seccomp` (Linux) then installs a syscall allowlist covering the
+//! steady state: socket I/O, files, clocks, sleeping, memory and threads
+//! already running. Anything else, such as `execve` or `ptrace`, fails
+//! with EPERM instead of killing the process, so an optional feature that
+//! needs more logs an error and carries on without it. The filter is
+//! applied to every thread of the process, and `no_new_privs` is set
+//! first, so it can't be escaped through a set-user-ID program either.
+
+use std::fmt;
+
+use crate::exit::Failure;
+use crate::filter::Insn;
+use crate::log;
+
+/// A `--user USER[:GROUP]`, by name or numeric ID.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UserSpec {
+    pub user: String,
+    /// Group to switch to instead of the user's primary group.
+    pub group: Option<String>,
+}
+
+impl UserSpec {
+    pub fn parse(s: &str) -> Result<UserSpec, String> {
+        let (user, group) = match s.split_once(':') {
+            Some((u, g)) => (u, Some(g)),
+            None => (s, None),
+        };
+        if user.is_empty() || group.is_some_and(str::is_empty) {
+            return Err(format!("expected USER or USER:GROUP, got {s:?}"));
+        }
+        Ok(UserSpec {
+            user: user.to_string(),
+            group: group.map(str::to_string),
+        })
+    }
+}
+
+impl fmt::Display for UserSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.group {
+            Some(g) => write!(f, "{}:{g}", self.user),
+            None => f.write_str(&self.user),
+        }
+    }
+}
+
+/// What to give up before entering the main loop.
+#[derive(Clone, Debug, Default)]
+pub struct Hardening {
+    /// Switch to this user and group.
+    pub user: Option<UserSpec>,
+    /// Restrict the process to the syscalls the loop makes.
+    pub seccomp: bool,
+}
+
+impl Hardening {
+    /// Drops to `user`, then installs the seccomp filter. Call once every
+    /// socket and file the loop needs is open.
+    pub fn apply(&self) -> Result<(), Failure> {
+        if let Some(spec) = &self.user {
+            let ids = imp::switch_user(spec).map_err(|e| {
+                Failure::io(&e, format!("Failed to drop privileges to {spec}: {e}"))
+            })?;
+            log::info!(
+                "Dropped privileges: running as {spec} (uid={} gid={})",
+                ids.0,
+                ids.1
+            );
+        }
+        if self.seccomp {
+            let n = seccomp::install()
+                .map_err(|e| Failure::io(&e, format!("Failed to install seccomp filter: {e}")))?;
+            log::info!("Seccomp filter installed: {n} syscalls allowed");
+        }
+        Ok(())
+    }
+}
+
+/// The `seccomp_data` return values and field offsets the filter uses.
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+/// SECCOMP_RET_ERRNO with EPERM, which is 1 on every Linux architecture.
+const SECCOMP_RET_EPERM: u32 = 0x0005_0001;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+const DATA_NR: u32 = 0;
+const DATA_ARCH: u32 = 4;
+
+/// A filter allowing the syscalls numbered `allowed` on `arch` (an
+/// AUDIT_ARCH_* value), failing any other with EPERM and killing the
+/// process on a syscall made through a different ABI, whose numbers the
+/// list doesn't describe.
+pub fn seccomp_program(arch: u32, allowed: &[u32]) -> Option<Vec<Insn>> {
+    use crate::filter::{BPF_ABS, BPF_JEQ, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W};
+    let insn = |code, jt, jf, k| Insn { code, jt, jf, k };
+    let mut prog = vec![
+        insn(BPF_LD | BPF_W | BPF_ABS, 0, 0, DATA_ARCH),
+        insn(BPF_JMP | BPF_JEQ | BPF_K, 1, 0, arch),
+        insn(BPF_RET | BPF_K, 0, 0, SECCOMP_RET_KILL_PROCESS),
+        insn(BPF_LD | BPF_W | BPF_ABS, 0, 0, DATA_NR),
+    ];
+    for (i, &nr) in allowed.iter().enumerate() {
+        // Past the remaining comparisons and the EPERM return
+        let to_allow = u8::try_from(allowed.len() - i).ok()?;
+        prog.push(insn(BPF_JMP | BPF_JEQ | BPF_K, to_allow, 0, nr));
+    }
+    prog.push(insn(BPF_RET | BPF_K, 0, 0, SECCOMP_RET_EPERM));
+    prog.push(insn(BPF_RET | BPF_K, 0, 0, SECCOMP_RET_ALLOW));
+    Some(prog)
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::ffi::{CStr, CString};
+    use std::io;
+    use std::mem;
+    use std::ptr;
+
+    use super::UserSpec;
+
+    fn check(rc: libc::c_int) -> io::Result<()> {
+        if rc < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn not_found(what: &str, name: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::NotFound, format!("no such {what} {name:?}"))
+    }
+
+    /// Calls a getpwnam_r-style `call`, growing the string buffer until
+    /// the entry fits, and takes what's needed from the entry with
+    /// `extract` while the buffer it points into is alive. `None` if there
+    /// is no such entry.
+    fn lookup<T, R>(
+        mut call: impl FnMut(*mut T, *mut libc::c_char, usize, *mut *mut T) -> libc::c_int,
+        extract: impl FnOnce(&T) -> R,
+    ) -> io::Result<Option<R>> {
+        let mut buf = vec![0 as libc::c_char; 1024];
+        loop {
+            // SAFETY: all-zero is a valid passwd and group, and the lookup
+            // only fills it in.
+            let mut entry: T = unsafe { mem::zeroed() };
+            let mut found = ptr::null_mut();
+            match call(&mut entry, buf.as_mut_ptr(), buf.len(), &mut found) {
+                0 if found.is_null() => return Ok(None),
+                0 => return Ok(Some(extract(&entry))),
+                libc::ERANGE if buf.len() < 1 << 20 => buf.resize(buf.len() * 2, 0),
+                e => return Err(io::Error::from_raw_os_error(e)),
+            }
+        }
+    }
+
+    /// The user's name, ID and primary group.
+    fn user(name: &str) -> io::Result<(CString, libc::uid_t, libc::gid_t)> {
+        let c = CString::new(name).map_err(|_| not_found("user", name))?;
+        // SAFETY: pw_name points into the lookup's buffer, which is alive
+        // while `extract` runs.
+        let extract = |pw: &libc::passwd| {
+            (
+                unsafe { CStr::from_ptr(pw.pw_name) }.to_owned(),
+                pw.pw_uid,
+                pw.pw_gid,
+            )
+        };
+        // SAFETY (both): the buffer and entry outlive the call, and their
+        // sizes are passed alongside.
+        let pw = match name.parse::<libc::uid_t>() {
+            Ok(uid) => lookup(
+                |pw, buf, len, found| unsafe { libc::getpwuid_r(uid, pw, buf, len, found) },
+                extract,
+            )?,
+            Err(_) => lookup(
+                |pw, buf, len, found| unsafe { libc::getpwnam_r(c.as_ptr(), pw, buf, len, found) },
+                extract,
+            )?,
+        };
+        pw.ok_or_else(|| not_found("user", name))
+    }
+
+    fn group(name: &str) -> io::Result<libc::gid_t> {
+        if let Ok(gid) = name.parse() {
+            return Ok(gid);
+        }
+        let c = CString::new(name).map_err(|_| not_found("group", name))?;
+        // SAFETY: as in `user`.
+        let gr = lookup(
+            |gr, buf, len, found| unsafe { libc::getgrnam_r(c.as_ptr(), gr, buf, len, found) },
+            |gr: &libc::group| gr.gr_gid,
+        )?;
+        gr.ok_or_else(|| not_found("group", name))
+    }
+
+    /// Switches the whole process to `spec`, returning the new user and
+    /// group IDs.
+    pub fn switch_user(spec: &UserSpec) -> io::Result<(libc::uid_t, libc::gid_t)> {
+        let (name, uid, primary) = user(&spec.user)?;
+        let gid = spec.group.as_deref().map_or(Ok(primary), group)?;
+        // Supplementary groups: the user's own, or only GROUP when given
+        // SAFETY: plain calls with a live C string and a one-element list.
+        match spec.group {
+            None => check(unsafe { libc::initgroups(name.as_ptr(), gid as _) })?,
+            Some(_) => check(unsafe { libc::setgroups(1, &gid) })?,
+        }
+        // Group first: once the user changes, the group can't
+        // SAFETY: plain setgid(2) and setuid(2) calls; as root they set the
+        // real, effective and saved IDs alike.
+        check(unsafe { libc::setgid(gid) })?;
+        check(unsafe { libc::setuid(uid) })?;
+        // SAFETY: as above; this must fail now.
+        if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err(io::Error::other("root could be regained after setuid"));
+        }
+        Ok((uid, gid))
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use std::io;
+
+    use super::UserSpec;
+
+    pub fn switch_user(_spec: &UserSpec) -> io::Result<(u32, u32)> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "--user is only supported on Unix",
+        ))
+    }
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod seccomp {
+    use std::io;
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    /// What the send and receive loops, their helper threads and the
+    /// standard library need once running.
+    const ALLOWED: &[libc::c_long] = &[
+        // Sockets
+        libc::SYS_socket,
+        libc::SYS_connect,
+        libc::SYS_bind,
+        libc::SYS_accept4,
+        libc::SYS_shutdown,
+        libc::SYS_getsockname,
+        libc::SYS_getpeername,
+        libc::SYS_setsockopt,
+        libc::SYS_getsockopt,
+        libc::SYS_sendto,
+        libc::SYS_recvfrom,
+        libc::SYS_sendmsg,
+        libc::SYS_recvmsg,
+        libc::SYS_sendmmsg,
+        libc::SYS_recvmmsg,
+        libc::SYS_ppoll,
+        // Files
+        libc::SYS_openat,
+        libc::SYS_close,
+        libc::SYS_read,
+        libc::SYS_write,
+        libc::SYS_readv,
+        libc::SYS_writev,
+        libc::SYS_pread64,
+        libc::SYS_pwrite64,
+        libc::SYS_lseek,
+        libc::SYS_fstat,
+        libc::SYS_newfstatat,
+        libc::SYS_statx,
+        libc::SYS_fcntl,
+        libc::SYS_ioctl,
+        libc::SYS_fsync,
+        libc::SYS_fdatasync,
+        libc::SYS_mkdirat,
+        libc::SYS_renameat2,
+        libc::SYS_unlinkat,
+        // Clocks and sleeping
+        libc::SYS_clock_gettime,
+        libc::SYS_clock_getres,
+        libc::SYS_adjtimex,
+        libc::SYS_clock_adjtime,
+        libc::SYS_gettimeofday,
+        libc::SYS_nanosleep,
+        libc::SYS_clock_nanosleep,
+        libc::SYS_sched_yield,
+        libc::SYS_futex,
+        // Memory
+        libc::SYS_brk,
+        libc::SYS_mmap,
+        libc::SYS_munmap,
+        libc::SYS_mremap,
+        libc::SYS_mprotect,
+        libc::SYS_madvise,
+        // Signals and exiting
+        libc::SYS_rt_sigaction,
+        libc::SYS_rt_sigprocmask,
+        libc::SYS_rt_sigreturn,
+        libc::SYS_sigaltstack,
+        libc::SYS_restart_syscall,
+        libc::SYS_getpid,
+        libc::SYS_gettid,
+        libc::SYS_tgkill,
+        libc::SYS_exit,
+        libc::SYS_exit_group,
+        // A helper thread started just before the filter may still be
+        // setting itself up under it
+        libc::SYS_rseq,
+        libc::SYS_set_robust_list,
+        // Odds and ends
+        libc::SYS_getrandom,
+        libc::SYS_uname,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_poll,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_rename,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_renameat,
+    ];
+
+    /// Sets no_new_privs and installs the filter on every thread,
+    /// returning how many syscalls it allows.
+    pub fn install() -> io::Result<usize> {
+        let allowed: Vec<u32> = ALLOWED.iter().map(|&nr| nr as u32).collect();
+        let prog = super::seccomp_program(AUDIT_ARCH, &allowed)
+            .ok_or_else(|| io::Error::other("allowlist too long"))?;
+        let mut filter: Vec<libc::sock_filter> = prog
+            .iter()
+            .map(|i| libc::sock_filter {
+                code: i.code,
+                jt: i.jt,
+                jf: i.jf,
+                k: i.k,
+            })
+            .collect();
+        let fprog = libc::sock_fprog {
+            len: filter.len() as libc::c_ushort,
+            filter: filter.as_mut_ptr(),
+        };
+        // SAFETY: plain prctl(2) call.
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fprog` points at `filter`, which outlives the call; the
+        // kernel copies the program.
+        let rc = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                libc::SECCOMP_FILTER_FLAG_TSYNC,
+                &fprog as *const libc::sock_fprog,
+            )
+        };
+        match rc {
+            0 => Ok(allowed.len()),
+            rc if rc < 0 => Err(io::Error::last_os_error()),
+            // TSYNC names a thread it couldn't apply the filter to
+            tid => Err(io::Error::other(format!(
+                "thread {tid} could not be synchronised"
+            ))),
+        }
+    }
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+mod seccomp {
+    use std::io;
+
+    pub fn install() -> io::Result<usize> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "--seccomp is only supported on Linux on x86_64 and aarch64",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `prog` the way the kernel would on a syscall.
+    fn run(prog: &[Insn], arch: u32, nr: u32) -> u32 {
+        let (mut a, mut pc) = (0u32, 0usize);
+        loop {
+            let i = prog[pc];
+            pc += 1;
+            match i.code {
+                0x20 if i.k == DATA_ARCH => a = arch,
+                0x20 if i.k == DATA_NR => a = nr,
+                0x15 => pc += usize::from(if a == i.k { i.jt } else { i.jf }),
+                0x06 => return i.k,
+                code => panic!("unexpected opcode {code:#x}"),
+            }
+        }
+    }
+
+    #[test]
+    fn seccomp_allows_only_the_listed_syscalls() {
+        let arch = 0xc000_003e;
+        let prog = seccomp_program(arch, &[0, 1, 44, 202]).unwrap();
+        for nr in [0, 1, 44, 202] {
+            assert_eq!(run(&prog, arch, nr), SECCOMP_RET_ALLOW);
+        }
+        // execve
+        assert_eq!(run(&prog, arch, 59), SECCOMP_RET_EPERM);
+        // The same number through the i386 ABI
+        assert_eq!(run(&prog, 0x4000_0003, 1), SECCOMP_RET_KILL_PROCESS);
+        // Jumps only reach 255 instructions ahead
+        assert!(seccomp_program(arch, &[0; 300]).is_none());
+
+        assert_eq!(
+            UserSpec::parse("nobody:nogroup"),
+            Ok(UserSpec {
+                user: "nobody".into(),
+                group: Some("nogroup".into()),
+            })
+        );
+        assert_eq!(UserSpec::parse("65534").unwrap().group, None);
+        assert!(UserSpec::parse(":wheel").is_err());
+        assert!(UserSpec::parse("nobody:").is_err());
+    }
+}
@@ -0,0 +1,387 @@
+//! The `proto` payload: each datagram is one protocol buffers `Beacon`
+//! message, as described by [`SCHEMA`] (`proto/udp_bcast_ts.proto`), so
+//! that services written in other languages can decode it with generated
+//! code instead of parsing the v2 layout by hand.
+//!
+//! The message is small and fixed, so it is encoded and decoded here
+//! directly rather than through generated Rust code. A `Beacon` starts
+//! with its `magic` field, as every generated encoder writes fields in
+//! field-number order; that first field's five bytes tell it apart from
+//! the legacy and v2 formats sharing the port. Unknown fields are skipped.
+//! There is no room for a MAC or signature: authenticated payloads need
+//! `--format v2`.
+
+use crate::epoch::Epoch;
+use crate::message::MessageType;
+use crate::payload::{Control, DecodeError, Field, Packet, Relay};
+
+/// The schema the payload follows, for publishing alongside a deployment.
+pub const SCHEMA: &str = include_str!("../proto/udp_bcast_ts.proto");
+
+/// Value of `Beacon.magic`.
+pub const MAGIC: u32 = 0x4250_5354;
+
+/// How every `Beacon` starts: the tag of field 1 as a fixed32, then
+/// [`MAGIC`].
+const PREFIX: [u8; 5] = [0x0d, b'T', b'S', b'P', b'B'];
+
+const VARINT: u8 = 0;
+const I64: u8 = 1;
+const LEN: u8 = 2;
+const I32: u8 = 5;
+
+const BEACON_MAGIC: u32 = 1;
+const BEACON_KIND: u32 = 2;
+const BEACON_SEQ: u32 = 3;
+const BEACON_TS_MS: u32 = 4;
+const BEACON_GROUP: u32 = 5;
+const BEACON_MONO_US: u32 = 6;
+const BEACON_RELAY: u32 = 7;
+const BEACON_CONTROL: u32 = 8;
+const BEACON_FIELDS: u32 = 9;
+const BEACON_NTP_TIME: u32 = 10;
+const BEACON_GPS_MS: u32 = 11;
+const BEACON_ERA: u32 = 12;
+const BEACON_PRIORITY: u32 = 13;
+const BEACON_UNSYNCED: u32 = 14;
+
+/// Whether `data` is meant to be a `Beacon`.
+pub fn is_beacon(data: &[u8]) -> bool {
+    data.starts_with(&PREFIX)
+}
+
+fn put_varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn put_tag(out: &mut Vec<u8>, field: u32, wire: u8) {
+    put_varint(out, u64::from(field) << 3 | u64::from(wire));
+}
+
+fn put_uint(out: &mut Vec<u8>, field: u32, v: u64) {
+    put_tag(out, field, VARINT);
+    put_varint(out, v);
+}
+
+fn put_bytes(out: &mut Vec<u8>, field: u32, v: &[u8]) {
+    put_tag(out, field, LEN);
+    put_varint(out, v.len() as u64);
+    out.extend_from_slice(v);
+}
+
+fn relay_message(relay: Relay) -> Vec<u8> {
+    let mut m = Vec::with_capacity(6);
+    put_uint(&mut m, 1, u64::from(relay.hops));
+    put_uint(&mut m, 2, u64::from(relay.id));
+    m
+}
+
+/// Appends `packet` and `fields` to `out` as a `Beacon`.
+pub fn encode(packet: &Packet, fields: &[Field], out: &mut Vec<u8>) {
+    put_tag(out, BEACON_MAGIC, I32);
+    out.extend_from_slice(&MAGIC.to_le_bytes());
+    // Zero is the default, left out as proto3 does
+    if packet.kind != MessageType::DATA {
+        put_uint(out, BEACON_KIND, u64::from(packet.kind.0));
+    }
+    if let Some(seq) = packet.seq {
+        put_uint(out, BEACON_SEQ, u64::from(seq));
+    }
+    // The time goes in whichever member of the `time` oneof its scale has
+    let ts = packet.epoch.value_of(packet.ts_ms);
+    if packet.epoch == Epoch::Unix {
+        put_tag(out, BEACON_TS_MS, I64);
+        out.extend_from_slice(&ts.to_le_bytes());
+    }
+    if let Some(group) = packet.group {
+        put_uint(out, BEACON_GROUP, u64::from(group));
+    }
+    if let Some(mono_us) = packet.mono_us {
+        put_uint(out, BEACON_MONO_US, mono_us);
+    }
+    if let Some(relay) = packet.relay {
+        put_bytes(out, BEACON_RELAY, &relay_message(relay));
+    }
+    if let Some(control) = packet.control {
+        let mut m = Vec::with_capacity(22);
+        put_uint(&mut m, 1, control.apply_at_ms);
+        put_uint(&mut m, 2, control.interval_ms);
+        put_bytes(out, BEACON_CONTROL, &m);
+    }
+    for field in fields {
+        let mut m = Vec::with_capacity(field.key.len() + field.value.len() + 4);
+        put_bytes(&mut m, 1, field.key.as_bytes());
+        put_bytes(&mut m, 2, field.value.as_bytes());
+        put_bytes(out, BEACON_FIELDS, &m);
+    }
+    let field = match packet.epoch {
+        Epoch::Unix => None,
+        Epoch::Ntp => Some(BEACON_NTP_TIME),
+        Epoch::Gps => Some(BEACON_GPS_MS),
+    };
+    if let Some(field) = field {
+        put_tag(out, field, I64);
+        out.extend_from_slice(&ts.to_le_bytes());
+    }
+    if let Some(era) = packet.era {
+        put_uint(out, BEACON_ERA, u64::from(era));
+    }
+    if let Some(priority) = packet.priority {
+        put_uint(out, BEACON_PRIORITY, u64::from(priority));
+    }
+    if packet.unsynced {
+        put_uint(out, BEACON_UNSYNCED, 1);
+    }
+}
+
+/// One field's value, by wire type.
+#[derive(Clone, Copy)]
+enum Value<'a> {
+    Varint(u64),
+    I64(u64),
+    Len(&'a [u8]),
+    I32(u32),
+}
+
+/// Walks the fields of one message.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data, pos: 0 }
+    }
+
+    fn varint(&mut self) -> Result<u64, DecodeError> {
+        let mut v = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = *self.data.get(self.pos).ok_or(DecodeError::ProtoTruncated)?;
+            self.pos += 1;
+            v |= u64::from(b & 0x7f) << shift;
+            if b < 0x80 {
+                return Ok(v);
+            }
+        }
+        Err(DecodeError::ProtoVarintTooLong)
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        let bytes = self
+            .pos
+            .checked_add(n)
+            .and_then(|end| self.data.get(self.pos..end))
+            .ok_or(DecodeError::ProtoTruncated)?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    /// The next field number and value, and where the field started.
+    fn field(&mut self) -> Result<Option<(u32, Value<'a>, usize)>, DecodeError> {
+        if self.pos == self.data.len() {
+            return Ok(None);
+        }
+        let start = self.pos;
+        let tag = self.varint()?;
+        let number = u32::try_from(tag >> 3).map_err(|_| DecodeError::ProtoBadFieldNumber)?;
+        let value = match (tag & 7) as u8 {
+            VARINT => Value::Varint(self.varint()?),
+            I64 => Value::I64(u64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+            LEN => {
+                let len =
+                    usize::try_from(self.varint()?).map_err(|_| DecodeError::ProtoTruncated)?;
+                Value::Len(self.take(len)?)
+            }
+            I32 => Value::I32(u32::from_le_bytes(self.take(4)?.try_into().unwrap())),
+            wire => {
+                return Err(DecodeError::ProtoUnsupportedWireType {
+                    wire,
+                    field: number,
+                })
+            }
+        };
+        Ok(Some((number, value, start)))
+    }
+}
+
+fn uint<T: TryFrom<u64>>(v: Value, field: &'static str) -> Result<T, DecodeError> {
+    match v {
+        Value::Varint(value) => {
+            T::try_from(value).map_err(|_| DecodeError::ProtoOutOfRange { field, value })
+        }
+        _ => Err(DecodeError::ProtoWrongWireType { field }),
+    }
+}
+
+fn message<'a>(v: Value<'a>, field: &'static str) -> Result<Reader<'a>, DecodeError> {
+    match v {
+        Value::Len(m) => Ok(Reader::new(m)),
+        _ => Err(DecodeError::ProtoWrongWireType { field }),
+    }
+}
+
+fn string<'a>(v: Value<'a>, field: &'static str) -> Result<&'a str, DecodeError> {
+    match v {
+        Value::Len(s) => std::str::from_utf8(s).map_err(|_| DecodeError::ProtoNotUtf8 { field }),
+        _ => Err(DecodeError::ProtoWrongWireType { field }),
+    }
+}
+
+/// A decoded `Beacon` and its `(key, value)` fields.
+pub type Beacon<'a> = (Packet, Vec<(&'a str, &'a str)>);
+
+/// Decodes a `Beacon`.
+pub fn decode(data: &[u8]) -> Result<Beacon<'_>, DecodeError> {
+    let mut packet = Packet::new(None, 0);
+    let mut magic = None;
+    let mut fields = Vec::new();
+    let mut r = Reader::new(data);
+    while let Some((number, v, _)) = r.field()? {
+        match number {
+            BEACON_MAGIC => match v {
+                Value::I32(m) => magic = Some(m),
+                _ => return Err(DecodeError::ProtoWrongWireType { field: "magic" }),
+            },
+            BEACON_KIND => packet.kind = MessageType(uint(v, "kind")?),
+            BEACON_SEQ => packet.seq = Some(uint(v, "seq")?),
+            // Members of the `time` oneof: the last one wins
+            BEACON_TS_MS | BEACON_NTP_TIME | BEACON_GPS_MS => match v {
+                Value::I64(ts) => {
+                    packet.ts_ms = ts;
+                    packet.epoch = match number {
+                        BEACON_NTP_TIME => Epoch::Ntp,
+                        BEACON_GPS_MS => Epoch::Gps,
+                        _ => Epoch::Unix,
+                    };
+                }
+                _ => {
+                    return Err(DecodeError::ProtoWrongWireType {
+                        field: "the timestamp",
+                    })
+                }
+            },
+            BEACON_GROUP => packet.group = Some(uint(v, "group")?),
+            BEACON_MONO_US => packet.mono_us = Some(uint(v, "mono_us")?),
+            BEACON_ERA => packet.era = Some(uint(v, "era")?),
+            BEACON_PRIORITY => packet.priority = Some(uint(v, "priority")?),
+            BEACON_UNSYNCED => packet.unsynced = uint::<u64>(v, "unsynced")? != 0,
+            BEACON_RELAY => {
+                let mut relay = Relay { hops: 0, id: 0 };
+                let mut m = message(v, "relay")?;
+                while let Some((n, v, _)) = m.field()? {
+                    match n {
+                        1 => relay.hops = uint(v, "relay.hops")?,
+                        2 => relay.id = uint(v, "relay.id")?,
+                        _ => {}
+                    }
+                }
+                packet.relay = Some(relay);
+            }
+            BEACON_CONTROL => {
+                let mut control = Control {
+                    apply_at_ms: 0,
+                    interval_ms: 0,
+                };
+                let mut m = message(v, "control")?;
+                while let Some((n, v, _)) = m.field()? {
+                    match n {
+                        1 => control.apply_at_ms = uint(v, "control.apply_at_ms")?,
+                        2 => control.interval_ms = uint(v, "control.interval_ms")?,
+                        _ => {}
+                    }
+                }
+                packet.control = Some(control);
+            }
+            BEACON_FIELDS => {
+                let (mut key, mut value) = ("", "");
+                let mut m = message(v, "field")?;
+                while let Some((n, v, _)) = m.field()? {
+                    match n {
+                        1 => key = string(v, "field key")?,
+                        2 => value = string(v, "field value")?,
+                        _ => {}
+                    }
+                }
+                fields.push((key, value));
+            }
+            // Unknown fields are skipped for forward compatibility
+            _ => {}
+        }
+    }
+    if magic != Some(MAGIC) {
+        return Err(DecodeError::ProtoMissingMagic);
+    }
+    packet.ts_ms = packet
+        .epoch
+        .to_unix_ms(packet.ts_ms)
+        .ok_or(DecodeError::TimestampOutOfRange)?;
+    Ok((packet, fields))
+}
+
+/// Copies a `Beacon` into `out` with its relay field replaced by `relay`.
+/// Other fields, including unknown ones, are kept as they are; a field
+/// that can't be parsed ends the copy.
+pub fn set_relay(data: &[u8], relay: Relay, out: &mut Vec<u8>) {
+    let mut r = Reader::new(data);
+    let mut start = 0;
+    while let Ok(Some((number, _, at))) = r.field() {
+        if number == BEACON_RELAY {
+            out.extend_from_slice(&data[start..at]);
+            start = r.pos;
+        }
+    }
+    out.extend_from_slice(&data[start..r.pos]);
+    put_bytes(out, BEACON_RELAY, &relay_message(relay));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_a_beacon_in_protobuf_encoding() {
+        let packet = Packet {
+            group: Some(300),
+            mono_us: Some(150),
+            ..Packet::new(Some(0), 1_700_000_000_123)
+        };
+        let fields = [Field::parse("site=lab1").unwrap()];
+        let mut out = Vec::new();
+        encode(&packet, &fields, &mut out);
+        // What protoc --decode would show as magic, seq: 0, ts_ms,
+        // group: 300, mono_us: 150 and one field
+        assert_eq!(
+            out,
+            [
+                &[0x0d, b'T', b'S', b'P', b'B', 0x18, 0x00, 0x21][..],
+                &1_700_000_000_123u64.to_le_bytes(),
+                &[0x28, 0xac, 0x02, 0x30, 0x96, 0x01],
+                &[0x4a, 0x0c, 0x0a, 0x04, b's', b'i', b't', b'e'],
+                &[0x12, 0x04, b'l', b'a', b'b', b'1'],
+            ]
+            .concat()
+        );
+        assert!(is_beacon(&out));
+        assert_eq!(decode(&out).unwrap(), (packet, vec![("site", "lab1")]));
+
+        // Relaying replaces the relay field and keeps unknown ones
+        put_uint(&mut out, 99, 7);
+        let mut relayed = Vec::new();
+        set_relay(&out, Relay { hops: 1, id: 7 }, &mut relayed);
+        let mut again = Vec::new();
+        set_relay(&relayed, Relay { hops: 2, id: 9 }, &mut again);
+        assert_eq!(again.len(), relayed.len());
+        let (p, _) = decode(&again).unwrap();
+        assert_eq!(p.relay, Some(Relay { hops: 2, id: 9 }));
+        assert!(again.ends_with(&[0x3a, 0x04, 0x08, 0x02, 0x10, 0x09]));
+
+        assert!(decode(&out[..out.len() - 4]).is_err());
+        assert!(decode(&out[5..]).is_err());
+        assert!(SCHEMA.contains("message Beacon"));
+    }
+}
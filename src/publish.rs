@@ -0,0 +1,514 @@
+//! Republishing accepted timestamps to local consumers (`recv --publish`).
+//!
+//! Only one process can bind the beacon port without `--reuse-port`, and
+//! every one that does decodes and checks each datagram again. A receiver
+//! with `--publish` hands every accepted timestamp on instead, as one JSON
+//! object per line, to any number of local processes:
+//!
+//! ```text
+//! {"sender":"10.0.0.5/3","src":"10.0.0.5:40512","group":3,"seq":1500,"send_ms":1772366400247,"recv_ms":1772366400250,"offset_ms":3,"host_delay_us":41,"lost":0,"annotation":null,"fields":{"site":"lab"}}
+//! ```
+//!
+//! - `unix:PATH` listens on a Unix stream socket; each client that connects
+//!   gets the lines from then on.
+//! - `fifo:PATH` writes to a named pipe, created if missing, while a reader
+//!   has it open. Readers of one pipe share the lines between them, so a
+//!   pipe serves one consumer.
+//! - `zmq:tcp://ADDR:PORT` binds a ZeroMQ PUB socket, speaking ZMTP 3.0
+//!   with the NULL mechanism, so any ZeroMQ SUB socket can connect. Each
+//!   message has two frames: the sender as the topic, then the JSON.
+//!   Subscriptions are left to the subscriber to apply.
+//!
+//! As with the web dashboard, one thread per target does the writing: the
+//! receive loop queues lines without waiting, and a consumer that can't
+//! keep up, or a full queue, costs lines rather than loop time.
+//!
+//! `--stream ndjson` writes the same lines to stdout instead, for a pipe
+//! into `jq`, Vector or Fluent Bit. There the receive loop writes each line
+//! and flushes it itself: a pipe has one reader, which should see every
+//! line, and one that falls behind holds the loop up until the socket's
+//! receive buffer overflows.
+
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::thread;
+use std::time::Duration;
+
+use crate::export::Observation;
+use crate::log;
+use crate::status::json_string;
+use crate::transmit;
+
+/// Clients served at once by each target.
+pub const MAX_CLIENTS: usize = 16;
+/// Lines queued for a target's thread before new ones are dropped.
+const QUEUE: usize = 4096;
+/// Longest a ZeroMQ peer may take over its greeting.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Longest a write to a client may block.
+const WRITE_TIMEOUT: Duration = Duration::from_millis(250);
+/// How often a thread checks for new clients, or a reader of its pipe.
+const POLL: Duration = Duration::from_millis(100);
+
+/// Where to publish (`--publish`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    Unix(String),
+    Fifo(String),
+    Zmq(SocketAddr),
+}
+
+impl Target {
+    pub fn parse(s: &str) -> Result<Target, String> {
+        match s.split_once(':') {
+            Some(("unix", path)) if !path.is_empty() => Ok(Target::Unix(path.to_string())),
+            Some(("fifo", path)) if !path.is_empty() => Ok(Target::Fifo(path.to_string())),
+            Some(("zmq", endpoint)) => {
+                let addr = endpoint
+                    .strip_prefix("tcp://")
+                    .ok_or("expected zmq:tcp://ADDR:PORT")?;
+                addr.parse()
+                    .map(Target::Zmq)
+                    .map_err(|_| format!("invalid ZeroMQ address '{addr}', expected ADDR:PORT"))
+            }
+            _ => Err("expected unix:PATH, fifo:PATH or zmq:tcp://ADDR:PORT".to_string()),
+        }
+    }
+}
+
+/// What `--stream` writes to stdout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamFormat {
+    /// One JSON object per line, as published.
+    Ndjson,
+}
+
+impl StreamFormat {
+    pub fn parse(s: &str) -> Result<StreamFormat, String> {
+        match s {
+            "ndjson" => Ok(StreamFormat::Ndjson),
+            _ => Err("expected ndjson".to_string()),
+        }
+    }
+}
+
+/// The receive loop's stream on stdout.
+pub struct Stream<W: Write> {
+    out: W,
+}
+
+impl<W: Write> Stream<W> {
+    /// Writes to `out`, which should be stdout once [`log::reserve_stdout`]
+    /// has moved log lines off it.
+    pub fn new(_format: StreamFormat, out: W) -> Stream<W> {
+        Stream { out }
+    }
+
+    /// Writes and flushes the line for `row`.
+    pub fn write(&mut self, row: &Observation, sender: &str, lost: u64) -> io::Result<()> {
+        writeln!(self.out, "{}", json(row, sender, lost))?;
+        self.out.flush()
+    }
+}
+
+/// One accepted timestamp: the sender, as the topic, and its JSON line.
+struct Line {
+    sender: String,
+    json: String,
+}
+
+/// The JSON object published for `row`.
+pub(crate) fn json(row: &Observation, sender: &str, lost: u64) -> String {
+    let opt = |v: Option<String>| v.unwrap_or_else(|| "null".to_string());
+    let fields: Vec<String> = row
+        .fields
+        .iter()
+        .map(|f| {
+            let (k, v) = f.split_once('=').unwrap_or((f, ""));
+            format!("{}:{}", json_string(k), json_string(v))
+        })
+        .collect();
+    format!(
+        "{{\"sender\":{},\"src\":\"{}\",\"group\":{},\"seq\":{},\"send_ms\":{},\"recv_ms\":{},\"offset_ms\":{},\"host_delay_us\":{},\"lost\":{lost},\"annotation\":{},\"fields\":{{{}}}}}",
+        json_string(sender),
+        row.src,
+        opt(row.group.map(|g| g.to_string())),
+        opt(row.seq.map(|s| s.to_string())),
+        row.send_ms,
+        row.recv_ms,
+        row.offset_ms,
+        opt(row.host_delay_us.map(|us| us.to_string())),
+        opt(row.annotation.as_deref().map(json_string)),
+        fields.join(",")
+    )
+}
+
+/// The receive loop's side of one target.
+pub struct Publisher {
+    tx: SyncSender<Line>,
+}
+
+impl Publisher {
+    /// Sets up `target` and starts its thread.
+    pub fn open(target: &Target) -> Result<Publisher, String> {
+        let (tx, rx) = mpsc::sync_channel(QUEUE);
+        match target {
+            #[cfg(unix)]
+            Target::Unix(path) => {
+                let listener = unix::listen(path)?;
+                log::info!("Publishing to unix:{path}");
+                thread::spawn(move || serve(rx, || unix::accept(&listener)));
+            }
+            #[cfg(unix)]
+            Target::Fifo(path) => {
+                unix::make_fifo(path)?;
+                log::info!("Publishing to fifo:{path}");
+                let path = path.clone();
+                thread::spawn(move || unix::feed_fifo(&path, rx));
+            }
+            #[cfg(not(unix))]
+            Target::Unix(_) | Target::Fifo(_) => {
+                return Err("--publish unix: and fifo: are only supported on Unix".to_string())
+            }
+            Target::Zmq(_) if transmit::is_disabled() => {
+                return Err("transmission is disabled (--observe-only)".to_string());
+            }
+            Target::Zmq(addr) => {
+                let listener = TcpListener::bind(addr)
+                    .and_then(|l| l.set_nonblocking(true).map(|()| l))
+                    .map_err(|e| {
+                        format!("Failed to listen for ZeroMQ subscribers on {addr}: {e}")
+                    })?;
+                let local = listener.local_addr().unwrap_or(*addr);
+                log::info!("Publishing to zmq:tcp://{local}");
+                thread::spawn(move || serve(rx, || zmtp::accept(&listener)));
+            }
+        }
+        Ok(Publisher { tx })
+    }
+
+    /// Queues an accepted timestamp from `sender`, `lost` being the
+    /// sequence numbers it skipped to get there.
+    pub fn publish(&self, row: &Observation, sender: &str, lost: u64) {
+        let _ = self.tx.try_send(Line {
+            sender: sender.to_string(),
+            json: json(row, sender, lost),
+        });
+    }
+}
+
+/// A connected consumer.
+trait Client {
+    fn send(&mut self, line: &Line) -> io::Result<()>;
+}
+
+/// Hands every line to each client `accept` yields, dropping those that
+/// fail, until the receive loop ends.
+fn serve<C: Client>(rx: Receiver<Line>, mut accept: impl FnMut() -> io::Result<Option<C>>) {
+    let mut clients: Vec<C> = Vec::new();
+    loop {
+        match rx.recv_timeout(POLL) {
+            Ok(line) => clients.retain_mut(|c| c.send(&line).is_ok()),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        while clients.len() < MAX_CLIENTS {
+            match accept() {
+                Ok(Some(c)) => clients.push(c),
+                Ok(None) => break,
+                Err(e) => log::debug!("Publish client refused: {e}"),
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use super::{Client, Line, POLL, WRITE_TIMEOUT};
+    use std::ffi::CString;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{self, Write};
+    use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::sync::mpsc::{Receiver, RecvTimeoutError};
+
+    use crate::log;
+
+    impl Client for UnixStream {
+        fn send(&mut self, line: &Line) -> io::Result<()> {
+            self.write_all(line.json.as_bytes())?;
+            self.write_all(b"\n")
+        }
+    }
+
+    /// Listens on `path`, replacing a socket left behind by an earlier run
+    /// but nothing else.
+    pub fn listen(path: &str) -> Result<UnixListener, String> {
+        if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+            let _ = fs::remove_file(path);
+        }
+        UnixListener::bind(path)
+            .and_then(|l| l.set_nonblocking(true).map(|()| l))
+            .map_err(|e| format!("Failed to listen on {path}: {e}"))
+    }
+
+    pub fn accept(listener: &UnixListener) -> io::Result<Option<UnixStream>> {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false)?;
+                stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                Ok(Some(stream))
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Creates a named pipe at `path` unless there is one already.
+    pub fn make_fifo(path: &str) -> Result<(), String> {
+        match fs::symlink_metadata(path) {
+            Ok(m) if m.file_type().is_fifo() => return Ok(()),
+            Ok(_) => return Err(format!("{path} exists and is not a named pipe")),
+            Err(_) => {}
+        }
+        let c_path = CString::new(path).map_err(|_| format!("Invalid path {path}"))?;
+        // SAFETY: `c_path` is a valid NUL-terminated string.
+        if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+            let e = io::Error::last_os_error();
+            return Err(format!("Failed to create named pipe {path}: {e}"));
+        }
+        Ok(())
+    }
+
+    /// Writes every line to the pipe at `path` while it has a reader,
+    /// opening it again whenever one comes back.
+    pub fn feed_fifo(path: &str, rx: Receiver<Line>) {
+        let mut pipe: Option<File> = None;
+        loop {
+            let line = match rx.recv_timeout(POLL) {
+                Ok(line) => Some(line),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => return,
+            };
+            if pipe.is_none() {
+                // Without a reader, opening the write end fails at once
+                pipe = OpenOptions::new()
+                    .write(true)
+                    .custom_flags(libc::O_NONBLOCK)
+                    .open(path)
+                    .ok();
+                if pipe.is_some() {
+                    log::debug!("Reader opened {path}");
+                }
+            }
+            let (Some(f), Some(line)) = (pipe.as_mut(), line) else {
+                continue;
+            };
+            // Lines up to PIPE_BUF long are written whole or not at all
+            let mut data = line.json.into_bytes();
+            data.push(b'\n');
+            match f.write(&data) {
+                Ok(_) => {}
+                // A reader that can't keep up loses the line
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(_) => {
+                    log::debug!("Reader closed {path}");
+                    pipe = None;
+                }
+            }
+        }
+    }
+}
+
+/// The PUB side of ZMTP 3.0 (rfc.zeromq.org/spec/23), NULL mechanism only.
+mod zmtp {
+    use super::{Client, Line, HANDSHAKE_TIMEOUT, WRITE_TIMEOUT};
+    use std::io::{self, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    const MORE: u8 = 0x01;
+    const LONG: u8 = 0x02;
+    const COMMAND: u8 = 0x04;
+
+    /// Signature, version 3.0, the NULL mechanism, not as server.
+    pub fn greeting() -> [u8; 64] {
+        let mut g = [0u8; 64];
+        g[0] = 0xff;
+        g[9] = 0x7f;
+        g[10] = 3;
+        g[12..16].copy_from_slice(b"NULL");
+        g
+    }
+
+    /// A READY command announcing `socket_type`.
+    pub fn ready(socket_type: &str) -> Vec<u8> {
+        let mut body = vec![5];
+        body.extend_from_slice(b"READY");
+        body.push(11);
+        body.extend_from_slice(b"Socket-Type");
+        body.extend_from_slice(&(socket_type.len() as u32).to_be_bytes());
+        body.extend_from_slice(socket_type.as_bytes());
+        let mut out = Vec::new();
+        frame(&mut out, COMMAND, &body);
+        out
+    }
+
+    /// Appends one frame with `flags` to `out`.
+    pub fn frame(out: &mut Vec<u8>, flags: u8, body: &[u8]) {
+        match u8::try_from(body.len()) {
+            Ok(n) => out.extend_from_slice(&[flags, n]),
+            Err(_) => {
+                out.push(flags | LONG);
+                out.extend_from_slice(&(body.len() as u64).to_be_bytes());
+            }
+        }
+        out.extend_from_slice(body);
+    }
+
+    /// Reads one frame, returning its flags and body.
+    pub fn read_frame(r: &mut impl Read) -> io::Result<(u8, Vec<u8>)> {
+        let mut flags = [0u8];
+        r.read_exact(&mut flags)?;
+        let len = if flags[0] & LONG != 0 {
+            let mut n = [0u8; 8];
+            r.read_exact(&mut n)?;
+            u64::from_be_bytes(n)
+        } else {
+            let mut n = [0u8];
+            r.read_exact(&mut n)?;
+            u64::from(n[0])
+        };
+        if len > 4096 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too long"));
+        }
+        let mut body = vec![0u8; len as usize];
+        r.read_exact(&mut body)?;
+        Ok((flags[0], body))
+    }
+
+    /// Exchanges greetings and READY commands with a new peer.
+    fn handshake(stream: &mut TcpStream) -> io::Result<()> {
+        let invalid = |what| io::Error::new(io::ErrorKind::InvalidData, what);
+        stream.write_all(&greeting())?;
+        let mut peer = [0u8; 64];
+        stream.read_exact(&mut peer)?;
+        if peer[0] != 0xff || peer[9] & 1 != 1 || peer[10] < 3 {
+            return Err(invalid("not a ZMTP 3 peer"));
+        }
+        if &peer[12..32] != b"NULL\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0" {
+            return Err(invalid("peer wants a security mechanism other than NULL"));
+        }
+        stream.write_all(&ready("PUB"))?;
+        let (flags, body) = read_frame(stream)?;
+        if flags & COMMAND == 0 || !body.starts_with(b"\x05READY") {
+            return Err(invalid("peer didn't send READY"));
+        }
+        Ok(())
+    }
+
+    pub struct Subscriber {
+        stream: TcpStream,
+        buf: Vec<u8>,
+    }
+
+    impl Client for Subscriber {
+        fn send(&mut self, line: &Line) -> io::Result<()> {
+            self.buf.clear();
+            frame(&mut self.buf, MORE, line.sender.as_bytes());
+            frame(&mut self.buf, 0, line.json.as_bytes());
+            self.stream.write_all(&self.buf)
+        }
+    }
+
+    pub fn accept(listener: &TcpListener) -> io::Result<Option<Subscriber>> {
+        let mut stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        // Accepted sockets inherit non-blocking mode on some systems
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        handshake(&mut stream)?;
+        // Subscriptions are never read; the subscriber applies its own
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        Ok(Some(Subscriber {
+            stream,
+            buf: Vec::new(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::Instant;
+
+    fn row() -> Observation {
+        Observation {
+            recv_ms: 1_772_366_400_250,
+            send_ms: 1_772_366_400_247,
+            src: "10.0.0.5:40512".parse().unwrap(),
+            group: Some(3),
+            seq: Some(1500),
+            offset_ms: 3,
+            host_delay_us: None,
+            annotation: Some("rack \"b\"".to_string()),
+            fields: vec!["site=lab".to_string()],
+        }
+    }
+
+    #[test]
+    fn zeromq_subscribers_get_the_sender_and_the_json() {
+        assert_eq!(
+            json(&row(), "10.0.0.5/3", 2),
+            r#"{"sender":"10.0.0.5/3","src":"10.0.0.5:40512","group":3,"seq":1500,"send_ms":1772366400247,"recv_ms":1772366400250,"offset_ms":3,"host_delay_us":null,"lost":2,"annotation":"rack \"b\"","fields":{"site":"lab"}}"#
+        );
+        assert!(Target::parse("zmq:127.0.0.1:5556").is_err());
+
+        // --stream writes the same line, then a newline
+        let mut stream = Stream::new(StreamFormat::parse("ndjson").unwrap(), Vec::new());
+        stream.write(&row(), "10.0.0.5/3", 2).unwrap();
+        assert_eq!(
+            stream.out,
+            format!("{}\n", json(&row(), "10.0.0.5/3", 2)).into_bytes()
+        );
+        assert!(StreamFormat::parse("json").is_err());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let publisher = Publisher::open(&Target::Zmq(addr)).unwrap();
+
+        // A SUB socket, as far as ZMTP goes
+        let mut sub = TcpStream::connect(addr).unwrap();
+        sub.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        sub.write_all(&zmtp::greeting()).unwrap();
+        sub.write_all(&zmtp::ready("SUB")).unwrap();
+        let mut greeting = [0u8; 64];
+        sub.read_exact(&mut greeting).unwrap();
+        assert_eq!(greeting, zmtp::greeting());
+        let (_, ready) = zmtp::read_frame(&mut sub).unwrap();
+        assert_eq!(ready, &zmtp::ready("PUB")[2..]);
+
+        // Published until the publisher has taken the subscriber on
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut frames = Vec::new();
+        sub.set_read_timeout(Some(POLL)).unwrap();
+        while frames.is_empty() && Instant::now() < deadline {
+            publisher.publish(&row(), "10.0.0.5/3", 0);
+            if let Ok(f) = zmtp::read_frame(&mut sub) {
+                frames.push(f);
+            }
+        }
+        sub.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        frames.push(zmtp::read_frame(&mut sub).unwrap());
+        assert_eq!(frames[0], (0x01, b"10.0.0.5/3".to_vec()));
+        assert_eq!(frames[1].0, 0);
+        assert!(frames[1].1.starts_with(br#"{"sender":"10.0.0.5/3""#));
+    }
+}
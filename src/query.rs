@@ -0,0 +1,146 @@
+//! On-demand time queries (`send --serve-queries`).
+//!
+//! Besides its periodic broadcasts, a sender can answer unicast queries on
+//! a port of its own, for consumers that would rather pull the time than
+//! wait for the next beacon, and for round-trip probes against a running
+//! broadcaster. A query is a v2 datagram of type [`MessageType::QUERY`]
+//! whose sequence number and timestamp the client picks freely, optionally
+//! with an [`EXT_ECHO`](crate::payload::EXT_ECHO) token of up to
+//! [`MAX_ECHO_LEN`] bytes. The reply, of type [`MessageType::REPLY`], is
+//! sent straight back to the query's source: the query's sequence number,
+//! the sender's current time in its `--epoch`, its `--group`, and the echo
+//! token, authenticated with the sender's group key or signing key if it
+//! has one.
+//!
+//! A reply can be larger than its query: by 4 bytes for a group, 18 for a
+//! MAC and 74 for a signature. The port shouldn't be reachable from beyond
+//! the networks whose consumers use it.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::auth::Key;
+use crate::ed25519::SigningKey;
+use crate::epoch::Epoch;
+use crate::exit::Failure;
+use crate::log;
+use crate::message::MessageType;
+use crate::payload::{self, Format, Packet, MAX_ECHO_LEN};
+use crate::transmit;
+use crate::units;
+
+/// What a sender puts in its replies.
+#[derive(Clone)]
+pub struct Responder {
+    pub group: Option<u16>,
+    pub epoch: Epoch,
+    pub key: Option<Key>,
+    pub sign_key: Option<SigningKey>,
+}
+
+impl Responder {
+    /// Encodes the reply to `data` into `out`, stamped `now_ms`. Returns
+    /// the query's sequence number, or why `data` gets no reply.
+    pub fn answer(&self, data: &[u8], now_ms: u64, out: &mut Vec<u8>) -> Result<u32, String> {
+        let query = payload::decode(data)?;
+        if query.format != Format::V2 || query.packet.kind != MessageType::QUERY {
+            return Err(format!("not a query (type {})", query.packet.kind));
+        }
+        let seq = query.packet.seq.unwrap_or(0);
+        let reply = Packet {
+            kind: MessageType::REPLY,
+            group: self.group,
+            epoch: self.epoch,
+            ..Packet::new(Some(seq), now_ms)
+        };
+        payload::encode_reply(&reply, query.echo, self.key.as_ref(), out);
+        if let Some(key) = &self.sign_key {
+            payload::sign(out, key);
+        }
+        Ok(seq)
+    }
+}
+
+/// Binds `port` in the address family of `family_of` and answers queries
+/// on it from a thread of its own, so replies don't wait for the next tick.
+pub fn serve(
+    port: u16,
+    family_of: SocketAddr,
+    responder: Responder,
+) -> Result<SocketAddr, Failure> {
+    let ip = match family_of.ip() {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let addr = SocketAddr::new(ip, port);
+    let sock = UdpSocket::bind(addr)
+        .map_err(|e| Failure::io(&e, format!("Failed to bind query socket on {addr}: {e}")))?;
+    let local = sock.local_addr().unwrap_or(addr);
+    log::info!("Answering time queries on {local}");
+    log::spawn(move || {
+        let mut buf = [0u8; 1500];
+        let mut out = Vec::with_capacity(payload::V2_LEN + 2 + MAX_ECHO_LEN + 80);
+        loop {
+            let (n, src) = match sock.recv_from(&mut buf) {
+                Ok(r) => r,
+                Err(e) => {
+                    log::warn!("Query socket error: {e}");
+                    continue;
+                }
+            };
+            let now_ms = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as u64);
+            match responder.answer(&buf[..n], now_ms, &mut out) {
+                Ok(seq) => match transmit::send_to(&sock, &out, src) {
+                    Ok(_) => {
+                        log::debug!("Answered query from {src} seq={seq} {}", units::ts(now_ms))
+                    }
+                    Err(e) => log::warn!("Failed to answer query from {src}: {e}"),
+                },
+                Err(e) => log::debug!("Ignoring datagram on query port from {src}: {e}"),
+            }
+        }
+    });
+    Ok(local)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replies_echo_the_token_and_carry_the_time() {
+        let responder = Responder {
+            group: Some(4),
+            epoch: Epoch::Unix,
+            key: Some(Key::new(b"secret")),
+            sign_key: None,
+        };
+        // "TS", version 2, type query, seq 9, the client's clock, a token
+        let mut query = b"TS\x02\x06".to_vec();
+        query.extend_from_slice(&9u32.to_be_bytes());
+        query.extend_from_slice(&123u64.to_be_bytes());
+        query.extend_from_slice(&[payload::EXT_ECHO, 4, 0xde, 0xad, 0xbe, 0xef]);
+
+        let mut out = Vec::new();
+        assert_eq!(responder.answer(&query, 1_700_000_000_000, &mut out), Ok(9));
+        let reply = payload::decode(&out).unwrap();
+        assert_eq!(reply.packet.kind, MessageType::REPLY);
+        assert_eq!(reply.packet.seq, Some(9));
+        assert_eq!(reply.packet.ts_ms, 1_700_000_000_000);
+        assert_eq!(reply.packet.group, Some(4));
+        assert_eq!(reply.echo, Some(&[0xde, 0xad, 0xbe, 0xef][..]));
+        assert!(reply.verify(responder.key.as_ref().unwrap()));
+
+        // Without a token, and not to anything else
+        let mut out = Vec::new();
+        assert_eq!(responder.answer(&query[..16], 5, &mut out), Ok(9));
+        assert_eq!(payload::decode(&out).unwrap().echo, None);
+        query[3] = MessageType::DATA.0;
+        assert!(responder.answer(&query, 5, &mut out).is_err());
+        assert!(responder
+            .answer(&123u64.to_be_bytes(), 5, &mut out)
+            .is_err());
+    }
+}
@@ -0,0 +1,79 @@
+//! Token bucket for `--rate` sending.
+//!
+//! Tokens accrue continuously at the configured rate and each datagram
+//! spends one. When a sleep overshoots, the tokens that piled up meanwhile
+//! are sent straight away, so the long-term rate holds even when the OS
+//! can't sleep as finely as the rate needs (200 µs at 5000 pps). At most
+//! [`CATCH_UP`] worth of tokens is kept, so a stall such as a suspended
+//! host doesn't end in a flood.
+
+use std::time::{Duration, Instant};
+
+/// How far behind the bucket may fall before tokens are dropped.
+pub const CATCH_UP: Duration = Duration::from_millis(20);
+
+pub struct TokenBucket {
+    /// Tokens per second.
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Option<Instant>,
+}
+
+impl TokenBucket {
+    /// A bucket for `rate` datagrams per second, which must be positive.
+    /// It starts with one token, so the first datagram goes out at once.
+    pub fn new(rate: f64) -> TokenBucket {
+        TokenBucket {
+            rate,
+            capacity: (rate * CATCH_UP.as_secs_f64()).max(1.0),
+            tokens: 1.0,
+            last: None,
+        }
+    }
+
+    /// Adds the tokens accrued up to `now` and takes every whole one,
+    /// returning how many were taken.
+    pub fn take(&mut self, now: Instant) -> u64 {
+        if let Some(last) = self.last {
+            let accrued = now.saturating_duration_since(last).as_secs_f64() * self.rate;
+            self.tokens = (self.tokens + accrued).min(self.capacity);
+        }
+        self.last = Some(now);
+        // Absorbs rounding, so 1 ms at 5000 pps is 5 tokens and not 4.999..
+        let whole = (self.tokens + 1e-9).floor();
+        self.tokens -= whole;
+        whole as u64
+    }
+
+    /// Time from the last [`Self::take`] until the next whole token.
+    pub fn wait(&self) -> Duration {
+        Duration::from_secs_f64((1.0 - self.tokens) / self.rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fractional_rate_spaces_tokens() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(0.5);
+        assert_eq!(bucket.take(start), 1);
+        assert_eq!(bucket.wait(), Duration::from_secs(2));
+        assert_eq!(bucket.take(start + Duration::from_secs(1)), 0);
+        assert_eq!(bucket.take(start + Duration::from_secs(2)), 1);
+    }
+
+    #[test]
+    fn overshoot_is_made_up_but_stalls_are_not() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(5_000.0);
+        assert_eq!(bucket.take(start), 1);
+        // A 1 ms sleep where 200 µs was asked for
+        assert_eq!(bucket.take(start + Duration::from_millis(1)), 5);
+        // A 10 s stall only yields the catch-up allowance
+        assert_eq!(bucket.take(start + Duration::from_secs(10)), 100);
+    }
+}
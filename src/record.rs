@@ -0,0 +1,185 @@
+//! Recording of received datagrams and their replay.
+//!
+//! A recording is a file starting with the 8-byte magic `UBTSREC1`,
+//! followed by one record per datagram (all integers big-endian):
+//!
+//! ```text
+//! size  field
+//!    8  arrival time, microseconds since Unix epoch
+//!    1  source address family: 4 or 6
+//! 4/16  source address
+//!    2  source port
+//!    2  payload length
+//!    n  payload
+//! ```
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::process::ExitCode;
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::exit;
+use crate::log;
+use crate::resolve::Target;
+use crate::send::bind_for;
+use crate::transmit;
+use crate::units;
+
+const MAGIC: &[u8; 8] = b"UBTSREC1";
+
+/// One recorded datagram.
+pub struct Record {
+    pub arrival_us: u64,
+    pub src: SocketAddr,
+    pub payload: Vec<u8>,
+}
+
+/// Appends received datagrams to a recording file.
+pub struct Recorder {
+    out: BufWriter<File>,
+}
+
+impl Recorder {
+    pub fn create(path: &str) -> Result<Recorder, String> {
+        let file =
+            File::create(path).map_err(|e| format!("Failed to create recording {path}: {e}"))?;
+        let mut out = BufWriter::new(file);
+        out.write_all(MAGIC)
+            .map_err(|e| format!("Failed to write recording {path}: {e}"))?;
+        Ok(Recorder { out })
+    }
+
+    /// Writes one datagram. Each record is flushed so that a killed
+    /// receiver leaves a usable file behind.
+    pub fn write(
+        &mut self,
+        arrival: SystemTime,
+        src: SocketAddr,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let arrival_us = arrival
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_micros() as u64);
+        self.out.write_all(&arrival_us.to_be_bytes())?;
+        match src.ip() {
+            IpAddr::V4(ip) => {
+                self.out.write_all(&[4])?;
+                self.out.write_all(&ip.octets())?;
+            }
+            IpAddr::V6(ip) => {
+                self.out.write_all(&[6])?;
+                self.out.write_all(&ip.octets())?;
+            }
+        }
+        self.out.write_all(&src.port().to_be_bytes())?;
+        self.out.write_all(&(payload.len() as u16).to_be_bytes())?;
+        self.out.write_all(payload)?;
+        self.out.flush()
+    }
+}
+
+/// Reads records back from a recording file.
+pub struct Reader {
+    input: BufReader<File>,
+}
+
+impl Reader {
+    pub fn open(path: &str) -> Result<Reader, String> {
+        let file = File::open(path).map_err(|e| format!("Failed to open recording {path}: {e}"))?;
+        let mut input = BufReader::new(file);
+        let mut magic = [0u8; 8];
+        input
+            .read_exact(&mut magic)
+            .map_err(|e| format!("Failed to read recording {path}: {e}"))?;
+        if &magic != MAGIC {
+            return Err(format!("{path} is not a recording"));
+        }
+        Ok(Reader { input })
+    }
+
+    fn read_array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut buf = [0u8; N];
+        self.input.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Returns the next record, or `None` at a clean end of file.
+    pub fn next_record(&mut self) -> io::Result<Option<Record>> {
+        let arrival_us = match self.read_array::<8>() {
+            Ok(b) => u64::from_be_bytes(b),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let ip = match self.read_array::<1>()?[0] {
+            4 => IpAddr::V4(Ipv4Addr::from(self.read_array::<4>()?)),
+            6 => IpAddr::V6(Ipv6Addr::from(self.read_array::<16>()?)),
+            f => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("bad address family {f}"),
+                ))
+            }
+        };
+        let port = u16::from_be_bytes(self.read_array()?);
+        let len = u16::from_be_bytes(self.read_array()?) as usize;
+        let mut payload = vec![0u8; len];
+        self.input.read_exact(&mut payload)?;
+        Ok(Some(Record {
+            arrival_us,
+            src: SocketAddr::new(ip, port),
+            payload,
+        }))
+    }
+}
+
+/// Re-sends every datagram in `path` to `target`, reproducing the
+/// original inter-packet timing.
+pub fn replay(path: &str, target: &Target, port: u16) -> ExitCode {
+    let mut reader = match Reader::open(path) {
+        Ok(r) => r,
+        Err(e) => return exit::fail(e),
+    };
+    let dest = match target.resolve(port, None) {
+        Ok(d) => d,
+        Err(e) => return exit::fail(e),
+    };
+    let sock = match bind_for(dest) {
+        Ok(s) => s,
+        Err(e) => return exit::fail(e),
+    };
+
+    let start = Instant::now();
+    let mut first_us = None;
+    let mut count: u64 = 0;
+
+    loop {
+        let record = match reader.next_record() {
+            Ok(Some(r)) => r,
+            Ok(None) => break,
+            Err(e) => return exit::fail(format!("Failed to read recording {path}: {e}")),
+        };
+
+        let first = *first_us.get_or_insert(record.arrival_us);
+        let due = start + Duration::from_micros(record.arrival_us.saturating_sub(first));
+        if let Some(wait) = due.checked_duration_since(Instant::now()) {
+            sleep(wait);
+        }
+
+        match transmit::send_to(&sock, &record.payload, dest) {
+            Ok(_) => {
+                count += 1;
+                log::info!(
+                    "Replayed {} from {} to {dest}",
+                    units::bytes(record.payload.len() as u64),
+                    record.src
+                );
+            }
+            Err(e) => log::warn!("send_to({dest}) failed: {e}"),
+        }
+    }
+
+    log::info!("Replay finished: {count} datagram(s) sent");
+    ExitCode::SUCCESS
+}
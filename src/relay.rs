@@ -0,0 +1,220 @@
+//! Relay mode: carries timestamps across a routed boundary.
+//!
+//! Broadcasts stop at the router, so a relay on the edge receives beacons
+//! on one side and sends each datagram on to a destination on the other,
+//! usually that segment's broadcast address. With a relay id, v2 payloads
+//! get a relay extension ([`payload::EXT_RELAY`]) counting the hops taken,
+//! which receivers report and which bounds how far a packet can travel if
+//! relays end up feeding each other. Authenticated payloads are forwarded
+//! byte for byte, since changing them would invalidate their MAC or
+//! signature.
+
+use std::net::{IpAddr, SocketAddr};
+use std::process::ExitCode;
+
+use crate::exit::{self, Failure};
+use crate::iface;
+use crate::listen;
+use crate::log;
+use crate::payload::{self, Format, Relay};
+use crate::resolve::Target;
+use crate::send::bind_for;
+use crate::sys;
+use crate::transmit;
+
+/// Default for `--max-hops`.
+pub const DEFAULT_MAX_HOPS: u8 = 8;
+
+/// Settings for `relay`.
+pub struct RelayConfig {
+    /// Local address to bind, or a multicast group to join.
+    pub listen_addr: Option<IpAddr>,
+    /// IPv6 zone of `listen_addr`, or 0.
+    pub scope_id: u32,
+    pub listen_port: u16,
+    pub target: Target,
+    pub port: u16,
+    /// Stamp relayed v2 payloads with this id and a hop count; without
+    /// one, payloads are forwarded unchanged.
+    pub relay_id: Option<u16>,
+    /// Payloads that have already taken this many hops are dropped.
+    pub max_hops: u8,
+}
+
+/// Decides what to send on for `data`. Returns the hop count if the
+/// payload was rewritten into `out`, `None` if it goes on unchanged, or
+/// why it is dropped.
+fn prepare(
+    data: &[u8],
+    relay_id: Option<u16>,
+    max_hops: u8,
+    out: &mut Vec<u8>,
+) -> Result<Option<u8>, String> {
+    let decoded = payload::decode(data)?;
+    if !decoded.checksum_ok() {
+        return Err("checksum mismatch".to_string());
+    }
+    let last = decoded.packet.relay;
+    let hops = last.map_or(0, |r| r.hops);
+    if hops >= max_hops {
+        return Err(format!("hop limit {max_hops} reached"));
+    }
+    let Some(id) = relay_id else {
+        return Ok(None);
+    };
+    if last.is_some_and(|r| r.id == id) {
+        return Err("already relayed by this relay".to_string());
+    }
+    if matches!(decoded.format, Format::Legacy | Format::Compact)
+        || decoded.mac.is_some()
+        || decoded.sig.is_some()
+    {
+        return Ok(None);
+    }
+    payload::set_relay(data, Relay { hops: hops + 1, id }, out);
+    Ok(Some(hops + 1))
+}
+
+/// Relays datagrams until a fatal error occurs.
+pub fn run(cfg: &RelayConfig) -> ExitCode {
+    match relay(cfg) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => exit::fail(e),
+    }
+}
+
+fn relay(cfg: &RelayConfig) -> Result<(), Failure> {
+    let dest = cfg.target.resolve(cfg.port, None)?;
+    let out = bind_for(dest)?;
+    let sock = listen::bind(
+        cfg.listen_addr,
+        cfg.scope_id,
+        cfg.listen_port,
+        false,
+        sys::Reuse::default(),
+    )?;
+
+    // Relaying onto the segment being listened on would hear its own
+    // output again; that is recognized by the sending socket's port on
+    // one of this host's addresses.
+    let own_port = out
+        .local_addr()
+        .map_err(|e| format!("Failed to read local address: {e}"))?
+        .port();
+    let own_ips: Vec<IpAddr> = iface::ipv4_interfaces()
+        .map_err(|e| format!("Failed to list interfaces: {e}"))?
+        .into_iter()
+        .map(|i| IpAddr::V4(i.addr))
+        .collect();
+    let is_own = |src: SocketAddr| src.port() == own_port && own_ips.contains(&src.ip());
+
+    let listening = sock
+        .local_addr()
+        .map_err(|e| format!("Failed to read local address: {e}"))?;
+    log::info!("Relaying from {listening} to {dest}");
+
+    let mut buf = [0u8; 1500];
+    let mut rewritten = Vec::with_capacity(64);
+    loop {
+        let (len, src) = match sock.recv_from(&mut buf) {
+            Ok(m) => m,
+            Err(e) => {
+                log::warn!("recv_from failed: {e}");
+                continue;
+            }
+        };
+        if is_own(src) {
+            continue;
+        }
+        let data = &buf[..len];
+        let (sent, hops) = match prepare(data, cfg.relay_id, cfg.max_hops, &mut rewritten) {
+            Ok(Some(hops)) => (rewritten.as_slice(), format!(" hops={hops}")),
+            Ok(None) => (data, String::new()),
+            Err(e) => {
+                log::warn!("Dropping datagram from {src}: {e}");
+                continue;
+            }
+        };
+        match transmit::send_to(&out, sent, dest) {
+            Ok(_) => log::info!("Relayed {len} bytes from {src} to {dest}{hops}"),
+            Err(e) => log::warn!("send_to({dest}) failed: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Key;
+    use crate::epoch::Epoch;
+    use crate::payload::{Format, Packet};
+
+    fn packet() -> Packet {
+        Packet {
+            group: Some(3),
+            mono_us: Some(42),
+            ..Packet::new(Some(9), 1_700_000_000_000)
+        }
+    }
+
+    #[test]
+    fn each_relay_adds_a_hop_until_the_limit() {
+        let mut data = Vec::new();
+        payload::encode(Format::V2, &packet(), None, &mut data);
+        // Unknown extensions travel along
+        data.extend_from_slice(&[0x7e, 1, 0xaa]);
+
+        let mut first = Vec::new();
+        assert_eq!(prepare(&data, Some(5), 2, &mut first), Ok(Some(1)));
+        let mut second = Vec::new();
+        assert_eq!(prepare(&first, Some(6), 2, &mut second), Ok(Some(2)));
+        assert!(second.windows(3).any(|w| w == [0x7e, 1, 0xaa]));
+        assert_eq!(
+            payload::decode(&second).unwrap().packet,
+            Packet {
+                relay: Some(Relay { hops: 2, id: 6 }),
+                epoch: Epoch::Unix,
+                ..packet()
+            }
+        );
+
+        let mut out = Vec::new();
+        assert!(prepare(&second, Some(7), 2, &mut out).is_err());
+        assert!(prepare(&first, Some(5), 8, &mut out).is_err());
+        assert_eq!(prepare(&second, None, 8, &mut out), Ok(None));
+    }
+
+    #[test]
+    fn authenticated_and_legacy_payloads_pass_unchanged() {
+        let key = Key::new(b"k");
+        let mut signed = Vec::new();
+        payload::encode(Format::V2, &packet(), Some(&key), &mut signed);
+        let mut out = Vec::new();
+        assert_eq!(prepare(&signed, Some(5), 8, &mut out), Ok(None));
+        let legacy = 1_700_000_000_000u64.to_be_bytes();
+        assert_eq!(prepare(&legacy, Some(5), 8, &mut out), Ok(None));
+        assert!(prepare(b"junk", Some(5), 8, &mut out).is_err());
+    }
+
+    #[test]
+    fn checksums_are_made_anew_and_corrupt_payloads_dropped() {
+        assert_eq!(payload::crc32c(b"123456789"), 0xE306_9283);
+
+        let mut data = Vec::new();
+        payload::encode(Format::V2, &packet(), None, &mut data);
+        payload::append_crc(&mut data);
+        let mut out = Vec::new();
+        assert_eq!(prepare(&data, Some(5), 8, &mut out), Ok(Some(1)));
+        let relayed = payload::decode(&out).unwrap();
+        assert!(relayed.crc.is_some() && relayed.checksum_ok());
+        assert_eq!(relayed.packet.relay, Some(Relay { hops: 1, id: 5 }));
+
+        // A flipped timestamp bit
+        data[15] ^= 0x01;
+        assert!(!payload::decode(&data).unwrap().checksum_ok());
+        assert_eq!(
+            prepare(&data, Some(5), 8, &mut out),
+            Err("checksum mismatch".to_string())
+        );
+    }
+}
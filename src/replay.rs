@@ -0,0 +1,175 @@
+//! Sliding-window replay protection for sequenced (v2) payloads.
+//!
+//! Each sender gets a window anchored at the highest sequence number seen
+//! so far. A packet is accepted if it is newer than that, or falls inside
+//! the window and has not been seen before; anything older than the window
+//! or already marked is rejected.
+//!
+//! Sequence numbers wrap, as senders count them, so "newer" is up to half
+//! the u32 range ahead of the highest seen, modulo 2^32: after
+//! `u32::MAX` comes 0.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+pub const DEFAULT_WINDOW: u32 = 64;
+pub const MAX_WINDOW: u32 = 4096;
+
+/// Why a packet was rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    /// Sequence number is older than the window.
+    Stale,
+    /// Sequence number inside the window was already accepted.
+    Duplicate,
+}
+
+/// Replay state for a single sender.
+struct Window {
+    highest: u32,
+    /// Bit `i` is set if sequence `highest - i` has been accepted.
+    bits: Vec<u64>,
+}
+
+impl Window {
+    fn new(size: u32, seq: u32) -> Window {
+        let mut w = Window {
+            highest: seq,
+            bits: vec![0; size.div_ceil(64) as usize],
+        };
+        w.set(0);
+        w
+    }
+
+    fn get(&self, i: u32) -> bool {
+        self.bits[(i / 64) as usize] & (1 << (i % 64)) != 0
+    }
+
+    fn set(&mut self, i: u32) {
+        self.bits[(i / 64) as usize] |= 1 << (i % 64);
+    }
+
+    /// Shifts the window forward by `n` positions.
+    fn advance(&mut self, n: u32, size: u32) {
+        if n >= size {
+            self.bits.iter_mut().for_each(|b| *b = 0);
+            return;
+        }
+        for i in (0..size).rev() {
+            let keep = i >= n && self.get(i - n);
+            let word = &mut self.bits[(i / 64) as usize];
+            if keep {
+                *word |= 1 << (i % 64);
+            } else {
+                *word &= !(1 << (i % 64));
+            }
+        }
+    }
+
+    fn check(&mut self, seq: u32, size: u32) -> Result<(), Rejection> {
+        let ahead = seq.wrapping_sub(self.highest);
+        if ahead != 0 && ahead < u32::MAX / 2 {
+            self.advance(ahead, size);
+            self.highest = seq;
+            self.set(0);
+            return Ok(());
+        }
+        let age = self.highest.wrapping_sub(seq);
+        if age >= size {
+            return Err(Rejection::Stale);
+        }
+        if self.get(age) {
+            return Err(Rejection::Duplicate);
+        }
+        self.set(age);
+        Ok(())
+    }
+}
+
+/// Per-sender replay windows.
+pub struct ReplayGuard {
+    size: u32,
+    senders: HashMap<SocketAddr, Window>,
+}
+
+impl ReplayGuard {
+    /// Creates a guard with the given window size (1..=MAX_WINDOW).
+    pub fn new(size: u32) -> ReplayGuard {
+        ReplayGuard {
+            size,
+            senders: HashMap::new(),
+        }
+    }
+
+    /// Records `seq` from `src`, rejecting it if it is stale or a duplicate.
+    pub fn check(&mut self, src: SocketAddr, seq: u32) -> Result<(), Rejection> {
+        let size = self.size;
+        match self.senders.get_mut(&src) {
+            Some(w) => w.check(seq, size),
+            None => {
+                self.senders.insert(src, Window::new(size, seq));
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_duplicates_and_stale_packets_across_the_window() {
+        let a: SocketAddr = "10.0.0.5:4000".parse().unwrap();
+        let b: SocketAddr = "10.0.0.6:4000".parse().unwrap();
+        let mut guard = ReplayGuard::new(DEFAULT_WINDOW);
+        assert_eq!(guard.check(a, 100), Ok(()));
+        assert_eq!(guard.check(a, 100), Err(Rejection::Duplicate));
+        // Late but inside the window, once
+        assert_eq!(guard.check(a, 90), Ok(()));
+        assert_eq!(guard.check(a, 90), Err(Rejection::Duplicate));
+        assert_eq!(guard.check(a, 37), Ok(()));
+        assert_eq!(guard.check(a, 36), Err(Rejection::Stale));
+        // Senders have windows of their own
+        assert_eq!(guard.check(b, 36), Ok(()));
+
+        // Marks carry over as the window moves across a word boundary
+        let mut guard = ReplayGuard::new(128);
+        for seq in [1000, 990, 937] {
+            assert_eq!(guard.check(a, seq), Ok(()));
+        }
+        assert_eq!(guard.check(a, 1070), Ok(()));
+        for seq in [1070, 1000, 990] {
+            assert_eq!(guard.check(a, seq), Err(Rejection::Duplicate), "{seq}");
+        }
+        assert_eq!(guard.check(a, 937), Err(Rejection::Stale));
+        assert_eq!(guard.check(a, 991), Ok(()));
+
+        // A jump of the window size or more forgets every mark
+        assert_eq!(guard.check(a, 1070 + 128), Ok(()));
+        assert_eq!(guard.check(a, 1071), Ok(()));
+        assert_eq!(guard.check(a, 1070), Err(Rejection::Stale));
+
+        let mut guard = ReplayGuard::new(MAX_WINDOW);
+        assert_eq!(guard.check(a, 5000), Ok(()));
+        assert_eq!(guard.check(a, 5000 - MAX_WINDOW + 1), Ok(()));
+        assert_eq!(guard.check(a, 5000 - MAX_WINDOW), Err(Rejection::Stale));
+        assert_eq!(guard.check(a, 5001), Ok(()));
+        assert_eq!(guard.check(a, 5000 - MAX_WINDOW + 1), Err(Rejection::Stale));
+        assert_eq!(guard.check(a, 5000), Err(Rejection::Duplicate));
+    }
+
+    #[test]
+    fn sequence_numbers_wrap_past_u32_max() {
+        let a: SocketAddr = "10.0.0.5:4000".parse().unwrap();
+        let mut guard = ReplayGuard::new(DEFAULT_WINDOW);
+        assert_eq!(guard.check(a, u32::MAX - 1), Ok(()));
+        assert_eq!(guard.check(a, 1), Ok(()));
+        assert_eq!(guard.check(a, 0), Ok(()));
+        assert_eq!(guard.check(a, u32::MAX), Ok(()));
+        assert_eq!(guard.check(a, u32::MAX - 1), Err(Rejection::Duplicate));
+        assert_eq!(guard.check(a, u32::MAX - 70), Err(Rejection::Stale));
+        // Half the range back is behind, not ahead
+        assert_eq!(guard.check(a, u32::MAX / 2 + 10), Err(Rejection::Stale));
+    }
+}
@@ -0,0 +1,149 @@
+//! Destination addresses given either as a literal IP or as a hostname.
+//!
+//! IPv6 literals may carry a zone, `ff02::1%eth0` or `fe80::1%2`, naming
+//! the interface a link-local address belongs to. Without one the OS picks
+//! an interface (or refuses), so link-local destinations require it.
+
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6, ToSocketAddrs};
+
+use crate::sys;
+
+/// The value of `--addr` when sending.
+#[derive(Clone, Debug)]
+pub enum Target {
+    Ip(IpAddr),
+    /// An IPv6 literal with a zone, resolved to its interface index, and
+    /// the interface name if the zone gave one.
+    Scoped(Ipv6Addr, u32, Option<String>),
+    Host(String),
+}
+
+/// Returns true for addresses only meaningful on one link: unicast
+/// link-local (`fe80::/10`) and interface- or link-local multicast.
+pub fn needs_zone(ip: &Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    first & 0xffc0 == 0xfe80 || matches!(first & 0xff0f, 0xff01 | 0xff02)
+}
+
+/// Resolves a zone, either an interface name or a numeric index.
+fn zone_index(zone: &str) -> Result<u32, String> {
+    match zone.parse::<u32>() {
+        Ok(0) => Err("zone index must be > 0".to_string()),
+        Ok(n) => Ok(n),
+        Err(_) if zone.is_empty() => Err("empty zone".to_string()),
+        Err(_) => sys::interface_index(zone).map_err(|e| format!("unknown interface {zone}: {e}")),
+    }
+}
+
+/// Parses an IP literal with an optional IPv6 zone. The scope id is 0
+/// when no zone is given.
+pub fn parse_ip(s: &str) -> Result<(IpAddr, u32), String> {
+    let (ip, zone) = match s.split_once('%') {
+        Some((ip, zone)) => (ip, Some(zone)),
+        None => (s, None),
+    };
+    let ip: IpAddr = ip.parse().map_err(|_| format!("invalid IP address {ip}"))?;
+    let scope_id = match (ip, zone) {
+        (_, None) => 0,
+        (IpAddr::V4(_), Some(_)) => {
+            return Err("zones are only valid on IPv6 addresses".to_string())
+        }
+        (IpAddr::V6(_), Some(zone)) => zone_index(zone)?,
+    };
+    Ok((ip, scope_id))
+}
+
+impl Target {
+    /// Parses `--addr`, treating anything that is not an IP literal as a
+    /// hostname. Link-local IPv6 literals must name a zone.
+    pub fn parse(s: &str) -> Result<Target, String> {
+        if !s.contains('%') && s.parse::<IpAddr>().is_err() {
+            return Ok(Target::Host(s.to_string()));
+        }
+        match parse_ip(s)? {
+            (IpAddr::V6(ip), 0) if needs_zone(&ip) => Err(format!(
+                "link-local destination {ip} needs a zone, e.g. {ip}%eth0"
+            )),
+            (IpAddr::V6(ip), scope_id) if scope_id != 0 => {
+                let name = s
+                    .split_once('%')
+                    .map(|(_, zone)| zone)
+                    .filter(|zone| zone.parse::<u32>().is_err());
+                Ok(Target::Scoped(ip, scope_id, name.map(str::to_string)))
+            }
+            (ip, _) => Ok(Target::Ip(ip)),
+        }
+    }
+
+    /// Returns true if the target has to go through DNS.
+    pub fn is_host(&self) -> bool {
+        matches!(self, Target::Host(_))
+    }
+
+    /// Resolves the target to a socket address.
+    ///
+    /// If `current` is among the resolved addresses it is kept, so that a
+    /// round-robin record doesn't make the destination flap on every lookup.
+    pub fn resolve(&self, port: u16, current: Option<SocketAddr>) -> Result<SocketAddr, String> {
+        let host = match self {
+            Target::Ip(ip) => return Ok(SocketAddr::new(*ip, port)),
+            Target::Scoped(ip, scope_id, name) => {
+                // A re-created interface may come back under a new index
+                let scope_id = match name {
+                    Some(name) => zone_index(name)?,
+                    None => *scope_id,
+                };
+                return Ok(SocketAddr::V6(SocketAddrV6::new(*ip, port, 0, scope_id)));
+            }
+            Target::Host(h) => h,
+        };
+        let addrs: Vec<SocketAddr> = (host.as_str(), port)
+            .to_socket_addrs()
+            .map_err(|e| format!("Failed to resolve {host}: {e}"))?
+            .collect();
+        match current {
+            Some(c) if addrs.contains(&c) => Ok(c),
+            _ => addrs
+                .first()
+                .copied()
+                .ok_or_else(|| format!("Failed to resolve {host}: no addresses")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn link_local_destinations_need_a_zone() {
+        for addr in ["ff02::1", "fe80::1", "ff01::1"] {
+            let err = Target::parse(addr).unwrap_err();
+            assert!(err.contains("needs a zone"), "{addr}: {err}");
+        }
+        assert!(matches!(Target::parse("ff05::1"), Ok(Target::Ip(_))));
+        assert!(matches!(Target::parse("2001:db8::1"), Ok(Target::Ip(_))));
+    }
+
+    #[test]
+    fn numeric_zone_sets_scope_id() {
+        let target = Target::parse("ff02::1%3").unwrap();
+        let addr = target.resolve(12321, None).unwrap();
+        match addr {
+            SocketAddr::V6(a) => {
+                assert_eq!(*a.ip(), "ff02::1".parse::<Ipv6Addr>().unwrap());
+                assert_eq!(a.scope_id(), 3);
+            }
+            SocketAddr::V4(_) => panic!("expected IPv6"),
+        }
+    }
+
+    #[test]
+    fn invalid_zones_are_rejected() {
+        assert!(Target::parse("192.168.1.255%eth0").is_err());
+        assert!(Target::parse("ff02::1%0").is_err());
+        assert!(Target::parse("ff02::1%").is_err());
+        assert!(Target::parse("ff02::1%no-such-interface-xyz").is_err());
+        assert!(matches!(Target::parse("example.net"), Ok(Target::Host(_))));
+    }
+}
@@ -0,0 +1,267 @@
+//! Primary/follower roles for redundant senders.
+//!
+//! A `--role follower` or `--role auto` sender listens on the beacon port
+//! and stays silent while another sender is heard. After `--failover-ms`
+//! of silence it is promoted and starts transmitting, continuing the
+//! silent sender's interval and grid so receivers see one steady stream.
+//!
+//! A promoted follower steps down as soon as it hears any other sender.
+//! An `auto` sender only steps down for a sender that has been
+//! transmitting at least as long (a higher or equal v2 sequence number),
+//! so a group of `auto` senders settles on one without a designated
+//! primary. Each node's failover timeout is randomly stretched by up to
+//! half so simultaneous promotions don't repeat forever.
+//!
+//! Where both sides have a `--priority`, it decides instead: a node stays
+//! silent for, and steps down for, only senders ranked higher, and takes
+//! over from one ranked lower after the failover timeout, so the highest
+//! ranked node heard transmits. Between equal priorities the role decides.
+//!
+//! Every transition is printed as one `event=role_transition` line of
+//! `key=value` fields.
+
+use std::fmt;
+
+use crate::payload::Packet;
+use crate::rng::Rng;
+
+/// Values accepted by `--role`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Primary,
+    Follower,
+    Auto,
+}
+
+impl Role {
+    /// Parses a `--role` value.
+    pub fn parse(s: &str) -> Result<Role, String> {
+        match s {
+            "primary" => Ok(Role::Primary),
+            "follower" => Ok(Role::Follower),
+            "auto" => Ok(Role::Auto),
+            _ => Err("expected primary, follower or auto".to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Role::Primary => "primary",
+            Role::Follower => "follower",
+            Role::Auto => "auto",
+        })
+    }
+}
+
+/// A change between transmitting and standing by.
+pub struct Transition {
+    role: Role,
+    active: bool,
+    reason: &'static str,
+    now_ms: u64,
+    detail: String,
+}
+
+impl fmt::Display for Transition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (from, to) = if self.active {
+            ("standby", "active")
+        } else {
+            ("active", "standby")
+        };
+        write!(
+            f,
+            "event=role_transition role={} from={from} to={to} reason={} unix_ms={}{}",
+            self.role, self.reason, self.now_ms, self.detail
+        )
+    }
+}
+
+/// A non-primary sender's view of who is transmitting.
+pub struct Election {
+    role: Role,
+    active: bool,
+    timeout_ms: u64,
+    /// This node's `--priority`, if given.
+    priority: Option<u8>,
+    /// When this node may promote itself if nothing more is heard.
+    deadline_ms: u64,
+    /// Last sequenced beacon heard, as `(seq, ts_ms)`.
+    last_beacon: Option<(u32, u64)>,
+    /// Interval the transmitting sender appears to use.
+    interval_ms: Option<u64>,
+    rng: Rng,
+}
+
+impl Election {
+    /// Starts on standby; `role` must not be [`Role::Primary`].
+    pub fn new(
+        role: Role,
+        timeout_ms: u64,
+        priority: Option<u8>,
+        rng: Rng,
+        now_ms: u64,
+    ) -> Election {
+        let mut election = Election {
+            role,
+            active: false,
+            timeout_ms,
+            priority,
+            deadline_ms: 0,
+            last_beacon: None,
+            interval_ms: None,
+            rng,
+        };
+        election.reset(now_ms);
+        election
+    }
+
+    fn reset(&mut self, now_ms: u64) {
+        let spread = (self.rng.next_f64() * (self.timeout_ms / 2) as f64) as u64;
+        self.deadline_ms = now_ms + self.timeout_ms + spread;
+    }
+
+    pub fn active(&self) -> bool {
+        self.active
+    }
+
+    /// The interval and next tick of the sender last heard, for a promoted
+    /// node to carry on its schedule. Needs two consecutive v2 beacons.
+    pub fn mirror(&self) -> Option<(u64, u64)> {
+        let interval = self.interval_ms?;
+        let (_, ts) = self.last_beacon?;
+        Some((interval, ts + interval))
+    }
+
+    /// Records a beacon from another sender. `own_seq` is the next sequence
+    /// number this node would send.
+    pub fn heard(&mut self, packet: &Packet, own_seq: u32, now_ms: u64) -> Option<Transition> {
+        // Whether the sender outranks this node, where priorities decide
+        let outranks = match (self.priority, packet.priority) {
+            (Some(own), Some(peer)) if own != peer => Some(peer > own),
+            _ => None,
+        };
+        // A lower ranked sender doesn't hold off a takeover
+        if outranks != Some(false) {
+            self.reset(now_ms);
+        }
+        if let Some(seq) = packet.seq {
+            if let Some((last_seq, last_ts)) = self.last_beacon {
+                if seq == last_seq.wrapping_add(1) && packet.ts_ms > last_ts {
+                    self.interval_ms = Some(packet.ts_ms - last_ts);
+                }
+            }
+            self.last_beacon = Some((seq, packet.ts_ms));
+        }
+
+        let yields = outranks.unwrap_or(match self.role {
+            Role::Auto => packet.seq.is_none_or(|s| s >= own_seq),
+            _ => true,
+        });
+        if !self.active || !yields {
+            return None;
+        }
+        self.active = false;
+        Some(Transition {
+            role: self.role,
+            active: false,
+            reason: "peer_heard",
+            now_ms,
+            detail: packet
+                .seq
+                .map_or_else(String::new, |s| format!(" peer_seq={s} own_seq={own_seq}"))
+                + &packet
+                    .priority
+                    .map_or_else(String::new, |p| format!(" peer_priority={p}")),
+        })
+    }
+
+    /// Promotes this node if the failover deadline has passed.
+    pub fn tick(&mut self, now_ms: u64) -> Option<Transition> {
+        if self.active || now_ms < self.deadline_ms {
+            return None;
+        }
+        self.active = true;
+        Some(Transition {
+            role: self.role,
+            active: true,
+            reason: "peer_silent",
+            now_ms,
+            detail: format!(" timeout_ms={}", self.timeout_ms),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn beacon(seq: u32, ts_ms: u64) -> Packet {
+        Packet::new(Some(seq), ts_ms)
+    }
+
+    fn election(role: Role) -> Election {
+        Election::new(role, 3_000, None, Rng::new(1), 0)
+    }
+
+    #[test]
+    fn follower_promotes_after_silence_and_yields_to_any_peer() {
+        let mut e = election(Role::Follower);
+        assert!(e.tick(2_999).is_none());
+        e.heard(&beacon(10, 2_000), 0, 2_000);
+        assert!(e.tick(4_999).is_none());
+        assert!(e.tick(20_000).is_some());
+        assert!(e.active());
+
+        let t = e.heard(&beacon(0, 20_100), 500, 20_100).unwrap();
+        assert_eq!(
+            t.to_string(),
+            "event=role_transition role=follower from=active to=standby \
+             reason=peer_heard unix_ms=20100 peer_seq=0 own_seq=500"
+        );
+        assert!(!e.active());
+    }
+
+    #[test]
+    fn auto_yields_only_to_longer_running_peer() {
+        let mut e = election(Role::Auto);
+        assert!(e.tick(10_000).is_some());
+
+        assert!(e.heard(&beacon(3, 10_100), 40, 10_100).is_none());
+        assert!(e.active());
+        assert!(e.heard(&beacon(40, 10_200), 40, 10_200).is_some());
+        assert!(!e.active());
+    }
+
+    #[test]
+    fn priority_decides_who_stays_silent() {
+        let ranked = |seq, ts_ms, priority| Packet {
+            priority: Some(priority),
+            ..beacon(seq, ts_ms)
+        };
+        let mut e = Election::new(Role::Follower, 3_000, Some(5), Rng::new(1), 0);
+        // A lower ranked sender doesn't hold off the takeover
+        e.heard(&ranked(1, 2_000, 3), 0, 2_000);
+        assert!(e.tick(4_600).is_some());
+        assert!(e.heard(&ranked(2, 4_700, 3), 0, 4_700).is_none());
+        assert!(e.active());
+
+        let t = e.heard(&ranked(9, 4_800, 7), 1, 4_800).unwrap();
+        assert!(t.to_string().ends_with(" peer_priority=7"), "{t}");
+        // A higher ranked one does, and an equal one by the role's rule
+        assert!(e.tick(6_000).is_none());
+        assert!(e.tick(20_000).is_some());
+        assert!(e.heard(&ranked(0, 20_100, 5), 9, 20_100).is_some());
+    }
+
+    #[test]
+    fn mirrors_interval_of_consecutive_beacons() {
+        let mut e = election(Role::Follower);
+        e.heard(&beacon(7, 1_000), 0, 1_000);
+        assert_eq!(e.mirror(), None);
+        e.heard(&beacon(8, 1_500), 0, 1_500);
+        assert_eq!(e.mirror(), Some((500, 2_000)));
+    }
+}
@@ -0,0 +1,237 @@
+//! Per-packet site logic in a script (`--script`), written in whatever
+//! language the site prefers, so it needn't live in a fork of the crate.
+//!
+//! The command is started once with `sh -c` and kept running. For every
+//! data packet it reads one line on stdin and answers with one on stdout.
+//! A line is the mode, then `key=value` pairs: the packet's own, then its
+//! `--field`s.
+//!
+//! ```text
+//! send seq=42 ts_ms=1700000000123 group=3 site=lab
+//! recv src=10.0.0.5:40512 seq=42 ts_ms=1700000000123 offset_ms=-3 group=3 site=lab
+//! ```
+//!
+//! The answer is `drop`, `keep`, or `keep` followed by text: in `send`,
+//! the fields to send instead of the configured ones; in `recv`, an
+//! annotation for the measurement, as `--annotate-cmd` would attach. A
+//! dropped packet isn't sent, or in `recv` isn't acted on.
+//!
+//! Scripts run between reading the clock and sending, or between receiving
+//! and reporting, so they must answer quickly. One that takes longer than
+//! [`TIMEOUT`], answers something else or exits lets the packet through
+//! unchanged, with a warning; one that exited isn't restarted.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::SocketAddr;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::hooks::{Hooks, Verdict};
+use crate::log;
+use crate::payload::{Field, Packet};
+
+/// Longest a script may take to answer.
+pub const TIMEOUT: Duration = Duration::from_millis(50);
+
+/// A script's answer for one packet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Reply {
+    Drop,
+    /// Keep the packet, with the text following `keep`, if any.
+    Keep(Option<String>),
+}
+
+impl Reply {
+    fn parse(line: &str) -> Result<Reply, String> {
+        let line = line.trim();
+        let (word, rest) = line.split_once(' ').unwrap_or((line, ""));
+        match (word, rest.trim()) {
+            ("drop", "") => Ok(Reply::Drop),
+            ("keep", "") => Ok(Reply::Keep(None)),
+            ("keep", text) => Ok(Reply::Keep(Some(text.to_string()))),
+            _ => Err(format!("expected drop or keep, got '{line}'")),
+        }
+    }
+}
+
+/// A running script.
+pub struct Script {
+    child: Child,
+    /// Gone once the script stops reading.
+    stdin: Option<ChildStdin>,
+    replies: Receiver<String>,
+    /// Answers still owed for packets that timed out, skipped on arrival.
+    late: usize,
+}
+
+impl Script {
+    /// Starts `cmd`.
+    pub fn spawn(cmd: &str) -> Result<Script, String> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(cmd)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Failed to start script: {e}"))?;
+        let stdin = child.stdin.take();
+        let stdout = BufReader::new(child.stdout.take().expect("stdout is piped"));
+        let (tx, replies) = mpsc::channel();
+        thread::Builder::new()
+            .name("script".to_string())
+            .spawn(move || {
+                for line in stdout.lines() {
+                    let Ok(line) = line else { return };
+                    if tx.send(line).is_err() {
+                        return;
+                    }
+                }
+            })
+            .map_err(|e| format!("Failed to start the script reader: {e}"))?;
+        Ok(Script {
+            child,
+            stdin,
+            replies,
+            late: 0,
+        })
+    }
+
+    /// Hands the script one request line and waits for its answer. `None`
+    /// lets the packet through unchanged.
+    pub fn ask(&mut self, request: &str) -> Option<Reply> {
+        let stdin = self.stdin.as_mut()?;
+        if let Err(e) = writeln!(stdin, "{request}").and_then(|()| stdin.flush()) {
+            log::warn!("Script stopped reading ({e}); packets pass unchanged");
+            self.stdin = None;
+            return None;
+        }
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            match self
+                .replies
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            {
+                Ok(_) if self.late > 0 => self.late -= 1,
+                Ok(line) => {
+                    return Reply::parse(&line)
+                        .map_err(|e| log::warn!("Script answer ignored: {e}"))
+                        .ok()
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    self.late += 1;
+                    log::warn!(
+                        "Script didn't answer within {} ms; packet passed unchanged",
+                        TIMEOUT.as_millis()
+                    );
+                    return None;
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    log::warn!("Script exited; packets pass unchanged");
+                    self.stdin = None;
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Script {
+    fn drop(&mut self) {
+        self.stdin = None;
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// The line a sender's script reads for `packet`.
+pub fn send_request(packet: &Packet, fields: &[Field]) -> String {
+    let mut line = format!(
+        "send seq={} ts_ms={}",
+        packet.seq.unwrap_or(0),
+        packet.ts_ms
+    );
+    if let Some(g) = packet.group {
+        line.push_str(&format!(" group={g}"));
+    }
+    for f in fields {
+        line.push_str(&format!(" {}={}", f.key, f.value));
+    }
+    line
+}
+
+/// The line a receiver's script reads for `packet`.
+pub fn recv_request(
+    src: SocketAddr,
+    packet: &Packet,
+    offset_ms: i64,
+    fields: &[(&str, &str)],
+) -> String {
+    let mut line = format!("recv src={src}");
+    if let Some(seq) = packet.seq {
+        line.push_str(&format!(" seq={seq}"));
+    }
+    line.push_str(&format!(" ts_ms={} offset_ms={offset_ms}", packet.ts_ms));
+    if let Some(g) = packet.group {
+        line.push_str(&format!(" group={g}"));
+    }
+    for (k, v) in fields {
+        line.push_str(&format!(" {k}={v}"));
+    }
+    line
+}
+
+/// Hooks asking a sender's script about every tick.
+pub fn send_hooks(cmd: &str) -> Result<Hooks, String> {
+    let script = Mutex::new(Script::spawn(cmd)?);
+    Ok(Hooks::new().on_before_send(move |packet, fields| {
+        let request = send_request(packet, fields);
+        match script.lock().unwrap().ask(&request) {
+            Some(Reply::Drop) => return Verdict::Veto,
+            Some(Reply::Keep(Some(text))) => {
+                match text.split_whitespace().map(Field::parse).collect() {
+                    Ok(f) => *fields = f,
+                    Err(e) => log::warn!("Script fields ignored: {e}"),
+                }
+            }
+            Some(Reply::Keep(None)) | None => {}
+        }
+        Verdict::Send
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scripts_drop_keep_and_time_out() {
+        let mut script = Script::spawn(
+            "while read mode rest; do \
+               case \"$rest\" in \
+                 seq=1\\ *) echo drop ;; \
+                 seq=2\\ *) sleep 0.2; echo keep late ;; \
+                 *) echo \"keep n=${#rest}\" ;; \
+               esac; \
+             done",
+        )
+        .unwrap();
+        assert_eq!(script.ask("send seq=1 ts_ms=5"), Some(Reply::Drop));
+        assert_eq!(
+            script.ask("send seq=3 ts_ms=5"),
+            Some(Reply::Keep(Some("n=13".to_string())))
+        );
+        // The late answer is skipped rather than taken for the next one
+        assert_eq!(script.ask("send seq=2 ts_ms=5"), None);
+        thread::sleep(Duration::from_millis(250));
+        assert_eq!(
+            script.ask("send seq=30 ts_ms=5"),
+            Some(Reply::Keep(Some("n=14".to_string())))
+        );
+
+        assert_eq!(Reply::parse("keep"), Ok(Reply::Keep(None)));
+        assert!(Reply::parse("maybe").is_err());
+    }
+}
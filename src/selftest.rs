@@ -0,0 +1,293 @@
+//! A post-install sanity check over loopback (`selftest`).
+//!
+//! A receiving socket is bound on the loopback address, the way `recv`
+//! binds, and a sending socket the way `send` does. Every payload format
+//! is then sent across in every time scale, plus the v2 and proto
+//! variants that carry fields, a MAC or a signature. Each must arrive
+//! byte for byte and decode back to what was sent, its timestamp exact to
+//! the millisecond. On Linux, the kernel must also have timestamped the
+//! datagrams on receipt, as `recv` relies on for its host delay figures.
+//!
+//! Each check prints one `PASS` or `FAIL` line; the exit code is 0 only
+//! if all of them pass.
+
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::process::ExitCode;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::auth::Key;
+use crate::ed25519::{SigningKey, KEY_ID_LEN};
+use crate::epoch::Epoch;
+use crate::exit::{self, Failure};
+use crate::payload::{self, Field, Format, Packet};
+use crate::rng::Rng;
+use crate::send::bind_for;
+use crate::sys;
+use crate::transmit;
+
+/// How long to wait for each datagram to come back.
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Settings for `selftest`.
+pub struct SelftestConfig {
+    /// Loopback address to run over, 127.0.0.1 or ::1.
+    pub addr: IpAddr,
+}
+
+/// One payload to send across, and what it exercises.
+struct Case {
+    name: String,
+    format: Format,
+    packet: Packet,
+    fields: Vec<Field>,
+    key: Option<Key>,
+    sign_key: Option<SigningKey>,
+}
+
+/// Builds the cases: every format in every time scale, then the optional
+/// parts of v2 and proto payloads.
+fn cases(rng: &mut Rng) -> Vec<Case> {
+    let formats = [
+        ("legacy", Format::Legacy),
+        ("v2", Format::V2),
+        ("proto", Format::Proto),
+        ("compact", Format::Compact),
+    ];
+    let packet = |epoch| Packet {
+        epoch,
+        ..Packet::new(Some(0), 0)
+    };
+    let case = |name: String, format, packet| Case {
+        name,
+        format,
+        packet,
+        fields: Vec::new(),
+        key: None,
+        sign_key: None,
+    };
+
+    let mut cases = Vec::new();
+    for (name, format) in formats {
+        for epoch in [Epoch::Unix, Epoch::Ntp, Epoch::Gps] {
+            cases.push(case(format!("{name}_{epoch}"), format, packet(epoch)));
+        }
+    }
+    for (name, format) in &formats[1..3] {
+        let mut c = case(
+            format!("{name}_group_monotonic_fields"),
+            *format,
+            Packet {
+                group: Some(7),
+                mono_us: Some(rng.next_u64() >> 24),
+                era: None,
+                ..packet(Epoch::Unix)
+            },
+        );
+        c.fields = vec![
+            Field::parse("site=selftest").expect("valid field"),
+            Field::parse("rack=A12").expect("valid field"),
+        ];
+        cases.push(c);
+    }
+    let mut secret = [0u8; 32];
+    secret.fill_with(|| rng.next_u64() as u8);
+    let mut c = case("v2_mac".into(), Format::V2, packet(Epoch::Unix));
+    c.packet.group = Some(7);
+    c.key = Some(Key::new(&secret));
+    cases.push(c);
+    let mut c = case("v2_signed".into(), Format::V2, packet(Epoch::Unix));
+    c.sign_key = Some(SigningKey::from_seed(&secret));
+    cases.push(c);
+    cases
+}
+
+/// What a receiver should decode `case` to, given what its format keeps.
+fn expected(case: &Case) -> Packet {
+    match case.format {
+        Format::Legacy => Packet {
+            seq: None,
+            group: None,
+            mono_us: None,
+            era: None,
+            ..case.packet
+        },
+        Format::Compact => Packet {
+            group: None,
+            mono_us: None,
+            era: None,
+            ..case.packet
+        },
+        Format::V2 | Format::Proto => case.packet,
+    }
+}
+
+/// Sends `case` from `tx` to `dest` and checks what `rx` receives,
+/// returning why it failed, if it did, and whether the kernel stamped it.
+fn round_trip(
+    tx: &UdpSocket,
+    rx: &UdpSocket,
+    dest: SocketAddr,
+    case: &Case,
+) -> (Option<String>, bool) {
+    let mut out = Vec::new();
+    payload::encode_with_fields(
+        case.format,
+        &case.packet,
+        &case.fields,
+        case.key.as_ref(),
+        &mut out,
+    );
+    if let Some(key) = &case.sign_key {
+        payload::sign(&mut out, key);
+    }
+    if let Err(e) = transmit::send_to(tx, &out, dest) {
+        return (Some(format!("send_to({dest}) failed: {e}")), false);
+    }
+
+    let mut buf = [0u8; 1500];
+    let meta = match sys::recv(rx, &mut buf) {
+        Ok(m) => m,
+        Err(e) => return (Some(format!("nothing received: {e}")), false),
+    };
+    let stamped = meta.kernel_time.is_some();
+    let data = &buf[..meta.len];
+    if data != out.as_slice() {
+        return (
+            Some(format!(
+                "sent {} bytes, received {} that differ",
+                out.len(),
+                data.len()
+            )),
+            stamped,
+        );
+    }
+    let decoded = match payload::decode(data) {
+        Ok(d) => d,
+        Err(e) => return (Some(format!("failed to decode: {e}")), stamped),
+    };
+    let mut got = decoded.packet;
+    if matches!(decoded.format, Format::Legacy | Format::Compact) {
+        // As `recv --epoch` does
+        got.epoch = case.packet.epoch;
+        match got.epoch.to_unix_ms(got.ts_ms) {
+            Some(ts_ms) => got.ts_ms = ts_ms,
+            None => return (Some("timestamp out of range".to_string()), stamped),
+        }
+    }
+    let fields: Vec<(&str, &str)> = case
+        .fields
+        .iter()
+        .map(|f| (f.key.as_str(), f.value.as_str()))
+        .collect();
+    let failure = if decoded.format != case.format {
+        Some(format!("decoded as {:?}", decoded.format))
+    } else if got != expected(case) {
+        Some(format!("sent {:?}, decoded {got:?}", expected(case)))
+    } else if decoded.fields != fields {
+        Some(format!("fields decoded as {:?}", decoded.fields))
+    } else if case.key.as_ref().is_some_and(|k| !decoded.verify(k)) {
+        Some("MAC rejected".to_string())
+    } else if let Some(key) = &case.sign_key {
+        match decoded.sig {
+            Some((value, covered)) => {
+                let (key_id, sig) = value.split_at(KEY_ID_LEN);
+                (key_id != key.public().id() || !key.public().verify(covered, sig))
+                    .then(|| "signature rejected".to_string())
+            }
+            None => Some("signature missing".to_string()),
+        }
+    } else {
+        None
+    };
+    (failure, stamped)
+}
+
+/// Runs every case, returning each one's name and failure, if any.
+fn check(addr: IpAddr) -> Result<Vec<(String, Option<String>)>, Failure> {
+    let rx = UdpSocket::bind(SocketAddr::new(addr, 0))
+        .map_err(|e| Failure::io(&e, format!("Failed to bind UDP socket on {addr}: {e}")))?;
+    let dest = rx
+        .local_addr()
+        .map_err(|e| format!("Failed to read local address: {e}"))?;
+    rx.set_read_timeout(Some(RECEIVE_TIMEOUT))
+        .map_err(|e| format!("Failed to set receive timeout: {e}"))?;
+    let timestamps = sys::enable_rx_timestamps(&rx);
+    let tx = bind_for(dest)?;
+    println!("Self-test over {dest}");
+
+    let start = Instant::now();
+    let mut rng = Rng::from_entropy();
+    let mut outcomes = Vec::new();
+    let mut stamped = 0;
+    let cases = cases(&mut rng);
+    for (seq, mut case) in cases.into_iter().enumerate() {
+        case.packet.seq = case.packet.seq.map(|_| seq as u32);
+        case.packet.ts_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| format!("System clock error (before UNIX_EPOCH): {e:?}"))?
+            .as_millis() as u64;
+        if case.packet.mono_us.is_some() {
+            case.packet.mono_us = Some(start.elapsed().as_micros() as u64);
+        }
+        let (failure, kernel_time) = round_trip(&tx, &rx, dest, &case);
+        stamped += usize::from(kernel_time);
+        outcomes.push((case.name, failure));
+    }
+
+    if cfg!(target_os = "linux") {
+        let failure = match timestamps {
+            Err(e) => Some(format!("SO_TIMESTAMPNS refused: {e}")),
+            Ok(()) if stamped < outcomes.len() => Some(format!(
+                "{stamped} of {} datagrams carried one",
+                outcomes.len()
+            )),
+            Ok(()) => None,
+        };
+        outcomes.push(("kernel_rx_timestamps".to_string(), failure));
+    }
+    Ok(outcomes)
+}
+
+/// Runs the self-test, exiting non-zero if any check fails.
+pub fn run(cfg: &SelftestConfig) -> ExitCode {
+    let outcomes = match check(cfg.addr) {
+        Ok(o) => o,
+        Err(e) => return exit::fail(e),
+    };
+    let mut failed = 0;
+    for (name, failure) in &outcomes {
+        match failure {
+            None => println!("PASS {name}"),
+            Some(why) => {
+                failed += 1;
+                println!("FAIL {name}: {why}");
+            }
+        }
+    }
+    println!(
+        "{} of {} checks passed",
+        outcomes.len() - failed,
+        outcomes.len()
+    );
+    if failed == 0 {
+        ExitCode::SUCCESS
+    } else {
+        exit::fail(format!("{failed} self-test check(s) failed"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn every_case_passes_over_loopback() {
+        let outcomes = check(IpAddr::V4(Ipv4Addr::LOCALHOST)).unwrap();
+        // Three formats in three scales, fields for two, MAC, signature
+        assert!(outcomes.len() >= 9 + 2 + 2);
+        for (name, failure) in outcomes {
+            assert_eq!(failure, None, "{name}");
+        }
+    }
+}
@@ -0,0 +1,3596 @@
+//! Sender mode: periodically broadcasts the current timestamp.
+//!
+//! The per-tick logic lives in [`Sender`], which is generic over its
+//! [`Clock`] and [`DatagramSink`] so it can be exercised without real
+//! networking; [`run`] wires it to the system clock and a UDP socket.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::adaptive::{self, Ack, AdaptiveConfig, Controller};
+use crate::aead::{self, AeadKey};
+use crate::api::{self, Api, ApiConfig, Op, Request};
+use crate::auth::Key;
+use crate::blackout::{self, Blackout};
+#[cfg(feature = "chaos")]
+use crate::chaos::ChaosConfig;
+use crate::clock;
+use crate::compact;
+use crate::control::{bind_receiver, FollowConfig, Follower};
+use crate::ed25519::SigningKey;
+use crate::epoch::Epoch;
+use crate::exit::{self, Failure};
+use crate::guard::{self, Guard, Mode as GuardMode};
+use crate::hooks::{Hooks, Outcome, Verdict};
+use crate::hotplug;
+use crate::iface;
+use crate::lateness::Lateness;
+use crate::leap::Smear;
+use crate::log;
+use crate::mdns::{self, Advert};
+use crate::message::MessageType;
+use crate::offload::Offload;
+use crate::otlp::{Otlp, OtlpConfig, Tick};
+use crate::payload::{self, Control, Field, Format, Packet, PayloadCodec};
+use crate::peers::PeerSink;
+use crate::plan::{self, Plan};
+use crate::privs::Hardening;
+use crate::query::{self, Responder};
+use crate::rate::TokenBucket;
+use crate::resolve::Target;
+use crate::rng::Rng;
+use crate::role::{Election, Role};
+use crate::snapshot::{DestCounts, DestSnapshot, Snapshot, Snapshots};
+use crate::sntp::{self, CheckConfig};
+use crate::sockerr::{ErrorClass, SendErrors};
+use crate::start::{self, StartConfig};
+use crate::state::{self, SeqState};
+use crate::statsd::{Statsd, StatsdConfig};
+use crate::status::{self, Health};
+use crate::step;
+use crate::sys;
+use crate::transmit::{self, DatagramSink};
+use crate::transport::{StreamServer, Transport};
+use crate::txdelay::{Compensation, SendDelay};
+use crate::units;
+use crate::{EXIT_CODE_CLOCK_ERROR, EXIT_CODE_USAGE_ERROR};
+
+/// The clocks a [`Sender`] can run on.
+pub use crate::clock::{Clock, SystemClock};
+
+/// Settings for send mode.
+#[derive(Clone)]
+pub struct SendConfig {
+    pub target: Target,
+    pub port: u16,
+    pub interval: Duration,
+    /// Send this many datagrams per second from a token bucket instead of
+    /// one burst per `interval`.
+    pub rate: Option<f64>,
+    /// Adjust the interval within these bounds from receiver acks.
+    pub adaptive: Option<AdaptiveConfig>,
+    /// Maximum deviation of each inter-send gap from `interval`.
+    pub jitter: Duration,
+    /// Warn about ticks that start sending more than this after their deadline.
+    pub max_lateness: Option<Duration>,
+    /// Add the time it takes to send to each timestamp, fixed or as
+    /// measured.
+    pub tx_compensation: Option<Compensation>,
+    /// Hand each tick's datagrams to the kernel this long before they are
+    /// due, with the time to send them (SO_TXTIME), and stamp that time.
+    pub txtime: Option<Duration>,
+    /// Datagrams sent back-to-back on each tick, and the gap between them.
+    pub burst: u32,
+    pub burst_spacing: Duration,
+    /// Copies of each datagram, all with the same sequence number, and the
+    /// gap between them.
+    pub redundancy: u32,
+    pub redundancy_spacing: Duration,
+    pub format: Format,
+    /// With [`Format::Compact`], send a full frame every this many datagrams.
+    pub full_every: u8,
+    /// Broadcast over UDP, or offer the stream to TCP consumers on
+    /// `target`:`port`.
+    pub transport: Transport,
+    /// Encodes payloads instead of `format`, see [`PayloadCodec`].
+    pub codec: Option<Arc<dyn PayloadCodec>>,
+    /// Callbacks around each datagram, see [`Hooks`].
+    pub hooks: Option<Arc<Hooks>>,
+    /// Scale to send the timestamp in.
+    pub epoch: Epoch,
+    /// How often to re-resolve a hostname target; `None` resolves once.
+    pub resolve_interval: Option<Duration>,
+    /// Beacon group to tag payloads with (v2 only).
+    pub group: Option<u16>,
+    /// Metadata to attach to every payload (v2 only).
+    pub fields: Vec<Field>,
+    /// Key to authenticate payloads with (v2 only).
+    pub key: Option<Key>,
+    /// Ed25519 key to sign payloads with (v2 only).
+    pub sign_key: Option<SigningKey>,
+    /// End payloads with a checksum (v2 only).
+    pub crc: bool,
+    /// Key to encrypt payloads with (v2 only).
+    pub encrypt_key: Option<AeadKey>,
+    /// Apply fleet control commands received on this port.
+    pub follow: Option<FollowConfig>,
+    /// Whether to transmit unconditionally or only while no other sender is heard.
+    pub role: Role,
+    /// Silence after which a follower is promoted.
+    pub failover: Duration,
+    /// Watch the port for another sender of the same group.
+    pub guard: Option<GuardMode>,
+    /// Send a single timestamp and exit.
+    pub once: bool,
+    /// With `once`, how long to wait for a receiver's ack.
+    pub wait_ack: Option<Duration>,
+    /// Rank among redundant senders, sent with every timestamp (v2 and
+    /// proto).
+    pub priority: Option<u8>,
+    /// Replace the limited broadcast with each interface's directed broadcast.
+    pub per_interface: bool,
+    /// Agent to push send counters to.
+    pub statsd: Option<StatsdConfig>,
+    /// Export a span per tick and send metrics over OTLP.
+    pub otlp: Option<OtlpConfig>,
+    /// Advertise the beacon over mDNS/DNS-SD.
+    pub mdns: bool,
+    /// Instance name to advertise instead of one derived from the host name.
+    pub mdns_name: Option<String>,
+    /// Smear leap seconds out of the broadcast timestamp.
+    pub leap_smear: Option<Smear>,
+    /// Stop sending for this long after the wall clock steps.
+    pub step_holddown: Option<Duration>,
+    /// Hold or flag broadcasts while the kernel doesn't count the clock
+    /// as synchronized.
+    pub require_clock_sync: Option<ClockSync>,
+    /// SCHED_FIFO priority for the send loop.
+    pub sched_fifo: Option<i32>,
+    /// CPU core to pin the send loop to.
+    pub cpu_affinity: Option<usize>,
+    /// User to switch to and syscalls to allow once set up.
+    pub hardening: Hardening,
+    /// Requested send buffer size (SO_SNDBUF), in bytes.
+    pub sndbuf: Option<u32>,
+    /// Longest a send may block before it fails (SO_SNDTIMEO).
+    pub send_timeout: Option<Duration>,
+    /// IP TTL for outgoing datagrams (IPv4 only).
+    pub ttl: Option<u32>,
+    /// Let payloads too large for the path MTU go out in fragments,
+    /// instead of refusing to start and setting don't-fragment.
+    pub allow_fragmentation: bool,
+    /// Unicast to these peers instead of `target`.
+    pub peers: Option<Vec<SocketAddr>>,
+    /// Send to each of these on its own interval instead of `target`.
+    pub dests: Vec<Dest>,
+    /// IPv6 multicast group to send every datagram to as well as `target`.
+    pub dual_stack: Option<Target>,
+    /// Send from an I/O thread, through a queue of this many ticks.
+    pub io_queue: Option<usize>,
+    /// How long to skip a peer after an ICMP unreachable.
+    pub peer_backoff: Option<Duration>,
+    /// Send to the peers from this many threads.
+    pub fanout_threads: Option<usize>,
+    /// Serve `/healthz` and `/status` over HTTP here.
+    pub status_addr: Option<SocketAddr>,
+    /// Answer time queries on this port.
+    pub serve_queries: Option<u16>,
+    /// Take pause, interval and destination changes over HTTP here.
+    pub control_api: Option<ApiConfig>,
+    /// Refuse to start unless the clock agrees with this NTP server.
+    pub require_sync: Option<CheckConfig>,
+    /// Print what would be sent where, and exit without sending.
+    pub dry_run: bool,
+    /// When to send the first datagram.
+    pub start: StartConfig,
+    /// Append statistics snapshots here instead of writing them to stderr.
+    pub snapshot_file: Option<String>,
+    /// Keep the next sequence number and clock era in a file here, to
+    /// resume from after a restart.
+    pub state_dir: Option<PathBuf>,
+    /// Windows in which to hold back datagrams, to all destinations or
+    /// to one.
+    pub blackout: Vec<Blackout>,
+    /// Drop and delay datagrams at random, for testing receivers.
+    #[cfg(feature = "chaos")]
+    pub chaos: Option<ChaosConfig>,
+}
+
+impl SendConfig {
+    /// The time between sends: `interval`, or about one token under `rate`.
+    pub fn pace(&self) -> Duration {
+        self.rate
+            .map_or(self.interval, |pps| Duration::from_secs_f64(1.0 / pps))
+    }
+
+    /// How long one tick's burst and its redundant copies take to send;
+    /// `None` if that overflows.
+    pub fn tick_span(&self) -> Option<Duration> {
+        let copies = self
+            .redundancy_spacing
+            .checked_mul(self.redundancy.max(1) - 1)?;
+        if self.burst_spacing.is_zero() {
+            // The whole burst goes out at once, copies after it
+            return Some(copies);
+        }
+        self.burst_spacing
+            .checked_mul(self.burst - 1)?
+            .checked_add(copies.checked_mul(self.burst)?)
+    }
+}
+
+/// How often `--require-clock-sync` re-reads the kernel's status.
+pub const SYNC_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What `--require-clock-sync` does while the clock isn't synchronized.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncMode {
+    /// Send nothing.
+    Hold,
+    /// Send, flagging each timestamp as unsynchronized.
+    Mark,
+}
+
+impl SyncMode {
+    pub fn parse(s: &str) -> Result<SyncMode, String> {
+        match s {
+            "hold" => Ok(SyncMode::Hold),
+            "mark" => Ok(SyncMode::Mark),
+            _ => Err(format!("expected hold or mark, got '{s}'")),
+        }
+    }
+}
+
+/// `--require-clock-sync`, with the largest error a synchronized clock
+/// may have.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClockSync {
+    pub mode: SyncMode,
+    pub max_error: Duration,
+}
+
+/// One `--dest`: a destination with an interval of its own.
+#[derive(Clone, Debug)]
+pub struct Dest {
+    pub target: Target,
+    pub port: u16,
+    pub interval: Duration,
+}
+
+impl Dest {
+    /// Parses `ADDR:PORT@INTERVAL`, e.g. `10.0.0.255:12321@100ms` or
+    /// `ff02::1%eth1:12321@1s`. IPv6 addresses may also be bracketed.
+    pub fn parse(s: &str) -> Result<Dest, String> {
+        let usage = || "expected ADDR:PORT@INTERVAL, e.g. 10.0.0.255:12321@100ms".to_string();
+        let (addr, interval) = s.rsplit_once('@').ok_or_else(usage)?;
+        let (host, port) = addr.rsplit_once(':').ok_or_else(usage)?;
+        let host = host
+            .strip_prefix('[')
+            .and_then(|h| h.strip_suffix(']'))
+            .unwrap_or(host);
+        let port = match port.parse::<u16>() {
+            Ok(p) if p > 0 => p,
+            _ => return Err(format!("bad port '{port}'")),
+        };
+        Ok(Dest {
+            target: Target::parse(host)?,
+            port,
+            interval: parse_interval(interval)?,
+        })
+    }
+}
+
+/// Parses a `--dual-stack` group: an IPv6 multicast address, with a zone
+/// if it is link-local.
+pub fn parse_dual_stack(s: &str) -> Result<Target, String> {
+    match Target::parse(s)? {
+        t @ Target::Scoped(ip, ..) if ip.is_multicast() => Ok(t),
+        t @ Target::Ip(IpAddr::V6(ip)) if ip.is_multicast() => Ok(t),
+        _ => Err(format!("'{s}' is not an IPv6 multicast group")),
+    }
+}
+
+/// Parses an interval with a unit, e.g. `250us`, `1.5ms` or `2s`. It must
+/// be at least 1µs.
+pub fn parse_interval(s: &str) -> Result<Duration, String> {
+    let (value, scale) = [("us", 1e-6), ("µs", 1e-6), ("ms", 1e-3), ("s", 1.0)]
+        .into_iter()
+        .find_map(|(unit, scale)| s.strip_suffix(unit).map(|v| (v, scale)))
+        .ok_or_else(|| format!("interval '{s}' needs a unit: us, ms or s"))?;
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|v| v.is_finite())
+        .map(|v| v * scale)
+        // Rounded to whole nanoseconds first, so that 0.001ms counts as 1µs
+        .filter(|&secs| (secs * 1e9).round() >= 1e3)
+        .map(|secs| Duration::from_nanos((secs * 1e9).round() as u64))
+        .ok_or_else(|| format!("interval '{s}' must be at least 1us"))
+}
+
+/// What [`run`] sends through: one socket, or one per `--peers` entry.
+pub enum Outlet {
+    Socket(UdpSocket),
+    Peers(PeerSink),
+    /// Every connected consumer, with `--transport tcp`.
+    Stream(StreamServer),
+    /// One socket per family, for `--dual-stack`.
+    DualStack {
+        v4: UdpSocket,
+        v6: UdpSocket,
+    },
+    /// Any of the above, sent through from an I/O thread with `--io-thread`.
+    IoThread {
+        worker: Offload<Outlet>,
+        /// The local port of the outlet the thread has.
+        port: Option<u16>,
+    },
+}
+
+impl Outlet {
+    /// Binds a socket per family for `--dual-stack`, returning the outlet
+    /// and the two destinations to fan out to.
+    fn dual_stack(
+        cfg: &SendConfig,
+        dest: SocketAddr,
+        group: &Target,
+    ) -> Result<(Outlet, Vec<SocketAddr>), Failure> {
+        let group = group.resolve(cfg.port, None)?;
+        check_route(group)?;
+        let outlet = Outlet::DualStack {
+            v4: bind_tuned(cfg, dest)?,
+            v6: bind_tuned(cfg, group)?,
+        };
+        Ok((outlet, vec![dest, group]))
+    }
+
+    /// Hands the outlet to an I/O thread, with `--io-thread`.
+    fn offloaded(self, cfg: &SendConfig) -> Outlet {
+        match cfg.io_queue {
+            Some(depth) => Outlet::IoThread {
+                port: self.local_port(),
+                worker: Offload::start(self, depth),
+            },
+            None => self,
+        }
+    }
+
+    /// The local port datagrams leave from, to recognize them looping back.
+    fn local_port(&self) -> Option<u16> {
+        match self {
+            Outlet::Socket(sock) | Outlet::DualStack { v4: sock, .. } => {
+                sock.local_addr().ok().map(|a| a.port())
+            }
+            Outlet::Peers(_) | Outlet::Stream(_) => None,
+            Outlet::IoThread { port, .. } => *port,
+        }
+    }
+}
+
+/// Points `sender` at `dest`, through `outlet` if given. An I/O thread
+/// carries on with the new outlet, rather than another being started.
+fn retarget<C: Clock>(
+    sender: &mut Sender<'_, C, Outlet>,
+    dest: SocketAddr,
+    outlet: Option<Outlet>,
+) {
+    let outlet = match (sender.sink_mut(), outlet) {
+        (Outlet::IoThread { worker, port }, Some(o)) => {
+            *port = o.local_port();
+            worker.replace(o);
+            None
+        }
+        (_, o) => o,
+    };
+    sender.retarget(dest, outlet);
+}
+
+impl DatagramSink for Outlet {
+    fn send_to(&mut self, payload: &[u8], dest: SocketAddr) -> io::Result<usize> {
+        match self {
+            Outlet::Socket(sock) => transmit::send_to(sock, payload, dest),
+            Outlet::Peers(peers) => peers.send_to(payload, dest),
+            Outlet::Stream(server) => server.send_to(payload, dest),
+            Outlet::DualStack { v4, v6 } => {
+                transmit::send_to(if dest.is_ipv4() { v4 } else { v6 }, payload, dest)
+            }
+            Outlet::IoThread { worker, .. } => worker.send_to(payload, dest),
+        }
+    }
+
+    fn send_batch(&mut self, msgs: &[(&[u8], SocketAddr)]) -> Vec<io::Result<usize>> {
+        match self {
+            Outlet::Socket(sock) => transmit::send_batch(sock, msgs),
+            // Each peer has its own socket, so there's nothing to batch
+            Outlet::Peers(peers) => peers.send_batch(msgs),
+            Outlet::Stream(server) => server.send_batch(msgs),
+            // Each family is batched on its own socket, and the results
+            // put back in order
+            Outlet::DualStack { v4, v6 } => {
+                let (four, six): (Vec<_>, Vec<_>) =
+                    msgs.iter().copied().partition(|(_, d)| d.is_ipv4());
+                let mut four = transmit::send_batch(v4, &four).into_iter();
+                let mut six = transmit::send_batch(v6, &six).into_iter();
+                msgs.iter()
+                    .map(|(_, d)| {
+                        let next = if d.is_ipv4() { four.next() } else { six.next() };
+                        next.unwrap_or_else(|| Err(io::ErrorKind::WriteZero.into()))
+                    })
+                    .collect()
+            }
+            Outlet::IoThread { worker, .. } => worker.send_batch(msgs),
+        }
+    }
+
+    fn send_batch_after(
+        &mut self,
+        msgs: &[(&[u8], SocketAddr)],
+        delay: Duration,
+    ) -> Vec<io::Result<usize>> {
+        let at_ns = match sys::clock_tai_ns() {
+            Ok(now) => now + delay.as_nanos() as u64,
+            Err(e) => {
+                return msgs
+                    .iter()
+                    .map(|_| Err(io::Error::new(e.kind(), e.to_string())))
+                    .collect()
+            }
+        };
+        match self {
+            Outlet::Socket(sock) => transmit::send_batch_at(sock, msgs, at_ns),
+            Outlet::DualStack { v4, v6 } => {
+                let (four, six): (Vec<_>, Vec<_>) =
+                    msgs.iter().copied().partition(|(_, d)| d.is_ipv4());
+                let mut four = transmit::send_batch_at(v4, &four, at_ns).into_iter();
+                let mut six = transmit::send_batch_at(v6, &six, at_ns).into_iter();
+                msgs.iter()
+                    .map(|(_, d)| {
+                        let next = if d.is_ipv4() { four.next() } else { six.next() };
+                        next.unwrap_or_else(|| Err(io::ErrorKind::WriteZero.into()))
+                    })
+                    .collect()
+            }
+            // --txtime rules these out
+            Outlet::Peers(_) | Outlet::Stream(_) | Outlet::IoThread { .. } => self.send_batch(msgs),
+        }
+    }
+
+    fn paused(&mut self, dest: SocketAddr) -> bool {
+        match self {
+            Outlet::Socket(_)
+            | Outlet::Stream(_)
+            | Outlet::DualStack { .. }
+            | Outlet::IoThread { .. } => false,
+            Outlet::Peers(peers) => peers.paused(dest),
+        }
+    }
+
+    fn offload(
+        &mut self,
+        msgs: &[(&[u8], SocketAddr)],
+        stamps: &[u64],
+    ) -> Option<Vec<io::Result<usize>>> {
+        match self {
+            Outlet::IoThread { worker, .. } => worker.offload(msgs, stamps),
+            _ => None,
+        }
+    }
+}
+
+/// Binds an ephemeral local port on the address family of `dest`, with
+/// broadcast enabled.
+pub fn bind_for(dest: SocketAddr) -> Result<UdpSocket, Failure> {
+    // Bind to an ephemeral local port on the appropriate address family.
+    // (This avoids having to know the local interface address.)
+    let bind_addr = match dest.ip() {
+        IpAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        IpAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    };
+
+    let sock = UdpSocket::bind(bind_addr)
+        .map_err(|e| Failure::io(&e, format!("Failed to bind UDP socket on {bind_addr}: {e}")))?;
+    sock.set_broadcast(true)
+        .map_err(|e| Failure::io(&e, format!("Failed to enable broadcast: {e}")))?;
+    Ok(sock)
+}
+
+/// IP and UDP header bytes in front of each payload sent to `dest`.
+fn header_len(dest: SocketAddr) -> usize {
+    if dest.is_ipv4() {
+        20 + 8
+    } else {
+        40 + 8
+    }
+}
+
+/// How long a run the largest payload is sized for: the monotonic time in
+/// a protobuf payload takes more bytes as it grows.
+const LONG_RUN: Duration = Duration::from_secs(10 * 365 * 24 * 3600);
+
+/// Encodes the largest payload `cfg` sends: after a clock step has set the
+/// era, and years into the run.
+fn largest_payload(cfg: &SendConfig, dest: SocketAddr) -> Result<Vec<u8>, Failure> {
+    let clock = SystemClock::for_interval(cfg.pace());
+    // Sizing isn't a tick, so the hooks don't see it
+    let cfg = &SendConfig {
+        hooks: None,
+        ..cfg.clone()
+    };
+    let mut sender = Sender::new(cfg, clock, Outlet::Socket(bind_for(dest)?), dest);
+    sender.era = u16::MAX;
+    sender.start = sender.start.checked_sub(LONG_RUN).unwrap_or(sender.start);
+    let mut payload = Vec::new();
+    sender.encode_next(&mut payload)?;
+    Ok(payload)
+}
+
+/// The MTU of the route to `dest`, where the platform reports it.
+fn route_mtu(dest: SocketAddr) -> Result<u32, String> {
+    let sock = bind_for(dest)?;
+    sock.connect(dest)
+        .map_err(|e| format!("No route to {dest}: {e}"))?;
+    sys::path_mtu(&sock).map_err(|e| e.to_string())
+}
+
+/// Refuses to start if the largest payload wouldn't fit the MTU towards
+/// each of `dests` unfragmented, unless `--allow-fragmentation` is set.
+fn check_mtu(cfg: &SendConfig, dests: &[SocketAddr]) -> Result<(), Failure> {
+    let Some(&first) = dests.first().filter(|_| !cfg.allow_fragmentation) else {
+        return Ok(());
+    };
+    let payload = largest_payload(cfg, first)?;
+    for &dest in dests {
+        let mtu = match route_mtu(dest) {
+            Ok(mtu) => mtu as usize,
+            Err(e) => {
+                log::debug!("Not checking the MTU towards {dest}: {e}");
+                continue;
+            }
+        };
+        let wire = payload.len() + header_len(dest);
+        if wire > mtu {
+            return Err(Failure::new(
+                EXIT_CODE_USAGE_ERROR,
+                format!(
+                    "A {}-byte payload takes {wire} bytes with its headers, more than the {mtu}-byte MTU towards {dest}: it would be fragmented, or dropped. Send fewer --field bytes, or pass --allow-fragmentation",
+                    payload.len()
+                ),
+            ));
+        }
+        log::debug!("MTU towards {dest}: {mtu} bytes, {wire} needed");
+    }
+    Ok(())
+}
+
+/// Checks that there is a route to `dest`, so that a host whose network
+/// isn't up yet fails at startup rather than on every send.
+fn check_route(dest: SocketAddr) -> Result<(), Failure> {
+    // Connecting a UDP socket sends nothing but does look up the route
+    bind_for(dest)?
+        .connect(dest)
+        .map_err(|e| Failure::io(&e, format!("No route to {dest}: {e}")))
+}
+
+/// The send loop's state: what to send, where, and through what.
+pub struct Sender<'a, C: Clock, S: DatagramSink> {
+    cfg: &'a SendConfig,
+    clock: C,
+    sink: S,
+    /// Every datagram goes to each of these; more than one with `--per-interface`.
+    dests: Vec<SocketAddr>,
+    seq: u32,
+    /// Salt of the nonces payloads are sealed with, see [`payload::seal`].
+    salt: [u8; payload::SALT_LEN],
+    /// One encoded payload per datagram of the burst being sent.
+    bufs: Vec<Vec<u8>>,
+    /// The timestamp of each payload in `bufs`.
+    stamps: Vec<u64>,
+    /// (payload index, destination) of each datagram of the burst.
+    queue: Vec<(usize, SocketAddr)>,
+    /// The timestamp of each queued datagram, for the I/O thread.
+    queued: Vec<u64>,
+    /// Current interval; starts at `cfg.interval`, changed by [`Self::reschedule`].
+    interval: Duration,
+    /// Nominal time of the next tick; ticks sit on a fixed grid.
+    next_tick: Option<Instant>,
+    /// When the next tick should start: `next_tick`, jittered.
+    deadline: Option<Instant>,
+    lateness: Lateness,
+    /// False while a follower stands by: ticks pass without sending.
+    active: bool,
+    /// Set over the control API; ticks pass without sending, as in standby.
+    paused: bool,
+    /// Replaces the tick grid with `--rate`.
+    bucket: Option<TokenBucket>,
+    rng: Rng,
+    /// Origin of the monotonic timestamps sent alongside wall-clock time.
+    start: Instant,
+    statsd: Option<Statsd>,
+    otlp: Option<Otlp>,
+    /// Outcome of the tick in progress, while exporting over OTLP.
+    span: Option<Tick>,
+    health: Option<Arc<Health>>,
+    smear: Option<Smear>,
+    /// Chooses full or delta frames with `--format compact`.
+    compact: compact::Encoder,
+    /// Failed sends by class, and destinations backing off.
+    errors: SendErrors,
+    /// Sends and failures by destination, for snapshots.
+    counts: BTreeMap<SocketAddr, DestCounts>,
+    /// Set by a control API request; the loop writes the snapshot.
+    snapshot_requested: bool,
+    /// Wall-clock steps seen so far, sent as the payload's era.
+    era: u16,
+    /// Both clocks at the last tick, to tell a step from time passing.
+    last_clocks: Option<(SystemTime, Instant)>,
+    /// End of the `--step-holddown` after the last step.
+    hold_until: Option<Instant>,
+    /// When `--require-clock-sync` last read the kernel's status.
+    sync_checked: Option<Instant>,
+    /// The clock wasn't synchronized at the last reading.
+    unsynced: bool,
+    /// From reading the clock to sending, for `--tx-compensation-us`.
+    send_delay: SendDelay,
+    /// When the tick in progress is to leave, with `--txtime`.
+    launch: Option<Instant>,
+    /// Where sequence numbers are kept across restarts.
+    state: Option<SeqState>,
+    /// Destinations in a `--blackout` window, with when it began and
+    /// when it ends.
+    blacked_out: BTreeMap<SocketAddr, (SystemTime, SystemTime)>,
+}
+
+/// Converts wall-clock `time` to milliseconds since the Unix epoch.
+fn unix_ms(time: SystemTime) -> Result<u64, String> {
+    let d = time
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| format!("System clock error (before UNIX_EPOCH): {e:?}"))?;
+    // Convert u128 to u64, checking for overflow
+    d.as_millis()
+        .try_into()
+        .map_err(|_| "Timestamp overflow: system time too large for u64".to_string())
+}
+
+impl<'a, C: Clock, S: DatagramSink> Sender<'a, C, S> {
+    pub fn new(cfg: &'a SendConfig, clock: C, sink: S, dest: SocketAddr) -> Self {
+        let start = clock.instant();
+        Sender {
+            cfg,
+            clock,
+            sink,
+            dests: vec![dest],
+            seq: 0,
+            salt: [0; payload::SALT_LEN],
+            bufs: Vec::new(),
+            stamps: Vec::new(),
+            queue: Vec::new(),
+            queued: Vec::new(),
+            interval: cfg.interval,
+            next_tick: None,
+            deadline: None,
+            lateness: Lateness::default(),
+            active: true,
+            paused: false,
+            bucket: cfg.rate.map(TokenBucket::new),
+            rng: Rng::from_entropy(),
+            start,
+            statsd: None,
+            otlp: None,
+            span: None,
+            health: None,
+            smear: cfg.leap_smear.clone(),
+            compact: compact::Encoder::new(cfg.full_every),
+            errors: SendErrors::default(),
+            counts: BTreeMap::new(),
+            snapshot_requested: false,
+            era: 0,
+            last_clocks: None,
+            hold_until: None,
+            sync_checked: None,
+            unsynced: false,
+            send_delay: SendDelay::default(),
+            launch: None,
+            state: None,
+            blacked_out: BTreeMap::new(),
+        }
+    }
+
+    /// Carries on from the sequence number and clock era in `state`, and
+    /// keeps them there from now on.
+    pub fn resume(&mut self, state: SeqState) {
+        let saved = state.saved();
+        if saved.seq != 0 || saved.era != 0 {
+            log::info!("Resuming at seq={} era={}", saved.seq, saved.era);
+        }
+        self.seq = saved.seq;
+        self.era = saved.era;
+        // Sealed datagrams of the last run used other salts with these
+        // sequence numbers, so a new one is needed even past 0
+        self.salt = aead::salt();
+        self.state = Some(state);
+    }
+
+    /// Saves exactly where to carry on in the `--state-dir` file, on a
+    /// clean exit.
+    pub fn save_state(&mut self) {
+        if let Some(state) = self.state.as_mut() {
+            state.close();
+        }
+    }
+
+    /// Returns the current wall-clock time in milliseconds since the Unix epoch.
+    fn timestamp_ms(&self) -> Result<u64, String> {
+        unix_ms(self.clock.now())
+    }
+
+    /// Returns the time to send in the next datagram: now, plus the time
+    /// it will take to send with `--tx-compensation-us`, or the time it is
+    /// to leave with `--txtime`.
+    fn send_time_ms(&self) -> Result<u64, String> {
+        let now = self.clock.now();
+        if let Some(launch) = self.launch {
+            let ahead = launch.saturating_duration_since(self.clock.instant());
+            return unix_ms(now + ahead);
+        }
+        match self.cfg.tx_compensation {
+            Some(c) => unix_ms(now + self.send_delay.correction(c)),
+            None => unix_ms(now),
+        }
+    }
+
+    /// Sends one timestamp. Send failures are logged and tolerated; only
+    /// clock errors are fatal.
+    pub fn send_once(&mut self) -> Result<(), String> {
+        self.send_burst(1)
+    }
+
+    /// Sends `count` timestamps back to back to every destination, handing
+    /// them to the sink as one batch, and again for each `--redundancy`
+    /// copy. Send failures are logged and tolerated; only clock errors are
+    /// fatal.
+    ///
+    /// The buffers are kept from one burst to the next, so a steady burst
+    /// allocates nothing here once the first has been sent.
+    pub fn send_burst(&mut self, count: u32) -> Result<(), String> {
+        let mut bufs = std::mem::take(&mut self.bufs);
+        bufs.resize_with(count as usize, || Vec::with_capacity(64));
+        let mut stamps = std::mem::take(&mut self.stamps);
+        stamps.clear();
+        let first_seq = self.seq;
+        let sampled = self.clock.instant();
+        for _ in 0..count {
+            // A vetoed datagram leaves its buffer to the next
+            match self.encode_next(&mut bufs[stamps.len()]) {
+                Ok(Some(ts_ms)) => stamps.push(ts_ms),
+                Ok(None) => {}
+                Err(e) => {
+                    self.bufs = bufs;
+                    self.stamps = stamps;
+                    return Err(e);
+                }
+            }
+        }
+        bufs.truncate(stamps.len());
+
+        let mut queue = std::mem::take(&mut self.queue);
+        queue.clear();
+        self.withhold(bufs.len() as u32);
+        let now = self.clock.instant();
+        for i in 0..bufs.len() {
+            for &dest in &self.dests {
+                if !self.sink.paused(dest)
+                    && !self.errors.paused(dest, now)
+                    && !self.blacked_out.contains_key(&dest)
+                {
+                    queue.push((i, dest));
+                }
+            }
+        }
+        for copy in 0..self.cfg.redundancy.max(1) {
+            // With --txtime the copies are scheduled rather than waited for
+            let launch = self.launch.map(|l| l + self.cfg.redundancy_spacing * copy);
+            if copy > 0 && launch.is_none() {
+                self.clock.sleep(self.cfg.redundancy_spacing);
+            }
+            let sent_at = self.transmit(&bufs, &stamps, first_seq, &queue, launch);
+            if let (0, Some(sent_at)) = (copy, sent_at) {
+                self.send_delay
+                    .observe(sent_at.saturating_duration_since(sampled));
+            }
+        }
+        self.bufs = bufs;
+        self.stamps = stamps;
+        self.queue = queue;
+        Ok(())
+    }
+
+    /// Sends each queued (payload index, destination) pair once, the
+    /// payloads being numbered from `first_seq`, to leave at `launch` if
+    /// given. Returns when the first send call returned, with
+    /// `--tx-compensation-us`.
+    fn transmit(
+        &mut self,
+        bufs: &[Vec<u8>],
+        stamps: &[u64],
+        first_seq: u32,
+        queue: &[(usize, SocketAddr)],
+        launch: Option<Instant>,
+    ) -> Option<Instant> {
+        let timed = self.cfg.tx_compensation.is_some();
+        let mut sent_at = None;
+        let hooks = self.cfg.hooks.as_deref();
+        let after_send = |i: usize, dest, result: &io::Result<usize>| {
+            if let Some(hooks) = hooks {
+                let outcome = Outcome {
+                    seq: first_seq.wrapping_add(i as u32),
+                    ts_ms: stamps[i],
+                    dest,
+                };
+                hooks.after_send(&outcome, result);
+            }
+        };
+        #[cfg(feature = "chaos")]
+        if let Some(chaos) = &self.cfg.chaos {
+            // Each datagram's fate and hold apply to it alone, so no batching
+            for &(i, dest) in queue {
+                match chaos.fate(&mut self.rng) {
+                    Some(hold) => {
+                        let result = match launch {
+                            // The hold delays the transmit time instead
+                            Some(at) => {
+                                let ahead = at.saturating_duration_since(self.clock.instant());
+                                let msg = [(bufs[i].as_slice(), dest)];
+                                let mut results = self.sink.send_batch_after(&msg, ahead + hold);
+                                results
+                                    .pop()
+                                    .unwrap_or_else(|| Err(io::ErrorKind::WriteZero.into()))
+                            }
+                            None => {
+                                self.clock.sleep(hold);
+                                self.sink.send_to(&bufs[i], dest)
+                            }
+                        };
+                        if timed && sent_at.is_none() {
+                            sent_at = Some(self.clock.instant());
+                        }
+                        after_send(i, dest, &result);
+                        self.record(dest, stamps[i], result);
+                    }
+                    None => log::info!(
+                        "Dropped broadcast to {dest} {} (chaos)",
+                        units::ts(stamps[i])
+                    ),
+                }
+            }
+            return sent_at;
+        }
+
+        let msgs: Vec<(&[u8], SocketAddr)> = queue
+            .iter()
+            .map(|&(i, dest)| (bufs[i].as_slice(), dest))
+            .collect();
+        // The I/O thread logs what it sends
+        let mut queued = std::mem::take(&mut self.queued);
+        queued.clear();
+        queued.extend(queue.iter().map(|&(i, _)| stamps[i]));
+        let offloaded = self.sink.offload(&msgs, &queued);
+        self.queued = queued;
+        if let Some(results) = offloaded {
+            if timed && !queue.is_empty() {
+                sent_at = Some(self.clock.instant());
+            }
+            for (&(i, dest), result) in queue.iter().zip(results) {
+                after_send(i, dest, &result);
+                self.count(dest, result);
+            }
+            return sent_at;
+        }
+        let results = match launch {
+            Some(at) => {
+                let ahead = at.saturating_duration_since(self.clock.instant());
+                self.sink.send_batch_after(&msgs, ahead)
+            }
+            None => self.sink.send_batch(&msgs),
+        };
+        if timed && !queue.is_empty() {
+            sent_at = Some(self.clock.instant());
+        }
+        for (&(i, dest), result) in queue.iter().zip(results) {
+            after_send(i, dest, &result);
+            self.record(dest, stamps[i], result);
+        }
+        sent_at
+    }
+
+    /// Encodes the next timestamp into `buf`, returning it, or `None` if
+    /// a hook vetoed it.
+    fn encode_next(&mut self, buf: &mut Vec<u8>) -> Result<Option<u64>, String> {
+        let mut ts_ms = self.send_time_ms()?;
+        if let Some(state) = self.state.as_mut() {
+            state.reserve(self.seq, self.era);
+        }
+        if let Some(smear) = self.smear.as_mut() {
+            ts_ms = smear.apply(ts_ms);
+        }
+
+        let packet = Packet {
+            group: self.cfg.group,
+            mono_us: Some(self.clock.instant().duration_since(self.start).as_micros() as u64),
+            era: (self.era > 0).then_some(self.era),
+            priority: self.cfg.priority,
+            unsynced: self.unsynced,
+            epoch: self.cfg.epoch,
+            ..Packet::new(Some(self.seq), ts_ms)
+        };
+        let mut hooked;
+        let fields = match &self.cfg.hooks {
+            Some(hooks) => {
+                hooked = self.cfg.fields.clone();
+                if hooks.before_send(&packet, &mut hooked) == Verdict::Veto {
+                    return Ok(None);
+                }
+                &hooked
+            }
+            None => &self.cfg.fields,
+        };
+        match &self.cfg.codec {
+            Some(codec) => codec.encode(&packet, fields, buf),
+            None if self.cfg.format == Format::Compact => self.compact.encode(&packet, buf),
+            None => {
+                payload::encode_with_fields(
+                    self.cfg.format,
+                    &packet,
+                    fields,
+                    self.cfg.key.as_ref(),
+                    buf,
+                );
+                if let Some(key) = &self.cfg.sign_key {
+                    payload::sign(buf, key);
+                }
+                if self.cfg.crc {
+                    payload::append_crc(buf);
+                }
+                if let Some(key) = &self.cfg.encrypt_key {
+                    // A new salt whenever sequence numbers start over
+                    if self.seq == 0 {
+                        self.salt = aead::salt();
+                    }
+                    payload::seal(buf, key, &self.salt);
+                }
+            }
+        }
+        self.seq = self.seq.wrapping_add(1);
+        Ok(Some(ts_ms))
+    }
+
+    /// Logs and counts the outcome of one send, backing off from `dest`
+    /// if the failure calls for it.
+    fn record(&mut self, dest: SocketAddr, ts_ms: u64, result: io::Result<usize>) {
+        if result.is_ok() {
+            log::info!("Sent broadcast to {dest} {}", units::ts(ts_ms));
+        }
+        self.count(dest, result);
+    }
+
+    /// Counts the outcome of one send, logging failures.
+    fn count(&mut self, dest: SocketAddr, result: io::Result<usize>) {
+        let class = match result {
+            Ok(_) => {
+                self.errors.succeeded(dest);
+                self.counts.entry(dest).or_default().sent += 1;
+                if let Some(health) = &self.health {
+                    health.sent(self.clock.instant());
+                }
+                if let Some(span) = self.span.as_mut() {
+                    span.record(Ok(()));
+                }
+                None
+            }
+            Err(e) => {
+                // Continue on send errors to allow recovery from transient network issues
+                let class = ErrorClass::of(&e);
+                let now = self.clock.instant();
+                self.counts.entry(dest).or_default().failed += 1;
+                match self.errors.failed(dest, class, now) {
+                    Some(delay) => log::warn!(
+                        "send_to({dest}) failed: {e} (class={class}); skipping {dest} for {}s",
+                        delay.as_secs()
+                    ),
+                    None => log::warn!("send_to({dest}) failed: {e} (class={class})"),
+                }
+                if let Some(health) = &self.health {
+                    health.failed(now, class, format!("send_to({dest}) failed: {e}"));
+                }
+                if let Some(span) = self.span.as_mut() {
+                    span.record(Err((class, format!("send_to({dest}) failed: {e}"))));
+                }
+                Some(class)
+            }
+        };
+        if let Some(statsd) = self.statsd.as_mut() {
+            let dest = dest.to_string();
+            match class {
+                None => statsd.count("sent", 1, &[("dest", &dest)]),
+                Some(c) => statsd.count("send_errors", 1, &[("dest", &dest), ("class", c.name())]),
+            }
+        }
+    }
+
+    /// Retries every destination backing off from a failure of `class` on
+    /// the next tick.
+    pub fn lift_backoff(&mut self, class: ErrorClass) {
+        self.errors.lift(class);
+    }
+
+    /// Failed sends so far of `class`.
+    pub fn error_count(&self, class: ErrorClass) -> u64 {
+        self.errors.count(class)
+    }
+
+    /// Compares how far the wall clock and the monotonic clock moved since
+    /// the last tick; a difference is a step, which starts a new era.
+    /// Returns whether sending is held down after one.
+    fn watch_clock(&mut self) -> bool {
+        let (wall, now) = (self.clock.now(), self.clock.instant());
+        if let Some((last_wall, last)) = self.last_clocks.replace((wall, now)) {
+            let wall_us = match wall.duration_since(last_wall) {
+                Ok(d) => d.as_micros() as i64,
+                Err(e) => -(e.duration().as_micros() as i64),
+            };
+            let mono_us = now.duration_since(last).as_micros() as i64;
+            let step_ms = (wall_us - mono_us) / 1000;
+            if step_ms.abs() >= step::THRESHOLD_MS {
+                self.era = self.era.wrapping_add(1);
+                log::warn!(
+                    "Clock step: {} era={}",
+                    units::pair("step_ms", units::millis(step_ms)),
+                    self.era
+                );
+                if let Some(hold) = self.cfg.step_holddown {
+                    log::warn!(
+                        "Holding broadcasts for {} after the step",
+                        units::millis(hold.as_millis() as i64)
+                    );
+                    self.hold_until = Some(now + hold);
+                }
+            }
+        }
+        let unsynced = self.watch_sync(now);
+        self.hold_until.is_some_and(|until| now < until) || unsynced
+    }
+
+    /// Reads the kernel's synchronization status every
+    /// [`SYNC_CHECK_INTERVAL`] with `--require-clock-sync`, logging each
+    /// change. A status that can't be read counts as unsynchronized.
+    /// Returns whether sending is held until the clock is synchronized.
+    fn watch_sync(&mut self, now: Instant) -> bool {
+        let Some(req) = self.cfg.require_clock_sync else {
+            return false;
+        };
+        if self
+            .sync_checked
+            .is_some_and(|at| now < at + SYNC_CHECK_INTERVAL)
+        {
+            return self.unsynced && req.mode == SyncMode::Hold;
+        }
+        let first = self.sync_checked.replace(now).is_none();
+        let (unsynced, why) = match self.clock.sync_status() {
+            Ok(s) if !s.synced => (true, "status=unsync".to_string()),
+            Ok(s) => (
+                s.max_error > req.max_error,
+                format!("max_error_ms={}", s.max_error.as_millis()),
+            ),
+            Err(e) => (true, format!("status unknown: {e}")),
+        };
+        if unsynced && (first || !self.unsynced) {
+            match req.mode {
+                SyncMode::Hold => {
+                    log::warn!("Clock not synchronized ({why}); holding broadcasts until it is")
+                }
+                SyncMode::Mark => {
+                    log::warn!("Clock not synchronized ({why}); marking broadcasts as unsynced")
+                }
+            }
+        } else if !unsynced && self.unsynced {
+            log::info!("Clock synchronized ({why}); broadcasting");
+        }
+        self.unsynced = unsynced;
+        unsynced && req.mode == SyncMode::Hold
+    }
+
+    /// Sends one tick's burst of timestamps, then waits until the next tick.
+    pub fn step(&mut self) -> Result<(), String> {
+        if self.bucket.is_some() {
+            return self.step_rate();
+        }
+        let due = self.tick()?;
+        self.sleep_until(due);
+        Ok(())
+    }
+
+    /// Waits for `due`, e.g. a time returned by [`Self::tick`].
+    pub fn sleep_until(&mut self, due: Instant) {
+        let now = self.clock.instant();
+        self.clock.sleep(due.saturating_duration_since(now));
+    }
+
+    /// Sends one tick's burst of timestamps and returns when the next tick
+    /// is due, without waiting for it.
+    pub fn tick(&mut self) -> Result<Instant, String> {
+        let start = self.clock.instant();
+        let nominal = *self.next_tick.get_or_insert(start);
+        let holding = self.watch_clock();
+        let mut burst = if self.active && !self.paused && !holding {
+            self.cfg.burst
+        } else {
+            0
+        };
+        if self.watch_blackouts() {
+            self.withhold(burst);
+            burst = 0;
+        }
+        if let Some(deadline) = self.deadline.filter(|_| burst > 0) {
+            self.record_lateness(start.saturating_duration_since(deadline));
+        }
+        // The tick started `lead` early; its datagrams leave on the grid,
+        // or `lead` from now if it is too late for that
+        self.launch = self.cfg.txtime.map(|lead| match self.deadline {
+            Some(d) if d + lead > start => d + lead,
+            _ => start + lead,
+        });
+        self.begin_span(burst);
+        if self.cfg.burst_spacing.is_zero() {
+            if burst > 0 {
+                self.send_burst(burst)?;
+            }
+        } else {
+            for i in 0..burst {
+                if i > 0 {
+                    self.clock.sleep(self.cfg.burst_spacing);
+                }
+                self.send_once()?;
+            }
+        }
+        self.end_span();
+
+        let interval = self.interval;
+        let now = self.clock.instant();
+        let mut next = nominal + interval;
+        if now > next + interval {
+            // More than a whole tick behind (e.g. the host was suspended):
+            // resync instead of bursting to catch up.
+            let missed = ((now - next).as_nanos() / interval.as_nanos()) as u64;
+            self.record_missed(missed);
+            next = now;
+        }
+        self.next_tick = Some(next);
+
+        let mut due = self.jittered(next);
+        if let Some(lead) = self.cfg.txtime {
+            due = due.checked_sub(lead).unwrap_or(due);
+        }
+        self.deadline = Some(due);
+        log::trace!(
+            "Tick: late_us={} sleep_us={}",
+            start.saturating_duration_since(nominal).as_micros(),
+            due.saturating_duration_since(now).as_micros()
+        );
+        Ok(due)
+    }
+
+    /// Notes which destinations are in a `--blackout` window, logging as
+    /// windows begin and end. Returns true if all of them are, so the
+    /// tick has nothing to send.
+    fn watch_blackouts(&mut self) -> bool {
+        if self.cfg.blackout.is_empty() {
+            return false;
+        }
+        let now = self.clock.now();
+        // Left over from a destination since retargeted
+        let dests = &self.dests;
+        self.blacked_out.retain(|dest, _| dests.contains(dest));
+        for &dest in &self.dests {
+            let until = blackout::until(&self.cfg.blackout, dest, now);
+            match (until, self.blacked_out.get(&dest).copied()) {
+                (Some(end), None) => {
+                    log::warn!(
+                        "Blackout of {dest} until {}; holding back its datagrams",
+                        start::format_rfc3339(end)
+                    );
+                    self.blacked_out.insert(dest, (now, end));
+                }
+                (Some(end), Some((since, _))) => {
+                    self.blacked_out.insert(dest, (since, end));
+                }
+                (None, Some((since, _))) => {
+                    let withheld = self.counts.get(&dest).map_or(0, |c| c.withheld);
+                    log::warn!(
+                        "Blackout of {dest} over after {:.1}s; sending again (withheld={withheld})",
+                        now.duration_since(since).unwrap_or_default().as_secs_f64()
+                    );
+                    self.blacked_out.remove(&dest);
+                }
+                (None, None) => {}
+            }
+        }
+        let all = self.dests.len() == self.blacked_out.len();
+        if let Some(health) = &self.health {
+            health.set_blackout(self.clock.instant(), all);
+        }
+        all
+    }
+
+    /// Counts `count` datagrams not sent to each destination in a
+    /// blackout.
+    fn withhold(&mut self, count: u32) {
+        if count == 0 || self.blacked_out.is_empty() {
+            return;
+        }
+        let n = u64::from(count) * self.blacked_out.len() as u64;
+        for dest in self.blacked_out.keys() {
+            self.counts.entry(*dest).or_default().withheld += u64::from(count);
+        }
+        if let Some(statsd) = self.statsd.as_mut() {
+            statsd.count("withheld", n, &[]);
+        }
+        if let Some(health) = &self.health {
+            health.withheld(n);
+        }
+    }
+
+    /// Counts a tick that started `late` after its deadline, warning if
+    /// that is over `--max-lateness-ms`.
+    fn record_lateness(&mut self, late: Duration) {
+        if self.lateness.record(late, self.cfg.max_lateness) {
+            log::warn!(
+                "Late tick: {} max_lateness_ms={}",
+                units::pair("late_us", units::micros(late.as_micros() as u64)),
+                self.cfg.max_lateness.unwrap_or_default().as_millis()
+            );
+            if let Some(statsd) = self.statsd.as_mut() {
+                statsd.count("late_ticks", 1, &[]);
+            }
+        }
+        if let Some(health) = &self.health {
+            health.set_lateness(self.lateness);
+        }
+    }
+
+    /// Counts ticks skipped after falling behind.
+    fn record_missed(&mut self, ticks: u64) {
+        log::warn!("Missed ticks: missed={ticks} (the loop fell behind; resyncing)");
+        self.lateness.skipped(ticks);
+        if let Some(statsd) = self.statsd.as_mut() {
+            statsd.count("missed_ticks", ticks, &[]);
+        }
+        if let Some(health) = &self.health {
+            health.set_lateness(self.lateness);
+        }
+    }
+
+    /// How late ticks have been so far.
+    pub fn lateness(&self) -> Lateness {
+        self.lateness
+    }
+
+    /// Starts timing a tick that sends `burst` timestamps, if exporting
+    /// spans; ticks that send nothing aren't exported.
+    fn begin_span(&mut self, burst: u32) {
+        if self.otlp.is_some() && burst > 0 {
+            self.span = Some(Tick::new(self.clock.now()));
+        }
+    }
+
+    fn end_span(&mut self) {
+        if let (Some(otlp), Some(mut span)) = (&self.otlp, self.span.take()) {
+            span.end = self.clock.now();
+            otlp.record(span);
+        }
+    }
+
+    /// Sends every datagram the token bucket allows, then waits for the
+    /// next token.
+    fn step_rate(&mut self) -> Result<(), String> {
+        let bucket = self.bucket.as_mut().expect("step_rate without --rate");
+        let due = bucket.take(self.clock.instant());
+        let holding = self.watch_clock();
+        let blackout = self.watch_blackouts();
+        if blackout && self.active && !holding {
+            self.withhold(u32::try_from(due).unwrap_or(u32::MAX));
+        } else if self.active && !holding && due > 0 {
+            let count = u32::try_from(due).unwrap_or(u32::MAX);
+            self.begin_span(count);
+            self.send_burst(count)?;
+            self.end_span();
+        }
+        let bucket = self.bucket.as_ref().expect("step_rate without --rate");
+        log::trace!("Tick: sent={due} sleep_us={}", bucket.wait().as_micros());
+        self.clock.sleep(bucket.wait());
+        Ok(())
+    }
+
+    /// Offsets a nominal tick by a random amount within half the jitter
+    /// bound either way, so consecutive gaps stay within `interval ± jitter`
+    /// while the grid itself never drifts.
+    fn jittered(&mut self, tick: Instant) -> Instant {
+        // A commanded interval may be shorter than the configured jitter
+        let jitter = self.cfg.jitter.min(self.interval);
+        if jitter.is_zero() {
+            return tick;
+        }
+        let offset = (self.rng.next_f64() - 0.5) * jitter.as_secs_f64();
+        if offset >= 0.0 {
+            tick + Duration::from_secs_f64(offset)
+        } else {
+            tick.checked_sub(Duration::from_secs_f64(-offset))
+                .unwrap_or(tick)
+        }
+    }
+
+    /// Switches to `interval` on a grid anchored at `apply_at_ms`, waiting
+    /// for the first tick of that grid that isn't already past. Followers
+    /// anchored at the same time tick together however late they apply it.
+    pub fn reschedule(&mut self, interval: Duration, apply_at_ms: u64) -> Result<(), String> {
+        let now_ms = self.timestamp_ms()?;
+        let now = self.clock.instant();
+        let behind = Duration::from_millis(now_ms.saturating_sub(apply_at_ms));
+        let anchor = now.checked_sub(behind).unwrap_or(now);
+        let ticks = (now - anchor).as_nanos().div_ceil(interval.as_nanos());
+        let tick = anchor + Duration::from_nanos((ticks * interval.as_nanos()) as u64);
+
+        self.interval = interval;
+        if let Some(health) = &self.health {
+            health.set_interval(interval);
+        }
+        self.next_tick = Some(tick);
+        self.deadline = Some(tick);
+        self.clock.sleep(tick.saturating_duration_since(now));
+        Ok(())
+    }
+
+    /// Pushes a count of sent datagrams and send errors to `statsd`.
+    pub fn set_statsd(&mut self, statsd: Statsd) {
+        self.statsd = Some(statsd);
+    }
+
+    /// Exports a span per tick, and the metrics behind it, to `otlp`.
+    pub fn set_otlp(&mut self, otlp: Otlp) {
+        self.otlp = Some(otlp);
+    }
+
+    /// Reports sends and send errors to `health`.
+    pub fn set_health(&mut self, health: Arc<Health>) {
+        self.health = Some(health);
+    }
+
+    pub fn set_active(&mut self, active: bool) {
+        self.active = active;
+        if let Some(health) = &self.health {
+            health.set_active(self.clock.instant(), active);
+        }
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        if let Some(health) = &self.health {
+            health.set_paused(self.clock.instant(), paused);
+        }
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Everything the sender counts, as of now.
+    pub fn snapshot(&self) -> Snapshot {
+        let now = self.clock.instant();
+        let state = if self.paused {
+            "paused"
+        } else if self.active {
+            "active"
+        } else {
+            "standby"
+        };
+        // Current destinations first, then any that were sent to before
+        let mut dests = self.dests.clone();
+        dests.extend(self.counts.keys().filter(|d| !self.dests.contains(d)));
+        Snapshot {
+            unix_ms: self.timestamp_ms().unwrap_or(0),
+            uptime: now.saturating_duration_since(self.start),
+            state,
+            interval: self.interval,
+            next_seq: self.seq,
+            errors: ErrorClass::ALL
+                .iter()
+                .map(|&c| (c, self.errors.count(c)))
+                .collect(),
+            lateness: self.lateness,
+            dests: dests
+                .into_iter()
+                .map(|dest| DestSnapshot {
+                    dest,
+                    counts: self.counts.get(&dest).copied().unwrap_or_default(),
+                    backoff: self
+                        .errors
+                        .backing_off(dest)
+                        .filter(|&(_, until)| until > now)
+                        .map(|(class, until)| (class, until - now)),
+                    blackout: self
+                        .blacked_out
+                        .get(&dest)
+                        .map(|&(_, end)| end.duration_since(self.clock.now()).unwrap_or_default()),
+                })
+                .collect(),
+            io_queue: None,
+            tx_compensation: self
+                .cfg
+                .tx_compensation
+                .map(|c| (self.send_delay.correction(c), self.send_delay)),
+        }
+    }
+
+    /// Asks the loop to write a snapshot once the current request is done.
+    pub fn request_snapshot(&mut self) {
+        self.snapshot_requested = true;
+    }
+
+    /// Whether a control API request asked for a snapshot; clears it.
+    pub fn take_snapshot_request(&mut self) -> bool {
+        std::mem::take(&mut self.snapshot_requested)
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Switches to `interval` from the tick after the next one, keeping
+    /// the grid, unlike [`Self::reschedule`].
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+        if let Some(health) = &self.health {
+            health.set_interval(interval);
+        }
+    }
+
+    /// The sequence number the next datagram will carry.
+    pub fn seq(&self) -> u32 {
+        self.seq
+    }
+
+    pub fn sink(&self) -> &S {
+        &self.sink
+    }
+
+    pub fn sink_mut(&mut self) -> &mut S {
+        &mut self.sink
+    }
+
+    pub fn dest(&self) -> SocketAddr {
+        self.dests[0]
+    }
+
+    pub fn dests(&self) -> &[SocketAddr] {
+        &self.dests
+    }
+
+    pub fn instant(&self) -> Instant {
+        self.clock.instant()
+    }
+
+    /// Switches to a new destination, and optionally a new sink for it.
+    pub fn retarget(&mut self, dest: SocketAddr, sink: Option<S>) {
+        if let Some(sink) = sink {
+            self.sink = sink;
+        }
+        self.dests = vec![dest];
+    }
+
+    /// Sends every datagram to each of `dests` instead; must not be empty.
+    pub fn fan_out(&mut self, dests: Vec<SocketAddr>) {
+        assert!(!dests.is_empty(), "fan_out needs at least one destination");
+        self.dests = dests;
+    }
+}
+
+/// Drives several senders, each ticking on its own interval, from one
+/// thread: it always waits for whichever tick is due first.
+pub struct Scheduler<'a, C: Clock, S: DatagramSink> {
+    senders: Vec<Sender<'a, C, S>>,
+    /// When each sender's next tick is due; `None` before its first.
+    due: Vec<Option<Instant>>,
+}
+
+impl<'a, C: Clock, S: DatagramSink> Scheduler<'a, C, S> {
+    pub fn new(senders: Vec<Sender<'a, C, S>>) -> Self {
+        let due = vec![None; senders.len()];
+        Scheduler { senders, due }
+    }
+
+    /// Waits for the earliest due tick and sends it. Only clock errors are
+    /// fatal.
+    pub fn step(&mut self) -> Result<(), String> {
+        // None sorts first, so every sender starts at once
+        let Some((i, due)) = self.due.iter().copied().enumerate().min_by_key(|&(_, d)| d) else {
+            return Ok(());
+        };
+        let sender = &mut self.senders[i];
+        if let Some(due) = due {
+            let now = sender.clock.instant();
+            sender.clock.sleep(due.saturating_duration_since(now));
+        }
+        self.due[i] = Some(sender.tick()?);
+        Ok(())
+    }
+
+    pub fn senders(&self) -> &[Sender<'a, C, S>] {
+        &self.senders
+    }
+
+    pub fn senders_mut(&mut self) -> &mut [Sender<'a, C, S>] {
+        &mut self.senders
+    }
+}
+
+/// Lists each up IPv4 interface's directed broadcast address on `port`.
+fn interface_broadcasts(port: u16) -> Result<Vec<SocketAddr>, String> {
+    let ifaces = iface::ipv4_interfaces().map_err(|e| format!("Failed to list interfaces: {e}"))?;
+    let mut dests = Vec::new();
+    for i in ifaces {
+        if let Some(bcast) = i.broadcast() {
+            let dest = SocketAddr::new(IpAddr::V4(bcast), port);
+            if !dests.contains(&dest) {
+                log::info!(
+                    "Broadcasting on {} ({}/{}) to {dest}",
+                    i.name,
+                    i.addr,
+                    i.prefix_len
+                );
+                dests.push(dest);
+            }
+        }
+    }
+    if dests.is_empty() {
+        return Err("No IPv4 interface with a broadcast address is up".to_string());
+    }
+    Ok(dests)
+}
+
+/// Re-resolves a hostname target and retargets `sender` if it changed.
+fn reresolve<C: Clock>(cfg: &SendConfig, sender: &mut Sender<'_, C, Outlet>) {
+    let dest = sender.dest();
+    match cfg.target.resolve(cfg.port, Some(dest)) {
+        Ok(new) if new != dest => {
+            // A family change needs a socket of the new family
+            let rebound = if new.is_ipv4() != dest.is_ipv4() {
+                bind_tuned(cfg, new).map(Some)
+            } else {
+                Ok(None)
+            };
+            match rebound {
+                Ok(sock) => {
+                    log::info!("Destination changed: {dest} -> {new}");
+                    retarget(sender, new, sock.map(Outlet::Socket));
+                }
+                Err(e) => log::warn!("{e}; keeping destination {dest}"),
+            }
+        }
+        Ok(_) => {}
+        // Keep sending to the last known address while DNS is unavailable
+        Err(e) => log::warn!("{e}; keeping destination {dest}"),
+    }
+}
+
+/// Re-selects the destination and opens a fresh socket for it after an
+/// interface change, re-listing the interfaces with `--per-interface`.
+fn reselect<C: Clock>(cfg: &SendConfig, sender: &mut Sender<'_, C, Outlet>) {
+    sender.lift_backoff(ErrorClass::InterfaceDown);
+    // Each peer keeps its own connected socket, and consumers their
+    // connections
+    if cfg.peers.is_some() || cfg.transport == Transport::Tcp {
+        return;
+    }
+    let dest = sender.dest();
+    let new = if cfg.per_interface {
+        // The directed broadcasts are re-listed below
+        cfg.target.resolve(cfg.port, None)
+    } else {
+        cfg.target.resolve(cfg.port, Some(dest))
+    };
+    let new = match new {
+        Ok(new) => new,
+        Err(e) => {
+            log::warn!("{e}; keeping destination {dest}");
+            return;
+        }
+    };
+    if let Some(group) = &cfg.dual_stack {
+        match Outlet::dual_stack(cfg, new, group) {
+            Ok((outlet, dests)) => {
+                retarget(sender, new, Some(outlet));
+                sender.fan_out(dests);
+            }
+            Err(e) => log::warn!("{e}; keeping the current sockets"),
+        }
+        return;
+    }
+    match bind_tuned(cfg, new) {
+        Ok(sock) => {
+            if !cfg.per_interface && new != dest {
+                log::info!("Destination changed: {dest} -> {new}");
+            }
+            retarget(sender, new, Some(Outlet::Socket(sock)));
+        }
+        Err(e) => {
+            log::warn!("{e}; keeping the current socket");
+            return;
+        }
+    }
+    if cfg.per_interface {
+        match interface_broadcasts(cfg.port) {
+            Ok(dests) => sender.fan_out(dests),
+            Err(e) => log::warn!("{e}; sending to {new} until one is"),
+        }
+    }
+}
+
+/// Drains the control socket, keeping the newest command accepted.
+fn poll_control(
+    sock: &UdpSocket,
+    follower: &mut Follower,
+    now_ms: u64,
+    pending: &mut Option<Control>,
+) {
+    let mut buf = [0u8; 1500];
+    loop {
+        let (n, src) = match sock.recv_from(&mut buf) {
+            Ok(r) => r,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+            Err(e) => {
+                log::warn!("Control socket error: {e}");
+                return;
+            }
+        };
+        match follower.accept(&buf[..n], now_ms) {
+            Ok(Some(c)) => {
+                log::info!(
+                    "Control from {src}: interval_ms={} at unix_ms={}",
+                    c.interval_ms,
+                    c.apply_at_ms
+                );
+                *pending = Some(c);
+            }
+            Ok(None) => {}
+            Err(e) => log::warn!("Rejected control from {src}: {e}"),
+        }
+    }
+}
+
+/// Drains beacons from other senders into `election`, switching `sender`
+/// on or off and carrying on the last heard schedule when promoted.
+fn poll_election<C: Clock>(
+    sock: &UdpSocket,
+    election: &mut Election,
+    sender: &mut Sender<'_, C, Outlet>,
+    now_ms: u64,
+) -> Result<(), String> {
+    // Our own broadcasts loop back; recognize them by source port
+    let own_port = sender.sink().local_port();
+    let mut buf = [0u8; 1500];
+    loop {
+        let (n, src) = match sock.recv_from(&mut buf) {
+            Ok(r) => r,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => {
+                log::warn!("Beacon socket error: {e}");
+                break;
+            }
+        };
+        if Some(src.port()) == own_port {
+            continue;
+        }
+        // Only data messages are beacons; undecodable datagrams are ignored
+        let Ok(decoded) = payload::decode(&buf[..n]) else {
+            continue;
+        };
+        if decoded.packet.kind != MessageType::DATA {
+            continue;
+        }
+        if let Some(t) = election.heard(&decoded.packet, sender.seq(), now_ms) {
+            log::info!("{t} peer={src}");
+        }
+    }
+
+    if let Some(t) = election.tick(now_ms) {
+        log::info!("{t}");
+        if let Some((interval_ms, next_ms)) = election.mirror() {
+            sender.reschedule(Duration::from_millis(interval_ms), next_ms)?;
+        }
+    }
+    sender.set_active(election.active());
+    Ok(())
+}
+
+/// Drains beacons from other senders into `guard`, warning about each
+/// newly heard one and each that fell silent.
+fn poll_guard(sock: &UdpSocket, guard: &mut Guard, own_port: Option<u16>, now_ms: u64) {
+    let mut buf = [0u8; 1500];
+    loop {
+        let (n, src) = match sock.recv_from(&mut buf) {
+            Ok(r) => r,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => {
+                log::warn!("Guard socket error: {e}");
+                break;
+            }
+        };
+        // Our own broadcasts loop back, as for the election
+        if Some(src.port()) == own_port {
+            continue;
+        }
+        let Ok(decoded) = payload::decode(&buf[..n]) else {
+            continue;
+        };
+        if let Some(intruder) = guard.heard(src, &decoded.packet, now_ms) {
+            log::warn!("Another sender is broadcasting on this port: {intruder}");
+        }
+    }
+    for src in guard.expire(now_ms) {
+        log::info!("Other sender went quiet: src={src}");
+    }
+}
+
+/// Listens for [`guard::PROBE`] before the first send, failing if another
+/// sender was heard.
+fn probe_guard(sock: &UdpSocket, guard: &mut Guard, port: u16) -> Result<(), String> {
+    log::info!(
+        "Listening on port {port} for {} ms before sending (--exclusive)",
+        guard::PROBE.as_millis()
+    );
+    let until = Instant::now() + guard::PROBE;
+    let mut heard = None;
+    while Instant::now() < until {
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        let mut buf = [0u8; 1500];
+        match sock.recv_from(&mut buf) {
+            Ok((n, src)) => {
+                if let Ok(decoded) = payload::decode(&buf[..n]) {
+                    heard = heard.or(guard.heard(src, &decoded.packet, now_ms));
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            Err(e) => return Err(format!("Guard socket error: {e}")),
+        }
+    }
+    match heard {
+        Some(intruder) => Err(format!(
+            "Another sender is already broadcasting on port {port}: {intruder}; not starting (--exclusive)"
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Applies `control` if it is due, unless the new interval can't hold a burst.
+fn apply_control<C: Clock>(
+    cfg: &SendConfig,
+    sender: &mut Sender<'_, C, Outlet>,
+    control: Control,
+) -> Result<(), String> {
+    let interval = Duration::from_millis(control.interval_ms);
+    if interval.is_zero() || cfg.tick_span().is_none_or(|d| d >= interval) {
+        log::warn!(
+            "Ignoring control: interval_ms={} can't hold the configured burst",
+            control.interval_ms
+        );
+        return Ok(());
+    }
+    sender.reschedule(interval, control.apply_at_ms)?;
+    log::info!("Interval changed to {} ms", control.interval_ms);
+    Ok(())
+}
+
+/// How long before a tick the loop stops taking control API requests, so
+/// answering one can't make the tick late.
+const API_SLACK: Duration = Duration::from_millis(2);
+
+/// The sender's state, as returned by the control API.
+fn api_state<C: Clock>(sender: &Sender<'_, C, Outlet>, health: &Health) -> String {
+    let dests: Vec<String> = sender
+        .dests()
+        .iter()
+        .map(|d| status::json_string(&d.to_string()))
+        .collect();
+    format!(
+        "{{\"paused\":{},\"interval_ms\":{},\"next_seq\":{},\"destinations\":[{}],\"status\":{}}}\n",
+        sender.paused(),
+        sender.interval().as_millis(),
+        sender.seq(),
+        dests.join(","),
+        health.json(sender.instant()).trim_end()
+    )
+}
+
+/// Carries out a control API request and answers it. Only clock errors
+/// are returned; a refused request is answered with the reason. Returns
+/// whether the interval changed, which voids the tick being waited for.
+fn answer_api<C: Clock>(
+    cfg: &SendConfig,
+    sender: &mut Sender<'_, C, Outlet>,
+    health: &Health,
+    added: &mut Vec<SocketAddr>,
+    req: Request,
+) -> Result<bool, String> {
+    let mut rescheduled = false;
+    let result = match req.op {
+        Op::Stats => Ok(()),
+        Op::Snapshot => {
+            sender.request_snapshot();
+            Ok(())
+        }
+        Op::Pause => {
+            sender.set_paused(true);
+            log::info!("Sending paused");
+            Ok(())
+        }
+        Op::Resume => {
+            sender.set_paused(false);
+            log::info!("Sending resumed");
+            Ok(())
+        }
+        Op::SetInterval(_) if cfg.rate.is_some() => {
+            Err("--rate paces the sends; there is no interval to set".to_string())
+        }
+        Op::SetInterval(interval) if cfg.tick_span().is_none_or(|d| d >= interval) => Err(format!(
+            "interval_ms={} can't hold the configured burst",
+            interval.as_millis()
+        )),
+        Op::SetInterval(interval) => {
+            sender.reschedule(interval, sender.timestamp_ms()?)?;
+            rescheduled = true;
+            log::info!("Interval changed to {} ms", interval.as_millis());
+            Ok(())
+        }
+        Op::AddDest(_) if cfg.peers.is_some() => Err("--peers sets the destinations".to_string()),
+        Op::AddDest(dest) if sender.dests().contains(&dest) => {
+            Err(format!("{dest} is already a destination"))
+        }
+        Op::AddDest(dest) if dest.is_ipv4() != sender.dest().is_ipv4() => Err(format!(
+            "{dest} is not in the address family of {}",
+            sender.dest()
+        )),
+        Op::AddDest(dest) => {
+            added.push(dest);
+            let mut dests = sender.dests().to_vec();
+            dests.push(dest);
+            sender.fan_out(dests);
+            log::info!("Destination added: {dest}");
+            Ok(())
+        }
+        Op::RemoveDest(dest) if !added.contains(&dest) => {
+            Err(format!("{dest} wasn't added over the control API"))
+        }
+        Op::RemoveDest(dest) => {
+            added.retain(|&d| d != dest);
+            let dests = sender.dests().iter().copied().filter(|&d| d != dest);
+            sender.fan_out(dests.collect());
+            log::info!("Destination removed: {dest}");
+            Ok(())
+        }
+    };
+    req.answer(result.map(|()| api_state(sender, health)));
+    Ok(rescheduled)
+}
+
+/// Adds the destinations added over the control API back after the
+/// configured ones were re-resolved or re-listed.
+fn restore_added<C: Clock>(sender: &mut Sender<'_, C, Outlet>, added: &[SocketAddr]) {
+    let mut dests = sender.dests().to_vec();
+    for &dest in added {
+        if !dests.contains(&dest) && dest.is_ipv4() == sender.dest().is_ipv4() {
+            dests.push(dest);
+        }
+    }
+    sender.fan_out(dests);
+}
+
+/// Like [`Sender::step`], but answers control API requests while waiting
+/// for the next tick.
+fn step_answering<C: Clock>(
+    cfg: &SendConfig,
+    sender: &mut Sender<'_, C, Outlet>,
+    (api, health): &(Api, Arc<Health>),
+    added: &mut Vec<SocketAddr>,
+) -> Result<(), String> {
+    if cfg.rate.is_some() {
+        // Token bucket steps are short; take whatever is waiting between them
+        while let Some(req) = api.next(None) {
+            answer_api(cfg, sender, health, added, req)?;
+        }
+        return sender.step();
+    }
+    let due = sender.tick()?;
+    let until = due.checked_sub(API_SLACK).unwrap_or(due);
+    while let Some(req) = api.next(Some(until)) {
+        if answer_api(cfg, sender, health, added, req)? {
+            // The first tick of the new interval is due now
+            return Ok(());
+        }
+    }
+    sender.sleep_until(due);
+    Ok(())
+}
+
+/// The socket datagrams leave from, for acks to be read off, if there
+/// is just one.
+fn sender_socket(outlet: &Outlet) -> Option<&UdpSocket> {
+    match outlet {
+        Outlet::Socket(sock) => Some(sock),
+        _ => None,
+    }
+}
+
+/// Binds a send socket for `dest` with the configured buffer size and
+/// send timeout.
+fn bind_tuned(cfg: &SendConfig, dest: SocketAddr) -> Result<UdpSocket, Failure> {
+    let sock = bind_for(dest)?;
+    if let Some(bytes) = cfg.sndbuf {
+        let got = sys::set_buffer_size(&sock, sys::Buffer::Send, bytes)
+            .map_err(|e| format!("Failed to set send buffer size: {e}"))?;
+        log::info!(
+            "Send buffer: {} (requested {})",
+            units::bytes(got as u64),
+            units::bytes(bytes as u64)
+        );
+    }
+    if cfg.txtime.is_some() {
+        sys::enable_txtime(&sock).map_err(|e| match e.kind() {
+            io::ErrorKind::PermissionDenied => {
+                "Failed to enable --txtime: setting SO_TXTIME to CLOCK_TAI needs CAP_NET_ADMIN"
+                    .to_string()
+            }
+            _ => format!("Failed to enable --txtime: {e}"),
+        })?;
+    }
+    if cfg.send_timeout.is_some() {
+        sock.set_write_timeout(cfg.send_timeout)
+            .map_err(|e| format!("Failed to set send timeout: {e}"))?;
+    }
+    if !cfg.allow_fragmentation {
+        if let Err(e) = sys::set_dont_fragment(&sock) {
+            log::debug!("Failed to set don't-fragment: {e}");
+        }
+    }
+    if let Some(ttl) = cfg.ttl {
+        let ip = match dest.ip() {
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(_) => {
+                return Err("--ttl applies to IPv4 destinations only".to_string().into())
+            }
+        };
+        // Multicast datagrams take their TTL from a separate option
+        let set = if ip.is_multicast() {
+            sock.set_multicast_ttl_v4(ttl)
+        } else {
+            sock.set_ttl(ttl)
+        };
+        set.map_err(|e| format!("Failed to set TTL: {e}"))?;
+    }
+    if log::enabled(log::Level::Debug) {
+        let local = sock
+            .local_addr()
+            .map_or_else(|_| "?".to_string(), |a| a.to_string());
+        let ttl = match dest.ip() {
+            IpAddr::V4(ip) if ip.is_multicast() => sock.multicast_ttl_v4(),
+            _ => sock.ttl(),
+        };
+        log::debug!(
+            "Socket {local} for {dest}: SO_BROADCAST=on ttl={} send_timeout={}",
+            ttl.map_or_else(|e| e.to_string(), |t| t.to_string()),
+            cfg.send_timeout.map_or_else(
+                || "none".to_string(),
+                |t| units::millis(t.as_millis() as i64)
+            )
+        );
+    }
+    Ok(sock)
+}
+
+/// Subscribes to interface changes, if the platform reports them.
+fn watch_interfaces() -> Option<hotplug::Monitor> {
+    match hotplug::Monitor::open() {
+        Ok(m) => {
+            log::debug!("Watching for interface changes");
+            Some(m)
+        }
+        Err(e) => {
+            log::debug!("Not watching for interface changes: {e}");
+            None
+        }
+    }
+}
+
+/// Logs any interface changes since the last call and returns whether
+/// there were some. Stops watching if the monitor fails.
+fn interfaces_changed(monitor: &mut Option<hotplug::Monitor>) -> bool {
+    let Some(m) = monitor.as_mut() else {
+        return false;
+    };
+    match m.poll() {
+        Ok(changes) => {
+            for c in &changes {
+                log::info!("Interface change: {c}");
+            }
+            !changes.is_empty()
+        }
+        Err(e) => {
+            log::warn!("Stopped watching for interface changes: {e}");
+            *monitor = None;
+            false
+        }
+    }
+}
+
+/// Applies `--sched` and `--cpu-affinity` to the calling thread.
+pub(crate) fn tune_thread(cfg: &SendConfig) -> Result<(), Failure> {
+    if let Some(priority) = cfg.sched_fifo {
+        sys::set_fifo_priority(priority).map_err(|e| {
+            Failure::io(
+                &e,
+                format!("Failed to set SCHED_FIFO priority {priority}: {e}"),
+            )
+        })?;
+        log::info!("Send loop running under SCHED_FIFO priority {priority}");
+    }
+    if let Some(core) = cfg.cpu_affinity {
+        sys::pin_to_cpu(core)
+            .map_err(|e| Failure::io(&e, format!("Failed to pin send loop to CPU {core}: {e}")))?;
+        log::info!("Send loop pinned to CPU {core}");
+    }
+    Ok(())
+}
+
+/// Sets up the sockets as `run` would and prints the plan instead of
+/// sending. The clock check is skipped, as it would send an NTP query.
+fn dry_run(cfg: &SendConfig) -> Result<Plan, Failure> {
+    // (destination, interval) of everything a sender would target
+    let mut targets = Vec::new();
+    if !cfg.dests.is_empty() {
+        for d in &cfg.dests {
+            targets.push((d.target.resolve(d.port, None)?, d.interval));
+        }
+    } else if let Some(peers) = &cfg.peers {
+        targets.extend(peers.iter().map(|&p| (p, cfg.pace())));
+    } else {
+        let dest = cfg.target.resolve(cfg.port, None)?;
+        if let Some(group) = &cfg.dual_stack {
+            targets.push((dest, cfg.pace()));
+            targets.push((group.resolve(cfg.port, None)?, cfg.pace()));
+        } else if cfg.per_interface {
+            targets.extend(
+                interface_broadcasts(cfg.port)?
+                    .into_iter()
+                    .map(|d| (d, cfg.pace())),
+            );
+        } else {
+            targets.push((dest, cfg.pace()));
+        }
+    }
+
+    let mut routes = Vec::with_capacity(targets.len());
+    let mut first = None;
+    for (dest, interval) in targets {
+        let sock = bind_tuned(cfg, dest)?;
+        let bind = sock
+            .local_addr()
+            .map_err(|e| format!("Failed to read local address: {e}"))?;
+        // As check_route does: connecting picks the source address
+        sock.connect(dest)
+            .map_err(|e| Failure::io(&e, format!("No route to {dest}: {e}")))?;
+        let source = sock
+            .local_addr()
+            .map_err(|e| format!("Failed to read local address: {e}"))?
+            .ip();
+        let ttl = match dest.ip() {
+            IpAddr::V4(ip) if ip.is_multicast() => sock.multicast_ttl_v4(),
+            IpAddr::V4(_) => sock.ttl(),
+            IpAddr::V6(ip) => sys::hop_limit_v6(&sock, ip.is_multicast()),
+        };
+        routes.push(plan::Route {
+            dest,
+            bind,
+            source,
+            interval,
+            ttl: ttl.ok(),
+            tos: sys::traffic_class(&sock).ok(),
+            mtu: sys::path_mtu(&sock).ok(),
+            sndbuf: sys::buffer_size(&sock, sys::Buffer::Send).ok(),
+        });
+        first.get_or_insert((sock, dest));
+    }
+
+    let pacing = match cfg.rate {
+        Some(pps) => format!("token bucket at {}", units::pps(pps)),
+        None => format!(
+            "jitter {}, burst {} spaced {}, redundancy {} spaced {}",
+            units::micros(cfg.jitter.as_micros() as u64),
+            cfg.burst,
+            units::micros(cfg.burst_spacing.as_micros() as u64),
+            cfg.redundancy,
+            units::micros(cfg.redundancy_spacing.as_micros() as u64)
+        ),
+    };
+    let dests: Vec<SocketAddr> = routes.iter().map(|r| r.dest).collect();
+    check_mtu(cfg, &dests)?;
+
+    // Encoded as the first datagram would be, fields, MAC and all
+    let (sock, dest) = first.ok_or_else(|| "Nothing to send to".to_string())?;
+    let mut sample = Vec::new();
+    let clock = SystemClock::for_interval(cfg.pace());
+    let cfg = &SendConfig {
+        hooks: None,
+        ..cfg.clone()
+    };
+    Sender::new(cfg, clock, Outlet::Socket(sock), dest).encode_next(&mut sample)?;
+    Ok(Plan {
+        routes,
+        pacing,
+        format: cfg.codec.is_none().then_some(cfg.format),
+        epoch: cfg.epoch,
+        sample,
+        clock_check: cfg.require_sync.as_ref().map(|c| c.server.clone()),
+    })
+}
+
+/// Starts answering time queries on `port`, in the family of `dest`.
+fn serve_queries(cfg: &SendConfig, port: u16, dest: SocketAddr) -> Result<(), Failure> {
+    let responder = Responder {
+        group: cfg.group,
+        epoch: cfg.epoch,
+        key: cfg.key.clone(),
+        sign_key: cfg.sign_key.clone(),
+    };
+    query::serve(port, dest, responder).map(|_| ())
+}
+
+/// Runs one sender per `--dest`, each with its own socket, interval and
+/// sequence numbers, until a fatal error occurs.
+fn run_dests(cfg: &SendConfig) -> ExitCode {
+    let cfgs: Vec<SendConfig> = cfg
+        .dests
+        .iter()
+        .map(|d| SendConfig {
+            target: d.target.clone(),
+            port: d.port,
+            interval: d.interval,
+            dests: Vec::new(),
+            ..cfg.clone()
+        })
+        .collect();
+
+    let otlp = cfg.otlp.clone().map(Otlp::start);
+    let mut senders = Vec::with_capacity(cfgs.len());
+    for c in &cfgs {
+        let dest = match c.target.resolve(c.port, None) {
+            Ok(d) => d,
+            Err(e) => return exit::fail(e),
+        };
+        let sock = match bind_tuned(c, dest)
+            .and_then(|s| check_route(dest).map(|()| s))
+            .and_then(|s| check_mtu(c, &[dest]).map(|()| s))
+        {
+            Ok(s) => s,
+            Err(e) => return exit::fail(e),
+        };
+        let clock = SystemClock::for_interval(c.interval);
+        let mut sender = Sender::new(c, clock, Outlet::Socket(sock).offloaded(c), dest);
+        match open_state(c) {
+            Ok(Some(state)) => sender.resume(state),
+            Ok(None) => {}
+            Err(e) => return exit::fail(e),
+        }
+        if let Some(s) = &c.statsd {
+            match Statsd::connect(s) {
+                Ok(statsd) => sender.set_statsd(statsd),
+                Err(e) => return exit::fail(e),
+            }
+        }
+        if let Some(o) = &otlp {
+            sender.set_otlp(o.clone());
+        }
+        log::info!(
+            "Sending to {dest} every {}",
+            units::micros(c.interval.as_micros() as u64)
+        );
+        senders.push(sender);
+    }
+
+    if let (Some(port), Some(sender)) = (cfg.serve_queries, senders.first()) {
+        if let Err(e) = serve_queries(cfg, port, sender.dest()) {
+            return exit::fail(e);
+        }
+    }
+
+    let mut hotplug = watch_interfaces();
+    let mut snapshots = match open_snapshots(cfg) {
+        Ok(s) => s,
+        Err(e) => return exit::fail(e),
+    };
+    if let Err(e) = tune_thread(cfg).and_then(|()| cfg.hardening.apply()) {
+        return exit::fail(e);
+    }
+
+    cfg.start.wait();
+    let mut scheduler = Scheduler::new(senders);
+    let mut usr1 = sys::sigusr1_count();
+    loop {
+        if sys::terminating() {
+            log::info!("Stopping; saving sequence numbers");
+            for sender in scheduler.senders_mut() {
+                sender.save_state();
+            }
+            return ExitCode::SUCCESS;
+        }
+        let signals = sys::sigusr1_count();
+        if std::mem::replace(&mut usr1, signals) != signals {
+            for sender in scheduler.senders() {
+                write_snapshot(&mut snapshots, sender);
+            }
+        }
+        if interfaces_changed(&mut hotplug) {
+            for sender in scheduler.senders_mut() {
+                reselect(sender.cfg, sender);
+            }
+        }
+        // Only clock errors are fatal once running
+        if let Err(e) = scheduler.step() {
+            return exit::fail(Failure::new(EXIT_CODE_CLOCK_ERROR, e));
+        }
+    }
+}
+
+/// Opens the `--state-dir` file of the sender `cfg` describes, named for
+/// its destination and group, and has SIGINT and SIGTERM end it cleanly
+/// so it is saved. Without the handler, [`sys::terminating`] stays false.
+fn open_state(cfg: &SendConfig) -> Result<Option<SeqState>, String> {
+    let Some(dir) = &cfg.state_dir else {
+        return Ok(None);
+    };
+    let target = match (&cfg.peers, &cfg.target) {
+        (Some(_), _) => "peers".to_string(),
+        (None, Target::Ip(ip)) => ip.to_string(),
+        (None, Target::Scoped(ip, index, name)) => {
+            format!("{ip}%{}", name.clone().unwrap_or_else(|| index.to_string()))
+        }
+        (None, Target::Host(host)) => host.clone(),
+    };
+    let port = cfg.port.to_string();
+    let group = cfg.group.map(|g| format!("g{g}"));
+    let parts: Vec<&str> = [Some(target.as_str()), Some(port.as_str()), group.as_deref()]
+        .into_iter()
+        .flatten()
+        .collect();
+    let state = SeqState::open(dir.join(state::file_name(&parts)))?;
+    if let Err(e) = sys::watch_termination() {
+        log::warn!("State is saved at intervals only: {e}");
+    }
+    Ok(Some(state))
+}
+
+/// Opens where snapshots go and starts taking SIGUSR1 as a request for one.
+fn open_snapshots(cfg: &SendConfig) -> Result<Snapshots, String> {
+    let snapshots = Snapshots::open(cfg.snapshot_file.as_deref())?;
+    if let Err(e) = sys::watch_sigusr1() {
+        log::debug!("Snapshots on SIGUSR1 unavailable: {e}");
+    }
+    Ok(snapshots)
+}
+
+fn write_snapshot<C: Clock>(snapshots: &mut Snapshots, sender: &Sender<'_, C, Outlet>) {
+    let mut snapshot = sender.snapshot();
+    if let Outlet::IoThread { worker, .. } = sender.sink() {
+        snapshot.io_queue = Some(worker.stats());
+    }
+    if let Err(e) = snapshots.write(&snapshot) {
+        log::warn!("Failed to write snapshot: {e}");
+    }
+}
+
+/// Runs the send loop until a fatal error occurs.
+/// Sends the one `--once` timestamp, returning its sequence number if
+/// every destination took it.
+fn send_single<C: Clock, S: DatagramSink>(sender: &mut Sender<'_, C, S>) -> Result<u32, Failure> {
+    if sender.watch_clock() {
+        return Err("The clock isn't synchronized; nothing was sent"
+            .to_string()
+            .into());
+    }
+    let seq = sender.seq();
+    sender
+        .send_once()
+        .map_err(|e| Failure::new(EXIT_CODE_CLOCK_ERROR, e))?;
+    if sender.seq() == seq {
+        return Err("The timestamp was vetoed by a hook; nothing was sent"
+            .to_string()
+            .into());
+    }
+    let (sent, failed) = sender
+        .snapshot()
+        .dests
+        .iter()
+        .fold((0, 0), |(s, f), d| (s + d.counts.sent, f + d.counts.failed));
+    match (sent, failed) {
+        (0, _) => Err("The timestamp wasn't sent to any destination"
+            .to_string()
+            .into()),
+        (_, 0) => Ok(seq),
+        _ => Err(format!(
+            "The timestamp wasn't sent to {failed} of {} destinations",
+            sent + failed
+        )
+        .into()),
+    }
+}
+
+/// Waits up to `wait` for a receiver to ack `seq`.
+fn wait_for_ack(acks: &mpsc::Receiver<Ack>, seq: u32, wait: Duration) -> Result<(), Failure> {
+    let deadline = Instant::now() + wait;
+    loop {
+        match acks.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(ack) if ack.seq == seq => {
+                log::info!("Acked by {} seq={seq} rtt_us={}", ack.from, ack.rtt_us);
+                return Ok(());
+            }
+            Ok(_) => {}
+            Err(_) => {
+                return Err(
+                    format!("No receiver acked seq={seq} within {} ms", wait.as_millis()).into(),
+                )
+            }
+        }
+    }
+}
+
+pub fn run(cfg: &SendConfig) -> ExitCode {
+    if cfg.dry_run {
+        return match dry_run(cfg) {
+            Ok(plan) => {
+                print!("{plan}");
+                ExitCode::SUCCESS
+            }
+            Err(e) => exit::fail(e),
+        };
+    }
+    if let Some(check) = &cfg.require_sync {
+        match sntp::verify(check) {
+            Ok(sample) => log::info!("Clock check: {} status=ok", sample.summary()),
+            Err(e) => {
+                return exit::fail(Failure::new(e.code, format!("Refusing to broadcast: {e}")))
+            }
+        }
+    }
+    // Checked once up front, so a platform that can't tell fails rather
+    // than holding forever
+    if cfg.require_clock_sync.is_some() {
+        if let Err(e) = clock::sync_status() {
+            return exit::fail(format!(
+                "Failed to read the clock's synchronization status: {e}"
+            ));
+        }
+    }
+
+    if !cfg.dests.is_empty() {
+        return run_dests(cfg);
+    }
+
+    let dest = match (&cfg.peers, cfg.target.resolve(cfg.port, None)) {
+        (Some(peers), _) => peers[0],
+        (None, Ok(d)) => d,
+        (None, Err(e)) => return exit::fail(e),
+    };
+
+    let mut dual_stack = Vec::new();
+    let outlet = match (&cfg.peers, &cfg.dual_stack) {
+        (Some(peers), _) => PeerSink::connect(peers, cfg.peer_backoff, cfg.fanout_threads, |a| {
+            bind_tuned(cfg, a)
+        })
+        .map(Outlet::Peers),
+        (None, Some(group)) => Outlet::dual_stack(cfg, dest, group).map(|(outlet, dests)| {
+            dual_stack = dests;
+            outlet
+        }),
+        (None, None) if cfg.transport == Transport::Tcp => {
+            StreamServer::listen(dest).map(Outlet::Stream)
+        }
+        (None, None) => bind_tuned(cfg, dest).map(Outlet::Socket),
+    };
+    let outlet = match outlet {
+        Ok(o) => o.offloaded(cfg),
+        Err(e) => return exit::fail(e),
+    };
+    // Peers were checked when their sockets were connected, and consumers
+    // come to the sender
+    if cfg.peers.is_none() && cfg.transport == Transport::Udp {
+        if let Err(e) = check_route(dest) {
+            return exit::fail(e);
+        }
+    }
+
+    let mut follow = match &cfg.follow {
+        Some(f) => match bind_receiver(f.port, dest) {
+            Ok(sock) => Some((sock, Follower::new(f.key.clone(), cfg.group))),
+            Err(e) => return exit::fail(e),
+        },
+        None => None,
+    };
+    let mut pending: Option<Control> = None;
+
+    let mut feedback = match (&cfg.adaptive, sender_socket(&outlet)) {
+        (Some(c), Some(sock)) => match adaptive::listen(sock) {
+            Ok(acks) => Some((acks, Controller::new(*c))),
+            Err(e) => return exit::fail(e),
+        },
+        _ => None,
+    };
+    // Listening before sending, so an ack can't arrive unread
+    let acks = match (cfg.wait_ack, sender_socket(&outlet)) {
+        (Some(_), Some(sock)) => match adaptive::listen(sock) {
+            Ok(acks) => Some(acks),
+            Err(e) => return exit::fail(e),
+        },
+        _ => None,
+    };
+
+    let mut guard = match cfg.guard {
+        Some(_) => match bind_receiver(cfg.port, dest) {
+            Ok(sock) => Some((sock, Guard::new(cfg.group))),
+            Err(e) => return exit::fail(e),
+        },
+        None => None,
+    };
+
+    let mut election = match cfg.role {
+        Role::Primary => None,
+        role => match bind_receiver(cfg.port, dest) {
+            Ok(sock) => {
+                let now_ms = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_millis() as u64);
+                let timeout_ms = cfg.failover.as_millis() as u64;
+                let priority = cfg
+                    .priority
+                    .map_or_else(String::new, |p| format!(" priority={p}"));
+                log::info!("event=role_start role={role} state=standby unix_ms={now_ms}{priority}");
+                Some((
+                    sock,
+                    Election::new(role, timeout_ms, cfg.priority, Rng::from_entropy(), now_ms),
+                ))
+            }
+            Err(e) => return exit::fail(e),
+        },
+    };
+
+    let clock = SystemClock::for_interval(cfg.pace());
+    let mut sender = Sender::new(cfg, clock, outlet, dest);
+    match open_state(cfg) {
+        Ok(Some(state)) => sender.resume(state),
+        Ok(None) => {}
+        Err(e) => return exit::fail(e),
+    }
+    if let Some(peers) = &cfg.peers {
+        log::info!("Sending to {} peer(s)", peers.len());
+        sender.fan_out(peers.clone());
+    }
+    if !dual_stack.is_empty() {
+        log::info!("Sending to {} and {}", dual_stack[0], dual_stack[1]);
+        sender.fan_out(dual_stack);
+    }
+    sender.set_active(election.is_none());
+    if let Some(c) = &cfg.statsd {
+        match Statsd::connect(c) {
+            Ok(statsd) => sender.set_statsd(statsd),
+            Err(e) => return exit::fail(e),
+        }
+    }
+    if let Some(c) = &cfg.otlp {
+        sender.set_otlp(Otlp::start(c.clone()));
+    }
+    if cfg.per_interface {
+        match interface_broadcasts(cfg.port) {
+            Ok(dests) => sender.fan_out(dests),
+            Err(e) => return exit::fail(e),
+        }
+    }
+    if cfg.transport == Transport::Udp {
+        if let Err(e) = check_mtu(cfg, sender.dests()) {
+            return exit::fail(e);
+        }
+    }
+    if cfg.mdns {
+        let mcast = match cfg.target {
+            Target::Ip(ip) if ip.is_multicast() => Some(ip),
+            _ => None,
+        };
+        let advert = Advert::for_host(
+            cfg.mdns_name.clone(),
+            cfg.port,
+            cfg.format,
+            cfg.group,
+            mcast,
+        );
+        if let Err(e) = advert.and_then(mdns::start) {
+            return exit::fail(e);
+        }
+    }
+    let health = (cfg.status_addr.is_some() || cfg.control_api.is_some())
+        .then(|| Arc::new(Health::new(cfg.pace(), sender.instant())));
+    if let Some(health) = &health {
+        if let Some(addr) = cfg.status_addr {
+            if let Err(e) = status::serve(addr, Arc::clone(health)) {
+                return exit::fail(e);
+            }
+        }
+        sender.set_health(Arc::clone(health));
+        sender.set_active(election.is_none());
+    }
+    let api = match (&cfg.control_api, health) {
+        (Some(c), Some(health)) => match api::serve(c) {
+            Ok((_, api)) => Some((api, health)),
+            Err(e) => return exit::fail(e),
+        },
+        _ => None,
+    };
+    let mut added = Vec::new();
+    if let Some(port) = cfg.serve_queries {
+        if let Err(e) = serve_queries(cfg, port, dest) {
+            return exit::fail(e);
+        }
+    }
+    let mut next_resolve = cfg.resolve_interval.map(|i| sender.instant() + i);
+    let mut hotplug = watch_interfaces();
+    let mut snapshots = match open_snapshots(cfg) {
+        Ok(s) => s,
+        Err(e) => return exit::fail(e),
+    };
+    match cfg.rate {
+        Some(pps) => log::debug!("Pacing: token bucket at {}", units::pps(pps)),
+        None => log::debug!(
+            "Pacing: interval={} jitter={} burst={} burst_spacing_us={} redundancy={}",
+            units::micros(cfg.interval.as_micros() as u64),
+            units::micros(cfg.jitter.as_micros() as u64),
+            cfg.burst,
+            cfg.burst_spacing.as_micros(),
+            cfg.redundancy
+        ),
+    }
+
+    // Last, so helper threads such as the mDNS responder don't inherit them
+    if let Err(e) = tune_thread(cfg) {
+        return exit::fail(e);
+    }
+    // Everything privileged is done by now
+    if let Err(e) = cfg.hardening.apply() {
+        return exit::fail(e);
+    }
+
+    cfg.start.wait();
+    if let (Some(GuardMode::Exclusive), Some((sock, g))) = (cfg.guard, guard.as_mut()) {
+        if let Err(e) = probe_guard(sock, g, cfg.port) {
+            return exit::fail(e);
+        }
+    }
+    if cfg.once {
+        let acked = send_single(&mut sender).and_then(|seq| match (&acks, cfg.wait_ack) {
+            (Some(acks), Some(wait)) => wait_for_ack(acks, seq, wait),
+            _ => Ok(()),
+        });
+        return match acked {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => exit::fail(e),
+        };
+    }
+    let mut usr1 = sys::sigusr1_count();
+    loop {
+        if sys::terminating() {
+            log::info!("Stopping; saving sequence numbers");
+            sender.save_state();
+            return ExitCode::SUCCESS;
+        }
+        let signals = sys::sigusr1_count();
+        if (std::mem::replace(&mut usr1, signals) != signals) | sender.take_snapshot_request() {
+            write_snapshot(&mut snapshots, &sender);
+        }
+        if let Some((sock, follower)) = follow.as_mut() {
+            let now_ms = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as u64);
+            poll_control(sock, follower, now_ms, &mut pending);
+            if let Some(c) = pending.filter(|c| c.apply_at_ms <= now_ms) {
+                pending = None;
+                if let Err(e) = apply_control(cfg, &mut sender, c) {
+                    return exit::fail(e);
+                }
+            }
+        }
+
+        if let Some((acks, controller)) = feedback.as_mut() {
+            for ack in acks.try_iter() {
+                controller.ack(&ack);
+            }
+            let was = sender.interval();
+            match controller.tick(was) {
+                Some(d) if d.interval != was => {
+                    log::info!("Adaptive interval: {d} was_us={}", was.as_micros());
+                    sender.set_interval(d.interval);
+                }
+                Some(d) => log::debug!("Adaptive interval: {d}"),
+                None => {}
+            }
+        }
+
+        if let Some((sock, election)) = election.as_mut() {
+            let now_ms = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as u64);
+            if let Err(e) = poll_election(sock, election, &mut sender, now_ms) {
+                return exit::fail(e);
+            }
+        }
+
+        if let Some((sock, g)) = guard.as_mut() {
+            let now_ms = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as u64);
+            poll_guard(sock, g, sender.sink().local_port(), now_ms);
+        }
+
+        if let (Some(at), Some(every)) = (next_resolve, cfg.resolve_interval) {
+            if sender.instant() >= at {
+                next_resolve = Some(at + every);
+                reresolve(cfg, &mut sender);
+                restore_added(&mut sender, &added);
+            }
+        }
+
+        if interfaces_changed(&mut hotplug) {
+            reselect(cfg, &mut sender);
+            restore_added(&mut sender, &added);
+        }
+
+        // Only clock errors are fatal once running
+        let stepped = match &api {
+            Some(api) => step_answering(cfg, &mut sender, api, &mut added),
+            None => sender.step(),
+        };
+        if let Err(e) = stepped {
+            return exit::fail(Failure::new(EXIT_CODE_CLOCK_ERROR, e));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SyncStatus;
+    use std::cell::RefCell;
+
+    /// A clock that only moves when slept on.
+    struct FakeClock {
+        wall: SystemTime,
+        base: Instant,
+        elapsed: Duration,
+        sleeps: Vec<Duration>,
+        /// Added to every sleep, like a coarse OS timer.
+        overshoot: Duration,
+        /// What the kernel would say of the clock's synchronization.
+        sync: SyncStatus,
+    }
+
+    impl FakeClock {
+        fn at_ms(ms: u64) -> FakeClock {
+            FakeClock {
+                wall: UNIX_EPOCH + Duration::from_millis(ms),
+                base: Instant::now(),
+                elapsed: Duration::ZERO,
+                sleeps: Vec::new(),
+                overshoot: Duration::ZERO,
+                sync: SyncStatus {
+                    synced: true,
+                    max_error: Duration::from_millis(1),
+                },
+            }
+        }
+    }
+
+    impl Clock for &mut FakeClock {
+        fn now(&self) -> SystemTime {
+            self.wall + self.elapsed
+        }
+
+        fn instant(&self) -> Instant {
+            self.base + self.elapsed
+        }
+
+        fn sleep(&mut self, d: Duration) {
+            self.elapsed += d + self.overshoot;
+            self.sleeps.push(d);
+        }
+
+        fn sync_status(&self) -> io::Result<SyncStatus> {
+            Ok(self.sync)
+        }
+    }
+
+    /// One fake clock shared by several senders.
+    impl Clock for &RefCell<FakeClock> {
+        fn now(&self) -> SystemTime {
+            let c = self.borrow();
+            c.wall + c.elapsed
+        }
+
+        fn instant(&self) -> Instant {
+            let c = self.borrow();
+            c.base + c.elapsed
+        }
+
+        fn sleep(&mut self, d: Duration) {
+            (&mut *self.borrow_mut()).sleep(d)
+        }
+
+        fn sync_status(&self) -> io::Result<SyncStatus> {
+            Ok(self.borrow().sync)
+        }
+    }
+
+    /// A sink that records datagrams, failing the first `fail_next` sends.
+    #[derive(Default)]
+    struct MemorySink {
+        sent: Vec<(Vec<u8>, SocketAddr)>,
+        fail_next: usize,
+        /// What the failures are; `Other` by default.
+        fail_kind: Option<io::ErrorKind>,
+        /// Size of each batch handed over.
+        batches: Vec<usize>,
+        /// How far ahead each `--txtime` batch was to leave.
+        delays: Vec<Duration>,
+    }
+
+    impl DatagramSink for &mut MemorySink {
+        fn send_to(&mut self, payload: &[u8], dest: SocketAddr) -> io::Result<usize> {
+            if self.fail_next > 0 {
+                self.fail_next -= 1;
+                let kind = self.fail_kind.unwrap_or(io::ErrorKind::Other);
+                return Err(io::Error::new(kind, "network down"));
+            }
+            self.sent.push((payload.to_vec(), dest));
+            Ok(payload.len())
+        }
+
+        fn send_batch(&mut self, msgs: &[(&[u8], SocketAddr)]) -> Vec<io::Result<usize>> {
+            self.batches.push(msgs.len());
+            msgs.iter().map(|&(p, d)| self.send_to(p, d)).collect()
+        }
+
+        fn send_batch_after(
+            &mut self,
+            msgs: &[(&[u8], SocketAddr)],
+            delay: Duration,
+        ) -> Vec<io::Result<usize>> {
+            self.delays.push(delay);
+            self.send_batch(msgs)
+        }
+    }
+
+    fn config(format: Format) -> SendConfig {
+        SendConfig {
+            target: Target::Ip(IpAddr::V4(Ipv4Addr::BROADCAST)),
+            port: 12321,
+            interval: Duration::from_millis(250),
+            rate: None,
+            adaptive: None,
+            jitter: Duration::ZERO,
+            max_lateness: None,
+            burst: 1,
+            burst_spacing: Duration::ZERO,
+            redundancy: 1,
+            redundancy_spacing: Duration::ZERO,
+            format,
+            full_every: compact::DEFAULT_FULL_EVERY,
+            transport: Transport::Udp,
+            codec: None,
+            hooks: None,
+            epoch: Epoch::Unix,
+            resolve_interval: None,
+            group: None,
+            fields: Vec::new(),
+            key: None,
+            sign_key: None,
+            crc: false,
+            encrypt_key: None,
+            follow: None,
+            role: Role::Primary,
+            failover: Duration::from_secs(1),
+            guard: None,
+            once: false,
+            wait_ack: None,
+            priority: None,
+            tx_compensation: None,
+            txtime: None,
+            per_interface: false,
+            statsd: None,
+            otlp: None,
+            mdns: false,
+            mdns_name: None,
+            leap_smear: None,
+            step_holddown: None,
+            require_clock_sync: None,
+            sched_fifo: None,
+            cpu_affinity: None,
+            hardening: Hardening::default(),
+            sndbuf: None,
+            send_timeout: None,
+            ttl: None,
+            allow_fragmentation: false,
+            peers: None,
+            dests: Vec::new(),
+            dual_stack: None,
+            io_queue: None,
+            peer_backoff: None,
+            fanout_threads: None,
+            status_addr: None,
+            serve_queries: None,
+            control_api: None,
+            require_sync: None,
+            dry_run: false,
+            start: StartConfig::default(),
+            snapshot_file: None,
+            state_dir: None,
+            blackout: Vec::new(),
+            #[cfg(feature = "chaos")]
+            chaos: None,
+        }
+    }
+
+    fn dest() -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), 12321)
+    }
+
+    #[test]
+    fn legacy_payload_is_big_endian_millis() {
+        let cfg = config(Format::Legacy);
+        let mut clock = FakeClock::at_ms(1_700_000_000_123);
+        let mut sink = MemorySink::default();
+        Sender::new(&cfg, &mut clock, &mut sink, dest())
+            .send_once()
+            .unwrap();
+
+        assert_eq!(sink.sent.len(), 1);
+        assert_eq!(sink.sent[0].0, 1_700_000_000_123u64.to_be_bytes());
+        assert_eq!(sink.sent[0].1, dest());
+    }
+
+    #[test]
+    fn tx_compensation_stamps_the_time_of_sending() {
+        let mut cfg = config(Format::Legacy);
+        cfg.tx_compensation = Some(Compensation::Fixed(Duration::from_micros(1_500)));
+        let mut clock = FakeClock::at_ms(1_700_000_000_123);
+        let mut sink = MemorySink::default();
+        let mut sender = Sender::new(&cfg, &mut clock, &mut sink, dest());
+        sender.send_once().unwrap();
+        let (correction, delay) = sender.snapshot().tx_compensation.unwrap();
+        assert_eq!(correction, Duration::from_micros(1_500));
+        assert_eq!(delay.samples(), 1);
+
+        assert_eq!(sink.sent[0].0, 1_700_000_000_124u64.to_be_bytes());
+    }
+
+    #[test]
+    fn txtime_stamps_the_time_of_leaving() {
+        let mut cfg = config(Format::Legacy);
+        cfg.txtime = Some(Duration::from_millis(2));
+        let mut clock = FakeClock::at_ms(1_700_000_000_000);
+        let mut sink = MemorySink::default();
+        let mut sender = Sender::new(&cfg, &mut clock, &mut sink, dest());
+        // The first tick leaves the lead from now, the rest on the grid,
+        // each handed over the lead ahead
+        let due = sender.tick().unwrap();
+        sender.sleep_until(due);
+        sender.tick().unwrap();
+
+        assert_eq!(sink.delays, [Duration::from_millis(2); 2]);
+        assert_eq!(sink.sent[0].0, 1_700_000_000_002u64.to_be_bytes());
+        assert_eq!(sink.sent[1].0, 1_700_000_000_250u64.to_be_bytes());
+    }
+
+    #[test]
+    fn sealed_payloads_open_only_with_the_key() {
+        let mut cfg = config(Format::V2);
+        cfg.fields = vec![Field::parse("site=lab").unwrap()];
+        cfg.encrypt_key = Some(AeadKey::new([7; 32]));
+        let mut clock = FakeClock::at_ms(1_000);
+        let mut sink = MemorySink::default();
+        let mut sender = Sender::new(&cfg, &mut clock, &mut sink, dest());
+        sender.step().unwrap();
+        sender.step().unwrap();
+
+        let sealed = &sink.sent[1].0;
+        assert!(!sealed.windows(8).any(|w| w == b"site=lab"));
+        assert!(payload::decode(sealed).is_err());
+        let mut opened = Vec::new();
+        payload::open(sealed, cfg.encrypt_key.as_ref().unwrap(), &mut opened).unwrap();
+        let d = payload::decode(&opened).unwrap();
+        assert_eq!((d.packet.seq, d.packet.ts_ms), (Some(1), 1_250));
+        assert_eq!(d.fields, [("site", "lab")]);
+        // Both ticks share the salt drawn at sequence number 0
+        assert_eq!(sink.sent[0].0[8..16], sealed[8..16]);
+
+        let wrong = AeadKey::new([8; 32]);
+        assert!(payload::open(sealed, &wrong, &mut opened).is_err());
+        let mut tampered = sealed.clone();
+        tampered[5] ^= 1;
+        assert!(payload::open(&tampered, cfg.encrypt_key.as_ref().unwrap(), &mut opened).is_err());
+    }
+
+    #[test]
+    fn largest_payload_allows_for_a_clock_era() {
+        let cfg = config(Format::V2);
+        let mut clock = FakeClock::at_ms(1_000);
+        let mut sink = MemorySink::default();
+        Sender::new(&cfg, &mut clock, &mut sink, dest())
+            .send_once()
+            .unwrap();
+
+        let largest = largest_payload(&cfg, dest()).unwrap();
+        assert!(largest.len() > sink.sent[0].0.len());
+        assert_eq!(
+            payload::decode(&largest).unwrap().packet.era,
+            Some(u16::MAX)
+        );
+        assert_eq!(header_len(dest()), 28);
+    }
+
+    #[test]
+    fn v2_payload_carries_increasing_sequence() {
+        let cfg = config(Format::V2);
+        let mut clock = FakeClock::at_ms(1_000);
+        let mut sink = MemorySink::default();
+        let mut sender = Sender::new(&cfg, &mut clock, &mut sink, dest());
+        for _ in 0..3 {
+            sender.step().unwrap();
+        }
+
+        let decoded: Vec<Packet> = sink
+            .sent
+            .iter()
+            .map(|(p, _)| payload::decode(p).unwrap().packet)
+            .collect();
+        assert_eq!(
+            decoded.iter().map(|p| p.seq).collect::<Vec<_>>(),
+            [Some(0), Some(1), Some(2)]
+        );
+        assert_eq!(
+            decoded.iter().map(|p| p.ts_ms).collect::<Vec<_>>(),
+            [1_000, 1_250, 1_500]
+        );
+        assert_eq!(
+            decoded.iter().map(|p| p.mono_us).collect::<Vec<_>>(),
+            [Some(0), Some(250_000), Some(500_000)]
+        );
+    }
+
+    #[test]
+    fn hooks_tag_veto_and_follow_each_datagram() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+        use std::sync::Mutex;
+
+        let ticks = Arc::new(AtomicU32::new(0));
+        let outcomes = Arc::new(Mutex::new(Vec::new()));
+        let (t, sent, failed) = (ticks.clone(), outcomes.clone(), outcomes.clone());
+        let mut cfg = config(Format::V2);
+        cfg.hooks = Some(Arc::new(
+            Hooks::new()
+                .on_before_send(move |_, fields| {
+                    // Every second tick is vetoed, the others tagged
+                    let n = t.fetch_add(1, Ordering::Relaxed);
+                    if n % 2 == 1 {
+                        return Verdict::Veto;
+                    }
+                    fields.push(Field::parse(&format!("corr=c{n}")).unwrap());
+                    Verdict::Send
+                })
+                .on_sent(move |o| sent.lock().unwrap().push((o.seq, true)))
+                .on_error(move |o, _| failed.lock().unwrap().push((o.seq, false))),
+        ));
+        let mut clock = FakeClock::at_ms(1_000);
+        let mut sink = MemorySink::default();
+        let mut sender = Sender::new(&cfg, &mut clock, &mut sink, dest());
+        for _ in 0..4 {
+            sender.send_once().unwrap();
+        }
+        sink.fail_next = 1;
+        Sender::new(&cfg, &mut clock, &mut sink, dest())
+            .send_once()
+            .unwrap();
+
+        let decoded: Vec<_> = sink
+            .sent
+            .iter()
+            .map(|(p, _)| {
+                let d = payload::decode(p).unwrap();
+                (d.packet.seq, d.fields[0].1.to_string())
+            })
+            .collect();
+        // Vetoed datagrams take no sequence number
+        assert_eq!(
+            decoded,
+            [(Some(0), "c0".to_string()), (Some(1), "c2".to_string())]
+        );
+        assert_eq!(
+            *outcomes.lock().unwrap(),
+            [(0, true), (1, true), (0, false)]
+        );
+        // Sizing the payload at startup isn't a tick
+        largest_payload(&cfg, dest()).unwrap();
+        assert_eq!(ticks.load(Ordering::Relaxed), 5);
+    }
+
+    #[test]
+    fn fields_ride_ahead_of_the_mac() {
+        let mut cfg = config(Format::V2);
+        cfg.fields = vec![
+            Field::parse("site=lab1").unwrap(),
+            Field::parse("rack=7").unwrap(),
+        ];
+        cfg.key = Some(Key::new(b"secret"));
+        let mut clock = FakeClock::at_ms(1_000);
+        let mut sink = MemorySink::default();
+        Sender::new(&cfg, &mut clock, &mut sink, dest())
+            .send_once()
+            .unwrap();
+
+        let decoded = payload::decode(&sink.sent[0].0).unwrap();
+        assert_eq!(decoded.fields, [("site", "lab1"), ("rack", "7")]);
+        assert!(decoded.verify(cfg.key.as_ref().unwrap()));
+        assert!(Field::parse("site").is_err());
+        assert!(Field::parse("bad key=1").is_err());
+        assert!(Field::parse("note=two words").is_err());
+        assert_eq!(Field::parse("empty=").unwrap().value, "");
+    }
+
+    /// An application's own format: a tag, the sequence number, the
+    /// timestamp and one field, all as text.
+    struct TextCodec;
+
+    impl PayloadCodec for TextCodec {
+        fn encode(&self, packet: &Packet, fields: &[Field], out: &mut Vec<u8>) {
+            out.clear();
+            let f = &fields[0];
+            let text = format!(
+                "app {} {} {}={}",
+                packet.seq.unwrap_or(0),
+                packet.ts_ms,
+                f.key,
+                f.value
+            );
+            out.extend_from_slice(text.as_bytes());
+        }
+
+        fn decode<'a>(&self, data: &'a [u8]) -> Result<payload::Decoded<'a>, payload::DecodeError> {
+            let codec = |e: &dyn std::fmt::Display| payload::DecodeError::Codec(e.to_string());
+            let text = std::str::from_utf8(data).map_err(|e| codec(&e))?;
+            let parts: Vec<&str> = text.split(' ').collect();
+            let [_, seq, ts_ms, field] = parts[..] else {
+                return Err(codec(&"not an app payload"));
+            };
+            let num = |s: &str| s.parse::<u64>().map_err(|e| codec(&e));
+            Ok(payload::Decoded {
+                packet: Packet::new(Some(num(seq)? as u32), num(ts_ms)?),
+                format: Format::V2,
+                mac: None,
+                key_id: None,
+                sig: None,
+                fields: field.split_once('=').into_iter().collect(),
+                echo: None,
+                crc: None,
+            })
+        }
+    }
+
+    #[test]
+    fn codec_replaces_the_format() {
+        let mut cfg = config(Format::V2);
+        cfg.codec = Some(Arc::new(TextCodec));
+        cfg.fields = vec![Field::parse("site=lab1").unwrap()];
+        let mut clock = FakeClock::at_ms(1_000);
+        let mut sink = MemorySink::default();
+        let mut sender = Sender::new(&cfg, &mut clock, &mut sink, dest());
+        sender.step().unwrap();
+        sender.step().unwrap();
+
+        assert_eq!(sink.sent[0].0, b"app 0 1000 site=lab1");
+        let codec = cfg.codec.as_ref().unwrap();
+        let decoded = codec.decode(&sink.sent[1].0).unwrap();
+        assert_eq!((decoded.packet.seq, decoded.packet.ts_ms), (Some(1), 1_250));
+        assert_eq!(decoded.fields, [("site", "lab1")]);
+        // The built-in formats are codecs too
+        let mut out = Vec::new();
+        Format::Proto.encode(&decoded.packet, &cfg.fields, &mut out);
+        assert_eq!(Format::Proto.decode(&out).unwrap().packet.ts_ms, 1_250);
+    }
+
+    #[test]
+    fn step_sleeps_for_the_interval() {
+        let cfg = config(Format::Legacy);
+        let mut clock = FakeClock::at_ms(0);
+        let mut sink = MemorySink::default();
+        let mut sender = Sender::new(&cfg, &mut clock, &mut sink, dest());
+        sender.step().unwrap();
+        sender.step().unwrap();
+
+        assert_eq!(clock.sleeps, [Duration::from_millis(250); 2]);
+    }
+
+    #[test]
+    fn burst_sends_spaced_sequenced_datagrams_per_tick() {
+        let mut cfg = config(Format::V2);
+        cfg.burst = 3;
+        cfg.burst_spacing = Duration::from_micros(100);
+        let mut clock = FakeClock::at_ms(0);
+        let mut sink = MemorySink::default();
+        let mut sender = Sender::new(&cfg, &mut clock, &mut sink, dest());
+        sender.step().unwrap();
+        sender.step().unwrap();
+
+        let seqs: Vec<_> = sink
+            .sent
+            .iter()
+            .map(|(p, _)| payload::decode(p).unwrap().packet.seq)
+            .collect();
+        assert_eq!(seqs, [0, 1, 2, 3, 4, 5].map(Some));
+        // Spacing inside the burst doesn't stretch the tick
+        let spacing = Duration::from_micros(100);
+        let rest = Duration::from_millis(250) - spacing * 2;
+        assert_eq!(
+            clock.sleeps,
+            [spacing, spacing, rest, spacing, spacing, rest]
+        );
+    }
+
+    #[test]
+    fn redundant_copies_repeat_the_sequence_number() {
+        let mut cfg = config(Format::V2);
+        cfg.burst = 2;
+        cfg.redundancy = 3;
+        cfg.redundancy_spacing = Duration::from_millis(5);
+        assert_eq!(cfg.tick_span(), Some(Duration::from_millis(10)));
+        let mut clock = FakeClock::at_ms(0);
+        let mut sink = MemorySink::default();
+        let mut sender = Sender::new(&cfg, &mut clock, &mut sink, dest());
+        sender.step().unwrap();
+
+        let seqs: Vec<_> = sink
+            .sent
+            .iter()
+            .map(|(p, _)| payload::decode(p).unwrap().packet.seq)
+            .collect();
+        assert_eq!(seqs, [0, 1, 0, 1, 0, 1].map(Some));
+        assert_eq!(sink.batches, [2, 2, 2]);
+        // The copies come out of the tick, not on top of it
+        let gap = Duration::from_millis(5);
+        assert_eq!(clock.sleeps, [gap, gap, Duration::from_millis(240)]);
+    }
+
+    #[test]
+    fn jitter_stays_within_bound_without_drifting() {
+        let mut cfg = config(Format::Legacy);
+        cfg.jitter = Duration::from_millis(100);
+        let mut clock = FakeClock::at_ms(0);
+        let mut sink = MemorySink::default();
+        let mut sender = Sender::new(&cfg, &mut clock, &mut sink, dest());
+        sender.rng = Rng::new(42);
+        for _ in 0..1000 {
+            sender.step().unwrap();
+        }
+
+        let times: Vec<u64> = sink
+            .sent
+            .iter()
+            .map(|(p, _)| payload::decode(p).unwrap().packet.ts_ms)
+            .collect();
+        for gap in times.windows(2).map(|w| w[1] - w[0]) {
+            assert!((150..=350).contains(&gap), "gap {gap} out of bounds");
+        }
+        assert!(times.windows(2).any(|w| w[1] - w[0] != 250));
+
+        // 1000 ticks end within half the jitter bound of the nominal grid
+        let total = clock.elapsed.as_millis() as i64;
+        assert!((total - 250_000).abs() <= 50, "drifted to {total}");
+    }
+
+    #[test]
+    fn send_errors_are_not_fatal() {
+        let cfg = config(Format::V2);
+        let mut clock = FakeClock::at_ms(0);
+        let mut sink = MemorySink {
+            fail_next: 2,
+            ..MemorySink::default()
+        };
+        let mut sender = Sender::new(&cfg, &mut clock, &mut sink, dest());
+        for _ in 0..3 {
+            assert!(sender.step().is_ok());
+        }
+
+        // The loop kept its schedule and sequence through the failures
+        assert_eq!(clock.sleeps.len(), 3);
+        assert_eq!(sink.sent.len(), 1);
+        assert_eq!(
+            payload::decode(&sink.sent[0].0).unwrap().packet.seq,
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn permission_errors_back_off() {
+        let cfg = config(Format::V2);
+        let mut clock = FakeClock::at_ms(0);
+        let mut sink = MemorySink {
+            fail_next: 2,
+            fail_kind: Some(io::ErrorKind::PermissionDenied),
+            ..MemorySink::default()
+        };
+        let mut sender = Sender::new(&cfg, &mut clock, &mut sink, dest());
+        // Fails at 0 ms and is skipped for 1 s, then fails at 1000 ms and is
+        // skipped for 2 s, then goes through at 3000 ms
+        for _ in 0..13 {
+            sender.step().unwrap();
+        }
+        assert_eq!(sender.error_count(ErrorClass::Permission), 2);
+        assert_eq!(sink.sent.len(), 1);
+        assert_eq!(
+            payload::decode(&sink.sent[0].0).unwrap().packet.seq,
+            Some(12)
+        );
+    }
+
+    #[test]
+    fn standby_keeps_ticking_without_sending() {
+        let cfg = config(Format::V2);
+        let mut clock = FakeClock::at_ms(0);
+        let mut sink = MemorySink::default();
+        let mut sender = Sender::new(&cfg, &mut clock, &mut sink, dest());
+        sender.set_active(false);
+        sender.step().unwrap();
+        // Paused over the control API, whatever the role says
+        sender.set_active(true);
+        sender.set_paused(true);
+        sender.step().unwrap();
+        sender.set_paused(false);
+        sender.step().unwrap();
+
+        assert_eq!(clock.sleeps, [Duration::from_millis(250); 3]);
+        assert_eq!(sink.sent.len(), 1);
+        assert_eq!(sender_seq(&sink), [Some(0)]);
+    }
+
+    #[test]
+    fn clock_steps_start_an_era_and_hold_sends_down() {
+        let cfg = SendConfig {
+            step_holddown: Some(Duration::from_millis(600)),
+            ..config(Format::V2)
+        };
+        let clock = RefCell::new(FakeClock::at_ms(1_700_000_000_000));
+        let mut sink = MemorySink::default();
+        let mut sender = Sender::new(&cfg, &clock, &mut sink, dest());
+        sender.step().unwrap();
+        sender.step().unwrap();
+        // NTP steps the clock back by 300 ms between ticks
+        clock.borrow_mut().wall -= Duration::from_millis(300);
+        for _ in 0..5 {
+            sender.step().unwrap();
+        }
+        drop(sender);
+
+        // Nothing goes out for the 600 ms after the step, three ticks
+        let eras: Vec<(Option<u32>, Option<u16>)> = sink
+            .sent
+            .iter()
+            .map(|(p, _)| payload::decode(p).unwrap().packet)
+            .map(|p| (p.seq, p.era))
+            .collect();
+        assert_eq!(
+            eras,
+            [
+                (Some(0), None),
+                (Some(1), None),
+                (Some(2), Some(1)),
+                (Some(3), Some(1))
+            ]
+        );
+    }
+
+    #[test]
+    fn unsynchronized_clock_holds_or_marks_sends() {
+        let unsynced = SyncStatus {
+            synced: false,
+            max_error: Duration::from_secs(16),
+        };
+        let mut cfg = config(Format::V2);
+        cfg.require_clock_sync = Some(ClockSync {
+            mode: SyncMode::Hold,
+            max_error: Duration::from_millis(100),
+        });
+        let clock = RefCell::new(FakeClock::at_ms(1_700_000_000_000));
+        clock.borrow_mut().sync = unsynced;
+        let mut sink = MemorySink::default();
+        let mut sender = Sender::new(&cfg, &clock, &mut sink, dest());
+        sender.step().unwrap();
+        sender.step().unwrap();
+        // Synchronized, but not yet closely enough
+        clock.borrow_mut().sync = SyncStatus {
+            synced: true,
+            max_error: Duration::from_millis(150),
+        };
+        for _ in 0..4 {
+            sender.step().unwrap();
+        }
+        clock.borrow_mut().sync.max_error = Duration::from_millis(20);
+        // Seen at the next reading, at 2 s, from which the ticks at 2,
+        // 2.25, 2.5 and 2.75 s are sent
+        for _ in 0..6 {
+            sender.step().unwrap();
+        }
+        drop(sender);
+        // Held ticks take no sequence numbers
+        assert_eq!(sender_seq(&sink), [Some(0), Some(1), Some(2), Some(3)]);
+
+        cfg.require_clock_sync = Some(ClockSync {
+            mode: SyncMode::Mark,
+            max_error: Duration::from_millis(100),
+        });
+        clock.borrow_mut().sync = unsynced;
+        let mut sink = MemorySink::default();
+        Sender::new(&cfg, &clock, &mut sink, dest())
+            .send_once()
+            .unwrap();
+        let mut sender = Sender::new(&cfg, &clock, &mut sink, dest());
+        sender.step().unwrap();
+        drop(sender);
+        let marked: Vec<bool> = sink
+            .sent
+            .iter()
+            .map(|(p, _)| payload::decode(p).unwrap().packet.unsynced)
+            .collect();
+        // A send that didn't look at the clock's status can't flag it
+        assert_eq!(marked, [false, true]);
+    }
+
+    fn sender_seq(sink: &MemorySink) -> Vec<Option<u32>> {
+        sink.sent
+            .iter()
+            .map(|(p, _)| payload::decode(p).unwrap().packet.seq)
+            .collect()
+    }
+
+    #[test]
+    fn reschedule_aligns_to_the_anchor_grid() {
+        let cfg = config(Format::Legacy);
+        let mut clock = FakeClock::at_ms(10_250);
+        let mut sink = MemorySink::default();
+        let mut sender = Sender::new(&cfg, &mut clock, &mut sink, dest());
+        // Applied 1.25 s late on a 1 s grid anchored at 9000
+        sender.reschedule(Duration::from_secs(1), 9_000).unwrap();
+        sender.step().unwrap();
+        sender.step().unwrap();
+
+        let times: Vec<u64> = sink
+            .sent
+            .iter()
+            .map(|(p, _)| u64::from_be_bytes(p[..8].try_into().unwrap()))
+            .collect();
+        assert_eq!(times, [11_000, 12_000]);
+    }
+
+    #[test]
+    fn fan_out_sends_each_datagram_to_every_destination() {
+        let cfg = config(Format::V2);
+        let mut clock = FakeClock::at_ms(0);
+        let mut sink = MemorySink::default();
+        let dests: Vec<SocketAddr> = ["192.168.1.255:12321", "10.255.255.255:12321"]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+        let mut sender = Sender::new(&cfg, &mut clock, &mut sink, dest());
+        sender.fan_out(dests.clone());
+        sender.step().unwrap();
+
+        assert_eq!(sink.sent.iter().map(|(_, d)| *d).collect::<Vec<_>>(), dests);
+        // Both copies are the same beacon
+        assert_eq!(sink.sent[0].0, sink.sent[1].0);
+    }
+
+    #[test]
+    fn dual_stack_sends_each_family_through_its_own_socket() {
+        assert!(parse_dual_stack("ff02::1").is_err(), "needs a zone");
+        assert!(parse_dual_stack("fd00::1").is_err());
+        assert!(parse_dual_stack("ff15::1234").is_ok());
+
+        let rx4 = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let rx6 = UdpSocket::bind("[::1]:0").unwrap();
+        let dests = [rx4.local_addr().unwrap(), rx6.local_addr().unwrap()];
+        let mut outlet = Outlet::DualStack {
+            v4: bind_for(dests[0]).unwrap(),
+            v6: bind_for(dests[1]).unwrap(),
+        };
+        let msgs = [(&b"v6"[..], dests[1]), (&b"v4"[..], dests[0])];
+        let sent = outlet.send_batch(&msgs);
+        assert_eq!(sent.len(), 2);
+        assert!(sent.iter().all(|r| matches!(r, Ok(2))), "{sent:?}");
+
+        let mut buf = [0u8; 8];
+        for (rx, want) in [(&rx4, b"v4"), (&rx6, b"v6")] {
+            rx.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+            let n = rx.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..n], want);
+        }
+    }
+
+    #[test]
+    fn scheduler_ticks_each_destination_on_its_own_interval() {
+        let fast = SendConfig {
+            interval: Duration::from_millis(100),
+            ..config(Format::V2)
+        };
+        let slow = SendConfig {
+            interval: Duration::from_secs(1),
+            ..config(Format::V2)
+        };
+        let clock = RefCell::new(FakeClock::at_ms(1_700_000_000_000));
+        let (mut a, mut b) = (MemorySink::default(), MemorySink::default());
+        let other: SocketAddr = "[ff02::1%1]:12321".parse().unwrap();
+        let mut scheduler = Scheduler::new(vec![
+            Sender::new(&fast, &clock, &mut a, dest()),
+            Sender::new(&slow, &clock, &mut b, other),
+        ]);
+        while clock.borrow().elapsed < Duration::from_secs(1) {
+            scheduler.step().unwrap();
+        }
+        // The fast one ticked at 0, 100, ..., 1000 ms, the slow one once;
+        // each numbers its own datagrams
+        let seqs: Vec<u32> = scheduler.senders().iter().map(|s| s.seq()).collect();
+        assert_eq!(seqs, [11, 1]);
+        drop(scheduler);
+        assert_eq!(a.sent.len(), 11);
+        assert_eq!(b.sent[0].1, other);
+        assert_eq!(payload::decode(&b.sent[0].0).unwrap().packet.seq, Some(0));
+
+        let d = Dest::parse("10.0.0.255:12321@100ms").unwrap();
+        assert_eq!((d.port, d.interval), (12321, Duration::from_millis(100)));
+        let d = Dest::parse("ff02::1%1:9@1.5s").unwrap();
+        assert_eq!(d.interval, Duration::from_millis(1500));
+        assert!(matches!(d.target, Target::Scoped(_, 1, None)));
+        for bad in [
+            "10.0.0.255:12321",
+            "10.0.0.255:12321@100",
+            "10.0.0.255:0@1s",
+            "h:1@0.1us",
+        ] {
+            assert!(Dest::parse(bad).is_err(), "{bad}");
+        }
+        assert_eq!(parse_interval("250us"), Ok(Duration::from_micros(250)));
+        assert_eq!(parse_interval("0.5ms"), Ok(Duration::from_micros(500)));
+        assert_eq!(parse_interval("0.001ms"), Ok(Duration::from_micros(1)));
+        assert!(parse_interval("0s").is_err());
+    }
+
+    #[test]
+    fn unspaced_burst_is_one_batch_per_tick() {
+        let mut cfg = config(Format::V2);
+        cfg.burst = 3;
+        let mut clock = FakeClock::at_ms(0);
+        let mut sink = MemorySink {
+            fail_next: 1,
+            ..MemorySink::default()
+        };
+        let dests: Vec<SocketAddr> = ["192.168.1.255:12321", "10.255.255.255:12321"]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+        let mut sender = Sender::new(&cfg, &mut clock, &mut sink, dest());
+        sender.fan_out(dests.clone());
+        sender.step().unwrap();
+        sender.step().unwrap();
+
+        assert_eq!(sink.batches, [6, 6]);
+        // A failure inside the batch only costs that datagram
+        assert_eq!(sink.sent.len(), 11);
+        assert_eq!(sink.sent[0].1, dests[1]);
+        assert_eq!(
+            sink.sent[..5]
+                .iter()
+                .map(|(p, _)| payload::decode(p).unwrap().packet.seq)
+                .collect::<Vec<_>>(),
+            [0, 1, 1, 2, 2].map(Some)
+        );
+    }
+
+    #[test]
+    fn rate_holds_despite_coarse_sleeps() {
+        let mut cfg = config(Format::V2);
+        cfg.rate = Some(5_000.0);
+        let mut clock = FakeClock::at_ms(0);
+        clock.overshoot = Duration::from_micros(900);
+        let mut sink = MemorySink::default();
+        let mut sender = Sender::new(&cfg, &mut clock, &mut sink, dest());
+        while sender.instant() - sender.clock.base < Duration::from_secs(2) {
+            sender.step().unwrap();
+        }
+
+        // Every 200 µs sleep takes over a millisecond, yet 2 s of sending
+        // still comes out at 5000 pps
+        let sent = sink.sent.len() as i64;
+        assert!((sent - 10_000).abs() <= 5, "sent {sent}");
+    }
+
+    #[test]
+    fn late_and_missed_ticks_are_counted() {
+        let cfg = SendConfig {
+            max_lateness: Some(Duration::from_millis(1)),
+            ..config(Format::V2)
+        };
+        let mut clock = FakeClock::at_ms(0);
+        clock.overshoot = Duration::from_millis(3);
+        let mut sink = MemorySink::default();
+        let mut sender = Sender::new(&cfg, &mut clock, &mut sink, dest());
+        for _ in 0..4 {
+            sender.step().unwrap();
+        }
+        // The first tick has no deadline; each later one overslept by 3 ms
+        let l = sender.lateness();
+        assert_eq!((l.ticks, l.late, l.missed, l.max_us), (3, 3, 0, 3_000));
+
+        // Stalled for a second: late by that much, then three ticks skipped
+        sender.clock.elapsed += Duration::from_secs(1);
+        sender.step().unwrap();
+        let l = sender.lateness();
+        assert_eq!((l.late, l.missed, l.max_us), (4, 3, 1_003_000));
+    }
+
+    #[test]
+    fn clock_before_epoch_is_fatal() {
+        let cfg = config(Format::Legacy);
+        let mut clock = FakeClock::at_ms(0);
+        clock.wall = UNIX_EPOCH - Duration::from_secs(1);
+        let mut sink = MemorySink::default();
+        let mut sender = Sender::new(&cfg, &mut clock, &mut sink, dest());
+
+        assert!(sender.send_once().is_err());
+        assert!(sink.sent.is_empty());
+    }
+
+    #[test]
+    fn once_succeeds_only_if_sent_everywhere() {
+        let cfg = config(Format::V2);
+        let mut clock = FakeClock::at_ms(1_000);
+        let mut sink = MemorySink::default();
+        let mut sender = Sender::new(&cfg, &mut clock, &mut sink, dest());
+        assert_eq!(send_single(&mut sender).unwrap(), 0);
+
+        sink.fail_next = 1;
+        let mut sender = Sender::new(&cfg, &mut clock, &mut sink, dest());
+        assert_eq!(send_single(&mut sender).unwrap_err().code, 1);
+
+        let mut cfg = config(Format::V2);
+        cfg.hooks = Some(Arc::new(Hooks::new().on_before_send(|_, _| Verdict::Veto)));
+        let mut sender = Sender::new(&cfg, &mut clock, &mut sink, dest());
+        assert!(send_single(&mut sender).is_err());
+        assert_eq!(sink.sent.len(), 1);
+    }
+}
@@ -0,0 +1,356 @@
+//! Per-sender tracking for receiver mode.
+//!
+//! Every sender heard is kept in a table keyed by source address and
+//! beacon group, with when it was last heard, its sequence numbers and
+//! offsets. On a subnet with many broadcasters the table shows which one
+//! went quiet or is losing packets, which a flat packet log hides.
+//!
+//! Sequence numbers are checked against a window of the last
+//! [`SEQ_WINDOW`] seen, so that a datagram arriving late fills the gap it
+//! left instead of counting as lost, and a second copy of one is told
+//! apart from a late first one. A number further behind than the window
+//! is taken as the sender having restarted.
+//!
+//! Each sender's arrival jitter is estimated as RTP receivers do (RFC 3550,
+//! section 6.4.1): the difference in transit time between consecutive
+//! datagrams, smoothed with a gain of 1/16. A perfectly regular stream has
+//! none, whatever its offset, so it measures delivery regularity rather
+//! than clock agreement.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use crate::aggregate::Row;
+use crate::units;
+
+/// How far behind the highest sequence number a datagram may arrive and
+/// still count as reordered rather than a restart.
+pub const SEQ_WINDOW: u32 = 64;
+
+/// What a sequence number says about the stream, if anything unusual.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeqEvent {
+    /// Sequence numbers `first..=last` were skipped.
+    Gap { first: u32, last: u32 },
+    /// It arrived `depth` numbers behind the highest one seen.
+    Reordered { depth: u32 },
+    /// It was already received.
+    Duplicate,
+    /// It was too far behind to be late: the sender started over.
+    Restart,
+}
+
+impl SeqEvent {
+    /// Sequence numbers a gap skipped; 0 for anything else.
+    pub fn lost(&self) -> u64 {
+        match *self {
+            SeqEvent::Gap { first, last } => u64::from(last.wrapping_sub(first)) + 1,
+            _ => 0,
+        }
+    }
+}
+
+/// What is known about one sender.
+struct Entry {
+    last_seen: Instant,
+    last_seq: Option<u32>,
+    /// Bit `i` is set if `last_seq - i` was received.
+    seen: u64,
+    received: u64,
+    /// Sequence numbers skipped and not (yet) received late.
+    lost: u64,
+    reordered: u64,
+    /// Furthest behind the highest sequence number a datagram arrived.
+    reorder_depth: u32,
+    duplicates: u64,
+    offset_min_ms: i64,
+    offset_max_ms: i64,
+    /// Wide enough that no run of offsets overflows it.
+    offset_sum_ms: i128,
+    /// Receive time less send time of the last datagram, in microseconds.
+    last_transit_us: Option<i64>,
+    /// Smoothed interarrival jitter, in microseconds.
+    jitter_us: f64,
+}
+
+impl Entry {
+    fn sequence(&mut self, seq: u32) -> Option<SeqEvent> {
+        let Some(last) = self.last_seq else {
+            self.last_seq = Some(seq);
+            self.seen = 1;
+            return None;
+        };
+        let ahead = seq.wrapping_sub(last);
+        if ahead == 0 {
+            self.duplicates += 1;
+            return Some(SeqEvent::Duplicate);
+        }
+        if ahead < u32::MAX / 2 {
+            self.last_seq = Some(seq);
+            self.seen = self.seen.checked_shl(ahead).unwrap_or(0) | 1;
+            self.lost += u64::from(ahead - 1);
+            return (ahead > 1).then(|| SeqEvent::Gap {
+                first: last.wrapping_add(1),
+                last: seq.wrapping_sub(1),
+            });
+        }
+        let depth = last.wrapping_sub(seq);
+        if depth >= SEQ_WINDOW {
+            self.last_seq = Some(seq);
+            self.seen = 1;
+            return Some(SeqEvent::Restart);
+        }
+        let bit = 1u64 << depth;
+        if self.seen & bit != 0 {
+            self.duplicates += 1;
+            return Some(SeqEvent::Duplicate);
+        }
+        // Counted as lost when the gap opened
+        self.seen |= bit;
+        self.lost = self.lost.saturating_sub(1);
+        self.reordered += 1;
+        self.reorder_depth = self.reorder_depth.max(depth);
+        Some(SeqEvent::Reordered { depth })
+    }
+
+    /// Updates the jitter estimate with one datagram's transit time.
+    fn transit(&mut self, transit_us: i64) {
+        if let Some(last) = self.last_transit_us {
+            let d = transit_us.abs_diff(last) as f64;
+            self.jitter_us += (d - self.jitter_us) / 16.0;
+        }
+        self.last_transit_us = Some(transit_us);
+    }
+
+    /// The mean of i64 offsets, which an i64 holds.
+    fn offset_mean_ms(&self) -> i64 {
+        (self.offset_sum_ms / i128::from(self.received.max(1))) as i64
+    }
+
+    /// Lost as a percentage of the datagrams that should have arrived.
+    fn loss_pct(&self) -> f64 {
+        match self.received + self.lost {
+            0 => 0.0,
+            n => self.lost as f64 * 100.0 / n as f64,
+        }
+    }
+}
+
+/// Every sender heard so far.
+#[derive(Default)]
+pub struct SenderTable {
+    entries: BTreeMap<(SocketAddr, Option<u16>), Entry>,
+}
+
+impl SenderTable {
+    /// Records an accepted timestamp from `src`, `transit_us` after it was
+    /// sent by the clock it carries, and returns what its sequence number
+    /// revealed.
+    pub fn observe(
+        &mut self,
+        src: SocketAddr,
+        group: Option<u16>,
+        seq: Option<u32>,
+        offset_ms: i64,
+        transit_us: i64,
+        now: Instant,
+    ) -> Option<SeqEvent> {
+        let e = self.entries.entry((src, group)).or_insert(Entry {
+            last_seen: now,
+            last_seq: None,
+            seen: 0,
+            received: 0,
+            lost: 0,
+            reordered: 0,
+            reorder_depth: 0,
+            duplicates: 0,
+            offset_min_ms: offset_ms,
+            offset_max_ms: offset_ms,
+            offset_sum_ms: 0,
+            last_transit_us: None,
+            jitter_us: 0.0,
+        });
+        e.last_seen = now;
+        e.received += 1;
+        e.offset_min_ms = e.offset_min_ms.min(offset_ms);
+        e.offset_max_ms = e.offset_max_ms.max(offset_ms);
+        e.offset_sum_ms += i128::from(offset_ms);
+        let event = seq.and_then(|seq| e.sequence(seq));
+        match event {
+            // The same datagram again says nothing about spacing
+            Some(SeqEvent::Duplicate) => {}
+            // Nor does a new run say anything against the old one's
+            Some(SeqEvent::Restart) => e.last_transit_us = Some(transit_us),
+            _ => e.transit(transit_us),
+        }
+        event
+    }
+
+    /// The smoothed interarrival jitter of `src`, in microseconds.
+    pub fn jitter_us(&self, src: SocketAddr, group: Option<u16>) -> Option<u64> {
+        self.entries
+            .get(&(src, group))
+            .map(|e| e.jitter_us.round() as u64)
+    }
+
+    /// Counts a copy of an already accepted datagram that was dropped
+    /// before [`Self::observe`], as the replay window does.
+    pub fn duplicate(&mut self, src: SocketAddr, group: Option<u16>) {
+        if let Some(e) = self.entries.get_mut(&(src, group)) {
+            e.duplicates += 1;
+        }
+    }
+
+    /// Notes a datagram dropped for being older than `--max-age-ms`: its
+    /// sequence number counts as received, so the drop isn't taken for
+    /// loss, but its offset isn't a sample.
+    pub fn stale(&mut self, src: SocketAddr, group: Option<u16>, seq: Option<u32>, now: Instant) {
+        if let Some(e) = self.entries.get_mut(&(src, group)) {
+            e.last_seen = now;
+            if let Some(seq) = seq {
+                e.sequence(seq);
+            }
+        }
+    }
+
+    /// One summary line per sender, least recently heard first.
+    pub fn lines(&self, now: Instant) -> Vec<String> {
+        let mut rows: Vec<_> = self.entries.iter().collect();
+        rows.sort_by_key(|(_, e)| e.last_seen);
+        rows.into_iter()
+            .map(|((src, group), e)| {
+                let group = group.map_or_else(|| "-".to_string(), |g| g.to_string());
+                let seq = e.last_seq.map_or_else(|| "-".to_string(), |s| s.to_string());
+                let ago = now.saturating_duration_since(e.last_seen);
+                format!(
+                    "Sender: source={src} group={group} {} last_seq={seq} received={} lost={} loss_pct={:.2} reordered={} reorder_depth={} duplicates={} {} {} {} {}",
+                    units::pair("last_seen_ms", units::millis(ago.as_millis() as i64)),
+                    e.received,
+                    e.lost,
+                    e.loss_pct(),
+                    e.reordered,
+                    e.reorder_depth,
+                    e.duplicates,
+                    units::pair("offset_min_ms", units::millis(e.offset_min_ms)),
+                    units::pair("offset_mean_ms", units::millis(e.offset_mean_ms())),
+                    units::pair("offset_max_ms", units::millis(e.offset_max_ms)),
+                    units::pair("jitter_us", units::micros(e.jitter_us.round() as u64)),
+                )
+            })
+            .collect()
+    }
+
+    /// Every sender as a row of a `--report-to` report.
+    pub fn rows(&self, now: Instant) -> Vec<Row> {
+        self.entries
+            .iter()
+            .map(|((src, group), e)| Row {
+                sender: format!(
+                    "{src}/{}",
+                    group.map_or_else(|| "-".to_string(), |g| g.to_string())
+                ),
+                received: e.received,
+                lost: e.lost,
+                offset_min_ms: e.offset_min_ms,
+                offset_mean_ms: e.offset_mean_ms(),
+                offset_max_ms: e.offset_max_ms,
+                last_seen_ms: now.saturating_duration_since(e.last_seen).as_millis() as u64,
+            })
+            .collect()
+    }
+
+    pub fn print(&self, now: Instant) {
+        for line in self.lines(now) {
+            println!("{line}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn tracks_loss_and_offsets_per_sender() {
+        let t0 = Instant::now();
+        let a: SocketAddr = "10.0.0.5:4000".parse().unwrap();
+        let b: SocketAddr = "10.0.0.6:4000".parse().unwrap();
+        let mut table = SenderTable::default();
+        table.observe(a, None, Some(1), 10, 10_000, t0);
+        table.observe(b, Some(3), Some(7), -4, -4_000, t0 + Duration::from_secs(1));
+        let gap = table.observe(a, None, Some(4), 20, 26_000, t0 + Duration::from_secs(2));
+        assert_eq!(gap, Some(SeqEvent::Gap { first: 2, last: 3 }));
+        // Late, so no longer lost, and not a new last sequence
+        let late = table.observe(a, None, Some(3), 30, 26_000, t0 + Duration::from_secs(3));
+        assert_eq!(late, Some(SeqEvent::Reordered { depth: 1 }));
+
+        let lines = table.lines(t0 + Duration::from_secs(5));
+        assert_eq!(
+            lines,
+            [
+                "Sender: source=10.0.0.6:4000 group=3 last_seen=4.00s last_seq=7 received=1 lost=0 loss_pct=0.00 reordered=0 reorder_depth=0 duplicates=0 offset_min=-4ms offset_mean=-4ms offset_max=-4ms jitter=0µs",
+                "Sender: source=10.0.0.5:4000 group=- last_seen=2.00s last_seq=4 received=3 lost=1 loss_pct=25.00 reordered=1 reorder_depth=1 duplicates=0 offset_min=10ms offset_mean=20ms offset_max=30ms jitter=938µs",
+            ]
+        );
+        // 16 ms late, then back on time: 16/16, then 15/16 of that
+        assert_eq!(table.jitter_us(a, None), Some(938));
+
+        // Offsets at the ends of the range don't overflow the mean
+        let c: SocketAddr = "10.0.0.7:4000".parse().unwrap();
+        table.observe(c, None, Some(1), i64::MAX, 0, t0);
+        table.observe(c, None, Some(2), i64::MAX, i64::MIN, t0);
+        let rows = table.rows(t0);
+        let row = rows
+            .iter()
+            .find(|r| r.sender.starts_with("10.0.0.7:"))
+            .unwrap();
+        assert_eq!(row.offset_mean_ms, i64::MAX);
+    }
+
+    #[test]
+    fn tells_duplicates_from_late_arrivals_and_restarts() {
+        let t0 = Instant::now();
+        let a: SocketAddr = "10.0.0.5:4000".parse().unwrap();
+        let mut table = SenderTable::default();
+        let mut seq = |n| table.observe(a, None, Some(n), 0, 0, t0);
+        assert_eq!(seq(u32::MAX), None);
+        // Across the wrap
+        assert_eq!(seq(2), Some(SeqEvent::Gap { first: 0, last: 1 }));
+        assert_eq!(seq(2), Some(SeqEvent::Duplicate));
+        assert_eq!(seq(0), Some(SeqEvent::Reordered { depth: 2 }));
+        assert_eq!(seq(0), Some(SeqEvent::Duplicate));
+        assert_eq!(seq(100), Some(SeqEvent::Gap { first: 3, last: 99 }));
+        assert_eq!(seq(100 - SEQ_WINDOW), Some(SeqEvent::Restart));
+        assert_eq!(seq(37), None);
+        table.duplicate(a, None);
+
+        let line = &table.lines(t0)[0];
+        assert!(
+            line.contains(
+                "received=8 lost=98 loss_pct=92.45 reordered=1 reorder_depth=2 duplicates=3"
+            ),
+            "{line}"
+        );
+        assert_eq!(SeqEvent::Gap { first: 3, last: 99 }.lost(), 97);
+    }
+
+    #[test]
+    fn stale_datagrams_are_neither_samples_nor_losses() {
+        let t0 = Instant::now();
+        let a: SocketAddr = "10.0.0.5:4000".parse().unwrap();
+        let mut table = SenderTable::default();
+        table.stale(a, None, Some(0), t0);
+        assert_eq!(table.observe(a, None, Some(1), 2, 2_000, t0), None);
+        table.stale(a, None, Some(2), t0);
+        assert_eq!(table.observe(a, None, Some(3), 4, 4_000, t0), None);
+
+        let line = &table.lines(t0)[0];
+        assert!(
+            line.contains("received=2 lost=0 loss_pct=0.00")
+                && line.contains("offset_min=2ms offset_mean=3ms offset_max=4ms"),
+            "{line}"
+        );
+    }
+}
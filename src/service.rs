@@ -0,0 +1,579 @@
+//! Running under the platform's service manager (`--service`).
+//!
+//! On Windows, `--service install` registers the command line it is given,
+//! with `--service install` replaced by `--service run`, as a service that
+//! starts with the system; `--service run` is then how the service control
+//! manager starts it. The mode runs on the thread the manager calls, and
+//! a stop request or system shutdown ends the process. There is no
+//! console, so output is only kept with `--log-file`.
+//!
+//! On macOS, `install` writes a launchd property list for the command line
+//! to `/Library/LaunchDaemons` and loads it, and `--service plist` prints
+//! one without installing it, to edit or install by hand. launchd starts
+//! the process in the foreground and restarts it if it exits, so `run`
+//! needs nothing more than running the mode. Elsewhere only `plist` and
+//! `run` are available; systemd units need nothing from the process.
+
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use crate::exit::{self, Failure};
+
+/// Default for `--service-name`.
+pub const DEFAULT_NAME: &str = "udp_bcast_ts";
+
+/// What `--service` does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Register the command line as a service.
+    Install,
+    /// Stop and remove the service.
+    Uninstall,
+    /// Run as the service manager expects.
+    Run,
+    /// Print a launchd property list for the command line.
+    Plist,
+}
+
+impl Action {
+    /// Parses a `--service` value.
+    pub fn parse(s: &str) -> Result<Action, String> {
+        match s {
+            "install" => Ok(Action::Install),
+            "uninstall" => Ok(Action::Uninstall),
+            "run" => Ok(Action::Run),
+            "plist" => Ok(Action::Plist),
+            _ => Err("expected install, uninstall, run or plist".to_string()),
+        }
+    }
+}
+
+/// Checks a `--service-name` value, which launchd also uses as the label.
+pub fn parse_name(s: &str) -> Result<String, String> {
+    let valid = s
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c));
+    if s.is_empty() || !valid {
+        return Err("expected letters, digits, '.', '_' and '-'".to_string());
+    }
+    Ok(s.to_string())
+}
+
+/// Settings for `--service` other than `run`.
+pub struct ServiceConfig {
+    pub action: Action,
+    pub name: String,
+    /// The command line after the program, as the service should run it.
+    pub args: Vec<String>,
+    /// Where the mode writes its output, if anywhere.
+    pub log_file: Option<PathBuf>,
+}
+
+/// The arguments after the program in `args`, with any `--service` and
+/// `--service-name` left out, behind `--service run --service-name <name>`.
+pub fn run_args(args: &[String], name: &str) -> Vec<String> {
+    let mut out = vec![
+        "--service".to_string(),
+        "run".to_string(),
+        "--service-name".to_string(),
+        name.to_string(),
+    ];
+    let mut it = args.iter().skip(1);
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--service" | "--service-name" => {
+                it.next();
+            }
+            a if a.starts_with("--service=") || a.starts_with("--service-name=") => {}
+            _ => out.push(arg.clone()),
+        }
+    }
+    out
+}
+
+/// Installs, uninstalls or prints the service.
+pub fn manage(cfg: &ServiceConfig) -> ExitCode {
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => return exit::fail(Failure::io(&e, format!("Failed to find this program: {e}"))),
+    };
+    let result = match cfg.action {
+        Action::Plist => {
+            print!("{}", plist(cfg, &exe));
+            Ok(())
+        }
+        Action::Install => imp::install(cfg, &exe),
+        Action::Uninstall => imp::uninstall(cfg),
+        Action::Run => unreachable!("run isn't managed"),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => exit::fail(e),
+    }
+}
+
+/// Runs `body` as the service named `name`.
+pub fn run<F>(name: &str, body: F) -> ExitCode
+where
+    F: FnOnce() -> ExitCode + Send + 'static,
+{
+    imp::run(name, body)
+}
+
+/// A launchd property list that runs the command line as a daemon.
+pub fn plist(cfg: &ServiceConfig, exe: &Path) -> String {
+    let mut out = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \
+         \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n\
+         <plist version=\"1.0\">\n<dict>\n",
+    );
+    let _ = writeln!(
+        out,
+        "  <key>Label</key>\n  <string>{}</string>",
+        xml_escape(&cfg.name)
+    );
+    out.push_str("  <key>ProgramArguments</key>\n  <array>\n");
+    let exe = exe.to_string_lossy();
+    for arg in std::iter::once(exe.as_ref()).chain(cfg.args.iter().map(String::as_str)) {
+        let _ = writeln!(out, "    <string>{}</string>", xml_escape(arg));
+    }
+    out.push_str("  </array>\n");
+    out.push_str("  <key>RunAtLoad</key>\n  <true/>\n  <key>KeepAlive</key>\n  <true/>\n");
+    // launchd keeps the output only if told where
+    if cfg.log_file.is_none() {
+        let log = format!("/var/log/{}.log", cfg.name);
+        for key in ["StandardOutPath", "StandardErrorPath"] {
+            let _ = writeln!(
+                out,
+                "  <key>{key}</key>\n  <string>{}</string>",
+                xml_escape(&log)
+            );
+        }
+    }
+    out.push_str("</dict>\n</plist>\n");
+    out
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Quotes `arg` so that `CommandLineToArgvW`, and so the Rust runtime,
+/// reads it back as one argument.
+#[cfg_attr(not(windows), allow(dead_code))]
+fn quote_windows(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains([' ', '\t', '\n', '"']) {
+        return arg.to_string();
+    }
+    let mut out = String::from('"');
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        match c {
+            '\\' => backslashes += 1,
+            '"' => {
+                // Each backslash before a quote is doubled, and the quote escaped
+                out.extend(std::iter::repeat_n('\\', backslashes * 2 + 1));
+                out.push('"');
+                backslashes = 0;
+            }
+            c => {
+                out.extend(std::iter::repeat_n('\\', backslashes));
+                out.push(c);
+                backslashes = 0;
+            }
+        }
+    }
+    // So the closing quote isn't escaped
+    out.extend(std::iter::repeat_n('\\', backslashes * 2));
+    out.push('"');
+    out
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::ffi::c_void;
+    use std::path::Path;
+    use std::process::ExitCode;
+    use std::ptr;
+    use std::sync::atomic::{AtomicPtr, Ordering};
+    use std::sync::{Mutex, OnceLock};
+
+    use windows_sys::Win32::Foundation::{
+        GetLastError, ERROR_CALL_NOT_IMPLEMENTED, ERROR_FAILED_SERVICE_CONTROLLER_CONNECT,
+        ERROR_SERVICE_SPECIFIC_ERROR, NO_ERROR,
+    };
+    use windows_sys::Win32::System::Services::{
+        CloseServiceHandle, ControlService, CreateServiceW, DeleteService, OpenSCManagerW,
+        OpenServiceW, RegisterServiceCtrlHandlerExW, SetServiceStatus, StartServiceCtrlDispatcherW,
+        SC_MANAGER_CREATE_SERVICE, SERVICE_ACCEPT_SHUTDOWN, SERVICE_ACCEPT_STOP,
+        SERVICE_ALL_ACCESS, SERVICE_AUTO_START, SERVICE_CONTROL_INTERROGATE,
+        SERVICE_CONTROL_SHUTDOWN, SERVICE_CONTROL_STOP, SERVICE_ERROR_NORMAL, SERVICE_RUNNING,
+        SERVICE_STATUS, SERVICE_STATUS_CURRENT_STATE, SERVICE_STOPPED, SERVICE_STOP_PENDING,
+        SERVICE_TABLE_ENTRYW, SERVICE_WIN32_OWN_PROCESS,
+    };
+
+    use super::{quote_windows, ServiceConfig};
+    use crate::exit::Failure;
+    use crate::log;
+
+    type Body = Box<dyn FnOnce() -> ExitCode + Send>;
+
+    /// What the service's thread runs, and the code it ended with.
+    static BODY: Mutex<Option<Body>> = Mutex::new(None);
+    static CODE: Mutex<Option<ExitCode>> = Mutex::new(None);
+    static NAME: OnceLock<Vec<u16>> = OnceLock::new();
+    static STATUS: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
+
+    fn wide(s: &str) -> Vec<u16> {
+        s.encode_utf16().chain(Some(0)).collect()
+    }
+
+    fn last_error(what: &str) -> Failure {
+        let e = std::io::Error::last_os_error();
+        Failure::io(&e, format!("{what}: {e}"))
+    }
+
+    /// A service control manager handle, closed when dropped.
+    struct Handle(*mut c_void);
+
+    impl Drop for Handle {
+        fn drop(&mut self) {
+            // SAFETY: the handle came from OpenSCManagerW, OpenServiceW or
+            // CreateServiceW and is closed once.
+            unsafe { CloseServiceHandle(self.0) };
+        }
+    }
+
+    fn manager(access: u32) -> Result<Handle, Failure> {
+        // SAFETY: null names select the local machine's active database.
+        let scm = unsafe { OpenSCManagerW(ptr::null(), ptr::null(), access) };
+        if scm.is_null() {
+            return Err(last_error(
+                "Failed to open the service control manager (run as Administrator)",
+            ));
+        }
+        Ok(Handle(scm))
+    }
+
+    pub fn install(cfg: &ServiceConfig, exe: &Path) -> Result<(), Failure> {
+        let scm = manager(SC_MANAGER_CREATE_SERVICE)?;
+        let command: Vec<String> = std::iter::once(exe.to_string_lossy().into_owned())
+            .chain(cfg.args.iter().cloned())
+            .map(|a| quote_windows(&a))
+            .collect();
+        let (name, command) = (wide(&cfg.name), wide(&command.join(" ")));
+        // SAFETY: every string is NUL-terminated and outlives the call;
+        // null pointers select the defaults (no group, no dependencies,
+        // LocalSystem).
+        let service = unsafe {
+            CreateServiceW(
+                scm.0,
+                name.as_ptr(),
+                name.as_ptr(),
+                SERVICE_ALL_ACCESS,
+                SERVICE_WIN32_OWN_PROCESS,
+                SERVICE_AUTO_START,
+                SERVICE_ERROR_NORMAL,
+                command.as_ptr(),
+                ptr::null(),
+                ptr::null_mut(),
+                ptr::null(),
+                ptr::null(),
+                ptr::null(),
+            )
+        };
+        if service.is_null() {
+            return Err(last_error(&format!(
+                "Failed to create service {}",
+                cfg.name
+            )));
+        }
+        drop(Handle(service));
+        log::info!(
+            "Installed service {}; start it with: sc start {}",
+            cfg.name,
+            cfg.name
+        );
+        Ok(())
+    }
+
+    pub fn uninstall(cfg: &ServiceConfig) -> Result<(), Failure> {
+        let scm = manager(SC_MANAGER_CREATE_SERVICE)?;
+        let name = wide(&cfg.name);
+        // SAFETY: `name` is NUL-terminated.
+        let service = unsafe { OpenServiceW(scm.0, name.as_ptr(), SERVICE_ALL_ACCESS) };
+        if service.is_null() {
+            return Err(last_error(&format!("Failed to open service {}", cfg.name)));
+        }
+        let service = Handle(service);
+        // SAFETY: a zeroed SERVICE_STATUS is valid to write into. Failing
+        // to stop one that isn't running is fine.
+        let mut status: SERVICE_STATUS = unsafe { std::mem::zeroed() };
+        unsafe { ControlService(service.0, SERVICE_CONTROL_STOP, &mut status) };
+        // SAFETY: `service` was opened with DELETE access.
+        if unsafe { DeleteService(service.0) } == 0 {
+            return Err(last_error(&format!(
+                "Failed to delete service {}",
+                cfg.name
+            )));
+        }
+        log::info!("Uninstalled service {}", cfg.name);
+        Ok(())
+    }
+
+    fn set_status(state: SERVICE_STATUS_CURRENT_STATE, code: Option<&ExitCode>) {
+        let failed = code.is_some_and(|c| *c != ExitCode::SUCCESS);
+        let status = SERVICE_STATUS {
+            dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+            dwCurrentState: state,
+            dwControlsAccepted: if state == SERVICE_RUNNING {
+                SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN
+            } else {
+                0
+            },
+            dwWin32ExitCode: if failed {
+                ERROR_SERVICE_SPECIFIC_ERROR
+            } else {
+                NO_ERROR
+            },
+            dwServiceSpecificExitCode: u32::from(failed),
+            dwCheckPoint: 0,
+            dwWaitHint: if state == SERVICE_STOP_PENDING {
+                2000
+            } else {
+                0
+            },
+        };
+        // SAFETY: the handle is the one RegisterServiceCtrlHandlerExW
+        // returned, and the status is a valid struct.
+        unsafe { SetServiceStatus(STATUS.load(Ordering::Acquire), &status) };
+    }
+
+    unsafe extern "system" fn handler(
+        control: u32,
+        _event: u32,
+        _data: *mut c_void,
+        _context: *mut c_void,
+    ) -> u32 {
+        match control {
+            SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+                set_status(SERVICE_STOP_PENDING, None);
+                log::info!("Stopping: the service control manager asked");
+                set_status(SERVICE_STOPPED, Some(&ExitCode::SUCCESS));
+                std::process::exit(0);
+            }
+            SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+            _ => ERROR_CALL_NOT_IMPLEMENTED,
+        }
+    }
+
+    unsafe extern "system" fn service_main(_argc: u32, _argv: *mut *mut u16) {
+        let name = NAME.get().expect("set before dispatching");
+        // SAFETY: `name` is NUL-terminated and static; the handler lives
+        // as long as the process.
+        let status =
+            unsafe { RegisterServiceCtrlHandlerExW(name.as_ptr(), Some(handler), ptr::null()) };
+        if status.is_null() {
+            log::error!(
+                "Failed to register the service handler: {}",
+                std::io::Error::last_os_error()
+            );
+            return;
+        }
+        STATUS.store(status, Ordering::Release);
+        set_status(SERVICE_RUNNING, None);
+        let body = BODY.lock().unwrap_or_else(|e| e.into_inner()).take();
+        let code = body.map_or(ExitCode::FAILURE, |body| body());
+        set_status(SERVICE_STOPPED, Some(&code));
+        *CODE.lock().unwrap_or_else(|e| e.into_inner()) = Some(code);
+    }
+
+    pub fn run<F>(name: &str, body: F) -> ExitCode
+    where
+        F: FnOnce() -> ExitCode + Send + 'static,
+    {
+        let name = NAME.get_or_init(|| wide(name));
+        *BODY.lock().unwrap_or_else(|e| e.into_inner()) = Some(Box::new(body));
+        let table = [
+            SERVICE_TABLE_ENTRYW {
+                lpServiceName: name.as_ptr() as *mut u16,
+                lpServiceProc: Some(service_main),
+            },
+            SERVICE_TABLE_ENTRYW {
+                lpServiceName: ptr::null_mut(),
+                lpServiceProc: None,
+            },
+        ];
+        // SAFETY: the table ends with a null entry, and its name is static.
+        // This blocks until the service has stopped.
+        if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+            // SAFETY: plain GetLastError call.
+            let msg = if unsafe { GetLastError() } == ERROR_FAILED_SERVICE_CONTROLLER_CONNECT {
+                "--service run is for the service control manager; use --service install, \
+                 then sc start"
+                    .to_string()
+            } else {
+                format!(
+                    "Failed to start the service dispatcher: {}",
+                    std::io::Error::last_os_error()
+                )
+            };
+            return crate::exit::fail(Failure::from(msg));
+        }
+        CODE.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+            .unwrap_or(ExitCode::FAILURE)
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod imp {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::process::{Command, ExitCode};
+
+    use super::{plist, ServiceConfig};
+    use crate::exit::Failure;
+    use crate::log;
+
+    fn plist_path(name: &str) -> PathBuf {
+        Path::new("/Library/LaunchDaemons").join(format!("{name}.plist"))
+    }
+
+    fn launchctl(args: &[&str]) -> Result<(), Failure> {
+        let status = Command::new("launchctl")
+            .args(args)
+            .status()
+            .map_err(|e| Failure::io(&e, format!("Failed to run launchctl: {e}")))?;
+        if !status.success() {
+            return Err(format!("launchctl {} failed: {status}", args.join(" ")).into());
+        }
+        Ok(())
+    }
+
+    pub fn install(cfg: &ServiceConfig, exe: &Path) -> Result<(), Failure> {
+        let path = plist_path(&cfg.name);
+        fs::write(&path, plist(cfg, exe)).map_err(|e| {
+            Failure::io(
+                &e,
+                format!("Failed to write {} (run as root): {e}", path.display()),
+            )
+        })?;
+        launchctl(&["load", "-w", &path.to_string_lossy()])?;
+        log::info!("Installed and started {} from {}", cfg.name, path.display());
+        Ok(())
+    }
+
+    pub fn uninstall(cfg: &ServiceConfig) -> Result<(), Failure> {
+        let path = plist_path(&cfg.name);
+        if !path.exists() {
+            return Err(
+                format!("No service {}: {} doesn't exist", cfg.name, path.display()).into(),
+            );
+        }
+        launchctl(&["unload", "-w", &path.to_string_lossy()])?;
+        fs::remove_file(&path)
+            .map_err(|e| Failure::io(&e, format!("Failed to remove {}: {e}", path.display())))?;
+        log::info!("Uninstalled {}", cfg.name);
+        Ok(())
+    }
+
+    pub fn run<F: FnOnce() -> ExitCode>(_name: &str, body: F) -> ExitCode {
+        // launchd runs the process in the foreground as it is
+        body()
+    }
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+mod imp {
+    use std::path::Path;
+    use std::process::ExitCode;
+
+    use super::ServiceConfig;
+    use crate::exit::Failure;
+
+    fn unsupported() -> Failure {
+        "--service install and uninstall are only supported on Windows and macOS; \
+         use --service plist or a systemd unit"
+            .to_string()
+            .into()
+    }
+
+    pub fn install(_cfg: &ServiceConfig, _exe: &Path) -> Result<(), Failure> {
+        Err(unsupported())
+    }
+
+    pub fn uninstall(_cfg: &ServiceConfig) -> Result<(), Failure> {
+        Err(unsupported())
+    }
+
+    pub fn run<F: FnOnce() -> ExitCode>(_name: &str, body: F) -> ExitCode {
+        body()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrites_the_command_line_for_the_service_manager() {
+        let args: Vec<String> = [
+            "udp_bcast_ts",
+            "--service",
+            "install",
+            "send",
+            "--service-name=ts-a",
+            "--addr",
+            "10.0.0.255",
+            "--field",
+            "site=a&b",
+        ]
+        .map(String::from)
+        .into();
+        let args = run_args(&args, "ts-a");
+        assert_eq!(
+            args,
+            [
+                "--service",
+                "run",
+                "--service-name",
+                "ts-a",
+                "send",
+                "--addr",
+                "10.0.0.255",
+                "--field",
+                "site=a&b"
+            ]
+        );
+
+        let cfg = ServiceConfig {
+            action: Action::Plist,
+            name: "ts-a".into(),
+            args,
+            log_file: None,
+        };
+        let plist = plist(&cfg, Path::new("/usr/local/bin/udp_bcast_ts"));
+        assert!(plist.contains("<key>Label</key>\n  <string>ts-a</string>"));
+        assert!(plist.contains(
+            "    <string>/usr/local/bin/udp_bcast_ts</string>\n    <string>--service</string>"
+        ));
+        assert!(plist.contains("<string>site=a&amp;b</string>"));
+        assert!(plist.contains("<string>/var/log/ts-a.log</string>"));
+
+        assert_eq!(quote_windows(r"C:\bin\ts.exe"), r"C:\bin\ts.exe");
+        assert_eq!(
+            quote_windows(r"C:\Program Files\ts\"),
+            r#""C:\Program Files\ts\\""#
+        );
+        assert_eq!(quote_windows(r#"a "b" \"c"#), r#""a \"b\" \\\"c""#);
+        assert_eq!(quote_windows(""), r#""""#);
+        assert!(parse_name("ts a").is_err());
+    }
+}
@@ -0,0 +1,201 @@
+//! A bridge to the shared-memory reference clock driver of ntpd and chrony.
+//!
+//! Both daemons can read time samples from a System V shared memory
+//! segment (ntpd's type 28 "SHM" driver, chrony's `refclock SHM`). Each
+//! sample pairs a reference time, here the sender's timestamp, with the
+//! local time it was taken at. Writing every accepted broadcast into a
+//! segment lets a host without access to NTP servers discipline its clock
+//! to the broadcast, to within the network delay and the millisecond
+//! resolution of the timestamps.
+//!
+//! Segment `n` has the key `0x4e545030 + n`. Units 0 and 1 are created
+//! readable by root only, as ntpd expects; higher units by anyone. The
+//! layout and the count/valid handshake follow ntpd's `refclock_shm.c`.
+
+use std::io;
+use std::time::SystemTime;
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::io;
+    use std::mem;
+    use std::ptr::{self, addr_of_mut};
+    use std::sync::atomic::{fence, Ordering};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    const KEY_BASE: libc::key_t = 0x4e54_5030;
+
+    /// Timestamps have millisecond resolution: about 2^-10 s.
+    pub const PRECISION: libc::c_int = -10;
+
+    /// ntpd's `struct shmTime`.
+    #[repr(C)]
+    pub struct ShmTime {
+        pub mode: libc::c_int,
+        pub count: libc::c_int,
+        pub clock_sec: libc::time_t,
+        pub clock_usec: libc::c_int,
+        pub receive_sec: libc::time_t,
+        pub receive_usec: libc::c_int,
+        pub leap: libc::c_int,
+        pub precision: libc::c_int,
+        pub nsamples: libc::c_int,
+        pub valid: libc::c_int,
+        pub clock_nsec: libc::c_uint,
+        pub receive_nsec: libc::c_uint,
+        pub dummy: [libc::c_int; 8],
+    }
+
+    pub fn key(unit: u8) -> libc::key_t {
+        KEY_BASE + libc::key_t::from(unit)
+    }
+
+    pub struct Refclock {
+        shm: *mut ShmTime,
+    }
+
+    impl Refclock {
+        pub fn open(unit: u8) -> io::Result<Refclock> {
+            let perms = if unit <= 1 { 0o600 } else { 0o666 };
+            // SAFETY: plain shmget(2) call.
+            let id = unsafe {
+                libc::shmget(
+                    key(unit),
+                    mem::size_of::<ShmTime>(),
+                    libc::IPC_CREAT | perms,
+                )
+            };
+            if id < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: attaches the segment just looked up at an address the
+            // kernel picks; the result is checked below.
+            let shm = unsafe { libc::shmat(id, ptr::null(), 0) };
+            if shm as isize == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Refclock {
+                shm: shm as *mut ShmTime,
+            })
+        }
+
+        pub fn update(&mut self, reference: SystemTime, received: SystemTime) {
+            let split = |t: SystemTime| {
+                let d = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+                (d.as_secs() as libc::time_t, d.subsec_nanos())
+            };
+            let (clock_sec, clock_nsec) = split(reference);
+            let (receive_sec, receive_nsec) = split(received);
+            let p = self.shm;
+            // SAFETY: `p` points to an attached segment at least the size of
+            // ShmTime. Another process reads it concurrently, so every
+            // access is volatile, and the count bumps on either side of the
+            // sample let the reader discard one it caught half-written.
+            unsafe {
+                ptr::write_volatile(addr_of_mut!((*p).mode), 1);
+                ptr::write_volatile(addr_of_mut!((*p).valid), 0);
+                let count = ptr::read_volatile(addr_of_mut!((*p).count));
+                ptr::write_volatile(addr_of_mut!((*p).count), count.wrapping_add(1));
+                fence(Ordering::SeqCst);
+                ptr::write_volatile(addr_of_mut!((*p).clock_sec), clock_sec);
+                ptr::write_volatile(
+                    addr_of_mut!((*p).clock_usec),
+                    (clock_nsec / 1000) as libc::c_int,
+                );
+                ptr::write_volatile(addr_of_mut!((*p).clock_nsec), clock_nsec);
+                ptr::write_volatile(addr_of_mut!((*p).receive_sec), receive_sec);
+                ptr::write_volatile(
+                    addr_of_mut!((*p).receive_usec),
+                    (receive_nsec / 1000) as libc::c_int,
+                );
+                ptr::write_volatile(addr_of_mut!((*p).receive_nsec), receive_nsec);
+                ptr::write_volatile(addr_of_mut!((*p).leap), 0);
+                ptr::write_volatile(addr_of_mut!((*p).precision), PRECISION);
+                fence(Ordering::SeqCst);
+                ptr::write_volatile(addr_of_mut!((*p).count), count.wrapping_add(2));
+                ptr::write_volatile(addr_of_mut!((*p).valid), 1);
+            }
+        }
+    }
+
+    impl Drop for Refclock {
+        fn drop(&mut self) {
+            // The segment itself stays for the daemon reading it
+            // SAFETY: `shm` was returned by shmat and is detached once.
+            unsafe { libc::shmdt(self.shm as *const libc::c_void) };
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use std::io;
+    use std::time::SystemTime;
+
+    pub enum Refclock {}
+
+    impl Refclock {
+        pub fn open(_unit: u8) -> io::Result<Refclock> {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "NTP shared memory segments are only supported on Linux",
+            ))
+        }
+
+        pub fn update(&mut self, _reference: SystemTime, _received: SystemTime) {
+            match *self {}
+        }
+    }
+}
+
+/// One NTP shared memory segment, written with each accepted timestamp.
+pub struct Refclock(imp::Refclock);
+
+impl Refclock {
+    /// Attaches segment `unit`, creating it if the NTP daemon hasn't yet.
+    pub fn open(unit: u8) -> io::Result<Refclock> {
+        imp::Refclock::open(unit).map(Refclock)
+    }
+
+    /// Publishes one sample: the time was `reference` by the sender's
+    /// clock when the local clock read `received`.
+    pub fn update(&mut self, reference: SystemTime, received: SystemTime) {
+        self.0.update(reference, received)
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::ptr;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn publishes_samples_in_the_ntpd_layout() {
+        // A unit no daemon is likely to be using
+        let unit = 251;
+        let mut clock = Refclock::open(unit).unwrap();
+        let reference = UNIX_EPOCH + Duration::from_millis(1_700_000_000_250);
+        let received = reference + Duration::from_micros(1_500);
+        clock.update(reference, received);
+        clock.update(reference, received);
+
+        // SAFETY: test-only reads of the segment just written, then removal.
+        unsafe {
+            let id = libc::shmget(imp::key(unit), 0, 0);
+            assert!(id >= 0);
+            let shm = libc::shmat(id, ptr::null(), libc::SHM_RDONLY) as *const imp::ShmTime;
+            let s = ptr::read_volatile(shm);
+            libc::shmdt(shm as *const libc::c_void);
+            libc::shmctl(id, libc::IPC_RMID, ptr::null_mut());
+
+            assert_eq!((s.mode, s.valid, s.count), (1, 1, 4));
+            assert_eq!((s.clock_sec, s.clock_usec), (1_700_000_000, 250_000));
+            assert_eq!(
+                (s.receive_sec, s.receive_nsec),
+                (1_700_000_000, 251_500_000)
+            );
+            assert_eq!((s.leap, s.precision), (0, imp::PRECISION));
+        }
+    }
+}
@@ -0,0 +1,123 @@
+//! Sink mode: a population of lightweight in-process receivers that only
+//! count what arrives, for stress-testing a sender's fan-out and pacing.
+//!
+//! Instance `i` binds `port + i`; the standard library offers no
+//! SO_REUSEPORT, so sharding a single port isn't available.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::exit::{self, Failure};
+use crate::log;
+use crate::payload;
+use crate::units;
+
+/// Settings for sink mode.
+pub struct SinkConfig {
+    pub addr: Option<IpAddr>,
+    /// First port; instances use consecutive ports from here.
+    pub port: u16,
+    pub instances: u16,
+    pub stats_interval: Duration,
+}
+
+/// Counters shared between one receiver thread and the reporter.
+#[derive(Default)]
+struct Counters {
+    packets: AtomicU64,
+    bytes: AtomicU64,
+    malformed: AtomicU64,
+}
+
+fn receive(sock: UdpSocket, counters: Arc<Counters>) {
+    let mut buf = [0u8; 1500];
+    loop {
+        match sock.recv_from(&mut buf) {
+            Ok((len, _)) => {
+                counters.packets.fetch_add(1, Ordering::Relaxed);
+                counters.bytes.fetch_add(len as u64, Ordering::Relaxed);
+                if payload::decode(&buf[..len]).is_err() {
+                    counters.malformed.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            Err(e) => log::warn!("recv_from failed: {e}"),
+        }
+    }
+}
+
+/// Binds every instance, then reports aggregate receive rates forever.
+pub fn run(cfg: &SinkConfig) -> ExitCode {
+    let ip = cfg.addr.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+
+    if cfg.port as u32 + cfg.instances as u32 - 1 > u16::MAX as u32 {
+        return exit::fail(format!(
+            "{} instances starting at port {} exceed port 65535",
+            cfg.instances, cfg.port
+        ));
+    }
+
+    let mut all = Vec::with_capacity(cfg.instances as usize);
+    for i in 0..cfg.instances {
+        let bind_addr = SocketAddr::new(ip, cfg.port + i);
+        let sock = match UdpSocket::bind(bind_addr) {
+            Ok(s) => s,
+            Err(e) => {
+                return exit::fail(Failure::io(
+                    &e,
+                    format!("Failed to bind UDP socket on {bind_addr}: {e}"),
+                ))
+            }
+        };
+        let counters = Arc::new(Counters::default());
+        let c = Arc::clone(&counters);
+        thread::spawn(move || receive(sock, c));
+        all.push(counters);
+    }
+
+    log::info!(
+        "Sink: {} receiver(s) on ports {}-{}",
+        cfg.instances,
+        cfg.port,
+        cfg.port + cfg.instances - 1
+    );
+
+    let mut last = Instant::now();
+    let mut last_packets: u64 = 0;
+    let mut last_bytes: u64 = 0;
+    loop {
+        thread::sleep(cfg.stats_interval);
+
+        let now = Instant::now();
+        let secs = now.duration_since(last).as_secs_f64();
+        last = now;
+
+        let sum = |f: fn(&Counters) -> &AtomicU64| -> u64 {
+            all.iter().map(|c| f(c).load(Ordering::Relaxed)).sum()
+        };
+        let packets = sum(|c| &c.packets);
+        let bytes = sum(|c| &c.bytes);
+        let malformed = sum(|c| &c.malformed);
+        let silent = all
+            .iter()
+            .filter(|c| c.packets.load(Ordering::Relaxed) == 0)
+            .count();
+
+        println!(
+            "Sink: packets={packets} malformed={malformed} silent_instances={silent} {} {}",
+            units::pair(
+                "rate_pps",
+                units::pps((packets - last_packets) as f64 / secs)
+            ),
+            units::pair(
+                "rate_bps",
+                units::bps((bytes - last_bytes) as f64 * 8.0 / secs)
+            ),
+        );
+        last_packets = packets;
+        last_bytes = bytes;
+    }
+}
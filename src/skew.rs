@@ -0,0 +1,143 @@
+//! Live offset and frequency skew estimation in listen mode.
+//!
+//! A single offset mixes the clock difference with that packet's network
+//! and queuing delay. Fitting a line through each sender's recent
+//! (arrival time, offset) pairs averages the delay out: the line's value
+//! at the newest arrival is the offset estimate, and its slope is the
+//! frequency skew between the sender's clock and ours. The residuals
+//! around the line measure how noisy the path is.
+//!
+//! [`drift`](crate::drift) does the same over days from a drift log; this
+//! covers a window of minutes, for calibration while watching.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+
+use crate::drift::linear_fit;
+use crate::units;
+
+/// Most samples kept per sender; at high rates the window is cut short
+/// rather than grow without bound.
+pub const MAX_SAMPLES: usize = 20_000;
+
+/// A sender's fitted offset and skew.
+#[derive(Clone, Copy, Debug)]
+pub struct Estimate {
+    pub samples: usize,
+    pub span_ms: u64,
+    /// Offset at the newest sample, in ms.
+    pub offset_ms: f64,
+    /// Offset gained per second, in µs per s; positive when the sender's
+    /// clock runs slow relative to this host's.
+    pub skew_ppm: f64,
+    /// Root mean square distance of the samples from the line, in ms.
+    pub residual_ms: f64,
+}
+
+/// Recent samples per sender.
+pub struct SkewEstimator {
+    window_ms: u64,
+    senders: BTreeMap<String, VecDeque<(u64, i64)>>,
+}
+
+impl SkewEstimator {
+    pub fn new(window: Duration) -> SkewEstimator {
+        SkewEstimator {
+            window_ms: window.as_millis() as u64,
+            senders: BTreeMap::new(),
+        }
+    }
+
+    pub fn observe(&mut self, sender: &str, recv_ms: u64, offset_ms: i64) {
+        let samples = match self.senders.get_mut(sender) {
+            Some(s) => s,
+            None => self.senders.entry(sender.to_string()).or_default(),
+        };
+        samples.push_back((recv_ms, offset_ms));
+        while samples.len() > MAX_SAMPLES
+            || samples
+                .front()
+                .is_some_and(|&(t, _)| t + self.window_ms < recv_ms)
+        {
+            samples.pop_front();
+        }
+    }
+
+    /// Drops the sender's samples, which no longer belong on one line.
+    pub fn forget(&mut self, sender: &str) {
+        self.senders.remove(sender);
+    }
+
+    /// Fits the sender's window; needs samples at two distinct times.
+    pub fn estimate(&self, sender: &str) -> Option<Estimate> {
+        let samples = self.senders.get(sender)?;
+        let &(first, _) = samples.front()?;
+        let &(last, _) = samples.back()?;
+        // Relative to the first sample, so the squares stay precise
+        let points: Vec<(f64, f64)> = samples
+            .iter()
+            .map(|&(t, o)| ((t - first) as f64, o as f64))
+            .collect();
+        let fit = linear_fit(&points)?;
+        let sum_sq: f64 = points
+            .iter()
+            .map(|&(x, y)| (y - fit.intercept - fit.slope * x).powi(2))
+            .sum();
+        Some(Estimate {
+            samples: points.len(),
+            span_ms: last - first,
+            offset_ms: fit.intercept + fit.slope * (last - first) as f64,
+            skew_ppm: fit.slope * 1e6,
+            residual_ms: (sum_sq / points.len() as f64).sqrt(),
+        })
+    }
+
+    /// One line per sender with enough samples for a fit.
+    pub fn lines(&self) -> Vec<String> {
+        self.senders
+            .keys()
+            .filter_map(|sender| {
+                let e = self.estimate(sender)?;
+                Some(format!(
+                    "Skew: sender={sender} samples={} {} {} skew_ppm={:.3} {}",
+                    e.samples,
+                    units::pair("span_ms", units::millis(e.span_ms as i64)),
+                    units::pair("offset_ms", units::millis_f64(e.offset_ms)),
+                    e.skew_ppm,
+                    units::pair("residual_ms", units::millis_f64(e.residual_ms)),
+                ))
+            })
+            .collect()
+    }
+
+    pub fn print(&self) {
+        for line in self.lines() {
+            println!("{line}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovers_skew_through_delay_noise() {
+        let mut est = SkewEstimator::new(Duration::from_secs(60));
+        // A sender 20 ppm slow and 5 ms off, with 0-3 ms of delay on top;
+        // only the last 60 s count
+        for i in 0..200u64 {
+            let t = 1_700_000_000_000 + i * 500;
+            let drift = (i * 500) as f64 * 20e-6;
+            let offset = 5.0 + drift + (i % 4) as f64;
+            est.observe("10.0.0.5", t, offset.round() as i64);
+        }
+        let e = est.estimate("10.0.0.5").unwrap();
+        assert_eq!(e.samples, 121);
+        assert_eq!(e.span_ms, 60_000);
+        assert!((e.skew_ppm - 20.0).abs() < 5.0, "skew {}", e.skew_ppm);
+        assert!((e.offset_ms - 8.5).abs() < 0.5, "offset {}", e.offset_ms);
+        assert!(e.residual_ms > 0.5 && e.residual_ms < 2.0);
+        assert!(est.estimate("10.0.0.6").is_none());
+    }
+}
@@ -0,0 +1,245 @@
+//! Statistics snapshots of a running sender (`send --snapshot-file`).
+//!
+//! On SIGUSR1, or a `POST /v1/snapshot` to the control API, the send loop
+//! writes everything it counts to stderr, or appends it to the snapshot
+//! file: what it sent and failed to send, by error class, how late its
+//! ticks were, and the state of each destination. It does so between two
+//! ticks, so sending carries on undisturbed:
+//!
+//! ```text
+//! Snapshot: unix_ms=1772366400250 uptime=3600.0s state=active interval=1.00s next_seq=3601
+//!   sent=3598 send_errors=2 unreachable=2 permission=0 buffer_full=0 interface_down=0 other=0
+//!   lateness: ticks=3600 late=0 missed=0 mean=61µs max=1.90ms
+//!   lateness histogram: <100µs 3412, <250µs 171, <500µs 14, <1ms 2, <2.5ms 1, <10ms 0, <100ms 0, later 0
+//!   dest 192.0.2.255:12321: sent=3598 failed=2 state=ok
+//! ```
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::lateness::Lateness;
+use crate::log;
+use crate::offload::QueueStats;
+use crate::sockerr::ErrorClass;
+use crate::txdelay::SendDelay;
+use crate::units;
+
+/// Sends and failures to one destination.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DestCounts {
+    pub sent: u64,
+    pub failed: u64,
+    /// Not sent during a `--blackout` window.
+    pub withheld: u64,
+}
+
+/// One destination's counters and whether it is being skipped.
+pub struct DestSnapshot {
+    pub dest: SocketAddr,
+    pub counts: DestCounts,
+    /// The class of error it is backing off from, and for how much longer.
+    pub backoff: Option<(ErrorClass, Duration)>,
+    /// How much longer it is blacked out for, in a `--blackout` window.
+    pub blackout: Option<Duration>,
+}
+
+/// A sender's statistics at one moment.
+pub struct Snapshot {
+    pub unix_ms: u64,
+    pub uptime: Duration,
+    /// `active`, `standby` or `paused`.
+    pub state: &'static str,
+    pub interval: Duration,
+    pub next_seq: u32,
+    pub errors: Vec<(ErrorClass, u64)>,
+    pub lateness: Lateness,
+    pub dests: Vec<DestSnapshot>,
+    /// How full the queue to the I/O thread has been, with `--io-thread`.
+    pub io_queue: Option<QueueStats>,
+    /// The correction added to timestamps and the delay measured, with
+    /// `--tx-compensation-us`.
+    pub tx_compensation: Option<(Duration, SendDelay)>,
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Snapshot: unix_ms={} uptime={:.1}s state={} interval={} next_seq={}",
+            self.unix_ms,
+            self.uptime.as_secs_f64(),
+            self.state,
+            units::micros(self.interval.as_micros() as u64),
+            self.next_seq
+        )?;
+        let sent: u64 = self.dests.iter().map(|d| d.counts.sent).sum();
+        let failed: u64 = self.errors.iter().map(|&(_, n)| n).sum();
+        let classes: Vec<String> = self
+            .errors
+            .iter()
+            .map(|(class, n)| format!("{class}={n}"))
+            .collect();
+        writeln!(
+            f,
+            "  sent={sent} send_errors={failed} {}",
+            classes.join(" ")
+        )?;
+        let l = &self.lateness;
+        writeln!(
+            f,
+            "  lateness: ticks={} late={} missed={} mean={} max={}",
+            l.ticks,
+            l.late,
+            l.missed,
+            units::micros(l.mean_us()),
+            units::micros(l.max_us)
+        )?;
+        writeln!(f, "  lateness histogram: {}", l.histogram_summary())?;
+        if let Some(q) = &self.io_queue {
+            writeln!(
+                f,
+                "  io_queue: capacity={} depth={} high_water={} dropped={}",
+                q.capacity, q.depth, q.high_water, q.dropped
+            )?;
+        }
+        if let Some((correction, delay)) = &self.tx_compensation {
+            writeln!(
+                f,
+                "  tx_compensation: correction={} measured={} samples={}",
+                units::micros(correction.as_micros() as u64),
+                units::micros(delay.mean().as_micros() as u64),
+                delay.samples()
+            )?;
+        }
+        for d in &self.dests {
+            let state = match (d.blackout, d.backoff) {
+                (Some(left), _) => format!("blackout for={:.1}s", left.as_secs_f64()),
+                (None, Some((class, left))) => {
+                    format!("backoff class={class} for={:.1}s", left.as_secs_f64())
+                }
+                (None, None) => "ok".to_string(),
+            };
+            let withheld = match d.counts.withheld {
+                0 => String::new(),
+                n => format!(" withheld={n}"),
+            };
+            writeln!(
+                f,
+                "  dest {}: sent={} failed={}{withheld} state={state}",
+                d.dest, d.counts.sent, d.counts.failed
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Where snapshots go: stderr, or a file opened up front, so writing one
+/// needs nothing a `--seccomp` filter would refuse.
+pub struct Snapshots {
+    file: Option<File>,
+}
+
+impl Snapshots {
+    pub fn open(path: Option<&str>) -> Result<Snapshots, String> {
+        let file = match path {
+            Some(p) => Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(p)
+                    .map_err(|e| format!("Failed to open snapshot file {p}: {e}"))?,
+            ),
+            None => None,
+        };
+        Ok(Snapshots { file })
+    }
+
+    pub fn write(&mut self, snapshot: &Snapshot) -> io::Result<()> {
+        // Under `multi`, which instance it is
+        let text = format!("{}{snapshot}", log::Prefix);
+        match self.file.as_mut() {
+            Some(f) => f.write_all(text.as_bytes()),
+            None => io::stderr().lock().write_all(text.as_bytes()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_counters_histogram_and_destinations() {
+        let mut lateness = Lateness::default();
+        lateness.record(Duration::from_micros(40), None);
+        lateness.record(Duration::from_millis(3), None);
+        let snapshot = Snapshot {
+            unix_ms: 1_772_366_400_250,
+            uptime: Duration::from_secs(2),
+            state: "active",
+            interval: Duration::from_secs(1),
+            next_seq: 3,
+            errors: ErrorClass::ALL
+                .iter()
+                .map(|&c| (c, u64::from(c == ErrorClass::Unreachable)))
+                .collect(),
+            lateness,
+            dests: vec![
+                DestSnapshot {
+                    dest: "192.0.2.255:12321".parse().unwrap(),
+                    counts: DestCounts {
+                        sent: 2,
+                        failed: 0,
+                        withheld: 0,
+                    },
+                    backoff: None,
+                    blackout: None,
+                },
+                DestSnapshot {
+                    dest: "192.0.2.9:12321".parse().unwrap(),
+                    counts: DestCounts {
+                        sent: 1,
+                        failed: 1,
+                        withheld: 0,
+                    },
+                    backoff: Some((ErrorClass::Unreachable, Duration::from_millis(1500))),
+                    blackout: None,
+                },
+                DestSnapshot {
+                    dest: "192.0.2.10:12321".parse().unwrap(),
+                    counts: DestCounts {
+                        sent: 0,
+                        failed: 0,
+                        withheld: 3,
+                    },
+                    backoff: None,
+                    blackout: Some(Duration::from_secs(600)),
+                },
+            ],
+            io_queue: None,
+            tx_compensation: None,
+        };
+        let text = snapshot.to_string();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 7, "{text}");
+        assert!(lines[1].starts_with("  sent=3 send_errors=1 "));
+        assert!(lines[1].contains(" unreachable=1"));
+        assert!(
+            lines[3].ends_with("<10ms 1, <100ms 0, later 0"),
+            "{}",
+            lines[3]
+        );
+        assert!(lines[3].contains("<100µs 1,"), "{}", lines[3]);
+        assert_eq!(
+            lines[5],
+            "  dest 192.0.2.9:12321: sent=1 failed=1 state=backoff class=unreachable for=1.5s"
+        );
+        assert_eq!(
+            lines[6],
+            "  dest 192.0.2.10:12321: sent=0 failed=0 withheld=3 state=blackout for=600.0s"
+        );
+    }
+}
@@ -0,0 +1,274 @@
+//! A minimal SNTP client (RFC 4330) for checking the local clock against
+//! an NTP server.
+//!
+//! One request, one reply: the four timestamps of the exchange give the
+//! local clock's offset from the server's and the round-trip delay. That is
+//! coarser than a disciplined NTP daemon but plenty to catch a host whose
+//! clock is seconds or years off before it broadcasts its time to the whole
+//! segment. `check` reports the offset; `send --require-sync` refuses to
+//! start when it is too large.
+
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::process::ExitCode;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::exit::{self, Failure};
+use crate::send::bind_for;
+use crate::transmit;
+use crate::units;
+use crate::EXIT_CODE_CLOCK_OFFSET;
+
+pub const NTP_PORT: u16 = 123;
+
+/// Largest offset accepted unless configured otherwise, in ms.
+pub const DEFAULT_MAX_OFFSET_MS: u64 = 100;
+
+/// How long `send --require-sync` waits for the server.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Seconds from the NTP era (1900) to the Unix epoch.
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+const PACKET_LEN: usize = 48;
+const VERSION: u8 = 4;
+const MODE_CLIENT: u8 = 3;
+const MODE_SERVER: u8 = 4;
+/// Leap indicator meaning the server's clock isn't synchronized.
+const LI_ALARM: u8 = 3;
+
+/// The outcome of one exchange.
+#[derive(Clone, Copy, Debug)]
+pub struct Sample {
+    pub server: SocketAddr,
+    pub stratum: u8,
+    /// Server clock minus local clock, in ms; positive when the local
+    /// clock is behind.
+    pub offset_ms: f64,
+    /// Round trip, less the server's processing time, in ms.
+    pub delay_ms: f64,
+}
+
+impl Sample {
+    pub fn summary(&self) -> String {
+        format!(
+            "server={} stratum={} {} {}",
+            self.server,
+            self.stratum,
+            units::pair("offset_ms", units::millis_f64(self.offset_ms)),
+            units::pair("delay_ms", units::millis_f64(self.delay_ms))
+        )
+    }
+}
+
+/// Settings for check mode, and for `send --require-sync`.
+#[derive(Clone, Debug)]
+pub struct CheckConfig {
+    /// `host` or `host:port`.
+    pub server: String,
+    pub timeout: Duration,
+    /// Largest offset accepted, in ms.
+    pub max_offset_ms: u64,
+}
+
+/// Converts a wall-clock time to a 64-bit NTP timestamp.
+fn to_ntp(t: SystemTime) -> u64 {
+    let d = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = d.as_secs() + NTP_UNIX_OFFSET;
+    let frac = (u64::from(d.subsec_nanos()) << 32) / 1_000_000_000;
+    (secs << 32) | frac
+}
+
+/// Converts a 64-bit NTP timestamp to ms since the Unix epoch.
+fn ntp_to_unix_ms(ts: u64) -> f64 {
+    let secs = (ts >> 32) as f64 - NTP_UNIX_OFFSET as f64;
+    let frac = (ts & 0xffff_ffff) as f64 / 4_294_967_296.0;
+    (secs + frac) * 1000.0
+}
+
+fn unix_ms(t: SystemTime) -> f64 {
+    t.duration_since(UNIX_EPOCH)
+        .map_or(0.0, |d| d.as_secs_f64() * 1000.0)
+}
+
+fn request(t1: u64) -> [u8; PACKET_LEN] {
+    let mut p = [0u8; PACKET_LEN];
+    p[0] = (VERSION << 3) | MODE_CLIENT;
+    // The server echoes this back as the originate timestamp
+    p[40..48].copy_from_slice(&t1.to_be_bytes());
+    p
+}
+
+/// Checks a reply to the request sent at `t1` and computes the offset and
+/// delay from it, with `t4` the local time it arrived.
+fn parse_reply(
+    reply: &[u8],
+    t1: u64,
+    t4: SystemTime,
+    server: SocketAddr,
+) -> Result<Sample, String> {
+    if reply.len() < PACKET_LEN {
+        return Err(format!("short reply ({} bytes)", reply.len()));
+    }
+    let field = |at: usize| u64::from_be_bytes(reply[at..at + 8].try_into().unwrap());
+    let (leap, mode, stratum) = (reply[0] >> 6, reply[0] & 0x07, reply[1]);
+    if mode != MODE_SERVER {
+        return Err(format!("reply has mode {mode}, not server"));
+    }
+    if stratum == 0 {
+        let code = String::from_utf8_lossy(&reply[12..16]).into_owned();
+        return Err(format!("server sent kiss-o'-death {code}"));
+    }
+    if leap == LI_ALARM {
+        return Err("server clock is not synchronized".to_string());
+    }
+    if field(24) != t1 {
+        return Err("reply does not match the request".to_string());
+    }
+    let (t2, t3) = (field(32), field(40));
+    if t3 == 0 {
+        return Err("reply has no transmit timestamp".to_string());
+    }
+    let t1 = ntp_to_unix_ms(t1);
+    let (t2, t3) = (ntp_to_unix_ms(t2), ntp_to_unix_ms(t3));
+    let t4 = unix_ms(t4);
+    Ok(Sample {
+        server,
+        stratum,
+        offset_ms: ((t2 - t1) + (t3 - t4)) / 2.0,
+        delay_ms: (t4 - t1) - (t3 - t2),
+    })
+}
+
+/// Queries `server` (`host` or `host:port`) once.
+pub fn query(server: &str, timeout: Duration) -> Result<Sample, String> {
+    let addr = if let Ok(addr) = server.parse::<SocketAddr>() {
+        addr
+    } else if let Ok(ip) = server.parse::<IpAddr>() {
+        SocketAddr::new(ip, NTP_PORT)
+    } else {
+        let (host, port) = match server.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse::<u16>()
+                    .map_err(|_| format!("Bad NTP server port '{port}'"))?,
+            ),
+            None => (server, NTP_PORT),
+        };
+        (host, port)
+            .to_socket_addrs()
+            .map_err(|e| format!("Failed to resolve NTP server {host}: {e}"))?
+            .next()
+            .ok_or_else(|| format!("NTP server {host} has no address"))?
+    };
+    let sock = bind_for(addr)?;
+    sock.set_read_timeout(Some(timeout))
+        .map_err(|e| format!("Failed to set receive timeout: {e}"))?;
+
+    let t1 = to_ntp(SystemTime::now());
+    transmit::send_to(&sock, &request(t1), addr)
+        .map_err(|e| format!("Failed to query NTP server {addr}: {e}"))?;
+    let mut buf = [0u8; 512];
+    let deadline = SystemTime::now() + timeout;
+    loop {
+        let (n, src) = sock.recv_from(&mut buf).map_err(|e| {
+            format!(
+                "No reply from NTP server {addr} within {}: {e}",
+                units::millis(timeout.as_millis() as i64)
+            )
+        })?;
+        let t4 = SystemTime::now();
+        // Anything else arriving on the ephemeral port is ignored
+        if src == addr {
+            return parse_reply(&buf[..n], t1, t4, addr)
+                .map_err(|e| format!("Bad reply from NTP server {addr}: {e}"));
+        }
+        if t4 >= deadline {
+            return Err(format!("No reply from NTP server {addr}"));
+        }
+    }
+}
+
+/// Queries the server and fails if the local clock is off by more than
+/// the limit.
+pub fn verify(cfg: &CheckConfig) -> Result<Sample, Failure> {
+    let sample = query(&cfg.server, cfg.timeout)?;
+    if sample.offset_ms.abs() > cfg.max_offset_ms as f64 {
+        return Err(Failure::new(
+            EXIT_CODE_CLOCK_OFFSET,
+            format!(
+                "Local clock is off by {} from {} (limit {})",
+                units::millis_f64(-sample.offset_ms),
+                sample.server,
+                units::millis(cfg.max_offset_ms as i64)
+            ),
+        ));
+    }
+    Ok(sample)
+}
+
+/// Runs check mode: one query, reported on one line.
+pub fn run(cfg: &CheckConfig) -> ExitCode {
+    match verify(cfg) {
+        Ok(sample) => {
+            println!("Clock check: {} status=ok", sample.summary());
+            ExitCode::SUCCESS
+        }
+        Err(e) => exit::fail(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+    use std::thread;
+
+    #[test]
+    fn ntp_timestamps_round_trip() {
+        let t = UNIX_EPOCH + Duration::from_millis(1_700_000_000_250);
+        assert_eq!(to_ntp(t) >> 32, 1_700_000_000 + NTP_UNIX_OFFSET);
+        assert!((ntp_to_unix_ms(to_ntp(t)) - 1_700_000_000_250.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn measures_offset_against_a_server() {
+        // A server whose clock is 1.5 s ahead of ours
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; PACKET_LEN];
+            let (_, src) = server.recv_from(&mut buf).unwrap();
+            let now = to_ntp(SystemTime::now() + Duration::from_millis(1500));
+            let mut reply = [0u8; PACKET_LEN];
+            reply[0] = (VERSION << 3) | MODE_SERVER;
+            reply[1] = 2;
+            reply[24..32].copy_from_slice(&buf[40..48]);
+            reply[32..40].copy_from_slice(&now.to_be_bytes());
+            reply[40..48].copy_from_slice(&now.to_be_bytes());
+            server.send_to(&reply, src).unwrap();
+        });
+        let cfg = CheckConfig {
+            server: addr.to_string(),
+            timeout: Duration::from_secs(2),
+            max_offset_ms: 100,
+        };
+        let e = verify(&cfg).unwrap_err();
+        assert_eq!(e.code, EXIT_CODE_CLOCK_OFFSET);
+        assert!(e.message.starts_with("Local clock is off by -1.50s"), "{e}");
+
+        let t1 = to_ntp(SystemTime::now());
+        let mut reply = request(t1);
+        reply[0] = (VERSION << 3) | MODE_SERVER;
+        reply[12..16].copy_from_slice(b"RATE");
+        assert_eq!(
+            parse_reply(&reply, t1, SystemTime::now(), addr).unwrap_err(),
+            "server sent kiss-o'-death RATE"
+        );
+        reply[1] = 1;
+        reply[24..32].copy_from_slice(&(t1 + 1).to_be_bytes());
+        assert_eq!(
+            parse_reply(&reply, t1, SystemTime::now(), addr).unwrap_err(),
+            "reply does not match the request"
+        );
+    }
+}
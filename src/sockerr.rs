@@ -0,0 +1,231 @@
+//! Classifying send failures, so each kind gets a counter and a retry
+//! policy of its own.
+//!
+//! A failed send lands in one of a few classes, each handled the way its
+//! cause tends to clear up:
+//!
+//! - `buffer_full`: the socket buffer (or the interface queue) was full.
+//!   It drains within microseconds, so the next tick simply tries again.
+//! - `unreachable`: an ICMP unreachable came back for an earlier send. The
+//!   host may return at any moment and only a send finds out, so sending
+//!   carries on; with `--peers`, `--peer-backoff` skips the peer instead.
+//! - `permission`: a firewall rule or a missing broadcast permission. That
+//!   doesn't change from one tick to the next, so the destination is
+//!   skipped for a second, doubling on each further failure up to a minute.
+//! - `interface_down`: the interface or its address is gone. The
+//!   destination is skipped for a second, doubling up to 30 seconds, and
+//!   retried at once when an interface change is seen.
+//! - `other`: anything else, retried on the next tick.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// What kind of failure a send ran into.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ErrorClass {
+    Unreachable,
+    Permission,
+    BufferFull,
+    InterfaceDown,
+    Other,
+}
+
+impl ErrorClass {
+    pub const ALL: [ErrorClass; 5] = [
+        ErrorClass::Unreachable,
+        ErrorClass::Permission,
+        ErrorClass::BufferFull,
+        ErrorClass::InterfaceDown,
+        ErrorClass::Other,
+    ];
+
+    pub fn of(e: &io::Error) -> ErrorClass {
+        #[cfg(unix)]
+        match e.raw_os_error() {
+            Some(libc::ENOBUFS) => return ErrorClass::BufferFull,
+            Some(libc::ENODEV | libc::ENXIO) => return ErrorClass::InterfaceDown,
+            _ => {}
+        }
+        // WSAENOBUFS
+        #[cfg(windows)]
+        if e.raw_os_error() == Some(10055) {
+            return ErrorClass::BufferFull;
+        }
+        match e.kind() {
+            ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::HostUnreachable
+            | ErrorKind::NetworkUnreachable => ErrorClass::Unreachable,
+            ErrorKind::PermissionDenied => ErrorClass::Permission,
+            // EAGAIN, including a send that hit --send-timeout-ms
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => ErrorClass::BufferFull,
+            ErrorKind::NetworkDown | ErrorKind::AddrNotAvailable => ErrorClass::InterfaceDown,
+            _ => ErrorClass::Other,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorClass::Unreachable => "unreachable",
+            ErrorClass::Permission => "permission",
+            ErrorClass::BufferFull => "buffer_full",
+            ErrorClass::InterfaceDown => "interface_down",
+            ErrorClass::Other => "other",
+        }
+    }
+
+    /// How long to skip a destination after its first failure of this
+    /// class, and the longest that doubles to; `None` to keep sending.
+    pub fn backoff(self) -> Option<(Duration, Duration)> {
+        match self {
+            ErrorClass::Permission => Some((Duration::from_secs(1), Duration::from_secs(60))),
+            ErrorClass::InterfaceDown => Some((Duration::from_secs(1), Duration::from_secs(30))),
+            ErrorClass::Unreachable | ErrorClass::BufferFull | ErrorClass::Other => None,
+        }
+    }
+}
+
+impl fmt::Display for ErrorClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A destination being skipped.
+struct Backoff {
+    class: ErrorClass,
+    delay: Duration,
+    until: Instant,
+}
+
+/// Send failures by class, and the destinations backing off.
+#[derive(Default)]
+pub struct SendErrors {
+    counts: BTreeMap<ErrorClass, u64>,
+    backoff: BTreeMap<SocketAddr, Backoff>,
+}
+
+impl SendErrors {
+    /// Counts a failed send to `dest`, returning how long to skip it for
+    /// if its class backs off.
+    pub fn failed(
+        &mut self,
+        dest: SocketAddr,
+        class: ErrorClass,
+        now: Instant,
+    ) -> Option<Duration> {
+        *self.counts.entry(class).or_default() += 1;
+        let Some((first, max)) = class.backoff() else {
+            self.backoff.remove(&dest);
+            return None;
+        };
+        let delay = match self.backoff.get(&dest) {
+            Some(b) if b.class == class => (b.delay * 2).min(max),
+            _ => first,
+        };
+        self.backoff.insert(
+            dest,
+            Backoff {
+                class,
+                delay,
+                until: now + delay,
+            },
+        );
+        Some(delay)
+    }
+
+    /// Clears `dest`'s backoff after a send to it went through.
+    pub fn succeeded(&mut self, dest: SocketAddr) {
+        self.backoff.remove(&dest);
+    }
+
+    /// Whether `dest` is being skipped.
+    pub fn paused(&self, dest: SocketAddr, now: Instant) -> bool {
+        self.backoff.get(&dest).is_some_and(|b| b.until > now)
+    }
+
+    /// The class `dest` is being skipped for, and until when.
+    pub fn backing_off(&self, dest: SocketAddr) -> Option<(ErrorClass, Instant)> {
+        self.backoff.get(&dest).map(|b| (b.class, b.until))
+    }
+
+    /// Retries every destination skipped for `class` on the next send.
+    pub fn lift(&mut self, class: ErrorClass) {
+        self.backoff.retain(|_, b| b.class != class);
+    }
+
+    pub fn count(&self, class: ErrorClass) -> u64 {
+        self.counts.get(&class).copied().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classes_back_off_by_their_own_policy() {
+        let err = |kind| io::Error::from(kind);
+        assert_eq!(
+            ErrorClass::of(&err(ErrorKind::HostUnreachable)),
+            ErrorClass::Unreachable
+        );
+        assert_eq!(
+            ErrorClass::of(&err(ErrorKind::PermissionDenied)),
+            ErrorClass::Permission
+        );
+        assert_eq!(
+            ErrorClass::of(&err(ErrorKind::WouldBlock)),
+            ErrorClass::BufferFull
+        );
+        assert_eq!(
+            ErrorClass::of(&err(ErrorKind::NetworkDown)),
+            ErrorClass::InterfaceDown
+        );
+        #[cfg(unix)]
+        assert_eq!(
+            ErrorClass::of(&io::Error::from_raw_os_error(libc::ENOBUFS)),
+            ErrorClass::BufferFull
+        );
+        assert_eq!(ErrorClass::of(&io::Error::other("odd")), ErrorClass::Other);
+
+        let t0 = Instant::now();
+        let at = |s| t0 + Duration::from_secs(s);
+        let (a, b) = (
+            "192.0.2.1:12321".parse().unwrap(),
+            "192.0.2.2:12321".parse().unwrap(),
+        );
+        let mut errors = SendErrors::default();
+        // Unreachable keeps sending
+        assert_eq!(errors.failed(a, ErrorClass::Unreachable, at(0)), None);
+        assert!(!errors.paused(a, at(0)));
+        // Permission doubles to its cap
+        let delays: Vec<u64> = (0..8)
+            .map(|_| {
+                errors
+                    .failed(a, ErrorClass::Permission, at(0))
+                    .unwrap()
+                    .as_secs()
+            })
+            .collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 32, 60, 60]);
+        assert!(errors.paused(a, at(59)));
+        assert!(!errors.paused(a, at(60)));
+        errors.succeeded(a);
+        assert_eq!(
+            errors.failed(a, ErrorClass::Permission, at(0)),
+            Some(Duration::from_secs(1))
+        );
+        // An interface change lifts only interface_down
+        errors.failed(b, ErrorClass::InterfaceDown, at(0));
+        errors.lift(ErrorClass::InterfaceDown);
+        assert!(!errors.paused(b, at(0)));
+        assert!(errors.paused(a, at(0)));
+
+        assert_eq!(errors.count(ErrorClass::Permission), 9);
+        assert_eq!(errors.count(ErrorClass::BufferFull), 0);
+    }
+}
@@ -0,0 +1,211 @@
+//! When `send` sends its first datagram (`--start-delay`, `--start-random`,
+//! `--start-at`).
+//!
+//! A rack of units powered on together would otherwise all start sending
+//! in the same millisecond, and keep sending in step. `--start-random`
+//! spreads them out by a random delay each; `--start-delay` holds every
+//! one back by a fixed amount, e.g. until the network is up; `--start-at`
+//! waits for a wall-clock time, so a set of senders begins an experiment
+//! together. They add up: the first tick goes out at `--start-at` (or
+//! right away), plus `--start-delay`, plus up to `--start-random`. Ticks
+//! then follow on the usual grid from the first one.
+
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::log;
+use crate::rng::Rng;
+
+/// Settings for delaying the first tick.
+#[derive(Clone, Debug, Default)]
+pub struct StartConfig {
+    pub delay: Duration,
+    /// Upper bound of an extra random delay.
+    pub random: Duration,
+    /// Wall-clock time to wait for first.
+    pub at: Option<SystemTime>,
+}
+
+impl StartConfig {
+    /// How long to wait from `now`; a `--start-at` already past adds nothing.
+    pub fn wait_from(&self, now: SystemTime, rng: &mut Rng) -> Duration {
+        let until_at = self
+            .at
+            .and_then(|at| at.duration_since(now).ok())
+            .unwrap_or_default();
+        until_at + self.delay + self.random.mul_f64(rng.next_f64())
+    }
+
+    /// Sleeps until the first tick is due, logging when that is.
+    pub fn wait(&self) {
+        if self.delay.is_zero() && self.random.is_zero() && self.at.is_none() {
+            return;
+        }
+        let now = SystemTime::now();
+        if let Some(at) = self.at.filter(|&at| at < now) {
+            log::warn!("--start-at {} has already passed", format_rfc3339(at));
+        }
+        let wait = self.wait_from(now, &mut Rng::from_entropy());
+        log::info!(
+            "Starting at {}, in {:.3}s",
+            format_rfc3339(now + wait),
+            wait.as_secs_f64()
+        );
+        thread::sleep(wait);
+    }
+}
+
+/// Parses a `--start-delay` or `--start-random` duration: a number with a
+/// unit of ms, s, m or h, e.g. `500ms` or `1.5s`.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (value, scale) = [("ms", 1e-3), ("s", 1.0), ("m", 60.0), ("h", 3600.0)]
+        .into_iter()
+        .find_map(|(unit, scale)| s.strip_suffix(unit).map(|v| (v, scale)))
+        .ok_or_else(|| format!("duration '{s}' needs a unit: ms, s, m or h"))?;
+    value
+        .parse::<f64>()
+        .ok()
+        .map(|v| v * scale)
+        .filter(|secs| (0.0..1e9).contains(secs))
+        .map(Duration::from_secs_f64)
+        .ok_or_else(|| format!("duration '{s}' must be a non-negative number"))
+}
+
+/// Days from 1970-01-01 to a proleptic Gregorian date.
+pub(crate) fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Parses an RFC 3339 timestamp, e.g. `2026-03-01T12:00:00Z` or
+/// `2026-03-01T13:00:00.5+01:00`.
+pub fn parse_rfc3339(s: &str) -> Result<SystemTime, String> {
+    let bad = || format!("'{s}' is not an RFC 3339 time, e.g. 2026-03-01T12:00:00Z");
+    let b = s.as_bytes();
+    if !s.is_ascii()
+        || b.len() < 20
+        || b[4] != b'-'
+        || b[7] != b'-'
+        || !matches!(b[10], b'T' | b't' | b' ')
+        || b[13] != b':'
+        || b[16] != b':'
+    {
+        return Err(bad());
+    }
+    let num = |from: usize, to: usize| -> Result<i64, String> {
+        let digits = &s[from..to];
+        if !digits.bytes().all(|c| c.is_ascii_digit()) {
+            return Err(bad());
+        }
+        digits.parse().map_err(|_| bad())
+    };
+    let (year, month, day) = (num(0, 4)?, num(5, 7)?, num(8, 10)?);
+    let (hour, min, sec) = (num(11, 13)?, num(14, 16)?, num(17, 19)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || min > 59 || sec > 60 {
+        return Err(bad());
+    }
+
+    let mut rest = &s[19..];
+    let mut nanos = 0u32;
+    if let Some(frac) = rest.strip_prefix('.') {
+        let len = frac.bytes().take_while(u8::is_ascii_digit).count();
+        if len == 0 {
+            return Err(bad());
+        }
+        let digits = &frac[..len.min(9)];
+        nanos = digits.parse::<u32>().map_err(|_| bad())? * 10u32.pow(9 - digits.len() as u32);
+        rest = &frac[len..];
+    }
+    let offset_s = match rest {
+        "Z" | "z" => 0,
+        _ if rest.len() == 6 && rest.as_bytes()[3] == b':' => {
+            let sign = match rest.as_bytes()[0] {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return Err(bad()),
+            };
+            let off = |from: usize| {
+                let digits = &rest[from..from + 2];
+                digits
+                    .bytes()
+                    .all(|c| c.is_ascii_digit())
+                    .then(|| digits.parse::<i64>().ok())
+                    .flatten()
+                    .ok_or_else(bad)
+            };
+            sign * (off(1)? * 3600 + off(4)? * 60)
+        }
+        _ => return Err(bad()),
+    };
+
+    let secs = days_from_civil(year, month, day) * 86_400 + hour * 3600 + min * 60 + sec - offset_s;
+    let secs = u64::try_from(secs).map_err(|_| format!("'{s}' is before 1970"))?;
+    Ok(UNIX_EPOCH + Duration::new(secs, nanos))
+}
+
+/// Formats `t` as RFC 3339 UTC, to the millisecond.
+pub fn format_rfc3339(t: SystemTime) -> String {
+    let d = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = d.as_secs() as i64;
+    let (days, rem) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    // Inverse of days_from_civil
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60,
+        d.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn start_waits_for_the_time_then_the_delays() {
+        let at = parse_rfc3339("2026-03-01T13:00:00.25+01:00").unwrap();
+        assert_eq!(at, UNIX_EPOCH + Duration::from_millis(1_772_366_400_250));
+        assert_eq!(format_rfc3339(at), "2026-03-01T12:00:00.250Z");
+        assert_eq!(parse_rfc3339("2026-03-01t12:00:00.250z"), Ok(at));
+        for bad in [
+            "2026-03-01",
+            "2026-13-01T00:00:00Z",
+            "2026-03-01T12:00:00+1:00",
+        ] {
+            assert!(parse_rfc3339(bad).is_err(), "{bad}");
+        }
+        assert_eq!(parse_duration("1.5s"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert!(parse_duration("5").is_err());
+
+        let start = StartConfig {
+            delay: Duration::from_secs(2),
+            random: Duration::from_secs(10),
+            at: Some(at),
+        };
+        let mut rng = Rng::new(7);
+        let before = at - Duration::from_secs(60);
+        for _ in 0..100 {
+            let wait = start.wait_from(before, &mut rng);
+            assert!((Duration::from_secs(62)..Duration::from_secs(72)).contains(&wait));
+        }
+        // Once past, only the delays are left
+        let wait = start.wait_from(at + Duration::from_secs(5), &mut rng);
+        assert!((Duration::from_secs(2)..Duration::from_secs(12)).contains(&wait));
+    }
+}
@@ -0,0 +1,336 @@
+//! Sequence numbers kept across restarts (`send --state-dir`).
+//!
+//! A sender that starts over at sequence number 0 looks to receivers like
+//! a replay until it catches up with where it was: `--replay-window`
+//! rejects its datagrams as stale, and loss counting sees a restart. With
+//! a state file the sender carries on from where it left off, clock era
+//! included.
+//!
+//! Writing the file on every datagram would cost a sync per tick, so the
+//! file holds a reservation instead: a sequence number [`LEASE`] ahead of
+//! the next one. A thread of its own writes the next reservation once the
+//! sender is halfway through the current one, so the send loop only waits
+//! for the disk if it gets to the end first. A clean exit writes the exact
+//! next number; after a crash the sender resumes from the reservation,
+//! skipping at most [`LEASE`] numbers but never reusing one. Each write
+//! goes to a temporary file that is synced and renamed over the old one,
+//! so a crash leaves either the old file or the new one.
+//!
+//! ```text
+//! seq=4000
+//! era=1
+//! ```
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+use crate::log;
+
+/// Sequence numbers reserved by each write, and so skipped at most after
+/// a crash.
+pub const LEASE: u32 = 1000;
+
+/// Where a sender resumes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Saved {
+    pub seq: u32,
+    pub era: u16,
+}
+
+impl Saved {
+    fn parse(text: &str) -> Result<Saved, String> {
+        let mut seq = None;
+        let mut era = None;
+        for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("expected key=value, got '{line}'"))?;
+            let bad = |_| format!("invalid {key} '{value}'");
+            match key {
+                "seq" => seq = Some(value.parse().map_err(bad)?),
+                "era" => era = Some(value.parse().map_err(bad)?),
+                // Left for later versions
+                _ => {}
+            }
+        }
+        Ok(Saved {
+            seq: seq.ok_or("no seq")?,
+            era: era.unwrap_or(0),
+        })
+    }
+}
+
+/// A sender's state file.
+#[derive(Debug)]
+pub struct SeqState {
+    path: PathBuf,
+    /// What the file says now.
+    saved: Saved,
+    /// The last reservation handed to the writer.
+    requested: Saved,
+    /// Reservations handed to the writer and not yet written.
+    pending: usize,
+    /// Where to carry on from, written in place of the reservation on a
+    /// clean exit.
+    next: Saved,
+    /// Takes reservations to the writer; `None` once closed.
+    writes: Option<Sender<Saved>>,
+    /// Each reservation the writer took, and whether it was written.
+    written: Receiver<(Saved, bool)>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl SeqState {
+    /// Reads the state at `path`, or starts at 0 if there is none yet,
+    /// creating its directory if need be.
+    pub fn open(path: PathBuf) -> Result<SeqState, String> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)
+                .map_err(|e| format!("Failed to create state directory {}: {e}", dir.display()))?;
+        }
+        let saved = match fs::read_to_string(&path) {
+            Ok(text) => Saved::parse(&text).map_err(|e| {
+                format!(
+                    "State file {} is corrupt ({e}); remove it to start over at seq=0",
+                    path.display()
+                )
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                log::info!("No state in {}; starting at seq=0", path.display());
+                Saved::default()
+            }
+            Err(e) => return Err(format!("Failed to read state file {}: {e}", path.display())),
+        };
+        // A sender that can't write it should find out now, not at the
+        // first reservation
+        if let Err(e) = write_atomic(&path, &saved) {
+            return Err(format!(
+                "Failed to write state file {}: {e}",
+                path.display()
+            ));
+        }
+        let (writes, reservations) = mpsc::channel();
+        let (done, written) = mpsc::channel();
+        let writer_path = path.clone();
+        let writer = thread::Builder::new()
+            .name("state".to_string())
+            .spawn(move || write_reservations(&writer_path, reservations, done))
+            .map_err(|e| format!("Failed to start the state writer: {e}"))?;
+        Ok(SeqState {
+            path,
+            saved,
+            requested: saved,
+            pending: 0,
+            next: saved,
+            writes: Some(writes),
+            written,
+            writer: Some(writer),
+        })
+    }
+
+    /// Where to resume: the reservation, or the exact number after a
+    /// clean exit.
+    pub fn saved(&self) -> Saved {
+        self.saved
+    }
+
+    /// Called before sending `seq`: has the next [`LEASE`] numbers
+    /// reserved once `seq` is halfway through the reservation, and a new
+    /// `era` recorded. Waits for the disk only if the reservation on it
+    /// doesn't cover `seq` yet.
+    pub fn reserve(&mut self, seq: u32, era: u16) {
+        self.next = Saved {
+            seq: seq.wrapping_add(1),
+            era,
+        };
+        self.collect(false);
+        // Numbers left before the reservation, wrapping with seq
+        let ahead = self.requested.seq.wrapping_sub(seq);
+        if era != self.requested.era || !(LEASE / 2 + 1..=LEASE).contains(&ahead) {
+            self.request(Saved {
+                seq: seq.wrapping_add(LEASE),
+                era,
+            });
+        }
+        let left = self.saved.seq.wrapping_sub(seq);
+        if era != self.saved.era || !(1..=LEASE).contains(&left) {
+            self.collect(true);
+        }
+    }
+
+    /// Saves exactly where to carry on; on a clean exit, after which
+    /// nothing more is reserved.
+    pub fn close(&mut self) {
+        // The writer finishes what it has, then ends
+        if self.writes.take().is_none() {
+            return;
+        }
+        self.collect(true);
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+        if self.next == self.saved {
+            return;
+        }
+        match write_atomic(&self.path, &self.next) {
+            Ok(()) => self.saved = self.next,
+            Err(e) => log::warn!(
+                "Failed to write state file {}: {e}; resuming from the reservation after a restart",
+                self.path.display()
+            ),
+        }
+    }
+
+    fn request(&mut self, saved: Saved) {
+        if let Some(writes) = &self.writes {
+            if writes.send(saved).is_ok() {
+                self.requested = saved;
+                self.pending += 1;
+            }
+        }
+    }
+
+    /// Takes in what the writer has written so far, or with `wait`,
+    /// everything it was handed.
+    fn collect(&mut self, wait: bool) {
+        while self.pending > 0 {
+            let result = match wait {
+                true => self.written.recv().ok(),
+                false => self.written.try_recv().ok(),
+            };
+            let Some((saved, ok)) = result else {
+                return;
+            };
+            self.pending -= 1;
+            match ok {
+                true => self.saved = saved,
+                // Asked for again on the next reserve
+                false => self.requested = self.saved,
+            }
+        }
+    }
+}
+
+impl Drop for SeqState {
+    /// Saves exactly where to carry on, if [`SeqState::close`] wasn't
+    /// called.
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Writes each reservation that arrives on `reservations`, reporting
+/// it on `done`, until the state is closed.
+fn write_reservations(path: &Path, reservations: Receiver<Saved>, done: Sender<(Saved, bool)>) {
+    // The last write failed; logged once until one succeeds
+    let mut failing = false;
+    for saved in reservations {
+        let ok = match write_atomic(path, &saved) {
+            Ok(()) => {
+                if std::mem::take(&mut failing) {
+                    log::info!("State file {} written again", path.display());
+                }
+                true
+            }
+            Err(e) => {
+                if !std::mem::replace(&mut failing, true) {
+                    log::warn!(
+                        "Failed to write state file {}: {e}; sequence numbers may repeat after a restart",
+                        path.display()
+                    );
+                }
+                false
+            }
+        };
+        if done.send((saved, ok)).is_err() {
+            return;
+        }
+    }
+}
+
+/// Writes `saved` to a temporary file beside `path`, syncs it, and
+/// renames it into place.
+fn write_atomic(path: &Path, saved: &Saved) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut f = File::create(&tmp)?;
+    write!(f, "seq={}\nera={}\n", saved.seq, saved.era)?;
+    f.sync_all()?;
+    fs::rename(&tmp, path)?;
+    // The rename itself survives a power cut once the directory is synced
+    #[cfg(unix)]
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// The state file's name for a sender, from what it sends to: letters,
+/// digits, `.`, `-` and `_` only.
+pub fn file_name(parts: &[&str]) -> String {
+    let name: String = parts
+        .join("_")
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+            _ => '-',
+        })
+        .collect();
+    format!("{name}.state")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserves_ahead_and_resumes() {
+        let dir = std::env::temp_dir().join(format!("udp_bcast_ts-state-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(file_name(&["ff02::1%eth0", "12321"]));
+        assert!(path.ends_with("ff02--1-eth0_12321.state"));
+        let _ = fs::remove_file(&path);
+
+        // A first run waits for its first reservation, then reserves the
+        // next one halfway through, without waiting
+        let mut state = SeqState::open(path.clone()).unwrap();
+        assert_eq!(state.saved(), Saved::default());
+        state.reserve(0, 0);
+        assert_eq!(SeqState::open(path.clone()).unwrap().saved().seq, LEASE);
+        state.reserve(LEASE / 2 - 1, 0);
+        assert_eq!((state.pending, state.saved().seq), (0, LEASE));
+        state.reserve(LEASE / 2, 0);
+        assert_eq!((state.pending, state.requested.seq), (1, LEASE * 3 / 2));
+        state.collect(true);
+        assert_eq!(state.saved().seq, LEASE * 3 / 2);
+        // A new era is written at once
+        state.reserve(LEASE / 2 + 1, 1);
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("seq={}\nera=1\n", LEASE * 3 / 2 + 1)
+        );
+
+        // A crash resumes from the reservation, a clean exit where it left off
+        let resumed = SeqState::open(path.clone()).unwrap().saved();
+        assert_eq!((resumed.seq, resumed.era), (LEASE * 3 / 2 + 1, 1));
+        state.close();
+        let mut state = SeqState::open(path.clone()).unwrap();
+        assert_eq!(state.saved().seq, LEASE / 2 + 2);
+
+        // Reservations wrap with sequence numbers
+        state.reserve(u32::MAX - LEASE / 2, 1);
+        assert_eq!(state.saved().seq, LEASE / 2 - 1);
+        state.reserve(u32::MAX, 1);
+        state.collect(true);
+        assert_eq!(state.saved().seq, LEASE - 1);
+        state.reserve(3, 1);
+        assert_eq!((state.pending, state.saved().seq), (0, LEASE - 1));
+
+        drop(state);
+        fs::write(&path, "seq=x\n").unwrap();
+        assert!(SeqState::open(path.clone()).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
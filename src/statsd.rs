@@ -0,0 +1,178 @@
+//! Metrics pushed to a statsd or DogStatsD agent over UDP.
+//!
+//! Metrics are batched, one per line, into datagrams of at most
+//! [`MAX_DATAGRAM`] bytes. A batch is sent once it is full, or with the
+//! first metric added after it is [`FLUSH_INTERVAL`] old. Every metric name starts with `udp_bcast_ts.`.
+//! With `tags`, metrics carry DogStatsD `|#key:value` tags such as the
+//! sender, and a `multi` instance's name; plain statsd agents get the
+//! untagged aggregate.
+//!
+//! Metrics are best effort: a failed send is logged and dropped, never
+//! fatal.
+
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::log;
+use crate::send::bind_for;
+use crate::transmit;
+
+const PREFIX: &str = "udp_bcast_ts.";
+
+/// Largest batch, sized to avoid IP fragmentation on common networks.
+pub const MAX_DATAGRAM: usize = 1432;
+
+/// Longest a metric waits in a batch before it is sent.
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Settings for `--statsd`.
+#[derive(Clone, Debug)]
+pub struct StatsdConfig {
+    /// Agent address as `host:port`.
+    pub addr: String,
+    /// Add DogStatsD tags.
+    pub tags: bool,
+    /// Tag every metric with this `multi` instance name.
+    pub instance: Option<String>,
+}
+
+/// A batching statsd client.
+pub struct Statsd {
+    sock: UdpSocket,
+    dest: SocketAddr,
+    tags: bool,
+    instance: Option<String>,
+    batch: String,
+    batch_start: Instant,
+}
+
+impl Statsd {
+    /// Resolves the agent address once and binds a socket for it.
+    pub fn connect(cfg: &StatsdConfig) -> Result<Statsd, String> {
+        let dest = cfg
+            .addr
+            .to_socket_addrs()
+            .map_err(|e| format!("Failed to resolve statsd address {}: {e}", cfg.addr))?
+            .next()
+            .ok_or_else(|| format!("statsd address {} resolved to nothing", cfg.addr))?;
+        Ok(Statsd {
+            sock: bind_for(dest)?,
+            dest,
+            tags: cfg.tags,
+            instance: cfg.instance.clone(),
+            batch: String::with_capacity(MAX_DATAGRAM),
+            batch_start: Instant::now(),
+        })
+    }
+
+    /// Adds `n` to a counter.
+    pub fn count(&mut self, name: &str, n: u64, tags: &[(&str, &str)]) {
+        let tags = self.with_instance(tags);
+        let line = metric(name, &n.to_string(), "c", self.tags.then_some(&tags));
+        self.push(&line);
+    }
+
+    /// Records a timing in milliseconds.
+    pub fn timing_ms(&mut self, name: &str, ms: i64, tags: &[(&str, &str)]) {
+        let tags = self.with_instance(tags);
+        let line = metric(name, &ms.to_string(), "ms", self.tags.then_some(&tags));
+        self.push(&line);
+    }
+
+    /// Sets a gauge to its current value.
+    pub fn gauge(&mut self, name: &str, value: u64, tags: &[(&str, &str)]) {
+        let tags = self.with_instance(tags);
+        let line = metric(name, &value.to_string(), "g", self.tags.then_some(&tags));
+        self.push(&line);
+    }
+
+    /// `tags`, after the instance's name if there is one.
+    fn with_instance<'t>(&'t self, tags: &[(&'t str, &'t str)]) -> Vec<(&'t str, &'t str)> {
+        let instance = self.instance.as_deref().map(|name| ("instance", name));
+        instance.into_iter().chain(tags.iter().copied()).collect()
+    }
+
+    fn push(&mut self, line: &str) {
+        if !self.batch.is_empty() && self.batch.len() + 1 + line.len() > MAX_DATAGRAM {
+            self.flush();
+        }
+        if self.batch.is_empty() {
+            self.batch_start = Instant::now();
+        } else {
+            self.batch.push('\n');
+        }
+        self.batch.push_str(line);
+        if self.batch_start.elapsed() >= FLUSH_INTERVAL {
+            self.flush();
+        }
+    }
+
+    /// Sends the pending batch now.
+    pub fn flush(&mut self) {
+        if self.batch.is_empty() {
+            return;
+        }
+        if let Err(e) = transmit::send_to(&self.sock, self.batch.as_bytes(), self.dest) {
+            log::warn!("Failed to send metrics to {}: {e}", self.dest);
+        }
+        self.batch.clear();
+    }
+}
+
+impl Drop for Statsd {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Formats one metric line, with DogStatsD tags if `tags` is given.
+fn metric(name: &str, value: &str, ty: &str, tags: Option<&[(&str, &str)]>) -> String {
+    let mut line = format!("{PREFIX}{name}:{value}|{ty}");
+    if let Some(tags) = tags.filter(|t| !t.is_empty()) {
+        let tags: Vec<String> = tags.iter().map(|(k, v)| format!("{k}:{v}")).collect();
+        line.push_str("|#");
+        line.push_str(&tags.join(","));
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_statsd_and_dogstatsd_lines() {
+        let tags = [("sender", "10.0.0.5"), ("group", "3")];
+        assert_eq!(
+            metric("offset_ms", "-12", "ms", None),
+            "udp_bcast_ts.offset_ms:-12|ms"
+        );
+        assert_eq!(
+            metric("received", "1", "c", Some(&tags)),
+            "udp_bcast_ts.received:1|c|#sender:10.0.0.5,group:3"
+        );
+        assert_eq!(metric("sent", "1", "c", Some(&[])), "udp_bcast_ts.sent:1|c");
+    }
+
+    #[test]
+    fn batches_until_full() {
+        let agent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut statsd = Statsd::connect(&StatsdConfig {
+            addr: agent.local_addr().unwrap().to_string(),
+            tags: false,
+            instance: None,
+        })
+        .unwrap();
+        // 25-byte lines: 55 fit in one datagram, newlines included
+        for _ in 0..60 {
+            statsd.count("received", 1, &[]);
+        }
+        statsd.flush();
+
+        let mut buf = [0u8; 2048];
+        let first = agent.recv(&mut buf).unwrap();
+        assert_eq!(first, 55 * 26 - 1);
+        let second = agent.recv(&mut buf).unwrap();
+        assert_eq!(second, 5 * 26 - 1);
+    }
+}
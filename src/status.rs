@@ -0,0 +1,293 @@
+//! Health and status over HTTP for send mode (`--status-addr`).
+//!
+//! `GET /healthz` answers 200 while the send loop is doing its job: the
+//! last datagram went out within three intervals, a follower is standing
+//! by as it should, sending is paused over the control API, or every
+//! destination is in a `--blackout` window. Otherwise it
+//! answers 503, so a liveness probe can tell a loop that fails every tick
+//! from one that works. `GET /status` returns the counters behind that
+//! answer as JSON.
+//!
+//! The server is a minimal HTTP/1.0 responder on its own thread: one
+//! request per connection, answered in order. A slow client times out
+//! rather than holding up the probes behind it.
+
+use std::collections::BTreeMap;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::http;
+use crate::lateness::Lateness;
+use crate::log;
+use crate::sockerr::ErrorClass;
+
+/// Intervals without a successful send before `/healthz` fails.
+pub const MISSED_INTERVALS: u32 = 3;
+
+/// What the send loop reports, shared with the server thread.
+pub struct Health {
+    start: Instant,
+    state: Mutex<State>,
+}
+
+struct State {
+    interval: Duration,
+    active: bool,
+    paused: bool,
+    /// Every destination is in a `--blackout` window.
+    blackout: bool,
+    sent: u64,
+    withheld: u64,
+    errors: u64,
+    by_class: BTreeMap<ErrorClass, u64>,
+    last_ok: Option<Instant>,
+    last_error: Option<(Instant, String)>,
+    lateness: Lateness,
+}
+
+impl Health {
+    pub fn new(interval: Duration, now: Instant) -> Health {
+        Health {
+            start: now,
+            state: Mutex::new(State {
+                interval,
+                active: true,
+                paused: false,
+                blackout: false,
+                sent: 0,
+                withheld: 0,
+                errors: 0,
+                by_class: BTreeMap::new(),
+                last_ok: None,
+                last_error: None,
+                lateness: Lateness::default(),
+            }),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        // A panic elsewhere leaves the counters usable
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn sent(&self, now: Instant) {
+        let mut s = self.state();
+        s.sent += 1;
+        s.last_ok = Some(now);
+    }
+
+    pub fn failed(&self, now: Instant, class: ErrorClass, error: String) {
+        let mut s = self.state();
+        s.errors += 1;
+        *s.by_class.entry(class).or_default() += 1;
+        s.last_error = Some((now, error));
+    }
+
+    /// Records how late the loop's ticks have been so far.
+    pub fn set_lateness(&self, lateness: Lateness) {
+        self.state().lateness = lateness;
+    }
+
+    pub fn set_interval(&self, interval: Duration) {
+        self.state().interval = interval;
+    }
+
+    /// Records whether the loop is transmitting or standing by.
+    pub fn set_active(&self, now: Instant, active: bool) {
+        let mut s = self.state();
+        if active && !s.active {
+            // A promoted follower gets a fresh grace period
+            s.last_ok = Some(now);
+        }
+        s.active = active;
+    }
+
+    /// Records whether sending is paused over the control API.
+    pub fn set_paused(&self, now: Instant, paused: bool) {
+        let mut s = self.state();
+        if !paused && s.paused {
+            s.last_ok = Some(now);
+        }
+        s.paused = paused;
+    }
+
+    /// Records whether every destination is blacked out.
+    pub fn set_blackout(&self, now: Instant, blackout: bool) {
+        let mut s = self.state();
+        if !blackout && s.blackout {
+            s.last_ok = Some(now);
+        }
+        s.blackout = blackout;
+    }
+
+    /// Counts datagrams held back by a `--blackout` window.
+    pub fn withheld(&self, n: u64) {
+        self.state().withheld += n;
+    }
+
+    /// Whether the loop sent within [`MISSED_INTERVALS`] intervals, counting
+    /// from startup until the first send.
+    pub fn healthy(&self, now: Instant) -> bool {
+        let s = self.state();
+        let since = s.last_ok.unwrap_or(self.start);
+        !s.active
+            || s.paused
+            || s.blackout
+            || now.saturating_duration_since(since) <= s.interval * MISSED_INTERVALS
+    }
+
+    /// The `/status` document.
+    pub fn json(&self, now: Instant) -> String {
+        let healthy = self.healthy(now);
+        let s = self.state();
+        let age = |t: Instant| now.saturating_duration_since(t).as_millis().to_string();
+        let by_class: Vec<String> = ErrorClass::ALL
+            .iter()
+            .map(|c| format!("\"{c}\":{}", s.by_class.get(c).copied().unwrap_or(0)))
+            .collect();
+        format!(
+            "{{\"healthy\":{healthy},\"state\":\"{}\",\"uptime_s\":{},\"interval_ms\":{},\
+             \"packets_sent\":{},\"packets_withheld\":{},\"send_errors\":{},\"send_errors_by_class\":{{{}}},\
+             \"last_send_age_ms\":{},\
+             \"last_error\":{},\"last_error_age_ms\":{},\"ticks_late\":{},\"ticks_missed\":{},\
+             \"tick_lateness_mean_us\":{},\"tick_lateness_max_us\":{}}}\n",
+            match (s.active, s.paused, s.blackout) {
+                (_, true, _) => "paused",
+                (true, false, true) => "blackout",
+                (true, false, false) => "active",
+                (false, false, _) => "standby",
+            },
+            now.saturating_duration_since(self.start).as_secs(),
+            s.interval.as_millis(),
+            s.sent,
+            s.withheld,
+            s.errors,
+            by_class.join(","),
+            s.last_ok.map_or_else(|| "null".to_string(), age),
+            s.last_error
+                .as_ref()
+                .map_or_else(|| "null".to_string(), |(_, e)| json_string(e)),
+            s.last_error
+                .as_ref()
+                .map_or_else(|| "null".to_string(), |&(t, _)| age(t)),
+            s.lateness.late,
+            s.lateness.missed,
+            s.lateness.mean_us(),
+            s.lateness.max_us,
+        )
+    }
+}
+
+/// Quotes `s` as a JSON string.
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Listens on `addr` and serves `health` from a background thread,
+/// returning the address bound.
+pub fn serve(addr: SocketAddr, health: Arc<Health>) -> Result<SocketAddr, String> {
+    let listener = TcpListener::bind(addr)
+        .map_err(|e| format!("Failed to listen for status requests on {addr}: {e}"))?;
+    let local = listener.local_addr().unwrap_or(addr);
+    log::info!("Serving /healthz and /status on http://{local}");
+    log::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = respond(stream, &health) {
+                        log::debug!("Status request failed: {e}");
+                    }
+                }
+                Err(e) => log::warn!("Status connection failed: {e}"),
+            }
+        }
+    });
+    Ok(local)
+}
+
+fn respond(stream: TcpStream, health: &Health) -> std::io::Result<()> {
+    let request = http::read_request(&stream)?;
+    let now = Instant::now();
+    let (status, content_type, body) = match (request.method.as_str(), request.target.as_str()) {
+        ("GET", "/healthz") if health.healthy(now) => ("200 OK", "text/plain", "ok\n".to_string()),
+        ("GET", "/healthz") => (
+            "503 Service Unavailable",
+            "text/plain",
+            "not sending\n".to_string(),
+        ),
+        ("GET", "/status") => ("200 OK", "application/json", health.json(now)),
+        ("GET", _) => ("404 Not Found", "text/plain", "not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "method not allowed\n".to_string(),
+        ),
+    };
+    http::respond(&stream, status, content_type, &[], &body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: test\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn healthz_fails_after_three_missed_intervals() {
+        let t0 = Instant::now();
+        let health = Arc::new(Health::new(Duration::from_secs(1), t0));
+        let at = |s| t0 + Duration::from_secs(s);
+        // Startup grace, then the first send
+        assert!(health.healthy(at(3)));
+        assert!(!health.healthy(at(4)));
+        health.sent(at(4));
+        health.failed(
+            at(5),
+            ErrorClass::InterfaceDown,
+            "Network is \"down\"".to_string(),
+        );
+        assert!(health.healthy(at(7)));
+        assert!(!health.healthy(at(8)));
+        assert_eq!(
+            health.json(at(8)),
+            "{\"healthy\":false,\"state\":\"active\",\"uptime_s\":8,\"interval_ms\":1000,\
+             \"packets_sent\":1,\"packets_withheld\":0,\"send_errors\":1,\"send_errors_by_class\":{\"unreachable\":0,\
+             \"permission\":0,\"buffer_full\":0,\"interface_down\":1,\"other\":0},\
+             \"last_send_age_ms\":4000,\
+             \"last_error\":\"Network is \\\"down\\\"\",\"last_error_age_ms\":3000,\"ticks_late\":0,\
+             \"ticks_missed\":0,\"tick_lateness_mean_us\":0,\"tick_lateness_max_us\":0}\n"
+        );
+        // A follower standing by isn't failing
+        health.set_active(at(8), false);
+        assert!(health.healthy(at(20)));
+
+        let addr = serve("127.0.0.1:0".parse().unwrap(), Arc::clone(&health)).unwrap();
+        assert!(get(addr, "/healthz").starts_with("HTTP/1.0 200 OK\r\n"));
+        let status = get(addr, "/status");
+        assert!(status.contains("Content-Type: application/json"));
+        assert!(status.contains("\"state\":\"standby\""));
+        assert!(get(addr, "/nope").starts_with("HTTP/1.0 404"));
+        health.set_active(Instant::now() - Duration::from_secs(10), true);
+        assert!(get(addr, "/healthz").starts_with("HTTP/1.0 503"));
+    }
+}
@@ -0,0 +1,97 @@
+//! Sender clock step detection.
+//!
+//! v2 senders stamp each datagram with both their wall clock and a
+//! monotonic clock. Between two datagrams from one sender, both advance by
+//! the same amount unless the wall clock was stepped (e.g. by NTP), so the
+//! difference is the step. Network delay affects neither, which is what
+//! tells a stepped sender clock apart from a change in delay.
+//!
+//! Senders watch their own clocks the same way between ticks, and count
+//! the steps they see in the payload's era ([`EXT_ERA`]). A receiver that
+//! sees a sender's era change knows the step happened even if the
+//! datagrams around it were lost, and drops what it was averaging across
+//! it ([`Eras`]).
+//!
+//! [`EXT_ERA`]: crate::payload::EXT_ERA
+
+use std::collections::HashMap;
+
+/// Smallest step reported. Wall-clock timestamps are whole milliseconds
+/// and clocks drift a little between datagrams, so smaller differences
+/// are noise.
+pub const THRESHOLD_MS: i64 = 5;
+
+/// Something the wall and monotonic timestamps revealed about a sender.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The wall clock jumped by this many milliseconds (negative: back).
+    Step { step_ms: i64 },
+    /// The monotonic clock went backwards: the sender was restarted.
+    Restart,
+}
+
+/// The last pair of timestamps seen from each sender.
+#[derive(Default)]
+pub struct StepDetector {
+    last: HashMap<String, (u64, u64)>,
+}
+
+impl StepDetector {
+    pub fn observe(&mut self, sender: &str, ts_ms: u64, mono_us: u64) -> Option<Event> {
+        let (last_ms, last_mono_us) = self.last.insert(sender.to_string(), (ts_ms, mono_us))?;
+        if mono_us < last_mono_us {
+            return Some(Event::Restart);
+        }
+        let wall_ms = ts_ms as i64 - last_ms as i64;
+        let mono_ms = ((mono_us - last_mono_us) as f64 / 1000.0).round() as i64;
+        let step_ms = wall_ms - mono_ms;
+        (step_ms.abs() >= THRESHOLD_MS).then_some(Event::Step { step_ms })
+    }
+}
+
+/// The era each sender last announced.
+#[derive(Default)]
+pub struct Eras {
+    last: HashMap<String, u16>,
+}
+
+impl Eras {
+    /// Records the era of a datagram from `sender`, 0 if it carried none.
+    /// Returns the previous era if this one differs.
+    pub fn observe(&mut self, sender: &str, era: u16) -> Option<u16> {
+        match self.last.get_mut(sender) {
+            Some(last) if *last != era => Some(std::mem::replace(last, era)),
+            Some(_) => None,
+            None => {
+                self.last.insert(sender.to_string(), era);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steps_show_up_but_steady_clocks_do_not() {
+        let mut d = StepDetector::default();
+        assert_eq!(d.observe("a", 10_000, 0), None);
+        assert_eq!(d.observe("a", 11_001, 1_000_400), None);
+        // NTP stepped the sender's clock back by 250 ms
+        assert_eq!(
+            d.observe("a", 11_751, 2_000_400),
+            Some(Event::Step { step_ms: -250 })
+        );
+        assert_eq!(d.observe("a", 12_751, 3_000_400), None);
+        assert_eq!(d.observe("a", 13_000, 1_000), Some(Event::Restart));
+
+        // A sender's first era is no change, whatever it is
+        let mut eras = Eras::default();
+        assert_eq!(eras.observe("a", 0), None);
+        assert_eq!(eras.observe("b", 3), None);
+        assert_eq!(eras.observe("a", 1), Some(0));
+        assert_eq!(eras.observe("a", 1), None);
+    }
+}
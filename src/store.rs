@@ -0,0 +1,615 @@
+//! Long-term storage of receive observations (`recv --store`).
+//!
+//! `sqlite:<path>` keeps every accepted packet and per-sender aggregates
+//! in a SQLite database, for querying weeks of data with SQL rather than
+//! parsing logs. The schema (`PRAGMA user_version` 1) is:
+//!
+//! ```sql
+//! CREATE TABLE observations (
+//!     recv_unix_ms  INTEGER NOT NULL,  -- receive time
+//!     send_unix_ms  INTEGER NOT NULL,  -- the sender's timestamp
+//!     source        TEXT NOT NULL,     -- address:port it came from
+//!     sender        TEXT NOT NULL,     -- address, or address/group
+//!     group_id      INTEGER,
+//!     seq           INTEGER,
+//!     offset_ms     INTEGER NOT NULL,  -- recv_unix_ms - send_unix_ms
+//!     host_delay_us INTEGER,
+//!     fields        TEXT,              -- the sender's --fields, ';'-separated
+//!     annotation    TEXT
+//! );
+//! CREATE TABLE aggregates (
+//!     start_unix_ms      INTEGER NOT NULL,  -- window, aligned to its length
+//!     end_unix_ms        INTEGER NOT NULL,
+//!     sender             TEXT NOT NULL,
+//!     packets            INTEGER NOT NULL,
+//!     lost               INTEGER NOT NULL,  -- sequence numbers skipped
+//!     min_offset_ms      INTEGER NOT NULL,
+//!     mean_offset_ms     REAL NOT NULL,
+//!     max_offset_ms      INTEGER NOT NULL,
+//!     mean_host_delay_us REAL
+//! );
+//! ```
+//!
+//! with both tables indexed by time and by sender and time. Rows go in
+//! inside a transaction committed about once a second, so a crash loses
+//! at most that much, and rows older than the retention are deleted as
+//! each window is written. SQLite is the system library, linked with the
+//! `sqlite` feature; builds without it refuse `--store`.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::export::Observation;
+
+/// Default for `--store-aggregate-interval`.
+pub const DEFAULT_AGGREGATE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often rows are committed.
+const COMMIT_INTERVAL: Duration = Duration::from_secs(1);
+
+const SCHEMA_VERSION: i64 = 1;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS observations (
+        recv_unix_ms INTEGER NOT NULL, send_unix_ms INTEGER NOT NULL,
+        source TEXT NOT NULL, sender TEXT NOT NULL, group_id INTEGER, seq INTEGER,
+        offset_ms INTEGER NOT NULL, host_delay_us INTEGER, fields TEXT, annotation TEXT);
+    CREATE INDEX IF NOT EXISTS observations_time ON observations (recv_unix_ms);
+    CREATE INDEX IF NOT EXISTS observations_sender ON observations (sender, recv_unix_ms);
+    CREATE TABLE IF NOT EXISTS aggregates (
+        start_unix_ms INTEGER NOT NULL, end_unix_ms INTEGER NOT NULL, sender TEXT NOT NULL,
+        packets INTEGER NOT NULL, lost INTEGER NOT NULL, min_offset_ms INTEGER NOT NULL,
+        mean_offset_ms REAL NOT NULL, max_offset_ms INTEGER NOT NULL, mean_host_delay_us REAL);
+    CREATE INDEX IF NOT EXISTS aggregates_time ON aggregates (start_unix_ms);
+    CREATE INDEX IF NOT EXISTS aggregates_sender ON aggregates (sender, start_unix_ms);
+    PRAGMA user_version = 1;";
+
+const INSERT_OBSERVATION: &str =
+    "INSERT INTO observations VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)";
+const INSERT_AGGREGATE: &str = "INSERT INTO aggregates VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)";
+
+/// A value bound to a statement parameter.
+#[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+enum Value<'a> {
+    Null,
+    Int(i64),
+    Real(f64),
+    Text(&'a str),
+}
+
+/// Where `--store` keeps observations.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Backend {
+    Sqlite(String),
+}
+
+impl Backend {
+    /// Parses a `--store` value, e.g. `sqlite:/var/lib/beacon.db`.
+    pub fn parse(s: &str) -> Result<Backend, String> {
+        match s.split_once(':') {
+            Some(("sqlite", path)) if !path.is_empty() => Ok(Backend::Sqlite(path.to_string())),
+            _ => Err(format!("expected sqlite:<path>, got '{s}'")),
+        }
+    }
+}
+
+/// Settings for `--store`.
+#[derive(Clone, Debug)]
+pub struct StoreConfig {
+    pub backend: Backend,
+    /// Length of each aggregate window.
+    pub aggregate_interval: Duration,
+    /// Rows older than this are deleted; `None` keeps everything.
+    pub retention: Option<Duration>,
+}
+
+/// One sender's packets in the current window.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Aggregate {
+    packets: u64,
+    lost: u64,
+    min_offset_ms: i64,
+    max_offset_ms: i64,
+    offset_sum: i64,
+    host_delay_sum: u64,
+    host_delay_samples: u64,
+}
+
+impl Aggregate {
+    fn new(offset_ms: i64) -> Aggregate {
+        Aggregate {
+            packets: 0,
+            lost: 0,
+            min_offset_ms: offset_ms,
+            max_offset_ms: offset_ms,
+            offset_sum: 0,
+            host_delay_sum: 0,
+            host_delay_samples: 0,
+        }
+    }
+
+    fn add(&mut self, o: &Observation, lost: u64) {
+        self.packets += 1;
+        self.lost += lost;
+        self.min_offset_ms = self.min_offset_ms.min(o.offset_ms);
+        self.max_offset_ms = self.max_offset_ms.max(o.offset_ms);
+        self.offset_sum += o.offset_ms;
+        if let Some(us) = o.host_delay_us {
+            self.host_delay_sum += us;
+            self.host_delay_samples += 1;
+        }
+    }
+
+    fn mean_offset_ms(&self) -> f64 {
+        self.offset_sum as f64 / self.packets as f64
+    }
+
+    fn mean_host_delay_us(&self) -> Option<f64> {
+        (self.host_delay_samples > 0)
+            .then(|| self.host_delay_sum as f64 / self.host_delay_samples as f64)
+    }
+}
+
+/// Milliseconds since the Unix epoch; times before it count as 0.
+fn unix_ms(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Start of the window of `every_ms` that `ms` falls in.
+fn window_start(ms: u64, every_ms: u64) -> u64 {
+    ms - ms % every_ms
+}
+
+/// An open store, writing observations as they come and aggregates as
+/// each window ends.
+pub struct Store {
+    db: imp::Db,
+    path: String,
+    every_ms: u64,
+    retention: Option<Duration>,
+    window: u64,
+    aggregates: BTreeMap<String, Aggregate>,
+    /// Rows are being added inside a transaction begun at this time.
+    pending_since: Option<SystemTime>,
+}
+
+impl Store {
+    /// Opens (creating if need be) the database and its tables.
+    pub fn open(cfg: &StoreConfig, now: SystemTime) -> Result<Store, String> {
+        let Backend::Sqlite(path) = &cfg.backend;
+        let fail = |e: String| format!("Failed to open store {path}: {e}");
+        let db = imp::Db::open(path).map_err(fail)?;
+        let version = db.query_i64("PRAGMA user_version").map_err(fail)?;
+        if version > SCHEMA_VERSION {
+            return Err(fail(format!(
+                "schema version {version} is newer than this build's {SCHEMA_VERSION}"
+            )));
+        }
+        db.exec("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;")
+            .and_then(|_| db.exec(SCHEMA))
+            .map_err(fail)?;
+        let every_ms = cfg.aggregate_interval.as_millis().max(1) as u64;
+        let mut store = Store {
+            db,
+            path: path.clone(),
+            every_ms,
+            retention: cfg.retention,
+            window: window_start(unix_ms(now), every_ms),
+            aggregates: BTreeMap::new(),
+            pending_since: None,
+        };
+        store.prune(now)?;
+        Ok(store)
+    }
+
+    fn fail(&self, e: String) -> String {
+        format!("Failed to write store {}: {e}", self.path)
+    }
+
+    /// Adds one accepted packet from `sender`, after `lost` skipped
+    /// sequence numbers.
+    pub fn observe(&mut self, o: &Observation, sender: &str, lost: u64) -> Result<(), String> {
+        self.begin(SystemTime::now())?;
+        let source = o.src.to_string();
+        let fields = o.fields.join(";");
+        let result = self.db.insert(
+            INSERT_OBSERVATION,
+            &[
+                Value::Int(o.recv_ms as i64),
+                Value::Int(o.send_ms as i64),
+                Value::Text(&source),
+                Value::Text(sender),
+                o.group.map_or(Value::Null, |g| Value::Int(g.into())),
+                o.seq.map_or(Value::Null, |s| Value::Int(s.into())),
+                Value::Int(o.offset_ms),
+                o.host_delay_us
+                    .map_or(Value::Null, |us| Value::Int(us as i64)),
+                match fields.is_empty() {
+                    true => Value::Null,
+                    false => Value::Text(&fields),
+                },
+                o.annotation.as_deref().map_or(Value::Null, Value::Text),
+            ],
+        );
+        result.map_err(|e| self.fail(e))?;
+        self.aggregates
+            .entry(sender.to_string())
+            .or_insert_with(|| Aggregate::new(o.offset_ms))
+            .add(o, lost);
+        Ok(())
+    }
+
+    /// Commits what is pending once a second, and writes the aggregates
+    /// once their window has ended.
+    pub fn tick(&mut self, now: SystemTime) -> Result<(), String> {
+        if unix_ms(now) >= self.window + self.every_ms {
+            return self.flush(now);
+        }
+        match self.pending_since {
+            Some(since) if now.duration_since(since).unwrap_or_default() >= COMMIT_INTERVAL => {
+                self.commit()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes the aggregates of the window so far, prunes old rows and
+    /// commits, e.g. before exiting.
+    pub fn flush(&mut self, now: SystemTime) -> Result<(), String> {
+        let end = unix_ms(now).max(self.window);
+        let aggregates = std::mem::take(&mut self.aggregates);
+        if !aggregates.is_empty() {
+            self.begin(now)?;
+        }
+        for (sender, a) in &aggregates {
+            let result = self.db.insert(
+                INSERT_AGGREGATE,
+                &[
+                    Value::Int(self.window as i64),
+                    Value::Int(end.min(self.window + self.every_ms) as i64),
+                    Value::Text(sender),
+                    Value::Int(a.packets as i64),
+                    Value::Int(a.lost as i64),
+                    Value::Int(a.min_offset_ms),
+                    Value::Real(a.mean_offset_ms()),
+                    Value::Int(a.max_offset_ms),
+                    a.mean_host_delay_us().map_or(Value::Null, Value::Real),
+                ],
+            );
+            result.map_err(|e| self.fail(e))?;
+        }
+        self.window = window_start(end, self.every_ms);
+        self.prune(now)?;
+        self.commit()
+    }
+
+    /// Deletes rows older than the retention.
+    fn prune(&mut self, now: SystemTime) -> Result<(), String> {
+        let Some(cutoff) = self
+            .retention
+            .map(|r| unix_ms(now).saturating_sub(r.as_millis() as u64))
+        else {
+            return Ok(());
+        };
+        let sql = format!(
+            "DELETE FROM observations WHERE recv_unix_ms < {cutoff};
+             DELETE FROM aggregates WHERE end_unix_ms < {cutoff};"
+        );
+        self.db.exec(&sql).map_err(|e| self.fail(e))
+    }
+
+    fn begin(&mut self, now: SystemTime) -> Result<(), String> {
+        if self.pending_since.is_none() {
+            self.db.exec("BEGIN").map_err(|e| self.fail(e))?;
+            self.pending_since = Some(now);
+        }
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), String> {
+        if self.pending_since.take().is_some() {
+            self.db.exec("COMMIT").map_err(|e| self.fail(e))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sqlite")]
+mod imp {
+    use std::ffi::{c_char, c_int, c_void, CStr, CString};
+    use std::ptr;
+
+    #[repr(C)]
+    struct Sqlite3 {
+        _private: [u8; 0],
+    }
+
+    #[repr(C)]
+    struct Stmt {
+        _private: [u8; 0],
+    }
+
+    const SQLITE_OK: c_int = 0;
+    const SQLITE_ROW: c_int = 100;
+    const SQLITE_DONE: c_int = 101;
+    const SQLITE_OPEN_READWRITE: c_int = 0x02;
+    const SQLITE_OPEN_CREATE: c_int = 0x04;
+    /// SQLITE_TRANSIENT: SQLite copies bound text before the call returns.
+    const TRANSIENT: isize = -1;
+
+    #[link(name = "sqlite3")]
+    extern "C" {
+        fn sqlite3_open_v2(
+            filename: *const c_char,
+            db: *mut *mut Sqlite3,
+            flags: c_int,
+            vfs: *const c_char,
+        ) -> c_int;
+        fn sqlite3_close_v2(db: *mut Sqlite3) -> c_int;
+        fn sqlite3_errmsg(db: *mut Sqlite3) -> *const c_char;
+        fn sqlite3_exec(
+            db: *mut Sqlite3,
+            sql: *const c_char,
+            callback: *const c_void,
+            arg: *mut c_void,
+            errmsg: *mut *mut c_char,
+        ) -> c_int;
+        fn sqlite3_prepare_v2(
+            db: *mut Sqlite3,
+            sql: *const c_char,
+            len: c_int,
+            stmt: *mut *mut Stmt,
+            tail: *mut *const c_char,
+        ) -> c_int;
+        fn sqlite3_bind_null(stmt: *mut Stmt, i: c_int) -> c_int;
+        fn sqlite3_bind_int64(stmt: *mut Stmt, i: c_int, v: i64) -> c_int;
+        fn sqlite3_bind_double(stmt: *mut Stmt, i: c_int, v: f64) -> c_int;
+        fn sqlite3_bind_text(
+            stmt: *mut Stmt,
+            i: c_int,
+            v: *const c_char,
+            len: c_int,
+            destructor: isize,
+        ) -> c_int;
+        fn sqlite3_step(stmt: *mut Stmt) -> c_int;
+        fn sqlite3_column_int64(stmt: *mut Stmt, i: c_int) -> i64;
+        fn sqlite3_finalize(stmt: *mut Stmt) -> c_int;
+    }
+
+    use super::Value;
+
+    pub struct Db(*mut Sqlite3);
+
+    // SAFETY: the connection is only used from the thread owning the
+    // `Db`, and SQLite's default threading mode serializes anyway.
+    unsafe impl Send for Db {}
+
+    impl Db {
+        pub fn open(path: &str) -> Result<Db, String> {
+            let c_path = CString::new(path).map_err(|_| "path contains NUL".to_string())?;
+            let mut db = ptr::null_mut();
+            // SAFETY: `c_path` is NUL-terminated and `db` is written by the call.
+            let rc = unsafe {
+                sqlite3_open_v2(
+                    c_path.as_ptr(),
+                    &mut db,
+                    SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE,
+                    ptr::null(),
+                )
+            };
+            // A handle comes back even on failure, to read the error from
+            let db = Db(db);
+            if rc != SQLITE_OK {
+                return Err(db.error());
+            }
+            Ok(db)
+        }
+
+        fn error(&self) -> String {
+            if self.0.is_null() {
+                return "out of memory".to_string();
+            }
+            // SAFETY: the connection is open; the message is NUL-terminated
+            // and valid until the next call on it.
+            unsafe { CStr::from_ptr(sqlite3_errmsg(self.0)) }
+                .to_string_lossy()
+                .into_owned()
+        }
+
+        /// Runs one or more statements that return no rows.
+        pub fn exec(&self, sql: &str) -> Result<(), String> {
+            let sql = CString::new(sql).map_err(|_| "SQL contains NUL".to_string())?;
+            // SAFETY: the connection is open and `sql` NUL-terminated; no
+            // callback or error buffer is passed.
+            let rc = unsafe {
+                sqlite3_exec(
+                    self.0,
+                    sql.as_ptr(),
+                    ptr::null(),
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                )
+            };
+            match rc {
+                SQLITE_OK => Ok(()),
+                _ => Err(self.error()),
+            }
+        }
+
+        fn prepare(&self, sql: &str) -> Result<*mut Stmt, String> {
+            let mut stmt = ptr::null_mut();
+            // SAFETY: `sql` is valid for `len` bytes and `stmt` is written
+            // by the call.
+            let rc = unsafe {
+                sqlite3_prepare_v2(
+                    self.0,
+                    sql.as_ptr() as *const c_char,
+                    sql.len() as c_int,
+                    &mut stmt,
+                    ptr::null_mut(),
+                )
+            };
+            match rc {
+                SQLITE_OK => Ok(stmt),
+                _ => Err(self.error()),
+            }
+        }
+
+        /// Runs a statement returning one integer, e.g. a pragma.
+        pub fn query_i64(&self, sql: &str) -> Result<i64, String> {
+            let stmt = self.prepare(sql)?;
+            // SAFETY: `stmt` was prepared above and is finalized once.
+            unsafe {
+                let value = match sqlite3_step(stmt) {
+                    SQLITE_ROW => Ok(sqlite3_column_int64(stmt, 0)),
+                    _ => Err(self.error()),
+                };
+                sqlite3_finalize(stmt);
+                value
+            }
+        }
+
+        /// Runs an insert with `values` bound to its parameters in order.
+        pub fn insert(&self, sql: &str, values: &[Value]) -> Result<(), String> {
+            let stmt = self.prepare(sql)?;
+            // SAFETY: `stmt` was prepared above and is finalized once; text
+            // is copied by SQLite (TRANSIENT) before each bind returns.
+            unsafe {
+                let mut rc = SQLITE_OK;
+                for (i, value) in values.iter().enumerate() {
+                    let i = i as c_int + 1;
+                    rc = match *value {
+                        Value::Null => sqlite3_bind_null(stmt, i),
+                        Value::Int(v) => sqlite3_bind_int64(stmt, i, v),
+                        Value::Real(v) => sqlite3_bind_double(stmt, i, v),
+                        Value::Text(v) => sqlite3_bind_text(
+                            stmt,
+                            i,
+                            v.as_ptr() as *const c_char,
+                            v.len() as c_int,
+                            TRANSIENT,
+                        ),
+                    };
+                    if rc != SQLITE_OK {
+                        break;
+                    }
+                }
+                if rc == SQLITE_OK {
+                    rc = sqlite3_step(stmt);
+                }
+                let result = match rc {
+                    SQLITE_DONE => Ok(()),
+                    _ => Err(self.error()),
+                };
+                sqlite3_finalize(stmt);
+                result
+            }
+        }
+    }
+
+    impl Drop for Db {
+        fn drop(&mut self) {
+            // SAFETY: every statement is finalized as soon as it is used.
+            unsafe {
+                sqlite3_close_v2(self.0);
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+mod imp {
+    use super::Value;
+
+    pub enum Db {}
+
+    impl Db {
+        pub fn open(_path: &str) -> Result<Db, String> {
+            Err("SQLite support needs a build with --features sqlite".to_string())
+        }
+
+        pub fn exec(&self, _sql: &str) -> Result<(), String> {
+            match *self {}
+        }
+
+        pub fn query_i64(&self, _sql: &str) -> Result<i64, String> {
+            match *self {}
+        }
+
+        pub fn insert(&self, _sql: &str, _values: &[Value]) -> Result<(), String> {
+            match *self {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn observation(offset_ms: i64, host_delay_us: Option<u64>) -> Observation {
+        Observation {
+            recv_ms: 1_700_000_000_000,
+            send_ms: (1_700_000_000_000 - offset_ms) as u64,
+            src: "192.0.2.10:40000".parse().unwrap(),
+            group: None,
+            seq: Some(1),
+            offset_ms,
+            host_delay_us,
+            annotation: None,
+            fields: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn aggregates_summarize_a_window() {
+        assert_eq!(
+            Backend::parse("sqlite:/tmp/b.db"),
+            Ok(Backend::Sqlite("/tmp/b.db".into()))
+        );
+        assert!(Backend::parse("sqlite:").is_err());
+        assert!(Backend::parse("postgres://x").is_err());
+        assert_eq!(window_start(1_700_000_065_432, 60_000), 1_700_000_040_000);
+
+        let mut a = Aggregate::new(3);
+        a.add(&observation(3, Some(100)), 0);
+        a.add(&observation(-1, None), 2);
+        a.add(&observation(7, Some(300)), 0);
+        assert_eq!((a.packets, a.lost), (3, 2));
+        assert_eq!((a.min_offset_ms, a.max_offset_ms), (-1, 7));
+        assert_eq!(a.mean_offset_ms(), 3.0);
+        assert_eq!(a.mean_host_delay_us(), Some(200.0));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn rows_are_stored_and_pruned() {
+        let path =
+            std::env::temp_dir().join(format!("udp_bcast_ts-store-{}.db", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let cfg = StoreConfig {
+            backend: Backend::Sqlite(path.clone()),
+            aggregate_interval: Duration::from_secs(60),
+            retention: Some(Duration::from_secs(3600)),
+        };
+        let now = UNIX_EPOCH + Duration::from_millis(1_700_000_010_000);
+        let mut store = Store::open(&cfg, now).unwrap();
+        let mut old = observation(2, Some(50));
+        old.recv_ms -= 7_200_000;
+        store.observe(&old, "192.0.2.10", 0).unwrap();
+        store
+            .observe(&observation(4, None), "192.0.2.10", 1)
+            .unwrap();
+        store.flush(now).unwrap();
+
+        let count = |sql| store.db.query_i64(sql).unwrap();
+        assert_eq!(count("SELECT count(*) FROM observations"), 1);
+        assert_eq!(count("SELECT lost FROM aggregates"), 1);
+        assert_eq!(count("SELECT max_offset_ms FROM aggregates"), 4);
+        assert_eq!(count("PRAGMA user_version"), SCHEMA_VERSION);
+        drop(store);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{path}{suffix}"));
+        }
+    }
+}
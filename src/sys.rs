@@ -0,0 +1,1052 @@
+//! Platform socket features the standard library doesn't expose.
+//!
+//! Everything here degrades gracefully: on platforms without the feature
+//! the functions report it as unavailable rather than failing to build.
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::SystemTime;
+
+/// A received datagram's metadata.
+#[derive(Debug)]
+pub struct RecvMeta {
+    pub len: usize,
+    pub src: SocketAddr,
+    /// When the kernel received the datagram, if kernel timestamps are enabled.
+    pub kernel_time: Option<SystemTime>,
+    /// Index of the interface the datagram arrived on, if packet info is
+    /// enabled.
+    pub iface: Option<u32>,
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use super::RecvMeta;
+    use crate::filter::Insn;
+    use std::io;
+    use std::mem;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+    use std::os::unix::io::AsRawFd;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn setsockopt_int(
+        sock: &UdpSocket,
+        level: libc::c_int,
+        name: libc::c_int,
+        value: libc::c_int,
+    ) -> io::Result<()> {
+        // SAFETY: `value` outlives the call and its size is passed alongside it.
+        let rc = unsafe {
+            libc::setsockopt(
+                sock.as_raw_fd(),
+                level,
+                name,
+                &value as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if rc == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    pub fn enable_rx_timestamps(sock: &UdpSocket) -> io::Result<()> {
+        setsockopt_int(sock, libc::SOL_SOCKET, libc::SO_TIMESTAMPNS, 1)
+    }
+
+    pub fn set_dont_fragment(sock: &UdpSocket) -> io::Result<()> {
+        match sock.local_addr()? {
+            SocketAddr::V4(_) => setsockopt_int(
+                sock,
+                libc::IPPROTO_IP,
+                libc::IP_MTU_DISCOVER,
+                libc::IP_PMTUDISC_DO,
+            ),
+            SocketAddr::V6(_) => setsockopt_int(
+                sock,
+                libc::IPPROTO_IPV6,
+                libc::IPV6_MTU_DISCOVER,
+                libc::IPV6_PMTUDISC_DO,
+            ),
+        }
+    }
+
+    pub fn path_mtu(sock: &UdpSocket) -> io::Result<u32> {
+        let got = match sock.local_addr()? {
+            SocketAddr::V4(_) => super::int_option(sock, libc::IPPROTO_IP, libc::IP_MTU)?,
+            SocketAddr::V6(_) => super::int_option(sock, libc::IPPROTO_IPV6, libc::IPV6_MTU)?,
+        };
+        Ok(got.max(0) as u32)
+    }
+
+    pub fn enable_pktinfo(sock: &UdpSocket) -> io::Result<()> {
+        match sock.local_addr()? {
+            SocketAddr::V4(_) => setsockopt_int(sock, libc::IPPROTO_IP, libc::IP_PKTINFO, 1),
+            SocketAddr::V6(_) => {
+                // IPv4-mapped datagrams on a dual-stack socket still report
+                // through IP_PKTINFO; a v6-only socket refuses it
+                let _ = setsockopt_int(sock, libc::IPPROTO_IP, libc::IP_PKTINFO, 1);
+                setsockopt_int(sock, libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO, 1)
+            }
+        }
+    }
+
+    fn to_socket_addr(storage: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+        match storage.ss_family as libc::c_int {
+            libc::AF_INET => {
+                // SAFETY: the kernel filled in a sockaddr_in for AF_INET.
+                let a = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+                Ok(SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::from(u32::from_be(a.sin_addr.s_addr)),
+                    u16::from_be(a.sin_port),
+                )))
+            }
+            libc::AF_INET6 => {
+                // SAFETY: the kernel filled in a sockaddr_in6 for AF_INET6.
+                let a = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+                Ok(SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(a.sin6_addr.s6_addr),
+                    u16::from_be(a.sin6_port),
+                    a.sin6_flowinfo,
+                    a.sin6_scope_id,
+                )))
+            }
+            f => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected address family {f}"),
+            )),
+        }
+    }
+
+    pub fn recv(sock: &UdpSocket, buf: &mut [u8]) -> io::Result<RecvMeta> {
+        // SAFETY: all-zero is a valid bit pattern for these C structs.
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        // u64 elements keep the control buffer aligned for cmsghdr
+        let mut control = [0u64; 16];
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = &mut storage as *mut _ as *mut libc::c_void;
+        msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+
+        // SAFETY: every pointer in `msg` refers to a live buffer of the stated size.
+        let n = unsafe { libc::recvmsg(sock.as_raw_fd(), &mut msg, 0) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut kernel_time = None;
+        let mut iface = None;
+        // SAFETY: the CMSG_* macros walk the control buffer the kernel just
+        // filled, and each payload is read as the type its level and type
+        // say it holds.
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                let h = &*cmsg;
+                let data = libc::CMSG_DATA(cmsg);
+                match (h.cmsg_level, h.cmsg_type) {
+                    (libc::SOL_SOCKET, libc::SCM_TIMESTAMPNS) => {
+                        let ts = std::ptr::read_unaligned(data as *const libc::timespec);
+                        kernel_time =
+                            Some(UNIX_EPOCH + Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32));
+                    }
+                    (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                        let info = std::ptr::read_unaligned(data as *const libc::in_pktinfo);
+                        iface = Some(info.ipi_ifindex as u32);
+                    }
+                    (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                        let info = std::ptr::read_unaligned(data as *const libc::in6_pktinfo);
+                        iface = Some(info.ipi6_ifindex);
+                    }
+                    _ => {}
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+
+        Ok(RecvMeta {
+            len: n as usize,
+            src: to_socket_addr(&storage)?,
+            kernel_time,
+            iface,
+        })
+    }
+
+    pub fn attach_filter(sock: &UdpSocket, prog: &[Insn]) -> io::Result<()> {
+        let mut filter: Vec<libc::sock_filter> = prog
+            .iter()
+            .map(|i| libc::sock_filter {
+                code: i.code,
+                jt: i.jt,
+                jf: i.jf,
+                k: i.k,
+            })
+            .collect();
+        let fprog = libc::sock_fprog {
+            len: filter.len() as libc::c_ushort,
+            filter: filter.as_mut_ptr(),
+        };
+        // SAFETY: `fprog` points at `filter`, which outlives the call; the
+        // kernel copies the program.
+        let rc = unsafe {
+            libc::setsockopt(
+                sock.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_ATTACH_FILTER,
+                &fprog as *const libc::sock_fprog as *const libc::c_void,
+                mem::size_of::<libc::sock_fprog>() as libc::socklen_t,
+            )
+        };
+        if rc == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    fn to_sockaddr(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+        // SAFETY: all-zero is a valid sockaddr_storage.
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let len = match addr {
+            SocketAddr::V4(a) => {
+                // SAFETY: sockaddr_storage is large and aligned enough for any sockaddr.
+                let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = a.port().to_be();
+                sin.sin_addr.s_addr = u32::from(*a.ip()).to_be();
+                mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(a) => {
+                // SAFETY: as above.
+                let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = a.port().to_be();
+                sin6.sin6_addr.s6_addr = a.ip().octets();
+                sin6.sin6_flowinfo = a.flowinfo();
+                sin6.sin6_scope_id = a.scope_id();
+                mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+        (storage, len as libc::socklen_t)
+    }
+
+    pub fn enable_txtime(sock: &UdpSocket) -> io::Result<()> {
+        let txtime = libc::sock_txtime {
+            clockid: libc::CLOCK_TAI,
+            flags: 0,
+        };
+        // SAFETY: `txtime` outlives the call and its size is passed alongside it.
+        let rc = unsafe {
+            libc::setsockopt(
+                sock.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_TXTIME,
+                &txtime as *const libc::sock_txtime as *const libc::c_void,
+                mem::size_of::<libc::sock_txtime>() as libc::socklen_t,
+            )
+        };
+        if rc == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    pub fn clock_tai_ns() -> io::Result<u64> {
+        // SAFETY: all-zero is a valid timespec, filled in by the call.
+        let mut ts: libc::timespec = unsafe { mem::zeroed() };
+        if unsafe { libc::clock_gettime(libc::CLOCK_TAI, &mut ts) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64)
+    }
+
+    /// Room for one SCM_TXTIME control message.
+    const TXTIME_SPACE: usize = 32;
+
+    pub fn send_batch(
+        sock: &UdpSocket,
+        msgs: &[(&[u8], SocketAddr)],
+        txtime: Option<u64>,
+    ) -> io::Result<Vec<usize>> {
+        let msgs = &msgs[..msgs.len().min(libc::UIO_MAXIOV as usize)];
+        let mut names: Vec<_> = msgs.iter().map(|&(_, dest)| to_sockaddr(dest)).collect();
+        let mut iovs: Vec<libc::iovec> = msgs
+            .iter()
+            .map(|&(payload, _)| libc::iovec {
+                iov_base: payload.as_ptr() as *mut libc::c_void,
+                iov_len: payload.len(),
+            })
+            .collect();
+        // u64 elements keep each control buffer aligned for cmsghdr
+        let mut controls =
+            vec![[0u64; TXTIME_SPACE / 8]; if txtime.is_some() { msgs.len() } else { 0 }];
+        let mut hdrs: Vec<libc::mmsghdr> = names
+            .iter_mut()
+            .zip(iovs.iter_mut())
+            .enumerate()
+            .map(|(i, ((name, name_len), iov))| {
+                // SAFETY: all-zero is a valid mmsghdr.
+                let mut h: libc::mmsghdr = unsafe { mem::zeroed() };
+                h.msg_hdr.msg_name = name as *mut _ as *mut libc::c_void;
+                h.msg_hdr.msg_namelen = *name_len;
+                h.msg_hdr.msg_iov = iov;
+                h.msg_hdr.msg_iovlen = 1;
+                if let (Some(at), Some(control)) = (txtime, controls.get_mut(i)) {
+                    // SAFETY: the buffer has room for one cmsghdr and a u64,
+                    // as CMSG_SPACE(8) is at most TXTIME_SPACE.
+                    unsafe {
+                        h.msg_hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+                        h.msg_hdr.msg_controllen = libc::CMSG_SPACE(8) as _;
+                        let cmsg = libc::CMSG_FIRSTHDR(&h.msg_hdr);
+                        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+                        (*cmsg).cmsg_type = libc::SCM_TXTIME;
+                        (*cmsg).cmsg_len = libc::CMSG_LEN(8) as _;
+                        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u64, at);
+                    }
+                }
+                h
+            })
+            .collect();
+
+        // SAFETY: every header points at a live address and payload of the
+        // stated sizes; the kernel only reads the payloads.
+        let n = unsafe {
+            libc::sendmmsg(
+                sock.as_raw_fd(),
+                hdrs.as_mut_ptr(),
+                hdrs.len() as libc::c_uint,
+                0,
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(hdrs[..n as usize]
+            .iter()
+            .map(|h| h.msg_len as usize)
+            .collect())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use super::RecvMeta;
+    use std::io;
+    use std::net::UdpSocket;
+
+    pub fn enable_rx_timestamps(_sock: &UdpSocket) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "kernel receive timestamps are only supported on Linux",
+        ))
+    }
+
+    pub fn enable_pktinfo(_sock: &UdpSocket) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "receiving interface info is only supported on Linux",
+        ))
+    }
+
+    pub fn set_dont_fragment(_sock: &UdpSocket) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "setting don't-fragment is only supported on Linux",
+        ))
+    }
+
+    pub fn path_mtu(_sock: &UdpSocket) -> io::Result<u32> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "reading the path MTU is only supported on Linux",
+        ))
+    }
+
+    pub fn recv(sock: &UdpSocket, buf: &mut [u8]) -> io::Result<RecvMeta> {
+        let (len, src) = sock.recv_from(buf)?;
+        Ok(RecvMeta {
+            len,
+            src,
+            kernel_time: None,
+            iface: None,
+        })
+    }
+
+    pub fn attach_filter(_sock: &UdpSocket, _prog: &[crate::filter::Insn]) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "socket filters are only supported on Linux",
+        ))
+    }
+
+    pub fn enable_txtime(_sock: &UdpSocket) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "transmit times (SO_TXTIME) are only supported on Linux",
+        ))
+    }
+
+    pub fn clock_tai_ns() -> io::Result<u64> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "CLOCK_TAI is only read on Linux",
+        ))
+    }
+
+    pub fn send_batch(
+        _sock: &UdpSocket,
+        _msgs: &[(&[u8], std::net::SocketAddr)],
+        _txtime: Option<u64>,
+    ) -> io::Result<Vec<usize>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "batched sends are only supported on Linux",
+        ))
+    }
+}
+
+/// Looks up an interface's index by name, for IPv6 zones.
+#[cfg(unix)]
+pub fn interface_index(name: &str) -> io::Result<u32> {
+    let name = std::ffi::CString::new(name)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "NUL in interface name"))?;
+    // SAFETY: `name` is a valid NUL-terminated string for the duration of the call.
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(io::Error::last_os_error()),
+        n => Ok(n),
+    }
+}
+
+/// Looks up an interface's name by index.
+#[cfg(unix)]
+pub fn interface_name(index: u32) -> io::Result<String> {
+    let mut name = [0 as libc::c_char; libc::IF_NAMESIZE];
+    // SAFETY: `name` has room for IF_NAMESIZE bytes, as if_indextoname requires.
+    let got = unsafe { libc::if_indextoname(index, name.as_mut_ptr()) };
+    if got.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: on success `name` holds a NUL-terminated string.
+    let name = unsafe { std::ffi::CStr::from_ptr(name.as_ptr()) };
+    Ok(name.to_string_lossy().into_owned())
+}
+
+/// Looks up an interface's name by index.
+#[cfg(not(unix))]
+pub fn interface_name(_index: u32) -> io::Result<String> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "interface names are not supported on this platform",
+    ))
+}
+
+/// Looks up an interface's index by name, for IPv6 zones.
+#[cfg(windows)]
+pub fn interface_index(name: &str) -> io::Result<u32> {
+    use windows_sys::Win32::NetworkManagement::IpHelper::if_nametoindex;
+
+    let name = std::ffi::CString::new(name)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "NUL in interface name"))?;
+    // SAFETY: `name` is a valid NUL-terminated string for the duration of the call.
+    match unsafe { if_nametoindex(name.as_ptr() as *const u8) } {
+        0 => Err(io::Error::new(io::ErrorKind::NotFound, "no such interface")),
+        n => Ok(n),
+    }
+}
+
+/// Looks up an interface's index by name, for IPv6 zones.
+#[cfg(not(any(unix, windows)))]
+pub fn interface_index(_name: &str) -> io::Result<u32> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "interface names are not supported on this platform; use a numeric zone",
+    ))
+}
+
+/// Socket options letting other sockets bind the same port.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Reuse {
+    /// SO_REUSEADDR
+    pub addr: bool,
+    /// SO_REUSEPORT, where available
+    pub port: bool,
+}
+
+/// Binds `addr` with the `reuse` options set first, so that other sockets
+/// setting them too can bind the same port.
+#[cfg(unix)]
+pub fn bind_reusable(addr: SocketAddr, reuse: Reuse) -> io::Result<UdpSocket> {
+    use std::mem;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    let set = |sock: &UdpSocket, name: libc::c_int| {
+        let one: libc::c_int = 1;
+        // SAFETY: `one` outlives the call and its size is passed alongside it.
+        let rc = unsafe {
+            libc::setsockopt(
+                sock.as_raw_fd(),
+                libc::SOL_SOCKET,
+                name,
+                &one as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if rc == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    };
+
+    let family = match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    // SAFETY: a plain socket(2) call; the descriptor is owned by `sock` at once.
+    let fd = unsafe { libc::socket(family, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let sock = unsafe { UdpSocket::from_raw_fd(fd) };
+    if reuse.addr {
+        set(&sock, libc::SO_REUSEADDR)?;
+    }
+    #[cfg(not(any(target_os = "solaris", target_os = "illumos")))]
+    if reuse.port {
+        set(&sock, libc::SO_REUSEPORT)?;
+    }
+    #[cfg(any(target_os = "solaris", target_os = "illumos"))]
+    if reuse.port {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "SO_REUSEPORT is not supported on this platform",
+        ));
+    }
+
+    let rc = match addr {
+        SocketAddr::V4(a) => {
+            // SAFETY: all-zero is a valid sockaddr_in.
+            let mut sa: libc::sockaddr_in = unsafe { mem::zeroed() };
+            sa.sin_family = libc::AF_INET as libc::sa_family_t;
+            sa.sin_port = a.port().to_be();
+            sa.sin_addr.s_addr = u32::from_ne_bytes(a.ip().octets());
+            // SAFETY: `sa` is a live sockaddr_in of the stated size.
+            unsafe {
+                libc::bind(
+                    sock.as_raw_fd(),
+                    &sa as *const libc::sockaddr_in as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                )
+            }
+        }
+        SocketAddr::V6(a) => {
+            // SAFETY: all-zero is a valid sockaddr_in6.
+            let mut sa: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+            sa.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sa.sin6_port = a.port().to_be();
+            sa.sin6_flowinfo = a.flowinfo();
+            sa.sin6_addr.s6_addr = a.ip().octets();
+            sa.sin6_scope_id = a.scope_id();
+            // SAFETY: `sa` is a live sockaddr_in6 of the stated size.
+            unsafe {
+                libc::bind(
+                    sock.as_raw_fd(),
+                    &sa as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+                )
+            }
+        }
+    };
+    if rc == 0 {
+        Ok(sock)
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Binds `addr`. Without the Unix socket options the port can't be
+/// shared, so asking for either fails.
+#[cfg(not(unix))]
+pub fn bind_reusable(addr: SocketAddr, reuse: Reuse) -> io::Result<UdpSocket> {
+    if reuse != Reuse::default() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "sharing a port is only supported on Unix",
+        ));
+    }
+    UdpSocket::bind(addr)
+}
+
+/// Binds `0.0.0.0:port` so that other processes can bind it too
+/// (SO_REUSEADDR and, where available, SO_REUSEPORT), as well-known
+/// multicast ports such as mDNS's require.
+#[cfg(unix)]
+pub fn bind_shared_v4(port: u16) -> io::Result<UdpSocket> {
+    let reuse = Reuse {
+        addr: true,
+        port: cfg!(not(any(target_os = "solaris", target_os = "illumos"))),
+    };
+    bind_reusable(
+        SocketAddr::new(std::net::Ipv4Addr::UNSPECIFIED.into(), port),
+        reuse,
+    )
+}
+
+/// Binds `0.0.0.0:port`. Without the Unix socket options this can't share
+/// the port, so it fails if another process holds it exclusively.
+#[cfg(not(unix))]
+pub fn bind_shared_v4(port: u16) -> io::Result<UdpSocket> {
+    UdpSocket::bind((std::net::Ipv4Addr::UNSPECIFIED, port))
+}
+
+/// The host's name, without any domain.
+#[cfg(unix)]
+pub fn hostname() -> io::Result<String> {
+    let mut buf = [0u8; 256];
+    // SAFETY: `buf` is writable for the length passed.
+    let rc = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let end = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    let name = String::from_utf8_lossy(&buf[..end]);
+    Ok(name.split('.').next().unwrap_or_default().to_string())
+}
+
+/// The host's name, without any domain.
+#[cfg(not(unix))]
+pub fn hostname() -> io::Result<String> {
+    std::env::var("COMPUTERNAME").map_err(|e| io::Error::new(io::ErrorKind::NotFound, e))
+}
+
+/// The local time `unix_secs` seconds after the epoch, in the zone `TZ`
+/// names or else the system's, as year, month, day, hour, minute and
+/// second.
+#[cfg(unix)]
+pub fn local_time(unix_secs: i64) -> io::Result<[i64; 6]> {
+    let t = unix_secs as libc::time_t;
+    // SAFETY: tm is plain data, for which all zeroes is valid.
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    // SAFETY: both pointers are to live values of the right types.
+    if unsafe { libc::localtime_r(&t, &mut tm) }.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    Ok([
+        i64::from(tm.tm_year) + 1900,
+        i64::from(tm.tm_mon) + 1,
+        i64::from(tm.tm_mday),
+        i64::from(tm.tm_hour),
+        i64::from(tm.tm_min),
+        i64::from(tm.tm_sec),
+    ])
+}
+
+/// The local time `unix_secs` seconds after the epoch.
+#[cfg(not(unix))]
+pub fn local_time(_unix_secs: i64) -> io::Result<[i64; 6]> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "local time is only available on Unix",
+    ))
+}
+
+/// A kernel socket buffer.
+#[derive(Clone, Copy, Debug)]
+pub enum Buffer {
+    Send,
+    Recv,
+}
+
+/// Sizes a socket buffer (SO_SNDBUF/SO_RCVBUF) and returns the size the
+/// kernel settled on. That may differ from the request: Linux doubles it
+/// for bookkeeping and caps it at `net.core.wmem_max`/`rmem_max`.
+#[cfg(unix)]
+pub fn set_buffer_size(sock: &UdpSocket, buffer: Buffer, bytes: u32) -> io::Result<usize> {
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    let name = match buffer {
+        Buffer::Send => libc::SO_SNDBUF,
+        Buffer::Recv => libc::SO_RCVBUF,
+    };
+    let value = bytes.min(i32::MAX as u32) as libc::c_int;
+    // SAFETY: `value` outlives the call and its size is passed alongside it.
+    let rc = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            libc::SOL_SOCKET,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    buffer_size(sock, buffer)
+}
+
+/// Reads an integer socket option.
+#[cfg(unix)]
+fn int_option(sock: &UdpSocket, level: libc::c_int, name: libc::c_int) -> io::Result<i32> {
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    let mut got: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: `got` and `len` are live and `len` holds the size of `got`.
+    let rc = unsafe {
+        libc::getsockopt(
+            sock.as_raw_fd(),
+            level,
+            name,
+            &mut got as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(got)
+}
+
+/// The size of a socket buffer (SO_SNDBUF/SO_RCVBUF), as the kernel
+/// accounts it.
+#[cfg(unix)]
+pub fn buffer_size(sock: &UdpSocket, buffer: Buffer) -> io::Result<usize> {
+    let name = match buffer {
+        Buffer::Send => libc::SO_SNDBUF,
+        Buffer::Recv => libc::SO_RCVBUF,
+    };
+    Ok(int_option(sock, libc::SOL_SOCKET, name)?.max(0) as usize)
+}
+
+/// The size of a socket buffer (SO_SNDBUF/SO_RCVBUF).
+#[cfg(not(unix))]
+pub fn buffer_size(_sock: &UdpSocket, _buffer: Buffer) -> io::Result<usize> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reading socket buffer sizes is not supported on this platform",
+    ))
+}
+
+/// The traffic class outgoing datagrams are marked with: IP_TOS for an
+/// IPv4 socket, IPV6_TCLASS for an IPv6 one. The DSCP is its upper six bits.
+#[cfg(unix)]
+pub fn traffic_class(sock: &UdpSocket) -> io::Result<u8> {
+    let got = match sock.local_addr()? {
+        SocketAddr::V4(_) => int_option(sock, libc::IPPROTO_IP, libc::IP_TOS)?,
+        SocketAddr::V6(_) => int_option(sock, libc::IPPROTO_IPV6, libc::IPV6_TCLASS)?,
+    };
+    // -1 asks for the kernel default, which is 0
+    Ok(got.max(0) as u8)
+}
+
+/// The traffic class outgoing datagrams are marked with.
+#[cfg(not(unix))]
+pub fn traffic_class(_sock: &UdpSocket) -> io::Result<u8> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reading the traffic class is not supported on this platform",
+    ))
+}
+
+/// The hop limit of unicast or multicast datagrams from an IPv6 socket,
+/// the counterpart of [`UdpSocket::ttl`].
+#[cfg(unix)]
+pub fn hop_limit_v6(sock: &UdpSocket, multicast: bool) -> io::Result<u32> {
+    let name = if multicast {
+        libc::IPV6_MULTICAST_HOPS
+    } else {
+        libc::IPV6_UNICAST_HOPS
+    };
+    let got = int_option(sock, libc::IPPROTO_IPV6, name)?;
+    // -1 stands for the default: the route's for unicast, 1 for multicast
+    Ok(match got {
+        0.. => got as u32,
+        _ if multicast => 1,
+        _ => 64,
+    })
+}
+
+/// The hop limit of unicast or multicast datagrams from an IPv6 socket.
+#[cfg(not(unix))]
+pub fn hop_limit_v6(_sock: &UdpSocket, _multicast: bool) -> io::Result<u32> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reading the hop limit is not supported on this platform",
+    ))
+}
+
+/// Sizes a socket buffer (SO_SNDBUF/SO_RCVBUF) and returns the size the
+/// kernel settled on.
+#[cfg(windows)]
+pub fn set_buffer_size(sock: &UdpSocket, buffer: Buffer, bytes: u32) -> io::Result<usize> {
+    use std::os::windows::io::AsRawSocket;
+    use windows_sys::Win32::Networking::WinSock::{
+        getsockopt, setsockopt, SOCKET_ERROR, SOL_SOCKET, SO_RCVBUF, SO_SNDBUF,
+    };
+
+    let name = match buffer {
+        Buffer::Send => SO_SNDBUF,
+        Buffer::Recv => SO_RCVBUF,
+    };
+    let raw = sock.as_raw_socket() as usize;
+    let value = bytes.min(i32::MAX as u32) as i32;
+    // SAFETY: `value` outlives the call and its size is passed alongside it.
+    let rc = unsafe { setsockopt(raw, SOL_SOCKET, name, &value as *const i32 as *const u8, 4) };
+    if rc == SOCKET_ERROR {
+        return Err(io::Error::last_os_error());
+    }
+    let mut got: i32 = 0;
+    let mut len: i32 = 4;
+    // SAFETY: `got` and `len` are live and `len` holds the size of `got`.
+    let rc = unsafe {
+        getsockopt(
+            raw,
+            SOL_SOCKET,
+            name,
+            &mut got as *mut i32 as *mut u8,
+            &mut len,
+        )
+    };
+    if rc == SOCKET_ERROR {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(got.max(0) as usize)
+}
+
+/// Sizes a socket buffer (SO_SNDBUF/SO_RCVBUF).
+#[cfg(not(any(unix, windows)))]
+pub fn set_buffer_size(_sock: &UdpSocket, _buffer: Buffer, _bytes: u32) -> io::Result<usize> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "socket buffer sizes are not supported on this platform",
+    ))
+}
+
+/// Runs the calling thread under SCHED_FIFO at `priority` (1-99), so it
+/// preempts ordinary threads as soon as it is runnable. Needs root or
+/// CAP_SYS_NICE.
+#[cfg(target_os = "linux")]
+pub fn set_fifo_priority(priority: i32) -> io::Result<()> {
+    let param = libc::sched_param {
+        sched_priority: priority,
+    };
+    // SAFETY: `param` is a valid sched_param for the duration of the call.
+    let rc = unsafe { libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param) };
+    match rc {
+        0 => Ok(()),
+        e => Err(io::Error::from_raw_os_error(e)),
+    }
+}
+
+/// Runs the calling thread under SCHED_FIFO at `priority` (1-99).
+#[cfg(not(target_os = "linux"))]
+pub fn set_fifo_priority(_priority: i32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "realtime scheduling is only supported on Linux",
+    ))
+}
+
+/// Restricts the calling thread to one CPU core.
+#[cfg(target_os = "linux")]
+pub fn pin_to_cpu(core: usize) -> io::Result<()> {
+    if core >= libc::CPU_SETSIZE as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "core number out of range",
+        ));
+    }
+    // SAFETY: all-zero is an empty cpu_set_t, and `core` is within it.
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    unsafe { libc::CPU_SET(core, &mut set) };
+    // SAFETY: `set` is a live cpu_set_t of the stated size; 0 is this thread.
+    let rc = unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) };
+    if rc == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Restricts the calling thread to one CPU core.
+#[cfg(not(target_os = "linux"))]
+pub fn pin_to_cpu(_core: usize) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "CPU affinity is only supported on Linux",
+    ))
+}
+
+#[cfg(unix)]
+static SIGUSR1_COUNT: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+#[cfg(unix)]
+extern "C" fn on_sigusr1(_signal: libc::c_int) {
+    SIGUSR1_COUNT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+}
+
+/// Counts SIGUSR1 for [`sigusr1_count`] instead of letting it end the
+/// process. System calls it interrupts are restarted.
+#[cfg(unix)]
+pub fn watch_sigusr1() -> io::Result<()> {
+    // SAFETY: all-zero is a valid sigaction, filled in before use; the
+    // handler only stores to an atomic, which is async-signal-safe.
+    let rc = unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_sigusr1 as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGUSR1, &action, std::ptr::null_mut())
+    };
+    if rc == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Counts SIGUSR1 for [`sigusr1_count`].
+#[cfg(not(unix))]
+pub fn watch_sigusr1() -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SIGUSR1 is only available on Unix",
+    ))
+}
+
+/// How many times SIGUSR1 has arrived; each loop watching for it keeps
+/// the count it last saw, so every `multi` instance sees each signal.
+#[cfg(unix)]
+pub fn sigusr1_count() -> u64 {
+    SIGUSR1_COUNT.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+/// How many times SIGUSR1 has arrived.
+#[cfg(not(unix))]
+pub fn sigusr1_count() -> u64 {
+    0
+}
+
+#[cfg(unix)]
+static TERMINATION_SEEN: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn on_termination(_signal: libc::c_int) {
+    TERMINATION_SEEN.store(true, std::sync::atomic::Ordering::Relaxed);
+}
+
+/// Notes SIGINT and SIGTERM for [`terminating`] instead of letting
+/// them end the process. System calls they interrupt fail with
+/// `Interrupted`, so a blocked loop gets to check.
+#[cfg(unix)]
+pub fn watch_termination() -> io::Result<()> {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        // SAFETY: as in watch_sigusr1.
+        let rc = unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction =
+                on_termination as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signal, &action, std::ptr::null_mut())
+        };
+        if rc != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Notes SIGINT and SIGTERM for [`terminating`].
+#[cfg(not(unix))]
+pub fn watch_termination() -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "termination signals are only caught on Unix",
+    ))
+}
+
+/// Whether SIGINT or SIGTERM arrived.
+#[cfg(unix)]
+pub fn terminating() -> bool {
+    TERMINATION_SEEN.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+/// Whether SIGINT or SIGTERM arrived.
+#[cfg(not(unix))]
+pub fn terminating() -> bool {
+    false
+}
+
+/// Asks the kernel to timestamp received datagrams (SO_TIMESTAMPNS).
+pub fn enable_rx_timestamps(sock: &UdpSocket) -> io::Result<()> {
+    imp::enable_rx_timestamps(sock)
+}
+
+/// Asks the kernel to report the interface each datagram arrived on
+/// (IP_PKTINFO/IPV6_PKTINFO).
+pub fn enable_pktinfo(sock: &UdpSocket) -> io::Result<()> {
+    imp::enable_pktinfo(sock)
+}
+
+/// Sets the don't-fragment bit on outgoing datagrams (IP_MTU_DISCOVER or
+/// IPV6_MTU_DISCOVER set to "do"), so one larger than the path MTU fails
+/// to send with EMSGSIZE instead of going out in fragments.
+pub fn set_dont_fragment(sock: &UdpSocket) -> io::Result<()> {
+    imp::set_dont_fragment(sock)
+}
+
+/// The MTU of the route a connected socket sends on (IP_MTU/IPV6_MTU):
+/// the interface's, or less if the kernel has learned a smaller path MTU.
+pub fn path_mtu(sock: &UdpSocket) -> io::Result<u32> {
+    imp::path_mtu(sock)
+}
+
+/// Receives one datagram, along with its kernel timestamp and receiving
+/// interface if enabled.
+pub fn recv(sock: &UdpSocket, buf: &mut [u8]) -> io::Result<RecvMeta> {
+    imp::recv(sock, buf)
+}
+
+/// Sends a prefix of `msgs` in one system call (sendmmsg), returning the
+/// length sent of each datagram that went out, each to leave at `txtime`
+/// (CLOCK_TAI nanoseconds) if given. Fails if the first one can't be
+/// sent, or with [`io::ErrorKind::Unsupported`] where batching isn't
+/// available.
+pub fn send_batch(
+    sock: &UdpSocket,
+    msgs: &[(&[u8], SocketAddr)],
+    txtime: Option<u64>,
+) -> io::Result<Vec<usize>> {
+    imp::send_batch(sock, msgs, txtime)
+}
+
+/// Lets datagrams sent on `sock` carry the time they should leave, as
+/// CLOCK_TAI nanoseconds (SO_TXTIME), for the ETF qdisc to hold them until
+/// then. Setting a clock other than CLOCK_MONOTONIC needs CAP_NET_ADMIN.
+pub fn enable_txtime(sock: &UdpSocket) -> io::Result<()> {
+    imp::enable_txtime(sock)
+}
+
+/// The kernel's TAI clock, in nanoseconds, as SO_TXTIME times are given.
+pub fn clock_tai_ns() -> io::Result<u64> {
+    imp::clock_tai_ns()
+}
+
+/// Attaches a classic BPF program to the socket (SO_ATTACH_FILTER), so the
+/// kernel drops the datagrams it rejects.
+pub fn attach_filter(sock: &UdpSocket, prog: &[crate::filter::Insn]) -> io::Result<()> {
+    imp::attach_filter(sock, prog)
+}
@@ -0,0 +1,226 @@
+//! How closely this host keeps the send loop's schedule (`timing-report`).
+//!
+//! The send loop runs as `send` would, with the same pacing, `--sched` and
+//! `--cpu-affinity`, for a set time, but each datagram goes to a sink that
+//! drops it: there is no network I/O, so what is measured is the host's
+//! timers, scheduler and load. The report is the lateness of each tick
+//! (see `lateness`): how long after its deadline it started
+//! sending, and how many ticks were missed by falling a whole interval
+//! behind. Being free of I/O, it is also a quiet loop to point a sampling
+//! profiler at.
+
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::process::ExitCode;
+use std::time::Duration;
+
+use crate::clock::{Clock, SystemClock};
+use crate::exit;
+use crate::lateness::{Lateness, BUCKETS_US};
+use crate::log;
+use crate::send::{self, SendConfig, Sender};
+use crate::transmit::DatagramSink;
+use crate::units;
+
+/// Settings for `timing-report`, besides the send options timed.
+#[derive(Clone, Debug)]
+pub struct TimingConfig {
+    /// How long to run the loop.
+    pub duration: Duration,
+    /// Print one JSON object instead of text.
+    pub json: bool,
+}
+
+/// Takes every datagram and drops it.
+struct Discard;
+
+impl DatagramSink for Discard {
+    fn send_to(&mut self, payload: &[u8], _dest: SocketAddr) -> io::Result<usize> {
+        Ok(payload.len())
+    }
+}
+
+/// What a run found.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Report {
+    /// Time between ticks asked for.
+    pub pace: Duration,
+    pub duration: Duration,
+    /// Datagrams the loop produced.
+    pub sent: u64,
+    pub lateness: Lateness,
+    /// `--max-lateness-ms`, against which ticks count as late.
+    pub limit: Option<Duration>,
+}
+
+impl Report {
+    /// No tick was missed, nor later than the limit.
+    pub fn sustained(&self) -> bool {
+        self.lateness.missed == 0 && self.lateness.late == 0
+    }
+
+    pub fn json(&self) -> String {
+        let l = &self.lateness;
+        let bound = |pct| {
+            l.bound_us(pct)
+                .map_or("null".to_string(), |us| us.to_string())
+        };
+        let buckets: Vec<String> = l
+            .histogram
+            .iter()
+            .enumerate()
+            .map(|(i, n)| match BUCKETS_US.get(i) {
+                Some(us) => format!("{{\"lt_us\":{us},\"ticks\":{n}}}"),
+                None => format!("{{\"lt_us\":null,\"ticks\":{n}}}"),
+            })
+            .collect();
+        format!(
+            "{{\"pace_us\":{},\"duration_ms\":{},\"sent\":{},\"ticks\":{},\"missed\":{},\"late\":{},\"max_lateness_us\":{},\"mean_us\":{},\"max_us\":{},\"p99_lt_us\":{},\"p999_lt_us\":{},\"histogram\":[{}],\"sustained\":{}}}",
+            self.pace.as_micros(),
+            self.duration.as_millis(),
+            self.sent,
+            l.ticks,
+            l.missed,
+            l.late,
+            self.limit
+                .map_or("null".to_string(), |d| d.as_micros().to_string()),
+            l.mean_us(),
+            l.max_us,
+            bound(99.0),
+            bound(99.9),
+            buckets.join(","),
+            self.sustained()
+        )
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let l = &self.lateness;
+        let bound = |pct| {
+            l.bound_us(pct)
+                .map_or("later".to_string(), |us| format!("<{}", units::micros(us)))
+        };
+        writeln!(
+            f,
+            "Timing report: pace={} duration={:.1}s sent={}",
+            units::micros(self.pace.as_micros() as u64),
+            self.duration.as_secs_f64(),
+            self.sent
+        )?;
+        writeln!(
+            f,
+            "  lateness: ticks={} late={} missed={} mean={} max={} p99={} p99.9={}",
+            l.ticks,
+            l.late,
+            l.missed,
+            units::micros(l.mean_us()),
+            units::micros(l.max_us),
+            bound(99.0),
+            bound(99.9)
+        )?;
+        writeln!(f, "  lateness histogram: {}", l.histogram_summary())?;
+        let verdict = match (l.missed, l.late, self.limit) {
+            (0, 0, Some(limit)) => format!(
+                "sustained: no tick missed or later than {}",
+                units::micros(limit.as_micros() as u64)
+            ),
+            (0, 0, None) => "sustained: no tick missed".to_string(),
+            (missed, late, _) => format!("not sustained: {missed} tick(s) missed, {late} late"),
+        };
+        writeln!(f, "  verdict: {verdict}")
+    }
+}
+
+/// Runs the loop `cfg` describes on `clock` for `duration`.
+pub fn measure<C: Clock>(cfg: &SendConfig, clock: C, duration: Duration) -> Result<Report, String> {
+    // Nothing leaves, so any address will do
+    let dest = SocketAddr::from(([127, 0, 0, 1], cfg.port));
+    let mut sender = Sender::new(cfg, clock, Discard, dest);
+    let end = sender.instant() + duration;
+    while sender.instant() < end {
+        sender.step()?;
+    }
+    let snapshot = sender.snapshot();
+    Ok(Report {
+        pace: cfg.pace(),
+        duration,
+        sent: snapshot.dests.iter().map(|d| d.counts.sent).sum(),
+        lateness: snapshot.lateness,
+        limit: cfg.max_lateness,
+    })
+}
+
+/// Times the send loop on this host and prints the report; fails if the
+/// loop didn't keep up.
+pub fn run(cfg: &SendConfig, timing: &TimingConfig) -> ExitCode {
+    if let Err(e) = send::tune_thread(cfg) {
+        return exit::fail(e);
+    }
+    log::info!(
+        "Timing the send loop for {:.1}s, without sending (pid {})",
+        timing.duration.as_secs_f64(),
+        std::process::id()
+    );
+    // A line per datagram would be timed too, and bury the report
+    if log::enabled(log::Level::Info) {
+        log::set_level(log::Level::Warn);
+    }
+    let clock = SystemClock::for_interval(cfg.pace());
+    let report = match measure(cfg, clock, timing.duration) {
+        Ok(r) => r,
+        Err(e) => return exit::fail(e),
+    };
+    if timing.json {
+        println!("{}", report.json());
+    } else {
+        print!("{report}");
+    }
+    match report.sustained() {
+        true => ExitCode::SUCCESS,
+        false => exit::fail(format!(
+            "The send loop didn't keep up: {} tick(s) missed, {} late",
+            report.lateness.missed, report.lateness.late
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_lateness_and_verdict() {
+        let mut lateness = Lateness::default();
+        let limit = Some(Duration::from_millis(5));
+        for us in [50, 80, 120, 300, 7_000] {
+            lateness.record(Duration::from_micros(us), limit);
+        }
+        let mut report = Report {
+            pace: Duration::from_millis(1),
+            duration: Duration::from_secs(2),
+            sent: 5,
+            lateness,
+            limit,
+        };
+        assert!(!report.sustained());
+        let text = report.to_string();
+        assert!(text.contains("ticks=5 late=1 missed=0"), "{text}");
+        assert!(text.contains("<100µs 2, <250µs 1, <500µs 1"), "{text}");
+        assert!(
+            text.contains("not sustained: 0 tick(s) missed, 1 late"),
+            "{text}"
+        );
+        let json = report.json();
+        assert!(json.contains("\"p99_lt_us\":10000"), "{json}");
+        assert!(json.contains("{\"lt_us\":null,\"ticks\":0}]"), "{json}");
+        assert!(json.ends_with("\"sustained\":false}"), "{json}");
+
+        report.lateness.late = 0;
+        assert!(report.sustained());
+        assert!(report
+            .to_string()
+            .contains("sustained: no tick missed or later than"));
+    }
+}
@@ -0,0 +1,219 @@
+//! The single funnel through which every datagram leaves the process.
+//!
+//! `--observe-only` flips [`disable`] at startup; from then on every call
+//! to [`send_to`] fails, and [`sent_count`] lets the listener verify at
+//! runtime that nothing slipped through.
+//!
+//! Loops that send go through the [`DatagramSink`] trait, whose socket
+//! implementation routes through [`send_to`]; tests substitute an
+//! in-memory sink. [`send_batch`] hands a whole tick's datagrams to the
+//! kernel in one call where the platform allows it, and [`send_batch_at`]
+//! with a transmit time for `--txtime`. Over `--transport tcp` the framed
+//! payloads go through [`write`] instead.
+
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::log;
+use crate::sys;
+
+static TRANSMIT_DISABLED: AtomicBool = AtomicBool::new(false);
+static DATAGRAMS_SENT: AtomicU64 = AtomicU64::new(0);
+/// Set once batched sends turn out to be unavailable.
+static BATCH_UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+
+/// Permanently disables all transmit paths for the rest of the process.
+pub fn disable() {
+    TRANSMIT_DISABLED.store(true, Ordering::SeqCst);
+}
+
+/// Returns true if [`disable`] has been called.
+pub fn is_disabled() -> bool {
+    TRANSMIT_DISABLED.load(Ordering::SeqCst)
+}
+
+/// Returns the number of datagrams handed to the kernel so far.
+pub fn sent_count() -> u64 {
+    DATAGRAMS_SENT.load(Ordering::SeqCst)
+}
+
+/// Sends `payload` to `dest`, unless transmission has been disabled.
+pub fn send_to(sock: &UdpSocket, payload: &[u8], dest: SocketAddr) -> io::Result<usize> {
+    if is_disabled() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "transmission disabled by --observe-only",
+        ));
+    }
+    let n = sock.send_to(payload, dest)?;
+    DATAGRAMS_SENT.fetch_add(1, Ordering::SeqCst);
+    Ok(n)
+}
+
+/// Sends `payload` on a connected socket, unless transmission has been
+/// disabled.
+pub fn send(sock: &UdpSocket, payload: &[u8]) -> io::Result<usize> {
+    if is_disabled() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "transmission disabled by --observe-only",
+        ));
+    }
+    let n = sock.send(payload)?;
+    DATAGRAMS_SENT.fetch_add(1, Ordering::SeqCst);
+    Ok(n)
+}
+
+/// Writes one framed payload to a stream, unless transmission has been
+/// disabled.
+pub fn write(stream: &mut TcpStream, frame: &[u8]) -> io::Result<()> {
+    if is_disabled() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "transmission disabled by --observe-only",
+        ));
+    }
+    stream.write_all(frame)?;
+    DATAGRAMS_SENT.fetch_add(1, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Sends each of `msgs`, in as few system calls as the platform allows,
+/// returning one result per datagram. Without batching support (anything
+/// but Linux, or a kernel without sendmmsg) it falls back to [`send_to`]
+/// per datagram.
+pub fn send_batch(sock: &UdpSocket, msgs: &[(&[u8], SocketAddr)]) -> Vec<io::Result<usize>> {
+    let mut results = Vec::with_capacity(msgs.len());
+    while let Some(rest) = msgs.get(results.len()..).filter(|r| !r.is_empty()) {
+        if is_disabled() || rest.len() == 1 || BATCH_UNSUPPORTED.load(Ordering::Relaxed) {
+            results.push(send_to(sock, rest[0].0, rest[0].1));
+            continue;
+        }
+        match sys::send_batch(sock, rest, None) {
+            Ok(sent) if !sent.is_empty() => {
+                DATAGRAMS_SENT.fetch_add(sent.len() as u64, Ordering::SeqCst);
+                results.extend(sent.into_iter().map(Ok));
+            }
+            Ok(_) => results.push(Err(io::ErrorKind::WriteZero.into())),
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+                log::debug!("Batched sends unavailable ({e}); sending one datagram per call");
+                BATCH_UNSUPPORTED.store(true, Ordering::Relaxed);
+            }
+            // The first datagram failed; the rest get their own attempt
+            Err(e) => results.push(Err(e)),
+        }
+    }
+    results
+}
+
+/// Sends each of `msgs` for the kernel to hold until `at_ns` on CLOCK_TAI
+/// (SO_TXTIME), returning one result per datagram. The socket must have
+/// had [`sys::enable_txtime`] called on it; there is no fallback, as a
+/// datagram sent early would carry the wrong time.
+pub fn send_batch_at(
+    sock: &UdpSocket,
+    msgs: &[(&[u8], SocketAddr)],
+    at_ns: u64,
+) -> Vec<io::Result<usize>> {
+    let mut results = Vec::with_capacity(msgs.len());
+    while let Some(rest) = msgs.get(results.len()..).filter(|r| !r.is_empty()) {
+        if is_disabled() {
+            results.push(send_to(sock, rest[0].0, rest[0].1));
+            continue;
+        }
+        match sys::send_batch(sock, rest, Some(at_ns)) {
+            Ok(sent) if !sent.is_empty() => {
+                DATAGRAMS_SENT.fetch_add(sent.len() as u64, Ordering::SeqCst);
+                results.extend(sent.into_iter().map(Ok));
+            }
+            Ok(_) => results.push(Err(io::ErrorKind::WriteZero.into())),
+            Err(e) => results.push(Err(e)),
+        }
+    }
+    results
+}
+
+/// Something datagrams can be sent through.
+pub trait DatagramSink {
+    fn send_to(&mut self, payload: &[u8], dest: SocketAddr) -> io::Result<usize>;
+
+    /// Sends each of `msgs`, returning one result per datagram. Sinks that
+    /// can submit several datagrams at once override this.
+    fn send_batch(&mut self, msgs: &[(&[u8], SocketAddr)]) -> Vec<io::Result<usize>> {
+        msgs.iter()
+            .map(|&(payload, dest)| self.send_to(payload, dest))
+            .collect()
+    }
+
+    /// Sends each of `msgs` to leave `delay` from now, with `--txtime`.
+    /// Sinks that can't schedule a transmit time send them right away.
+    fn send_batch_after(
+        &mut self,
+        msgs: &[(&[u8], SocketAddr)],
+        _delay: Duration,
+    ) -> Vec<io::Result<usize>> {
+        self.send_batch(msgs)
+    }
+
+    /// Returns true if `dest` should be skipped for now. Sinks that track
+    /// the health of their destinations override this.
+    fn paused(&mut self, _dest: SocketAddr) -> bool {
+        false
+    }
+
+    /// Queues `msgs` for another thread to send, if this sink does, and
+    /// returns how queueing each went; that thread then logs each one
+    /// with its timestamp from `stamps`. Other sinks return `None`, and
+    /// are sent through with [`Self::send_batch`].
+    fn offload(
+        &mut self,
+        _msgs: &[(&[u8], SocketAddr)],
+        _stamps: &[u64],
+    ) -> Option<Vec<io::Result<usize>>> {
+        None
+    }
+}
+
+impl DatagramSink for UdpSocket {
+    fn send_to(&mut self, payload: &[u8], dest: SocketAddr) -> io::Result<usize> {
+        send_to(self, payload, dest)
+    }
+
+    fn send_batch(&mut self, msgs: &[(&[u8], SocketAddr)]) -> Vec<io::Result<usize>> {
+        send_batch(self, msgs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn batch_delivers_every_datagram_in_order() {
+        let rx = UdpSocket::bind("127.0.0.1:0").unwrap();
+        rx.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let dest = rx.local_addr().unwrap();
+        let tx = UdpSocket::bind("127.0.0.1:0").unwrap();
+        // Port 9 on loopback exists but drops everything
+        let discard: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        let msgs: [(&[u8], SocketAddr); 4] = [
+            (b"one", dest),
+            (b"two", discard),
+            (b"three", dest),
+            (b"", dest),
+        ];
+        let before = sent_count();
+        let results = send_batch(&tx, &msgs);
+        let lens: Vec<usize> = results.into_iter().map(|r| r.unwrap()).collect();
+        assert_eq!(lens, [3, 3, 5, 0]);
+        assert!(sent_count() >= before + 4);
+        let mut buf = [0u8; 16];
+        for want in [&b"one"[..], b"three", b""] {
+            let n = rx.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..n], want);
+        }
+    }
+}
@@ -0,0 +1,320 @@
+//! Carrying the timestamp stream over TCP, for consumers on networks that
+//! filter UDP broadcast (`--transport tcp`).
+//!
+//! The sender listens for consumers instead of broadcasting, and writes
+//! every payload it would have sent to each connected one, prefixed with
+//! its length as a big-endian u16:
+//!
+//! ```text
+//! offset  size  field
+//!      0     2  payload length N
+//!      2     N  payload, in any --format
+//! ```
+//!
+//! Consumers connect to the sender and read frames back into datagrams.
+//! The receive loop reads through [`DatagramSource`], which both UDP
+//! sockets and [`StreamClient`] implement, as the send loop sends through
+//! [`DatagramSink`], which [`StreamServer`]
+//! implements.
+//!
+//! A consumer that falls behind until the sender's socket buffer fills is
+//! disconnected rather than allowed to stall the sender; it reconnects by
+//! itself. There is no kernel receive time for a byte stream, so offsets
+//! are measured from when each frame is read, and include any time TCP
+//! spent retransmitting. QUIC would avoid that head-of-line blocking, but
+//! needs a TLS stack this crate doesn't have.
+
+use std::io::{self, Read};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::exit::Failure;
+use crate::log;
+use crate::sys::{self, RecvMeta};
+use crate::transmit;
+
+/// Where a sender's datagrams go, for a custom transport.
+pub use crate::transmit::DatagramSink;
+
+/// Consumers served at once.
+pub const MAX_CONSUMERS: usize = 64;
+/// Longest a consumer waits for the sender to accept its connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long a consumer waits before connecting again.
+const RECONNECT: Duration = Duration::from_secs(1);
+
+/// How timestamps travel (`--transport`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Transport {
+    #[default]
+    Udp,
+    Tcp,
+}
+
+impl Transport {
+    /// Parses a `--transport` value.
+    pub fn parse(s: &str) -> Result<Transport, String> {
+        match s {
+            "udp" => Ok(Transport::Udp),
+            "tcp" => Ok(Transport::Tcp),
+            _ => Err("expected udp or tcp".to_string()),
+        }
+    }
+}
+
+/// Something datagrams are received from.
+pub trait DatagramSource {
+    /// Receives one datagram into `buf`, failing with `WouldBlock` or
+    /// `TimedOut` if none arrives within the read timeout.
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<RecvMeta>;
+
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl DatagramSource for UdpSocket {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<RecvMeta> {
+        sys::recv(self, buf)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        UdpSocket::set_read_timeout(self, timeout)
+    }
+}
+
+/// The sender's side: consumers connected so far.
+pub struct StreamServer {
+    listener: TcpListener,
+    consumers: Vec<(TcpStream, SocketAddr)>,
+}
+
+impl StreamServer {
+    /// Listens for consumers on `addr`.
+    pub fn listen(addr: SocketAddr) -> Result<StreamServer, Failure> {
+        let listener = TcpListener::bind(addr)
+            .and_then(|l| l.set_nonblocking(true).map(|()| l))
+            .map_err(|e| Failure::io(&e, format!("Failed to listen on TCP {addr}: {e}")))?;
+        log::info!(
+            "Offering timestamps over TCP on {}",
+            listener.local_addr().unwrap_or(addr)
+        );
+        Ok(StreamServer {
+            listener,
+            consumers: Vec::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Takes on the consumers waiting to connect.
+    fn accept(&mut self) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, peer)) if self.consumers.len() < MAX_CONSUMERS => {
+                    // A frame per write, at once, and never waiting on a slow reader
+                    let ready = stream
+                        .set_nodelay(true)
+                        .and_then(|()| stream.set_nonblocking(true));
+                    match ready {
+                        Ok(()) => {
+                            log::info!("Consumer connected: {peer}");
+                            self.consumers.push((stream, peer));
+                        }
+                        Err(e) => log::warn!("Failed to set up consumer {peer}: {e}"),
+                    }
+                }
+                Ok((_, peer)) => log::warn!("Refused consumer {peer}: {MAX_CONSUMERS} connected"),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    log::warn!("Failed to accept a consumer: {e}");
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl DatagramSink for StreamServer {
+    /// Writes `payload` to every consumer; `dest` is ignored.
+    fn send_to(&mut self, payload: &[u8], _dest: SocketAddr) -> io::Result<usize> {
+        self.accept();
+        let len = u16::try_from(payload.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "payload too long"))?;
+        let mut frame = Vec::with_capacity(2 + payload.len());
+        frame.extend_from_slice(&len.to_be_bytes());
+        frame.extend_from_slice(payload);
+        self.consumers.retain_mut(|(stream, peer)| {
+            // A partial frame would garble the rest of the stream
+            match transmit::write(stream, &frame) {
+                Ok(()) => true,
+                Err(e) => {
+                    log::info!("Consumer disconnected: {peer} ({e})");
+                    false
+                }
+            }
+        });
+        Ok(payload.len())
+    }
+}
+
+/// The consumer's side: a connection to the sender, remade when lost.
+pub struct StreamClient {
+    addr: SocketAddr,
+    stream: Option<TcpStream>,
+    timeout: Option<Duration>,
+    /// When to connect again after failing.
+    retry_at: Instant,
+    /// Bytes read but not yet returned as a datagram.
+    pending: Vec<u8>,
+}
+
+impl StreamClient {
+    /// Prepares to read from the sender at `addr`, connecting on the
+    /// first receive.
+    pub fn new(addr: SocketAddr) -> StreamClient {
+        StreamClient {
+            addr,
+            stream: None,
+            timeout: None,
+            retry_at: Instant::now(),
+            pending: Vec::new(),
+        }
+    }
+
+    fn connect(&mut self) -> io::Result<&mut TcpStream> {
+        if let Some(stream) = self.stream.take() {
+            return Ok(self.stream.insert(stream));
+        }
+        if transmit::is_disabled() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "transmission is disabled (--observe-only)",
+            ));
+        }
+        let wait = self.retry_at.saturating_duration_since(Instant::now());
+        if !wait.is_zero() {
+            // Sleeps as a read timeout would, so the loop still wakes up
+            thread::sleep(self.timeout.map_or(wait, |t| t.min(wait)));
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let stream = TcpStream::connect_timeout(&self.addr, CONNECT_TIMEOUT)
+            .and_then(|s| s.set_read_timeout(self.timeout).map(|()| s));
+        match stream {
+            Ok(stream) => {
+                log::info!("Connected to {} over TCP", self.addr);
+                self.pending.clear();
+                Ok(self.stream.insert(stream))
+            }
+            Err(e) => {
+                log::warn!("Failed to connect to {}: {e}", self.addr);
+                self.retry_at = Instant::now() + RECONNECT;
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+        }
+    }
+
+    /// Drops the connection, to be remade after a pause.
+    fn lost(&mut self, why: &str) -> io::Error {
+        log::warn!("Lost connection to {}: {why}; reconnecting", self.addr);
+        self.stream = None;
+        self.retry_at = Instant::now() + RECONNECT;
+        io::ErrorKind::WouldBlock.into()
+    }
+}
+
+impl DatagramSource for StreamClient {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<RecvMeta> {
+        loop {
+            if let [hi, lo, ref rest @ ..] = self.pending[..] {
+                let len = usize::from(u16::from_be_bytes([hi, lo]));
+                if len > buf.len() {
+                    return Err(self.lost(&format!("frame of {len} bytes")));
+                }
+                if rest.len() >= len {
+                    buf[..len].copy_from_slice(&rest[..len]);
+                    self.pending.drain(..2 + len);
+                    return Ok(RecvMeta {
+                        len,
+                        src: self.addr,
+                        kernel_time: None,
+                        iface: None,
+                    });
+                }
+            }
+            let mut chunk = [0u8; 4096];
+            let read = self.connect()?.read(&mut chunk);
+            match read {
+                Ok(0) => return Err(self.lost("closed by the sender")),
+                Ok(n) => self.pending.extend_from_slice(&chunk[..n]),
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    return Err(e)
+                }
+                Err(e) => return Err(self.lost(&e.to_string())),
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        match &self.stream {
+            Some(stream) => stream.local_addr(),
+            // Nothing local yet; where it's going stands in
+            None => Ok(self.addr),
+        }
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.timeout = timeout;
+        match &self.stream {
+            Some(stream) => stream.set_read_timeout(timeout),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_reach_every_consumer_whole() {
+        let mut server = StreamServer::listen("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = server.local_addr().unwrap();
+        let mut client = StreamClient::new(addr);
+        client
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let mut buf = [0u8; 1500];
+        // Connects, but nothing has been sent yet
+        assert!(client.recv(&mut buf).is_err());
+
+        // Writing also accepts the consumer that connected
+        let big = vec![7u8; 1400];
+        server.send_to(b"one", addr).unwrap();
+        server.send_to(&big, addr).unwrap();
+        server.send_to(b"", addr).unwrap();
+        let got = client.recv(&mut buf).unwrap();
+        assert_eq!((&buf[..got.len], got.src), (&b"one"[..], addr));
+        let got = client.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..got.len], &big[..]);
+        assert_eq!(client.recv(&mut buf).unwrap().len, 0);
+
+        drop(server);
+        assert_eq!(
+            client.recv(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+    }
+}
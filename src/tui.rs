@@ -0,0 +1,205 @@
+//! Live terminal dashboard for receiver mode (`recv --tui`).
+//!
+//! Redraws a table of senders in place instead of scrolling one line per
+//! packet: arrival rate, loss, the latest offset and a sparkline of recent
+//! offsets, so a sender that stalls, drops or drifts during bring-up is
+//! visible at a glance. Plain ANSI escapes keep it dependency-free; any
+//! VT100-compatible terminal will do.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use crate::units;
+
+/// How often the dashboard is redrawn.
+pub const REFRESH: Duration = Duration::from_millis(500);
+/// Offsets kept per sender for the sparkline.
+const HISTORY: usize = 40;
+/// Arrivals are counted over this long to give the rate.
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// What the dashboard shows for one sender.
+struct Row {
+    first_seen: Instant,
+    last_seen: Instant,
+    last_seq: Option<u32>,
+    received: u64,
+    /// Sequence numbers skipped; reordered packets don't count.
+    lost: u64,
+    arrivals: VecDeque<Instant>,
+    offsets: VecDeque<i64>,
+}
+
+/// Senders heard so far and when to redraw next.
+pub struct Dashboard {
+    title: String,
+    rows: BTreeMap<String, Row>,
+    next_draw: Instant,
+}
+
+impl Dashboard {
+    pub fn new(title: String) -> Dashboard {
+        Dashboard {
+            title,
+            rows: BTreeMap::new(),
+            next_draw: Instant::now(),
+        }
+    }
+
+    /// Records an accepted timestamp from `sender`.
+    pub fn observe(&mut self, sender: &str, seq: Option<u32>, offset_ms: i64, now: Instant) {
+        let row = match self.rows.get_mut(sender) {
+            Some(r) => r,
+            None => self.rows.entry(sender.to_string()).or_insert(Row {
+                first_seen: now,
+                last_seen: now,
+                last_seq: None,
+                received: 0,
+                lost: 0,
+                arrivals: VecDeque::new(),
+                offsets: VecDeque::with_capacity(HISTORY),
+            }),
+        };
+        row.last_seen = now;
+        row.received += 1;
+        if let Some(seq) = seq {
+            match row.last_seq {
+                Some(last) => {
+                    let ahead = seq.wrapping_sub(last);
+                    if ahead < u32::MAX / 2 {
+                        row.lost += ahead.saturating_sub(1) as u64;
+                        row.last_seq = Some(seq);
+                    }
+                }
+                None => row.last_seq = Some(seq),
+            }
+        }
+        row.arrivals.push_back(now);
+        while row
+            .arrivals
+            .front()
+            .is_some_and(|&t| now.duration_since(t) > RATE_WINDOW)
+        {
+            row.arrivals.pop_front();
+        }
+        if row.offsets.len() == HISTORY {
+            row.offsets.pop_front();
+        }
+        row.offsets.push_back(offset_ms);
+    }
+
+    /// The dashboard as text, one line per sender under a header.
+    pub fn render(&self, now: Instant) -> String {
+        let total: u64 = self.rows.values().map(|r| r.received).sum();
+        let mut out = format!(
+            "{} | {} sender(s), {total} packet(s) | Ctrl-C to quit\n\n",
+            self.title,
+            self.rows.len()
+        );
+        out.push_str(&format!(
+            "{:<24} {:>9} {:>7} {:>9} {:>8}  {:<w$}  RANGE\n",
+            "SENDER",
+            "RATE",
+            "LOSS",
+            "OFFSET",
+            "LAST",
+            "HISTORY",
+            w = HISTORY
+        ));
+        for (sender, row) in &self.rows {
+            // Rate over the window, or since first heard if that is shorter
+            let span = now
+                .saturating_duration_since(row.first_seen)
+                .min(RATE_WINDOW)
+                .max(Duration::from_secs(1));
+            let recent = row
+                .arrivals
+                .iter()
+                .filter(|&&t| now.saturating_duration_since(t) <= RATE_WINDOW)
+                .count();
+            let loss = 100.0 * row.lost as f64 / (row.received + row.lost) as f64;
+            let (min, max) = row
+                .offsets
+                .iter()
+                .fold((i64::MAX, i64::MIN), |(lo, hi), &o| (lo.min(o), hi.max(o)));
+            out.push_str(&format!(
+                "{sender:<24} {:>9} {:>6.1}% {:>9} {:>8}  {:<w$}  {}..{}\n",
+                units::pps(recent as f64 / span.as_secs_f64()),
+                loss,
+                units::millis(row.offsets.back().copied().unwrap_or_default()),
+                units::millis(now.saturating_duration_since(row.last_seen).as_millis() as i64),
+                sparkline(&row.offsets),
+                units::millis(min),
+                units::millis(max),
+                w = HISTORY
+            ));
+        }
+        out
+    }
+
+    /// Redraws the screen if a refresh is due.
+    pub fn draw(&mut self, now: Instant) {
+        if now < self.next_draw {
+            return;
+        }
+        self.next_draw = now + REFRESH;
+        let mut stdout = io::stdout().lock();
+        // Home the cursor and clear, then draw the frame
+        let _ = write!(stdout, "\x1b[H\x1b[2J{}", self.render(now));
+        let _ = stdout.flush();
+    }
+}
+
+/// One bar per value, scaled between the smallest and largest.
+fn sparkline(values: &VecDeque<i64>) -> String {
+    let (Some(&min), Some(&max)) = (values.iter().min(), values.iter().max()) else {
+        return String::new();
+    };
+    let range = (max - min).max(1) as f64;
+    values
+        .iter()
+        .map(|&v| {
+            if max == min {
+                BARS[3]
+            } else {
+                BARS[((v - min) as f64 / range * 7.0).round() as usize]
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shows_rate_loss_and_offset_history() {
+        let t0 = Instant::now();
+        let mut dash = Dashboard::new("Listening on 0.0.0.0:12321".to_string());
+        for (i, seq) in [1u32, 2, 3, 5, 6].into_iter().enumerate() {
+            let at = t0 + Duration::from_secs(i as u64);
+            dash.observe("10.0.0.5/3", Some(seq), seq as i64 * 2, at);
+        }
+        let frame = dash.render(t0 + Duration::from_secs(5));
+        let lines: Vec<&str> = frame.lines().collect();
+        assert_eq!(
+            lines[0],
+            "Listening on 0.0.0.0:12321 | 1 sender(s), 5 packet(s) | Ctrl-C to quit"
+        );
+        let row: Vec<&str> = lines[3].split_whitespace().collect();
+        assert_eq!(
+            row,
+            [
+                "10.0.0.5/3",
+                "1pps",
+                "16.7%",
+                "12ms",
+                "1.00s",
+                "▁▂▄▇█",
+                "2ms..12ms"
+            ]
+        );
+    }
+}
@@ -0,0 +1,118 @@
+//! Correcting the sent timestamp for the time it takes to send it
+//! (`send --tx-compensation-us`).
+//!
+//! A timestamp is read from the clock before it is encoded and handed to
+//! the kernel, so by the time the datagram leaves it is already that much
+//! old. The delay is nearly constant for a given host and format, a few
+//! to some tens of microseconds, and shows up at a receiver as a bias in
+//! every offset. Compensation adds it to each timestamp as it is read:
+//! either a fixed amount, or the mean delay measured so far from reading
+//! the clock to the send call returning. With `--io-thread` that is the
+//! time to queue the datagram, not to send it, so a fixed amount suits it
+//! better.
+
+use std::time::Duration;
+
+/// Largest fixed compensation accepted.
+pub const MAX_FIXED: Duration = Duration::from_secs(1);
+/// Longer delays are stalls, such as a send blocking on a full buffer,
+/// rather than the usual cost of sending; they aren't averaged in.
+const MAX_SAMPLE: Duration = Duration::from_millis(10);
+/// Weight of each new delay in the running mean.
+const WEIGHT: f64 = 1.0 / 16.0;
+
+/// Values accepted by `--tx-compensation-us`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compensation {
+    Fixed(Duration),
+    /// The mean delay measured so far.
+    Learned,
+}
+
+impl Compensation {
+    /// Parses microseconds, or `auto` to learn them.
+    pub fn parse(s: &str) -> Result<Compensation, String> {
+        if s == "auto" {
+            return Ok(Compensation::Learned);
+        }
+        let us: u64 = s
+            .parse()
+            .map_err(|_| "expected microseconds or auto".to_string())?;
+        let fixed = Duration::from_micros(us);
+        if fixed > MAX_FIXED {
+            return Err(format!("at most {} microseconds", MAX_FIXED.as_micros()));
+        }
+        Ok(Compensation::Fixed(fixed))
+    }
+}
+
+/// The delay from reading the clock to sending, as measured.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SendDelay {
+    /// Running mean, in microseconds; `None` before the first sample.
+    mean_us: Option<f64>,
+    samples: u64,
+}
+
+impl SendDelay {
+    /// Averages in one delay, unless it was a stall.
+    pub fn observe(&mut self, delay: Duration) {
+        if delay > MAX_SAMPLE {
+            return;
+        }
+        let us = delay.as_secs_f64() * 1e6;
+        self.mean_us = Some(match self.mean_us {
+            Some(mean) => mean + (us - mean) * WEIGHT,
+            None => us,
+        });
+        self.samples += 1;
+    }
+
+    pub fn mean(&self) -> Duration {
+        Duration::from_secs_f64(self.mean_us.unwrap_or(0.0) / 1e6)
+    }
+
+    /// Delays averaged in so far.
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    /// What to add to the next timestamp under `compensation`.
+    pub fn correction(&self, compensation: Compensation) -> Duration {
+        match compensation {
+            Compensation::Fixed(fixed) => fixed,
+            Compensation::Learned => self.mean(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn learns_the_usual_delay_and_ignores_stalls() {
+        assert_eq!(
+            Compensation::parse("40"),
+            Ok(Compensation::Fixed(Duration::from_micros(40)))
+        );
+        assert_eq!(Compensation::parse("auto"), Ok(Compensation::Learned));
+        assert!(Compensation::parse("-3").is_err());
+        assert!(Compensation::parse("2000000").is_err());
+
+        let mut delay = SendDelay::default();
+        assert_eq!(delay.correction(Compensation::Learned), Duration::ZERO);
+        delay.observe(Duration::from_micros(20));
+        assert!((delay.mean().as_secs_f64() * 1e6 - 20.0).abs() < 0.01);
+        for _ in 0..200 {
+            delay.observe(Duration::from_micros(36));
+        }
+        delay.observe(Duration::from_millis(50));
+        assert_eq!(delay.samples(), 201);
+        assert!((delay.mean().as_secs_f64() * 1e6 - 36.0).abs() < 0.01);
+        assert_eq!(
+            delay.correction(Compensation::Fixed(Duration::from_micros(5))),
+            Duration::from_micros(5)
+        );
+    }
+}
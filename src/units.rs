@@ -0,0 +1,296 @@
+//! Human-readable quantities for log and summary lines.
+//!
+//! Rates, delays and byte counts are scaled to a fitting unit with three
+//! significant digits (`5.00kpps`, `850µs`, `1.50MiB`). The number and unit
+//! are written without a space so `key=value` lines still split on
+//! whitespace. A key names the unit of its value only while the value is
+//! a plain number: scaled, it's `last_seen=1.03s`, and with
+//! `--raw-numbers`, for scripts, `last_seen_ms=1030` (see [`pair`]).
+//!
+//! Timestamps are printed as Unix milliseconds, `ts_ms=1772366400250`,
+//! unless `--display-format` asks for ISO 8601 in UTC or local time, as
+//! `ts=2026-03-01T12:00:00.250Z` or `ts=2026-03-01T13:00:00.250+01:00`.
+//! Only the rendering changes; payloads are encoded as before.
+//!
+//! Timestamps go on every sent and received line, so [`ts`] returns a
+//! value that writes itself into the line rather than a `String`.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::start::{days_from_civil, format_rfc3339};
+use crate::sys;
+
+static RAW: AtomicBool = AtomicBool::new(false);
+static TIME_DISPLAY: AtomicU8 = AtomicU8::new(TimeDisplay::EpochMs as u8);
+
+/// How timestamps are printed (`--display-format`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeDisplay {
+    EpochMs,
+    Iso8601,
+    /// ISO 8601 in the zone `TZ` names, or the system's.
+    Local,
+}
+
+impl TimeDisplay {
+    /// Parses a `--display-format` value.
+    pub fn parse(s: &str) -> Result<TimeDisplay, String> {
+        match s {
+            "epoch-ms" => Ok(TimeDisplay::EpochMs),
+            "iso8601" => Ok(TimeDisplay::Iso8601),
+            "local" => Ok(TimeDisplay::Local),
+            _ => Err("expected iso8601, epoch-ms or local".to_string()),
+        }
+    }
+}
+
+const TIME_US: &[(f64, &str)] = &[(1.0, "µs"), (1e3, "ms"), (1e6, "s")];
+const TIME_MS: &[(f64, &str)] = &[(1.0, "ms"), (1e3, "s")];
+const PACKET_RATE: &[(f64, &str)] = &[(1.0, "pps"), (1e3, "kpps"), (1e6, "Mpps")];
+const BIT_RATE: &[(f64, &str)] = &[
+    (1.0, "bit/s"),
+    (1e3, "kbit/s"),
+    (1e6, "Mbit/s"),
+    (1e9, "Gbit/s"),
+];
+const BYTES: &[(f64, &str)] = &[
+    (1.0, "B"),
+    (1024.0, "KiB"),
+    (1048576.0, "MiB"),
+    (1073741824.0, "GiB"),
+];
+
+/// Prints plain numbers from now on, for the whole process.
+pub fn set_raw(raw: bool) {
+    RAW.store(raw, Ordering::Relaxed);
+}
+
+fn raw() -> bool {
+    RAW.load(Ordering::Relaxed)
+}
+
+/// Prints timestamps as `display` asks from now on, for the whole process.
+pub fn set_time_display(display: TimeDisplay) {
+    TIME_DISPLAY.store(display as u8, Ordering::Relaxed);
+    if display == TimeDisplay::Local {
+        // Loads the zone while the files are still within reach
+        let _ = sys::local_time(0);
+    }
+}
+
+/// A timestamp in Unix milliseconds, as a whole `key=value` pair: the key
+/// is `ts_ms` only while the value is that number, and `ts` otherwise.
+pub fn ts(ts_ms: u64) -> Ts {
+    let display = match TIME_DISPLAY.load(Ordering::Relaxed) {
+        x if x == TimeDisplay::Iso8601 as u8 => TimeDisplay::Iso8601,
+        x if x == TimeDisplay::Local as u8 => TimeDisplay::Local,
+        _ => TimeDisplay::EpochMs,
+    };
+    Ts { display, ts_ms }
+}
+
+/// A timestamp as [`ts`] prints it.
+#[derive(Clone, Copy, Debug)]
+pub struct Ts {
+    display: TimeDisplay,
+    ts_ms: u64,
+}
+
+impl fmt::Display for Ts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ts_ms = self.ts_ms;
+        let utc = || format_rfc3339(UNIX_EPOCH + Duration::from_millis(ts_ms));
+        match self.display {
+            TimeDisplay::EpochMs => write!(f, "ts_ms={ts_ms}"),
+            TimeDisplay::Iso8601 => write!(f, "ts={}", utc()),
+            TimeDisplay::Local => {
+                let secs = (ts_ms / 1000) as i64;
+                // Where the zone can't be found, UTC says so with its `Z`
+                let Ok([year, month, day, hour, min, sec]) = sys::local_time(secs) else {
+                    return write!(f, "ts={}", utc());
+                };
+                let offset =
+                    days_from_civil(year, month, day) * 86_400 + hour * 3600 + min * 60 + sec
+                        - secs;
+                write!(
+                    f,
+                    "ts={year:04}-{month:02}-{day:02}T{hour:02}:{min:02}:{sec:02}.{:03}{}{:02}:{:02}",
+                    ts_ms % 1000,
+                    if offset < 0 { '-' } else { '+' },
+                    offset.abs() / 3600,
+                    offset.abs() / 60 % 60
+                )
+            }
+        }
+    }
+}
+
+/// Key suffixes naming the unit of a plain value.
+const SUFFIXES: &[&str] = &["_ms", "_us", "_pps", "_bps"];
+
+/// A quantity formatted by one of the functions below as a whole
+/// `key=value` pair, `key` naming the unit of the plain number, e.g.
+/// `offset_ms`. As with [`ts`], the key keeps that suffix only while the
+/// value is the plain number: `offset_ms=-3` with `--raw-numbers` and
+/// `offset=-3ms` otherwise.
+pub fn pair(key: &'static str, value: String) -> Pair {
+    Pair { key, value }
+}
+
+/// A quantity as [`pair`] prints it.
+#[derive(Clone, Debug)]
+pub struct Pair {
+    key: &'static str,
+    value: String,
+}
+
+impl Pair {
+    fn key(&self, raw: bool) -> &'static str {
+        match raw {
+            true => self.key,
+            false => SUFFIXES
+                .iter()
+                .find_map(|s| self.key.strip_suffix(s))
+                .unwrap_or(self.key),
+        }
+    }
+}
+
+impl fmt::Display for Pair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.key(raw()), self.value)
+    }
+}
+
+/// A packet rate, in packets per second.
+pub fn pps(v: f64) -> String {
+    if raw() {
+        format!("{v:.1}")
+    } else {
+        scaled(v, PACKET_RATE)
+    }
+}
+
+/// A bit rate, in bits per second.
+pub fn bps(v: f64) -> String {
+    if raw() {
+        format!("{v:.1}")
+    } else {
+        scaled(v, BIT_RATE)
+    }
+}
+
+/// A byte count. Raw, it reads `<n> bytes`.
+pub fn bytes(n: u64) -> String {
+    if raw() {
+        format!("{n} bytes")
+    } else {
+        scaled(n as f64, BYTES)
+    }
+}
+
+/// A delay or offset in microseconds.
+pub fn micros(us: u64) -> String {
+    if raw() {
+        us.to_string()
+    } else {
+        scaled(us as f64, TIME_US)
+    }
+}
+
+/// A delay or offset in microseconds, possibly negative.
+pub fn micros_i64(us: i64) -> String {
+    if raw() {
+        us.to_string()
+    } else {
+        scaled(us as f64, TIME_US)
+    }
+}
+
+/// A delay or offset in whole milliseconds, possibly negative.
+pub fn millis(ms: i64) -> String {
+    if raw() {
+        ms.to_string()
+    } else {
+        scaled(ms as f64, TIME_MS)
+    }
+}
+
+/// A fractional delay or offset in milliseconds, possibly negative.
+pub fn millis_f64(ms: f64) -> String {
+    if raw() {
+        format!("{ms:.1}")
+    } else {
+        scaled(ms, TIME_MS)
+    }
+}
+
+/// Formats `v`, given in the first unit of `units`, in the largest unit
+/// it amounts to at least one of. Whole values in the base unit are
+/// printed without decimals.
+fn scaled(v: f64, units: &[(f64, &str)]) -> String {
+    let (factor, unit) = units
+        .iter()
+        .rev()
+        .find(|(f, _)| v.abs() >= *f)
+        .unwrap_or(&units[0]);
+    let x = v / factor;
+    if *factor == 1.0 && x.fract() == 0.0 {
+        return format!("{x}{unit}");
+    }
+    let decimals = match x.abs() {
+        a if a >= 99.95 => 0,
+        a if a >= 9.995 => 1,
+        _ => 2,
+    };
+    format!("{x:.decimals$}{unit}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scales_to_three_significant_digits() {
+        assert_eq!(scaled(5_000.0, PACKET_RATE), "5.00kpps");
+        assert_eq!(scaled(40_960_000.0, BIT_RATE), "41.0Mbit/s");
+        assert_eq!(scaled(1_572_864.0, BYTES), "1.50MiB");
+        assert_eq!(scaled(1_250.0, TIME_US), "1.25ms");
+        assert_eq!(scaled(0.5, PACKET_RATE), "0.50pps");
+    }
+
+    #[test]
+    fn whole_base_units_have_no_decimals() {
+        assert_eq!(scaled(850.0, TIME_US), "850µs");
+        assert_eq!(scaled(0.0, TIME_MS), "0ms");
+        assert_eq!(scaled(-12.0, TIME_MS), "-12ms");
+        assert_eq!(scaled(-1_500.0, TIME_MS), "-1.50s");
+        assert_eq!(scaled(16.0, BYTES), "16B");
+    }
+
+    #[test]
+    fn keys_name_a_unit_only_for_plain_numbers() {
+        let offset = pair("offset_ms", scaled(-1_500.0, TIME_MS));
+        assert_eq!(
+            (offset.key(false), offset.key(true)),
+            ("offset", "offset_ms")
+        );
+        assert_eq!(pair("rate_pps", String::new()).key(false), "rate");
+        assert_eq!(pair("loss", String::new()).key(false), "loss");
+    }
+
+    #[test]
+    fn timestamps_render_as_asked() {
+        let ts_ms = 1_772_366_400_250;
+        let render = |display| Ts { display, ts_ms }.to_string();
+        assert_eq!(render(TimeDisplay::EpochMs), "ts_ms=1772366400250");
+        assert_eq!(render(TimeDisplay::Iso8601), "ts=2026-03-01T12:00:00.250Z");
+        // Whatever the zone, it names the same instant
+        let local = render(TimeDisplay::Local);
+        let at = crate::start::parse_rfc3339(local.strip_prefix("ts=").unwrap());
+        assert_eq!(at, Ok(UNIX_EPOCH + Duration::from_millis(ts_ms)), "{local}");
+        assert!(TimeDisplay::parse("rfc2822").is_err());
+    }
+}
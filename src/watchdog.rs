@@ -0,0 +1,220 @@
+//! Sender allowlist for receiver mode (`recv --expect-sender`).
+//!
+//! The receiver is told which senders the broadcast topology has, by
+//! address or, with `--verify-keys`, by the name of the key they sign
+//! with. It reports the first datagram from any other sender, and each
+//! expected sender that goes unheard for the timeout, once per silence.
+//! Datagrams are processed as usual either way: `--allow-source` is what
+//! drops them. With `--strict` each report is an alarm, with the same
+//! action as an offset alert.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::alert::Action;
+use crate::log;
+
+/// Default for `--expect-timeout`.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// One `--expect-sender`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Expected {
+    Addr(IpAddr),
+    /// Name of a key in the `--verify-keys` directory.
+    Key(String),
+}
+
+impl Expected {
+    /// Parses an address, or anything else as a key name.
+    pub fn parse(s: &str) -> Result<Expected, String> {
+        match s.parse() {
+            Ok(ip) => Ok(Expected::Addr(ip)),
+            Err(_) if s.is_empty() => Err("expected an address or key name".to_string()),
+            Err(_) => Ok(Expected::Key(s.to_string())),
+        }
+    }
+
+    fn matches(&self, src: IpAddr, signer: Option<&str>) -> bool {
+        match self {
+            Expected::Addr(ip) => *ip == src,
+            Expected::Key(name) => signer == Some(name.as_str()),
+        }
+    }
+}
+
+impl fmt::Display for Expected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expected::Addr(ip) => write!(f, "{ip}"),
+            Expected::Key(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+/// Settings for the sender allowlist.
+#[derive(Clone, Debug)]
+pub struct ExpectConfig {
+    pub senders: Vec<Expected>,
+    /// Longest an expected sender may go unheard.
+    pub timeout: Duration,
+    /// What to do on each report with `--strict`; without, they are only
+    /// logged.
+    pub strict: Option<Action>,
+}
+
+/// Something the topology wasn't meant to do.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Finding {
+    /// The first datagram from a sender not on the list.
+    Unexpected(IpAddr),
+    /// An expected sender unheard for the timeout.
+    Quiet(Expected),
+}
+
+impl Finding {
+    /// What the finding is, for `--on-alert` hooks.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Finding::Unexpected(_) => "unexpected_sender",
+            Finding::Quiet(_) => "sender_quiet",
+        }
+    }
+
+    pub fn sender(&self) -> String {
+        match self {
+            Finding::Unexpected(ip) => ip.to_string(),
+            Finding::Quiet(e) => e.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Finding::Unexpected(ip) => write!(f, "Unexpected sender: sender={ip}"),
+            Finding::Quiet(e) => write!(f, "Expected sender quiet: sender={e}"),
+        }
+    }
+}
+
+struct Watch {
+    expected: Expected,
+    last_heard: Instant,
+    quiet: bool,
+}
+
+/// When each expected sender was last heard, and which others have been.
+pub struct Watchdog {
+    timeout: Duration,
+    watches: Vec<Watch>,
+    /// Unexpected senders already reported.
+    unexpected: HashSet<IpAddr>,
+}
+
+impl Watchdog {
+    /// Starts the clock on every expected sender at `now`.
+    pub fn new(cfg: &ExpectConfig, now: Instant) -> Watchdog {
+        Watchdog {
+            timeout: cfg.timeout,
+            watches: cfg
+                .senders
+                .iter()
+                .map(|e| Watch {
+                    expected: e.clone(),
+                    last_heard: now,
+                    quiet: false,
+                })
+                .collect(),
+            unexpected: HashSet::new(),
+        }
+    }
+
+    /// Records a datagram from `src`, signed by the key named `signer` if
+    /// it was, returning a finding the first time an unexpected sender is
+    /// heard.
+    pub fn observe(&mut self, src: IpAddr, signer: Option<&str>, now: Instant) -> Option<Finding> {
+        let mut expected = false;
+        for w in self.watches.iter_mut() {
+            if !w.expected.matches(src, signer) {
+                continue;
+            }
+            expected = true;
+            if w.quiet {
+                log::info!(
+                    "Expected sender heard again: sender={} quiet_s={}",
+                    w.expected,
+                    now.duration_since(w.last_heard).as_secs()
+                );
+                w.quiet = false;
+            }
+            w.last_heard = now;
+        }
+        (!expected && self.unexpected.insert(src)).then_some(Finding::Unexpected(src))
+    }
+
+    /// Returns the expected senders that have gone quiet since the last call.
+    pub fn check(&mut self, now: Instant) -> Vec<Finding> {
+        self.watches
+            .iter_mut()
+            .filter(|w| !w.quiet && now.duration_since(w.last_heard) >= self.timeout)
+            .map(|w| {
+                w.quiet = true;
+                Finding::Quiet(w.expected.clone())
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_strangers_once_and_silences_once_each() {
+        let a: IpAddr = "192.0.2.10".parse().unwrap();
+        let b: IpAddr = "192.0.2.11".parse().unwrap();
+        let stranger: IpAddr = "192.0.2.99".parse().unwrap();
+        let cfg = ExpectConfig {
+            senders: vec![
+                Expected::parse("192.0.2.10").unwrap(),
+                Expected::parse("gm1").unwrap(),
+            ],
+            timeout: Duration::from_secs(10),
+            strict: None,
+        };
+        let t0 = Instant::now();
+        let mut dog = Watchdog::new(&cfg, t0);
+
+        assert_eq!(dog.observe(a, None, t0), None);
+        // Known by the key it signs with, from any address
+        assert_eq!(dog.observe(b, Some("gm1"), t0), None);
+        assert_eq!(
+            dog.observe(stranger, None, t0),
+            Some(Finding::Unexpected(stranger))
+        );
+        assert_eq!(dog.observe(stranger, None, t0), None);
+        assert_eq!(
+            dog.observe(b, Some("gm2"), t0),
+            Some(Finding::Unexpected(b))
+        );
+
+        let t = t0 + Duration::from_secs(5);
+        dog.observe(a, None, t);
+        assert!(dog.check(t).is_empty());
+        let t = t0 + Duration::from_secs(12);
+        assert_eq!(dog.check(t), [Finding::Quiet(Expected::Key("gm1".into()))]);
+        assert!(dog.check(t + Duration::from_secs(1)).is_empty());
+        // Heard again re-arms it
+        dog.observe(b, Some("gm1"), t);
+        assert_eq!(
+            dog.check(t + Duration::from_secs(11)),
+            [
+                Finding::Quiet(Expected::Addr(a)),
+                Finding::Quiet(Expected::Key("gm1".into()))
+            ]
+        );
+    }
+}
@@ -0,0 +1,245 @@
+//! Live web dashboard for receiver mode (`recv --web-addr`).
+//!
+//! `GET /` serves a single page charting each sender's offsets over the
+//! last five minutes, with its loss counters, for anyone with a browser
+//! but no terminal on the receiver. The page follows `GET /events`, a
+//! Server-Sent Events stream carrying one JSON object per accepted
+//! timestamp, with the sender's totals so far:
+//!
+//! ```text
+//! data: {"sender":"192.0.2.10","seq":1500,"recv_ms":1772366400250,"offset_ms":3,"received":1500,"lost":2}
+//! ```
+//!
+//! SSE is plain HTTP, which a browser's `EventSource` reconnects by
+//! itself, so there is no WebSocket handshake to implement. One thread,
+//! started before privileges are dropped, serves every client: the receive
+//! loop queues events for it without waiting, and it writes them to each
+//! stream with a short timeout, dropping clients that can't keep up. A
+//! new client first gets the latest event from each sender. The page can't
+//! change anything, but nor is it authenticated: bind it where only those
+//! who should see the figures can reach it.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::http;
+use crate::log;
+use crate::status::json_string;
+
+/// Event streams served at once.
+pub const MAX_CLIENTS: usize = 16;
+/// Events queued for the server thread before new ones are dropped.
+const QUEUE: usize = 1024;
+/// Longest a write to an event stream may block.
+const WRITE_TIMEOUT: Duration = Duration::from_millis(250);
+/// How often an idle stream gets a comment, to notice closed clients.
+const KEEPALIVE: Duration = Duration::from_secs(15);
+/// How often the server thread checks for new connections.
+const POLL: Duration = Duration::from_millis(100);
+
+const PAGE: &str = include_str!("../web/dashboard.html");
+
+/// One accepted timestamp, with its sender's totals.
+struct Event {
+    sender: String,
+    seq: Option<u32>,
+    recv_ms: u64,
+    offset_ms: i64,
+    received: u64,
+    lost: u64,
+}
+
+impl Event {
+    fn json(&self) -> String {
+        format!(
+            "{{\"sender\":{},\"seq\":{},\"recv_ms\":{},\"offset_ms\":{},\"received\":{},\"lost\":{}}}",
+            json_string(&self.sender),
+            self.seq.map_or_else(|| "null".to_string(), |s| s.to_string()),
+            self.recv_ms,
+            self.offset_ms,
+            self.received,
+            self.lost
+        )
+    }
+}
+
+/// The receive loop's side: totals per sender, and the queue to the
+/// server thread.
+pub struct Web {
+    tx: SyncSender<Event>,
+    totals: HashMap<String, (u64, u64)>,
+}
+
+impl Web {
+    /// Records an accepted timestamp from `sender`, `lost` being the
+    /// sequence numbers it skipped to get there.
+    pub fn observe(
+        &mut self,
+        sender: &str,
+        seq: Option<u32>,
+        recv_ms: u64,
+        offset_ms: i64,
+        lost: u64,
+    ) {
+        let totals = self.totals.entry(sender.to_string()).or_default();
+        totals.0 += 1;
+        totals.1 += lost;
+        // A busy server thread costs the page events, never the loop time
+        let _ = self.tx.try_send(Event {
+            sender: sender.to_string(),
+            seq,
+            recv_ms,
+            offset_ms,
+            received: totals.0,
+            lost: totals.1,
+        });
+    }
+}
+
+/// Listens on `addr` and serves the dashboard from a background thread,
+/// returning the loop's handle and the address bound.
+pub fn serve(addr: SocketAddr) -> Result<(Web, SocketAddr), String> {
+    let listener = TcpListener::bind(addr)
+        .and_then(|l| l.set_nonblocking(true).map(|()| l))
+        .map_err(|e| format!("Failed to listen for dashboard requests on {addr}: {e}"))?;
+    let local = listener.local_addr().unwrap_or(addr);
+    log::info!("Serving the dashboard on http://{local}/");
+    let (tx, rx) = mpsc::sync_channel(QUEUE);
+    thread::spawn(move || run(listener, rx));
+    Ok((
+        Web {
+            tx,
+            totals: HashMap::new(),
+        },
+        local,
+    ))
+}
+
+fn run(listener: TcpListener, rx: Receiver<Event>) {
+    let mut streams: Vec<TcpStream> = Vec::new();
+    let mut latest: BTreeMap<String, String> = BTreeMap::new();
+    let mut last_write = Instant::now();
+    loop {
+        match rx.recv_timeout(POLL) {
+            Ok(event) => {
+                let data = format!("data: {}\n\n", event.json());
+                streams.retain_mut(|s| s.write_all(data.as_bytes()).is_ok());
+                latest.insert(event.sender, data);
+                last_write = Instant::now();
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            // The receive loop has ended
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        if last_write.elapsed() >= KEEPALIVE {
+            streams.retain_mut(|s| s.write_all(b": keepalive\n\n").is_ok());
+            last_write = Instant::now();
+        }
+        loop {
+            match listener.accept() {
+                Ok((stream, _)) => match respond(stream, streams.len(), &latest) {
+                    Ok(Some(stream)) => streams.push(stream),
+                    Ok(None) => {}
+                    Err(e) => log::debug!("Dashboard request failed: {e}"),
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    log::warn!("Dashboard connection failed: {e}");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Answers one request, returning the stream if it is to carry events.
+fn respond(
+    stream: TcpStream,
+    clients: usize,
+    latest: &BTreeMap<String, String>,
+) -> io::Result<Option<TcpStream>> {
+    // Accepted sockets inherit non-blocking mode on some systems
+    stream.set_nonblocking(false)?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let request = http::read_request(&stream)?;
+    let (status, content_type, body) = match (request.method.as_str(), request.target.as_str()) {
+        ("GET", "/events") if clients < MAX_CLIENTS => {
+            let mut out = &stream;
+            write!(
+                out,
+                "HTTP/1.0 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\r\n"
+            )?;
+            for data in latest.values() {
+                out.write_all(data.as_bytes())?;
+            }
+            return Ok(Some(stream));
+        }
+        ("GET", "/events") => (
+            "503 Service Unavailable",
+            "text/plain",
+            "too many clients\n",
+        ),
+        ("GET", "/") => ("200 OK", "text/html; charset=utf-8", PAGE),
+        ("GET", _) => ("404 Not Found", "text/plain", "not found\n"),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "method not allowed\n",
+        ),
+    };
+    http::respond(&stream, status, content_type, &[], body)?;
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read};
+
+    fn request(addr: SocketAddr, path: &str) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: test\r\n\r\n").unwrap();
+        stream
+    }
+
+    #[test]
+    fn streams_events_with_totals_and_replays_the_latest() {
+        let (mut web, addr) = serve("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut page = String::new();
+        request(addr, "/").read_to_string(&mut page).unwrap();
+        assert!(page.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(page.contains("new EventSource(\"events\")"));
+
+        web.observe("192.0.2.10", Some(1), 1_000, 3, 0);
+        web.observe("192.0.2.10", Some(4), 2_000, -2, 2);
+        thread::sleep(POLL * 3);
+        // A late client starts from each sender's latest event
+        let stream = request(addr, "/events");
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        while !line.starts_with("data: ") {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+        }
+        assert_eq!(
+            line,
+            "data: {\"sender\":\"192.0.2.10\",\"seq\":4,\"recv_ms\":2000,\"offset_ms\":-2,\"received\":2,\"lost\":2}\n"
+        );
+        web.observe("192.0.2.11", None, 3_000, 1, 0);
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        assert!(
+            line.contains("\"sender\":\"192.0.2.11\",\"seq\":null"),
+            "{line}"
+        );
+    }
+}